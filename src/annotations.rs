@@ -0,0 +1,300 @@
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use uuid::Uuid;
+
+use crate::storage::{FragmentMatch, Storage};
+
+/// Meta key holding a database's ranking weights as JSON
+pub const RANKING_KEY: &str = "ranking_weights";
+
+/// A reviewer's verdict on a fragment or a whole document, or a user's thumbs up or down on it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum AnnotationLabel {
+    /// Ranked above equally similar fragments
+    Authoritative,
+    /// Ranked below equally similar fragments
+    Outdated,
+    /// Never returned by searches
+    Wrong,
+    /// Thumbs up: each user's vote lifts the fragment a little
+    Helpful,
+    /// Thumbs down: each user's vote lowers the fragment a little
+    Unhelpful,
+}
+
+impl AnnotationLabel {
+    pub fn name(&self) -> &'static str {
+        match self {
+            AnnotationLabel::Authoritative => "authoritative",
+            AnnotationLabel::Outdated => "outdated",
+            AnnotationLabel::Wrong => "wrong",
+            AnnotationLabel::Helpful => "helpful",
+            AnnotationLabel::Unhelpful => "unhelpful",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "authoritative" => Some(AnnotationLabel::Authoritative),
+            "outdated" => Some(AnnotationLabel::Outdated),
+            "wrong" => Some(AnnotationLabel::Wrong),
+            "helpful" => Some(AnnotationLabel::Helpful),
+            "unhelpful" => Some(AnnotationLabel::Unhelpful),
+            _ => None,
+        }
+    }
+
+    /// Whether it is feedback, counted per user, rather than a verdict counted once
+    pub fn is_vote(&self) -> bool {
+        matches!(self, AnnotationLabel::Helpful | AnnotationLabel::Unhelpful)
+    }
+}
+
+/// How much labels and feedback move a fragment's similarity score, set per database with
+/// `annotate --weight`. The defaults are on the scale of preset boosts: enough to reorder
+/// close results without overriding relevance.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RankingWeights {
+    /// Added to fragments marked authoritative
+    pub authoritative: f64,
+    /// Taken from fragments marked outdated
+    pub outdated: f64,
+    /// Added per helpful vote and taken per unhelpful one
+    pub vote: f64,
+    /// Largest change the votes on a fragment can make either way
+    pub max_votes: f64,
+}
+
+impl Default for RankingWeights {
+    fn default() -> Self {
+        Self { authoritative: 0.05, outdated: 0.05, vote: 0.01, max_votes: 0.05 }
+    }
+}
+
+impl RankingWeights {
+    /// Change to a fragment's similarity from the labels on it and its document. Verdicts
+    /// count once, however many reviewers gave them; votes count once per user, and their
+    /// net sum is capped at `max_votes`.
+    pub fn delta(&self, notes: &[AnnotationNote]) -> f64 {
+        let mut verdicts = HashSet::new();
+        let mut voters = HashSet::new();
+        let mut votes = 0.0;
+        for note in notes {
+            let Some(label) = note.label else {
+                continue;
+            };
+            if !label.is_vote() {
+                verdicts.insert(label);
+            } else if voters.insert((label, note.author.as_str())) {
+                votes += if label == AnnotationLabel::Helpful { 1.0 } else { -1.0 };
+            }
+        }
+        let verdicts: f64 = verdicts.into_iter().map(|label| self.verdict(label)).sum();
+        verdicts + (votes * self.vote).clamp(-self.max_votes, self.max_votes)
+    }
+
+    fn verdict(&self, label: AnnotationLabel) -> f64 {
+        match label {
+            AnnotationLabel::Authoritative => self.authoritative,
+            AnnotationLabel::Outdated => -self.outdated,
+            _ => 0.0,
+        }
+    }
+
+    /// Whether annotations with `label` change scores under these weights
+    fn moves(&self, label: AnnotationLabel) -> bool {
+        if label.is_vote() {
+            self.vote != 0.0 && self.max_votes != 0.0
+        } else {
+            self.verdict(label) != 0.0
+        }
+    }
+
+    /// Set one weight from `name=value`, e.g. `vote=0.02`
+    pub fn set(&mut self, spec: &str) -> Result<()> {
+        let (name, value) = spec.split_once('=')
+            .ok_or_else(|| anyhow::anyhow!("Expected name=value, got '{}'", spec))?;
+        let value: f64 = value.trim().parse()
+            .with_context(|| format!("Invalid weight '{}'", value.trim()))?;
+        if !value.is_finite() || value < 0.0 {
+            anyhow::bail!("Weights must be zero or positive, got {}", value);
+        }
+        match name.trim() {
+            "authoritative" => self.authoritative = value,
+            "outdated" => self.outdated = value,
+            "vote" => self.vote = value,
+            "max_votes" => self.max_votes = value,
+            other => anyhow::bail!("Unknown weight '{}' (expected authoritative, outdated, vote or max_votes)", other),
+        }
+        Ok(())
+    }
+
+    /// e.g. `authoritative +0.05, outdated -0.05, per vote ±0.01 up to ±0.05`
+    pub fn describe(&self) -> String {
+        format!("authoritative +{}, outdated -{}, per vote ±{} up to ±{}", self.authoritative, self.outdated, self.vote, self.max_votes)
+    }
+}
+
+/// A note and/or label a user attached to a fragment or document
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Annotation {
+    pub id: String,
+    /// Id of the fragment or document annotated
+    pub target: String,
+    pub label: Option<AnnotationLabel>,
+    pub note: Option<String>,
+    pub author: String,
+    /// When it was added, in microseconds since the Unix epoch
+    pub created: i64,
+}
+
+impl Annotation {
+    pub fn new(target: &str, label: Option<AnnotationLabel>, note: Option<String>, author: &str) -> Self {
+        Self {
+            id: Uuid::new_v4().to_string(),
+            target: target.to_string(),
+            label,
+            note,
+            author: author.to_string(),
+            created: chrono::Utc::now().timestamp_micros(),
+        }
+    }
+
+    /// What a search result shows of it
+    pub fn summary(&self) -> AnnotationNote {
+        AnnotationNote { label: self.label, note: self.note.clone(), author: self.author.clone() }
+    }
+}
+
+/// An annotation on a search result's fragment or document, as shown with the result
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AnnotationNote {
+    pub label: Option<AnnotationLabel>,
+    pub note: Option<String>,
+    pub author: String,
+}
+
+impl AnnotationNote {
+    /// e.g. `outdated (alice): superseded by the 2024 policy`
+    pub fn describe(&self) -> String {
+        let mut text = match self.label {
+            Some(label) => format!("{} ({})", label.name(), self.author),
+            None => format!("({})", self.author),
+        };
+        if let Some(note) = &self.note {
+            text.push_str(": ");
+            text.push_str(note);
+        }
+        text
+    }
+}
+
+/// The database's ranking weights, or the defaults when none were set
+pub async fn ranking_weights(storage: &mut dyn Storage) -> Result<RankingWeights> {
+    match storage.get_meta_value(RANKING_KEY).await?.filter(|json| !json.is_empty()) {
+        Some(json) => serde_json::from_str(&json).context("Failed to parse the recorded ranking weights"),
+        None => Ok(RankingWeights::default()),
+    }
+}
+
+pub async fn set_ranking_weights(storage: &mut dyn Storage, weights: &RankingWeights) -> Result<()> {
+    storage.set_meta_value(RANKING_KEY, &serde_json::to_string(weights)?).await
+}
+
+/// The database's ranking weights when any annotation in it changes ranking scores, so
+/// similarity searches need a wider pool and can't resume in the storage layer
+pub async fn ranking_boost(storage: &mut dyn Storage) -> Result<Option<RankingWeights>> {
+    let weights = ranking_weights(storage).await?;
+    let moved = storage.list_annotations(None).await?.iter()
+        .any(|annotation| annotation.label.is_some_and(|label| weights.moves(label)));
+    Ok(moved.then_some(weights))
+}
+
+/// Re-rank similarity results by their labels and feedback, returning each with its score change
+pub fn rerank(results: Vec<FragmentMatch>, weights: &RankingWeights) -> Vec<(FragmentMatch, f64)> {
+    let mut ranked: Vec<_> = results.into_iter()
+        .map(|fragment| {
+            let delta = weights.delta(&fragment.source.annotations);
+            (FragmentMatch { score: fragment.score + delta, ..fragment }, delta)
+        })
+        .collect();
+    ranked.sort_by(|a, b| b.0.score.total_cmp(&a.0.score));
+    ranked
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn note(label: Option<AnnotationLabel>, author: &str) -> AnnotationNote {
+        AnnotationNote { label, note: None, author: author.to_string() }
+    }
+
+    #[test]
+    fn test_labels_count_once() {
+        let weights = RankingWeights::default();
+        let notes = vec![
+            note(Some(AnnotationLabel::Outdated), "alice"),
+            note(Some(AnnotationLabel::Outdated), "bob"),
+            note(None, "carol"),
+        ];
+        assert_eq!(weights.delta(&notes), -weights.outdated);
+        assert_eq!(weights.delta(&[]), 0.0);
+    }
+
+    #[test]
+    fn test_votes_count_per_user_and_are_capped() {
+        let weights = RankingWeights { vote: 0.02, max_votes: 0.05, ..RankingWeights::default() };
+        let votes = |helpful: &[&str], unhelpful: &[&str]| {
+            let mut notes: Vec<AnnotationNote> = helpful.iter().map(|author| note(Some(AnnotationLabel::Helpful), author)).collect();
+            notes.extend(unhelpful.iter().map(|author| note(Some(AnnotationLabel::Unhelpful), author)));
+            weights.delta(&notes)
+        };
+        assert!((votes(&["alice", "alice", "bob"], &[]) - 0.04).abs() < 1e-9);
+        assert!((votes(&["alice"], &["bob", "carol"]) + 0.02).abs() < 1e-9);
+        assert_eq!(votes(&["a", "b", "c", "d", "e", "f"], &[]), 0.05);
+    }
+
+    #[test]
+    fn test_weights_set_by_name() {
+        let mut weights = RankingWeights::default();
+        weights.set("vote=0.02").unwrap();
+        weights.set("outdated = 0").unwrap();
+        assert_eq!((weights.vote, weights.outdated), (0.02, 0.0));
+        assert!(!weights.moves(AnnotationLabel::Outdated));
+        assert!(weights.set("stars=1").is_err());
+        assert!(weights.set("vote=-1").is_err());
+    }
+
+    #[test]
+    fn test_rerank_respects_labels() {
+        let fragment = |id: &str, score: f64, notes: Vec<AnnotationNote>| {
+            let mut fragment = FragmentMatch { fragment_id: id.to_string(), content: String::new(), score, source: Default::default() };
+            fragment.source.annotations = notes;
+            fragment
+        };
+        let weights = RankingWeights::default();
+        let ranked = rerank(vec![
+            fragment("old", 0.82, vec![note(Some(AnnotationLabel::Outdated), "alice")]),
+            fragment("plain", 0.80, Vec::new()),
+            fragment("policy", 0.79, vec![note(Some(AnnotationLabel::Authoritative), "bob")]),
+        ], &weights);
+        assert_eq!(ranked.iter().map(|(f, _)| f.fragment_id.as_str()).collect::<Vec<_>>(), vec!["policy", "plain", "old"]);
+        assert_eq!(ranked[0].1, weights.authoritative);
+    }
+
+    #[test]
+    fn test_describe() {
+        let outdated = AnnotationNote {
+            label: Some(AnnotationLabel::Outdated),
+            note: Some("superseded by the 2024 policy".to_string()),
+            author: "alice".to_string(),
+        };
+        assert_eq!(outdated.describe(), "outdated (alice): superseded by the 2024 policy");
+        assert_eq!(AnnotationLabel::parse("wrong"), Some(AnnotationLabel::Wrong));
+    }
+}
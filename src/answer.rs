@@ -0,0 +1,361 @@
+use clap::ValueEnum;
+use serde::Serialize;
+
+use crate::brains::BrainHit;
+use crate::llm::TokenUsage;
+use crate::storage::FragmentSource;
+use crate::verification::{ground_answer, split_cited_sentences, VerificationReport};
+
+/// How long answers should be
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum AnswerLength {
+    /// A sentence or two, for chat replies
+    Short,
+    /// Whatever length the question needs
+    #[default]
+    Normal,
+    /// Thorough answers covering every relevant passage, for drafting reports
+    Detailed,
+}
+
+impl AnswerLength {
+    /// Token budget for the answer; detailed answers would be cut off at the normal one
+    pub fn max_tokens(self) -> u32 {
+        match self {
+            AnswerLength::Short => 300,
+            AnswerLength::Normal => 1000,
+            AnswerLength::Detailed => 3000,
+        }
+    }
+}
+
+/// How answers are laid out
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum AnswerFormat {
+    /// A list of short bullet points
+    Bullet,
+    /// Paragraphs of running text
+    Prose,
+}
+
+/// Language, length and layout requested for answers, added to the system prompt
+#[derive(Debug, Clone, Default)]
+pub struct AnswerStyle {
+    pub language: Option<String>,
+    pub length: AnswerLength,
+    pub format: Option<AnswerFormat>,
+}
+
+impl AnswerStyle {
+    /// Instructions for the system prompt; empty when nothing beyond the defaults was asked for
+    pub fn instructions(&self) -> String {
+        let mut instructions = Vec::new();
+        match self.length {
+            AnswerLength::Short => instructions.push("Keep the answer to one or two sentences.".to_string()),
+            AnswerLength::Normal => {}
+            AnswerLength::Detailed => instructions.push(
+                "Answer in detail, covering every relevant point in the context.".to_string()
+            ),
+        }
+        match self.format {
+            Some(AnswerFormat::Bullet) => instructions.push(
+                "Format the answer as a list of bullet points, one statement per bullet.".to_string()
+            ),
+            Some(AnswerFormat::Prose) => instructions.push(
+                "Write the answer as prose paragraphs, without lists or headings.".to_string()
+            ),
+            None => {}
+        }
+        if let Some(language) = self.language.as_deref().map(str::trim).filter(|l| !l.is_empty()) {
+            instructions.push(format!(
+                "Answer in {}, whatever the language of the question or the context.",
+                language
+            ));
+        }
+        instructions.join(" ")
+    }
+}
+
+/// A retrieved passage as handed to the LLM, numbered as it appeared in the prompt
+#[derive(Debug, Clone, Serialize)]
+pub struct RetrievedPassage {
+    pub index: usize,
+    pub brain: String,
+    pub fragment_id: String,
+    pub score: f64,
+    pub content: String,
+    pub source: FragmentSource,
+    /// Dataset version of the brain it was retrieved from, to reproduce the retrieval
+    pub version: u64,
+}
+
+/// A passage the answer cites, with the number of sentences citing it
+#[derive(Debug, Clone, Serialize)]
+pub struct Citation {
+    pub index: usize,
+    pub brain: String,
+    pub fragment_id: String,
+    pub source: FragmentSource,
+    pub cited_by: usize,
+}
+
+/// Machine-readable answer produced by `eatmybrain --question ... --output json`
+#[derive(Debug, Clone, Serialize)]
+pub struct StructuredAnswer {
+    pub answer: String,
+    pub citations: Vec<Citation>,
+    /// Heuristic in [0, 1]: mean retrieval score of the cited passages, scaled by the
+    /// share of cited statements the verifier found supported when `--verify` is on
+    pub confidence: f64,
+    /// Share in [0, 1] of the answer's words that appear in the retrieved passages
+    pub grounding: f64,
+    pub retrieved: Vec<RetrievedPassage>,
+    /// Follow-up questions the retrieved passages can answer, with `--suggest`
+    pub follow_ups: Vec<String>,
+    pub token_usage: TokenUsage,
+    /// Why the output filters withheld the answer, which is then their refusal and comes
+    /// without citations or passages
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub withheld: Option<String>,
+}
+
+impl StructuredAnswer {
+    pub fn new(
+        answer: String,
+        hits: &[BrainHit],
+        token_usage: TokenUsage,
+        verification: Option<&VerificationReport>,
+    ) -> Self {
+        let retrieved: Vec<RetrievedPassage> = hits.iter()
+            .enumerate()
+            .map(|(i, hit)| RetrievedPassage {
+                index: i + 1,
+                brain: hit.brain.clone(),
+                fragment_id: hit.fragment_id.clone(),
+                score: hit.score,
+                content: hit.content.clone(),
+                source: hit.source.clone(),
+                version: hit.version,
+            })
+            .collect();
+
+        let mut citations: Vec<Citation> = Vec::new();
+        for sentence in split_cited_sentences(&answer) {
+            for index in sentence.citations {
+                let Some(passage) = retrieved.get(index.wrapping_sub(1)) else {
+                    continue;
+                };
+                match citations.iter_mut().find(|c| c.index == index) {
+                    Some(citation) => citation.cited_by += 1,
+                    None => citations.push(Citation {
+                        index,
+                        brain: passage.brain.clone(),
+                        fragment_id: passage.fragment_id.clone(),
+                        source: passage.source.clone(),
+                        cited_by: 1,
+                    }),
+                }
+            }
+        }
+        citations.sort_by_key(|c| c.index);
+
+        let confidence = confidence(&citations, &retrieved, verification);
+        let passages: Vec<String> = retrieved.iter().map(|passage| passage.content.clone()).collect();
+        let grounding = ground_answer(&answer, &passages, 0.0).score;
+
+        Self {
+            answer,
+            citations,
+            confidence,
+            grounding,
+            retrieved,
+            follow_ups: Vec::new(),
+            token_usage,
+            withheld: None,
+        }
+    }
+}
+
+/// A retrieved passage as given to the model, labelled with how current its source is so
+/// the model can caveat old information
+pub fn dated_passage(hit: &BrainHit) -> String {
+    match hit.source.freshness() {
+        Some(freshness) => format!("({}) {}", freshness, hit.content),
+        None => hit.content.clone(),
+    }
+}
+
+/// Instructions asking the model to flag statements resting on passages dated more than
+/// `days` before `today`
+pub fn staleness_instructions(days: u32, today: chrono::NaiveDate) -> String {
+    format!(
+        "Passages are labelled with the date their source was last modified or indexed; today is {}. \
+         When a statement relies on a passage dated more than {} days ago, say that it may be out of date \
+         and give the passage's date.",
+        today.format("%Y-%m-%d"), days
+    )
+}
+
+/// The "Sources" listing printed after an answer: one line per retrieved passage with its
+/// citation, marking the passages the answer cites
+pub fn render_sources(answer: &str, hits: &[BrainHit], show_brain: bool) -> String {
+    let cited: Vec<usize> = split_cited_sentences(answer).into_iter()
+        .flat_map(|sentence| sentence.citations)
+        .collect();
+
+    let mut rendered = String::from("📎 Sources\n");
+    for (i, hit) in hits.iter().enumerate() {
+        let index = i + 1;
+        let marker = if cited.contains(&index) { "*" } else { " " };
+        let brain = if show_brain { format!("{}: ", hit.brain) } else { String::new() };
+        rendered.push_str(&format!("  {}[{}] {}{}\n", marker, index, brain, hit.source.citation()));
+    }
+    if !cited.is_empty() {
+        rendered.push_str("   * cited in the answer\n");
+    }
+    rendered
+}
+
+/// An answer as Markdown for `/savefile`: the question as a heading, the answer, then its
+/// sources numbered as the answer cites them
+pub fn render_markdown(question: &str, answer: &str, hits: &[BrainHit], show_brain: bool) -> String {
+    let cited: Vec<usize> = split_cited_sentences(answer).into_iter()
+        .flat_map(|sentence| sentence.citations)
+        .collect();
+
+    let mut rendered = format!("## {}\n\n{}\n", question.trim(), answer.trim());
+    if !hits.is_empty() {
+        rendered.push_str("\n### Sources\n\n");
+        for (i, hit) in hits.iter().enumerate() {
+            let index = i + 1;
+            let brain = if show_brain { format!("{}: ", hit.brain) } else { String::new() };
+            let marker = if cited.contains(&index) { " (cited)" } else { "" };
+            rendered.push_str(&format!("- [{}] {}{}{}\n", index, brain, hit.source.citation(), marker));
+        }
+    }
+    rendered
+}
+
+fn confidence(
+    citations: &[Citation],
+    retrieved: &[RetrievedPassage],
+    verification: Option<&VerificationReport>,
+) -> f64 {
+    if citations.is_empty() {
+        return 0.0;
+    }
+
+    let retrieval = citations.iter()
+        .filter_map(|c| retrieved.get(c.index - 1))
+        .map(|p| p.score.clamp(0.0, 1.0))
+        .sum::<f64>() / citations.len() as f64;
+
+    let support = match verification {
+        Some(report) => {
+            let checked = report.checks.iter()
+                .filter(|c| c.support != crate::verification::Support::Uncited)
+                .count();
+            if checked == 0 {
+                0.0
+            } else {
+                (checked - report.flagged()) as f64 / checked as f64
+            }
+        }
+        None => 1.0,
+    };
+
+    retrieval * support
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hit(id: &str, score: f64) -> BrainHit {
+        BrainHit {
+            brain: "work".to_string(),
+            fragment_id: id.to_string(),
+            content: format!("content of {}", id),
+            score,
+            normalized_score: score,
+            source: FragmentSource {
+                filename: format!("{}.pdf", id),
+                page: Some(3),
+                ..FragmentSource::default()
+            },
+            version: 4,
+        }
+    }
+
+    #[test]
+    fn test_citations_and_confidence() {
+        let hits = vec![hit("a", 0.8), hit("b", 0.6), hit("c", 0.4)];
+        let answer = StructuredAnswer::new(
+            "Sales rose [1]. Margins held [1][2]. Unknown source [9].".to_string(),
+            &hits,
+            TokenUsage::default(),
+            None,
+        );
+
+        assert_eq!(answer.retrieved.len(), 3);
+        assert_eq!(answer.retrieved[0].version, 4);
+        assert_eq!(answer.citations.len(), 2);
+        assert_eq!(answer.citations[0].cited_by, 2);
+        assert_eq!(answer.citations[1].fragment_id, "b");
+        assert_eq!(answer.citations[1].source.filename, "b.pdf");
+        assert!((answer.confidence - 0.7).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_markdown_lists_cited_sources() {
+        let hits = vec![hit("a", 0.8), hit("b", 0.6)];
+        let markdown = render_markdown("What rose? ", "Sales rose [2].", &hits, false);
+
+        assert!(markdown.starts_with("## What rose?\n\nSales rose [2].\n\n### Sources\n\n"));
+        assert!(markdown.contains(&format!("- [1] {}\n", hits[0].source.citation())));
+        assert!(markdown.contains(&format!("- [2] {} (cited)\n", hits[1].source.citation())));
+    }
+
+    #[test]
+    fn test_answer_style_instructions() {
+        assert_eq!(AnswerStyle::default().instructions(), "");
+
+        let style = AnswerStyle {
+            language: Some("French".to_string()),
+            length: AnswerLength::Short,
+            format: Some(AnswerFormat::Bullet),
+        };
+        let instructions = style.instructions();
+        assert!(instructions.starts_with("Keep the answer to one or two sentences."));
+        assert!(instructions.contains("bullet points"));
+        assert!(instructions.ends_with("Answer in French, whatever the language of the question or the context."));
+    }
+
+    #[test]
+    fn test_passages_dated_by_source() {
+        let mut dated = hit("a", 0.8);
+        // 2019-04-02T12:00:00Z
+        dated.source.modified = Some(1_554_206_400_000_000);
+        assert_eq!(dated_passage(&dated), "(modified 2019-04-02) content of a");
+        assert!(dated.source.citation().ends_with("fragment 0, modified 2019-04-02"));
+
+        dated.source.modified = None;
+        dated.source.indexed = Some(1_554_206_400_000_000);
+        assert_eq!(dated_passage(&dated), "(indexed 2019-04-02) content of a");
+        assert_eq!(dated_passage(&hit("b", 0.6)), "content of b");
+
+        let today = chrono::NaiveDate::from_ymd_opt(2026, 1, 15).unwrap();
+        assert!(staleness_instructions(365, today).contains("today is 2026-01-15"));
+    }
+
+    #[test]
+    fn test_render_sources_marks_cited_passages() {
+        let hits = vec![hit("a", 0.8), hit("b", 0.6)];
+        let rendered = render_sources("Margins held [2].", &hits, false);
+
+        assert!(rendered.contains("   [1] a.pdf, p. 3"));
+        assert!(rendered.contains("  *[2] b.pdf, p. 3"));
+        assert!(!rendered.contains("work:"));
+        assert!(render_sources("No citations.", &hits, true).contains("[1] work: a.pdf"));
+    }
+}
@@ -0,0 +1,283 @@
+use anyhow::{Context, Result};
+use duckdb::{params, Connection};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// A passage an answer was written from, by the fragment it came from
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AuditedFragment {
+    pub brain: String,
+    pub fragment_id: String,
+    /// Dataset version of its brain when it was retrieved
+    pub version: u64,
+    /// Retrieval score; `None` for fragments read whole by a corpus answer
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub score: Option<f64>,
+    /// Document it belongs to, recorded for corpus answers
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub document_id: Option<String>,
+}
+
+/// What is recorded about one generated answer
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub question: String,
+    /// The messages sent for the answer, exactly as sent, each with its role and content. A
+    /// corpus answer makes several requests, so it holds each request's stage, the documents
+    /// and fragments a map request read, and its messages.
+    pub prompt: Value,
+    /// Retrieved passages given to the model, in the order they were numbered
+    pub fragments: Vec<AuditedFragment>,
+    pub model: String,
+    pub provider: String,
+    pub endpoint: String,
+    /// Generation and retrieval settings the answer was produced with
+    pub parameters: Value,
+    /// The answer as shown, after output filters
+    pub answer: String,
+    /// Why the output filters withheld the answer
+    pub withheld: Option<String>,
+}
+
+/// An entry as stored, with its place in the log's hash chain
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AuditRecord {
+    /// Position in the log, from 1
+    pub sequence: u64,
+    pub id: String,
+    /// When the answer was recorded, RFC 3339
+    pub recorded_at: String,
+    #[serde(flatten)]
+    pub entry: AuditEntry,
+    /// Hash of the record before, empty for the first
+    pub previous_hash: String,
+    /// SHA-256 of this record's other fields, `previous_hash` included, so a record changed
+    /// or removed after it was written breaks the chain
+    pub hash: String,
+}
+
+impl AuditRecord {
+    fn compute_hash(&self) -> Result<String> {
+        let unhashed = AuditRecord { hash: String::new(), ..self.clone() };
+        let mut hasher = Sha256::new();
+        hasher.update(serde_json::to_vec(&unhashed)?);
+        Ok(format!("{:x}", hasher.finalize()))
+    }
+}
+
+/// Append-only log of generated answers in a DuckDB file, for audits of what was asked, what
+/// the model was sent and what it answered. Records are only ever inserted; each carries the
+/// hash of the one before, so `verify` finds records edited or deleted outside this log.
+pub struct AuditLog {
+    path: PathBuf,
+}
+
+impl AuditLog {
+    /// The log in `path`, created on the first answer
+    pub fn new(path: &Path) -> Self {
+        Self { path: path.to_path_buf() }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Opened for each write rather than held, so several chat sessions can share one log
+    fn connect(&self) -> Result<Connection> {
+        let conn = Connection::open(&self.path)
+            .with_context(|| format!("Failed to open the audit log {}", self.path.display()))?;
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS answer_audit (
+                sequence BIGINT PRIMARY KEY,
+                id VARCHAR NOT NULL,
+                recorded_at VARCHAR NOT NULL,
+                question VARCHAR NOT NULL,
+                prompt VARCHAR NOT NULL,
+                fragments VARCHAR NOT NULL,
+                model VARCHAR NOT NULL,
+                provider VARCHAR NOT NULL,
+                endpoint VARCHAR NOT NULL,
+                parameters VARCHAR NOT NULL,
+                answer VARCHAR NOT NULL,
+                withheld VARCHAR,
+                previous_hash VARCHAR NOT NULL,
+                hash VARCHAR NOT NULL
+            )",
+            [],
+        ).context("Failed to create the audit table")?;
+        Ok(conn)
+    }
+
+    /// Record an answer at the end of the log
+    pub fn append(&self, entry: AuditEntry) -> Result<AuditRecord> {
+        let mut conn = self.connect()?;
+        let tx = conn.transaction()?;
+        let last: Option<(i64, String)> = match tx.query_row(
+            "SELECT sequence, hash FROM answer_audit ORDER BY sequence DESC LIMIT 1",
+            [],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        ) {
+            Ok(last) => Some(last),
+            Err(duckdb::Error::QueryReturnedNoRows) => None,
+            Err(e) => return Err(e).context("Failed to read the audit log"),
+        };
+        let (sequence, previous_hash) = last.map_or((1, String::new()), |(sequence, hash)| (sequence as u64 + 1, hash));
+
+        let mut record = AuditRecord {
+            sequence,
+            id: uuid::Uuid::new_v4().to_string(),
+            recorded_at: chrono::Utc::now().to_rfc3339(),
+            entry,
+            previous_hash,
+            hash: String::new(),
+        };
+        record.hash = record.compute_hash()?;
+
+        tx.execute(
+            "INSERT INTO answer_audit VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+            params![
+                record.sequence as i64,
+                record.id,
+                record.recorded_at,
+                record.entry.question,
+                record.entry.prompt.to_string(),
+                serde_json::to_string(&record.entry.fragments)?,
+                record.entry.model,
+                record.entry.provider,
+                record.entry.endpoint,
+                record.entry.parameters.to_string(),
+                record.entry.answer,
+                record.entry.withheld,
+                record.previous_hash,
+                record.hash,
+            ],
+        ).context("Failed to write to the audit log")?;
+        tx.commit()?;
+        Ok(record)
+    }
+
+    /// Every record, oldest first
+    pub fn records(&self) -> Result<Vec<AuditRecord>> {
+        let conn = self.connect()?;
+        let mut stmt = conn.prepare(
+            "SELECT sequence, id, recorded_at, question, prompt, fragments, model, provider, endpoint,
+                    parameters, answer, withheld, previous_hash, hash
+             FROM answer_audit ORDER BY sequence",
+        )?;
+        let rows = stmt.query_map([], |row| {
+            Ok((
+                (row.get::<_, i64>(0)?, row.get::<_, String>(1)?, row.get::<_, String>(2)?, row.get::<_, String>(3)?),
+                (row.get::<_, String>(4)?, row.get::<_, String>(5)?, row.get::<_, String>(6)?, row.get::<_, String>(7)?),
+                (row.get::<_, String>(8)?, row.get::<_, String>(9)?, row.get::<_, String>(10)?, row.get::<_, Option<String>>(11)?),
+                (row.get::<_, String>(12)?, row.get::<_, String>(13)?),
+            ))
+        })?;
+
+        let mut records = Vec::new();
+        for row in rows {
+            let ((sequence, id, recorded_at, question), (prompt, fragments, model, provider), (endpoint, parameters, answer, withheld), (previous_hash, hash)) = row?;
+            records.push(AuditRecord {
+                sequence: sequence as u64,
+                id,
+                recorded_at,
+                entry: AuditEntry {
+                    question,
+                    prompt: serde_json::from_str(&prompt).context("Invalid prompt in the audit log")?,
+                    fragments: serde_json::from_str(&fragments).context("Invalid fragments in the audit log")?,
+                    model,
+                    provider,
+                    endpoint,
+                    parameters: serde_json::from_str(&parameters).context("Invalid parameters in the audit log")?,
+                    answer,
+                    withheld,
+                },
+                previous_hash,
+                hash,
+            });
+        }
+        Ok(records)
+    }
+
+    /// Write every record as a line of JSON, oldest first, after checking the hash chain.
+    /// Returns how many were written.
+    pub fn export(&self, out: &mut impl Write) -> Result<usize> {
+        let records = self.records()?;
+        verify(&records)?;
+        for record in &records {
+            serde_json::to_writer(&mut *out, record)?;
+            out.write_all(b"\n")?;
+        }
+        out.flush()?;
+        Ok(records.len())
+    }
+}
+
+/// Check that records follow each other without gaps and that each hash matches its record
+/// and the next record's `previous_hash`, naming the first record that doesn't. Records
+/// deleted from the end leave no gap, so the chain can't reveal them; comparing the last
+/// record with the one exported before does.
+pub fn verify(records: &[AuditRecord]) -> Result<()> {
+    let mut previous = String::new();
+    for (i, record) in records.iter().enumerate() {
+        if record.sequence != i as u64 + 1 {
+            anyhow::bail!("The audit log is missing record {}", i + 1);
+        }
+        if record.previous_hash != previous || record.compute_hash()? != record.hash {
+            anyhow::bail!("Audit record {} ({}) was changed after it was written", record.sequence, record.id);
+        }
+        previous = record.hash.clone();
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn entry(question: &str) -> AuditEntry {
+        AuditEntry {
+            question: question.to_string(),
+            prompt: json!([{"role": "system", "content": "Answer from the context."}, {"role": "user", "content": question}]),
+            fragments: vec![AuditedFragment { brain: "policies".to_string(), fragment_id: "f1".to_string(), version: 3, score: Some(0.8125), document_id: None }],
+            model: "gpt-4".to_string(),
+            provider: "openai".to_string(),
+            endpoint: "https://api.openai.com/v1/chat/completions".to_string(),
+            parameters: json!({"temperature": 0.7, "max_tokens": 1000}),
+            answer: "Twenty days [1].".to_string(),
+            withheld: None,
+        }
+    }
+
+    #[test]
+    fn test_audit_log_chains_and_exports_records() {
+        let path = std::env::temp_dir().join(format!("pb-audit-{}.audit", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let log = AuditLog::new(&path);
+        let first = log.append(entry("How many vacation days?")).unwrap();
+        let second = log.append(entry("Who approves leave?")).unwrap();
+        assert_eq!((first.sequence, second.sequence), (1, 2));
+        assert_eq!(second.previous_hash, first.hash);
+
+        let mut jsonl = Vec::new();
+        assert_eq!(log.export(&mut jsonl).unwrap(), 2);
+        let lines: Vec<Value> = String::from_utf8(jsonl).unwrap().lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(lines[1]["question"], "Who approves leave?");
+        assert_eq!(lines[1]["fragments"][0]["fragment_id"], "f1");
+        assert_eq!(lines[0]["prompt"][1]["content"], "How many vacation days?");
+
+        // Edits and deletions made behind the log's back break the chain
+        let conn = Connection::open(log.path()).unwrap();
+        conn.execute("UPDATE answer_audit SET answer = 'Thirty days [1].' WHERE sequence = 1", []).unwrap();
+        drop(conn);
+        assert!(log.export(&mut Vec::new()).unwrap_err().to_string().contains("Audit record 1"));
+
+        let mut records = log.records().unwrap();
+        records.remove(0);
+        assert!(verify(&records).is_err());
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,322 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use utoipa::ToSchema;
+
+use crate::storage::arrow::array::{Array, StringArray};
+use crate::storage::{self, fragment_column, Storage};
+
+/// Meta key holding the terms queries are completed with, rebuilt at the end of each index run
+pub const AUTOCOMPLETE_KEY: &str = "autocomplete_terms";
+
+/// Completions offered unless asked for more or fewer
+pub const DEFAULT_SUGGESTIONS: usize = 8;
+
+/// Most frequent entities offered as completions
+const MAX_ENTITIES: usize = 200;
+/// Headings kept, those heading the most documents first
+const MAX_HEADINGS: usize = 5000;
+/// Shorter terms complete too little to be worth offering
+const MIN_TERM_CHARS: usize = 3;
+/// Longer titles and headings read as sentences rather than something to complete to
+const MAX_TERM_CHARS: usize = 80;
+/// Characters of a word typed before it is completed
+const MIN_PREFIX_CHARS: usize = 2;
+
+/// Where a completion term was found
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum TermKind {
+    Title,
+    Heading,
+    Entity,
+}
+
+/// A document title, heading or entity a query can be completed to
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Term {
+    pub text: String,
+    pub kind: TermKind,
+    /// Documents it appears in
+    pub documents: usize,
+    /// Collections of those documents, so tenants are only offered terms from their own
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub collections: Vec<String>,
+}
+
+/// A completion of the query typed so far
+#[derive(Debug, Clone, PartialEq, Serialize, ToSchema)]
+pub struct Suggestion {
+    /// The whole query, completed
+    pub query: String,
+    /// The term it was completed with
+    pub term: String,
+    pub kind: TermKind,
+    /// Byte offset in the typed query where the completed text starts; what was typed
+    /// before it is kept as is
+    pub start: usize,
+}
+
+/// Terms looked up by the start of any of their words, lowercased, so "bud" and "quarterly
+/// bud" both complete to "Quarterly Budget Review"
+#[derive(Debug, Default)]
+pub struct PrefixIndex {
+    terms: Vec<Term>,
+    /// Lowercased term text from one of its word starts, the term and the word's byte offset
+    /// in the term, sorted by key
+    keys: Vec<(String, usize, usize)>,
+}
+
+impl PrefixIndex {
+    /// Index `terms`; the same term from several brains is merged into one
+    pub fn new(terms: Vec<Term>) -> Self {
+        let mut merged: BTreeMap<(TermKind, String), Term> = BTreeMap::new();
+        for term in terms {
+            match merged.get_mut(&(term.kind, term.text.to_lowercase())) {
+                Some(existing) => {
+                    existing.documents += term.documents;
+                    for collection in term.collections {
+                        if !existing.collections.contains(&collection) {
+                            existing.collections.push(collection);
+                        }
+                    }
+                }
+                None => {
+                    merged.insert((term.kind, term.text.to_lowercase()), term);
+                }
+            }
+        }
+        let terms: Vec<Term> = merged.into_values().collect();
+
+        let mut keys = Vec::new();
+        for (index, term) in terms.iter().enumerate() {
+            for start in word_starts(&term.text) {
+                keys.push((term.text[start..].to_lowercase(), index, start));
+            }
+        }
+        keys.sort();
+        Self { terms, keys }
+    }
+
+    /// The terms stored in a database by its last index run
+    pub async fn load(storage: &mut dyn Storage) -> Result<Self> {
+        Ok(Self::new(stored_terms(storage).await?))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.terms.is_empty()
+    }
+
+    /// Up to `limit` completions of `input` from the terms `visible` keeps, best first. The
+    /// longest tail of the input that starts a word of some term is completed, so earlier
+    /// words of the query are kept and the words typed last pick the term. Titles and
+    /// headings the input starts are preferred to those it matches a later word of, and
+    /// terms found in more documents to rarer ones.
+    pub fn suggest(&self, input: &str, limit: usize, visible: impl Fn(&Term) -> bool) -> Vec<Suggestion> {
+        for start in word_starts(input) {
+            let typed = &input[start..];
+            if typed.trim_end().chars().count() < MIN_PREFIX_CHARS {
+                break;
+            }
+            let prefix = typed.to_lowercase();
+            let first = self.keys.partition_point(|(key, _, _)| key.as_str() < prefix.as_str());
+
+            // Each term once, by its earliest matching word
+            let mut earliest: HashMap<usize, usize> = HashMap::new();
+            let found = self.keys[first..].iter()
+                .take_while(|(key, _, _)| key.starts_with(&prefix))
+                .filter(|(key, index, _)| key.len() > prefix.len() && visible(&self.terms[*index]));
+            for (_, index, offset) in found {
+                let word = earliest.entry(*index).or_insert(*offset);
+                *word = (*word).min(*offset);
+            }
+            if earliest.is_empty() {
+                continue;
+            }
+            let mut matches: Vec<(&Term, usize)> = earliest.into_iter()
+                .map(|(index, offset)| (&self.terms[index], offset))
+                .collect();
+
+            matches.sort_by(|(a, a_offset), (b, b_offset)| {
+                (*a_offset > 0).cmp(&(*b_offset > 0))
+                    .then_with(|| b.documents.cmp(&a.documents))
+                    .then_with(|| a.kind.cmp(&b.kind))
+                    .then_with(|| a.text.len().cmp(&b.text.len()))
+                    .then_with(|| a.text.cmp(&b.text))
+            });
+            return matches.into_iter()
+                .take(limit)
+                .map(|(term, offset)| Suggestion {
+                    query: format!("{}{}", &input[..start], &term.text[offset..]),
+                    term: term.text.clone(),
+                    kind: term.kind,
+                    start,
+                })
+                .collect();
+        }
+        Vec::new()
+    }
+}
+
+/// Byte offsets of the words of `text`, first to last
+fn word_starts(text: &str) -> Vec<usize> {
+    let mut starts = Vec::new();
+    let mut previous: Option<char> = None;
+    for (offset, c) in text.char_indices() {
+        if !c.is_whitespace() && previous.is_none_or(char::is_whitespace) {
+            starts.push(offset);
+        }
+        previous = Some(c);
+    }
+    starts
+}
+
+/// Count `text` as appearing in one more document, in `collection`
+fn add_term(terms: &mut BTreeMap<(TermKind, String), Term>, kind: TermKind, text: &str, collection: Option<&str>) {
+    let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    let chars = text.chars().count();
+    if !(MIN_TERM_CHARS..=MAX_TERM_CHARS).contains(&chars) {
+        return;
+    }
+    let term = terms.entry((kind, text.to_lowercase()))
+        .or_insert_with(|| Term { text, kind, documents: 0, collections: Vec::new() });
+    term.documents += 1;
+    if let Some(collection) = collection.filter(|collection| !term.collections.iter().any(|own| own == collection)) {
+        term.collections.push(collection.to_string());
+    }
+}
+
+/// The titles, headings and most frequent entities of the documents in `storage`, leaving
+/// out tombstoned documents and those in `excluded`
+pub async fn collect_terms(storage: &mut dyn Storage, excluded: &HashSet<String>) -> Result<Vec<Term>> {
+    let mut terms = BTreeMap::new();
+    let mut collections: HashMap<String, Option<String>> = HashMap::new();
+    for document in storage.list_documents().await? {
+        if document.tombstoned || excluded.contains(&document.id) {
+            continue;
+        }
+        let collection = storage.get_document_collection(&document.id).await?;
+        if let Some(title) = &document.title {
+            add_term(&mut terms, TermKind::Title, title, collection.as_deref());
+        }
+        collections.insert(document.id, collection);
+    }
+
+    // Each heading of a section path, counted once per document
+    let mut headed = HashSet::new();
+    let mut after: Option<String> = None;
+    while let Some(batch) = storage.read_fragment_batch(after.as_deref(), storage::FRAGMENT_BATCH_SIZE).await? {
+        let ids = fragment_column::<StringArray>(&batch, "id")?;
+        let documents = fragment_column::<StringArray>(&batch, "document_id")?;
+        let sections = fragment_column::<StringArray>(&batch, "section")?;
+        for row in 0..batch.num_rows() {
+            let Some(collection) = collections.get(documents.value(row)).filter(|_| !sections.is_null(row)) else {
+                continue;
+            };
+            for heading in sections.value(row).split(" > ") {
+                if headed.insert((documents.value(row).to_string(), heading.trim().to_lowercase())) {
+                    add_term(&mut terms, TermKind::Heading, heading, collection.as_deref());
+                }
+            }
+        }
+        after = Some(ids.value(ids.len() - 1).to_string());
+    }
+
+    for entity in storage.list_entities(MAX_ENTITIES).await? {
+        for mention in storage.find_entity(&entity.name).await? {
+            if let Some(collection) = collections.get(&mention.document_id) {
+                add_term(&mut terms, TermKind::Entity, &entity.name, collection.as_deref());
+            }
+        }
+    }
+
+    let mut terms: Vec<Term> = terms.into_values().collect();
+    for term in &mut terms {
+        term.collections.sort();
+    }
+    terms.sort_by(|a, b| a.kind.cmp(&b.kind).then_with(|| b.documents.cmp(&a.documents)).then_with(|| a.text.cmp(&b.text)));
+    let mut headings = 0;
+    terms.retain(|term| term.kind != TermKind::Heading || {
+        headings += 1;
+        headings <= MAX_HEADINGS
+    });
+    Ok(terms)
+}
+
+/// Rebuild the completion terms stored in `storage` from its documents as they are now.
+/// Returns how many there are.
+pub async fn refresh(storage: &mut dyn Storage) -> Result<usize> {
+    let terms = collect_terms(storage, &HashSet::new()).await?;
+    storage.set_meta_value(AUTOCOMPLETE_KEY, &serde_json::to_string(&terms)?).await?;
+    Ok(terms.len())
+}
+
+/// The completion terms stored by the last index run; none for databases indexed before
+/// completions were added
+pub async fn stored_terms(storage: &mut dyn Storage) -> Result<Vec<Term>> {
+    match storage.get_meta_value(AUTOCOMPLETE_KEY).await? {
+        Some(json) => serde_json::from_str(&json).context("Failed to parse completion terms"),
+        None => Ok(Vec::new()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lancedb_storage::LanceDBStorage;
+    use crate::storage::FragmentMeta;
+    use std::path::Path;
+
+    fn term(text: &str, kind: TermKind, documents: usize) -> Term {
+        Term { text: text.to_string(), kind, documents, collections: Vec::new() }
+    }
+
+    #[test]
+    fn test_suggestions_complete_the_last_words_typed() {
+        let index = PrefixIndex::new(vec![
+            term("Quarterly Budget Review", TermKind::Title, 1),
+            term("Budget Approvals", TermKind::Heading, 3),
+            Term { collections: vec!["finance".to_string()], ..term("Maria Chen", TermKind::Entity, 2) },
+            term("budget approvals", TermKind::Heading, 1),
+        ]);
+        let queries = |input: &str| -> Vec<String> {
+            index.suggest(input, DEFAULT_SUGGESTIONS, |_| true).into_iter().map(|suggestion| suggestion.query).collect()
+        };
+
+        // Terms the typed word starts come first, then those it matches a later word of
+        assert_eq!(queries("who signs bud"), vec!["who signs Budget Approvals", "who signs Budget Review"]);
+        // The longest matching tail wins, keeping the rest of the query
+        assert_eq!(queries("the quarterly bu"), vec!["the Quarterly Budget Review"]);
+        let suggestion = &index.suggest("ask maria", 1, |_| true)[0];
+        assert_eq!((suggestion.start, suggestion.kind, suggestion.term.as_str()), (4, TermKind::Entity, "Maria Chen"));
+        // Merged across brains, counting the documents of both
+        assert_eq!(index.terms.iter().find(|term| term.kind == TermKind::Heading).unwrap().documents, 4);
+
+        assert!(queries("b").is_empty());
+        assert!(queries("budget approvals").is_empty());
+        assert!(index.suggest("mar", 5, |term| term.collections.is_empty()).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_terms_collected_from_titles_headings_and_entities() {
+        let mut storage = LanceDBStorage::new(Path::new("autocomplete")).await.unwrap();
+        let handbook = storage.store_document(Path::new("handbook.md"), b"handbook").await.unwrap();
+        storage.set_document_title(&handbook, "Employee Handbook").await.unwrap();
+        for (order, section) in ["Leave > Vacation Days", "Leave > Sick Days"].iter().enumerate() {
+            let meta = FragmentMeta { section: Some(section.to_string()), ..FragmentMeta::default() };
+            storage.store_text_fragment(&handbook, order as i32, "Ask HR about leave.", &meta).await.unwrap();
+        }
+        let gone = storage.store_document(Path::new("gone.md"), b"gone").await.unwrap();
+        storage.set_document_title(&gone, "Retired Policies").await.unwrap();
+        storage.tombstone_document(&gone, true).await.unwrap();
+
+        assert_eq!(refresh(&mut storage).await.unwrap(), 4);
+        let index = PrefixIndex::load(&mut storage).await.unwrap();
+        let suggestion = &index.suggest("how many vac", 1, |_| true)[0];
+        assert_eq!((suggestion.query.as_str(), suggestion.kind), ("how many Vacation Days", TermKind::Heading));
+        // "Leave" heads both fragments but is counted once for the document
+        assert_eq!(index.suggest("lea", 1, |_| true)[0].term, "Leave");
+        assert_eq!(index.terms.iter().find(|term| term.text == "Leave").unwrap().documents, 1);
+        assert!(index.suggest("retired", 1, |_| true).is_empty());
+    }
+}
@@ -0,0 +1,202 @@
+use anyhow::Result;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+
+use crate::annotations;
+use crate::document_processor;
+use crate::presets;
+use crate::storage::{self, DocumentSummary, Storage};
+
+/// Meta keys recording how a brain was indexed and embedded, compared by `diff-db`
+const SETTING_KEYS: &[&str] = &[
+    storage::DIMENSION_KEY,
+    storage::PROVIDER_KEY,
+    storage::DOCUMENT_PREFIX_KEY,
+    storage::QUERY_PREFIX_KEY,
+    presets::PRESET_KEY,
+    document_processor::CHUNKING_KEY,
+    storage::LATE_CHUNKING_KEY,
+    storage::STORE_ORIGINALS_KEY,
+    storage::SIMILARITY_METRIC_KEY,
+    storage::DETERMINISTIC_IDS_KEY,
+    storage::INDEXER_VERSION_KEY,
+    annotations::RANKING_KEY,
+    storage::DATA_VERSION_KEY,
+];
+
+/// How a brain was indexed and embedded: its model, schema version, the settings recorded
+/// in its meta table, and the model of each collection embedded with one of its own
+pub async fn settings(storage: &mut dyn Storage) -> Result<BTreeMap<String, String>> {
+    let meta = storage.get_meta_info().await?;
+    let mut settings = BTreeMap::new();
+    settings.insert("embedding_model".to_string(), meta.embedding_model);
+    settings.insert("schema_version".to_string(), meta.version);
+    for key in SETTING_KEYS {
+        if let Some(value) = storage.get_meta_value(key).await? {
+            settings.insert(key.to_string(), value);
+        }
+    }
+    for (collection, model) in storage.collection_models() {
+        settings.insert(format!("collection {} model", collection), model);
+    }
+    Ok(settings)
+}
+
+/// A document indexed from the same path in both brains whose content or fragments differ
+#[derive(Debug, Clone, Serialize)]
+pub struct ChangedDocument {
+    pub file_path: String,
+    /// What differs, e.g. `content` or `tombstoned`
+    pub changes: Vec<&'static str>,
+    pub old_fragments: i32,
+    pub new_fragments: i32,
+}
+
+/// A setting recorded differently, or only, in one of the brains
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SettingChange {
+    pub key: String,
+    pub old: Option<String>,
+    pub new: Option<String>,
+}
+
+/// What changed between two brains, documents matched by path
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct BrainDiff {
+    pub added: Vec<DocumentSummary>,
+    pub removed: Vec<DocumentSummary>,
+    pub changed: Vec<ChangedDocument>,
+    pub unchanged: usize,
+    pub old_fragments: i64,
+    pub new_fragments: i64,
+    pub settings: Vec<SettingChange>,
+}
+
+impl BrainDiff {
+    /// Compare the documents and settings of the old brain with the new one. A document
+    /// changed when its content hash differs (where both were hashed), it was split into
+    /// another number of fragments, or it was tombstoned or restored.
+    pub fn new(
+        old: Vec<DocumentSummary>,
+        new: Vec<DocumentSummary>,
+        old_settings: &BTreeMap<String, String>,
+        new_settings: &BTreeMap<String, String>,
+    ) -> Self {
+        let mut diff = BrainDiff {
+            old_fragments: old.iter().map(|d| d.fragments as i64).sum(),
+            new_fragments: new.iter().map(|d| d.fragments as i64).sum(),
+            ..Default::default()
+        };
+
+        let mut old: HashMap<String, DocumentSummary> = old.into_iter().map(|d| (d.file_path.clone(), d)).collect();
+        for document in new {
+            let Some(before) = old.remove(&document.file_path) else {
+                diff.added.push(document);
+                continue;
+            };
+            let mut changes = Vec::new();
+            if let (Some(before), Some(after)) = (&before.content_hash, &document.content_hash) {
+                if before != after {
+                    changes.push("content");
+                }
+            }
+            if before.fragments != document.fragments {
+                changes.push("fragments");
+            }
+            match (before.tombstoned, document.tombstoned) {
+                (false, true) => changes.push("tombstoned"),
+                (true, false) => changes.push("restored"),
+                _ => {}
+            }
+            if changes.is_empty() {
+                diff.unchanged += 1;
+            } else {
+                diff.changed.push(ChangedDocument {
+                    file_path: document.file_path,
+                    changes,
+                    old_fragments: before.fragments,
+                    new_fragments: document.fragments,
+                });
+            }
+        }
+        diff.removed = old.into_values().collect();
+
+        diff.added.sort_by(|a, b| a.file_path.cmp(&b.file_path));
+        diff.removed.sort_by(|a, b| a.file_path.cmp(&b.file_path));
+        diff.changed.sort_by(|a, b| a.file_path.cmp(&b.file_path));
+
+        let mut keys: Vec<&String> = old_settings.keys().chain(new_settings.keys()).collect();
+        keys.sort();
+        keys.dedup();
+        diff.settings = keys.into_iter()
+            .filter(|key| old_settings.get(*key) != new_settings.get(*key))
+            .map(|key| SettingChange {
+                key: key.clone(),
+                old: old_settings.get(key).cloned(),
+                new: new_settings.get(key).cloned(),
+            })
+            .collect();
+        diff
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty() && self.settings.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lancedb_storage::LanceDBStorage;
+    use std::path::Path;
+
+    fn document(path: &str, fragments: i32, hash: &str) -> DocumentSummary {
+        DocumentSummary {
+            id: path.to_string(),
+            filename: path.rsplit('/').next().unwrap().to_string(),
+            file_path: path.to_string(),
+            fragments,
+            quality_score: None,
+            quality_flags: Vec::new(),
+            tombstoned: false,
+            content_hash: Some(hash.to_string()),
+            modified: None,
+            title: None,
+        }
+    }
+
+    #[test]
+    fn test_diff_documents() {
+        let old = vec![
+            document("/docs/kept.pdf", 4, "a"),
+            document("/docs/edited.pdf", 3, "b"),
+            document("/docs/rechunked.pdf", 5, "c"),
+            document("/docs/gone.pdf", 2, "d"),
+        ];
+        let new = vec![
+            document("/docs/kept.pdf", 4, "a"),
+            document("/docs/edited.pdf", 3, "b2"),
+            document("/docs/rechunked.pdf", 8, "c"),
+            document("/docs/new.pdf", 6, "e"),
+        ];
+        let diff = BrainDiff::new(old, new, &BTreeMap::new(), &BTreeMap::new());
+
+        assert_eq!(diff.added.iter().map(|d| d.file_path.as_str()).collect::<Vec<_>>(), vec!["/docs/new.pdf"]);
+        assert_eq!(diff.removed.iter().map(|d| d.file_path.as_str()).collect::<Vec<_>>(), vec!["/docs/gone.pdf"]);
+        assert_eq!(diff.changed.len(), 2);
+        assert_eq!(diff.changed[0].changes, vec!["content"]);
+        assert_eq!((diff.changed[1].old_fragments, diff.changed[1].new_fragments), (5, 8));
+        assert_eq!(diff.unchanged, 1);
+        assert_eq!((diff.old_fragments, diff.new_fragments), (14, 21));
+    }
+
+    #[tokio::test]
+    async fn test_diff_settings() {
+        let mut old = LanceDBStorage::new(Path::new("old")).await.unwrap();
+        let mut new = LanceDBStorage::new(Path::new("new")).await.unwrap();
+        new.set_meta_value(presets::PRESET_KEY, "legal").await.unwrap();
+
+        let diff = BrainDiff::new(Vec::new(), Vec::new(), &settings(&mut old).await.unwrap(), &settings(&mut new).await.unwrap());
+        assert_eq!(diff.settings, vec![SettingChange { key: "preset".to_string(), old: None, new: Some("legal".to_string()) }]);
+    }
+}
@@ -0,0 +1,619 @@
+use anyhow::{Context, Result};
+use futures::future::join_all;
+use log::{info, warn};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+use crate::annotations::{self, Annotation, AnnotationLabel, RankingWeights};
+use crate::autocomplete::{self, PrefixIndex};
+use crate::presets::{self, Booster, Preset};
+use crate::embedding_manager::{EmbeddingManager, EmbeddingPrefixes};
+use crate::hybrid::SearchMode;
+use crate::remote_storage;
+use crate::storage::{self, create_storage, DocumentSummary, FragmentSource, SearchFilter, SimilarityMetric, Storage, StorageBackend};
+use crate::summary_tree::{self, SummaryTree};
+
+/// Number of fragment embeddings averaged into a brain's centroid for routing
+const CENTROID_SAMPLE_SIZE: usize = 1000;
+
+/// Share of a brain's results given to its summaries, so specific questions keep their detail
+const SUMMARY_SHARE: usize = 3;
+
+/// How queries are dispatched when several brains are configured
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RoutingMode {
+    /// Send each query only to the brain whose content centroid is most similar
+    Route,
+    /// Query every brain and merge the results into one ranking
+    Federate,
+}
+
+/// One database participating in a multi-brain setup
+pub struct Brain {
+    pub name: String,
+    pub path: PathBuf,
+    pub storage: Box<dyn Storage>,
+    centroid: Option<Vec<f64>>,
+    /// Queries compare against the vectors a model upgrade left stale
+    stale: bool,
+    /// Preset recorded at index time and its compiled search boosts
+    preset: Option<Preset>,
+    booster: Option<Booster>,
+    /// Its ranking weights, when reviewers' labels or feedback re-rank its similarity results
+    labelled: Option<RankingWeights>,
+    /// Its collections embedded with a model of their own
+    spaces: Vec<CollectionSpace>,
+    /// Its summary tree, when one was built with the model queries are embedded with
+    summaries: Option<SummaryTree>,
+    /// How its vectors are compared, which its summaries are searched by too
+    metric: SimilarityMetric,
+}
+
+/// A collection embedded with a model of its own, searched with queries embedded by it
+struct CollectionSpace {
+    collection: String,
+    model: String,
+    prefixes: EmbeddingPrefixes,
+}
+
+/// A search hit tagged with the brain it came from
+#[derive(Debug, Clone)]
+pub struct BrainHit {
+    pub brain: String,
+    pub fragment_id: String,
+    pub content: String,
+    pub score: f64,
+    /// Score min-max normalized within the ranking it came from, used to merge rankings
+    pub normalized_score: f64,
+    /// Where the fragment came from within its brain, for citing it
+    pub source: FragmentSource,
+    /// Dataset version of its brain when it was searched
+    pub version: u64,
+}
+
+/// A whole document's text, read for questions answered over the entire corpus
+#[derive(Debug, Clone)]
+pub struct CorpusDocument {
+    pub brain: String,
+    pub document_id: String,
+    pub file_path: String,
+    /// Its fragments, in the order their text is joined
+    pub fragment_ids: Vec<String>,
+    /// Dataset version of its brain when it was read
+    pub version: u64,
+    pub text: String,
+}
+
+/// Result of a multi-brain search
+pub struct BrainSearch {
+    pub hits: Vec<BrainHit>,
+    /// Brain selected by routing and its centroid similarity, when routing was used
+    pub routed_to: Option<(String, f64)>,
+}
+
+/// A set of brains queried through a single front end
+pub struct BrainSet {
+    brains: Vec<Brain>,
+    /// Embedding prefixes shared by every brain
+    prefixes: EmbeddingPrefixes,
+    /// Brain index and id of the document searches are restricted to
+    focus: Option<(usize, String)>,
+    /// Embed queries for collections with a model of their own, by model
+    collection_embedders: HashMap<String, EmbeddingManager>,
+}
+
+impl BrainSet {
+    /// Open every database, checking they were all indexed with `embedding_model` (or
+    /// upgraded from it, in which case their stale vectors are searched).
+    ///
+    /// When `backend` is `None` each database's backend is inferred from its extension.
+    pub async fn open(
+        paths: &[PathBuf],
+        backend: Option<StorageBackend>,
+        embedding_model: &str,
+        mode: RoutingMode,
+    ) -> Result<Self> {
+        if paths.is_empty() {
+            anyhow::bail!("At least one database is required");
+        }
+
+        let mut brains = Vec::new();
+        let mut prefixes: Option<EmbeddingPrefixes> = None;
+        let mut metric: Option<SimilarityMetric> = None;
+        for path in paths {
+            if !remote_storage::is_remote(path) && !path.exists() {
+                anyhow::bail!("Database file does not exist: {}", path.display());
+            }
+
+            let backend = backend.clone().unwrap_or_else(|| StorageBackend::from_path(path));
+            let mut storage = create_storage(&backend, path).await
+                .with_context(|| format!("Failed to open database {}", path.display()))?;
+
+            let space = storage::query_space(&mut *storage, embedding_model).await
+                .with_context(|| format!("Cannot search {}", path.display()))?;
+            if let Some(warning) = &space.warning {
+                warn!("{}: {}", path.display(), warning);
+            }
+
+            // A query is embedded once for every brain, so they must all expect the same prefix
+            let brain_prefixes = storage::embedding_prefixes(&mut *storage).await?;
+            match &prefixes {
+                Some(first) if *first != brain_prefixes => anyhow::bail!(
+                    "{} expects query prefix {:?} but {} expects {:?}",
+                    path.display(), brain_prefixes.query, paths[0].display(), first.query
+                ),
+                Some(_) => {}
+                None => prefixes = Some(brain_prefixes),
+            }
+
+            // Similarities by different metrics don't rank the same way, even normalized
+            let brain_metric = storage::similarity_metric(&mut *storage).await?;
+            match metric {
+                Some(first) if first != brain_metric => anyhow::bail!(
+                    "{} scores by {} similarity but {} by {}; federated brains must share a metric",
+                    path.display(), brain_metric.name(), paths[0].display(), first.name()
+                ),
+                Some(_) => {}
+                None => metric = Some(brain_metric),
+            }
+
+            // Centroids are only needed to pick a brain when routing
+            let centroid = if mode == RoutingMode::Route && paths.len() > 1 {
+                let centroid = compute_centroid(&mut *storage).await?;
+                if centroid.is_none() {
+                    warn!("{} has no embeddings yet and will never be routed to", path.display());
+                }
+                centroid
+            } else {
+                None
+            };
+
+            let preset = presets::recorded_preset(&mut *storage).await?;
+            let booster = presets::recorded_booster(&mut *storage).await?;
+            let labelled = annotations::ranking_boost(&mut *storage).await?;
+
+            let mut spaces = Vec::new();
+            for (collection, model) in storage.collection_models() {
+                let Some(space) = storage.collection_space(&collection) else {
+                    continue;
+                };
+                let prefixes = storage::embedding_prefixes(space).await?;
+                info!("{}: collection {} is searched with {}", path.display(), collection, model);
+                spaces.push(CollectionSpace { collection, model, prefixes });
+            }
+
+            let summaries = match summary_tree::load(&mut *storage).await? {
+                Some(tree) if tree.model != embedding_model => {
+                    warn!("{}: its summary tree was built with {} and isn't searched", path.display(), tree.model);
+                    None
+                }
+                Some(tree) => {
+                    if tree.data_version != storage::data_version(&mut *storage).await? {
+                        warn!("{}: its summary tree predates the latest changes; run `portable-brains summarize` to rebuild it", path.display());
+                    }
+                    info!("{}: {} summaries in {} levels are searched", path.display(), tree.nodes.len(), tree.levels());
+                    Some(tree)
+                }
+                None => None,
+            };
+
+            brains.push(Brain {
+                name: brain_name(path),
+                path: path.clone(),
+                storage,
+                centroid,
+                stale: space.stale,
+                preset,
+                booster,
+                labelled,
+                spaces,
+                summaries,
+                metric: brain_metric,
+            });
+        }
+
+        info!("Opened {} brain(s)", brains.len());
+        Ok(Self { brains, prefixes: prefixes.unwrap_or_default(), focus: None, collection_embedders: HashMap::new() })
+    }
+
+    /// Models that collections of the brains are embedded with instead of their brain's.
+    /// Those collections are only searched once an embedder for their model is added.
+    pub fn collection_models(&self) -> Vec<String> {
+        let mut models: Vec<String> = self.brains.iter()
+            .flat_map(|brain| brain.spaces.iter().map(|space| space.model.clone()))
+            .collect();
+        models.sort();
+        models.dedup();
+        models
+    }
+
+    /// Embed queries for the collections embedded with `model` with `embedder`
+    pub fn add_collection_embedder(&mut self, model: &str, embedder: EmbeddingManager) {
+        self.collection_embedders.insert(model.to_string(), embedder);
+    }
+
+    pub fn len(&self) -> usize {
+        self.brains.len()
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        self.brains.iter().map(|b| b.name.clone()).collect()
+    }
+
+    /// Id of the document searches are restricted to, if any
+    pub fn focused_document(&self) -> Option<&str> {
+        self.focus.as_ref().map(|(_, document)| document.as_str())
+    }
+
+    /// The embedding prefixes of the brains; queries must be embedded with the query prefix
+    pub fn prefixes(&self) -> &EmbeddingPrefixes {
+        &self.prefixes
+    }
+
+    /// The preset every brain was indexed with, if they agree on one
+    pub fn preset(&self) -> Option<Preset> {
+        let first = self.brains.first()?.preset;
+        self.brains.iter().all(|b| b.preset == first).then_some(first).flatten()
+    }
+
+    /// Check queries embedded as `dimension`-long vectors by `model` can be compared against
+    /// every brain's vectors, naming the first brain that stores vectors of another length.
+    /// Brains searched through their stale vectors are left out, as the recorded dimension is
+    /// the new model's.
+    pub async fn verify_dimension(&mut self, model: &str, dimension: usize) -> Result<()> {
+        for brain in self.brains.iter_mut().filter(|brain| !brain.stale) {
+            storage::check_dimension(&mut *brain.storage, model, dimension).await
+                .with_context(|| format!("Cannot search {}", brain.path.display()))?;
+        }
+        Ok(())
+    }
+
+    /// Restrict searches to one document, found by id, file name or path in any brain, or
+    /// lift the restriction with `None`. Returns the brain and document focused on.
+    pub async fn focus(&mut self, name_or_id: Option<&str>) -> Result<Option<(String, DocumentSummary)>> {
+        let Some(name_or_id) = name_or_id else {
+            self.focus = None;
+            return Ok(None);
+        };
+
+        let mut found = Vec::new();
+        let mut errors = Vec::new();
+        for (index, brain) in self.brains.iter_mut().enumerate() {
+            match storage::find_document(&mut *brain.storage, name_or_id).await {
+                Ok(document) => found.push((index, document)),
+                Err(e) => errors.push(e),
+            }
+        }
+
+        match found.len() {
+            // With a single brain its own error says why, e.g. which documents share the name
+            0 if errors.len() == 1 => Err(errors.remove(0)),
+            0 => anyhow::bail!("No document with id or file name '{}' in any brain", name_or_id),
+            1 => {
+                let (index, document) = found.remove(0);
+                self.focus = Some((index, document.id.clone()));
+                Ok(Some((self.brains[index].name.clone(), document)))
+            }
+            _ => anyhow::bail!(
+                "'{}' is in several brains: {}; use its id instead",
+                name_or_id,
+                found.iter().map(|(index, _)| self.brains[*index].name.as_str()).collect::<Vec<_>>().join(", ")
+            ),
+        }
+    }
+
+    /// The text of every searchable document in every brain, or only the focused document
+    /// while focused on one. Tombstoned documents are left out, as they are from searches.
+    pub async fn corpus(&mut self) -> Result<Vec<CorpusDocument>> {
+        let mut corpus = Vec::new();
+        for (index, brain) in self.brains.iter_mut().enumerate() {
+            let focused = match &self.focus {
+                Some((focus, _)) if *focus != index => continue,
+                Some((_, document)) => Some(document),
+                None => None,
+            };
+            let version = storage::data_version(&mut *brain.storage).await?;
+            let documents = brain.storage.list_documents().await
+                .with_context(|| format!("Failed to list documents in {}", brain.path.display()))?;
+            for document in documents {
+                if document.tombstoned || focused.is_some_and(|id| *id != document.id) {
+                    continue;
+                }
+                let fragments = brain.storage.get_fragment_records(&document.id).await?;
+                if fragments.is_empty() {
+                    continue;
+                }
+                corpus.push(CorpusDocument {
+                    brain: brain.name.clone(),
+                    document_id: document.id,
+                    file_path: document.file_path,
+                    fragment_ids: fragments.iter().map(|fragment| fragment.id.clone()).collect(),
+                    version,
+                    text: fragments.iter().map(|fragment| fragment.content.as_str()).collect::<Vec<_>>().join("\n\n"),
+                });
+            }
+        }
+        Ok(corpus)
+    }
+
+    /// The terms questions are completed with, from every brain's last index run
+    pub async fn completions(&mut self) -> Result<PrefixIndex> {
+        let mut terms = Vec::new();
+        for brain in &mut self.brains {
+            terms.extend(autocomplete::stored_terms(&mut *brain.storage).await
+                .with_context(|| format!("Failed to read query completions from {}", brain.path.display()))?);
+        }
+        Ok(PrefixIndex::new(terms))
+    }
+
+    /// Write the original of the document `hit` was retrieved from into `dir`, returning
+    /// the file written
+    pub async fn extract_source(&mut self, hit: &BrainHit, dir: &Path) -> Result<PathBuf> {
+        let brain = self.brains.iter_mut()
+            .find(|brain| brain.name == hit.brain)
+            .ok_or_else(|| anyhow::anyhow!("No brain named {}", hit.brain))?;
+        let document = brain.storage.list_documents().await?
+            .into_iter()
+            .find(|document| document.file_path == hit.source.file_path)
+            .ok_or_else(|| anyhow::anyhow!("{} is no longer in {}", hit.source.file_path, brain.path.display()))?;
+        storage::extract_document(&mut *brain.storage, &document.id, dir).await
+    }
+
+    /// The stored vectors of `hits`' fragments by fragment id, read from the brain each came
+    /// from, for comparing the passages with each other
+    pub async fn hit_vectors(&mut self, hits: &[BrainHit]) -> Result<HashMap<String, Vec<f64>>> {
+        let mut vectors = HashMap::new();
+        for brain in self.brains.iter_mut() {
+            let ids: Vec<String> = hits.iter()
+                .filter(|hit| hit.brain == brain.name)
+                .map(|hit| hit.fragment_id.clone())
+                .collect();
+            if ids.is_empty() {
+                continue;
+            }
+            let found = brain.storage.get_fragment_vectors(&ids).await
+                .with_context(|| format!("Failed to read vectors from {}", brain.path.display()))?;
+            vectors.extend(found);
+        }
+        Ok(vectors)
+    }
+
+    /// Record `author`'s thumbs up or down on each of `hits` in the brain it came from, so
+    /// later searches rank those fragments up or down. Returns the number recorded.
+    pub async fn record_feedback(&mut self, hits: &[BrainHit], label: AnnotationLabel, author: &str) -> Result<usize> {
+        let mut recorded = 0;
+        for brain in self.brains.iter_mut() {
+            let mine: Vec<&BrainHit> = hits.iter().filter(|hit| hit.brain == brain.name).collect();
+            if mine.is_empty() {
+                continue;
+            }
+            for hit in mine {
+                brain.storage.add_annotation(&Annotation::new(&hit.fragment_id, Some(label), None, author)).await
+                    .with_context(|| format!("Failed to record feedback in {}", brain.path.display()))?;
+                recorded += 1;
+            }
+            brain.labelled = annotations::ranking_boost(&mut *brain.storage).await?;
+        }
+        Ok(recorded)
+    }
+
+    /// Search the configured brains according to `mode`, ranking each brain's fragments by
+    /// `search_mode`. Routing always compares `query_embedding` against the centroids. While
+    /// focused on a document, only its brain is searched and only its fragments are returned.
+    /// Collections with a model of their own are searched by similarity to `query` embedded
+    /// with that model, and ranked on their own like another brain.
+    pub async fn search(
+        &mut self,
+        query: &str,
+        query_embedding: &[f64],
+        limit: usize,
+        mode: RoutingMode,
+        search_mode: SearchMode,
+        filter: &SearchFilter,
+    ) -> Result<BrainSearch> {
+        let mut routed_to = None;
+        let filter = match &self.focus {
+            Some((_, document)) => SearchFilter { document: Some(document.clone()), ..filter.clone() },
+            None => filter.clone(),
+        };
+        let filter = &filter;
+
+        let selected: Vec<usize> = if let Some((index, _)) = self.focus {
+            vec![index]
+        } else if mode == RoutingMode::Route && self.brains.len() > 1 {
+            let best = self.brains.iter()
+                .enumerate()
+                .filter_map(|(i, brain)| {
+                    brain.centroid.as_ref().map(|c| (i, cosine_similarity(query_embedding, c)))
+                })
+                .max_by(|a, b| a.1.total_cmp(&b.1));
+
+            match best {
+                Some((index, similarity)) => {
+                    routed_to = Some((self.brains[index].name.clone(), similarity));
+                    vec![index]
+                }
+                // No centroids available: fall back to querying everything
+                None => (0..self.brains.len()).collect(),
+            }
+        } else {
+            (0..self.brains.len()).collect()
+        };
+
+        // Collections with a model of their own need the query embedded with it; keyword
+        // search covers them along with the rest of their brain
+        let mut space_queries: HashMap<(usize, String), Vec<f64>> = HashMap::new();
+        if search_mode != SearchMode::Keyword {
+            for &index in &selected {
+                for space in &self.brains[index].spaces {
+                    let Some(embedder) = self.collection_embedders.get_mut(&space.model) else {
+                        warn!("{}: collection {} needs queries embedded with {} and isn't searched",
+                              self.brains[index].path.display(), space.collection, space.model);
+                        continue;
+                    };
+                    let embedding = embedder.generate_embedding(&space.prefixes.query(query)).await
+                        .with_context(|| format!("Failed to embed the query for collection {}", space.collection))?;
+                    space_queries.insert((index, space.collection.clone()), embedding);
+                }
+            }
+        }
+        let space_queries = &space_queries;
+        // Summaries span documents, so they can't be narrowed by filters
+        let summarized = search_mode != SearchMode::Keyword && filter.describe().is_empty();
+
+        // Query the selected brains concurrently
+        let searches = self.brains.iter_mut()
+            .enumerate()
+            .filter(|(i, _)| selected.contains(i))
+            .map(|(index, brain)| async move {
+                let filter = SearchFilter { stale: brain.stale, ..filter.clone() };
+                let version = storage::data_version(&mut *brain.storage).await?;
+                let results = match search_mode {
+                    SearchMode::Vector => {
+                        // Boosts and labels can lift candidates from below the cutoff, so fetch a wider pool
+                        let reranked = brain.booster.is_some() || brain.labelled.is_some();
+                        let pool = if reranked { (limit * 3).max(limit + 10) } else { limit };
+                        brain.storage.search_similar(query_embedding, pool, &filter).await.map(|results| {
+                            let results = match &brain.booster {
+                                Some(booster) => booster.rerank(results).into_iter().map(|(fragment, _)| fragment).collect(),
+                                None => results,
+                            };
+                            let results = match &brain.labelled {
+                                Some(weights) => annotations::rerank(results, weights).into_iter().map(|(fragment, _)| fragment).collect(),
+                                None => results,
+                            };
+                            results.into_iter().take(limit).collect()
+                        })
+                    }
+                    SearchMode::Hybrid => brain.storage.search_hybrid(query, query_embedding, limit, &filter).await
+                        .map(|hits| hits.into_iter().map(|hit| hit.fragment).collect()),
+                    SearchMode::Keyword => brain.storage.search_keyword(query, limit, &filter).await,
+                };
+                let results = results.with_context(|| format!("Failed to search {}", brain.path.display()))?;
+
+                let mut rankings = vec![results];
+                for space in &brain.spaces {
+                    let Some(embedding) = space_queries.get(&(index, space.collection.clone())) else {
+                        continue;
+                    };
+                    let filter = SearchFilter { stale: false, ..filter.clone() };
+                    let results = brain.storage.search_collection(&space.collection, embedding, limit, &filter).await
+                        .with_context(|| format!("Failed to search {}", brain.path.display()))?;
+                    rankings.push(results);
+                }
+
+                // Hit counts only steer re-embedding order, so a failure isn't fatal
+                let ids: Vec<String> = rankings.iter().flatten().map(|hit| hit.fragment_id.clone()).collect();
+                if let Err(e) = brain.storage.record_fragment_hits(&ids).await {
+                    warn!("Failed to record hits in {}: {}", brain.path.display(), e);
+                }
+
+                // Ranked on their own like a collection, taking some of the brain's share
+                if let Some(tree) = brain.summaries.as_ref().filter(|_| summarized) {
+                    rankings.push(tree.search(query_embedding, (limit / SUMMARY_SHARE).max(1), brain.metric));
+                }
+                Ok::<_, anyhow::Error>((brain.name.clone(), version, rankings))
+            });
+
+        let mut hits = Vec::new();
+        for outcome in join_all(searches).await {
+            let (name, version, rankings) = outcome?;
+            for results in rankings {
+                let scores: Vec<f64> = results.iter().map(|hit| hit.score).collect();
+                let normalized = normalize_scores(&scores);
+
+                hits.extend(results.into_iter().zip(normalized).map(|(hit, normalized_score)| BrainHit {
+                    brain: name.clone(),
+                    fragment_id: hit.fragment_id,
+                    content: hit.content,
+                    score: hit.score,
+                    normalized_score,
+                    source: hit.source,
+                    version,
+                }));
+            }
+        }
+
+        // Hybrid rankings hold fusion scores while summaries and collections hold similarities,
+        // so rank on the normalized score and break ties with the raw score
+        hits.sort_by(|a, b| {
+            b.normalized_score.total_cmp(&a.normalized_score)
+                .then(b.score.total_cmp(&a.score))
+        });
+        // A hybrid search can find a fragment of such a collection by keyword too
+        let mut seen = HashSet::new();
+        hits.retain(|hit| seen.insert((hit.brain.clone(), hit.fragment_id.clone())));
+        hits.truncate(limit);
+
+        Ok(BrainSearch { hits, routed_to })
+    }
+}
+
+/// Min-max normalize scores to [0, 1]; a single or uniform result set maps to 1.0
+pub fn normalize_scores(scores: &[f64]) -> Vec<f64> {
+    let min = scores.iter().cloned().fold(f64::INFINITY, f64::min);
+    let max = scores.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+    let span = max - min;
+
+    scores.iter()
+        .map(|score| if span > f64::EPSILON { (score - min) / span } else { 1.0 })
+        .collect()
+}
+
+fn brain_name(path: &Path) -> String {
+    path.file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_else(|| path.display().to_string())
+}
+
+/// Average of a sample of a brain's embeddings, used as its routing signature
+async fn compute_centroid(storage: &mut dyn Storage) -> Result<Option<Vec<f64>>> {
+    let sample = storage.get_fragment_embeddings(CENTROID_SAMPLE_SIZE).await?;
+    let Some((_, _, first)) = sample.first() else {
+        return Ok(None);
+    };
+
+    let mut centroid = vec![0.0; first.len()];
+    let mut count = 0.0;
+    for (_, _, embedding) in &sample {
+        if embedding.len() != centroid.len() {
+            continue;
+        }
+        for (c, v) in centroid.iter_mut().zip(embedding) {
+            *c += v;
+        }
+        count += 1.0;
+    }
+    centroid.iter_mut().for_each(|c| *c /= count);
+
+    Ok(Some(centroid))
+}
+
+pub fn cosine_similarity(a: &[f64], b: &[f64]) -> f64 {
+    if a.len() != b.len() {
+        return 0.0;
+    }
+    let dot: f64 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm_a: f64 = a.iter().map(|x| x * x).sum::<f64>().sqrt();
+    let norm_b: f64 = b.iter().map(|x| x * x).sum::<f64>().sqrt();
+    if norm_a == 0.0 || norm_b == 0.0 {
+        0.0
+    } else {
+        dot / (norm_a * norm_b)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_scores() {
+        let normalized = normalize_scores(&[0.2, 0.6, 0.4]);
+        assert!((normalized[0] - 0.0).abs() < 1e-9);
+        assert!((normalized[1] - 1.0).abs() < 1e-9);
+        assert!((normalized[2] - 0.5).abs() < 1e-9);
+
+        assert_eq!(normalize_scores(&[0.7]), vec![1.0]);
+    }
+}
@@ -0,0 +1,147 @@
+use anyhow::{Context, Result};
+use std::path::Path;
+
+use crate::brains::cosine_similarity;
+use crate::embedding_manager::EmbeddingManager;
+
+/// Characters of a document's text embedded to classify it
+const EXCERPT_CHARS: usize = 2000;
+
+/// A document class and the description it is matched against
+#[derive(Debug, Clone)]
+pub struct ClassLabel {
+    pub name: String,
+    pub description: String,
+}
+
+/// Category assigned to a document and its similarity to the label description
+#[derive(Debug, Clone, PartialEq)]
+pub struct Classification {
+    pub category: String,
+    pub score: f64,
+}
+
+pub fn default_labels() -> Vec<ClassLabel> {
+    [
+        ("invoice", "An invoice or bill listing items, quantities, prices, totals, taxes and payment terms"),
+        ("contract", "A legal contract or agreement with parties, clauses, obligations, terms and signatures"),
+        ("report", "A report presenting findings, analysis, results and conclusions on a topic"),
+        ("email", "An email message with sender, recipients, subject line, greeting and sign-off"),
+        ("presentation", "Presentation slides with short headings and bullet points"),
+        ("manual", "A manual or guide with step-by-step instructions and procedures"),
+        ("article", "An article, paper or essay discussing a subject in prose"),
+    ]
+    .into_iter()
+    .map(|(name, description)| ClassLabel {
+        name: name.to_string(),
+        description: description.to_string(),
+    })
+    .collect()
+}
+
+/// Load labels from a file with one `name: description` per line (`#` starts a comment)
+pub fn load_labels(path: &Path) -> Result<Vec<ClassLabel>> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read labels file {}", path.display()))?;
+    parse_labels(&text)
+}
+
+fn parse_labels(text: &str) -> Result<Vec<ClassLabel>> {
+    let mut labels = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (name, description) = line.split_once(':')
+            .ok_or_else(|| anyhow::anyhow!("Line {}: expected `name: description`", number + 1))?;
+        labels.push(ClassLabel {
+            name: name.trim().to_lowercase(),
+            description: description.trim().to_string(),
+        });
+    }
+
+    if labels.is_empty() {
+        anyhow::bail!("No labels defined");
+    }
+    Ok(labels)
+}
+
+/// Zero-shot classifier comparing a document excerpt's embedding with embedded label descriptions
+pub struct ZeroShotClassifier {
+    embedding_manager: EmbeddingManager,
+    labels: Vec<(String, Vec<f64>)>,
+}
+
+impl ZeroShotClassifier {
+    pub async fn new(labels: Vec<ClassLabel>, mut embedding_manager: EmbeddingManager) -> Result<Self> {
+        let descriptions: Vec<String> = labels.iter().map(|l| l.description.clone()).collect();
+        let embeddings = embedding_manager.generate_embeddings_batch(&descriptions).await
+            .context("Failed to embed label descriptions")?;
+
+        Ok(Self {
+            embedding_manager,
+            labels: labels.into_iter().map(|l| l.name).zip(embeddings).collect(),
+        })
+    }
+
+    /// Classify a document from its extracted fragments
+    pub async fn classify<'a>(&mut self, fragments: impl IntoIterator<Item = &'a str>) -> Result<Option<Classification>> {
+        let excerpt: String = fragments.into_iter()
+            .flat_map(|fragment| fragment.chars().chain(std::iter::once('\n')))
+            .take(EXCERPT_CHARS)
+            .collect();
+        if excerpt.trim().is_empty() {
+            return Ok(None);
+        }
+
+        let embedding = self.embedding_manager.generate_embedding(&excerpt).await
+            .context("Failed to embed document excerpt")?;
+        Ok(best_label(&self.labels, &embedding))
+    }
+
+    /// Borrow the embedding manager, e.g. to run the embed phase between classifications
+    pub fn embedding_manager(&mut self) -> &mut EmbeddingManager {
+        &mut self.embedding_manager
+    }
+
+    /// Hand back the embedding manager, e.g. to reuse it for the embed phase
+    pub fn into_embedding_manager(self) -> EmbeddingManager {
+        self.embedding_manager
+    }
+}
+
+fn best_label(labels: &[(String, Vec<f64>)], embedding: &[f64]) -> Option<Classification> {
+    labels.iter()
+        .map(|(name, label_embedding)| Classification {
+            category: name.clone(),
+            score: cosine_similarity(embedding, label_embedding),
+        })
+        .max_by(|a, b| a.score.total_cmp(&b.score))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_labels() {
+        let labels = parse_labels("# classes\nInvoice: a bill\n\nmemo: internal note: short\n").unwrap();
+
+        assert_eq!(labels.len(), 2);
+        assert_eq!(labels[0].name, "invoice");
+        assert_eq!(labels[1].description, "internal note: short");
+        assert!(parse_labels("no separator").is_err());
+    }
+
+    #[test]
+    fn test_best_label() {
+        let labels = vec![
+            ("invoice".to_string(), vec![1.0, 0.0]),
+            ("email".to_string(), vec![0.0, 1.0]),
+        ];
+
+        let result = best_label(&labels, &[0.2, 0.9]).unwrap();
+        assert_eq!(result.category, "email");
+    }
+}
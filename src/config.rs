@@ -0,0 +1,708 @@
+use anyhow::{Context, Result};
+use globset::{Glob, GlobBuilder, GlobMatcher};
+use regex::Regex;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::sync::watch;
+
+use crate::document_processor::{CleanupRule, DocumentFormat, FormatCleanup, TextCleanup};
+use crate::presets::Preset;
+use crate::profiles::{self, Profile};
+use crate::retrieval_pipeline::{RetrievalPipeline, Stage};
+
+/// Settings loaded from a `portable-brains.toml` file
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Collection for documents no routing rule assigns one to
+    #[serde(default)]
+    pub default_collection: Option<String>,
+
+    /// Collection routing rules, checked in order
+    #[serde(default)]
+    pub rules: Vec<RoutingRule>,
+
+    /// Settings of individual collections, by name
+    #[serde(default)]
+    pub collections: BTreeMap<String, CollectionConfig>,
+
+    /// Content scanning applied to originals before they are indexed
+    #[serde(default)]
+    pub scan: ScanConfig,
+
+    /// Cleanup applied to extracted text before it is chunked
+    #[serde(default)]
+    pub cleanup: CleanupConfig,
+
+    /// REST server settings
+    #[serde(default)]
+    pub serve: ServeConfig,
+
+    /// Teams sharing one REST server, by tenant id; each sees only its own collections
+    #[serde(default)]
+    pub tenants: BTreeMap<String, TenantConfig>,
+
+    /// Retrieval pipeline `search` runs instead of its search mode
+    #[serde(default)]
+    pub retrieval: RetrievalConfig,
+
+    /// Profile whose flag defaults apply when `--profile` isn't given
+    #[serde(default)]
+    pub profile: Option<String>,
+
+    /// Named sets of flag defaults, applied before the command line is parsed
+    #[serde(default)]
+    pub profiles: BTreeMap<String, Profile>,
+}
+
+/// The `[retrieval]` section of the config file
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RetrievalConfig {
+    /// Stages run in order, e.g. dense and bm25 retrieval fused by rrf, then mmr
+    #[serde(default)]
+    pub stages: Vec<Stage>,
+}
+
+impl RetrievalConfig {
+    /// The configured pipeline, or `None` when no stages are given
+    pub fn pipeline(&self) -> Result<Option<RetrievalPipeline>> {
+        if self.stages.is_empty() {
+            return Ok(None);
+        }
+        RetrievalPipeline::new(self.stages.clone()).map(Some)
+    }
+}
+
+/// REST server settings, the `[serve]` section of the config file
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ServeConfig {
+    /// Bearer tokens accepted in addition to `--token`; edit and reload to rotate them
+    #[serde(default)]
+    pub tokens: Vec<String>,
+}
+
+/// One team's access to a shared REST server, a `[tenants.<id>]` section of the config file
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TenantConfig {
+    /// Bearer tokens that act as this tenant
+    pub tokens: Vec<String>,
+    /// Collections the tenant can search and read; documents it ingests go into the first
+    /// unless routing assigns another of them
+    pub collections: Vec<String>,
+}
+
+/// One collection's settings, a `[collections.<name>]` section of the config file
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CollectionConfig {
+    /// Model the collection is embedded and queried with instead of the database's, such as a
+    /// code model for a repository. Needs a brain sharded by collection, where the name is
+    /// that of the documents' parent directory.
+    #[serde(default)]
+    pub embedding_model: Option<String>,
+}
+
+/// Assigns a collection and/or tags to documents matching every condition given
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RoutingRule {
+    /// Path glob such as `**/invoices/**`
+    #[serde(default)]
+    pub glob: Option<String>,
+    /// MIME type, optionally with a wildcard subtype (`text/*`)
+    #[serde(default)]
+    pub mime: Option<String>,
+    /// Category assigned by the classifier (`index --classify`)
+    #[serde(default)]
+    pub category: Option<String>,
+
+    #[serde(default)]
+    pub collection: Option<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+/// Content scanning settings, the `[scan]` section of the config file
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ScanConfig {
+    /// Document types whose content (not just extension) may be indexed:
+    /// pdf, docx, pptx, xlsx, html, text, md, epub, csv, tsv, eml
+    #[serde(default)]
+    pub allow_types: Option<Vec<String>>,
+    /// External scanner run on each file, with `{}` replaced by its path, e.g.
+    /// `yara -w rules.yar {}` or `clamscan --no-summary --infected {}`
+    #[serde(default)]
+    pub command: Option<String>,
+    /// Where suspicious files are copied (defaults to `<database>.quarantine`)
+    #[serde(default)]
+    pub quarantine_dir: Option<PathBuf>,
+}
+
+/// Text cleanup settings, the `[cleanup]` section of the config file
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CleanupConfig {
+    /// Collapse runs of whitespace into single spaces
+    #[serde(default = "default_collapse_whitespace")]
+    pub collapse_whitespace: bool,
+    /// Characters deleted from extracted text, as regex character classes such as
+    /// `[\x{AD}\x{200B}]` or `\p{Co}`
+    #[serde(default)]
+    pub remove: Vec<String>,
+    /// Regex replacements, applied in order after `remove`
+    #[serde(default)]
+    pub rules: Vec<CleanupRuleConfig>,
+    /// Per-format overrides, keyed by pdf, docx, pptx, xlsx, html, text, md, epub, csv, tsv or eml
+    #[serde(default)]
+    pub format: HashMap<String, FormatCleanupConfig>,
+}
+
+fn default_collapse_whitespace() -> bool {
+    true
+}
+
+impl Default for CleanupConfig {
+    fn default() -> Self {
+        Self {
+            collapse_whitespace: true,
+            remove: Vec::new(),
+            rules: Vec::new(),
+            format: HashMap::new(),
+        }
+    }
+}
+
+/// A `[[cleanup.rules]]` entry
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CleanupRuleConfig {
+    pub pattern: String,
+    /// Replacement text; `$1` or `${name}` insert capture groups
+    #[serde(default)]
+    pub replace: String,
+    /// Only apply to these formats (all formats when omitted)
+    #[serde(default)]
+    pub formats: Vec<String>,
+}
+
+/// A `[cleanup.format.<name>]` table
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FormatCleanupConfig {
+    #[serde(default)]
+    pub collapse_whitespace: Option<bool>,
+    /// Set to false to skip `remove` and the rules for this format
+    #[serde(default = "default_apply_rules")]
+    pub rules: bool,
+}
+
+fn default_apply_rules() -> bool {
+    true
+}
+
+impl CleanupConfig {
+    /// A preset's built-in cleanup rules
+    pub fn for_preset(preset: Preset) -> Result<Self> {
+        toml::from_str(preset.cleanup_toml())
+            .with_context(|| format!("Invalid cleanup settings in the {} preset", preset.name()))
+    }
+
+    /// Add `other`'s rules after this config's. Whitespace is only collapsed when both
+    /// agree, and `other`'s per-format overrides win.
+    pub fn merged(mut self, other: &CleanupConfig) -> Self {
+        self.collapse_whitespace &= other.collapse_whitespace;
+        self.remove.extend(other.remove.iter().cloned());
+        self.rules.extend(other.rules.iter().cloned());
+        self.format.extend(other.format.iter().map(|(name, overrides)| (name.clone(), overrides.clone())));
+        self
+    }
+
+    /// Compile the configured patterns, reporting the first invalid one
+    pub fn compile(&self) -> Result<TextCleanup> {
+        let mut rules = Vec::new();
+        for (i, class) in self.remove.iter().enumerate() {
+            let regex = Regex::new(class)
+                .with_context(|| format!("Invalid character class in cleanup.remove entry {}", i + 1))?;
+            rules.push(CleanupRule { regex, replace: String::new(), formats: Vec::new() });
+        }
+        for (i, rule) in self.rules.iter().enumerate() {
+            let regex = Regex::new(&rule.pattern)
+                .with_context(|| format!("Invalid pattern in cleanup rule {}", i + 1))?;
+            let formats = rule.formats.iter()
+                .map(|name| parse_format(name).with_context(|| format!("In cleanup rule {}", i + 1)))
+                .collect::<Result<_>>()?;
+            rules.push(CleanupRule { regex, replace: rule.replace.clone(), formats });
+        }
+
+        let mut formats = HashMap::new();
+        for (name, overrides) in &self.format {
+            formats.insert(parse_format(name)?, FormatCleanup {
+                collapse_whitespace: overrides.collapse_whitespace,
+                skip_rules: !overrides.rules,
+            });
+        }
+
+        Ok(TextCleanup {
+            rules,
+            collapse_whitespace: self.collapse_whitespace,
+            formats,
+        })
+    }
+}
+
+fn parse_format(name: &str) -> Result<DocumentFormat> {
+    DocumentFormat::from_extension(name)
+        .ok_or_else(|| anyhow::anyhow!("Unknown document format '{}' (expected pdf, docx, pptx, xlsx, html, text, md, epub, csv, tsv or eml)", name))
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config file {}", path.display()))?;
+        toml::from_str(&text)
+            .with_context(|| format!("Invalid config file {}", path.display()))
+    }
+
+    /// Load a config file and check its rules compile, so a bad edit is caught before it
+    /// replaces a working config
+    pub fn load_checked(path: &Path) -> Result<Self> {
+        let config = Self::load(path)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Check the settings a file can't express wrongly through its shape alone
+    fn validate(&self) -> Result<()> {
+        CollectionRouter::new(self)?;
+        self.cleanup.compile()?;
+        self.retrieval.pipeline()?;
+        profiles::select(&self.profiles, None, self.profile.as_deref())?;
+        if self.serve.tokens.iter().any(|token| token.trim().is_empty()) {
+            anyhow::bail!("[serve] tokens must not be empty");
+        }
+
+        if let Some(name) = self.collections.iter()
+            .find(|(_, collection)| collection.embedding_model.as_deref().is_some_and(|model| model.trim().is_empty()))
+            .map(|(name, _)| name) {
+            anyhow::bail!("[collections.{}] embedding_model must not be empty", name);
+        }
+
+        let mut seen: HashSet<&str> = self.serve.tokens.iter().map(String::as_str).collect();
+        for (id, tenant) in &self.tenants {
+            if tenant.tokens.is_empty() || tenant.tokens.iter().any(|token| token.trim().is_empty()) {
+                anyhow::bail!("[tenants.{}] needs at least one token, and tokens must not be empty", id);
+            }
+            if tenant.collections.is_empty() {
+                anyhow::bail!("[tenants.{}] needs at least one collection", id);
+            }
+            // A token shared between tenants would make the caller ambiguous
+            if tenant.tokens.iter().any(|token| !seen.insert(token.as_str())) {
+                anyhow::bail!("[tenants.{}] reuses a token already given to [serve] or another tenant", id);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// How often a watched config file is checked for changes
+const RELOAD_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// A config file that is reloaded when it changes on disk or the process receives SIGHUP.
+///
+/// Each reload that parses and validates is published to subscribers; a broken edit is
+/// reported and the previous config stays in effect.
+pub struct LiveConfig {
+    path: PathBuf,
+    updates: watch::Receiver<Arc<Config>>,
+}
+
+impl LiveConfig {
+    /// Load the config and start watching it. Must be called within a tokio runtime.
+    pub fn watch(path: &Path) -> Result<Self> {
+        let config = Config::load_checked(path)?;
+        let (sender, updates) = watch::channel(Arc::new(config));
+        tokio::spawn(reload_on_change(path.to_path_buf(), sender));
+        Ok(Self { path: path.to_path_buf(), updates })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn current(&self) -> Arc<Config> {
+        self.updates.borrow().clone()
+    }
+
+    /// The reloaded config, if it changed since the last call
+    pub fn changed(&mut self) -> Option<Arc<Config>> {
+        match self.updates.has_changed() {
+            Ok(true) => Some(self.updates.borrow_and_update().clone()),
+            _ => None,
+        }
+    }
+
+    /// A receiver that is notified of every reload
+    pub fn subscribe(&self) -> watch::Receiver<Arc<Config>> {
+        self.updates.clone()
+    }
+}
+
+async fn reload_on_change(path: PathBuf, sender: watch::Sender<Arc<Config>>) {
+    let mut hangup = ReloadSignal::new();
+    let mut modified = modified_time(&path);
+
+    loop {
+        let signalled = tokio::select! {
+            _ = hangup.recv() => true,
+            _ = tokio::time::sleep(RELOAD_POLL_INTERVAL) => false,
+        };
+        let current = modified_time(&path);
+        if !signalled && current == modified {
+            continue;
+        }
+        modified = current;
+
+        match Config::load_checked(&path) {
+            Ok(config) => {
+                println!("🔄 Reloaded {}", path.display());
+                if sender.send(Arc::new(config)).is_err() {
+                    return;
+                }
+            }
+            Err(e) => println!("⚠️  Keeping the previous config; {:#}", e),
+        }
+    }
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+/// SIGHUP where the platform has it; elsewhere only file changes trigger a reload
+struct ReloadSignal {
+    #[cfg(unix)]
+    signal: Option<tokio::signal::unix::Signal>,
+}
+
+impl ReloadSignal {
+    #[cfg(unix)]
+    fn new() -> Self {
+        use tokio::signal::unix::{signal, SignalKind};
+        Self { signal: signal(SignalKind::hangup()).ok() }
+    }
+
+    #[cfg(not(unix))]
+    fn new() -> Self {
+        Self {}
+    }
+
+    #[cfg(unix)]
+    async fn recv(&mut self) {
+        match &mut self.signal {
+            Some(signal) => { signal.recv().await; }
+            None => std::future::pending().await,
+        }
+    }
+
+    #[cfg(not(unix))]
+    async fn recv(&mut self) {
+        std::future::pending().await
+    }
+}
+
+/// Collection and tags assigned to a document at ingest time
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Routing {
+    pub collection: Option<String>,
+    pub tags: Vec<String>,
+}
+
+struct CompiledRule {
+    glob: Option<GlobMatcher>,
+    rule: RoutingRule,
+}
+
+/// Routing rules compiled for matching.
+///
+/// The first matching rule that names a collection decides the collection; tags are
+/// collected from every matching rule.
+pub struct CollectionRouter {
+    default_collection: Option<String>,
+    rules: Vec<CompiledRule>,
+}
+
+impl CollectionRouter {
+    pub fn new(config: &Config) -> Result<Self> {
+        let mut rules = Vec::new();
+        for (i, rule) in config.rules.iter().enumerate() {
+            if rule.glob.is_none() && rule.mime.is_none() && rule.category.is_none() {
+                anyhow::bail!("Routing rule {} has no glob, mime or category condition", i + 1);
+            }
+            if rule.collection.is_none() && rule.tags.is_empty() {
+                anyhow::bail!("Routing rule {} assigns neither a collection nor tags", i + 1);
+            }
+            // Tags are stored as a comma-separated list
+            if let Some(tag) = rule.tags.iter().find(|t| t.contains(',') || t.trim().is_empty()) {
+                anyhow::bail!("Routing rule {} has an invalid tag '{}'", i + 1, tag);
+            }
+
+            let glob = match &rule.glob {
+                Some(pattern) => Some(compile_glob(pattern)
+                    .with_context(|| format!("Invalid glob in routing rule {}", i + 1))?),
+                None => None,
+            };
+            rules.push(CompiledRule { glob, rule: rule.clone() });
+        }
+
+        Ok(Self {
+            default_collection: config.default_collection.clone(),
+            rules,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty() && self.default_collection.is_none()
+    }
+
+    /// Route a document by its path and, if it was classified, its category
+    pub fn route(&self, file_path: &Path, category: Option<&str>) -> Routing {
+        let mime = mime_type(file_path);
+        let mut routing = Routing::default();
+
+        for compiled in &self.rules {
+            let rule = &compiled.rule;
+            let glob_matches = compiled.glob.as_ref().is_none_or(|glob| glob.is_match(file_path));
+            let mime_matches = rule.mime.as_deref().is_none_or(|pattern| mime_matches(pattern, mime));
+            let category_matches = rule.category.as_deref()
+                .is_none_or(|wanted| category.is_some_and(|c| c.eq_ignore_ascii_case(wanted)));
+
+            if !(glob_matches && mime_matches && category_matches) {
+                continue;
+            }
+
+            if routing.collection.is_none() {
+                routing.collection = rule.collection.clone();
+            }
+            for tag in &rule.tags {
+                if !routing.tags.contains(tag) {
+                    routing.tags.push(tag.clone());
+                }
+            }
+        }
+
+        if routing.collection.is_none() {
+            routing.collection = self.default_collection.clone();
+        }
+        routing
+    }
+}
+
+fn compile_glob(pattern: &str) -> Result<GlobMatcher> {
+    // `*` stays within one path component; use `**` to cross directories
+    let glob: Glob = GlobBuilder::new(pattern).literal_separator(true).build()?;
+    Ok(glob.compile_matcher())
+}
+
+/// MIME type of a supported document, from its extension
+pub fn mime_type(path: &Path) -> &'static str {
+    let extension = path.extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| ext.to_lowercase())
+        .unwrap_or_default();
+
+    match extension.as_str() {
+        "pdf" => "application/pdf",
+        "txt" => "text/plain",
+        "html" | "htm" => "text/html",
+        "docx" => "application/vnd.openxmlformats-officedocument.wordprocessingml.document",
+        "pptx" => "application/vnd.openxmlformats-officedocument.presentationml.presentation",
+        "xlsx" => "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet",
+        "md" | "markdown" => "text/markdown",
+        "epub" => "application/epub+zip",
+        "csv" => "text/csv",
+        "tsv" => "text/tab-separated-values",
+        "eml" => "message/rfc822",
+        _ => "application/octet-stream",
+    }
+}
+
+fn mime_matches(pattern: &str, mime: &str) -> bool {
+    match pattern.strip_suffix("/*") {
+        Some(prefix) => mime.split('/').next() == Some(prefix),
+        None => pattern.eq_ignore_ascii_case(mime),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_routing_rules() {
+        let config: Config = toml::from_str(r#"
+            default_collection = "general"
+
+            [[rules]]
+            glob = "**/invoices/**"
+            collection = "finance"
+            tags = ["billing"]
+
+            [[rules]]
+            mime = "application/pdf"
+            tags = ["pdf"]
+
+            [[rules]]
+            category = "contract"
+            collection = "legal"
+        "#).unwrap();
+        let router = CollectionRouter::new(&config).unwrap();
+
+        let routing = router.route(Path::new("/data/invoices/2024/march.pdf"), None);
+        assert_eq!(routing.collection.as_deref(), Some("finance"));
+        assert_eq!(routing.tags, vec!["billing", "pdf"]);
+
+        let routing = router.route(Path::new("/data/misc/nda.docx"), Some("contract"));
+        assert_eq!(routing.collection.as_deref(), Some("legal"));
+        assert!(routing.tags.is_empty());
+
+        let routing = router.route(Path::new("/data/misc/notes.txt"), None);
+        assert_eq!(routing.collection.as_deref(), Some("general"));
+    }
+
+    #[test]
+    fn test_mime_wildcard() {
+        assert!(mime_matches("text/*", "text/html"));
+        assert!(!mime_matches("text/*", "application/pdf"));
+    }
+
+    #[test]
+    fn test_tenants_need_distinct_tokens() {
+        let config: Config = toml::from_str(r#"
+            [serve]
+            tokens = ["admin"]
+
+            [tenants.legal]
+            tokens = ["legal-token"]
+            collections = ["legal", "contracts"]
+
+            [tenants.finance]
+            tokens = ["finance-token"]
+            collections = ["finance"]
+        "#).unwrap();
+        config.validate().unwrap();
+        assert_eq!(config.tenants["legal"].collections, vec!["legal", "contracts"]);
+
+        let shared: Config = toml::from_str(r#"
+            [tenants.legal]
+            tokens = ["same"]
+            collections = ["legal"]
+
+            [tenants.finance]
+            tokens = ["same"]
+            collections = ["finance"]
+        "#).unwrap();
+        assert!(shared.validate().is_err());
+
+        let unscoped: Config = toml::from_str(r#"
+            [tenants.legal]
+            tokens = ["legal-token"]
+            collections = []
+        "#).unwrap();
+        assert!(unscoped.validate().is_err());
+    }
+
+    #[test]
+    fn test_retrieval_stages() {
+        let config: Config = toml::from_str(r#"
+            [[retrieval.stages]]
+            stage = "dense"
+            k = 50
+
+            [[retrieval.stages]]
+            stage = "bm25"
+            k = 50
+
+            [[retrieval.stages]]
+            stage = "rrf"
+
+            [[retrieval.stages]]
+            stage = "mmr"
+            top = 8
+            lambda = 0.5
+        "#).unwrap();
+        config.validate().unwrap();
+        assert_eq!(config.retrieval.pipeline().unwrap().unwrap().describe(), "dense k=50 → bm25 k=50 → rrf → mmr top=8 lambda=0.5");
+        assert!(Config::default().retrieval.pipeline().unwrap().is_none());
+
+        let unfused: Config = toml::from_str(r#"
+            retrieval.stages = [{ stage = "dense", k = 50 }, { stage = "bm25", k = 50 }]
+        "#).unwrap();
+        assert!(unfused.validate().is_err());
+        assert!(toml::from_str::<Config>(r#"retrieval.stages = [{ stage = "colbert" }]"#).is_err());
+    }
+
+    #[test]
+    fn test_cleanup_rules() {
+        let config: Config = toml::from_str(r#"
+            [cleanup]
+            remove = ['[\x{AD}]']
+
+            [[cleanup.rules]]
+            pattern = '(\w)-\n(\w)'
+            replace = '$1$2'
+            formats = ["pdf"]
+
+            [cleanup.format.xlsx]
+            collapse_whitespace = false
+        "#).unwrap();
+        let cleanup = config.cleanup.compile().unwrap();
+        assert_eq!(cleanup.rules.len(), 2);
+        assert_eq!(cleanup.rules[1].formats, vec![DocumentFormat::Pdf]);
+        assert_eq!(cleanup.formats[&DocumentFormat::Xlsx].collapse_whitespace, Some(false));
+
+        let bad: Config = toml::from_str("[[cleanup.rules]]\npattern = '('").unwrap();
+        assert!(bad.cleanup.compile().is_err());
+    }
+
+    #[test]
+    fn test_preset_cleanup_merges_with_config() {
+        for preset in <Preset as clap::ValueEnum>::value_variants() {
+            CleanupConfig::for_preset(*preset).unwrap().compile().unwrap();
+        }
+
+        let config: Config = toml::from_str("[cleanup]\nremove = ['[\\x{200B}]']").unwrap();
+        let cleanup = CleanupConfig::for_preset(Preset::Papers).unwrap().merged(&config.cleanup).compile().unwrap();
+        assert_eq!(cleanup.rules.len(), 3);
+        assert!(cleanup.collapse_whitespace);
+
+        let code = CleanupConfig::for_preset(Preset::Code).unwrap().merged(&config.cleanup);
+        assert!(!code.collapse_whitespace);
+    }
+
+    #[tokio::test]
+    async fn test_live_config_reloads_valid_edits() {
+        let path = std::env::temp_dir().join(format!("pb-live-config-{}.toml", std::process::id()));
+        std::fs::write(&path, "[serve]\ntokens = [\"first\"]\n").unwrap();
+        let mut live = LiveConfig::watch(&path).unwrap();
+        assert_eq!(live.current().serve.tokens, vec!["first"]);
+
+        // Invalid edits are ignored; the next valid one is published
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        std::fs::write(&path, "[[cleanup.rules]]\npattern = '('\n").unwrap();
+        tokio::time::sleep(RELOAD_POLL_INTERVAL * 2).await;
+        assert!(live.changed().is_none());
+
+        std::fs::write(&path, "[serve]\ntokens = [\"second\"]\n").unwrap();
+        tokio::time::sleep(RELOAD_POLL_INTERVAL * 2).await;
+        assert_eq!(live.changed().unwrap().serve.tokens, vec!["second"]);
+
+        let _ = std::fs::remove_file(&path);
+    }
+}
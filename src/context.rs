@@ -0,0 +1,374 @@
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::Path;
+use tokenizers::Tokenizer;
+
+use crate::brains::{cosine_similarity, normalize_scores, BrainHit};
+use crate::document_processor::CHARS_PER_TOKEN;
+use crate::hybrid::select_mmr;
+use crate::storage::Structure;
+
+/// Tokens of retrieved passages sent with each question unless `--context-tokens` says otherwise
+pub const DEFAULT_CONTEXT_TOKENS: usize = 3000;
+
+/// Weight of relevance against novelty when picking passages unless `--mmr-lambda` says otherwise
+pub const DEFAULT_MMR_LAMBDA: f64 = 0.7;
+
+/// Candidate passages retrieved per passage the context can hold, so diversification has
+/// alternatives to near-duplicates to choose from
+pub const CANDIDATE_POOL_FACTOR: usize = 2;
+
+/// Picks the retrieved passages a prompt's context is made of: diverse ones by maximal
+/// marginal relevance, as many as fit the token budget, in reading order
+pub struct ContextBuilder {
+    /// Largest number of passages
+    passages: usize,
+    /// Tokens the passages may take together
+    budget: usize,
+    lambda: f64,
+    /// Measures passages; without one they're estimated at `CHARS_PER_TOKEN`
+    tokenizer: Option<Tokenizer>,
+}
+
+impl ContextBuilder {
+    pub fn new(passages: usize, budget: usize, lambda: f64) -> Result<Self> {
+        if !(0.0..=1.0).contains(&lambda) {
+            anyhow::bail!("MMR lambda must be between 0 and 1, got {}", lambda);
+        }
+        Ok(Self { passages, budget, lambda, tokenizer: None })
+    }
+
+    /// Measure passages with `tokenizer`, with truncation and padding turned off so long
+    /// passages are counted in full
+    pub fn with_tokenizer(mut self, mut tokenizer: Tokenizer) -> Result<Self> {
+        tokenizer.with_truncation(None)
+            .map_err(|e| anyhow::anyhow!("Failed to configure tokenizer: {}", e))?;
+        tokenizer.with_padding(None);
+        self.tokenizer = Some(tokenizer);
+        Ok(self)
+    }
+
+    pub fn budget(&self) -> usize {
+        self.budget
+    }
+
+    /// Tokens `text` takes
+    pub fn count_tokens(&self, text: &str) -> usize {
+        match &self.tokenizer {
+            Some(tokenizer) => match tokenizer.encode(text, false) {
+                Ok(encoding) => encoding.len(),
+                Err(_) => text.chars().count().div_ceil(CHARS_PER_TOKEN),
+            },
+            None => text.chars().count().div_ceil(CHARS_PER_TOKEN),
+        }
+    }
+
+    /// Pick passages from `hits`, best first, until the budget or the passage count is used
+    /// up. Each pick maximises `lambda · relevance − (1 − lambda) · similarity to the closest
+    /// pick so far`, with relevance the hits' min-max normalized scores and similarity the
+    /// cosine of the fragments' `vectors`, keyed by fragment id; a hit without a vector is
+    /// never taken for a duplicate. Passages are measured as `render` writes them, and one
+    /// that doesn't fit is passed over for smaller ones, though the first pick is always
+    /// kept. The picks are returned grouped by document, in the order the documents' best
+    /// passages ranked, and in document order within each.
+    pub fn pack(
+        &self,
+        hits: Vec<BrainHit>,
+        vectors: &HashMap<String, Vec<f64>>,
+        render: impl Fn(&BrainHit) -> String,
+    ) -> Vec<BrainHit> {
+        let relevance = normalize_scores(&hits.iter().map(|hit| hit.score).collect::<Vec<_>>());
+        let candidates: Vec<((BrainHit, usize), f64)> = hits.into_iter()
+            .zip(relevance)
+            .map(|(hit, relevance)| {
+                let tokens = self.count_tokens(&render(&hit));
+                ((hit, tokens), relevance)
+            })
+            .collect();
+
+        let similarity = |(a, _): &(BrainHit, usize), (b, _): &(BrainHit, usize)| {
+            match (vectors.get(&a.fragment_id), vectors.get(&b.fragment_id)) {
+                (Some(a), Some(b)) => cosine_similarity(a, b),
+                _ => 0.0,
+            }
+        };
+        let fits = |(_, tokens): &(BrainHit, usize), picked: &[((BrainHit, usize), f64)]| {
+            picked.is_empty() || picked.iter().map(|((_, used), _)| used).sum::<usize>() + tokens <= self.budget
+        };
+        let picked = select_mmr(candidates, self.passages, self.lambda, similarity, fits);
+
+        reading_order(picked.into_iter().map(|((hit, _), _)| hit).collect())
+    }
+}
+
+/// Group passages by document, documents in the order their first passage appears, and sort
+/// each document's passages by their position in it
+fn reading_order(hits: Vec<BrainHit>) -> Vec<BrainHit> {
+    let mut documents: Vec<(String, String)> = Vec::new();
+    for hit in &hits {
+        let document = (hit.brain.clone(), hit.source.file_path.clone());
+        if !documents.contains(&document) {
+            documents.push(document);
+        }
+    }
+    let mut ordered = hits;
+    ordered.sort_by_key(|hit| {
+        let rank = documents.iter()
+            .position(|(brain, file_path)| *brain == hit.brain && *file_path == hit.source.file_path);
+        (rank, hit.source.order)
+    });
+    ordered
+}
+
+/// Kind of content a passage holds, which decides how it is laid out in the prompt and
+/// where it goes among the others
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum PassageKind {
+    Prose,
+    List,
+    Table,
+    Code,
+}
+
+impl PassageKind {
+    /// The kind of a retrieved passage: the block the chunker kept whole, or a table for
+    /// spreadsheet and CSV rows, which are extracted as `a | b` lines
+    pub fn of(hit: &BrainHit) -> Self {
+        match hit.source.structure {
+            Some(Structure::Code) => PassageKind::Code,
+            Some(Structure::Table) => PassageKind::Table,
+            Some(Structure::List) => PassageKind::List,
+            None if looks_like_table(&hit.content) => PassageKind::Table,
+            None => PassageKind::Prose,
+        }
+    }
+}
+
+fn looks_like_table(text: &str) -> bool {
+    let rows: Vec<&str> = text.lines().filter(|line| !line.trim().is_empty()).collect();
+    rows.len() >= 2 && rows.iter().all(|row| row.contains(" | ") || row.trim().starts_with('|'))
+}
+
+/// Per-kind templates of `--passage-templates`; kinds left out keep the built-in template
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TemplateFile {
+    prose: Option<String>,
+    list: Option<String>,
+    table: Option<String>,
+    code: Option<String>,
+}
+
+/// Lays passages out in the prompt by kind: prose quoted, tables as Markdown tables, code in
+/// fences and lists as they are. Each template may use `{n}` (the number answers cite),
+/// `{source}` (the passage's citation, with its date) and `{text}` (the laid-out passage).
+#[derive(Debug, Clone)]
+pub struct PassageTemplates {
+    prose: String,
+    list: String,
+    table: String,
+    code: String,
+}
+
+impl Default for PassageTemplates {
+    fn default() -> Self {
+        Self {
+            prose: "[{n}] {source}\n{text}".to_string(),
+            list: "[{n}] {source}\n{text}".to_string(),
+            table: "[{n}] Table from {source}\n{text}".to_string(),
+            code: "[{n}] Code from {source}\n{text}".to_string(),
+        }
+    }
+}
+
+impl PassageTemplates {
+    /// Read templates from a TOML file with `prose`, `list`, `table` and `code` keys
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read passage templates {}", path.display()))?;
+        Self::parse(&text).with_context(|| format!("Invalid passage templates {}", path.display()))
+    }
+
+    fn parse(text: &str) -> Result<Self> {
+        let file: TemplateFile = toml::from_str(text)?;
+        let defaults = Self::default();
+        let templates = Self {
+            prose: file.prose.unwrap_or(defaults.prose),
+            list: file.list.unwrap_or(defaults.list),
+            table: file.table.unwrap_or(defaults.table),
+            code: file.code.unwrap_or(defaults.code),
+        };
+        for (kind, template) in [("prose", &templates.prose), ("list", &templates.list), ("table", &templates.table), ("code", &templates.code)] {
+            // Without the number answers can't cite the passage, and without the text there
+            // is nothing to cite
+            if !template.contains("{n}") || !template.contains("{text}") {
+                anyhow::bail!("The {} template must contain {{n}} and {{text}}", kind);
+            }
+        }
+        Ok(templates)
+    }
+
+    /// Passage `number` laid out by its kind
+    pub fn render(&self, number: usize, hit: &BrainHit) -> String {
+        let kind = PassageKind::of(hit);
+        let (template, text) = match kind {
+            PassageKind::Prose => (&self.prose, quote(&hit.content)),
+            PassageKind::List => (&self.list, hit.content.trim().to_string()),
+            PassageKind::Table => (&self.table, markdown_table(&hit.content)),
+            PassageKind::Code => (&self.code, fence(&hit.content)),
+        };
+        // The text goes in last, so braces in it are never taken for placeholders
+        template
+            .replace("{n}", &number.to_string())
+            .replace("{source}", &hit.source.citation())
+            .replace("{text}", &text)
+    }
+}
+
+/// Order picked passages for a prompt by kind: prose first to frame the question, then
+/// lists, tables and code, each kind keeping the order it was in
+pub fn order_by_kind(mut hits: Vec<BrainHit>) -> Vec<BrainHit> {
+    hits.sort_by_key(PassageKind::of);
+    hits
+}
+
+fn quote(text: &str) -> String {
+    text.trim()
+        .lines()
+        .map(|line| if line.trim().is_empty() { ">".to_string() } else { format!("> {}", line) })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Rows of `a | b` cells as a Markdown table, with the first row as its header
+fn markdown_table(text: &str) -> String {
+    let rows: Vec<Vec<&str>> = text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(|line| line.trim_matches('|').split('|').map(str::trim).collect())
+        .collect();
+    // A header separator already there is written anew
+    let is_separator = |row: &Vec<&str>| row.iter().all(|cell| !cell.is_empty() && cell.chars().all(|c| matches!(c, '-' | ':')));
+    let rows: Vec<Vec<&str>> = rows.into_iter().filter(|row| !is_separator(row)).collect();
+    let columns = rows.iter().map(Vec::len).max().unwrap_or(0);
+    let mut table = Vec::new();
+    for (i, row) in rows.iter().enumerate() {
+        let cells: Vec<&str> = (0..columns).map(|column| row.get(column).copied().unwrap_or("")).collect();
+        table.push(format!("| {} |", cells.join(" | ")));
+        if i == 0 {
+            table.push(format!("|{}", " --- |".repeat(columns)));
+        }
+    }
+    table.join("\n")
+}
+
+/// Code in a fence, unless it is fenced already; the fence is longer than any run of
+/// backticks in the code
+fn fence(text: &str) -> String {
+    let text = text.trim();
+    if text.starts_with("```") || text.starts_with("~~~") {
+        return text.to_string();
+    }
+    let mut fence = "```".to_string();
+    while text.contains(fence.as_str()) {
+        fence.push('`');
+    }
+    format!("{}\n{}\n{}", fence, text, fence)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::FragmentSource;
+
+    fn hit(id: &str, file: &str, order: i32, score: f64, content: &str) -> BrainHit {
+        BrainHit {
+            brain: "work".to_string(),
+            fragment_id: id.to_string(),
+            content: content.to_string(),
+            score,
+            normalized_score: score,
+            source: FragmentSource { file_path: file.to_string(), order, ..FragmentSource::default() },
+            version: 1,
+        }
+    }
+
+    #[test]
+    fn test_pack_skips_duplicates_and_keeps_reading_order() {
+        let hits = vec![
+            hit("a", "policy.pdf", 7, 0.9, "Backups run nightly"),
+            hit("b", "copy.pdf", 2, 0.85, "Backups run nightly"),
+            hit("c", "policy.pdf", 3, 0.6, "Restores are tested monthly"),
+            hit("d", "keys.md", 1, 0.5, "Keys rotate yearly"),
+        ];
+        let vectors: HashMap<String, Vec<f64>> = [
+            ("a", vec![1.0, 0.0, 0.0]),
+            ("b", vec![1.0, 0.0, 0.0]),
+            ("c", vec![0.0, 1.0, 0.0]),
+            ("d", vec![0.0, 0.0, 1.0]),
+        ].into_iter().map(|(id, vector)| (id.to_string(), vector)).collect();
+
+        let builder = ContextBuilder::new(3, 1000, 0.5).unwrap();
+        let packed = builder.pack(hits.clone(), &vectors, |hit| hit.content.clone());
+        let ids: Vec<&str> = packed.iter().map(|hit| hit.fragment_id.as_str()).collect();
+        // The copy is left out, and policy.pdf's passages are read in document order
+        assert_eq!(ids, vec!["c", "a", "d"]);
+
+        // Relevance alone keeps the duplicate
+        let builder = ContextBuilder::new(3, 1000, 1.0).unwrap();
+        let ids: Vec<String> = builder.pack(hits, &vectors, |hit| hit.content.clone())
+            .into_iter().map(|hit| hit.fragment_id).collect();
+        assert_eq!(ids, vec!["c", "a", "b"]);
+
+        assert!(ContextBuilder::new(3, 1000, 1.5).is_err());
+    }
+
+    #[test]
+    fn test_passages_laid_out_by_kind() {
+        let named = |id, file: &str, order, content| {
+            let mut hit = hit(id, file, order, 0.5, content);
+            hit.source.filename = file.to_string();
+            hit
+        };
+        let mut code = named("c", "guide.md", 4, "```rust\nlet x = 1;\n```");
+        code.source.structure = Some(Structure::Code);
+        let hits = vec![
+            code,
+            named("t", "budget.xlsx", 0, "Region | Cost\n\nNorth | 12"),
+            named("p", "guide.md", 1, "Deploys run nightly.\n\nRollbacks take minutes."),
+        ];
+        let ordered = order_by_kind(hits);
+        let ids: Vec<&str> = ordered.iter().map(|hit| hit.fragment_id.as_str()).collect();
+        assert_eq!(ids, vec!["p", "t", "c"]);
+
+        let templates = PassageTemplates::default();
+        assert_eq!(templates.render(1, &ordered[0]), "[1] guide.md, fragment 1\n> Deploys run nightly.\n>\n> Rollbacks take minutes.");
+        assert_eq!(templates.render(2, &ordered[1]), "[2] Table from budget.xlsx, fragment 0\n| Region | Cost |\n| --- | --- |\n| North | 12 |");
+        assert_eq!(templates.render(3, &ordered[2]), "[3] Code from guide.md, fragment 4\n```rust\nlet x = 1;\n```");
+        assert_eq!(fence("a ``` b"), "````\na ``` b\n````");
+
+        let custom = PassageTemplates::parse("code = \"Passage {n}, code:\\n{text}\"").unwrap();
+        assert_eq!(custom.render(3, &ordered[2]), "Passage 3, code:\n```rust\nlet x = 1;\n```");
+        assert!(PassageTemplates::parse("prose = \"{text}\"").is_err());
+    }
+
+    #[test]
+    fn test_pack_fits_the_token_budget() {
+        let long = "word ".repeat(100);
+        let hits = vec![
+            hit("a", "a.md", 0, 0.9, &long),
+            hit("b", "b.md", 0, 0.8, &long),
+            hit("c", "c.md", 0, 0.7, "short"),
+        ];
+        let builder = ContextBuilder::new(5, 140, 1.0).unwrap();
+        assert_eq!(builder.count_tokens(&long), 125);
+        let ids: Vec<String> = builder.pack(hits.clone(), &HashMap::new(), |hit| hit.content.clone())
+            .into_iter().map(|hit| hit.fragment_id).collect();
+        assert_eq!(ids, vec!["a", "c"]);
+
+        // The best passage is kept even when it alone is over budget
+        let builder = ContextBuilder::new(5, 10, 1.0).unwrap();
+        assert_eq!(builder.pack(hits, &HashMap::new(), |hit| hit.content.clone()).len(), 1);
+    }
+}
@@ -0,0 +1,325 @@
+use anyhow::{Context, Result};
+use futures::stream::{self, StreamExt, TryStreamExt};
+use serde::Serialize;
+
+use crate::brains::CorpusDocument;
+use crate::llm::{ChatMessage, LlmClient, TokenUsage};
+
+/// Characters of text read by one map or reduce request, roughly 6,000 tokens
+pub const BATCH_CHARS: usize = 24_000;
+
+/// Map or reduce requests in flight at once
+const CONCURRENCY: usize = 4;
+
+/// Longest notes one map or reduce request writes
+const NOTES_MAX_TOKENS: u32 = 800;
+
+/// Reply of a map request whose excerpts say nothing about the question
+const NOTHING_RELEVANT: &str = "NONE";
+
+/// A piece of a document small enough to read in one request
+#[derive(Debug, Clone, PartialEq)]
+pub struct Excerpt {
+    /// Position of its document among those planned from
+    pub document: usize,
+    /// The document, named as the notes and answer cite it
+    pub source: String,
+    /// Which part of its document this is and how many there are, when it had to be split
+    pub part: Option<(usize, usize)>,
+    pub text: String,
+}
+
+impl Excerpt {
+    fn render(&self) -> String {
+        match self.part {
+            Some((part, parts)) => format!("### [{}] (part {} of {})\n{}", self.source, part, parts, self.text),
+            None => format!("### [{}]\n{}", self.source, self.text),
+        }
+    }
+}
+
+/// One LLM request made for a corpus answer, with the messages exactly as sent
+#[derive(Clone, Serialize)]
+pub struct CorpusRequest {
+    /// `map`, `reduce` or `answer`
+    pub stage: &'static str,
+    /// Position of the batch a map request read among the planned batches
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub batch: Option<usize>,
+    pub messages: Vec<ChatMessage>,
+}
+
+/// An answer written from notes on every document rather than the top retrieved passages
+#[derive(Clone, Serialize)]
+pub struct CorpusAnswer {
+    pub answer: String,
+    /// Documents whose notes were relevant to the question
+    pub sources: Vec<String>,
+    /// Documents read
+    pub documents: usize,
+    /// LLM requests made, including the final answer
+    pub requests: usize,
+    pub token_usage: TokenUsage,
+    /// Why the output filters withheld the answer, which is then their refusal
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub withheld: Option<String>,
+    /// Every request made, in order, for the audit log
+    #[serde(skip)]
+    pub prompts: Vec<CorpusRequest>,
+}
+
+/// Split text into pieces of at most `budget` characters, preferring paragraph breaks
+fn split_text(text: &str, budget: usize) -> Vec<&str> {
+    let mut pieces = Vec::new();
+    let mut rest = text.trim();
+    while rest.chars().count() > budget {
+        let limit = rest.char_indices().nth(budget).map_or(rest.len(), |(i, _)| i);
+        let cut = rest[..limit].rfind("\n\n")
+            .filter(|&i| i > limit / 2)
+            .unwrap_or(limit);
+        pieces.push(rest[..cut].trim());
+        rest = rest[cut..].trim_start();
+    }
+    if !rest.is_empty() {
+        pieces.push(rest);
+    }
+    pieces
+}
+
+/// Cut every document into excerpts of at most `budget` characters and pack them into
+/// batches read by one map request each. Documents are named by path, prefixed with their
+/// brain when several are open.
+pub fn plan(documents: &[CorpusDocument], budget: usize, multi_brain: bool) -> Vec<Vec<Excerpt>> {
+    let mut batches: Vec<Vec<Excerpt>> = Vec::new();
+    let mut used = 0;
+    for (index, document) in documents.iter().enumerate() {
+        let source = if multi_brain {
+            format!("{}: {}", document.brain, document.file_path)
+        } else {
+            document.file_path.clone()
+        };
+        let pieces = split_text(&document.text, budget);
+        let parts = pieces.len();
+        for (i, text) in pieces.into_iter().enumerate() {
+            let length = text.chars().count();
+            if batches.is_empty() || used + length > budget {
+                batches.push(Vec::new());
+                used = 0;
+            }
+            used += length;
+            batches.last_mut().expect("a batch was just pushed").push(Excerpt {
+                document: index,
+                source: source.clone(),
+                part: (parts > 1).then_some((i + 1, parts)),
+                text: text.to_string(),
+            });
+        }
+    }
+    batches
+}
+
+/// Group notes for reduce requests of about `budget` characters. Every group but a lone
+/// leftover takes at least two notes, so each round leaves fewer notes than it started with.
+fn group_notes(notes: Vec<String>, budget: usize) -> Vec<Vec<String>> {
+    let mut groups: Vec<Vec<String>> = Vec::new();
+    let mut used = 0;
+    for note in notes {
+        let length = note.chars().count();
+        match groups.last_mut() {
+            Some(group) if group.len() < 2 || used + length <= budget => {
+                used += length;
+                group.push(note);
+            }
+            _ => {
+                used = length;
+                groups.push(vec![note]);
+            }
+        }
+    }
+    groups
+}
+
+/// The sources of `batch` its notes cite
+fn cited_sources(batch: &[Excerpt], notes: &str) -> Vec<String> {
+    let mut sources: Vec<String> = Vec::new();
+    for excerpt in batch {
+        if notes.contains(&format!("[{}]", excerpt.source)) && !sources.contains(&excerpt.source) {
+            sources.push(excerpt.source.clone());
+        }
+    }
+    sources
+}
+
+/// Notes on what one batch of excerpts says about the question, or `None` when nothing,
+/// with the messages sent for them
+async fn map_batch(llm: &LlmClient, question: &str, batch: &[Excerpt]) -> Result<(Option<String>, TokenUsage, Vec<ChatMessage>)> {
+    let excerpts = batch.iter().map(Excerpt::render).collect::<Vec<_>>().join("\n\n");
+    let messages = vec![
+        ChatMessage::system(format!(
+            "You take notes for a question that will be answered from notes on every document \
+             in a knowledge base. Write down every fact, figure, date and example in the \
+             excerpts below that helps answer it, as short bullet points. Start each bullet \
+             with the name of its document in square brackets exactly as given, e.g. [{}]. \
+             Don't answer the question itself. If the excerpts say nothing relevant, reply \
+             {} and nothing else.",
+            batch.first().map_or("report.pdf", |excerpt| excerpt.source.as_str()),
+            NOTHING_RELEVANT,
+        )),
+        ChatMessage::user(format!("Question: {}\n\nExcerpts:\n{}", question, excerpts)),
+    ];
+    let reply = llm.complete(messages.clone(), NOTES_MAX_TOKENS, 0.2).await
+        .context("Map request failed")?;
+
+    let notes = reply.content.trim();
+    let relevant = !notes.is_empty() && !notes.trim_matches(|c: char| !c.is_alphanumeric()).eq_ignore_ascii_case(NOTHING_RELEVANT);
+    Ok((relevant.then(|| notes.to_string()), reply.usage, messages))
+}
+
+/// Merge several sets of notes into one, keeping the document each point comes from, with
+/// the messages sent for it
+async fn reduce_notes(llm: &LlmClient, question: &str, notes: &[String]) -> Result<(String, TokenUsage, Vec<ChatMessage>)> {
+    let messages = vec![
+        ChatMessage::system(
+            "You combine notes taken on different documents for a question. Merge them into one \
+             set of short bullet points: keep every distinct fact, figure, date and example, \
+             fold duplicates together, and keep the bracketed document names each point \
+             comes from. Don't answer the question itself.",
+        ),
+        ChatMessage::user(format!("Question: {}\n\nNotes:\n{}", question, notes.join("\n\n"))),
+    ];
+    let reply = llm.complete(messages.clone(), NOTES_MAX_TOKENS, 0.2).await
+        .context("Reduce request failed")?;
+    Ok((reply.content.trim().to_string(), reply.usage, messages))
+}
+
+/// Answer a question that needs the whole corpus, e.g. a summary across many documents:
+/// notes are taken on every batch of excerpts (map), merged until they fit one request
+/// (reduce), and the answer is written from them with `instructions`.
+pub async fn answer_over_corpus(
+    llm: &LlmClient,
+    question: &str,
+    batches: &[Vec<Excerpt>],
+    instructions: &str,
+    max_tokens: u32,
+) -> Result<CorpusAnswer> {
+    let mut token_usage = TokenUsage::default();
+    let mut requests = batches.len();
+    let documents = batches.iter()
+        .flatten()
+        .filter(|excerpt| excerpt.part.is_none_or(|(part, _)| part == 1))
+        .count();
+
+    let mapped: Vec<(Option<String>, TokenUsage, Vec<ChatMessage>)> = stream::iter(batches)
+        .map(|batch| map_batch(llm, question, batch))
+        .buffered(CONCURRENCY)
+        .try_collect()
+        .await?;
+
+    let mut sources: Vec<String> = Vec::new();
+    let mut notes = Vec::new();
+    let mut prompts = Vec::new();
+    for (index, (batch, (batch_notes, usage, messages))) in batches.iter().zip(mapped).enumerate() {
+        token_usage += usage;
+        prompts.push(CorpusRequest { stage: "map", batch: Some(index), messages });
+        if let Some(batch_notes) = batch_notes {
+            for source in cited_sources(batch, &batch_notes) {
+                if !sources.contains(&source) {
+                    sources.push(source);
+                }
+            }
+            notes.push(batch_notes);
+        }
+    }
+
+    // Merge notes in rounds until they fit the final request
+    while notes.len() > 1 && notes.iter().map(|note| note.chars().count()).sum::<usize>() > BATCH_CHARS {
+        let groups = group_notes(notes, BATCH_CHARS);
+        requests += groups.len();
+        let reduced: Vec<(String, TokenUsage, Vec<ChatMessage>)> = stream::iter(&groups)
+            .map(|group| reduce_notes(llm, question, group))
+            .buffered(CONCURRENCY)
+            .try_collect()
+            .await?;
+        notes = Vec::new();
+        for (note, usage, messages) in reduced {
+            token_usage += usage;
+            prompts.push(CorpusRequest { stage: "reduce", batch: None, messages });
+            notes.push(note);
+        }
+    }
+
+    let notes_text = if notes.is_empty() {
+        "No document says anything relevant to the question.".to_string()
+    } else {
+        notes.join("\n\n")
+    };
+    let messages = vec![
+        ChatMessage::system(instructions),
+        ChatMessage::system(format!("Notes on every document:\n{}", notes_text)),
+        ChatMessage::user(question),
+    ];
+    let reply = llm.complete(messages.clone(), max_tokens, 0.7).await
+        .context("Answer request failed")?;
+    token_usage += reply.usage;
+    requests += 1;
+    prompts.push(CorpusRequest { stage: "answer", batch: None, messages });
+
+    Ok(CorpusAnswer { answer: reply.content, sources, documents, requests, token_usage, withheld: None, prompts })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn document(file_path: &str, text: &str) -> CorpusDocument {
+        CorpusDocument {
+            brain: "archive".to_string(),
+            document_id: format!("id-{}", file_path),
+            file_path: file_path.to_string(),
+            fragment_ids: Vec::new(),
+            version: 1,
+            text: text.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_long_documents_split_into_parts() {
+        let text = format!("{}\n\n{}", "a".repeat(30), "b".repeat(30));
+        let batches = plan(&[document("long.txt", &text), document("short.txt", "tiny")], 40, false);
+
+        let excerpts: Vec<&Excerpt> = batches.iter().flatten().collect();
+        assert_eq!(excerpts.len(), 3);
+        assert_eq!(excerpts[0].text, "a".repeat(30));
+        assert_eq!(excerpts[0].part, Some((1, 2)));
+        assert_eq!(excerpts[1].part, Some((2, 2)));
+        assert_eq!(excerpts[2].part, None);
+        assert_eq!(excerpts.iter().map(|e| e.document).collect::<Vec<_>>(), vec![0, 0, 1]);
+        // The short document shares the second batch, which still has room
+        assert_eq!(batches.len(), 2);
+        assert!(batches.iter().all(|batch| batch.iter().map(|e| e.text.len()).sum::<usize>() <= 40));
+    }
+
+    #[test]
+    fn test_sources_named_by_brain_when_several() {
+        let batches = plan(&[document("notes/a.md", "text")], 100, true);
+        assert_eq!(batches[0][0].source, "archive: notes/a.md");
+        assert!(batches[0][0].render().starts_with("### [archive: notes/a.md]"));
+    }
+
+    #[test]
+    fn test_reduce_rounds_shrink() {
+        let notes: Vec<String> = (0..5).map(|i| format!("{}", i).repeat(50)).collect();
+        // Even notes each over budget are merged in pairs
+        let groups = group_notes(notes, 10);
+        assert_eq!(groups.iter().map(Vec::len).collect::<Vec<_>>(), vec![2, 2, 1]);
+    }
+
+    #[test]
+    fn test_cited_sources() {
+        let batch = vec![
+            Excerpt { document: 0, source: "a.md".to_string(), part: None, text: String::new() },
+            Excerpt { document: 1, source: "b.md".to_string(), part: None, text: String::new() },
+        ];
+        assert_eq!(cited_sources(&batch, "- [b.md] Revenue grew 4%"), vec!["b.md"]);
+    }
+}
@@ -0,0 +1,328 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+use utoipa::ToSchema;
+
+use crate::jobs::{ItemOutcome, JobKind, JobRecord, JobState, JobStore};
+use crate::storage::Storage;
+
+/// Meta key holding how often each search query was asked
+pub const QUERY_COUNTS_KEY: &str = "query_counts";
+/// Meta key holding one sample of the database's size per day it changed
+pub const GROWTH_KEY: &str = "storage_growth";
+
+/// Distinct queries counted; the least asked are forgotten beyond this
+const MAX_TRACKED_QUERIES: usize = 500;
+/// Queries listed by the dashboard
+const TOP_QUERIES: usize = 10;
+/// Jobs listed in the ingest history
+const RECENT_JOBS: usize = 20;
+/// Days of growth kept
+const MAX_GROWTH_SAMPLES: usize = 365;
+
+/// Everything the dashboard shows about whether a database is healthy
+#[derive(Debug, Serialize, ToSchema)]
+pub struct IndexHealth {
+    pub embedding_model: String,
+    pub documents: usize,
+    pub tombstoned: usize,
+    /// Documents whose extracted text looks garbled
+    pub flagged: usize,
+    pub fragments: i64,
+    /// Fragments with a vector from the current model
+    pub embedded: i64,
+    /// Fragments waiting for a vector, including stale ones
+    pub pending: i64,
+    /// Fragments whose vector is from the previous model
+    pub stale: i64,
+    /// Fragments whose last embedding attempt failed
+    pub embedding_failures: i64,
+    /// `embedded` as a percentage of `fragments`
+    pub coverage: f64,
+    /// Size of the database on disk
+    pub database_bytes: Option<u64>,
+    /// Most recent index and ingest jobs, newest first
+    pub jobs: Vec<JobSummary>,
+    pub failed_jobs: usize,
+    /// Documents that failed across the listed jobs
+    pub failed_documents: usize,
+    /// Size of the database by day, oldest first
+    pub growth: Vec<GrowthSample>,
+    /// Most asked `POST /search` queries
+    pub top_queries: Vec<QueryCount>,
+}
+
+/// One job of the ingest history
+#[derive(Debug, Serialize, ToSchema)]
+pub struct JobSummary {
+    pub id: String,
+    pub kind: JobKind,
+    pub state: JobState,
+    pub created_at: String,
+    pub updated_at: String,
+    /// Documents indexed, for jobs that name them
+    pub done: usize,
+    pub failed: usize,
+    pub error: Option<String>,
+}
+
+impl From<&JobRecord> for JobSummary {
+    fn from(record: &JobRecord) -> Self {
+        let count = |outcome| record.items.iter().filter(|item| item.outcome == outcome).count();
+        Self {
+            id: record.id.clone(),
+            kind: record.kind,
+            state: record.state,
+            created_at: record.created_at.clone(),
+            updated_at: record.updated_at.clone(),
+            done: count(ItemOutcome::Done),
+            failed: count(ItemOutcome::Failed),
+            error: record.error.clone(),
+        }
+    }
+}
+
+/// The database's size at the end of a day it was indexed into
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct GrowthSample {
+    /// `YYYY-MM-DD`, UTC
+    pub date: String,
+    pub documents: usize,
+    pub fragments: i64,
+    pub bytes: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, ToSchema)]
+pub struct QueryCount {
+    pub query: String,
+    pub count: u64,
+}
+
+/// Queries differing only in case and spacing are counted together
+fn normalize_query(query: &str) -> String {
+    query.split_whitespace().collect::<Vec<_>>().join(" ").to_lowercase()
+}
+
+async fn load_query_counts(storage: &mut dyn Storage) -> Result<HashMap<String, u64>> {
+    match storage.get_meta_value(QUERY_COUNTS_KEY).await? {
+        Some(json) => serde_json::from_str(&json).context("Failed to parse query counts"),
+        None => Ok(HashMap::new()),
+    }
+}
+
+/// Count a search query. Once `MAX_TRACKED_QUERIES` are tracked, the least asked other
+/// query is forgotten to make room.
+pub async fn record_query(storage: &mut dyn Storage, query: &str) -> Result<()> {
+    let query = normalize_query(query);
+    if query.is_empty() {
+        return Ok(());
+    }
+    let mut counts = load_query_counts(storage).await?;
+    *counts.entry(query.clone()).or_default() += 1;
+    while counts.len() > MAX_TRACKED_QUERIES {
+        let least = counts.iter()
+            .filter(|(tracked, _)| **tracked != query)
+            .min_by(|a, b| a.1.cmp(b.1).then_with(|| b.0.cmp(a.0)))
+            .map(|(tracked, _)| tracked.clone());
+        match least {
+            Some(least) => counts.remove(&least),
+            None => break,
+        };
+    }
+    storage.set_meta_value(QUERY_COUNTS_KEY, &serde_json::to_string(&counts)?).await
+}
+
+/// The `limit` most asked queries, most asked first
+fn top_queries(counts: HashMap<String, u64>, limit: usize) -> Vec<QueryCount> {
+    let mut queries: Vec<QueryCount> = counts.into_iter()
+        .map(|(query, count)| QueryCount { query, count })
+        .collect();
+    queries.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.query.cmp(&b.query)));
+    queries.truncate(limit);
+    queries
+}
+
+/// Bytes taken on disk by a database file (with its write-ahead log) or directory
+fn database_bytes(path: &Path) -> Option<u64> {
+    fn directory_bytes(dir: &Path) -> u64 {
+        std::fs::read_dir(dir).into_iter().flatten().flatten()
+            .map(|entry| match entry.metadata() {
+                Ok(metadata) if metadata.is_dir() => directory_bytes(&entry.path()),
+                Ok(metadata) => metadata.len(),
+                Err(_) => 0,
+            })
+            .sum()
+    }
+
+    let metadata = std::fs::metadata(path).ok()?;
+    if metadata.is_dir() {
+        return Some(directory_bytes(path));
+    }
+    let mut wal = path.as_os_str().to_owned();
+    wal.push(".wal");
+    Some(metadata.len() + std::fs::metadata(wal).map_or(0, |wal| wal.len()))
+}
+
+async fn load_growth(storage: &mut dyn Storage) -> Result<Vec<GrowthSample>> {
+    match storage.get_meta_value(GROWTH_KEY).await? {
+        Some(json) => serde_json::from_str(&json).context("Failed to parse storage growth"),
+        None => Ok(Vec::new()),
+    }
+}
+
+/// Add `sample` to the growth history, replacing the sample of the same day
+fn push_sample(growth: &mut Vec<GrowthSample>, sample: GrowthSample) {
+    match growth.last_mut() {
+        Some(last) if last.date == sample.date => *last = sample,
+        _ => growth.push(sample),
+    }
+    if growth.len() > MAX_GROWTH_SAMPLES {
+        growth.drain(..growth.len() - MAX_GROWTH_SAMPLES);
+    }
+}
+
+/// Record today's size of the database, after an index run or ingest job
+pub async fn record_growth(storage: &mut dyn Storage, database: &Path) -> Result<()> {
+    let documents = storage.list_documents().await?;
+    let sample = GrowthSample {
+        date: chrono::Utc::now().format("%Y-%m-%d").to_string(),
+        documents: documents.len(),
+        fragments: documents.iter().map(|d| d.fragments as i64).sum(),
+        bytes: database_bytes(database),
+    };
+    let mut growth = load_growth(storage).await?;
+    push_sample(&mut growth, sample);
+    storage.set_meta_value(GROWTH_KEY, &serde_json::to_string(&growth)?).await
+}
+
+/// Gather the dashboard's figures for the database at `database`
+pub async fn index_health(storage: &mut dyn Storage, jobs: &JobStore, database: &Path) -> Result<IndexHealth> {
+    let meta = storage.get_meta_info().await?;
+    let documents = storage.list_documents().await?;
+    let fragments: i64 = documents.iter().map(|d| d.fragments as i64).sum();
+    let pending = storage.count_fragments_without_embeddings().await? as i64;
+    let stale = storage.count_stale_fragments().await? as i64;
+    let embedding_failures = storage.count_embedding_failures().await? as i64;
+    let embedded = fragments - pending;
+
+    let records = jobs.list()?;
+    let jobs: Vec<JobSummary> = records.iter()
+        .filter(|record| record.kind == JobKind::Ingest)
+        .take(RECENT_JOBS)
+        .map(JobSummary::from)
+        .collect();
+
+    Ok(IndexHealth {
+        embedding_model: meta.embedding_model,
+        tombstoned: documents.iter().filter(|d| d.tombstoned).count(),
+        flagged: documents.iter().filter(|d| !d.quality_flags.is_empty()).count(),
+        documents: documents.len(),
+        fragments,
+        embedded,
+        pending,
+        stale,
+        embedding_failures,
+        coverage: if fragments > 0 { embedded as f64 * 100.0 / fragments as f64 } else { 100.0 },
+        database_bytes: database_bytes(database),
+        failed_jobs: jobs.iter().filter(|job| matches!(job.state, JobState::Failed | JobState::Interrupted)).count(),
+        failed_documents: jobs.iter().map(|job| job.failed).sum(),
+        jobs,
+        growth: load_growth(storage).await?,
+        top_queries: top_queries(load_query_counts(storage).await?, TOP_QUERIES),
+    })
+}
+
+/// The dashboard page served at `/dashboard`. It asks for an access token, keeps it for the
+/// browser session and reads everything from `GET /stats`.
+pub const PAGE: &str = r##"<!DOCTYPE html>
+<html><head><meta charset="utf-8"><title>Portable Brains health</title>
+<style>
+body { font-family: system-ui, sans-serif; margin: 2em; color: #222; }
+.cards { display: flex; flex-wrap: wrap; gap: 1em; }
+.card { border: 1px solid #ccc; border-radius: 6px; padding: 0.8em 1.2em; min-width: 10em; }
+.card b { display: block; font-size: 1.6em; }
+.bad { color: #b00; }
+table { border-collapse: collapse; margin-top: 0.5em; }
+td, th { border-bottom: 1px solid #eee; padding: 0.3em 0.8em; text-align: left; }
+</style></head>
+<body>
+<h1>Portable Brains health</h1>
+<p id="status">Loading…</p>
+<div class="cards" id="cards"></div>
+<h2>Storage growth</h2><svg id="growth" width="600" height="120" style="border:1px solid #ccc"></svg>
+<h2>Ingest history</h2><table id="jobs"></table>
+<h2>Top queries</h2><table id="queries"></table>
+<script>
+const escape = (text) => String(text ?? "").replace(/[&<>"]/g, (c) => ({"&": "&amp;", "<": "&lt;", ">": "&gt;", "\"": "&quot;"})[c]);
+const bytes = (n) => n == null ? "?" : n > 1e9 ? (n / 1e9).toFixed(1) + " GB" : (n / 1e6).toFixed(1) + " MB";
+const row = (cells, tag = "td") => "<tr>" + cells.map((c) => `<${tag}>${escape(c)}</${tag}>`).join("") + "</tr>";
+
+async function load() {
+  let token = sessionStorage.getItem("token") || prompt("Access token");
+  const response = await fetch("/stats", { headers: { "Authorization": `Bearer ${token}` } });
+  if (response.status === 401 || response.status === 403) {
+    sessionStorage.removeItem("token");
+    document.getElementById("status").textContent = "This token can't read the dashboard; reload to try another.";
+    return;
+  }
+  sessionStorage.setItem("token", token);
+  const s = await response.json();
+  document.getElementById("status").textContent = `Model ${s.embedding_model}, updated ${new Date().toLocaleTimeString()}`;
+
+  const cards = [
+    ["Documents", s.documents, `${s.tombstoned} tombstoned, ${s.flagged} flagged`],
+    ["Embedding coverage", s.coverage.toFixed(1) + "%", `${s.pending} pending, ${s.stale} stale`, s.coverage < 100],
+    ["Embedding failures", s.embedding_failures, "", s.embedding_failures > 0],
+    ["Failed jobs", s.failed_jobs, `${s.failed_documents} documents failed`, s.failed_jobs > 0],
+    ["Database", bytes(s.database_bytes), `${s.fragments} fragments`],
+  ];
+  document.getElementById("cards").innerHTML = cards.map(([title, value, detail, bad]) =>
+    `<div class="card">${escape(title)}<b class="${bad ? "bad" : ""}">${escape(value)}</b>${escape(detail)}</div>`).join("");
+
+  const growth = s.growth, svg = document.getElementById("growth");
+  const most = Math.max(1, ...growth.map((g) => g.fragments));
+  const points = growth.map((g, i) => `${growth.length > 1 ? i * 590 / (growth.length - 1) + 5 : 300},${115 - g.fragments * 105 / most}`);
+  svg.innerHTML = `<polyline fill="none" stroke="#36c" stroke-width="2" points="${points.join(" ")}"/>` +
+    growth.map((g, i) => `<circle r="3" fill="#36c" cx="${points[i].split(",")[0]}" cy="${points[i].split(",")[1]}">` +
+      `<title>${escape(g.date)}: ${g.documents} documents, ${g.fragments} fragments, ${bytes(g.bytes)}</title></circle>`).join("");
+
+  document.getElementById("jobs").innerHTML = row(["Job", "Started", "State", "Indexed", "Failed", "Error"], "th") +
+    s.jobs.map((j) => row([j.id.slice(0, 8), j.created_at, j.state, j.done, j.failed, j.error])).join("");
+  document.getElementById("queries").innerHTML = row(["Query", "Searches"], "th") +
+    s.top_queries.map((q) => row([q.query, q.count])).join("");
+}
+load();
+setInterval(load, 30000);
+</script>
+</body></html>
+"##;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lancedb_storage::LanceDBStorage;
+
+    #[tokio::test]
+    async fn test_queries_counted_and_ranked() {
+        let mut storage = LanceDBStorage::new(Path::new("queries")).await.unwrap();
+        for query in ["Vacation days", "vacation   DAYS", "expense policy", "  "] {
+            record_query(&mut storage, query).await.unwrap();
+        }
+        let top = top_queries(load_query_counts(&mut storage).await.unwrap(), 10);
+        assert_eq!(top, vec![
+            QueryCount { query: "vacation days".to_string(), count: 2 },
+            QueryCount { query: "expense policy".to_string(), count: 1 },
+        ]);
+    }
+
+    #[test]
+    fn test_growth_keeps_one_sample_per_day() {
+        let sample = |date: &str, documents| GrowthSample { date: date.to_string(), documents, fragments: 0, bytes: None };
+        let mut growth = Vec::new();
+        push_sample(&mut growth, sample("2026-10-01", 3));
+        push_sample(&mut growth, sample("2026-10-01", 5));
+        push_sample(&mut growth, sample("2026-10-02", 8));
+        assert_eq!(growth.iter().map(|g| g.documents).collect::<Vec<_>>(), vec![5, 8]);
+    }
+}
@@ -0,0 +1,143 @@
+use anyhow::{Context, Result};
+use ignore::overrides::OverrideBuilder;
+use ignore::WalkBuilder;
+use log::warn;
+use std::path::{Path, PathBuf};
+
+use crate::document_processor::DocumentFormat;
+
+/// Ignore file read in every scanned directory, in `.gitignore` syntax
+pub const IGNORE_FILE: &str = ".brainignore";
+
+/// Which files under an input directory get indexed
+#[derive(Debug, Clone, Default)]
+pub struct Discovery {
+    /// Descend into subdirectories
+    pub recursive: bool,
+    /// When non-empty, only files matching one of these globs
+    pub include: Vec<String>,
+    /// Files and directories matching these globs are skipped
+    pub exclude: Vec<String>,
+}
+
+impl Discovery {
+    /// Supported documents under `dir`, sorted by path.
+    ///
+    /// Globs use `.gitignore` syntax relative to `dir`: `*.pdf` matches at any depth and
+    /// `node_modules/` any directory of that name. Rules in `.gitignore` and `.brainignore`
+    /// files are honoured too, whether or not `dir` is in a git repository.
+    pub fn find_files(&self, dir: &Path) -> Result<Vec<PathBuf>> {
+        let mut overrides = OverrideBuilder::new(dir);
+        for glob in &self.include {
+            overrides.add(glob).with_context(|| format!("Invalid include glob '{}'", glob))?;
+        }
+        for glob in &self.exclude {
+            overrides.add(&format!("!{}", glob)).with_context(|| format!("Invalid exclude glob '{}'", glob))?;
+        }
+        overrides.add("!.git/")?;
+
+        let walker = WalkBuilder::new(dir)
+            .max_depth((!self.recursive).then_some(1))
+            .hidden(false)
+            .parents(false)
+            .ignore(false)
+            .git_global(false)
+            .git_exclude(false)
+            .require_git(false)
+            .add_custom_ignore_filename(IGNORE_FILE)
+            .overrides(overrides.build()?)
+            .build();
+
+        let mut files = Vec::new();
+        for entry in walker {
+            // An unreadable subdirectory shouldn't stop the rest of the tree being indexed
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(e) if self.recursive && entry_depth(&e) > 0 => {
+                    warn!("Skipping {}", e);
+                    continue;
+                }
+                Err(e) => return Err(e).with_context(|| format!("Failed to read {}", dir.display())),
+            };
+            let path = entry.into_path();
+            let supported = path.extension()
+                .and_then(|extension| DocumentFormat::from_extension(&extension.to_string_lossy()))
+                .is_some();
+            if supported && path.is_file() {
+                files.push(path);
+            }
+        }
+
+        // Walk order varies by filesystem; path order makes runs repeatable
+        files.sort();
+        Ok(files)
+    }
+
+    /// Whether a document stored at `file_path` is one `find_files(dir)` would look for, so
+    /// watch only applies its delete policy to documents from the watched tree
+    pub fn covers(&self, dir: &Path, file_path: &Path) -> bool {
+        if self.recursive {
+            file_path.starts_with(dir)
+        } else {
+            file_path.parent() == Some(dir)
+        }
+    }
+}
+
+fn entry_depth(error: &ignore::Error) -> usize {
+    match error {
+        ignore::Error::WithDepth { depth, .. } => *depth,
+        ignore::Error::WithPath { err, .. } => entry_depth(err),
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tree(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("pb-discovery-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        for file in ["top.pdf", "notes.txt", "image.png", "docs/guide.html", "docs/api/ref.docx",
+                     "node_modules/pkg/readme.txt", "build/out.txt", "drafts/wip.txt"] {
+            let path = dir.join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, "x").unwrap();
+        }
+        std::fs::write(dir.join(".gitignore"), "build/\n").unwrap();
+        std::fs::write(dir.join("docs").join(IGNORE_FILE), "api/\n").unwrap();
+        dir
+    }
+
+    fn names(dir: &Path, files: Vec<PathBuf>) -> Vec<String> {
+        files.iter().map(|file| file.strip_prefix(dir).unwrap().to_string_lossy().replace('\\', "/")).collect()
+    }
+
+    #[test]
+    fn test_top_level_only_by_default() {
+        let dir = tree("flat");
+        let files = Discovery::default().find_files(&dir).unwrap();
+        assert_eq!(names(&dir, files), vec!["notes.txt", "top.pdf"]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_recursive_with_globs_and_ignore_files() {
+        let dir = tree("recursive");
+        let discovery = Discovery {
+            recursive: true,
+            include: Vec::new(),
+            exclude: vec!["node_modules/".to_string(), "drafts/**".to_string()],
+        };
+        let files = discovery.find_files(&dir).unwrap();
+        assert_eq!(names(&dir, files), vec!["docs/guide.html", "notes.txt", "top.pdf"]);
+
+        let pdfs_and_html = Discovery { include: vec!["*.pdf".to_string(), "*.html".to_string()], ..discovery.clone() };
+        assert_eq!(names(&dir, pdfs_and_html.find_files(&dir).unwrap()), vec!["docs/guide.html", "top.pdf"]);
+
+        assert!(discovery.covers(&dir, &dir.join("docs/guide.html")));
+        assert!(!Discovery::default().covers(&dir, &dir.join("docs/guide.html")));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
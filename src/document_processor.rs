@@ -1,17 +1,81 @@
 use anyhow::{Context, Result};
+use clap::ValueEnum;
+use lopdf::encryption::DecryptionError;
 use lopdf::Document;
 use regex::Regex;
 use log::{debug, warn};
+use std::borrow::Cow;
+use std::collections::HashMap;
 use std::path::Path;
+use std::sync::Arc;
 use scraper::{Html, Selector};
 use calamine::{Reader, open_workbook_auto, DataType};
 use std::io::{Cursor, Read};
 use zip::ZipArchive;
 use quick_xml::Reader as XmlReader;
-use quick_xml::events::Event;
+use quick_xml::events::{BytesStart, Event};
 use uuid::Uuid;
+use serde::{Deserialize, Serialize};
+use flate2::Compression;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use tokenizers::Tokenizer;
 
-#[derive(Debug, Clone, PartialEq)]
+use crate::error::{self, PortableBrainsError};
+use crate::passwords::Passwords;
+use crate::storage::{FragmentMeta, Location, Storage, Structure};
+
+/// Separator between the headings of a section path, e.g. `Design > Security Requirements`
+pub const SECTION_SEPARATOR: &str = " > ";
+
+/// Meta key recording the chunking strategy a database was indexed with
+pub const CHUNKING_KEY: &str = "chunking";
+
+/// Rough characters per token, for converting character sizes to token sizes
+pub const CHARS_PER_TOKEN: usize = 4;
+
+/// How prose is split into fragments, chosen with `--chunking`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum ChunkingStrategy {
+    /// Pack whole sentences up to the chunk size in characters
+    #[default]
+    Sentence,
+    /// Pack whole sentences up to the chunk size in tokens of the embedding model's tokenizer
+    Token,
+    /// Pack whole paragraphs; a paragraph longer than the chunk size is split by sentence
+    Paragraph,
+    /// Split at paragraphs, then lines, sentences and words until pieces fit, and pack them
+    Recursive,
+}
+
+impl ChunkingStrategy {
+    pub fn name(self) -> &'static str {
+        match self {
+            ChunkingStrategy::Sentence => "sentence",
+            ChunkingStrategy::Token => "token",
+            ChunkingStrategy::Paragraph => "paragraph",
+            ChunkingStrategy::Recursive => "recursive",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        <Self as ValueEnum>::from_str(name, true).ok()
+    }
+}
+
+/// The chunking strategy recorded in a database by `index --chunking`
+pub async fn recorded_chunking(storage: &mut dyn Storage) -> Result<Option<ChunkingStrategy>> {
+    let Some(name) = storage.get_meta_value(CHUNKING_KEY).await? else {
+        return Ok(None);
+    };
+    let strategy = ChunkingStrategy::from_name(&name);
+    if strategy.is_none() {
+        warn!("Ignoring unknown chunking strategy '{}' recorded in the database", name);
+    }
+    Ok(strategy)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum DocumentFormat {
     Pdf,
     Text,
@@ -19,6 +83,11 @@ pub enum DocumentFormat {
     Docx,
     Pptx,
     Xlsx,
+    Markdown,
+    Epub,
+    Csv,
+    Tsv,
+    Email,
 }
 
 impl DocumentFormat {
@@ -30,6 +99,11 @@ impl DocumentFormat {
             "docx" => Some(DocumentFormat::Docx),
             "pptx" => Some(DocumentFormat::Pptx),
             "xlsx" => Some(DocumentFormat::Xlsx),
+            "md" | "markdown" => Some(DocumentFormat::Markdown),
+            "epub" => Some(DocumentFormat::Epub),
+            "csv" => Some(DocumentFormat::Csv),
+            "tsv" => Some(DocumentFormat::Tsv),
+            "eml" => Some(DocumentFormat::Email),
             _ => None,
         }
     }
@@ -42,46 +116,258 @@ impl DocumentFormat {
             DocumentFormat::Docx => &["docx"],
             DocumentFormat::Pptx => &["pptx"],
             DocumentFormat::Xlsx => &["xlsx"],
+            DocumentFormat::Markdown => &["md", "markdown"],
+            DocumentFormat::Epub => &["epub"],
+            DocumentFormat::Csv => &["csv"],
+            DocumentFormat::Tsv => &["tsv"],
+            DocumentFormat::Email => &["eml"],
+        }
+    }
+}
+
+/// A run of extracted text and the headings it sits under
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Section {
+    /// Enclosing headings, outermost first; empty for text before the first heading or
+    /// for formats without an outline
+    pub path: Vec<String>,
+    pub text: String,
+    /// PDF page the text was extracted from; PDFs are split into a section per page so
+    /// fragments can be cited by page
+    #[serde(default)]
+    pub page: Option<u32>,
+    /// Slide or worksheet the text was extracted from; decks and workbooks are split into
+    /// a section per slide or sheet
+    #[serde(default)]
+    pub location: Option<Location>,
+}
+
+impl Section {
+    fn untitled(text: String) -> Self {
+        Self { path: Vec::new(), text, page: None, location: None }
+    }
+    
+    /// The heading path joined for storage, or `None` outside any heading
+    pub fn label(&self) -> Option<String> {
+        (!self.path.is_empty()).then(|| self.path.join(SECTION_SEPARATOR))
+    }
+}
+
+/// Join the text of extracted sections back into a single document text
+pub fn join_sections(sections: &[Section]) -> String {
+    sections.iter()
+        .map(|section| section.text.as_str())
+        .collect::<Vec<_>>()
+        .join("\n\n")
+}
+
+/// Longest title derived for a document, in characters
+pub const MAX_TITLE_CHARS: usize = 80;
+
+/// A title for a document from its extracted sections, for listings and citations to show
+/// instead of a file name like `scan_0234.pdf`: the heading the document opens with, or its
+/// first line when that reads like a title (short, not ending like a sentence), or else its
+/// opening words. `None` when there's no text.
+pub fn derive_title(sections: &[Section]) -> Option<String> {
+    let first = sections.iter().find(|section| !section.text.trim().is_empty() || !section.path.is_empty())?;
+    if let Some(heading) = first.path.first() {
+        return Some(shorten_title(heading));
+    }
+    // Page numbers and rules above the text aren't a title
+    let line = first.text.lines()
+        .map(|line| line.split_whitespace().collect::<Vec<_>>().join(" "))
+        .find(|line| line.chars().any(char::is_alphabetic))?;
+    let reads_like_title = line.chars().count() <= MAX_TITLE_CHARS
+        && line.split(' ').count() <= 12
+        && !line.ends_with(['.', ',', ';', ':']);
+    if reads_like_title {
+        return Some(line);
+    }
+    Some(shorten_title(&line))
+}
+
+/// Cut a title at the last whole word that fits `MAX_TITLE_CHARS`, marking the cut
+fn shorten_title(text: &str) -> String {
+    let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    if text.chars().count() <= MAX_TITLE_CHARS {
+        return text;
+    }
+    let mut title = String::new();
+    for word in text.split(' ') {
+        if title.chars().count() + word.chars().count() + 1 > MAX_TITLE_CHARS {
+            break;
         }
+        if !title.is_empty() {
+            title.push(' ');
+        }
+        title.push_str(word);
+    }
+    if title.is_empty() {
+        title = text.chars().take(MAX_TITLE_CHARS - 1).collect();
     }
+    let title = title.trim_end_matches(['.', ',', ';', ':']);
+    format!("{}…", title)
+}
+
+/// A user-supplied regex replacement applied to extracted text
+#[derive(Debug, Clone)]
+pub struct CleanupRule {
+    pub regex: Regex,
+    /// Replacement text; `$1`, `${name}` refer to capture groups
+    pub replace: String,
+    /// Formats the rule applies to; all formats when empty
+    pub formats: Vec<DocumentFormat>,
+}
+
+/// Per-format overrides of the cleanup settings
+#[derive(Debug, Clone, Default)]
+pub struct FormatCleanup {
+    /// Overrides `TextCleanup::collapse_whitespace` for this format
+    pub collapse_whitespace: Option<bool>,
+    /// Skip the custom rules for this format
+    pub skip_rules: bool,
+}
+
+/// How extracted text is cleaned before chunking
+#[derive(Debug, Clone)]
+pub struct TextCleanup {
+    /// Replacements applied in order to the raw extraction, line breaks included
+    pub rules: Vec<CleanupRule>,
+    /// Collapse runs of whitespace into single spaces
+    pub collapse_whitespace: bool,
+    pub formats: HashMap<DocumentFormat, FormatCleanup>,
+}
+
+impl Default for TextCleanup {
+    fn default() -> Self {
+        Self {
+            rules: Vec::new(),
+            collapse_whitespace: true,
+            formats: HashMap::new(),
+        }
+    }
+}
+
+/// Compress extracted sections for storage alongside a document, so it can be re-chunked
+/// later without parsing the original again
+pub fn pack_sections(sections: &[Section]) -> Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    serde_json::to_writer(&mut encoder, sections)?;
+    Ok(encoder.finish()?)
+}
+
+/// Read back sections stored by `pack_sections`
+pub fn unpack_sections(data: &[u8]) -> Result<Vec<Section>> {
+    serde_json::from_reader(GzDecoder::new(data)).context("Failed to read stored document text")
 }
 
 pub struct DocumentProcessor {
     chunk_size: usize,
     overlap: usize,
+    strategy: ChunkingStrategy,
+    /// Measures chunk sizes in tokens for the token strategy
+    tokenizer: Option<Tokenizer>,
     cleanup_regex: Regex,
+    cleanup: TextCleanup,
     max_file_size: usize,      // Maximum file size to process (in bytes)
-    max_text_length: usize,    // Maximum extracted text length (in chars)
+    segment_length: usize,     // Text chunked per pass (in bytes); longer extractions continue in further segments
+    /// Passwords tried on encrypted PDFs
+    passwords: Option<Arc<Passwords>>,
 }
 
 impl DocumentProcessor {
     pub fn new() -> Self {
-        // Regex to clean up extracted text
-        let cleanup_regex = Regex::new(r"\s+").unwrap();
+        // Regex to clean up extracted text; line breaks are kept as paragraph breaks
+        let cleanup_regex = Regex::new(r"[^\S\n]+").unwrap();
         
         Self {
             chunk_size: 512,
             overlap: 50,
+            strategy: ChunkingStrategy::Sentence,
+            tokenizer: None,
             cleanup_regex,
+            cleanup: TextCleanup::default(),
             max_file_size: 100 * 1024 * 1024,  // 100MB max file size
-            max_text_length: 10_000_000,        // 10M characters max
+            segment_length: 10_000_000,         // 10M characters per segment
+            passwords: None,
         }
     }
     
-    pub fn with_limits(chunk_size: usize, overlap: usize, max_file_size: usize, max_text_length: usize) -> Self {
-        let cleanup_regex = Regex::new(r"\s+").unwrap();
+    pub fn with_limits(chunk_size: usize, overlap: usize, max_file_size: usize, segment_length: usize) -> Self {
+        let cleanup_regex = Regex::new(r"[^\S\n]+").unwrap();
         
         Self {
             chunk_size,
             overlap,
+            strategy: ChunkingStrategy::Sentence,
+            tokenizer: None,
             cleanup_regex,
+            cleanup: TextCleanup::default(),
             max_file_size,
-            max_text_length,
+            segment_length,
+            passwords: None,
         }
     }
     
+    /// Use a different chunk size and overlap, keeping the other limits
+    pub fn with_chunking(mut self, chunk_size: usize, overlap: usize) -> Self {
+        self.chunk_size = chunk_size;
+        self.overlap = overlap;
+        self
+    }
+    
+    /// Refuse files larger than `max_file_size` bytes
+    pub fn with_max_file_size(mut self, max_file_size: usize) -> Self {
+        self.max_file_size = max_file_size;
+        self
+    }
+    
+    /// Largest file processed, in bytes
+    pub fn max_file_size(&self) -> usize {
+        self.max_file_size
+    }
+    
+    /// Split prose with another strategy; the token strategy is set with `with_token_chunking`
+    pub fn with_strategy(mut self, strategy: ChunkingStrategy) -> Self {
+        self.strategy = strategy;
+        self
+    }
+    
+    /// Chunk with the token strategy, measuring sizes with this tokenizer. The current
+    /// chunk size and overlap are converted from characters at `CHARS_PER_TOKEN`; call
+    /// `with_chunking` afterwards to give them in tokens. Truncation and padding are turned
+    /// off so long texts are counted in full.
+    pub fn with_token_chunking(mut self, mut tokenizer: Tokenizer) -> Result<Self> {
+        tokenizer.with_truncation(None)
+            .map_err(|e| anyhow::anyhow!("Failed to configure tokenizer: {}", e))?;
+        tokenizer.with_padding(None);
+        self.strategy = ChunkingStrategy::Token;
+        self.tokenizer = Some(tokenizer);
+        self.chunk_size = (self.chunk_size / CHARS_PER_TOKEN).max(1);
+        self.overlap /= CHARS_PER_TOKEN;
+        Ok(self)
+    }
+    
+    /// Use custom cleanup rules instead of the default whitespace normalization alone
+    pub fn with_cleanup(mut self, cleanup: TextCleanup) -> Self {
+        self.cleanup = cleanup;
+        self
+    }
+    
+    /// Open encrypted PDFs with these passwords, asking for one when they allow it
+    pub fn with_passwords(mut self, passwords: Arc<Passwords>) -> Self {
+        self.passwords = Some(passwords);
+        self
+    }
+    
     /// Extract text from PDF with memory limits and streaming processing
     pub fn extract_text_from_pdf(&self, pdf_data: &[u8]) -> Result<String> {
+        Ok(join_sections(&self.extract_sections_from_pdf(pdf_data, None)?))
+    }
+    
+    /// Extract PDF text split at its bookmarks, so each section carries its heading path.
+    /// The path picks the passwords tried when the PDF is encrypted.
+    fn extract_sections_from_pdf(&self, pdf_data: &[u8], file_path: Option<&Path>) -> Result<Vec<Section>> {
         // Check file size limit
         if pdf_data.len() > self.max_file_size {
             anyhow::bail!(
@@ -91,64 +377,121 @@ impl DocumentProcessor {
             );
         }
         
-        let document = Document::load_mem(pdf_data)
+        let mut document = Document::load_mem(pdf_data)
             .context("Failed to load PDF document")?;
+        if document.is_encrypted() {
+            self.decrypt_pdf(&mut document, file_path)?;
+        }
         
-        let mut text_content = String::new();
         let page_count = document.get_pages().len();
+        let mut headings = pdf_outline(&document);
+        // Stable, so headings on the same page keep their outline order
+        headings.sort_by_key(|heading| heading.page);
         
-        debug!("Processing PDF with {} pages", page_count);
+        debug!("Processing PDF with {} pages and {} bookmarks", page_count, headings.len());
         
-        // Extract text from each page with memory monitoring
+        let mut sections = Vec::new();
+        let mut path: Vec<String> = Vec::new();
+        let mut text_content = String::new();
+        let mut extracted = 0;
+        let mut next_heading = 0;
+        
+        // Extract text from each page, ending a section at every page break as well as at
+        // every bookmark; long sections are split into segments afterwards
         for page_num in 1..=page_count {
-            // Check if we're approaching text length limit
-            if text_content.len() > self.max_text_length {
-                warn!("Reached maximum text length limit, stopping at page {}/{}", page_num - 1, page_count);
-                break;
-            }
-            
-            match document.extract_text(&[page_num as u32]) {
-                Ok(page_text) => {
-                    // Only add page text if it won't exceed our limit
-                    if text_content.len() + page_text.len() <= self.max_text_length {
-                        text_content.push_str(&page_text);
-                        text_content.push('\n');
-                    } else {
-                        warn!("Page {} would exceed text limit, truncating document", page_num);
-                        // Add as much as we can
-                        let remaining_capacity = self.max_text_length.saturating_sub(text_content.len());
-                        if remaining_capacity > 0 {
-                            let truncated_text: String = page_text.chars().take(remaining_capacity).collect();
-                            text_content.push_str(&truncated_text);
-                        }
-                        break;
-                    }
-                }
+            let page_text = match document.extract_text(&[page_num as u32]) {
+                Ok(page_text) => page_text,
                 Err(e) => {
                     debug!("Failed to extract text from page {}: {}", page_num, e);
                     // Continue with other pages
+                    String::new()
+                }
+            };
+            extracted += page_text.len();
+            
+            let mut rest = page_text.as_str();
+            while let Some(heading) = headings.get(next_heading).filter(|h| h.page <= page_num as u32) {
+                next_heading += 1;
+                // A bookmark points at a page; split where its title appears on that page,
+                // or at the top of the page when the title isn't found in the extracted text
+                if let Some(at) = rest.find(heading.title.as_str()) {
+                    text_content.push_str(&rest[..at]);
+                    rest = &rest[at..];
                 }
+                self.push_section(&mut sections, &path, &text_content, &DocumentFormat::Pdf, Some(page_num as u32));
+                text_content.clear();
+                path.truncate(heading.level);
+                path.push(heading.title.clone());
             }
+            text_content.push_str(rest);
+            self.push_section(&mut sections, &path, &text_content, &DocumentFormat::Pdf, Some(page_num as u32));
+            text_content.clear();
             
             // Periodic memory cleanup hint for large documents
             if page_num % 50 == 0 {
-                debug!("Processed {} pages, current text length: {} chars", page_num, text_content.len());
+                debug!("Processed {} pages, current text length: {} chars", page_num, extracted);
             }
         }
         
-        if text_content.trim().is_empty() {
+        if sections.is_empty() {
             anyhow::bail!("No text could be extracted from PDF");
         }
         
-        // Clean up the extracted text
-        let cleaned_text = self.cleanup_text(&text_content);
+        debug!("Extracted {} sections of text from {} pages", sections.len(), page_count);
+        Ok(sections)
+    }
+    
+    /// Decrypt a PDF with the first password that opens it: the empty password of PDFs that
+    /// only restrict printing or copying, then the known passwords for its path, then any typed
+    /// at the prompt
+    fn decrypt_pdf(&self, document: &mut Document, file_path: Option<&Path>) -> Result<()> {
+        let passwords = self.passwords.as_deref().zip(file_path);
+        let mut candidates = vec![String::new()];
+        if let Some((passwords, path)) = passwords {
+            candidates.extend(passwords.candidates(path));
+        }
         
-        debug!("Extracted {} characters of text from {} pages", cleaned_text.len(), page_count);
-        Ok(cleaned_text)
+        let mut tried = 0;
+        let mut password = candidates.into_iter();
+        while let Some(next) = password.next().or_else(|| passwords.and_then(|(passwords, path)| passwords.ask(path))) {
+            // A wrong password is rejected before anything is decrypted, so the next can be tried
+            match document.decrypt(&next) {
+                Ok(()) => return Ok(()),
+                Err(lopdf::Error::Decryption(DecryptionError::IncorrectPassword)) => tried += 1,
+                Err(e) => {
+                    return Err(PortableBrainsError::Encrypted(format!("the PDF's encryption isn't supported ({})", e)).into());
+                }
+            }
+        }
+        
+        let reason = match passwords {
+            // The empty password doesn't count, it is always tried
+            Some(_) if tried > 1 => format!("none of the {} passwords tried opens the PDF", tried - 1),
+            _ => "the PDF is password-protected; give its password with --password-file or --ask-password".to_string(),
+        };
+        Err(PortableBrainsError::Encrypted(reason).into())
+    }
+    
+    /// Clean a section's text and keep it if anything is left
+    fn push_section(&self, sections: &mut Vec<Section>, path: &[String], text: &str, format: &DocumentFormat, page: Option<u32>) {
+        let text = self.cleanup_text(text, format);
+        if !text.is_empty() {
+            sections.push(Section { path: path.to_vec(), text, page, location: None });
+        }
     }
 
     /// Extract text from any supported document format
     pub fn extract_text_from_document(&self, file_path: &Path, file_data: &[u8]) -> Result<String> {
+        Ok(join_sections(&self.extract_sections_from_document(file_path, file_data)?))
+    }
+    
+    /// Extract text from any supported document format, split into sections at the
+    /// document's headings where the format has them (DOCX heading styles, PDF bookmarks)
+    pub fn extract_sections_from_document(&self, file_path: &Path, file_data: &[u8]) -> Result<Vec<Section>> {
+        self.extract_sections(file_path, file_data).map_err(error::document_processing)
+    }
+    
+    fn extract_sections(&self, file_path: &Path, file_data: &[u8]) -> Result<Vec<Section>> {
         // Check file size limit
         if file_data.len() > self.max_file_size {
             anyhow::bail!(
@@ -167,63 +510,63 @@ impl DocumentProcessor {
         let format = DocumentFormat::from_extension(extension)
             .ok_or_else(|| anyhow::anyhow!("Unsupported file format: {}", extension))?;
 
-        let text = match format {
-            DocumentFormat::Pdf => self.extract_text_from_pdf(file_data)?,
-            DocumentFormat::Text => self.extract_text_from_text(file_data)?,
-            DocumentFormat::Html => self.extract_text_from_html(file_data)?,
-            DocumentFormat::Docx => self.extract_text_from_docx(file_data)?,
-            DocumentFormat::Pptx => self.extract_text_from_pptx(file_data)?,
-            DocumentFormat::Xlsx => self.extract_text_from_xlsx(file_data)?,
+        if matches!(format, DocumentFormat::Docx | DocumentFormat::Pptx | DocumentFormat::Xlsx) && is_encrypted_office(file_data) {
+            return Err(PortableBrainsError::Encrypted(
+                "the Office document is password-protected, which isn't supported; save a copy without the password to index it".to_string(),
+            ).into());
+        }
+
+        let sections = match format {
+            DocumentFormat::Pdf => self.extract_sections_from_pdf(file_data, Some(file_path))?,
+            DocumentFormat::Text => vec![Section::untitled(self.extract_text_from_text(file_data)?)],
+            DocumentFormat::Html => vec![Section::untitled(self.extract_text_from_html(file_data)?)],
+            DocumentFormat::Docx => self.extract_sections_from_docx(file_data)?,
+            DocumentFormat::Pptx => self.extract_sections_from_pptx(file_data)?,
+            DocumentFormat::Xlsx => self.extract_sections_from_xlsx(file_data)?,
+            DocumentFormat::Markdown => self.extract_sections_from_markdown(file_data)?,
+            DocumentFormat::Epub => self.extract_sections_from_epub(file_data)?,
+            DocumentFormat::Csv => vec![Section::untitled(self.extract_text_from_delimited(file_data, b',', &format)?)],
+            DocumentFormat::Tsv => vec![Section::untitled(self.extract_text_from_delimited(file_data, b'\t', &format)?)],
+            DocumentFormat::Email => vec![Section::untitled(self.extract_text_from_email(file_data)?)],
         };
 
-        if text.trim().is_empty() {
+        if sections.iter().all(|section| section.text.trim().is_empty()) {
             anyhow::bail!("No text could be extracted from file: {:?}", file_path);
         }
 
-        Ok(text)
+        Ok(sections)
     }
 
     /// Extract text from plain text files
     fn extract_text_from_text(&self, file_data: &[u8]) -> Result<String> {
         let text = String::from_utf8_lossy(file_data).to_string();
-        let cleaned_text = self.cleanup_text(&text);
+        let cleaned_text = self.cleanup_text(&text, &DocumentFormat::Text);
         
-        if cleaned_text.len() > self.max_text_length {
-            let truncated: String = cleaned_text.chars().take(self.max_text_length).collect();
-            warn!("Text file truncated to {} characters", self.max_text_length);
-            Ok(truncated)
-        } else {
-            Ok(cleaned_text)
-        }
+        Ok(cleaned_text)
     }
 
     /// Extract text from HTML files
     fn extract_text_from_html(&self, file_data: &[u8]) -> Result<String> {
         let html_content = String::from_utf8_lossy(file_data);
         let document = Html::parse_document(&html_content);
+        let text_content = self.extract_text_from_html_document(&document);
+        let cleaned_text = self.cleanup_text(&text_content, &DocumentFormat::Html);
         
+        Ok(cleaned_text)
+    }
+
+    /// Text of a parsed HTML document's body, or of the whole document if it has none
+    fn extract_text_from_html_document(&self, document: &Html) -> String {
         // Remove script and style elements
         let script_selector = Selector::parse("script, style").unwrap();
         let text_selector = Selector::parse("body").unwrap();
         
-        let mut text_content = String::new();
-        
         // Try to get body content first, fallback to full document
         if let Some(body) = document.select(&text_selector).next() {
-            text_content = self.extract_text_from_html_element(&body, &script_selector);
+            self.extract_text_from_html_element(&body, &script_selector)
         } else {
             // No body tag, extract from entire document
-            text_content = document.root_element().text().collect::<Vec<_>>().join(" ");
-        }
-        
-        let cleaned_text = self.cleanup_text(&text_content);
-        
-        if cleaned_text.len() > self.max_text_length {
-            let truncated: String = cleaned_text.chars().take(self.max_text_length).collect();
-            warn!("HTML file truncated to {} characters", self.max_text_length);
-            Ok(truncated)
-        } else {
-            Ok(cleaned_text)
+            document.root_element().text().collect::<Vec<_>>().join(" ")
         }
     }
 
@@ -233,12 +576,22 @@ impl DocumentProcessor {
         element.text().collect::<Vec<_>>().join(" ")
     }
 
-    /// Extract text from DOCX files
-    fn extract_text_from_docx(&self, file_data: &[u8]) -> Result<String> {
+    /// Extract text from DOCX files, split into sections at heading paragraphs
+    fn extract_sections_from_docx(&self, file_data: &[u8]) -> Result<Vec<Section>> {
         let cursor = Cursor::new(file_data);
         let mut archive = ZipArchive::new(cursor)
             .context("Failed to open DOCX file as ZIP archive")?;
         
+        // styles.xml says which paragraph styles are headings; it is optional
+        let mut styles_xml = String::new();
+        if let Ok(mut styles) = archive.by_name("word/styles.xml") {
+            if let Err(e) = styles.read_to_string(&mut styles_xml) {
+                debug!("Failed to read styles.xml: {}", e);
+                styles_xml.clear();
+            }
+        }
+        let heading_styles = docx_heading_styles(&styles_xml);
+        
         // Extract document.xml which contains the main content
         let mut document_xml = archive.by_name("word/document.xml")
             .context("Failed to find document.xml in DOCX file")?;
@@ -247,71 +600,61 @@ impl DocumentProcessor {
         document_xml.read_to_string(&mut xml_content)
             .context("Failed to read document.xml content")?;
         
-        let text = self.extract_text_from_docx_xml(&xml_content)?;
-        let cleaned_text = self.cleanup_text(&text);
-        
-        if cleaned_text.len() > self.max_text_length {
-            let truncated: String = cleaned_text.chars().take(self.max_text_length).collect();
-            warn!("DOCX file truncated to {} characters", self.max_text_length);
-            Ok(truncated)
-        } else {
-            Ok(cleaned_text)
-        }
+        self.extract_sections_from_docx_xml(&xml_content, &heading_styles)
     }
 
-    /// Extract text from PowerPoint PPTX files
-    fn extract_text_from_pptx(&self, file_data: &[u8]) -> Result<String> {
+    /// Extract text from PowerPoint PPTX files, a section per slide in slide order
+    fn extract_sections_from_pptx(&self, file_data: &[u8]) -> Result<Vec<Section>> {
         let cursor = Cursor::new(file_data);
         let mut archive = ZipArchive::new(cursor)
             .context("Failed to open PPTX file as ZIP archive")?;
         
-        let mut all_text = String::new();
+        let mut slides = Vec::new();
         
-        // Extract text from all slides
+        // Extract text from all slides; archive order isn't slide order, the file names are
         for i in 0..archive.len() {
             let mut file = archive.by_index(i)?;
             let file_name = file.name().to_string();
             
             // Look for slide XML files
-            if file_name.starts_with("ppt/slides/slide") && file_name.ends_with(".xml") {
+            let number = file_name.strip_prefix("ppt/slides/slide")
+                .and_then(|rest| rest.strip_suffix(".xml"))
+                .and_then(|number| number.parse::<u32>().ok());
+            if let Some(number) = number {
                 let mut xml_content = String::new();
                 file.read_to_string(&mut xml_content)
                     .context("Failed to read slide XML content")?;
                 
-                let slide_text = self.extract_text_from_pptx_xml(&xml_content)?;
-                if !slide_text.is_empty() {
-                    all_text.push_str(&slide_text);
-                    all_text.push('\n');
-                }
+                slides.push((number, self.extract_text_from_pptx_xml(&xml_content)?));
             }
         }
+        slides.sort_by_key(|(number, _)| *number);
         
-        let cleaned_text = self.cleanup_text(&all_text);
-        
-        if cleaned_text.len() > self.max_text_length {
-            let truncated: String = cleaned_text.chars().take(self.max_text_length).collect();
-            warn!("PPTX file truncated to {} characters", self.max_text_length);
-            Ok(truncated)
-        } else {
-            Ok(cleaned_text)
-        }
+        Ok(slides.into_iter()
+            .filter_map(|(number, slide_text)| {
+                let text = self.cleanup_text(&slide_text, &DocumentFormat::Pptx);
+                (!text.is_empty()).then(|| Section { location: Some(Location::Slide(number)), ..Section::untitled(text) })
+            })
+            .collect())
     }
 
-    /// Extract text from Excel XLSX files
-    fn extract_text_from_xlsx(&self, file_data: &[u8]) -> Result<String> {
+    /// Extract text from Excel XLSX files, a section per worksheet
+    fn extract_sections_from_xlsx(&self, file_data: &[u8]) -> Result<Vec<Section>> {
         // Create a temporary file for calamine to read
         let temp_path = std::env::temp_dir().join(format!("temp_excel_{}.xlsx", Uuid::new_v4()));
         std::fs::write(&temp_path, file_data)?;
         
-        let result = (|| -> Result<String> {
+        let result = (|| -> Result<Vec<(String, String)>> {
             let mut workbook = open_workbook_auto(&temp_path)
                 .context("Failed to open XLSX file")?;
             
-            let mut all_text = String::new();
+            let mut sheets = Vec::new();
             
             // Process all worksheets
             for sheet_name in workbook.sheet_names().to_vec() {
                 if let Some(Ok(range)) = workbook.worksheet_range(&sheet_name) {
+                    let mut sheet_text = String::new();
+                    
                     // Extract text from all cells
                     for row in range.rows() {
                         let mut row_text = Vec::new();
@@ -330,46 +673,273 @@ impl DocumentProcessor {
                         }
                         
                         if !row_text.is_empty() {
-                            all_text.push_str(&row_text.join(" | "));
-                            all_text.push('\n');
+                            sheet_text.push_str(&row_text.join(" | "));
+                            sheet_text.push('\n');
                         }
                     }
                     
-                    // Add sheet separator
-                    all_text.push_str(&format!("\n--- End of sheet: {} ---\n", sheet_name));
+                    sheets.push((sheet_name, sheet_text));
                 }
             }
             
-            Ok(all_text)
+            Ok(sheets)
         })();
         
         // Clean up temp file
         let _ = std::fs::remove_file(&temp_path);
         
-        let all_text = result?;
-        let cleaned_text = self.cleanup_text(&all_text);
+        Ok(result?.into_iter()
+            .filter_map(|(sheet_name, sheet_text)| {
+                let text = self.cleanup_text(&sheet_text, &DocumentFormat::Xlsx);
+                (!text.is_empty()).then(|| Section { location: Some(Location::Sheet(sheet_name)), ..Section::untitled(text) })
+            })
+            .collect())
+    }
+
+    /// Extract text from Markdown files, split into sections at ATX (`#`) and setext
+    /// (underlined) headings. Code blocks, tables and lists keep their markup so chunking
+    /// keeps them whole; links, images and emphasis are reduced to their text.
+    fn extract_sections_from_markdown(&self, file_data: &[u8]) -> Result<Vec<Section>> {
+        let text = String::from_utf8_lossy(file_data);
+        let inline_rules = markdown_inline_rules();
+        let heading = Regex::new(r"^ {0,3}(#{1,6})(?:\s+(.*?))?(?:\s+#+)?\s*$").unwrap();
+        let reference = Regex::new(r"^ {0,3}\[[^\]]+\]:\s*\S").unwrap();
         
-        if cleaned_text.len() > self.max_text_length {
-            let truncated: String = cleaned_text.chars().take(self.max_text_length).collect();
-            warn!("XLSX file truncated to {} characters", self.max_text_length);
-            Ok(truncated)
-        } else {
-            Ok(cleaned_text)
+        let mut sections = Vec::new();
+        let mut path: Vec<String> = Vec::new();
+        let mut lines: Vec<String> = Vec::new();
+        let mut fence: Option<&str> = None;
+        
+        for line in strip_front_matter(&text).lines() {
+            if let Some(open) = fence {
+                if line.trim_start().starts_with(open) {
+                    fence = None;
+                }
+                lines.push(line.to_string());
+                continue;
+            }
+            if let Some(open) = code_fence(line.trim_start()) {
+                fence = Some(open);
+                lines.push(line.to_string());
+                continue;
+            }
+            if reference.is_match(line) {
+                continue;
+            }
+            
+            // A setext underline turns the paragraph line above it into a heading
+            let trimmed = line.trim();
+            let underline = if !trimmed.is_empty() && trimmed.chars().all(|c| c == '=') {
+                Some(0)
+            } else if trimmed.len() >= 2 && trimmed.chars().all(|c| c == '-') {
+                Some(1)
+            } else {
+                None
+            };
+            let title = match underline {
+                Some(level) if lines.last().is_some_and(|above| is_setext_title(above)) => {
+                    lines.pop().map(|title| (level, title))
+                }
+                _ => heading.captures(line).map(|captures| {
+                    let level = captures[1].len() - 1;
+                    (level, markdown_inline(captures.get(2).map_or("", |title| title.as_str()), &inline_rules))
+                }),
+            };
+            
+            match title {
+                Some((level, title)) => {
+                    let title = title.split_whitespace().collect::<Vec<_>>().join(" ");
+                    if title.is_empty() {
+                        continue;
+                    }
+                    self.push_section(&mut sections, &path, &lines.join("\n"), &DocumentFormat::Markdown, None);
+                    lines.clear();
+                    path.truncate(level);
+                    path.push(title.clone());
+                    // Headings stay part of their section's text, as for DOCX
+                    lines.push(title);
+                }
+                None => lines.push(markdown_inline(line, &inline_rules)),
+            }
+        }
+        self.push_section(&mut sections, &path, &lines.join("\n"), &DocumentFormat::Markdown, None);
+        
+        Ok(sections)
+    }
+
+    /// Extract text from EPUB files, one section per chapter in reading order, titled by
+    /// the chapter's first heading
+    fn extract_sections_from_epub(&self, file_data: &[u8]) -> Result<Vec<Section>> {
+        let cursor = Cursor::new(file_data);
+        let mut archive = ZipArchive::new(cursor)
+            .context("Failed to open EPUB file as ZIP archive")?;
+        
+        // container.xml points at the package document, which lists the chapters
+        let container = read_zip_text(&mut archive, "META-INF/container.xml")
+            .context("Failed to find container.xml in EPUB file")?;
+        let package_path = epub_package_path(&container)
+            .ok_or_else(|| anyhow::anyhow!("EPUB container.xml names no package document"))?;
+        let package = read_zip_text(&mut archive, &package_path)
+            .with_context(|| format!("Failed to read EPUB package document {}", package_path))?;
+        let base = package_path.rsplit_once('/').map_or("", |(dir, _)| dir);
+        
+        let heading_selector = Selector::parse("h1, h2, h3, title").unwrap();
+        let mut sections = Vec::new();
+        for href in epub_spine(&package) {
+            let name = resolve_zip_path(base, &href);
+            let chapter = match read_zip_text(&mut archive, &name) {
+                Ok(chapter) => chapter,
+                Err(e) => {
+                    warn!("Skipping EPUB chapter {}: {:#}", name, e);
+                    continue;
+                }
+            };
+            
+            let document = Html::parse_document(&chapter);
+            let title = document.select(&heading_selector)
+                .map(|heading| heading.text().collect::<Vec<_>>().join(" ").split_whitespace().collect::<Vec<_>>().join(" "))
+                .find(|title| !title.is_empty());
+            let text = self.extract_text_from_html_document(&document);
+            self.push_section(&mut sections, &title.into_iter().collect::<Vec<_>>(), &text, &DocumentFormat::Epub, None);
+        }
+        
+        Ok(sections)
+    }
+
+    /// Extract text from CSV or TSV files, one line per row with cells joined as for XLSX
+    fn extract_text_from_delimited(&self, file_data: &[u8], delimiter: u8, format: &DocumentFormat) -> Result<String> {
+        let mut reader = csv::ReaderBuilder::new()
+            .delimiter(delimiter)
+            .has_headers(false)
+            .flexible(true)
+            .from_reader(file_data);
+        
+        let mut all_text = String::new();
+        for (i, record) in reader.byte_records().enumerate() {
+            let record = record.with_context(|| format!("Failed to read row {}", i + 1))?;
+            let row_text: Vec<Cow<str>> = record.iter()
+                .map(String::from_utf8_lossy)
+                .filter(|cell| !cell.trim().is_empty())
+                .collect();
+            if !row_text.is_empty() {
+                all_text.push_str(&row_text.join(" | "));
+                all_text.push('\n');
+            }
+        }
+        
+        let cleaned_text = self.cleanup_text(&all_text, format);
+        
+        Ok(cleaned_text)
+    }
+
+    /// Extract text from RFC 822 email (.eml) files: the sender, recipients, date and
+    /// subject, then the message body. HTML-only messages are read like HTML files;
+    /// attachments are skipped.
+    fn extract_text_from_email(&self, file_data: &[u8]) -> Result<String> {
+        let message = mail_parser::MessageParser::default()
+            .parse(file_data)
+            .ok_or_else(|| anyhow::anyhow!("Failed to parse email message"))?;
+        
+        let mut all_text = String::new();
+        let addresses = |address: Option<&mail_parser::Address>| address
+            .map(|address| address.iter()
+                .map(|addr| match (addr.name(), addr.address()) {
+                    (Some(name), Some(email)) => format!("{} <{}>", name, email),
+                    (name, email) => name.or(email).unwrap_or_default().to_string(),
+                })
+                .collect::<Vec<_>>()
+                .join(", "))
+            .filter(|list| !list.is_empty());
+        let headers = [
+            ("From", addresses(message.from())),
+            ("To", addresses(message.to())),
+            ("Cc", addresses(message.cc())),
+            ("Date", message.date().map(|date| date.to_rfc3339())),
+            ("Subject", message.subject().map(str::to_string)),
+        ];
+        for (name, value) in headers {
+            if let Some(value) = value {
+                all_text.push_str(&format!("{}: {}\n", name, value));
+            }
+        }
+        all_text.push('\n');
+        
+        // A message with only an HTML body lists that part as its text body too
+        for part in message.text_body.iter().filter_map(|&id| message.part(id)) {
+            let Some(body) = part.text_contents() else {
+                continue;
+            };
+            if part.is_text_html() {
+                all_text.push_str(&self.extract_text_from_html_document(&Html::parse_document(body)));
+            } else {
+                all_text.push_str(body);
+            }
+            all_text.push_str("\n\n");
         }
+        
+        let cleaned_text = self.cleanup_text(&all_text, &DocumentFormat::Email);
+        
+        Ok(cleaned_text)
     }
 
-    /// Extract text from DOCX XML content
-    fn extract_text_from_docx_xml(&self, xml_content: &str) -> Result<String> {
+    /// Extract text from DOCX XML content, starting a new section at each heading paragraph.
+    /// `heading_styles` maps paragraph style ids to outline levels (0 = top).
+    fn extract_sections_from_docx_xml(&self, xml_content: &str, heading_styles: &HashMap<String, usize>) -> Result<Vec<Section>> {
         let mut reader = XmlReader::from_str(xml_content);
+        let mut sections = Vec::new();
+        let mut path: Vec<String> = Vec::new();
         let mut text_content = String::new();
         let mut buf = Vec::new();
         
+        // The paragraph being read: its text and, if it is a heading, its level and title
+        let mut in_paragraph = false;
+        let mut paragraph = String::new();
+        let mut level: Option<usize> = None;
+        let mut title = String::new();
+        
         loop {
             match reader.read_event_into(&mut buf) {
+                Ok(Event::Start(e)) | Ok(Event::Empty(e)) => match e.name().as_ref() {
+                    b"w:p" => {
+                        in_paragraph = true;
+                        paragraph.clear();
+                        title.clear();
+                        level = None;
+                    }
+                    b"w:pStyle" if in_paragraph => {
+                        level = xml_attribute(&e, b"w:val")
+                            .and_then(|style| heading_styles.get(&style).copied()
+                                .or_else(|| heading_level_from_style_id(&style)));
+                    }
+                    // Direct formatting overrides the style's level; 9 is body text
+                    b"w:outlineLvl" if in_paragraph => {
+                        if let Some(outline) = xml_attribute(&e, b"w:val").and_then(|v| v.parse::<usize>().ok()) {
+                            level = (outline < 9).then_some(outline);
+                        }
+                    }
+                    _ => {}
+                },
+                Ok(Event::End(e)) if e.name().as_ref() == b"w:p" => {
+                    in_paragraph = false;
+                    let heading = title.split_whitespace().collect::<Vec<_>>().join(" ");
+                    if let (Some(level), false) = (level, heading.is_empty()) {
+                        self.push_section(&mut sections, &path, &text_content, &DocumentFormat::Docx, None);
+                        text_content.clear();
+                        path.truncate(level);
+                        path.push(heading);
+                    }
+                    text_content.push_str(&paragraph);
+                }
                 Ok(Event::Text(e)) => {
                     if let Ok(text) = e.unescape() {
-                        text_content.push_str(&text);
-                        text_content.push(' ');
+                        if in_paragraph {
+                            paragraph.push_str(&text);
+                            paragraph.push(' ');
+                            title.push_str(&text);
+                        } else {
+                            text_content.push_str(&text);
+                            text_content.push(' ');
+                        }
                     }
                 }
                 Ok(Event::Eof) => break,
@@ -382,7 +952,11 @@ impl DocumentProcessor {
             buf.clear();
         }
         
-        Ok(text_content)
+        // A paragraph cut off by a parse error still counts
+        text_content.push_str(&paragraph);
+        self.push_section(&mut sections, &path, &text_content, &DocumentFormat::Docx, None);
+        
+        Ok(sections)
     }
 
     /// Extract text from PPTX XML content
@@ -426,56 +1000,254 @@ impl DocumentProcessor {
         Ok(text_content)
     }
     
-    /// Chunk text with memory-efficient processing
-    pub fn chunk_text(&self, text: &str) -> anyhow::Result<Vec<String>> {
+    /// Split extracted text into continuation segments of at most `segment_length` bytes,
+    /// breaking at a paragraph or sentence end where possible, so a long book is chunked
+    /// in sequence rather than cut off
+    pub fn split_segments<'a>(&self, text: &'a str) -> Vec<&'a str> {
+        let mut segments = Vec::new();
+        let mut rest = text;
+        
+        while rest.len() > self.segment_length {
+            let mut limit = self.segment_length;
+            while !rest.is_char_boundary(limit) {
+                limit -= 1;
+            }
+            
+            let window = &rest[..limit];
+            let mut split = window.rfind("\n\n").map(|i| i + 2)
+                .or_else(|| window.rfind(". ").map(|i| i + 2))
+                .filter(|&i| i > limit / 2)
+                .unwrap_or(limit);
+            // A code block left open would be split across segments; start it in the next one
+            if let Some(fence) = open_code_fence(&rest[..split]).filter(|&i| i > 0) {
+                split = fence;
+            }
+            
+            segments.push(&rest[..split]);
+            rest = &rest[split..];
+        }
+        
+        if !rest.trim().is_empty() {
+            segments.push(rest);
+        }
+        segments
+    }
+    
+    /// Split sections into semantic chunks, one continuation segment at a time so long
+    /// documents are chunked in full; chunks never straddle a section boundary. Chunks found
+    /// verbatim in the text get their byte range in `join_sections`' text as their span.
+    pub fn chunk_sections(&self, sections: &[Section]) -> Result<Vec<(String, FragmentMeta)>> {
+        let mut fragments = Vec::new();
+        let mut section_start = 0;
+        for section in sections {
+            let label = section.label();
+            // Segments are consecutive slices of the section's text
+            let mut segment_start = section_start;
+            for (segment, part) in self.split_segments(&section.text).into_iter().enumerate() {
+                let chunks = self.chunk_blocks(part)
+                    .with_context(|| format!("Failed to chunk text segment {}", segment))?;
+                // Chunks come in order but may overlap, so each is looked for from just after
+                // the previous one's start
+                let mut cursor = 0;
+                for (content, structure) in chunks {
+                    let span = part[cursor..].find(content.as_str()).map(|found| {
+                        let start = cursor + found;
+                        cursor = start + content.chars().next().map_or(1, char::len_utf8);
+                        (segment_start + start, segment_start + start + content.len())
+                    });
+                    let meta = FragmentMeta { segment: segment as u32, section: label.clone(), page: section.page, location: section.location.clone(), structure, span };
+                    fragments.push((content, meta));
+                }
+                segment_start += part.len();
+            }
+            // `join_sections` separates sections with a blank line
+            section_start += section.text.len() + 2;
+        }
+        Ok(fragments)
+    }
+    
+    /// Chunk text made of prose and structured blocks: prose is split into sentences, while
+    /// code blocks, tables and lists become fragments of their own, tagged with their kind.
+    /// A block longer than the chunk size is split between lines, rows or items only.
+    pub fn chunk_blocks(&self, text: &str) -> Result<Vec<(String, Option<Structure>)>> {
         let mut chunks = Vec::new();
+        for (structure, block) in text_blocks(text) {
+            match structure {
+                None => chunks.extend(self.chunk_text(&block)?.into_iter().map(|chunk| (chunk, None))),
+                Some(structure) => chunks.extend(
+                    self.split_structure(structure, &block).into_iter().map(|chunk| (chunk, Some(structure)))
+                ),
+            }
+        }
+        Ok(chunks)
+    }
+    
+    /// Pieces of a structured block that fit the chunk size, each whole lines of it. Code
+    /// pieces are fenced again, table pieces repeat the header, and list pieces start at an item.
+    fn split_structure(&self, structure: Structure, block: &str) -> Vec<String> {
+        if self.measure(block) <= self.chunk_size {
+            return vec![block.to_string()];
+        }
         
-        if text.is_empty() {
-            return Ok(chunks);
+        let lines: Vec<&str> = block.lines().collect();
+        let (head, tail, body): (&[&str], &[&str], &[&str]) = match structure {
+            Structure::Code => {
+                let closed = lines.len() > 1 && code_fence(lines[lines.len() - 1].trim_start()).is_some();
+                let end = if closed { lines.len() - 1 } else { lines.len() };
+                (&lines[..1], &lines[end..], &lines[1..end])
+            }
+            Structure::Table => {
+                let header = if lines.len() > 1 && is_table_delimiter(lines[1]) { 2 } else { 1 };
+                (&lines[..header], &[], &lines[header..])
+            }
+            Structure::List => (&[], &[], &lines[..]),
+        };
+        
+        // Units that are never split: lines, rows, or an item with its nested lines
+        let item_indent = body.first().map_or(0, |line| indentation(line));
+        let mut units: Vec<Vec<&str>> = Vec::new();
+        for &line in body {
+            let starts_item = indentation(line) <= item_indent && is_list_item(line.trim_start());
+            match units.last_mut() {
+                Some(unit) if structure == Structure::List && !starts_item => unit.push(line),
+                _ => units.push(vec![line]),
+            }
         }
         
-        debug!("Starting sentence-based chunking of {} chars", text.len());
+        let frame = head.iter().chain(tail).map(|line| self.measure(line) + 1).sum::<usize>();
+        let mut pieces = Vec::new();
+        let mut current: Vec<&str> = Vec::new();
+        let mut length = frame;
+        for unit in units {
+            let unit_length = unit.iter().map(|line| self.measure(line) + 1).sum::<usize>();
+            if !current.is_empty() && length + unit_length > self.chunk_size {
+                pieces.push(head.iter().chain(&current).chain(tail).copied().collect::<Vec<_>>().join("\n"));
+                current.clear();
+                length = frame;
+            }
+            current.extend(unit);
+            length += unit_length;
+        }
+        if !current.is_empty() {
+            pieces.push(head.iter().chain(&current).chain(tail).copied().collect::<Vec<_>>().join("\n"));
+        }
+        pieces
+    }
+    
+    /// Chunk prose with the processor's strategy
+    pub fn chunk_text(&self, text: &str) -> anyhow::Result<Vec<String>> {
+        if text.is_empty() {
+            return Ok(Vec::new());
+        }
+        if self.strategy == ChunkingStrategy::Token && self.tokenizer.is_none() {
+            anyhow::bail!("Token chunking needs a tokenizer; use with_token_chunking");
+        }
         
-        // Split text into sentences first
-        let sentences = self.split_into_sentences(text);
-        debug!("Found {} sentences", sentences.len());
+        debug!("Starting {} chunking of {} chars", self.strategy.name(), text.len());
+        let chunks = match self.strategy {
+            ChunkingStrategy::Sentence | ChunkingStrategy::Token => {
+                self.pack(&self.split_into_sentences(text), " ")
+            }
+            ChunkingStrategy::Paragraph => {
+                // Short paragraphs are packed together; a long one is chunked on its own
+                let mut chunks = Vec::new();
+                let mut pending = Vec::new();
+                for paragraph in paragraphs(text) {
+                    if self.measure(&paragraph) <= self.chunk_size {
+                        pending.push(paragraph);
+                        continue;
+                    }
+                    chunks.extend(self.pack(&pending, "\n\n"));
+                    pending.clear();
+                    chunks.extend(self.pack(&self.split_into_sentences(&paragraph), " "));
+                }
+                chunks.extend(self.pack(&pending, "\n\n"));
+                chunks
+            }
+            ChunkingStrategy::Recursive => self.pack(&self.split_recursive(text, 0), " "),
+        };
         
-        let mut current_chunk = String::new();
-        let mut i = 0;
+        debug!("Created {} chunks", chunks.len());
+        Ok(chunks)
+    }
+    
+    /// Length of text in the unit chunk sizes are given in: tokens for the token strategy,
+    /// otherwise bytes
+    fn measure(&self, text: &str) -> usize {
+        match &self.tokenizer {
+            Some(tokenizer) if self.strategy == ChunkingStrategy::Token => tokenizer.encode(text, false)
+                .map(|encoding| encoding.len())
+                .unwrap_or(text.len() / CHARS_PER_TOKEN),
+            _ => text.len(),
+        }
+    }
+    
+    /// Pack consecutive units into chunks of at most the chunk size (a unit longer than that
+    /// becomes a chunk of its own), starting each chunk after the first with up to `overlap`
+    /// of the units before it
+    fn pack(&self, units: &[String], separator: &str) -> Vec<String> {
+        let sizes: Vec<usize> = units.iter().map(|unit| self.measure(unit)).collect();
+        // Separators don't add tokens worth counting
+        let gap = if self.strategy == ChunkingStrategy::Token { 0 } else { separator.len() };
         
-        while i < sentences.len() {
-            let sentence = &sentences[i];
-            
-            // If adding this sentence would exceed chunk size, finalize current chunk
-            if !current_chunk.is_empty() && 
-               (current_chunk.len() + sentence.len() + 1) > self.chunk_size {
+        let mut chunks = Vec::new();
+        let mut current: Vec<usize> = Vec::new();
+        let mut size = 0;
+        for i in 0..units.len() {
+            if !current.is_empty() && size + gap + sizes[i] > self.chunk_size {
+                push_chunk(&mut chunks, &current, units, separator);
                 
-                let trimmed_chunk = current_chunk.trim();
-                if !trimmed_chunk.is_empty() && trimmed_chunk.len() > 10 {
-                    chunks.push(trimmed_chunk.to_string());
+                // Start the next chunk with overlap from the units just packed
+                current.clear();
+                size = 0;
+                if !chunks.is_empty() {
+                    for j in (0..i).rev() {
+                        if size + sizes[j] + gap > self.overlap {
+                            break;
+                        }
+                        current.insert(0, j);
+                        size += sizes[j] + gap;
+                    }
                 }
-                
-                // Start new chunk with overlap
-                current_chunk = self.create_overlap_chunk(&chunks, &sentences, i);
+                size = size.saturating_sub(gap);
             }
             
-            // Add current sentence to chunk
-            if !current_chunk.is_empty() {
-                current_chunk.push(' ');
+            if !current.is_empty() {
+                size += gap;
             }
-            current_chunk.push_str(sentence);
-            
-            i += 1;
+            current.push(i);
+            size += sizes[i];
         }
-        
-        // Add final chunk if it has content
-        let final_chunk = current_chunk.trim();
-        if !final_chunk.is_empty() && final_chunk.len() > 10 {
-            chunks.push(final_chunk.to_string());
+        push_chunk(&mut chunks, &current, units, separator);
+        chunks
+    }
+    
+    /// Pieces of text no longer than the chunk size, split at paragraphs, then lines,
+    /// sentences, words and finally characters, only as deep as a piece needs
+    fn split_recursive(&self, text: &str, level: usize) -> Vec<String> {
+        let text = text.trim();
+        if text.is_empty() {
+            return Vec::new();
+        }
+        if self.measure(text) <= self.chunk_size {
+            return vec![text.to_string()];
         }
         
-        debug!("Created {} chunks using sentence-based segmentation", chunks.len());
-        Ok(chunks)
+        let parts: Vec<String> = match level {
+            0 => paragraphs(text),
+            1 => text.lines().map(str::to_string).collect(),
+            2 => self.split_into_sentences(text),
+            3 => text.split_whitespace().map(str::to_string).collect(),
+            _ => {
+                let width = match self.strategy {
+                    ChunkingStrategy::Token => self.chunk_size * CHARS_PER_TOKEN,
+                    _ => self.chunk_size,
+                };
+                let chars: Vec<char> = text.chars().collect();
+                return chars.chunks(width.max(1)).map(|piece| piece.iter().collect()).collect();
+            }
+        };
+        parts.iter().flat_map(|part| self.split_recursive(part, level + 1)).collect()
     }
     
     fn split_into_sentences(&self, text: &str) -> Vec<String> {
@@ -531,42 +1303,51 @@ impl DocumentProcessor {
         sentences
     }
     
-    fn create_overlap_chunk(&self, chunks: &[String], sentences: &[String], current_index: usize) -> String {
-        if chunks.is_empty() || self.overlap == 0 {
-            return String::new();
-        }
-        
-        // Try to create overlap by including some sentences from the previous chunk
-        let mut overlap_text = String::new();
-        let mut overlap_chars = 0;
+    fn cleanup_text(&self, text: &str, format: &DocumentFormat) -> String {
+        let overrides = self.cleanup.formats.get(format);
         
-        // Work backwards from current sentence to find overlap content
-        for i in (0..current_index).rev() {
-            let sentence = &sentences[i];
-            if overlap_chars + sentence.len() + 1 <= self.overlap {
-                if !overlap_text.is_empty() {
-                    overlap_text = format!("{} {}", sentence, overlap_text);
-                } else {
-                    overlap_text = sentence.clone();
+        // Custom rules run first so they can match across the original line breaks
+        let mut text = Cow::Borrowed(text);
+        if !overrides.is_some_and(|o| o.skip_rules) {
+            for rule in &self.cleanup.rules {
+                if rule.formats.is_empty() || rule.formats.contains(format) {
+                    text = Cow::Owned(rule.regex.replace_all(&text, rule.replace.as_str()).into_owned());
                 }
-                overlap_chars += sentence.len() + 1;
-            } else {
-                break;
             }
         }
         
-        overlap_text
+        // Code blocks, tables and lists keep their line breaks so the chunker can find them
+        let collapse = overrides.and_then(|o| o.collapse_whitespace).unwrap_or(self.cleanup.collapse_whitespace);
+        text_blocks(&text)
+            .into_iter()
+            .map(|(structure, block)| match structure {
+                None => self.cleanup_prose(&block, collapse),
+                Some(Structure::Code) => block.lines()
+                    .map(|line| without_control_chars(line.trim_end()))
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+                Some(_) => block.lines()
+                    .filter(|line| !line.trim().is_empty())
+                    .map(|line| {
+                        let content = line.trim_start();
+                        let content = if collapse { self.cleanup_regex.replace_all(content, " ") } else { Cow::Borrowed(content) };
+                        let indent = &line[..line.len() - line.trim_start().len()];
+                        without_control_chars(&format!("{}{}", indent, content.trim_end()))
+                    })
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+            })
+            .filter(|block| !block.trim().is_empty())
+            .collect::<Vec<_>>()
+            .join("\n\n")
     }
     
-    fn cleanup_text(&self, text: &str) -> String {
+    fn cleanup_prose(&self, text: &str, collapse: bool) -> String {
         // Remove excessive whitespace and normalize line breaks
-        let normalized = self.cleanup_regex.replace_all(text, " ");
+        let normalized = if collapse { self.cleanup_regex.replace_all(text, " ") } else { Cow::Borrowed(text) };
         
         // Remove control characters but keep basic punctuation
-        let cleaned: String = normalized
-            .chars()
-            .filter(|c| !c.is_control() || *c == '\n' || *c == '\t')
-            .collect();
+        let cleaned = without_control_chars(&normalized);
         
         // Normalize paragraph breaks
         let with_paragraphs = cleaned
@@ -580,6 +1361,458 @@ impl DocumentProcessor {
     }
 }
 
+/// Whether a .docx, .pptx or .xlsx is a password-protected one. Those aren't ZIP archives but
+/// OLE compound files holding the encrypted package in an `EncryptedPackage` stream, whose name
+/// is stored in UTF-16 in the file's directory.
+fn is_encrypted_office(file_data: &[u8]) -> bool {
+    const COMPOUND_FILE: [u8; 8] = [0xD0, 0xCF, 0x11, 0xE0, 0xA1, 0xB1, 0x1A, 0xE1];
+    if !file_data.starts_with(&COMPOUND_FILE) {
+        return false;
+    }
+    let stream: Vec<u8> = "EncryptedPackage".encode_utf16().flat_map(u16::to_le_bytes).collect();
+    file_data.windows(stream.len()).any(|window| window == stream.as_slice())
+}
+
+/// A bookmark from a PDF's outline
+struct PdfHeading {
+    /// Nesting depth, 0 for top-level bookmarks
+    level: usize,
+    title: String,
+    page: u32,
+}
+
+/// Flatten a PDF's bookmarks in outline order; empty when it has none or they can't be read
+fn pdf_outline(document: &Document) -> Vec<PdfHeading> {
+    // lopdf indexes into destination arrays unchecked, so a malformed outline can panic;
+    // losing the outline is better than losing the document
+    let outlines = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        document.get_outlines(None, None, &mut std::collections::BTreeMap::new())
+    }));
+    let outlines = match outlines {
+        Ok(Ok(Some(outlines))) => outlines,
+        Ok(Ok(None)) => return Vec::new(),
+        Ok(Err(e)) => {
+            debug!("No usable PDF outline: {}", e);
+            return Vec::new();
+        }
+        Err(_) => {
+            warn!("Malformed PDF outline; extracting without sections");
+            return Vec::new();
+        }
+    };
+    
+    let page_numbers: HashMap<lopdf::ObjectId, u32> = document.get_pages()
+        .into_iter()
+        .map(|(number, id)| (id, number))
+        .collect();
+    
+    let mut headings = Vec::new();
+    collect_pdf_headings(&outlines, 0, &page_numbers, &mut headings);
+    headings
+}
+
+fn collect_pdf_headings(
+    outlines: &[lopdf::Outline],
+    level: usize,
+    page_numbers: &HashMap<lopdf::ObjectId, u32>,
+    headings: &mut Vec<PdfHeading>,
+) {
+    for outline in outlines {
+        match outline {
+            lopdf::Outline::Destination(destination) => {
+                let title = match destination.title() {
+                    Some(lopdf::Object::String(bytes, _)) => decode_pdf_text(bytes),
+                    _ => continue,
+                };
+                let page = match destination.page() {
+                    Some(lopdf::Object::Reference(id)) => page_numbers.get(id).copied(),
+                    // Remote destinations give a zero-based page index
+                    Some(lopdf::Object::Integer(index)) => u32::try_from(*index).ok().map(|i| i + 1),
+                    _ => None,
+                };
+                let title = title.split_whitespace().collect::<Vec<_>>().join(" ");
+                if let (Some(page), false) = (page, title.is_empty()) {
+                    headings.push(PdfHeading { level, title, page });
+                }
+            }
+            lopdf::Outline::SubOutlines(children) => {
+                collect_pdf_headings(children, level + 1, page_numbers, headings);
+            }
+        }
+    }
+}
+
+/// Decode a PDF text string: UTF-16BE with a byte order mark, otherwise PDFDocEncoding,
+/// which matches Latin-1 for printable text
+fn decode_pdf_text(bytes: &[u8]) -> String {
+    match bytes {
+        [0xfe, 0xff, rest @ ..] => {
+            let units: Vec<u16> = rest.chunks_exact(2)
+                .map(|pair| u16::from_be_bytes([pair[0], pair[1]]))
+                .collect();
+            String::from_utf16_lossy(&units)
+        }
+        _ => bytes.iter().map(|&b| b as char).collect(),
+    }
+}
+
+/// Outline level (0 = top) of each heading paragraph style in a DOCX's styles.xml.
+///
+/// Built-in heading styles keep their English names (`heading 1`) whatever the UI
+/// language, while their ids are localised; custom styles may set an outline level.
+fn docx_heading_styles(xml_content: &str) -> HashMap<String, usize> {
+    let mut reader = XmlReader::from_str(xml_content);
+    let mut styles = HashMap::new();
+    let mut buf = Vec::new();
+    let mut style: Option<(String, Option<usize>)> = None;
+    
+    loop {
+        match reader.read_event_into(&mut buf) {
+            Ok(Event::Start(e)) | Ok(Event::Empty(e)) => match e.name().as_ref() {
+                b"w:style" => {
+                    style = xml_attribute(&e, b"w:styleId").map(|id| (id, None));
+                }
+                b"w:name" => {
+                    if let (Some((_, level)), Some(name)) = (style.as_mut(), xml_attribute(&e, b"w:val")) {
+                        let heading = name.to_lowercase().strip_prefix("heading ")
+                            .and_then(|n| n.parse::<usize>().ok());
+                        if let Some(n) = heading.filter(|n| (1..=9).contains(n)) {
+                            level.get_or_insert(n - 1);
+                        }
+                    }
+                }
+                b"w:outlineLvl" => {
+                    if let (Some((_, level)), Some(outline)) = (style.as_mut(), xml_attribute(&e, b"w:val")) {
+                        match outline.parse::<usize>() {
+                            Ok(outline) if outline < 9 => *level = Some(outline),
+                            _ => *level = None,
+                        }
+                    }
+                }
+                _ => {}
+            },
+            Ok(Event::End(e)) if e.name().as_ref() == b"w:style" => {
+                if let Some((id, Some(level))) = style.take() {
+                    styles.insert(id, level);
+                }
+            }
+            Ok(Event::Eof) => break,
+            Err(e) => {
+                warn!("Error parsing DOCX styles: {}", e);
+                break;
+            }
+            _ => {}
+        }
+        buf.clear();
+    }
+    
+    styles
+}
+
+/// Fallback for documents without styles.xml: the English built-in ids `Heading1`..`Heading9`
+fn heading_level_from_style_id(style_id: &str) -> Option<usize> {
+    style_id.strip_prefix("Heading")
+        .and_then(|n| n.parse::<usize>().ok())
+        .filter(|n| (1..=9).contains(n))
+        .map(|n| n - 1)
+}
+
+pub(crate) fn xml_attribute(element: &BytesStart, name: &[u8]) -> Option<String> {
+    element.attributes()
+        .flatten()
+        .find(|attribute| attribute.key.as_ref() == name)
+        .and_then(|attribute| {
+            let value = String::from_utf8_lossy(&attribute.value).into_owned();
+            quick_xml::escape::unescape(&value).ok().map(|value| value.into_owned())
+        })
+}
+
+/// Read a file from a zip archive as text
+fn read_zip_text<R: Read + std::io::Seek>(archive: &mut ZipArchive<R>, name: &str) -> Result<String> {
+    let mut file = archive.by_name(name)?;
+    let mut bytes = Vec::new();
+    file.read_to_end(&mut bytes)?;
+    Ok(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+/// Path of the package document (`.opf`) named by an EPUB's `META-INF/container.xml`
+fn epub_package_path(container_xml: &str) -> Option<String> {
+    let mut reader = XmlReader::from_str(container_xml);
+    let mut buf = Vec::new();
+    loop {
+        match reader.read_event_into(&mut buf) {
+            Ok(Event::Start(e)) | Ok(Event::Empty(e)) if e.local_name().as_ref() == b"rootfile" => {
+                return xml_attribute(&e, b"full-path");
+            }
+            Ok(Event::Eof) | Err(_) => return None,
+            _ => {}
+        }
+        buf.clear();
+    }
+}
+
+/// The hrefs of an EPUB package's (X)HTML chapters, in spine (reading) order
+fn epub_spine(package_xml: &str) -> Vec<String> {
+    let mut reader = XmlReader::from_str(package_xml);
+    let mut buf = Vec::new();
+    let mut manifest: HashMap<String, String> = HashMap::new();
+    let mut spine: Vec<String> = Vec::new();
+    
+    loop {
+        match reader.read_event_into(&mut buf) {
+            Ok(Event::Start(e)) | Ok(Event::Empty(e)) => match e.local_name().as_ref() {
+                b"item" => {
+                    let media_type = xml_attribute(&e, b"media-type").unwrap_or_default();
+                    if media_type == "application/xhtml+xml" || media_type == "text/html" {
+                        if let (Some(id), Some(href)) = (xml_attribute(&e, b"id"), xml_attribute(&e, b"href")) {
+                            manifest.insert(id, href);
+                        }
+                    }
+                }
+                b"itemref" => spine.extend(xml_attribute(&e, b"idref")),
+                _ => {}
+            },
+            Ok(Event::Eof) => break,
+            Err(e) => {
+                warn!("Error parsing EPUB package document: {}", e);
+                break;
+            }
+            _ => {}
+        }
+        buf.clear();
+    }
+    
+    spine.iter().filter_map(|id| manifest.get(id).cloned()).collect()
+}
+
+/// Resolve an href relative to a directory inside a zip archive, dropping any `#fragment`
+fn resolve_zip_path(base: &str, href: &str) -> String {
+    let href = href.split('#').next().unwrap_or("");
+    let mut parts: Vec<&str> = base.split('/').filter(|part| !part.is_empty()).collect();
+    for part in href.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop();
+            }
+            part => parts.push(part),
+        }
+    }
+    parts.join("/")
+}
+
+/// Drop a leading YAML (`---`) or TOML (`+++`) front matter block
+fn strip_front_matter(text: &str) -> &str {
+    for marker in ["---", "+++"] {
+        let Some(rest) = text.strip_prefix(marker).and_then(|rest| rest.strip_prefix('\n').or_else(|| rest.strip_prefix("\r\n"))) else {
+            continue;
+        };
+        let mut offset = 0;
+        for line in rest.split_inclusive('\n') {
+            offset += line.len();
+            if line.trim_end() == marker {
+                return &rest[offset..];
+            }
+        }
+    }
+    text
+}
+
+/// Whether a line can be the title of a setext heading: paragraph text, not a list item,
+/// table row or blank line
+fn is_setext_title(line: &str) -> bool {
+    let line = line.trim();
+    !line.is_empty() && !is_list_item(line) && !line.contains('|')
+}
+
+/// Patterns reducing inline Markdown to its text: images and links to their text,
+/// emphasis and code spans to their contents, and HTML comments to nothing
+fn markdown_inline_rules() -> Vec<(Regex, &'static str)> {
+    [
+        (r"<!--.*?-->", ""),
+        (r"!\[([^\]]*)\]\([^)]*\)", "$1"),
+        (r"\[([^\]]+)\](?:\([^)]*\)|\[[^\]]*\])", "$1"),
+        (r"\*\*([^*]+)\*\*", "$1"),
+        (r"__([^_]+)__", "$1"),
+        (r"\*([^*\s][^*]*)\*", "$1"),
+        (r"`([^`]+)`", "$1"),
+    ]
+    .into_iter()
+    .map(|(pattern, replace)| (Regex::new(pattern).unwrap(), replace))
+    .collect()
+}
+
+fn markdown_inline(line: &str, rules: &[(Regex, &str)]) -> String {
+    let mut line = Cow::Borrowed(line);
+    for (regex, replace) in rules {
+        if let Cow::Owned(replaced) = regex.replace_all(&line, *replace) {
+            line = Cow::Owned(replaced);
+        }
+    }
+    line.into_owned()
+}
+
+/// Add the units at `indices`, joined, as a chunk unless it is too short to be useful
+fn push_chunk(chunks: &mut Vec<String>, indices: &[usize], units: &[String], separator: &str) {
+    let chunk = indices.iter().map(|&i| units[i].as_str()).collect::<Vec<_>>().join(separator);
+    let chunk = chunk.trim();
+    if chunk.len() > 10 {
+        chunks.push(chunk.to_string());
+    }
+}
+
+/// Paragraphs of text, separated by blank lines
+fn paragraphs(text: &str) -> Vec<String> {
+    let mut paragraphs = Vec::new();
+    let mut current: Vec<&str> = Vec::new();
+    for line in text.lines() {
+        if line.trim().is_empty() {
+            if !current.is_empty() {
+                paragraphs.push(current.join("\n"));
+                current.clear();
+            }
+        } else {
+            current.push(line.trim());
+        }
+    }
+    if !current.is_empty() {
+        paragraphs.push(current.join("\n"));
+    }
+    paragraphs
+}
+
+/// Drop control characters other than line breaks and tabs
+fn without_control_chars(text: &str) -> String {
+    text.chars()
+        .filter(|c| !c.is_control() || *c == '\n' || *c == '\t')
+        .collect()
+}
+
+/// Split text into runs of prose and the code blocks, tables and lists between them, in
+/// order. Blocks are found by their Markdown-style markup: ``` or ~~~ fences, rows between
+/// pipes, and `-`, `*`, `+`, `•` or `1.` item markers on two or more items.
+fn text_blocks(text: &str) -> Vec<(Option<Structure>, String)> {
+    let lines: Vec<&str> = text.lines().collect();
+    let mut blocks = Vec::new();
+    let mut prose: Vec<&str> = Vec::new();
+    
+    let mut i = 0;
+    while i < lines.len() {
+        match structure_at(&lines, i) {
+            Some((structure, end)) => {
+                if prose.iter().any(|line| !line.trim().is_empty()) {
+                    blocks.push((None, prose.join("\n")));
+                }
+                prose.clear();
+                blocks.push((Some(structure), lines[i..end].join("\n")));
+                i = end;
+            }
+            None => {
+                prose.push(lines[i]);
+                i += 1;
+            }
+        }
+    }
+    if prose.iter().any(|line| !line.trim().is_empty()) {
+        blocks.push((None, prose.join("\n")));
+    }
+    blocks
+}
+
+/// The structured block starting at `lines[start]`, with the index of the line after it
+fn structure_at(lines: &[&str], start: usize) -> Option<(Structure, usize)> {
+    let first = lines[start].trim_start();
+    
+    if let Some(fence) = code_fence(first) {
+        // An unclosed fence runs to the end of the text
+        let end = lines[start + 1..].iter()
+            .position(|line| line.trim_start().starts_with(fence))
+            .map_or(lines.len(), |offset| start + offset + 2);
+        return Some((Structure::Code, end));
+    }
+    
+    if first.contains('|') {
+        // Rows between pipes, or pipe-separated rows under a `---|---` delimiter row
+        let delimited = lines.get(start + 1).is_some_and(|line| is_table_delimiter(line));
+        let rows = lines[start..].iter()
+            .take_while(|line| {
+                let line = line.trim_start();
+                if delimited { line.contains('|') } else { line.starts_with('|') }
+            })
+            .count();
+        if rows >= 2 && (delimited || first.starts_with('|')) {
+            return Some((Structure::Table, start + rows));
+        }
+    }
+    
+    if is_list_item(first) {
+        // Items may be separated by blank lines and followed by indented continuation lines
+        let mut items = 1;
+        let mut end = start + 1;
+        let mut i = start + 1;
+        while i < lines.len() {
+            let line = lines[i];
+            if line.trim().is_empty() {
+                i += 1;
+                continue;
+            }
+            if is_list_item(line.trim_start()) {
+                items += 1;
+            } else if indentation(line) == 0 {
+                break;
+            }
+            i += 1;
+            end = i;
+        }
+        if items >= 2 {
+            return Some((Structure::List, end));
+        }
+    }
+    
+    None
+}
+
+/// The fence marker a code block opens with, if the line opens or closes one
+fn code_fence(line: &str) -> Option<&'static str> {
+    ["```", "~~~"].into_iter().find(|fence| line.starts_with(fence))
+}
+
+/// Byte offset of the line opening a code block that `text` leaves unclosed
+fn open_code_fence(text: &str) -> Option<usize> {
+    let mut open: Option<(usize, &str)> = None;
+    let mut offset = 0;
+    for line in text.split_inclusive('\n') {
+        if let Some(fence) = code_fence(line.trim_start()) {
+            open = match open {
+                Some((_, opened)) if opened == fence => None,
+                Some(opened) => Some(opened),
+                None => Some((offset, fence)),
+            };
+        }
+        offset += line.len();
+    }
+    open.map(|(offset, _)| offset)
+}
+
+/// A table's header delimiter row, e.g. `|---|:--:|`
+fn is_table_delimiter(line: &str) -> bool {
+    let line = line.trim();
+    line.contains('-') && line.contains('|') && line.chars().all(|c| matches!(c, '|' | '-' | ':' | ' '))
+}
+
+/// Whether a line (without its indentation) starts a bulleted or numbered list item
+fn is_list_item(line: &str) -> bool {
+    if ["- ", "* ", "+ ", "• "].iter().any(|marker| line.starts_with(marker)) {
+        return true;
+    }
+    let digits = line.chars().take_while(|c| c.is_ascii_digit()).count();
+    (1..=3).contains(&digits) && (line[digits..].starts_with(". ") || line[digits..].starts_with(") "))
+}
+
+fn indentation(line: &str) -> usize {
+    line.len() - line.trim_start().len()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -588,7 +1821,7 @@ mod tests {
     fn test_text_cleanup() {
         let processor = DocumentProcessor::new();
         let messy_text = "This   is    a\n\n\ntest    text\twith\nexcessive\n\n   whitespace.";
-        let cleaned = processor.cleanup_text(messy_text);
+        let cleaned = processor.cleanup_text(messy_text, &DocumentFormat::Text);
         
         assert_eq!(cleaned, "This is a\n\ntest text with\n\nexcessive\n\nwhitespace.");
     }
@@ -607,4 +1840,293 @@ mod tests {
             assert!(chunk.len() >= 10); // Should filter out very short chunks
         }
     }
-}
\ No newline at end of file
+    
+    #[test]
+    fn test_paragraph_and_recursive_chunking() {
+        let text = "Cats sleep most of the day and hunt at night.\n\n\
+                    Dogs need a walk every morning and evening.\n\n\
+                    Parrots can live for decades and learn many words.";
+        let processor = DocumentProcessor::new().with_chunking(100, 0).with_strategy(ChunkingStrategy::Paragraph);
+        let chunks = processor.chunk_text(text).unwrap();
+        assert_eq!(chunks.len(), 2);
+        assert!(chunks[0].starts_with("Cats") && chunks[0].ends_with("evening."));
+        assert!(chunks[1].starts_with("Parrots"));
+        
+        // A run-on paragraph without sentence breaks still fits, split between words
+        let run_on = "word ".repeat(100);
+        let processor = DocumentProcessor::new().with_chunking(60, 0).with_strategy(ChunkingStrategy::Recursive);
+        let chunks = processor.chunk_text(&run_on).unwrap();
+        assert!(chunks.len() > 1);
+        assert!(chunks.iter().all(|chunk| chunk.len() <= 60 && chunk.starts_with("word")));
+    }
+    
+    #[test]
+    fn test_token_chunking_counts_tokens() {
+        use tokenizers::models::wordlevel::WordLevel;
+        use tokenizers::pre_tokenizers::whitespace::Whitespace;
+        
+        let vocab = ["[UNK]", "one", "two", "three", "."].iter().enumerate()
+            .map(|(id, word)| (word.to_string(), id as u32))
+            .collect();
+        let model = WordLevel::builder().vocab(vocab).unk_token("[UNK]".to_string()).build().unwrap();
+        let mut tokenizer = Tokenizer::new(model);
+        tokenizer.with_pre_tokenizer(Some(Whitespace {}));
+        
+        // 48 characters of chunk size become 12 tokens, which fit two six-token sentences
+        let processor = DocumentProcessor::new().with_chunking(48, 0).with_token_chunking(tokenizer).unwrap();
+        let chunks = processor.chunk_text(&"one two three one two. ".repeat(4)).unwrap();
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[0], "one two three one two. one two three one two.");
+        
+        let untokenized = DocumentProcessor::new().with_strategy(ChunkingStrategy::Token);
+        assert!(untokenized.chunk_text("one two three.").is_err());
+    }
+    
+    #[test]
+    fn test_split_segments_keeps_all_text() {
+        let processor = DocumentProcessor::with_limits(512, 50, 1024 * 1024, 100);
+        let text = "Première phrase du livre. ".repeat(20);
+        let segments = processor.split_segments(&text);
+        
+        assert!(segments.len() > 1);
+        assert_eq!(segments.concat(), text);
+        for segment in &segments {
+            assert!(segment.len() <= 100);
+            // Breaks fall after a full stop, not mid-sentence
+            assert!(segment.ends_with(". "));
+        }
+    }
+    
+    #[test]
+    fn test_docx_sections_follow_headings() {
+        let processor = DocumentProcessor::new();
+        let styles = docx_heading_styles(r#"<w:styles>
+            <w:style w:type="paragraph" w:styleId="berschrift1"><w:name w:val="heading 1"/></w:style>
+            <w:style w:type="paragraph" w:styleId="Heading2"><w:name w:val="heading 2"/></w:style>
+            <w:style w:type="paragraph" w:styleId="Normal"><w:name w:val="Normal"/></w:style>
+        </w:styles>"#);
+        let body = r#"<w:document><w:body>
+            <w:p><w:r><w:t>Preamble text.</w:t></w:r></w:p>
+            <w:p><w:pPr><w:pStyle w:val="berschrift1"/></w:pPr><w:r><w:t>Design</w:t></w:r></w:p>
+            <w:p><w:pPr><w:pStyle w:val="Heading2"/></w:pPr><w:r><w:t>Security </w:t></w:r><w:r><w:t>Requirements</w:t></w:r></w:p>
+            <w:p><w:r><w:t>All traffic is encrypted.</w:t></w:r></w:p>
+            <w:p><w:pPr><w:pStyle w:val="berschrift1"/></w:pPr><w:r><w:t>Operations</w:t></w:r></w:p>
+            <w:p><w:r><w:t>Backups run nightly.</w:t></w:r></w:p>
+        </w:body></w:document>"#;
+        let sections = processor.extract_sections_from_docx_xml(body, &styles).unwrap();
+        
+        let labels: Vec<Option<String>> = sections.iter().map(Section::label).collect();
+        assert_eq!(labels, vec![
+            None,
+            Some("Design".to_string()),
+            Some("Design > Security Requirements".to_string()),
+            Some("Operations".to_string()),
+        ]);
+        // Headings stay part of their section's text
+        assert!(sections[2].text.contains("Requirements"));
+        assert!(sections[2].text.contains("All traffic is encrypted."));
+        assert!(sections[3].text.contains("Backups run nightly."));
+    }
+    
+    #[test]
+    fn test_markdown_sections_follow_headings() {
+        let processor = DocumentProcessor::new();
+        let markdown = "---\ntitle: Spec\n---\nIntro with a [link](https://example.com) and **bold** text.\n\n\
+            # Design\n\nSee ![the diagram](d.png).\n\n## Security\n\n```\n# not a heading\n```\n\n\
+            Operations\n==========\n\n- backups\n- restores\n";
+        let sections = processor.extract_sections_from_document(Path::new("spec.md"), markdown.as_bytes()).unwrap();
+        
+        let labels: Vec<Option<String>> = sections.iter().map(Section::label).collect();
+        assert_eq!(labels, vec![
+            None,
+            Some("Design".to_string()),
+            Some("Design > Security".to_string()),
+            Some("Operations".to_string()),
+        ]);
+        assert_eq!(sections[0].text, "Intro with a link and bold text.");
+        assert!(sections[1].text.contains("See the diagram."));
+        assert!(sections[2].text.contains("```\n# not a heading\n```"));
+        assert!(sections[3].text.contains("- backups\n- restores"));
+    }
+    
+    #[test]
+    fn test_epub_chapters_in_spine_order() {
+        use std::io::Write;
+        
+        let mut zip = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        let files = [
+            ("mimetype", "application/epub+zip"),
+            ("META-INF/container.xml", r#"<container><rootfiles><rootfile full-path="OEBPS/content.opf"/></rootfiles></container>"#),
+            ("OEBPS/content.opf", r#"<package><manifest>
+                <item id="c1" href="text/one.xhtml" media-type="application/xhtml+xml"/>
+                <item id="c2" href="text/two.xhtml" media-type="application/xhtml+xml"/>
+                <item id="css" href="style.css" media-type="text/css"/>
+            </manifest><spine><itemref idref="c2"/><itemref idref="c1"/><itemref idref="css"/></spine></package>"#),
+            ("OEBPS/text/one.xhtml", "<html><body><h1>Arrival</h1><p>The ship docked.</p></body></html>"),
+            ("OEBPS/text/two.xhtml", "<html><body><h2>Prologue</h2><p>It was night.</p></body></html>"),
+        ];
+        for (name, content) in files {
+            zip.start_file(name, zip::write::FileOptions::default()).unwrap();
+            zip.write_all(content.as_bytes()).unwrap();
+        }
+        let epub = zip.finish().unwrap().into_inner();
+        
+        let processor = DocumentProcessor::new();
+        let sections = processor.extract_sections_from_document(Path::new("book.epub"), &epub).unwrap();
+        let labels: Vec<Option<String>> = sections.iter().map(Section::label).collect();
+        assert_eq!(labels, vec![Some("Prologue".to_string()), Some("Arrival".to_string())]);
+        assert!(sections[1].text.contains("The ship docked."));
+    }
+    
+    #[test]
+    fn test_delimited_and_email_text() {
+        let processor = DocumentProcessor::new();
+        let csv = "name,city\n\"Smith, Jane\",Oslo\n,\n";
+        let text = processor.extract_text_from_document(Path::new("people.csv"), csv.as_bytes()).unwrap();
+        assert_eq!(text, "name | city\n\nSmith, Jane | Oslo");
+        let tsv = "a\tb\nc\td\n";
+        assert_eq!(processor.extract_text_from_document(Path::new("t.tsv"), tsv.as_bytes()).unwrap(), "a | b\n\nc | d");
+        
+        let email = "From: Ann Lee <ann@example.com>\r\nTo: bob@example.com\r\nSubject: Quarterly numbers\r\n\
+            Content-Type: text/html; charset=utf-8\r\n\r\n<html><body><p>Revenue is <b>up</b>.</p><script>x()</script></body></html>\r\n";
+        let text = processor.extract_text_from_document(Path::new("mail.eml"), email.as_bytes()).unwrap();
+        assert!(text.starts_with("From: Ann Lee <ann@example.com>\n\nTo: bob@example.com\n\nSubject: Quarterly numbers"));
+        assert!(text.contains("Revenue is up"));
+        assert!(!text.contains("<b>"));
+    }
+    
+    #[test]
+    fn test_encrypted_office_files_are_reported() {
+        let mut encrypted = vec![0xD0, 0xCF, 0x11, 0xE0, 0xA1, 0xB1, 0x1A, 0xE1];
+        encrypted.extend(vec![0; 504]);
+        encrypted.extend("EncryptedPackage".encode_utf16().flat_map(u16::to_le_bytes));
+        
+        let error = DocumentProcessor::new().extract_sections_from_document(Path::new("budget.xlsx"), &encrypted).unwrap_err();
+        assert_eq!(error::find(&error).map(PortableBrainsError::kind), Some("encrypted"));
+        assert_eq!(error::severity(&error), error::Severity::Skip);
+        
+        // A compound file without an encrypted package is a legacy file with the wrong extension
+        assert!(!is_encrypted_office(&encrypted[..512]));
+        assert!(!is_encrypted_office(b"PK\x03\x04"));
+    }
+    
+    #[test]
+    fn test_custom_cleanup_rules() {
+        let mut formats = HashMap::new();
+        formats.insert(DocumentFormat::Text, FormatCleanup { collapse_whitespace: Some(false), skip_rules: false });
+        let processor = DocumentProcessor::new().with_cleanup(TextCleanup {
+            rules: vec![
+                CleanupRule { regex: Regex::new(r"(\w)-\n(\w)").unwrap(), replace: "$1$2".to_string(), formats: vec![] },
+                CleanupRule { regex: Regex::new(r"Page \d+").unwrap(), replace: String::new(), formats: vec![DocumentFormat::Pdf] },
+            ],
+            collapse_whitespace: true,
+            formats,
+        });
+        
+        // Hyphenation is rejoined before line breaks are collapsed; the page rule is PDF-only
+        assert_eq!(processor.cleanup_text("infor-\nmation  Page 3", &DocumentFormat::Pdf), "information");
+        assert_eq!(processor.cleanup_text("infor-\nmation  Page 3", &DocumentFormat::Text), "information  Page 3");
+    }
+    
+    #[test]
+    fn test_structures_are_chunked_whole() {
+        let processor = DocumentProcessor::with_limits(200, 20, 1024 * 1024, 1024 * 1024);
+        let text = [
+            "Install the client first. It needs a recent toolchain.",
+            "```rust",
+            "fn main() {",
+            "",
+            "    println!(\"Hello. World.\");",
+            "}",
+            "```",
+            "The limits are:",
+            "| Plan | Requests |",
+            "|------|----------|",
+            "| Free |   100    |",
+            "Before you start:",
+            "- Create an account.",
+            "- Verify your e-mail address.",
+            "That is all you need to do.",
+        ].join("\n");
+        let cleaned = processor.cleanup_text(&text, &DocumentFormat::Text);
+        let chunks = processor.chunk_blocks(&cleaned).unwrap();
+        
+        let code: Vec<_> = chunks.iter().filter(|(_, s)| *s == Some(Structure::Code)).collect();
+        assert_eq!(code.len(), 1);
+        assert_eq!(code[0].0, "```rust\nfn main() {\n\n    println!(\"Hello. World.\");\n}\n```");
+        
+        let table = chunks.iter().find(|(_, s)| *s == Some(Structure::Table)).unwrap();
+        assert_eq!(table.0, "| Plan | Requests |\n|------|----------|\n| Free | 100 |");
+        let list = chunks.iter().find(|(_, s)| *s == Some(Structure::List)).unwrap();
+        assert_eq!(list.0, "- Create an account.\n- Verify your e-mail address.");
+        
+        assert!(chunks.iter().any(|(chunk, s)| s.is_none() && chunk.starts_with("Install the client")));
+    }
+    
+    #[test]
+    fn test_long_structures_split_between_rows() {
+        let processor = DocumentProcessor::with_limits(100, 0, 1024 * 1024, 1024 * 1024);
+        let rows: String = (1..=12).map(|i| format!("| item {} | {} |\n", i, i * 10)).collect();
+        let table = format!("| Item | Price |\n|---|---|\n{}", rows);
+        
+        let pieces = processor.chunk_blocks(&table).unwrap();
+        assert!(pieces.len() > 1);
+        for (piece, structure) in &pieces {
+            assert_eq!(*structure, Some(Structure::Table));
+            assert!(piece.starts_with("| Item | Price |\n|---|---|\n| item"));
+            assert!(piece.len() <= 100);
+        }
+        let body_rows: usize = pieces.iter().map(|(piece, _)| piece.lines().count() - 2).sum();
+        assert_eq!(body_rows, 12);
+        
+        // Segments never start inside a code block
+        let text = format!("Intro text here. {}\n\n```\nlet x = 1;\n\nlet y = 2;\n```\n", "Filler. ".repeat(5));
+        let segments = DocumentProcessor::with_limits(100, 0, 1024 * 1024, 80).split_segments(&text);
+        assert_eq!(segments.len(), 2);
+        assert!(segments[1].starts_with("```"));
+    }
+    
+    #[test]
+    fn test_chunk_spans_point_into_joined_text() {
+        let processor = DocumentProcessor::with_limits(60, 0, 1024 * 1024, 1024 * 1024);
+        let sections = vec![
+            Section { path: Vec::new(), text: "Backups run nightly. Restores are tested monthly.".to_string(), page: None, location: None },
+            Section { path: vec!["Keys".to_string()], text: "Keys rotate yearly. Old keys are revoked at once.".to_string(), page: None, location: None },
+        ];
+        let text = join_sections(&sections);
+        
+        let fragments = processor.chunk_sections(&sections).unwrap();
+        assert!(fragments.len() >= 2);
+        for (content, meta) in &fragments {
+            let (start, end) = meta.span.expect("chunks of plain prose are found verbatim");
+            assert_eq!(&text[start..end], content);
+        }
+    }
+
+    #[test]
+    fn test_derive_title() {
+        let titled = |text: &str| derive_title(&[Section::untitled(text.to_string())]);
+        assert_eq!(titled("  3\nQuarterly   Budget Review\nRevenue rose in every region."), Some("Quarterly Budget Review".to_string()));
+        assert_eq!(titled(&format!("{}and the rest of a long opening sentence.", "word ".repeat(20))),
+                   Some(format!("{}…", "word ".repeat(16).trim_end())));
+        assert_eq!(titled("   \n"), None);
+
+        let sections = vec![
+            Section { path: vec!["Incident Response".to_string()], ..Section::untitled("Call the on-call engineer.".to_string()) },
+        ];
+        assert_eq!(derive_title(&sections), Some("Incident Response".to_string()));
+    }
+
+    #[test]
+    fn test_packed_sections_round_trip() {
+        let sections = vec![
+            Section { path: Vec::new(), text: "Preamble.".to_string(), page: None, location: None },
+            Section { path: vec!["Design".to_string(), "Security".to_string()], text: "Keys rotate yearly. ".repeat(50), page: Some(4), location: None },
+            Section { location: Some(Location::Slide(12)), ..Section::untitled("Roadmap".to_string()) },
+        ];
+        let packed = pack_sections(&sections).unwrap();
+        
+        assert!(packed.len() < sections[1].text.len());
+        assert_eq!(unpack_sections(&packed).unwrap(), sections);
+    }
+}
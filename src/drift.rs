@@ -0,0 +1,96 @@
+use anyhow::Result;
+
+use crate::brains::cosine_similarity;
+
+/// Cosine deviation below which a re-embedded vector counts as unchanged; ONNX runtimes on
+/// different CPUs differ in the last few digits, well below this
+pub const DEFAULT_THRESHOLD: f64 = 0.01;
+
+/// How far freshly computed vectors are from the stored ones, per sampled fragment
+#[derive(Debug, Clone)]
+pub struct DriftReport {
+    /// (fragment id, cosine deviation), worst first
+    pub deviations: Vec<(String, f64)>,
+}
+
+impl DriftReport {
+    /// Compare stored `(fragment id, vector)` pairs with fresh vectors of the same text, in
+    /// the same order
+    pub fn measure(stored: &[(String, Vec<f64>)], fresh: &[Vec<f64>]) -> Result<Self> {
+        if stored.len() != fresh.len() {
+            anyhow::bail!("Got {} fresh vectors for {} sampled fragments", fresh.len(), stored.len());
+        }
+        if let (Some((_, old)), Some(new)) = (stored.first(), fresh.first()) {
+            if old.len() != new.len() {
+                anyhow::bail!(
+                    "The model now produces {}-dimensional vectors but the database stores {}-dimensional ones; \
+                     it is not the model the database was embedded with",
+                    new.len(), old.len()
+                );
+            }
+        }
+
+        let mut deviations: Vec<(String, f64)> = stored.iter()
+            .zip(fresh)
+            .map(|((fragment_id, old), new)| (fragment_id.clone(), 1.0 - cosine_similarity(old, new)))
+            .collect();
+        deviations.sort_by(|a, b| b.1.total_cmp(&a.1));
+        Ok(Self { deviations })
+    }
+
+    pub fn mean(&self) -> f64 {
+        if self.deviations.is_empty() {
+            return 0.0;
+        }
+        self.deviations.iter().map(|(_, deviation)| deviation).sum::<f64>() / self.deviations.len() as f64
+    }
+
+    /// The deviation `fraction` of the sample stays at or below, e.g. 0.95 for the 95th percentile
+    pub fn percentile(&self, fraction: f64) -> f64 {
+        if self.deviations.is_empty() {
+            return 0.0;
+        }
+        // Worst first, so the 95th percentile sits 5% of the way down
+        let index = ((1.0 - fraction) * self.deviations.len() as f64).floor() as usize;
+        self.deviations[index.min(self.deviations.len() - 1)].1
+    }
+
+    pub fn max(&self) -> f64 {
+        self.deviations.first().map_or(0.0, |(_, deviation)| *deviation)
+    }
+
+    /// Fragments whose vectors moved further than `threshold`, worst first
+    pub fn drifted(&self, threshold: f64) -> &[(String, f64)] {
+        let count = self.deviations.iter().take_while(|(_, deviation)| *deviation > threshold).count();
+        &self.deviations[..count]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_drift_flags_moved_vectors() {
+        let stored = vec![
+            ("same".to_string(), vec![1.0, 0.0, 0.0]),
+            ("scaled".to_string(), vec![0.0, 2.0, 0.0]),
+            ("moved".to_string(), vec![0.0, 0.0, 1.0]),
+        ];
+        let fresh = vec![vec![1.0, 0.0, 0.0], vec![0.0, 1.0, 0.0], vec![0.0, 0.6, 0.8]];
+
+        let report = DriftReport::measure(&stored, &fresh).unwrap();
+
+        assert_eq!(report.drifted(DEFAULT_THRESHOLD).len(), 1);
+        assert_eq!(report.drifted(DEFAULT_THRESHOLD)[0].0, "moved");
+        assert!((report.max() - 0.2).abs() < 1e-9);
+        assert!(report.percentile(0.5) < 1e-9);
+    }
+
+    #[test]
+    fn test_drift_rejects_other_dimensions() {
+        let stored = vec![("a".to_string(), vec![1.0, 0.0])];
+        assert!(DriftReport::measure(&stored, &[vec![1.0, 0.0, 0.0]]).is_err());
+        assert!(DriftReport::measure(&stored, &[]).is_err());
+    }
+}
@@ -1,24 +1,119 @@
 use anyhow::{Context, Result};
-use duckdb::{Connection, params};
-use log::info;
+use duckdb::{Connection, Params, params, params_from_iter};
+use duckdb::vtab::arrow::ArrowVTab;
+use duckdb::vtab::arrow_recordbatch_to_query_params;
+use log::{debug, info, warn};
+use std::collections::HashMap;
 use std::path::Path;
-use uuid::Uuid;
+use std::sync::Arc;
 use async_trait::async_trait;
 
-use crate::storage::{Storage, MetaInfo};
+use crate::annotations::{Annotation, AnnotationLabel};
+use crate::entities;
+use crate::offline;
+use crate::paths::StoredPath;
+use crate::storage::arrow::array::{ArrayRef, ListArray, RecordBatch, StringArray};
+use crate::storage::arrow::datatypes::Float32Type;
+use crate::storage::{check_dimension, chunk_hash, conform_fragment_batch, content_hash, decode_original, fragment_schema, modified_micros, parse_dimension, path_glob, sort_file_types, sort_ranked, validate_fragment, CorpusStats, DocumentAttributes, DocumentInfo, DocumentSize, DocumentSummary, EntityCount, EntityMention, FragmentChanges, FragmentMatch, FragmentMeta, FragmentRecord, FragmentSource, IdScheme, IndexState, Location, OriginalsMode, PendingFragment, Storage, MetaInfo, SearchFilter, SimilarityMetric, BulkAction, Structure, VectorIndexCoverage, DETERMINISTIC_IDS_KEY, DIMENSION_KEY, PROVIDER_KEY, SIMILARITY_METRIC_KEY, STORE_ORIGINALS_KEY};
 
 const DB_VERSION: &str = "1.0.0";
 
+/// Meta key recording which fragments the full-text index was built from
+const FTS_FINGERPRINT_KEY: &str = "fts_fingerprint";
+
+/// Meta key recording the fragments the entity index was last brought up to date with
+const ENTITY_FINGERPRINT_KEY: &str = "entity_fingerprint";
+
+/// Meta key recording the fragments the chunk hash index was last brought up to date with
+const CHUNK_FINGERPRINT_KEY: &str = "chunk_fingerprint";
+
+/// Meta key recording the vector length of the compact fragment layout; absent while
+/// fragments use the standard layout
+pub const COMPACT_LAYOUT_KEY: &str = "compact_fragments";
+
+/// Meta key recording that the vectors have an HNSW index
+pub const VECTOR_INDEX_KEY: &str = "vector_index";
+
+/// Table of the vectors the HNSW index covers, copied from the fragments when it was built or
+/// last brought up to date. DuckDB can't update vectors under an HNSW index, so the index has
+/// its own copy: fragments embedded since are scanned until the next embed phase adds them.
+const VECTOR_INDEX_TABLE: &str = "fragment_index";
+
+/// Name of the HNSW index over `VECTOR_INDEX_TABLE`
+const VECTOR_INDEX_NAME: &str = "idx_fragment_index_hnsw";
+
+/// Nearest neighbours fetched through the HNSW index for every result asked for, so that
+/// enough remain once search filters are applied
+const VECTOR_INDEX_OVERSAMPLE: usize = 4;
+
+/// Fewest nearest neighbours fetched through the HNSW index
+const VECTOR_INDEX_MIN_POOL: usize = 100;
+
+/// Prepared statements kept per connection; enough for every fixed statement the
+/// per-document and per-fragment calls run
+const STATEMENT_CACHE_CAPACITY: usize = 64;
+
 pub struct DuckDBStorage {
     conn: Connection,
+    ids: IdScheme,
+    /// How the originals of new documents are stored
+    originals: OriginalsMode,
+    /// Vector length when fragments use the compact layout
+    compact: Option<usize>,
+    /// The vectors have an HNSW index and the vss extension is loaded to use it
+    vector_index: bool,
+    /// How searches compare vectors
+    metric: SimilarityMetric,
+}
+
+/// The vss extension's name for `metric` and the distance function its HNSW index answers
+fn hnsw_metric(metric: SimilarityMetric) -> (&'static str, &'static str) {
+    match metric {
+        SimilarityMetric::Cosine => ("cosine", "array_cosine_distance"),
+        SimilarityMetric::Dot => ("ip", "array_negative_inner_product"),
+        SimilarityMetric::Euclidean => ("l2sq", "array_distance"),
+    }
+}
+
+/// DDL for the fragments table. Vectors are `FLOAT` lists; the compact layout stores them as
+/// fixed-size `FLOAT` arrays instead, dictionary-encodes the repetitive text columns and
+/// FSST-compresses content.
+fn fragments_table(compact: Option<usize>) -> String {
+    let (embedding, dictionary, fsst) = match compact {
+        Some(dimension) => (format!("FLOAT[{}]", dimension), " USING COMPRESSION dictionary", " USING COMPRESSION fsst"),
+        None => ("FLOAT[]".to_string(), "", ""),
+    };
+    format!(
+        "CREATE TABLE IF NOT EXISTS fragments (
+            id VARCHAR PRIMARY KEY,
+            document_id VARCHAR NOT NULL{dictionary},
+            fragment_order INTEGER NOT NULL,
+            segment INTEGER DEFAULT 0,
+            section VARCHAR{dictionary},
+            page INTEGER,
+            structure VARCHAR{dictionary},
+            location VARCHAR,
+            content TEXT NOT NULL{fsst},
+            embedding {embedding},
+            stale BOOLEAN DEFAULT FALSE,
+            hit_count INTEGER DEFAULT 0,
+            embedded_at TIMESTAMP,
+            created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+            FOREIGN KEY (document_id) REFERENCES documents(id)
+        )"
+    )
 }
 
 impl DuckDBStorage {
     pub async fn new(db_path: &Path) -> Result<Self> {
         let conn = Connection::open(db_path)
             .context("Failed to open DuckDB connection")?;
+        // Scans Arrow batches handed over by `write_fragment_batch` in place
+        conn.register_table_function::<ArrowVTab>("arrow")
+            .context("Failed to register the Arrow table function")?;
+        conn.set_prepared_statement_cache_capacity(STATEMENT_CACHE_CAPACITY);
         
-        let mut storage = DuckDBStorage { conn };
+        let mut storage = DuckDBStorage { conn, ids: IdScheme::Random, originals: OriginalsMode::Full, compact: None, vector_index: false, metric: SimilarityMetric::Cosine };
         storage.initialize().await?;
         
         Ok(storage)
@@ -40,8 +135,23 @@ impl DuckDBStorage {
                 id VARCHAR PRIMARY KEY,
                 filename VARCHAR NOT NULL,
                 file_path VARCHAR NOT NULL,
+                file_path_raw BLOB,
                 file_type VARCHAR NOT NULL,
                 file_data BLOB NOT NULL,
+                file_encoding VARCHAR,
+                priority INTEGER DEFAULT 0,
+                category VARCHAR,
+                category_score DOUBLE,
+                collection VARCHAR,
+                tags VARCHAR[],
+                quality_score DOUBLE,
+                quality_flags VARCHAR[],
+                title VARCHAR,
+                deleted_at TIMESTAMP,
+                extracted_text BLOB,
+                content_hash VARCHAR,
+                modified_at TIMESTAMP,
+                index_state VARCHAR,
                 created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
                 UNIQUE(file_path)
             )",
@@ -60,20 +170,140 @@ impl DuckDBStorage {
             [],
         );
         
+        // Add priority column if it doesn't exist (for existing databases)
+        let _ = self.conn.execute(
+            "ALTER TABLE documents ADD COLUMN priority INTEGER DEFAULT 0",
+            [],
+        );
+        
+        // Add classification columns if they don't exist (for existing databases)
+        let _ = self.conn.execute(
+            "ALTER TABLE documents ADD COLUMN category VARCHAR",
+            [],
+        );
+        let _ = self.conn.execute(
+            "ALTER TABLE documents ADD COLUMN category_score DOUBLE",
+            [],
+        );
+        
+        // Add collection routing columns if they don't exist (for existing databases)
+        let _ = self.conn.execute(
+            "ALTER TABLE documents ADD COLUMN collection VARCHAR",
+            [],
+        );
+        let _ = self.conn.execute(
+            "ALTER TABLE documents ADD COLUMN tags VARCHAR[]",
+            [],
+        );
+        
+        // Add raw path column if it doesn't exist (for existing databases)
+        let _ = self.conn.execute(
+            "ALTER TABLE documents ADD COLUMN file_path_raw BLOB",
+            [],
+        );
+        
+        // Add extraction quality columns if they don't exist (for existing databases)
+        let _ = self.conn.execute(
+            "ALTER TABLE documents ADD COLUMN quality_score DOUBLE",
+            [],
+        );
+        let _ = self.conn.execute(
+            "ALTER TABLE documents ADD COLUMN quality_flags VARCHAR[]",
+            [],
+        );
+        
+        // Add title column if it doesn't exist (for existing databases)
+        let _ = self.conn.execute(
+            "ALTER TABLE documents ADD COLUMN title VARCHAR",
+            [],
+        );
+        
+        // Add tombstone column if it doesn't exist (for existing databases)
+        let _ = self.conn.execute(
+            "ALTER TABLE documents ADD COLUMN deleted_at TIMESTAMP",
+            [],
+        );
+        
+        // Add extracted text column if it doesn't exist (for existing databases)
+        let _ = self.conn.execute(
+            "ALTER TABLE documents ADD COLUMN extracted_text BLOB",
+            [],
+        );
+        
+        // Add change detection columns if they don't exist (for existing databases)
+        let _ = self.conn.execute(
+            "ALTER TABLE documents ADD COLUMN content_hash VARCHAR",
+            [],
+        );
+        let _ = self.conn.execute(
+            "ALTER TABLE documents ADD COLUMN modified_at TIMESTAMP",
+            [],
+        );
+        
+        // Add indexing state column if it doesn't exist (for existing databases, whose
+        // documents are left without a state)
+        let _ = self.conn.execute(
+            "ALTER TABLE documents ADD COLUMN index_state VARCHAR",
+            [],
+        );
+        
+        // Add original encoding column if it doesn't exist (for existing databases, whose
+        // originals are stored as read)
+        let _ = self.conn.execute(
+            "ALTER TABLE documents ADD COLUMN file_encoding VARCHAR",
+            [],
+        );
+        
         // Create fragments table
         self.conn.execute(
-            "CREATE TABLE IF NOT EXISTS fragments (
-                id VARCHAR PRIMARY KEY,
-                document_id VARCHAR NOT NULL,
-                fragment_order INTEGER NOT NULL,
-                content TEXT NOT NULL,
-                embedding DOUBLE[],
-                created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
-                FOREIGN KEY (document_id) REFERENCES documents(id)
-            )",
+            &fragments_table(None),
             [],
         ).context("Failed to create fragments table")?;
         
+        // Add segment column if it doesn't exist (for existing databases)
+        let _ = self.conn.execute(
+            "ALTER TABLE fragments ADD COLUMN segment INTEGER DEFAULT 0",
+            [],
+        );
+        
+        // Add section column if it doesn't exist (for existing databases)
+        let _ = self.conn.execute(
+            "ALTER TABLE fragments ADD COLUMN section VARCHAR",
+            [],
+        );
+        
+        // Add page column if it doesn't exist (for existing databases)
+        let _ = self.conn.execute(
+            "ALTER TABLE fragments ADD COLUMN page INTEGER",
+            [],
+        );
+        
+        // Add structure column if it doesn't exist (for existing databases)
+        let _ = self.conn.execute(
+            "ALTER TABLE fragments ADD COLUMN structure VARCHAR",
+            [],
+        );
+        
+        // Add location column if it doesn't exist (for existing databases)
+        let _ = self.conn.execute(
+            "ALTER TABLE fragments ADD COLUMN location VARCHAR",
+            [],
+        );
+        
+        // Add re-embedding columns if they don't exist (for existing databases)
+        let _ = self.conn.execute(
+            "ALTER TABLE fragments ADD COLUMN stale BOOLEAN DEFAULT FALSE",
+            [],
+        );
+        let _ = self.conn.execute(
+            "ALTER TABLE fragments ADD COLUMN hit_count INTEGER DEFAULT 0",
+            [],
+        );
+        let _ = self.conn.execute(
+            "ALTER TABLE fragments ADD COLUMN embedded_at TIMESTAMP",
+            [],
+        );
+        
         // Create index on document_id and fragment_order
         self.conn.execute(
             "CREATE INDEX IF NOT EXISTS idx_fragments_doc_order 
@@ -81,107 +311,1247 @@ impl DuckDBStorage {
             [],
         ).context("Failed to create fragments index")?;
         
+        // Fragments the embedding server failed on, set aside until the next run retries them
+        self.conn.execute(
+            "CREATE TABLE IF NOT EXISTS embedding_failures (
+                fragment_id VARCHAR PRIMARY KEY,
+                error VARCHAR,
+                attempts INTEGER DEFAULT 0,
+                failed_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+                set_aside BOOLEAN DEFAULT TRUE
+            )",
+            [],
+        ).context("Failed to create embedding failures table")?;
+        
+        // Entities named by each fragment, extracted when entities are first looked up;
+        // fragments naming none get one row without an entity so they aren't scanned again
+        self.conn.execute(
+            "CREATE TABLE IF NOT EXISTS entities (
+                fragment_id VARCHAR NOT NULL,
+                entity VARCHAR,
+                name VARCHAR
+            )",
+            [],
+        ).context("Failed to create entities table")?;
+        
+        // Hash of each fragment's text (`chunk_hash`), kept up to date when vectors are looked
+        // up so identical chunks are embedded once
+        self.conn.execute(
+            "CREATE TABLE IF NOT EXISTS chunk_hashes (
+                fragment_id VARCHAR NOT NULL,
+                chunk_hash VARCHAR NOT NULL
+            )",
+            [],
+        ).context("Failed to create chunk hashes table")?;
+        
+        // Reviewers' labels and notes on fragments or whole documents; `target_id` is either
+        self.conn.execute(
+            "CREATE TABLE IF NOT EXISTS annotations (
+                id VARCHAR PRIMARY KEY,
+                target_id VARCHAR NOT NULL,
+                label VARCHAR,
+                note VARCHAR,
+                author VARCHAR NOT NULL,
+                created_at TIMESTAMP
+            )",
+            [],
+        ).context("Failed to create annotations table")?;
+        
         info!("DuckDB tables initialized successfully");
         Ok(())
     }
+
+    /// Vector length of the compact fragment layout, or `None` for the standard layout
+    pub fn compact_dimension(&self) -> Option<usize> {
+        self.compact
+    }
+
+    /// Rebuild the fragments table in the compact layout, which shrinks the database and
+    /// speeds up similarity scans. Every vector must have the recorded dimension, so a model
+    /// upgrade has to finish first. Returns the vector length.
+    pub fn compact(&mut self) -> Result<usize> {
+        let dimension: Option<usize> = self.conn.query_row(
+            "SELECT value FROM meta WHERE key = ?",
+            params![DIMENSION_KEY],
+            |row| row.get::<_, String>(0),
+        ).ok().and_then(|value| value.parse().ok());
+        let dimension = dimension
+            .ok_or_else(|| anyhow::anyhow!("No embedding dimension recorded; run `embed` before compacting"))?;
+
+        let stale: i64 = self.conn.query_row("SELECT COUNT(*) FROM fragments WHERE stale", [], |row| row.get(0))?;
+        if stale > 0 {
+            anyhow::bail!("{} fragments still have vectors from the previous model; finish the upgrade with `embed` first", stale);
+        }
+        let mismatched: i64 = self.conn.query_row(
+            "SELECT COUNT(*) FROM fragments WHERE embedding IS NOT NULL AND len(embedding) != ?",
+            params![dimension as i64],
+            |row| row.get(0),
+        )?;
+        if mismatched > 0 {
+            anyhow::bail!("{} fragments have vectors that aren't {}-dimensional", mismatched, dimension);
+        }
+
+        self.rebuild_fragments(Some(dimension))?;
+        Ok(dimension)
+    }
+
+    /// Rebuild the fragments table in the standard layout, whose vectors may have any length
+    /// Run a statement prepared once per connection; indexing calls the same few
+    /// statements for every document and fragment, so they skip parsing and planning
+    fn execute_cached<P: Params>(&self, sql: &str, params: P) -> duckdb::Result<usize> {
+        self.conn.prepare_cached(sql)?.execute(params)
+    }
+
+    pub fn expand(&mut self) -> Result<()> {
+        self.rebuild_fragments(None)
+    }
+
+    /// Copy every fragment into a fresh fragments table with the given layout
+    fn rebuild_fragments(&mut self, compact: Option<usize>) -> Result<()> {
+        const FRAGMENT_COLUMNS: &str = "id, document_id, fragment_order, segment, section, page, structure, location, content, \
+            embedding, stale, hit_count, embedded_at, created_at";
+        let embedding_type = match compact {
+            Some(dimension) => format!("FLOAT[{}]", dimension),
+            None => "FLOAT[]".to_string(),
+        };
+        let selected = FRAGMENT_COLUMNS.replace("embedding,", &format!("CAST(embedding AS {}),", embedding_type));
+        let layout = match compact {
+            Some(dimension) => format!("INSERT OR REPLACE INTO meta (key, value) VALUES ('{}', '{}')", COMPACT_LAYOUT_KEY, dimension),
+            None => format!("DELETE FROM meta WHERE key = '{}'", COMPACT_LAYOUT_KEY),
+        };
+
+        // The full-text index is rebuilt from the new table on the next keyword search; the
+        // HNSW index has its own copy of the vectors and stays
+        let migration = format!(
+            "BEGIN TRANSACTION;
+             CREATE TEMPORARY TABLE fragments_copy AS SELECT * FROM fragments;
+             DROP INDEX IF EXISTS idx_fragments_doc_order;
+             DROP TABLE fragments;
+             {create};
+             INSERT INTO fragments ({columns}) SELECT {selected} FROM fragments_copy;
+             DROP TABLE fragments_copy;
+             CREATE INDEX idx_fragments_doc_order ON fragments(document_id, fragment_order);
+             DELETE FROM meta WHERE key = '{fts}';
+             {layout};
+             COMMIT;",
+            create = fragments_table(compact),
+            columns = FRAGMENT_COLUMNS,
+            fts = FTS_FINGERPRINT_KEY,
+        );
+        // Cached statements were planned against the table being replaced
+        self.conn.flush_prepared_statement_cache();
+        if let Err(e) = self.conn.execute_batch(&migration) {
+            let _ = self.conn.execute_batch("ROLLBACK");
+            return Err(e).context("Failed to rebuild fragments table");
+        }
+
+        // Write the new table out compressed now rather than at the next automatic checkpoint
+        self.conn.execute_batch("CHECKPOINT").context("Failed to checkpoint database")?;
+        self.compact = compact;
+        Ok(())
+    }
+
+    /// Load DuckDB's vector similarity search extension, installing it the first time (except
+    /// in strict offline mode), with HNSW indexes allowed in database files. Returns false
+    /// when it can't be loaded.
+    fn load_vss(&self) -> bool {
+        let loaded = self.conn.execute_batch("LOAD vss").is_ok()
+            || (!offline::is_enabled() && self.conn.execute_batch("INSTALL vss; LOAD vss").is_ok());
+        loaded && self.conn.execute_batch("SET hnsw_enable_experimental_persistence = true").is_ok()
+    }
+
+    /// The id derived from the fragment's document bytes and span, when ids are content-derived,
+    /// the span is known, and no other fragment has it yet (a copy of the file under another name)
+    fn span_fragment_id(&self, document_id: &str, meta: &FragmentMeta) -> Result<Option<String>> {
+        let Some(span) = meta.span.filter(|_| self.ids == IdScheme::Content) else {
+            return Ok(None);
+        };
+        let hash: Option<String> = self.conn.prepare_cached("SELECT content_hash FROM documents WHERE id = ?")?
+            .query_row(params![document_id], |row| row.get(0))
+            .ok().flatten();
+        let Some(fragment_id) = hash.map(|hash| IdScheme::span_fragment_id(&hash, span)) else {
+            return Ok(None);
+        };
+
+        let taken: i64 = self.conn.prepare_cached("SELECT COUNT(*) FROM fragments WHERE id = ?")?
+            .query_row(params![&fragment_id], |row| row.get(0))?;
+        Ok((taken == 0).then_some(fragment_id))
+    }
+
+    /// Drop the HNSW index and its copy of the vectors, when they no longer match the
+    /// fragments' model or metric
+    fn drop_vector_index(&mut self) -> Result<()> {
+        // Indexes built before they had their own table covered the fragments table
+        self.conn.execute_batch(&format!(
+            "DROP INDEX IF EXISTS idx_fragments_hnsw;
+             DROP TABLE IF EXISTS {};
+             DELETE FROM meta WHERE key = '{}';",
+            VECTOR_INDEX_TABLE, VECTOR_INDEX_KEY,
+        )).context("Failed to drop HNSW index")?;
+        self.vector_index = false;
+        Ok(())
+    }
+
+    /// The `limit` fragments nearest the query among those the HNSW index returns first,
+    /// keeping those `filter` allows
+    fn nearest_fragments(&self, query_list: &str, dimension: usize, limit: usize, filter: &SearchFilter) -> Result<Vec<FragmentMatch>> {
+        let (conditions, filter_params) = filter_conditions(filter);
+        let (after, after_params) = after_condition(filter);
+        let pool = (limit * VECTOR_INDEX_OVERSAMPLE).max(VECTOR_INDEX_MIN_POOL);
+
+        // The index answers a plain top-k on its metric's distance to a constant vector, so
+        // the query is inlined rather than bound and filters apply to the index's candidates.
+        // Fragments are scored by their own vectors, which the index's copies match.
+        let query = format!("{}::FLOAT[{}]", query_list, dimension);
+        let (_, distance) = hnsw_metric(self.metric);
+        let mut stmt = self.conn.prepare(&with_sources(&format!(
+            "SELECT * FROM (
+                SELECT *, {score} AS score, {rank_path}
+                FROM fragments
+                WHERE id IN (
+                    SELECT fragment_id FROM {table}
+                    ORDER BY {distance}(embedding, {query})
+                    LIMIT {pool}
+                )
+                AND embedding IS NOT NULL AND NOT stale{conditions}
+             )
+             WHERE true{after}
+             ORDER BY score DESC, rank_path, fragment_order
+             LIMIT {limit}",
+            score = self.score_expression(&format!("{}::{}", query_list, self.embedding_type())),
+            rank_path = RANK_PATH,
+            table = VECTOR_INDEX_TABLE,
+        )))?;
+
+        let rows = stmt.query_map(params_from_iter(filter_params.into_iter().chain(after_params)), fragment_match)?;
+
+        let mut results = Vec::new();
+        for row in rows {
+            results.push(row?);
+        }
+        Ok(results)
+    }
+
+    /// The `limit` best fragments by comparing the query with every vector `filter` allows,
+    /// or only with those the HNSW index doesn't cover yet when `unindexed`
+    fn scan_fragments(&self, query_list: &str, limit: usize, filter: &SearchFilter, unindexed: bool) -> Result<Vec<FragmentMatch>> {
+        let (conditions, filter_params) = filter_conditions(filter);
+        let (after, after_params) = after_condition(filter);
+        let coverage = if unindexed {
+            format!(" AND id NOT IN (SELECT fragment_id FROM {})", VECTOR_INDEX_TABLE)
+        } else {
+            String::new()
+        };
+
+        let mut stmt = self.conn.prepare(&with_sources(&format!(
+            "SELECT * FROM (
+                SELECT *, {} AS score, {}
+                FROM fragments
+                WHERE embedding IS NOT NULL AND stale = {}{}{}
+             )
+             WHERE true{}
+             ORDER BY score DESC, rank_path, fragment_order
+             LIMIT {}",
+            self.score_expression(&format!("?::{}", self.embedding_type())),
+            RANK_PATH, filter.stale, coverage, conditions, after, limit
+        )))?;
+
+        let query_params = std::iter::once(query_list.to_string()).chain(filter_params).chain(after_params);
+        let rows = stmt.query_map(params_from_iter(query_params), fragment_match)?;
+
+        let mut results = Vec::new();
+        for row in rows {
+            results.push(row?);
+        }
+        Ok(results)
+    }
+
+    /// SQL scoring fragments' embeddings against `query` by the database's metric
+    fn score_expression(&self, query: &str) -> String {
+        let prefix = if self.compact.is_some() { "array" } else { "list" };
+        match self.metric {
+            SimilarityMetric::Cosine => format!("{}_cosine_similarity(embedding, {})", prefix, query),
+            SimilarityMetric::Dot => format!("{}_inner_product(embedding, {})", prefix, query),
+            SimilarityMetric::Euclidean => format!("1.0 / (1.0 + {}_distance(embedding, {}))", prefix, query),
+        }
+    }
+
+    /// How a vector parameter is cast to the embedding column's type
+    fn embedding_type(&self) -> String {
+        match self.compact {
+            Some(dimension) => format!("FLOAT[{}]", dimension),
+            None => "FLOAT[]".to_string(),
+        }
+    }
+
+    /// Load the full-text search extension and (re)build the BM25 index over fragment
+    /// content. DuckDB doesn't maintain FTS indexes as rows change, so the index is rebuilt
+    /// whenever the set of fragments differs from the one it was built from.
+    fn ensure_fts_index(&mut self) -> Result<()> {
+        if self.conn.execute_batch("LOAD fts").is_err() {
+            if offline::is_enabled() {
+                return Err(offline::refused("Installing the DuckDB full-text search extension (keyword and hybrid search)"));
+            }
+            self.conn.execute_batch("INSTALL fts; LOAD fts")
+                .context("Failed to load the DuckDB full-text search extension (keyword and hybrid search need it installed once, with network access)")?;
+        }
+
+        let fingerprint = self.fragments_fingerprint()?;
+        let indexed: Option<String> = self.conn.query_row(
+            "SELECT value FROM meta WHERE key = ?",
+            params![FTS_FINGERPRINT_KEY],
+            |row| row.get(0),
+        ).ok();
+        if indexed.as_deref() == Some(fingerprint.as_str()) {
+            return Ok(());
+        }
+
+        info!("Building full-text index over fragments");
+        self.conn.execute_batch(
+            "PRAGMA create_fts_index('fragments', 'id', 'content', stemmer = 'porter', stopwords = 'english', overwrite = 1)"
+        ).context("Failed to build full-text index")?;
+        self.conn.execute(
+            "INSERT OR REPLACE INTO meta (key, value) VALUES (?, ?)",
+            params![FTS_FINGERPRINT_KEY, fingerprint],
+        )?;
+        Ok(())
+    }
+
+    /// Changes whenever fragments are added or removed
+    fn fragments_fingerprint(&self) -> Result<String> {
+        Ok(self.conn.query_row(
+            "SELECT count(*)::VARCHAR || ':' || coalesce(sum(hash(id))::VARCHAR, '0') FROM fragments",
+            [],
+            |row| row.get(0),
+        )?)
+    }
+
+    /// Bring the entity index up to date with the fragments: entries of removed fragments
+    /// are dropped and fragments not scanned yet have their entities extracted. Like the
+    /// full-text index, it is left alone while the set of fragments is unchanged.
+    fn ensure_entity_index(&mut self) -> Result<()> {
+        let fingerprint = self.fragments_fingerprint()?;
+        let indexed: Option<String> = self.conn.query_row(
+            "SELECT value FROM meta WHERE key = ?",
+            params![ENTITY_FINGERPRINT_KEY],
+            |row| row.get(0),
+        ).ok();
+        if indexed.as_deref() == Some(fingerprint.as_str()) {
+            return Ok(());
+        }
+
+        self.conn.execute("DELETE FROM entities WHERE fragment_id NOT IN (SELECT id FROM fragments)", [])
+            .context("Failed to prune entity index")?;
+        let mut stmt = self.conn.prepare(
+            "SELECT id, content FROM fragments WHERE id NOT IN (SELECT fragment_id FROM entities)"
+        )?;
+        let fragments = stmt.query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))?
+            .collect::<duckdb::Result<Vec<_>>>()?;
+        drop(stmt);
+
+        if !fragments.is_empty() {
+            info!("Extracting entities from {} fragments", fragments.len());
+        }
+        let mut appender = self.conn.appender("entities")?;
+        for (id, content) in &fragments {
+            let names = entities::extract(content);
+            if names.is_empty() {
+                appender.append_row(params![id, None::<String>, None::<String>])?;
+            }
+            for name in &names {
+                appender.append_row(params![id, entities::key(name), name])?;
+            }
+        }
+        appender.flush().context("Failed to write entity index")?;
+
+        self.conn.execute(
+            "INSERT OR REPLACE INTO meta (key, value) VALUES (?, ?)",
+            params![ENTITY_FINGERPRINT_KEY, fingerprint],
+        )?;
+        Ok(())
+    }
+
+    /// Bring the chunk hash index up to date with the fragments, as `ensure_entity_index`
+    /// does the entity index
+    fn ensure_chunk_index(&mut self) -> Result<()> {
+        let fingerprint = self.fragments_fingerprint()?;
+        let indexed: Option<String> = self.conn.query_row(
+            "SELECT value FROM meta WHERE key = ?",
+            params![CHUNK_FINGERPRINT_KEY],
+            |row| row.get(0),
+        ).ok();
+        if indexed.as_deref() == Some(fingerprint.as_str()) {
+            return Ok(());
+        }
+
+        self.conn.execute("DELETE FROM chunk_hashes WHERE fragment_id NOT IN (SELECT id FROM fragments)", [])
+            .context("Failed to prune chunk hash index")?;
+        let mut stmt = self.conn.prepare(
+            "SELECT id, content FROM fragments WHERE id NOT IN (SELECT fragment_id FROM chunk_hashes)"
+        )?;
+        let fragments = stmt.query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))?
+            .collect::<duckdb::Result<Vec<_>>>()?;
+        drop(stmt);
+
+        let mut appender = self.conn.appender("chunk_hashes")?;
+        for (id, content) in &fragments {
+            appender.append_row(params![id, chunk_hash(content)])?;
+        }
+        appender.flush().context("Failed to write chunk hash index")?;
+
+        self.conn.execute(
+            "INSERT OR REPLACE INTO meta (key, value) VALUES (?, ?)",
+            params![CHUNK_FINGERPRINT_KEY, fingerprint],
+        )?;
+        Ok(())
+    }
+}
+
+/// SQL condition on an `entity` column matching an entity key as `entities::matches` does,
+/// taking the key three times
+const ENTITY_MATCH: &str = "(entity = ? OR ends_with(entity, ' ' || ?) OR starts_with(entity, ? || ' '))";
+
+/// Parse a FLOAT[] (or, before migration, DOUBLE[]) rendered as VARCHAR (e.g. `[0.1, -0.2]`) back into a vector
+fn parse_embedding(text: &str) -> Result<Vec<f64>> {
+    serde_json::from_str(text).context("Failed to parse stored embedding")
+}
+
+/// Wrap a query returning ranked fragment rows with a `score` column, adding the columns
+/// `fragment_match` reads
+fn with_sources(ranked: &str) -> String {
+    format!(
+        "SELECT f.id, f.content, f.score, d.filename, d.file_path, f.fragment_order, f.section, f.page, f.structure,
+                f.location, epoch_us(d.modified_at), epoch_us(d.created_at), d.title,
+                (SELECT to_json(list({{'label': a.label, 'note': a.note, 'author': a.author}} ORDER BY a.created_at))::VARCHAR
+                 FROM annotations a WHERE a.target_id IN (f.id, f.document_id)),
+                d.id
+         FROM ({}) f
+         JOIN documents d ON d.id = f.document_id
+         ORDER BY f.score DESC, d.file_path, f.fragment_order",
+        ranked
+    )
+}
+
+/// Read a row of a query built by `with_sources`
+fn fragment_match(row: &duckdb::Row<'_>) -> duckdb::Result<FragmentMatch> {
+    Ok(FragmentMatch {
+        fragment_id: row.get(0)?,
+        content: row.get(1)?,
+        score: row.get(2)?,
+        source: FragmentSource {
+            document_id: row.get(14)?,
+            filename: row.get(3)?,
+            file_path: row.get(4)?,
+            order: row.get(5)?,
+            section: row.get(6)?,
+            page: row.get(7)?,
+            structure: row.get::<_, Option<String>>(8)?.as_deref().and_then(Structure::parse),
+            location: row.get::<_, Option<String>>(9)?.as_deref().and_then(Location::parse),
+            modified: row.get(10)?,
+            indexed: row.get(11)?,
+            title: row.get(12)?,
+            annotations: row.get::<_, Option<String>>(13)?
+                .and_then(|json| serde_json::from_str(&json).ok())
+                .unwrap_or_default(),
+        },
+    })
+}
+
+/// SQL conditions (each starting with ` AND `) restricting fragments to those a search may
+/// return, with their parameters in order
+fn filter_conditions(filter: &SearchFilter) -> (String, Vec<String>) {
+    // Documents whose source file is gone stay stored but are never answered from
+    let mut conditions = String::from(" AND document_id NOT IN (SELECT id FROM documents WHERE deleted_at IS NOT NULL)");
+    // Nor from anything a reviewer marked wrong
+    conditions.push_str(" AND id NOT IN (SELECT target_id FROM annotations WHERE label = 'wrong')");
+    conditions.push_str(" AND document_id NOT IN (SELECT target_id FROM annotations WHERE label = 'wrong')");
+    if !filter.categories.is_empty() {
+        let placeholders = vec!["?"; filter.categories.len()].join(", ");
+        conditions.push_str(&format!(
+            " AND document_id IN (SELECT id FROM documents WHERE category IN ({}))",
+            placeholders
+        ));
+    }
+    // Matches the heading at any depth, so a section's subsections are included
+    if filter.section.is_some() {
+        conditions.push_str(" AND contains(lower(section), ?)");
+    }
+    if !filter.collections.is_empty() {
+        let placeholders = vec!["?"; filter.collections.len()].join(", ");
+        conditions.push_str(&format!(
+            " AND document_id IN (SELECT id FROM documents WHERE collection IN ({}))",
+            placeholders
+        ));
+    }
+    if filter.document.is_some() {
+        conditions.push_str(" AND document_id = ?");
+    }
+    // The entity index must be brought up to date first (`ensure_entity_index`)
+    if filter.entity.is_some() {
+        conditions.push_str(&format!(" AND id IN (SELECT fragment_id FROM entities WHERE {})", ENTITY_MATCH));
+    }
+    if !filter.tags.is_empty() {
+        let placeholders = vec!["?"; filter.tags.len()].join(", ");
+        conditions.push_str(&format!(
+            " AND document_id IN (SELECT id FROM documents WHERE list_has_any(tags, [{}]))",
+            placeholders
+        ));
+    }
+    if !filter.file_types.is_empty() {
+        let placeholders = vec!["?"; filter.file_types.len()].join(", ");
+        conditions.push_str(&format!(
+            " AND document_id IN (SELECT id FROM documents WHERE file_type IN ({}))",
+            placeholders
+        ));
+    }
+    let path = filter.path.as_deref().map(path_glob);
+    if let Some((_, whole_path)) = &path {
+        let column = if *whole_path { "file_path" } else { "filename" };
+        conditions.push_str(&format!(" AND document_id IN (SELECT id FROM documents WHERE {} GLOB ?)", column));
+    }
+    // Documents indexed before modification times were recorded are dated by when they were indexed
+    if filter.modified_after.is_some() {
+        conditions.push_str(" AND document_id IN (SELECT id FROM documents WHERE epoch_us(COALESCE(modified_at, created_at)) >= ?::BIGINT)");
+    }
+    if filter.modified_before.is_some() {
+        conditions.push_str(" AND document_id IN (SELECT id FROM documents WHERE epoch_us(COALESCE(modified_at, created_at)) < ?::BIGINT)");
+    }
+
+    let entity = filter.entity.as_deref().map(entities::key);
+    let params = filter.categories.iter().cloned()
+        .chain(filter.section.iter().map(|section| section.to_lowercase()))
+        .chain(filter.collections.iter().cloned())
+        .chain(filter.document.iter().cloned())
+        .chain(entity.iter().flat_map(|key| std::iter::repeat_n(key.clone(), 3)))
+        .chain(filter.tags.iter().cloned())
+        .chain(filter.file_types.iter().cloned())
+        .chain(path.map(|(glob, _)| glob))
+        .chain(filter.modified_after.map(|after| after.to_string()))
+        .chain(filter.modified_before.map(|before| before.to_string()))
+        .collect();
+    (conditions, params)
+}
+
+/// SQL conditions (each starting with ` AND `) on the documents table for the document-level
+/// parts of a filter, as `bulk_update` selects documents, with their parameters in order
+fn document_conditions(filter: &SearchFilter) -> (String, Vec<String>) {
+    let placeholders = |values: &[String]| vec!["?"; values.len()].join(", ");
+    let mut conditions = String::new();
+    if !filter.categories.is_empty() {
+        conditions.push_str(&format!(" AND category IN ({})", placeholders(&filter.categories)));
+    }
+    if !filter.collections.is_empty() {
+        conditions.push_str(&format!(" AND collection IN ({})", placeholders(&filter.collections)));
+    }
+    if filter.document.is_some() {
+        conditions.push_str(" AND id = ?");
+    }
+    if !filter.tags.is_empty() {
+        conditions.push_str(&format!(" AND list_has_any(tags, [{}])", placeholders(&filter.tags)));
+    }
+    if !filter.file_types.is_empty() {
+        conditions.push_str(&format!(" AND file_type IN ({})", placeholders(&filter.file_types)));
+    }
+    let path = filter.path.as_deref().map(path_glob);
+    if let Some((_, whole_path)) = &path {
+        conditions.push_str(if *whole_path { " AND file_path GLOB ?" } else { " AND filename GLOB ?" });
+    }
+    // Matching `list_documents`, which only reports recorded modification times
+    if filter.modified_after.is_some() {
+        conditions.push_str(" AND epoch_us(modified_at) >= ?::BIGINT");
+    }
+    if filter.modified_before.is_some() {
+        conditions.push_str(" AND epoch_us(modified_at) < ?::BIGINT");
+    }
+
+    let params = filter.categories.iter().cloned()
+        .chain(filter.collections.iter().cloned())
+        .chain(filter.document.iter().cloned())
+        .chain(filter.tags.iter().cloned())
+        .chain(filter.file_types.iter().cloned())
+        .chain(path.map(|(glob, _)| glob))
+        .chain(filter.modified_after.map(|after| after.to_string()))
+        .chain(filter.modified_before.map(|before| before.to_string()))
+        .collect();
+    (conditions, params)
+}
+
+/// Expression ranking fragments like `RankPosition`, by document path after score
+const RANK_PATH: &str = "(SELECT file_path FROM documents WHERE documents.id = fragments.document_id) AS rank_path";
+
+/// SQL condition (starting with ` AND `) keeping ranked rows after `filter.after`, with its
+/// parameters in order
+fn after_condition(filter: &SearchFilter) -> (&'static str, Vec<String>) {
+    match &filter.after {
+        Some(after) => (
+            " AND (score < ?::DOUBLE OR (score = ?::DOUBLE AND (rank_path > ? OR (rank_path = ? AND fragment_order > ?::INTEGER))))",
+            vec![
+                after.score.to_string(),
+                after.score.to_string(),
+                after.file_path.clone(),
+                after.file_path.clone(),
+                after.order.to_string(),
+            ],
+        ),
+        None => ("", Vec::new()),
+    }
+}
+
+/// Writes fragment batches from any backend to a Parquet file in `fragment_schema`'s
+/// columns, collecting them in an in-memory DuckDB that scans each batch in place
+pub struct ParquetWriter {
+    conn: Connection,
+    rows: usize,
+}
+
+impl ParquetWriter {
+    pub fn new() -> Result<Self> {
+        let conn = Connection::open_in_memory()
+            .context("Failed to open in-memory DuckDB")?;
+        conn.register_table_function::<ArrowVTab>("arrow")
+            .context("Failed to register the Arrow table function")?;
+        conn.execute_batch(
+            "CREATE TABLE fragments (
+                id VARCHAR NOT NULL,
+                document_id VARCHAR NOT NULL,
+                fragment_order INTEGER NOT NULL,
+                segment INTEGER NOT NULL,
+                section VARCHAR,
+                page INTEGER,
+                structure VARCHAR,
+                location VARCHAR,
+                content VARCHAR NOT NULL,
+                embedding FLOAT[],
+                stale BOOLEAN NOT NULL
+            )"
+        )?;
+        Ok(Self { conn, rows: 0 })
+    }
+
+    /// Add a batch of fragments in `fragment_schema`
+    pub fn append(&mut self, batch: &RecordBatch) -> Result<()> {
+        let batch = conform_fragment_batch(batch)?;
+        self.rows += self.conn.execute(
+            "INSERT INTO fragments SELECT * FROM arrow(?, ?)",
+            arrow_recordbatch_to_query_params(batch),
+        ).context("Failed to collect fragment batch")?;
+        Ok(())
+    }
+
+    /// Write the collected fragments, ZSTD-compressed, to `path`. Returns the number written.
+    pub fn finish(self, path: &Path) -> Result<usize> {
+        let target = path.to_string_lossy().replace('\'', "''");
+        self.conn.execute_batch(&format!("COPY fragments TO '{}' (FORMAT parquet, COMPRESSION zstd)", target))
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+        Ok(self.rows)
+    }
+}
+
+/// Reads fragment batches back from a Parquet file written by `ParquetWriter`, in id order
+pub struct ParquetReader {
+    conn: Connection,
+}
+
+impl ParquetReader {
+    pub fn open(path: &Path) -> Result<Self> {
+        let conn = Connection::open_in_memory()
+            .context("Failed to open in-memory DuckDB")?;
+        let source = path.to_string_lossy().replace('\'', "''");
+        conn.execute_batch(&format!("CREATE VIEW fragments AS SELECT * FROM read_parquet('{}')", source))
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        Ok(Self { conn })
+    }
+
+    /// Up to `limit` fragments with ids after `after`, or `None` once all have been read
+    pub fn read_batch(&self, after: Option<&str>, limit: usize) -> Result<Option<RecordBatch>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT id, document_id, fragment_order, segment, section, page, structure, location, content,
+                    CAST(embedding AS FLOAT[]) AS embedding, stale
+             FROM fragments WHERE id > COALESCE(?, '')
+             ORDER BY id
+             LIMIT ?"
+        )?;
+        let batches = stmt.query_arrow(params![after, limit as i64])?
+            .map(|batch| conform_fragment_batch(&batch))
+            .collect::<Result<Vec<_>>>()?;
+        
+        let batch = crate::storage::arrow::compute::concat_batches(&fragment_schema(), &batches)?;
+        Ok((batch.num_rows() > 0).then_some(batch))
+    }
 }
 
 #[async_trait]
 impl Storage for DuckDBStorage {
     async fn initialize(&mut self) -> Result<()> {
-        self.initialize_tables().await
+        self.initialize_tables().await?;
+        self.ids = IdScheme::from_meta(self.get_meta_value(DETERMINISTIC_IDS_KEY).await?.as_deref());
+        self.originals = OriginalsMode::from_meta(self.get_meta_value(STORE_ORIGINALS_KEY).await?.as_deref());
+        self.metric = SimilarityMetric::from_meta(self.get_meta_value(SIMILARITY_METRIC_KEY).await?.as_deref());
+        self.compact = self.get_meta_value(COMPACT_LAYOUT_KEY).await?.and_then(|value| value.parse().ok());
+        // Databases created before vectors were stored as f32 hold DOUBLE lists
+        let column_type: Option<String> = self.conn.query_row(
+            "SELECT data_type FROM information_schema.columns WHERE table_name = 'fragments' AND column_name = 'embedding'",
+            [],
+            |row| row.get(0),
+        ).ok();
+        if self.compact.is_none() && column_type.as_deref() == Some("DOUBLE[]") {
+            info!("Converting stored vectors from DOUBLE to FLOAT lists");
+            self.expand().context("Failed to convert stored vectors to FLOAT lists")?;
+        }
+        if self.get_meta_value(VECTOR_INDEX_KEY).await?.is_some() {
+            self.vector_index = self.load_vss();
+            if !self.vector_index {
+                warn!("The database has an HNSW index but DuckDB's vss extension can't be loaded; searches scan every fragment");
+            } else if self.conn.query_row(
+                "SELECT COUNT(*) FROM duckdb_indexes() WHERE index_name = 'idx_fragments_hnsw'",
+                [],
+                |row| row.get::<_, i64>(0),
+            )? > 0 {
+                info!("Moving the HNSW index to a table of its own; the next embed builds it again");
+                self.drop_vector_index()?;
+            }
+        }
+        Ok(())
     }
 
-    async fn verify_or_set_model(&mut self, model_name: &str) -> Result<()> {
-        // Check if version and model are already set
-        let mut stmt = self.conn.prepare(
-            "SELECT value FROM meta WHERE key = ?"
-        )?;
+    async fn verify_or_set_model(&mut self, model_name: &str, dimension: Option<usize>) -> Result<()> {
+        // The statement is dropped before the dimension check awaits
+        {
+            // Check if version and model are already set
+            let mut stmt = self.conn.prepare(
+                "SELECT value FROM meta WHERE key = ?"
+            )?;
         
-        // Check version
-        let version_result: Result<String, _> = stmt.query_row(params!["version"], |row| {
-            Ok(row.get(0)?)
-        });
-        
-        match version_result {
-            Ok(existing_version) => {
-                if existing_version != DB_VERSION {
-                    anyhow::bail!(
-                        "Database version mismatch. Expected: {}, Found: {}",
-                        DB_VERSION, existing_version
-                    );
+            // Check version
+            let version_result: Result<String, _> = stmt.query_row(params!["version"], |row| {
+                Ok(row.get(0)?)
+            });
+        
+            match version_result {
+                Ok(existing_version) => {
+                    if existing_version != DB_VERSION {
+                        anyhow::bail!(
+                            "Database version mismatch. Expected: {}, Found: {}",
+                            DB_VERSION, existing_version
+                        );
+                    }
+                }
+                Err(_) => {
+                    // Version not set, initialize it
+                    self.conn.execute(
+                        "INSERT INTO meta (key, value) VALUES (?, ?)",
+                        params!["version", DB_VERSION],
+                    )?;
+                    info!("Set database version to {}", DB_VERSION);
                 }
             }
-            Err(_) => {
-                // Version not set, initialize it
-                self.conn.execute(
-                    "INSERT INTO meta (key, value) VALUES (?, ?)",
-                    params!["version", DB_VERSION],
-                )?;
-                info!("Set database version to {}", DB_VERSION);
+        
+            // Check embedding model
+            let model_result: Result<String, _> = stmt.query_row(params!["embedding_model"], |row| {
+                Ok(row.get(0)?)
+            });
+        
+            match model_result {
+                Ok(existing_model) => {
+                    if existing_model != model_name {
+                        anyhow::bail!(
+                            "Embedding model mismatch. Expected: {}, Found: {}",
+                            model_name, existing_model
+                        );
+                    }
+                    info!("Verified embedding model: {}", model_name);
+                }
+                Err(_) => {
+                    // Model not set, initialize it
+                    self.conn.execute(
+                        "INSERT INTO meta (key, value) VALUES (?, ?)",
+                        params!["embedding_model", model_name],
+                    )?;
+                    info!("Set embedding model to {}", model_name);
+                }
             }
         }
         
-        // Check embedding model
-        let model_result: Result<String, _> = stmt.query_row(params!["embedding_model"], |row| {
+        // A model of the same name served another way can produce vectors of another length
+        if let Some(dimension) = dimension {
+            check_dimension(self, model_name, dimension).await?;
+        }
+        
+        Ok(())
+    }
+
+    async fn enable_deterministic_ids(&mut self) -> Result<()> {
+        self.set_meta_value(DETERMINISTIC_IDS_KEY, "true").await?;
+        self.ids = IdScheme::Content;
+        Ok(())
+    }
+
+    async fn set_originals_mode(&mut self, mode: OriginalsMode) -> Result<()> {
+        self.set_meta_value(STORE_ORIGINALS_KEY, mode.name()).await?;
+        self.originals = mode;
+        Ok(())
+    }
+
+    async fn set_similarity_metric(&mut self, metric: SimilarityMetric) -> Result<()> {
+        // The HNSW index is built for one metric; `build_vector_index` makes a new one
+        if metric != self.metric && self.get_meta_value(VECTOR_INDEX_KEY).await?.is_some() {
+            self.drop_vector_index()?;
+        }
+        self.set_meta_value(SIMILARITY_METRIC_KEY, metric.name()).await?;
+        self.metric = metric;
+        Ok(())
+    }
+
+    async fn document_exists(&mut self, file_path: &Path) -> Result<bool> {
+        let path = StoredPath::new(file_path);
+        // Rows written before paths were normalized hold the path as it was given
+        let mut stmt = self.conn.prepare_cached(
+            "SELECT COUNT(*) FROM documents WHERE file_path = ? OR file_path = ?"
+        )?;
+        
+        let count: i64 = stmt.query_row(params![&path.key, &path.legacy_key], |row| {
             Ok(row.get(0)?)
-        });
-        
-        match model_result {
-            Ok(existing_model) => {
-                if existing_model != model_name {
-                    anyhow::bail!(
-                        "Embedding model mismatch. Expected: {}, Found: {}",
-                        model_name, existing_model
-                    );
+        })?;
+        
+        Ok(count > 0)
+    }
+
+    async fn find_duplicate(&mut self, content_hash: &str) -> Result<Option<String>> {
+        let mut stmt = self.conn.prepare_cached(
+            "SELECT file_path FROM documents
+             WHERE content_hash = ? AND deleted_at IS NULL
+             ORDER BY file_path
+             LIMIT 1"
+        )?;
+        
+        let mut rows = stmt.query_map(params![content_hash], |row| row.get(0))?;
+        Ok(rows.next().transpose()?)
+    }
+
+    async fn store_document(&mut self, file_path: &Path, file_data: &[u8]) -> Result<String> {
+        let path = StoredPath::new(file_path);
+        let document_id = self.ids.document_id(&path.filename, file_data);
+        
+        // Determine file type from extension
+        let file_type = file_path.extension()
+            .and_then(|ext| ext.to_str())
+            .unwrap_or("unknown")
+            .to_lowercase();
+        
+        // The hash is of the original, so duplicates are found however it is stored
+        let (blob, encoding) = self.originals.encode(file_data)?;
+        self.execute_cached(
+            "INSERT INTO documents (id, filename, file_path, file_path_raw, file_type, file_data, file_encoding, content_hash, modified_at, index_state)
+             VALUES (?, ?, ?, ?, ?, ?, ?, ?, make_timestamp(?), ?)",
+            params![&document_id, &path.filename, &path.key, &path.raw, &file_type, blob, encoding,
+                    content_hash(file_data), modified_micros(file_path), IndexState::Stored.name()],
+        ).context("Failed to store document")?;
+        
+        Ok(document_id)
+    }
+
+    async fn set_index_state(&mut self, document_id: &str, state: IndexState) -> Result<()> {
+        self.execute_cached(
+            "UPDATE documents SET index_state = ? WHERE id = ?",
+            params![state.name(), document_id],
+        ).context("Failed to update document index state")?;
+        
+        Ok(())
+    }
+
+    async fn list_index_state(&mut self, state: IndexState) -> Result<Vec<String>> {
+        let mut stmt = self.conn.prepare_cached(
+            "SELECT id FROM documents WHERE index_state = ? ORDER BY file_path"
+        )?;
+        let ids = stmt.query_map(params![state.name()], |row| row.get::<_, String>(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(ids)
+    }
+
+    async fn set_document_source(&mut self, document_id: &str, content_hash: &str, modified: Option<i64>) -> Result<()> {
+        self.execute_cached(
+            "UPDATE documents SET content_hash = ?, modified_at = make_timestamp(?) WHERE id = ?",
+            params![content_hash, modified, document_id],
+        ).context("Failed to update document source")?;
+        
+        Ok(())
+    }
+
+    async fn set_document_priority(&mut self, document_id: &str, priority: i32) -> Result<()> {
+        self.execute_cached(
+            "UPDATE documents SET priority = ? WHERE id = ?",
+            params![priority, document_id],
+        ).context("Failed to update document priority")?;
+        
+        Ok(())
+    }
+
+    async fn set_document_category(&mut self, document_id: &str, category: &str, score: f64) -> Result<()> {
+        self.execute_cached(
+            "UPDATE documents SET category = ?, category_score = ? WHERE id = ?",
+            params![category, score, document_id],
+        ).context("Failed to update document category")?;
+        
+        Ok(())
+    }
+
+    async fn set_document_collection(&mut self, document_id: &str, collection: Option<&str>, tags: &[String]) -> Result<()> {
+        // Tags never contain commas (checked when routing rules are loaded)
+        let tags = if tags.is_empty() { None } else { Some(tags.join(",")) };
+        
+        self.execute_cached(
+            "UPDATE documents SET collection = ?, tags = string_split(?, ',') WHERE id = ?",
+            params![collection, tags, document_id],
+        ).context("Failed to update document collection")?;
+        
+        Ok(())
+    }
+
+    async fn get_document_collection(&mut self, document_id: &str) -> Result<Option<String>> {
+        let mut stmt = self.conn.prepare_cached(
+            "SELECT collection FROM documents WHERE id = ?"
+        )?;
+        let mut rows = stmt.query(params![document_id])?;
+        
+        match rows.next()? {
+            Some(row) => Ok(row.get(0)?),
+            None => Ok(None),
+        }
+    }
+
+    async fn get_document_attributes(&mut self, document_id: &str) -> Result<Option<DocumentAttributes>> {
+        let mut stmt = self.conn.prepare_cached(
+            "SELECT COALESCE(priority, 0), category, category_score, collection, array_to_string(tags, ',')
+             FROM documents WHERE id = ?"
+        )?;
+        let mut rows = stmt.query(params![document_id])?;
+        
+        match rows.next()? {
+            Some(row) => {
+                let category: Option<String> = row.get(1)?;
+                let score: Option<f64> = row.get(2)?;
+                let tags: Option<String> = row.get(4)?;
+                Ok(Some(DocumentAttributes {
+                    priority: row.get(0)?,
+                    category: category.map(|category| (category, score.unwrap_or(0.0))),
+                    collection: row.get(3)?,
+                    tags: tags.filter(|t| !t.is_empty())
+                        .map(|t| t.split(',').map(str::to_string).collect())
+                        .unwrap_or_default(),
+                }))
+            }
+            None => Ok(None),
+        }
+    }
+
+    async fn set_document_quality(&mut self, document_id: &str, score: f64, flags: &[String]) -> Result<()> {
+        // Flag reasons are generated text that never contains a semicolon
+        let flags = if flags.is_empty() { None } else { Some(flags.join(";")) };
+        
+        self.execute_cached(
+            "UPDATE documents SET quality_score = ?, quality_flags = string_split(?, ';') WHERE id = ?",
+            params![score, flags, document_id],
+        ).context("Failed to update document quality")?;
+        
+        Ok(())
+    }
+
+    async fn set_document_title(&mut self, document_id: &str, title: &str) -> Result<()> {
+        self.execute_cached(
+            "UPDATE documents SET title = ? WHERE id = ?",
+            params![title, document_id],
+        ).context("Failed to update document title")?;
+        
+        Ok(())
+    }
+
+    async fn tombstone_document(&mut self, document_id: &str, tombstoned: bool) -> Result<()> {
+        self.execute_cached(
+            "UPDATE documents SET deleted_at = CASE WHEN ? THEN CURRENT_TIMESTAMP END WHERE id = ?",
+            params![tombstoned, document_id],
+        ).context("Failed to update document tombstone")?;
+        
+        Ok(())
+    }
+
+    async fn remove_document(&mut self, document_id: &str) -> Result<()> {
+        self.execute_cached(
+            "DELETE FROM annotations
+             WHERE target_id = ? OR target_id IN (SELECT id FROM fragments WHERE document_id = ?)",
+            params![document_id, document_id],
+        ).context("Failed to remove document annotations")?;
+        // Fragments first: they reference the document
+        self.execute_cached(
+            "DELETE FROM fragments WHERE document_id = ?",
+            params![document_id],
+        ).context("Failed to remove document fragments")?;
+        self.execute_cached(
+            "DELETE FROM documents WHERE id = ?",
+            params![document_id],
+        ).context("Failed to remove document")?;
+        
+        Ok(())
+    }
+
+    async fn bulk_update(&mut self, filter: &SearchFilter, action: &BulkAction) -> Result<usize> {
+        let (conditions, params) = document_conditions(filter);
+        let selected = format!("SELECT id FROM documents WHERE TRUE{}", conditions);
+        let tx = self.conn.transaction()?;
+        let count = match action {
+            BulkAction::Delete => {
+                tx.execute(
+                    &format!(
+                        "DELETE FROM annotations
+                         WHERE target_id IN ({0}) OR target_id IN (SELECT id FROM fragments WHERE document_id IN ({0}))",
+                        selected
+                    ),
+                    params_from_iter(params.iter().chain(&params)),
+                ).context("Failed to remove document annotations")?;
+                tx.execute(
+                    &format!("DELETE FROM fragments WHERE document_id IN ({})", selected),
+                    params_from_iter(&params),
+                ).context("Failed to remove document fragments")?;
+                tx.execute(
+                    &format!("DELETE FROM documents WHERE TRUE{}", conditions),
+                    params_from_iter(&params),
+                ).context("Failed to remove documents")?
+            }
+            BulkAction::Reorganize { add_tags, remove_tags, collection } => {
+                let list = |tags: &[String]| format!("[{}]::VARCHAR[]", vec!["?"; tags.len()].join(", "));
+                // Removed tags are dropped and added ones appended unless already there
+                let mut sql = format!(
+                    "UPDATE documents SET tags = list_concat(
+                         list_filter(COALESCE(tags, []::VARCHAR[]), tag -> NOT list_contains({0}, tag)),
+                         list_filter({1}, tag -> NOT list_contains(list_filter(COALESCE(tags, []::VARCHAR[]), kept -> NOT list_contains({0}, kept)), tag)))",
+                    list(remove_tags), list(add_tags),
+                );
+                let mut values: Vec<Option<&str>> = remove_tags.iter()
+                    .chain(add_tags)
+                    .chain(remove_tags)
+                    .map(|tag| Some(tag.as_str()))
+                    .collect();
+                if let Some(collection) = collection {
+                    sql.push_str(", collection = ?");
+                    values.push(collection.as_deref());
                 }
-                info!("Verified embedding model: {}", model_name);
+                sql.push_str(&format!(" WHERE TRUE{}", conditions));
+                values.extend(params.iter().map(|param| Some(param.as_str())));
+                tx.execute(&sql, params_from_iter(values)).context("Failed to update documents")?
             }
-            Err(_) => {
-                // Model not set, initialize it
-                self.conn.execute(
-                    "INSERT INTO meta (key, value) VALUES (?, ?)",
-                    params!["embedding_model", model_name],
-                )?;
-                info!("Set embedding model to {}", model_name);
+        };
+        tx.commit().context("Failed to commit bulk update")?;
+        Ok(count)
+    }
+
+    async fn list_documents(&mut self) -> Result<Vec<DocumentSummary>> {
+        let mut stmt = self.conn.prepare_cached(
+            "SELECT d.id, d.filename, d.file_path,
+                    (SELECT COUNT(*) FROM fragments f WHERE f.document_id = d.id),
+                    d.quality_score, array_to_string(d.quality_flags, ';'),
+                    d.deleted_at IS NOT NULL, d.content_hash, epoch_us(d.modified_at), d.title
+             FROM documents d
+             ORDER BY d.file_path"
+        )?;
+        
+        let rows = stmt.query_map([], |row| {
+            let flags: Option<String> = row.get(5)?;
+            Ok(DocumentSummary {
+                id: row.get(0)?,
+                filename: row.get(1)?,
+                file_path: row.get(2)?,
+                fragments: row.get::<_, i64>(3)? as i32,
+                quality_score: row.get(4)?,
+                quality_flags: flags.filter(|f| !f.is_empty())
+                    .map(|f| f.split(';').map(str::to_string).collect())
+                    .unwrap_or_default(),
+                tombstoned: row.get(6)?,
+                content_hash: row.get(7)?,
+                modified: row.get(8)?,
+                title: row.get(9)?,
+            })
+        })?;
+        
+        let mut documents = Vec::new();
+        for row in rows {
+            documents.push(row?);
+        }
+        Ok(documents)
+    }
+
+    async fn get_document(&mut self, document_id: &str) -> Result<Option<DocumentInfo>> {
+        let mut stmt = self.conn.prepare_cached(
+            "SELECT id, filename, file_path, file_type, file_data, CAST(created_at AS VARCHAR), file_encoding
+             FROM documents WHERE id = ?"
+        )?;
+        let mut rows = stmt.query(params![document_id])?;
+        
+        match rows.next()? {
+            Some(row) => Ok(Some(DocumentInfo {
+                id: row.get(0)?,
+                filename: row.get(1)?,
+                file_path: row.get(2)?,
+                file_type: row.get(3)?,
+                file_data: decode_original(row.get::<_, Option<String>>(6)?.as_deref(), row.get(4)?)?,
+                created_at: row.get(5)?,
+            })),
+            None => Ok(None),
+        }
+    }
+
+    async fn set_document_text(&mut self, document_id: &str, text: &[u8]) -> Result<()> {
+        self.execute_cached(
+            "UPDATE documents SET extracted_text = ? WHERE id = ?",
+            params![text, document_id],
+        ).context("Failed to store extracted text")?;
+        
+        Ok(())
+    }
+
+    async fn get_document_text(&mut self, document_id: &str) -> Result<Option<Vec<u8>>> {
+        let mut stmt = self.conn.prepare_cached(
+            "SELECT extracted_text FROM documents WHERE id = ?"
+        )?;
+        let mut rows = stmt.query(params![document_id])?;
+        
+        match rows.next()? {
+            Some(row) => Ok(row.get(0)?),
+            None => Ok(None),
+        }
+    }
+
+    async fn replace_fragments(
+        &mut self,
+        document_id: &str,
+        fragments: &[(String, FragmentMeta)],
+    ) -> Result<FragmentChanges> {
+        // Existing fragments by text, so unchanged chunks keep their id and vector; ids are
+        // popped lowest order first when a text repeats
+        let mut existing: HashMap<String, Vec<String>> = HashMap::new();
+        {
+            let mut stmt = self.conn.prepare_cached(
+                "SELECT id, content FROM fragments WHERE document_id = ? ORDER BY fragment_order DESC"
+            )?;
+            let rows = stmt.query_map(params![document_id], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+            })?;
+            for row in rows {
+                let (id, content) = row?;
+                existing.entry(content).or_default().push(id);
+            }
+        }
+        
+        let mut changes = FragmentChanges::default();
+        let mut placements = Vec::new();
+        for (order, (content, meta)) in fragments.iter().enumerate() {
+            let reused = existing.get_mut(content).and_then(|ids| ids.pop());
+            placements.push((order as i32, content, meta, reused));
+        }
+        
+        // Fragments with no counterpart in the new chunking are dropped
+        for id in existing.into_values().flatten() {
+            self.execute_cached("DELETE FROM fragments WHERE id = ?", params![&id])
+                .context("Failed to remove fragment")?;
+            changes.removed += 1;
+        }
+        
+        for (order, content, meta, reused) in placements {
+            match reused {
+                Some(id) => {
+                    self.execute_cached(
+                        "UPDATE fragments SET fragment_order = ?, segment = ?, section = ?, page = ?, structure = ?, location = ? WHERE id = ?",
+                        params![order, meta.segment, &meta.section, meta.page, meta.structure.map(|s| s.as_str()),
+                                meta.location.as_ref().map(Location::label), &id],
+                    ).context("Failed to reorder fragment")?;
+                    changes.kept += 1;
+                }
+                None => {
+                    self.store_text_fragment(document_id, order, content, meta).await?;
+                    changes.added += 1;
+                }
             }
         }
-        
-        Ok(())
+        
+        Ok(changes)
+    }
+
+    async fn get_document_fragments(&mut self, document_id: &str, offset: i32, limit: i32) -> Result<Vec<String>> {
+        let mut stmt = self.conn.prepare_cached(
+            "SELECT content FROM fragments WHERE document_id = ?
+             ORDER BY fragment_order LIMIT ? OFFSET ?"
+        )?;
+        
+        let rows = stmt.query_map(params![document_id, limit, offset], |row| row.get(0))?;
+        
+        let mut fragments = Vec::new();
+        for row in rows {
+            fragments.push(row?);
+        }
+        Ok(fragments)
     }
 
-    async fn document_exists(&mut self, file_path: &Path) -> Result<bool> {
-        let path_str = file_path.to_string_lossy();
-        let mut stmt = self.conn.prepare(
-            "SELECT COUNT(*) FROM documents WHERE file_path = ?"
+    async fn get_fragment_records(&mut self, document_id: &str) -> Result<Vec<FragmentRecord>> {
+        let mut stmt = self.conn.prepare_cached(
+            "SELECT id, fragment_order, section, content, CAST(embedding AS VARCHAR), COALESCE(stale, false)
+             FROM fragments WHERE document_id = ?
+             ORDER BY fragment_order"
         )?;
         
-        let count: i64 = stmt.query_row(params![path_str.as_ref()], |row| {
-            Ok(row.get(0)?)
+        let rows = stmt.query_map(params![document_id], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, i32>(1)?,
+                row.get::<_, Option<String>>(2)?,
+                row.get::<_, String>(3)?,
+                row.get::<_, Option<String>>(4)?,  // embedding as list literal
+                row.get::<_, bool>(5)?,
+            ))
         })?;
         
-        Ok(count > 0)
+        let mut fragments = Vec::new();
+        for row in rows {
+            let (id, order, section, content, embedding, stale) = row?;
+            let embedding = embedding.as_deref().map(parse_embedding).transpose()?;
+            fragments.push(FragmentRecord { id, order, section, content, embedding, stale });
+        }
+        Ok(fragments)
     }
 
-    async fn store_document(&mut self, file_path: &Path, file_data: &[u8]) -> Result<String> {
-        let document_id = Uuid::new_v4().to_string();
-        let filename = file_path.file_name()
-            .and_then(|name| name.to_str())
-            .unwrap_or("unknown");
-        let path_str = file_path.to_string_lossy();
-        
-        // Determine file type from extension
-        let file_type = file_path.extension()
-            .and_then(|ext| ext.to_str())
-            .unwrap_or("unknown")
-            .to_lowercase();
+    async fn read_fragment_batch(&mut self, after: Option<&str>, limit: usize) -> Result<Option<RecordBatch>> {
+        // Vectors of either layout come back as FLOAT lists; ids are '' or longer
+        let mut stmt = self.conn.prepare_cached(
+            "SELECT id, document_id, fragment_order, COALESCE(segment, 0) AS segment, section, page,
+                    structure, location, content, CAST(embedding AS FLOAT[]) AS embedding, COALESCE(stale, false) AS stale
+             FROM fragments WHERE id > COALESCE(?, '')
+             ORDER BY id
+             LIMIT ?"
+        )?;
+        let batches = stmt.query_arrow(params![after, limit as i64])?
+            .map(|batch| conform_fragment_batch(&batch))
+            .collect::<Result<Vec<_>>>()?;
         
-        self.conn.execute(
-            "INSERT INTO documents (id, filename, file_path, file_type, file_data) VALUES (?, ?, ?, ?, ?)",
-            params![&document_id, filename, path_str.as_ref(), &file_type, file_data],
-        ).context("Failed to store document")?;
+        let batch = crate::storage::arrow::compute::concat_batches(&fragment_schema(), &batches)?;
+        Ok((batch.num_rows() > 0).then_some(batch))
+    }
+
+    async fn write_fragment_batch(&mut self, batch: &RecordBatch) -> Result<usize> {
+        let batch = conform_fragment_batch(batch)?;
+        if batch.num_rows() == 0 {
+            return Ok(0);
+        }
         
-        Ok(document_id)
+        // DuckDB scans the batch where it is; a wrong vector length fails the compact cast
+        let inserted = self.conn.execute(
+            &format!(
+                "INSERT INTO fragments (id, document_id, fragment_order, segment, section, page, structure,
+                                        location, content, embedding, stale, embedded_at)
+                 SELECT id, document_id, fragment_order, segment, section, page, structure, location, content,
+                        CAST(embedding AS {}), stale, CASE WHEN embedding IS NOT NULL THEN CURRENT_TIMESTAMP END
+                 FROM arrow(?, ?)",
+                self.embedding_type()
+            ),
+            arrow_recordbatch_to_query_params(batch),
+        ).context("Failed to insert fragment batch")?;
+        Ok(inserted)
     }
 
     async fn store_text_fragment(
@@ -189,13 +1559,19 @@ impl Storage for DuckDBStorage {
         document_id: &str,
         order: i32,
         content: &str,
+        meta: &FragmentMeta,
     ) -> Result<String> {
-        let fragment_id = Uuid::new_v4().to_string();
+        let content = validate_fragment(content)?;
+        let fragment_id = match self.span_fragment_id(document_id, meta)? {
+            Some(fragment_id) => fragment_id,
+            None => self.ids.fragment_id(document_id, order, &content),
+        };
         
-        self.conn.execute(
-            "INSERT INTO fragments (id, document_id, fragment_order, content) 
-             VALUES (?, ?, ?, ?)",
-            params![&fragment_id, document_id, order, content],
+        self.execute_cached(
+            "INSERT INTO fragments (id, document_id, fragment_order, segment, section, page, structure, location, content) 
+             VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)",
+            params![&fragment_id, document_id, order, meta.segment, &meta.section, meta.page, meta.structure.map(|s| s.as_str()),
+                    meta.location.as_ref().map(Location::label), content.as_ref()],
         ).context("Failed to store text fragment")?;
         
         Ok(fragment_id)
@@ -206,31 +1582,129 @@ impl Storage for DuckDBStorage {
         fragment_id: &str,
         embedding: &[f64],
     ) -> Result<()> {
+        if let Some(dimension) = self.compact.filter(|&dimension| dimension != embedding.len()) {
+            anyhow::bail!(
+                "The compact layout stores {}-dimensional vectors, not {}; run `compact --expand` first",
+                dimension, embedding.len()
+            );
+        }
+        
+        // The index's copy of a re-embedded fragment's vector is out of date, so the fragment
+        // is scanned until the next embed phase indexes it again
+        if self.vector_index {
+            self.execute_cached(&format!("DELETE FROM {} WHERE fragment_id = ?", VECTOR_INDEX_TABLE), params![fragment_id])
+                .context("Failed to update the HNSW index")?;
+        }
+        
         // Convert embedding to JSON for DuckDB storage
         let embedding_json = serde_json::to_string(embedding)
             .context("Failed to serialize embedding")?;
         
-        self.conn.execute(
-            "UPDATE fragments SET embedding = CAST(? AS DOUBLE[]) WHERE id = ?",
+        self.execute_cached(
+            &format!(
+                "UPDATE fragments SET embedding = CAST(? AS {}), stale = FALSE, embedded_at = CURRENT_TIMESTAMP WHERE id = ?",
+                self.embedding_type()
+            ),
             params![embedding_json, fragment_id],
         ).context("Failed to update fragment embedding")?;
         
+        self.execute_cached(
+            "DELETE FROM embedding_failures WHERE fragment_id = ?",
+            params![fragment_id],
+        ).context("Failed to clear embedding failure")?;
+        
         Ok(())
     }
 
-    async fn get_fragments_without_embeddings(&mut self, limit: i32) -> Result<Vec<(String, String)>> {
-        let mut stmt = self.conn.prepare(
-            "SELECT id, content FROM fragments 
-             WHERE embedding IS NULL 
-             ORDER BY document_id, fragment_order 
+    async fn update_fragment_embeddings_batch(&mut self, embeddings: &[(String, Vec<f32>)]) -> Result<()> {
+        if embeddings.is_empty() {
+            return Ok(());
+        }
+        if let Some(dimension) = self.compact {
+            if let Some((_, embedding)) = embeddings.iter().find(|(_, embedding)| embedding.len() != dimension) {
+                anyhow::bail!(
+                    "The compact layout stores {}-dimensional vectors, not {}; run `compact --expand` first",
+                    dimension, embedding.len()
+                );
+            }
+        }
+
+        let ids = StringArray::from_iter_values(embeddings.iter().map(|(id, _)| id.as_str()));
+        let vectors = ListArray::from_iter_primitive::<Float32Type, _, _>(
+            embeddings.iter().map(|(_, embedding)| Some(embedding.iter().copied().map(Some))),
+        );
+        let batch = RecordBatch::try_from_iter([
+            ("id", Arc::new(ids) as ArrayRef),
+            ("embedding", Arc::new(vectors) as ArrayRef),
+        ])?;
+
+        // The vectors arrive as one Arrow batch instead of a JSON string per row
+        let embedding_type = self.embedding_type();
+        let placeholders = vec!["?"; embeddings.len()].join(", ");
+        let tx = self.conn.transaction()?;
+        tx.execute(
+            &format!(
+                "UPDATE fragments SET embedding = CAST(batch.embedding AS {}), stale = FALSE, embedded_at = CURRENT_TIMESTAMP
+                 FROM arrow(?, ?) AS batch
+                 WHERE fragments.id = batch.id",
+                embedding_type
+            ),
+            arrow_recordbatch_to_query_params(batch),
+        ).context("Failed to update fragment embeddings")?;
+        tx.execute(
+            &format!("DELETE FROM embedding_failures WHERE fragment_id IN ({})", placeholders),
+            params_from_iter(embeddings.iter().map(|(id, _)| id)),
+        ).context("Failed to clear embedding failures")?;
+        if self.vector_index {
+            // Re-embedded fragments are scanned until the next embed phase indexes them again
+            tx.execute(
+                &format!("DELETE FROM {} WHERE fragment_id IN ({})", VECTOR_INDEX_TABLE, placeholders),
+                params_from_iter(embeddings.iter().map(|(id, _)| id)),
+            ).context("Failed to update the HNSW index")?;
+        }
+        tx.commit().context("Failed to commit fragment embeddings")?;
+
+        Ok(())
+    }
+
+    async fn clear_fragment_text(&mut self, fragment_ids: &[String]) -> Result<()> {
+        if fragment_ids.is_empty() {
+            return Ok(());
+        }
+        // Hash the texts first, so identical chunks indexed later still reuse the vectors
+        self.ensure_chunk_index()?;
+        let placeholders = vec!["?"; fragment_ids.len()].join(", ");
+        self.conn.execute(
+            &format!("UPDATE fragments SET content = '' WHERE id IN ({})", placeholders),
+            params_from_iter(fragment_ids),
+        ).context("Failed to clear fragment text")?;
+        Ok(())
+    }
+
+    async fn get_pending_fragments(&mut self, limit: i32) -> Result<Vec<PendingFragment>> {
+        // Higher priority documents are embedded first so urgent additions
+        // become searchable before a backlog of archival material. Fragments with no
+        // vector come before stale ones, which are replaced most-queried, then oldest, first
+        let mut stmt = self.conn.prepare_cached(
+            "SELECT f.id, f.content, COALESCE(d.priority, 0), f.embedding IS NOT NULL, COALESCE(f.hit_count, 0)
+             FROM fragments f
+             JOIN documents d ON d.id = f.document_id
+             WHERE (f.embedding IS NULL OR f.stale)
+               AND f.id NOT IN (SELECT fragment_id FROM embedding_failures WHERE set_aside)
+             ORDER BY COALESCE(d.priority, 0) DESC, f.embedding IS NOT NULL,
+                      COALESCE(f.hit_count, 0) DESC, f.embedded_at ASC NULLS FIRST,
+                      f.document_id, f.fragment_order 
              LIMIT ?"
         )?;
         
         let rows = stmt.query_map(params![limit], |row| {
-            Ok((
-                row.get::<_, String>(0)?,  // id
-                row.get::<_, String>(1)?,  // content
-            ))
+            Ok(PendingFragment {
+                id: row.get(0)?,
+                content: row.get(1)?,
+                priority: row.get(2)?,
+                stale: row.get(3)?,
+                hits: row.get(4)?,
+            })
         })?;
         
         let mut fragments = Vec::new();
@@ -242,7 +1716,11 @@ impl Storage for DuckDBStorage {
     }
 
     async fn count_fragments_without_embeddings(&mut self) -> Result<i32> {
-        let mut stmt = self.conn.prepare("SELECT COUNT(*) FROM fragments WHERE embedding IS NULL")?;
+        let mut stmt = self.conn.prepare_cached(
+            "SELECT COUNT(*) FROM fragments
+             WHERE (embedding IS NULL OR stale)
+               AND id NOT IN (SELECT fragment_id FROM embedding_failures WHERE set_aside)"
+        )?;
         
         let count: i64 = stmt.query_row([], |row| {
             Ok(row.get(0)?)
@@ -251,23 +1729,331 @@ impl Storage for DuckDBStorage {
         Ok(count as i32)
     }
 
+    async fn get_embeddings_by_chunk(&mut self, chunk_hashes: &[String]) -> Result<HashMap<String, Vec<f32>>> {
+        if chunk_hashes.is_empty() {
+            return Ok(HashMap::new());
+        }
+        self.ensure_chunk_index()?;
+        
+        let placeholders = vec!["?"; chunk_hashes.len()].join(", ");
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT c.chunk_hash, CAST(f.embedding AS VARCHAR)
+             FROM chunk_hashes c
+             JOIN fragments f ON f.id = c.fragment_id
+             WHERE c.chunk_hash IN ({}) AND f.embedding IS NOT NULL AND NOT COALESCE(f.stale, false)
+             QUALIFY row_number() OVER (PARTITION BY c.chunk_hash ORDER BY f.id) = 1",
+            placeholders
+        ))?;
+        
+        let rows = stmt.query_map(params_from_iter(chunk_hashes), |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+        })?;
+        
+        let mut embeddings = HashMap::new();
+        for row in rows {
+            let (hash, embedding) = row?;
+            let embedding = parse_embedding(&embedding)?.into_iter().map(|x| x as f32).collect();
+            embeddings.insert(hash, embedding);
+        }
+        Ok(embeddings)
+    }
+
+    async fn record_embedding_failures(&mut self, failures: &[(String, String)]) -> Result<()> {
+        let mut stmt = self.conn.prepare(
+            "INSERT INTO embedding_failures (fragment_id, error, attempts, failed_at, set_aside)
+             VALUES (?, ?, 1, CURRENT_TIMESTAMP, TRUE)
+             ON CONFLICT (fragment_id) DO UPDATE SET
+                 error = excluded.error,
+                 attempts = embedding_failures.attempts + 1,
+                 failed_at = excluded.failed_at,
+                 set_aside = TRUE"
+        )?;
+        for (fragment_id, error) in failures {
+            stmt.execute(params![fragment_id, error])
+                .context("Failed to record embedding failure")?;
+        }
+        Ok(())
+    }
+
+    async fn retry_embedding_failures(&mut self) -> Result<i32> {
+        // Failures of fragments removed since are dropped
+        self.conn.execute(
+            "DELETE FROM embedding_failures WHERE fragment_id NOT IN (SELECT id FROM fragments)",
+            [],
+        ).context("Failed to prune embedding failures")?;
+        
+        let released = self.conn.execute(
+            "UPDATE embedding_failures SET set_aside = FALSE WHERE set_aside",
+            [],
+        ).context("Failed to release embedding failures")?;
+        
+        Ok(released as i32)
+    }
+
+    async fn count_embedding_failures(&mut self) -> Result<i32> {
+        let count: i64 = self.conn.query_row(
+            "SELECT COUNT(*) FROM embedding_failures WHERE fragment_id IN (SELECT id FROM fragments)",
+            [],
+            |row| row.get(0),
+        )?;
+        Ok(count as i32)
+    }
+
+    async fn build_vector_index(&mut self) -> Result<bool> {
+        if !self.vector_index && !self.load_vss() {
+            info!("DuckDB's vss extension isn't available; searches scan every fragment");
+            return Ok(false);
+        }
+
+        // The index covers the current model's vectors only
+        let (embedded, stale): (i64, i64) = self.conn.query_row(
+            "SELECT COUNT(embedding), COUNT(*) FILTER (WHERE stale) FROM fragments",
+            [],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )?;
+        if embedded == 0 || stale > 0 {
+            return Ok(false);
+        }
+        let dimension: i64 = self.conn.query_row(
+            "SELECT len(embedding) FROM fragments WHERE embedding IS NOT NULL LIMIT 1",
+            [],
+            |row| row.get(0),
+        )?;
+        let current = "SELECT id, embedding FROM fragments WHERE embedding IS NOT NULL AND NOT stale";
+
+        if self.vector_index {
+            // Index the fragments embedded since, and forget removed and re-embedded ones
+            let removed = self.conn.execute(
+                &format!("DELETE FROM {table} WHERE fragment_id NOT IN (SELECT id FROM ({current}))", table = VECTOR_INDEX_TABLE, current = current),
+                [],
+            ).context("Failed to update the HNSW index")?;
+            let added = self.conn.execute(
+                &format!(
+                    "INSERT INTO {table} SELECT id, CAST(embedding AS FLOAT[{dimension}]) FROM ({current})
+                     WHERE id NOT IN (SELECT fragment_id FROM {table})",
+                    table = VECTOR_INDEX_TABLE, current = current, dimension = dimension,
+                ),
+                [],
+            ).context("Failed to update the HNSW index")?;
+            if removed > 0 {
+                // Deleted vectors stay in the graph until it is compacted
+                if let Err(e) = self.conn.execute_batch(&format!("PRAGMA hnsw_compact_index('{}')", VECTOR_INDEX_NAME)) {
+                    warn!("Failed to compact the HNSW index: {}", e);
+                }
+            }
+            if added > 0 || removed > 0 {
+                info!("Updated the HNSW index: {} fragment vectors added, {} removed", added, removed);
+            }
+            return Ok(true);
+        }
+
+        info!("Building HNSW index over {} fragment vectors", embedded);
+        let (metric, _) = hnsw_metric(self.metric);
+        self.conn.execute_batch(&format!(
+            "DROP TABLE IF EXISTS {table};
+             CREATE TABLE {table} AS SELECT id AS fragment_id, CAST(embedding AS FLOAT[{dimension}]) AS embedding FROM ({current});
+             CREATE INDEX {index} ON {table} USING HNSW (embedding) WITH (metric = '{metric}');",
+            table = VECTOR_INDEX_TABLE, index = VECTOR_INDEX_NAME, current = current, dimension = dimension, metric = metric,
+        )).context("Failed to build HNSW index")?;
+        self.set_meta_value(VECTOR_INDEX_KEY, "hnsw").await?;
+        self.vector_index = true;
+        Ok(true)
+    }
+
+    async fn vector_index_coverage(&mut self) -> Result<Option<VectorIndexCoverage>> {
+        if !self.vector_index {
+            return Ok(None);
+        }
+        let (indexed, embedded): (i64, i64) = self.conn.query_row(
+            &format!(
+                "SELECT COUNT(i.fragment_id), COUNT(*) FROM fragments f
+                 LEFT JOIN {} i ON i.fragment_id = f.id
+                 WHERE f.embedding IS NOT NULL AND NOT f.stale",
+                VECTOR_INDEX_TABLE
+            ),
+            [],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )?;
+        Ok(Some(VectorIndexCoverage { indexed: indexed as usize, unindexed: (embedded - indexed) as usize }))
+    }
+
+    async fn mark_embeddings_stale(&mut self) -> Result<i32> {
+        // The index's vectors are the old model's; the embed phase that completes the upgrade
+        // builds a new one
+        if self.get_meta_value(VECTOR_INDEX_KEY).await?.is_some() {
+            self.drop_vector_index()?;
+        }
+        // The next model's vectors may have another length than the compact layout's
+        if self.compact.is_some() {
+            info!("Expanding the compact fragment layout for the model upgrade");
+            self.expand()?;
+        }
+        
+        let updated = self.conn.execute(
+            "UPDATE fragments SET stale = TRUE WHERE embedding IS NOT NULL AND NOT stale",
+            [],
+        ).context("Failed to mark embeddings stale")?;
+        
+        Ok(updated as i32)
+    }
+
+    async fn count_stale_fragments(&mut self) -> Result<i32> {
+        let count: i64 = self.conn.query_row("SELECT COUNT(*) FROM fragments WHERE stale", [], |row| row.get(0))?;
+        
+        Ok(count as i32)
+    }
+
+    async fn corpus_stats(&mut self, largest: usize) -> Result<CorpusStats> {
+        let mut stmt = self.conn.prepare(
+            "SELECT file_type, COUNT(*) FROM documents GROUP BY file_type"
+        )?;
+        let mut file_types = stmt.query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)? as usize)))?
+            .collect::<Result<Vec<_>, _>>()?;
+        sort_file_types(&mut file_types);
+        
+        let (documents, fragments, fragment_chars, document_bytes): (i64, i64, i64, i64) = self.conn.query_row(
+            "SELECT (SELECT COUNT(*) FROM documents),
+                    (SELECT COUNT(*) FROM fragments),
+                    (SELECT CAST(COALESCE(SUM(length(content)), 0) AS BIGINT) FROM fragments),
+                    (SELECT CAST(COALESCE(SUM(octet_length(file_data)), 0) AS BIGINT) FROM documents)",
+            [],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+        )?;
+        
+        let mut stmt = self.conn.prepare(
+            "SELECT d.file_path, octet_length(d.file_data),
+                    (SELECT COUNT(*) FROM fragments f WHERE f.document_id = d.id)
+             FROM documents d
+             ORDER BY octet_length(d.file_data) DESC, d.file_path
+             LIMIT ?"
+        )?;
+        let largest = stmt.query_map(params![largest as i64], |row| {
+            Ok(DocumentSize {
+                file_path: row.get(0)?,
+                bytes: row.get::<_, i64>(1)? as u64,
+                fragments: row.get::<_, i64>(2)? as usize,
+            })
+        })?.collect::<Result<Vec<_>, _>>()?;
+        
+        Ok(CorpusStats {
+            file_types,
+            documents: documents as usize,
+            fragments: fragments as usize,
+            fragment_chars: fragment_chars as u64,
+            document_bytes: document_bytes as u64,
+            largest,
+        })
+    }
+
+    async fn record_fragment_hits(&mut self, fragment_ids: &[String]) -> Result<()> {
+        if fragment_ids.is_empty() {
+            return Ok(());
+        }
+        
+        let placeholders = vec!["?"; fragment_ids.len()].join(", ");
+        self.conn.execute(
+            &format!("UPDATE fragments SET hit_count = COALESCE(hit_count, 0) + 1 WHERE id IN ({})", placeholders),
+            params_from_iter(fragment_ids),
+        ).context("Failed to record fragment hits")?;
+        
+        Ok(())
+    }
+
+    async fn get_fragment_vectors(&mut self, fragment_ids: &[String]) -> Result<HashMap<String, Vec<f64>>> {
+        if fragment_ids.is_empty() {
+            return Ok(HashMap::new());
+        }
+        let placeholders = vec!["?"; fragment_ids.len()].join(", ");
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT id, CAST(embedding AS VARCHAR) FROM fragments
+             WHERE embedding IS NOT NULL AND id IN ({})",
+            placeholders
+        ))?;
+        let rows = stmt.query_map(params_from_iter(fragment_ids), |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+        })?;
+        
+        let mut vectors = HashMap::new();
+        for row in rows {
+            let (id, embedding) = row?;
+            vectors.insert(id, parse_embedding(&embedding)?);
+        }
+        Ok(vectors)
+    }
+
+    async fn get_fragment_embeddings(&mut self, limit: usize) -> Result<Vec<(String, String, Vec<f64>)>> {
+        // Hashing the id gives a stable sample that is spread across documents
+        let mut stmt = self.conn.prepare(
+            "SELECT f.id, d.filename, CAST(f.embedding AS VARCHAR) FROM fragments f
+             JOIN documents d ON d.id = f.document_id
+             WHERE f.embedding IS NOT NULL AND NOT f.stale
+             ORDER BY hash(f.id)
+             LIMIT ?"
+        )?;
+        
+        let rows = stmt.query_map(params![limit as i64], |row| {
+            Ok((
+                row.get::<_, String>(0)?,  // id
+                row.get::<_, String>(1)?,  // filename
+                row.get::<_, String>(2)?,  // embedding as list literal
+            ))
+        })?;
+        
+        let mut embeddings = Vec::new();
+        for row in rows {
+            let (id, filename, embedding_text) = row?;
+            embeddings.push((id, filename, parse_embedding(&embedding_text)?));
+        }
+        
+        Ok(embeddings)
+    }
+
+    async fn sample_fragments(&mut self, limit: usize) -> Result<Vec<(String, String, Vec<f64>)>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT id, content, CAST(embedding AS VARCHAR) FROM fragments
+             WHERE embedding IS NOT NULL AND NOT stale
+             ORDER BY hash(id)
+             LIMIT ?"
+        )?;
+        
+        let rows = stmt.query_map(params![limit as i64], |row| {
+            Ok((
+                row.get::<_, String>(0)?,  // id
+                row.get::<_, String>(1)?,  // content
+                row.get::<_, String>(2)?,  // embedding as list literal
+            ))
+        })?;
+        
+        let mut samples = Vec::new();
+        for row in rows {
+            let (id, content, embedding_text) = row?;
+            samples.push((id, content, parse_embedding(&embedding_text)?));
+        }
+        
+        Ok(samples)
+    }
+
     async fn get_meta_info(&mut self) -> Result<MetaInfo> {
         let mut stmt = self.conn.prepare(
-            "SELECT key, value FROM meta WHERE key IN ('version', 'embedding_model')"
+            "SELECT key, value FROM meta WHERE key IN ('version', 'embedding_model', ?, ?)"
         )?;
         
-        let rows = stmt.query_map([], |row| {
+        let rows = stmt.query_map(params![DIMENSION_KEY, PROVIDER_KEY], |row| {
             Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
         })?;
         
         let mut version = None;
         let mut embedding_model = None;
+        let mut embedding_dimension = None;
+        let mut embedding_provider = None;
         
         for row in rows {
             let (key, value) = row?;
             match key.as_str() {
                 "version" => version = Some(value),
                 "embedding_model" => embedding_model = Some(value),
+                DIMENSION_KEY => embedding_dimension = parse_dimension(Some(&value)),
+                PROVIDER_KEY => embedding_provider = Some(value).filter(|provider| !provider.is_empty()),
                 _ => {}
             }
         }
@@ -275,14 +2061,150 @@ impl Storage for DuckDBStorage {
         Ok(MetaInfo {
             version: version.unwrap_or_else(|| "unknown".to_string()),
             embedding_model: embedding_model.unwrap_or_else(|| "unknown".to_string()),
+            embedding_dimension,
+            embedding_provider,
         })
     }
 
+    async fn get_meta_value(&mut self, key: &str) -> Result<Option<String>> {
+        let mut stmt = self.conn.prepare_cached("SELECT value FROM meta WHERE key = ?")?;
+        let mut rows = stmt.query(params![key])?;
+        
+        match rows.next()? {
+            Some(row) => Ok(Some(row.get(0)?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn set_meta_value(&mut self, key: &str, value: &str) -> Result<()> {
+        self.execute_cached(
+            "INSERT OR REPLACE INTO meta (key, value) VALUES (?, ?)",
+            params![key, value],
+        ).with_context(|| format!("Failed to set meta value {}", key))?;
+        
+        Ok(())
+    }
+
+    async fn list_entities(&mut self, limit: usize) -> Result<Vec<EntityCount>> {
+        self.ensure_entity_index()?;
+        let mut stmt = self.conn.prepare(
+            "SELECT min(e.name), COUNT(DISTINCT e.fragment_id), COUNT(DISTINCT f.document_id)
+             FROM entities e
+             JOIN fragments f ON f.id = e.fragment_id
+             WHERE e.entity IS NOT NULL
+               AND f.document_id NOT IN (SELECT id FROM documents WHERE deleted_at IS NOT NULL)
+             GROUP BY e.entity
+             ORDER BY 2 DESC, 1
+             LIMIT ?"
+        )?;
+        
+        let rows = stmt.query_map(params![i64::try_from(limit).unwrap_or(i64::MAX)], |row| {
+            Ok(EntityCount {
+                name: row.get(0)?,
+                fragments: row.get::<_, i64>(1)? as usize,
+                documents: row.get::<_, i64>(2)? as usize,
+            })
+        })?;
+        
+        let mut entities = Vec::new();
+        for row in rows {
+            entities.push(row?);
+        }
+        Ok(entities)
+    }
+
+    async fn find_entity(&mut self, entity: &str) -> Result<Vec<EntityMention>> {
+        self.ensure_entity_index()?;
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT d.id, d.file_path, array_to_string(list_sort(list_distinct(list(e.name))), ';'),
+                    COUNT(DISTINCT e.fragment_id)
+             FROM entities e
+             JOIN fragments f ON f.id = e.fragment_id
+             JOIN documents d ON d.id = f.document_id
+             WHERE {} AND d.deleted_at IS NULL
+             GROUP BY d.id, d.file_path
+             ORDER BY 4 DESC, d.file_path",
+            ENTITY_MATCH
+        ))?;
+        
+        let key = entities::key(entity);
+        let rows = stmt.query_map(params![&key, &key, &key], |row| {
+            let names: String = row.get(2)?;
+            Ok(EntityMention {
+                document_id: row.get(0)?,
+                file_path: row.get(1)?,
+                names: names.split(';').map(str::to_string).collect(),
+                fragments: row.get::<_, i64>(3)? as usize,
+            })
+        })?;
+        
+        let mut mentions = Vec::new();
+        for row in rows {
+            mentions.push(row?);
+        }
+        Ok(mentions)
+    }
+
+    async fn add_annotation(&mut self, annotation: &Annotation) -> Result<()> {
+        self.conn.execute(
+            "INSERT INTO annotations (id, target_id, label, note, author, created_at)
+             VALUES (?, ?, ?, ?, ?, make_timestamp(?::BIGINT))",
+            params![
+                annotation.id,
+                annotation.target,
+                annotation.label.map(|label| label.name()),
+                annotation.note,
+                annotation.author,
+                annotation.created,
+            ],
+        ).context("Failed to store annotation")?;
+        Ok(())
+    }
+
+    async fn list_annotations(&mut self, target: Option<&str>) -> Result<Vec<Annotation>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT id, target_id, label, note, author, epoch_us(created_at)
+             FROM annotations
+             WHERE ?::VARCHAR IS NULL OR target_id = ?
+             ORDER BY created_at, id"
+        )?;
+        
+        let rows = stmt.query_map(params![target, target], |row| {
+            Ok(Annotation {
+                id: row.get(0)?,
+                target: row.get(1)?,
+                label: row.get::<_, Option<String>>(2)?.as_deref().and_then(AnnotationLabel::parse),
+                note: row.get(3)?,
+                author: row.get(4)?,
+                created: row.get::<_, Option<i64>>(5)?.unwrap_or_default(),
+            })
+        })?;
+        
+        let mut annotations = Vec::new();
+        for row in rows {
+            annotations.push(row?);
+        }
+        Ok(annotations)
+    }
+
+    async fn remove_annotation(&mut self, annotation_id: &str) -> Result<bool> {
+        let removed = self.conn.execute(
+            "DELETE FROM annotations WHERE id = ?",
+            params![annotation_id],
+        ).context("Failed to remove annotation")?;
+        Ok(removed > 0)
+    }
+
     async fn search_similar(
         &mut self,
         query_embedding: &[f64],
         limit: usize,
-    ) -> Result<Vec<(String, String, f64)>> {
+        filter: &SearchFilter,
+    ) -> Result<Vec<FragmentMatch>> {
+        if filter.entity.is_some() {
+            self.ensure_entity_index()?;
+        }
+        
         // Convert query embedding to DuckDB list format  
         let query_list: String = format!("[{}]", 
             query_embedding.iter()
@@ -291,27 +2213,103 @@ impl Storage for DuckDBStorage {
                 .join(",")
         );
         
-        let mut stmt = self.conn.prepare(&format!(
-            "SELECT id, content, list_cosine_similarity(embedding, ?::DOUBLE[]) AS similarity 
-             FROM fragments 
-             WHERE embedding IS NOT NULL 
-             ORDER BY similarity DESC 
-             LIMIT {}", limit
-        ))?;
-        
-        let rows = stmt.query_map(params![query_list], |row| {
-            Ok((
-                row.get::<_, String>(0)?,  // id
-                row.get::<_, String>(1)?,  // content
-                row.get::<_, f64>(2)?,     // similarity
-            ))
-        })?;
-        
+        // Searches of the previous model's stale vectors aren't covered by the index
+        if self.vector_index && !filter.stale {
+            match self.nearest_fragments(&query_list, query_embedding.len(), limit, filter) {
+                Ok(mut results) if results.len() == limit => {
+                    // Fragments embedded since the index was built or last brought up to date
+                    // are scanned, so they are found before the next embed phase indexes them
+                    let unindexed = self.scan_fragments(&query_list, limit, filter, true)?;
+                    if !unindexed.is_empty() {
+                        debug!("Merging {} HNSW results with {} from a scan of fragments not yet indexed", results.len(), unindexed.len());
+                        results.extend(unindexed);
+                        sort_ranked(&mut results);
+                        results.truncate(limit);
+                    }
+                    return Ok(results);
+                }
+                // The filters left too few of the index's candidates
+                Ok(_) => debug!("HNSW candidates didn't fill {} results; scanning every fragment", limit),
+                Err(e) => warn!("HNSW index search failed, scanning every fragment instead: {:#}", e),
+            }
+        }
+
+        self.scan_fragments(&query_list, limit, filter, false)
+    }
+
+    async fn search_keyword(
+        &mut self,
+        query: &str,
+        limit: usize,
+        filter: &SearchFilter,
+    ) -> Result<Vec<FragmentMatch>> {
+        self.ensure_fts_index()?;
+        if filter.entity.is_some() {
+            self.ensure_entity_index()?;
+        }
+        let (conditions, filter_params) = filter_conditions(filter);
+        let (after, after_params) = after_condition(filter);
+
+        let mut stmt = self.conn.prepare(&with_sources(&format!(
+            "SELECT * FROM (
+                SELECT *, fts_main_fragments.match_bm25(id, ?) AS score, {}
+                FROM fragments
+             )
+             WHERE score IS NOT NULL{}{}
+             ORDER BY score DESC, rank_path, fragment_order
+             LIMIT {}", RANK_PATH, conditions, after, limit
+        )))?;
+
+        let query_params = std::iter::once(query.to_string()).chain(filter_params).chain(after_params);
+        let rows = stmt.query_map(params_from_iter(query_params), fragment_match)?;
+
         let mut results = Vec::new();
         for row in rows {
             results.push(row?);
         }
-        
+
         Ok(results)
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_fragments_embedded_after_the_index_are_scanned() {
+        let path = std::env::temp_dir().join(format!("pb-vector-index-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut storage = DuckDBStorage::new(&path).await.unwrap();
+        let document = storage.store_document(Path::new("notes.txt"), b"notes").await.unwrap();
+        let mut ids = Vec::new();
+        for (order, text) in ["North", "East", "South"].iter().enumerate() {
+            ids.push(storage.store_text_fragment(&document, order as i32, text, &FragmentMeta::default()).await.unwrap());
+        }
+        storage.update_fragment_embedding(&ids[0], &[1.0, 0.0]).await.unwrap();
+        storage.update_fragment_embedding(&ids[1], &[0.0, 1.0]).await.unwrap();
+
+        // The vss extension can't be installed here, so the index's table stands in for it
+        // without its HNSW graph; searches read the table the same way
+        storage.conn.execute_batch(&format!(
+            "CREATE TABLE {} AS SELECT id AS fragment_id, CAST(embedding AS FLOAT[2]) AS embedding FROM fragments WHERE embedding IS NOT NULL",
+            VECTOR_INDEX_TABLE
+        )).unwrap();
+        storage.vector_index = true;
+        storage.update_fragment_embedding(&ids[2], &[-1.0, 0.0]).await.unwrap();
+        assert_eq!(storage.vector_index_coverage().await.unwrap(), Some(VectorIndexCoverage { indexed: 2, unindexed: 1 }));
+
+        // The fragment embedded after the index is found, ranked among the indexed ones
+        let hits = storage.search_similar(&[-1.0, 0.1], 2, &SearchFilter::default()).await.unwrap();
+        let found: Vec<&str> = hits.iter().map(|hit| hit.fragment_id.as_str()).collect();
+        assert_eq!(found, vec![ids[2].as_str(), ids[1].as_str()]);
+
+        // A re-embedded fragment leaves the index's outdated copy behind and is scanned
+        storage.update_fragment_embeddings_batch(&[(ids[0].clone(), vec![0.0, -1.0])]).await.unwrap();
+        assert_eq!(storage.vector_index_coverage().await.unwrap(), Some(VectorIndexCoverage { indexed: 1, unindexed: 2 }));
+        let hits = storage.search_similar(&[0.0, -1.0], 1, &SearchFilter::default()).await.unwrap();
+        assert_eq!(hits[0].fragment_id, ids[0]);
+
+        drop(storage);
+        let _ = std::fs::remove_file(&path);
+    }
+}
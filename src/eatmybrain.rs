@@ -1,18 +1,73 @@
 use anyhow::{Context, Result};
-use clap::{Parser, ValueEnum};
-use console::{style, Term};
-use std::path::PathBuf;
+use clap::{CommandFactory, Parser, ValueEnum};
+use console::style;
+use rustyline::completion::{Completer, Pair};
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::history::DefaultHistory;
+use rustyline::validate::Validator;
+use rustyline::{Config, Editor, Helper};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 use std::io::{self, Write};
 use tokio;
 use log;
 
+mod annotations;
+mod answer;
+mod audit;
+mod autocomplete;
+mod brains;
+mod context;
+mod corpus;
+mod document_processor;
 mod duckdb_storage;
+mod lancedb_storage;
+mod sharded_storage;
 mod embedding_manager;
+mod entities;
+mod error;
+mod expansion;
+mod extractive;
+mod followups;
+mod hybrid;
+mod llm;
+mod model_downloads;
+mod offline;
+mod output_filter;
+mod passwords;
+mod paths;
+mod presets;
+mod profiles;
+mod remote_storage;
+mod reranker;
+mod retrieval;
+mod spending;
 mod storage;
+mod storage_thread;
+mod summary_tree;
+mod trace;
+mod verification;
 
-use duckdb_storage::DuckDBStorage;
+use annotations::AnnotationLabel;
+use answer::{dated_passage, render_markdown, render_sources, staleness_instructions, AnswerFormat, AnswerLength, AnswerStyle, StructuredAnswer};
+use audit::{AuditEntry, AuditLog, AuditedFragment};
+use autocomplete::PrefixIndex;
+use brains::{BrainHit, BrainSet, CorpusDocument, RoutingMode};
+use context::{ContextBuilder, PassageTemplates};
+use corpus::{CorpusAnswer, Excerpt};
+use storage::{create_storage, SearchFilter, StorageBackend};
+use hybrid::SearchMode;
 use embedding_manager::EmbeddingManager;
-use storage::Storage;
+use expansion::QueryExpansion;
+use llm::{ChatMessage, ChatReply, LlmClient, Provider, TokenUsage};
+use output_filter::{FilteredAnswer, OutputFilters};
+use presets::Preset;
+use reranker::{CrossEncoder, RERANK_POOL};
+use spending::{ModelPrice, SpendingCap};
+use trace::{RetrievalTrace, TraceLog, TracedFilter, TracedHit};
 
 #[derive(Clone, ValueEnum)]
 enum AIModel {
@@ -54,23 +109,114 @@ impl AIModel {
             AIModel::Custom => "", // User must specify
         }
     }
+
+    fn provider(&self) -> Option<Provider> {
+        match self {
+            AIModel::Gpt4 | AIModel::Gpt4Turbo | AIModel::Gpt35Turbo => Some(Provider::OpenAi),
+            AIModel::Claude3Opus | AIModel::Claude3Sonnet | AIModel::Claude3Haiku => Some(Provider::Anthropic),
+            AIModel::Custom => None, // Detected from the endpoint
+        }
+    }
+}
+
+#[derive(Clone, ValueEnum)]
+enum Routing {
+    /// Send each query to the single most relevant brain (by content centroid similarity)
+    Route,
+    /// Search every brain and merge results into one ranking
+    Federate,
+}
+
+impl Routing {
+    fn mode(&self) -> RoutingMode {
+        match self {
+            Routing::Route => RoutingMode::Route,
+            Routing::Federate => RoutingMode::Federate,
+        }
+    }
+}
+
+#[derive(Clone, ValueEnum)]
+enum Backend {
+    Duckdb,
+    Lancedb,
+    /// A brain on a Qdrant server; the database is its URL, e.g. http://localhost:6333/team
+    Remote,
+}
+
+impl Backend {
+    fn storage_backend(&self) -> StorageBackend {
+        match self {
+            Backend::Duckdb => StorageBackend::DuckDB,
+            Backend::Lancedb => StorageBackend::LanceDB,
+            Backend::Remote => StorageBackend::Remote,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug, ValueEnum)]
+enum EmbeddingProvider {
+    /// FastEmbed models run in-process
+    Local,
+    /// OpenAI's embeddings API, or --embedding-endpoint; needs --embedding-api-key
+    Remote,
+    /// A local Ollama server (http://localhost:11434/api/embeddings unless --embedding-endpoint is given)
+    Ollama,
+    /// Any server implementing OpenAI's /v1/embeddings at --embedding-endpoint; --embedding-api-key is optional
+    Compatible,
+}
+
+impl EmbeddingProvider {
+    /// The provider to embed queries with when none is given, from the one recorded in the
+    /// database. Indexing through `compatible` records `remote` too, so a recorded remote
+    /// provider with an endpoint but no key means a compatible server.
+    fn recorded(recorded: Option<&str>, endpoint: Option<&str>, api_key: Option<&str>) -> Self {
+        match recorded {
+            Some("ollama") => EmbeddingProvider::Ollama,
+            Some("remote") if endpoint.is_some() && api_key.is_none() => EmbeddingProvider::Compatible,
+            Some("remote") => EmbeddingProvider::Remote,
+            // `hashing` models are recognized by name and built like local ones
+            _ => EmbeddingProvider::Local,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+enum OutputFormat {
+    /// Human-readable terminal output
+    Text,
+    /// A single JSON object: {answer, citations, confidence, retrieved, token_usage}
+    Json,
 }
 
 #[derive(Parser)]
 #[command(name = "eatmybrain")]
 #[command(about = "Conversational RAG using Portable Brains vector database")]
 struct Args {
-    /// Path to the DuckDB database file created by portable-brains
-    #[arg(short, long)]
-    database: PathBuf,
+    /// Path to a DuckDB database file created by portable-brains, or the URL of a remote brain
+    /// (http://localhost:6333/team); repeat to use several brains
+    #[arg(short, long, required_unless_present = "export_audit")]
+    database: Vec<PathBuf>,
+    
+    /// Storage backend of the databases (inferred from each file extension when omitted)
+    #[arg(long, value_enum)]
+    backend: Option<Backend>,
+    
+    /// How queries are dispatched when several databases are given
+    #[arg(long, value_enum, default_value = "federate")]
+    routing: Routing,
     
     /// LLM API endpoint URL (auto-detected for known AI models if not specified)
     #[arg(short, long)]
     endpoint: Option<String>,
     
-    /// API key for the LLM service
+    /// API key for the LLM service (optional for custom providers such as a local Ollama)
     #[arg(short, long)]
-    api_key: String,
+    api_key: Option<String>,
+
+    /// API format of the LLM endpoint (detected from --ai-model or the endpoint if not specified)
+    #[arg(long, value_enum)]
+    provider: Option<Provider>,
 
     /// Select from popular AI models (auto-configures endpoint and model name)
     #[arg(long, value_enum)]
@@ -80,56 +226,484 @@ struct Args {
     #[arg(short, long, default_value = "gpt-4")]
     model: String,
     
+    /// Answer with instructions tuned for a kind of corpus (default: the preset the databases
+    /// were indexed with)
+    #[arg(long, value_enum)]
+    preset: Option<Preset>,
+    
     /// Number of similar documents to retrieve for context (1-20)
     #[arg(short, long, default_value = "5")]
     results: usize,
     
-    /// Embedding model name (must match what was used for indexing)
-    /// Popular options: BAAI/bge-small-en-v1.5, sentence-transformers/all-MiniLM-L6-v2, 
-    /// sentence-transformers/all-mpnet-base-v2, nomic-ai/nomic-embed-text-v1
-    #[arg(short = 'E', long, default_value = "BAAI/bge-small-en-v1.5")]
-    embedding_model: String,
+    /// Take at most this many passages from each document, so the context covers more
+    /// sources than the one that matches best
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    per_document_limit: Option<u32>,
+    
+    /// Language to answer in, e.g. "German" (default: the language of the question)
+    #[arg(long)]
+    answer_language: Option<String>,
+    
+    /// How long answers should be
+    #[arg(long, value_enum, default_value_t = AnswerLength::Normal)]
+    answer_length: AnswerLength,
+    
+    /// Lay answers out as bullet points or prose (default: left to the model)
+    #[arg(long, value_enum)]
+    format: Option<AnswerFormat>,
+    
+    /// Retrieve context by embedding similarity, BM25 keyword matching, or both fused (hybrid)
+    #[arg(long, value_enum, default_value_t = SearchMode::Vector)]
+    search_mode: SearchMode,
+    
+    /// Cross-encoder model that re-scores the top 50 passages and keeps the best --results,
+    /// e.g. BAAI/bge-reranker-base
+    #[arg(long)]
+    rerank_model: Option<String>,
+    
+    /// Also search with paraphrases of each question (multi) or with a hypothetical passage
+    /// answering it (hyde), written by the LLM, fusing the results by rank
+    #[arg(long, value_enum, default_value_t = QueryExpansion::Off)]
+    query_expansion: QueryExpansion,
+    
+    /// Tokens the retrieved passages sent with a question may take together; passages that
+    /// don't fit are left out
+    #[arg(long, value_name = "N", default_value_t = context::DEFAULT_CONTEXT_TOKENS)]
+    context_tokens: usize,
+    
+    /// Weight of relevance against novelty when picking passages, from 0 to 1; lower values
+    /// pass over more near-duplicates, 1 ranks by relevance alone
+    #[arg(long, value_name = "LAMBDA", default_value_t = context::DEFAULT_MMR_LAMBDA)]
+    mmr_lambda: f64,
+    
+    /// Tokenizer --context-tokens is measured with: a FastEmbed model name or a
+    /// tokenizer.json file (default: the embedding model's, when it runs locally)
+    #[arg(long, value_name = "MODEL_OR_FILE")]
+    context_tokenizer: Option<String>,
+    
+    /// Lay passages out by kind in the prompt, prose first and quoted, then lists, tables as
+    /// Markdown tables and code in fences, each headed by its source
+    #[arg(long)]
+    typed_context: bool,
+    
+    /// TOML file of `prose`, `list`, `table` and `code` templates for --typed-context, using
+    /// {n}, {source} and {text}; implies --typed-context
+    #[arg(long, value_name = "FILE")]
+    passage_templates: Option<PathBuf>,
+    
+    /// Embedding model that embeds questions (default: the model recorded in the first
+    /// database); must be the one the databases were indexed with
+    #[arg(short = 'E', long)]
+    embedding_model: Option<String>,
+    
+    /// Provider that embeds questions (default: the provider recorded in the first database)
+    #[arg(long, value_enum)]
+    embedding_provider: Option<EmbeddingProvider>,
+    
+    /// Endpoint URL of a remote embedding provider (defaults to OpenAI for remote and to the
+    /// local server for ollama; required for compatible)
+    #[arg(long)]
+    embedding_endpoint: Option<String>,
+    
+    /// API key of a remote embedding provider (required for remote, optional for compatible)
+    #[arg(long)]
+    embedding_api_key: Option<String>,
+    
+    /// Only answer from this document, given by id, file name or path ("chat with this PDF")
+    #[arg(long)]
+    document: Option<String>,
+    
+    /// Only answer from documents of these file types, e.g. `--types pdf,docx`, so
+    /// spreadsheet rows don't crowd out prose; `/filter` replaces it during the chat
+    #[arg(long, value_delimiter = ',')]
+    types: Vec<String>,
+    
+    /// After each answer, check that every cited passage supports the sentence citing it
+    #[arg(long)]
+    verify: bool,
+    
+    /// Warn when less than this share of an answer's words appear in the retrieved
+    /// passages, as shown after each answer (0 never warns)
+    #[arg(long, value_name = "FRACTION", default_value_t = 0.5)]
+    grounding_threshold: f64,
+    
+    /// After each answer, suggest follow-up questions the retrieved passages can answer
+    #[arg(long)]
+    suggest: bool,
+    
+    /// Answer without a language model, with the retrieved passages' sentences most similar
+    /// to the question and their citations, for offline or zero-cost use; needs no endpoint
+    #[arg(long, conflicts_with_all = ["verify", "suggest", "whole_corpus"])]
+    no_llm: bool,
+    
+    /// Sentences a --no-llm answer is made of
+    #[arg(long, value_name = "N", default_value_t = extractive::DEFAULT_SENTENCES, requires = "no_llm")]
+    sentences: usize,
+    
+    /// Answer this single question and exit instead of starting the chat
+    #[arg(long)]
+    question: Option<String>,
+    
+    /// Output format for --question
+    #[arg(long, value_enum, default_value = "text")]
+    output: OutputFormat,
+    
+    /// TOML file of stop sequences, regex redactions and a banned-topics hook applied to
+    /// every answer before it is shown or returned
+    #[arg(long, value_name = "FILE")]
+    output_filters: Option<PathBuf>,
+    
+    /// Record every answer with the exact prompt sent, the retrieved fragment ids, the model
+    /// and its parameters in this append-only log (a DuckDB file)
+    #[arg(long, value_name = "FILE")]
+    audit_log: Option<PathBuf>,
+    
+    /// Write the answers recorded in --audit-log to this JSONL file, after checking none were
+    /// changed or removed, and exit
+    #[arg(long, value_name = "FILE", requires = "audit_log")]
+    export_audit: Option<PathBuf>,
+    
+    /// Don't mark the instructions and retrieved context as cacheable for the provider's
+    /// prompt cache
+    #[arg(long)]
+    no_prompt_cache: bool,
+    
+    /// Don't keep the chat's questions in `<database>.history`
+    #[arg(long)]
+    no_history: bool,
+    
+    /// Don't record how each question's passages were retrieved in `<database>.traces`
+    #[arg(long, conflicts_with = "replay")]
+    no_trace: bool,
+    
+    /// Retrieve the passages of turns recorded in `<database>.traces` again, with the corpus
+    /// and flags as they are now, show what changed and exit: a turn number, trace id, `last`
+    /// or `all`
+    #[arg(long, value_name = "TURN", conflicts_with_all = ["question", "whole_corpus"])]
+    replay: Option<String>,
+    
+    /// Give the model each passage's source date and have it caveat statements resting on
+    /// sources last modified more than this many days ago
+    #[arg(long, value_name = "DAYS")]
+    stale_after: Option<u32>,
+    
+    /// Answer by reading every document instead of the top matching passages, for questions
+    /// that aggregate over the corpus ("summarize all complaints this year"). Makes an LLM
+    /// request per batch of documents.
+    #[arg(long)]
+    whole_corpus: bool,
+    
+    /// Estimated spend in US dollars after which the chat stops to ask before sending more
+    /// LLM requests, and again after each further such amount
+    #[arg(long, value_name = "USD")]
+    max_session_cost: Option<f64>,
+    
+    /// Price of the model's prompt tokens in US dollars per million, for models without a
+    /// known list price
+    #[arg(long, value_name = "USD", requires = "output_price")]
+    input_price: Option<f64>,
+    
+    /// Price of the model's completion tokens in US dollars per million
+    #[arg(long, value_name = "USD", requires = "input_price")]
+    output_price: Option<f64>,
     
     /// Enable verbose logging
     #[arg(short, long)]
     verbose: bool,
+    
+    /// Refuse all network access except to this machine, so only a local LLM and embedding
+    /// model can be used (also set by PORTABLE_BRAINS_STRICT_OFFLINE=1)
+    #[arg(long)]
+    strict_offline: bool,
+    
+    /// API key sent to the Qdrant server of a remote brain (also set by QDRANT_API_KEY)
+    #[arg(long)]
+    remote_api_key: Option<String>,
+    
+    /// Download the local embedding and reranker models from this mirror of the Hugging Face
+    /// hub, such as an internal artifact store (also set by PORTABLE_BRAINS_MODEL_MIRROR)
+    #[arg(long, value_name = "URL")]
+    model_mirror: Option<String>,
+    
+    /// TOML file of SHA-256 checksums that local model files must match before they are
+    /// loaded (also set by PORTABLE_BRAINS_MODEL_CHECKSUMS)
+    #[arg(long, value_name = "FILE")]
+    model_checksums: Option<PathBuf>,
+    
+    /// Config file whose profiles fill in flags not given (default: portablebrains.toml in
+    /// the current directory, or the file PORTABLE_BRAINS_CONFIG names)
+    #[arg(long)]
+    #[allow(dead_code)] // Read from the raw arguments by profiles::apply before parsing
+    config: Option<PathBuf>,
+    
+    /// Profile of the config file to use (default: the file's `profile`, or one named
+    /// `default`); also set by PORTABLE_BRAINS_PROFILE
+    #[arg(long)]
+    #[allow(dead_code)] // Read from the raw arguments by profiles::apply before parsing
+    profile: Option<String>,
 }
 
-#[derive(serde::Serialize, serde::Deserialize)]
-struct ChatMessage {
-    role: String,
-    content: String,
+/// Questions kept in a database's chat history
+const HISTORY_SIZE: usize = 1000;
+
+/// Sampling temperature answers are generated with
+const ANSWER_TEMPERATURE: f32 = 0.7;
+
+/// Name of a flag value as given on the command line
+fn value_name(value: &impl ValueEnum) -> Option<String> {
+    value.to_possible_value().map(|value| value.get_name().to_string())
 }
 
-#[derive(serde::Serialize)]
-struct ChatRequest {
-    model: String,
-    messages: Vec<ChatMessage>,
-    max_tokens: Option<u32>,
-    temperature: Option<f32>,
+/// Where the chat keeps the questions asked of a database, next to it like its jobs
+fn history_path(database: &Path) -> PathBuf {
+    storage::sidecar_path(database, ".history")
+}
+
+/// Where the chat records how each question's passages were retrieved
+fn trace_path(database: &Path) -> PathBuf {
+    storage::sidecar_path(database, ".traces")
+}
+
+/// A retrieved passage as recorded in a trace
+fn traced_hit(hit: &BrainHit) -> TracedHit {
+    TracedHit {
+        brain: hit.brain.clone(),
+        fragment_id: hit.fragment_id.clone(),
+        file_path: hit.source.file_path.clone(),
+        version: hit.version,
+        score: hit.score,
+        rerank_score: None,
+    }
+}
+
+/// A corpus answer's provenance for the audit log: every request it made as the prompt,
+/// each map request with the documents its batch read, and every fragment of those
+/// documents, tied to them by document id
+fn corpus_provenance(answer: &CorpusAnswer, batches: &[Vec<Excerpt>], documents: &[CorpusDocument]) -> Result<(serde_json::Value, Vec<AuditedFragment>)> {
+    let mut prompt = Vec::new();
+    for request in &answer.prompts {
+        let mut entry = serde_json::to_value(request)?;
+        if let Some(batch) = request.batch.and_then(|batch| batches.get(batch)) {
+            // A document's excerpts sit next to each other in a batch
+            let mut read: Vec<usize> = batch.iter().map(|excerpt| excerpt.document).collect();
+            read.dedup();
+            entry["documents"] = read.into_iter()
+                .map(|index| serde_json::json!({ "brain": documents[index].brain, "document_id": documents[index].document_id }))
+                .collect();
+        }
+        prompt.push(entry);
+    }
+    let fragments = documents.iter()
+        .flat_map(|document| document.fragment_ids.iter().map(move |fragment_id| AuditedFragment {
+            brain: document.brain.clone(),
+            fragment_id: fragment_id.clone(),
+            version: document.version,
+            score: None,
+            document_id: Some(document.document_id.clone()),
+        }))
+        .collect();
+    Ok((serde_json::Value::Array(prompt), fragments))
 }
 
-#[derive(serde::Deserialize)]
-struct ChatChoice {
-    message: ChatMessage,
+/// Settings of the audit parameters that decide which passages are retrieved, recorded with
+/// each turn's trace
+const RETRIEVAL_SETTINGS: &[&str] = &[
+    "databases", "routing", "embedding_model", "search_mode", "query_expansion", "rerank_model",
+    "results", "per_document_limit", "context_tokens", "mmr_lambda", "typed_context",
+];
+
+/// Completes questions as they are typed from the brains' document titles, headings and
+/// frequent entities: the best completion is hinted after the cursor (→ takes it) and Tab
+/// goes through the others
+struct QuestionHelper {
+    completions: PrefixIndex,
+}
+
+type QuestionEditor = Editor<QuestionHelper, DefaultHistory>;
+
+impl Completer for QuestionHelper {
+    type Candidate = Pair;
+
+    fn complete(&self, line: &str, pos: usize, _ctx: &rustyline::Context<'_>) -> rustyline::Result<(usize, Vec<Pair>)> {
+        let suggestions = self.completions.suggest(&line[..pos], autocomplete::DEFAULT_SUGGESTIONS, |_| true);
+        let start = suggestions.first().map_or(pos, |suggestion| suggestion.start);
+        let candidates = suggestions.into_iter()
+            .map(|suggestion| Pair { replacement: suggestion.query[start..].to_string(), display: suggestion.term })
+            .collect();
+        Ok((start, candidates))
+    }
+}
+
+impl Hinter for QuestionHelper {
+    type Hint = String;
+
+    fn hint(&self, line: &str, pos: usize, _ctx: &rustyline::Context<'_>) -> Option<String> {
+        if pos < line.len() {
+            return None;
+        }
+        // The completion keeps what was typed, so the hint is the rest of it
+        let suggestion = self.completions.suggest(line, 1, |_| true).into_iter().next()?;
+        suggestion.query.get(line.len()..).filter(|rest| !rest.is_empty()).map(str::to_string)
+    }
+}
+
+impl Highlighter for QuestionHelper {
+    fn highlight_hint<'h>(&self, hint: &'h str) -> Cow<'h, str> {
+        Cow::Owned(style(hint).dim().to_string())
+    }
+}
+
+impl Validator for QuestionHelper {}
+
+impl Helper for QuestionHelper {}
+
+/// Build the embedder for questions from the model and provider recorded in the first
+/// database, with any given flags taking precedence. Also returns the length of the vectors
+/// stored under that model, when it is the database's current one, and the provider used.
+async fn query_embedder(args: &Args, backend: Option<StorageBackend>) -> Result<(EmbeddingManager, Option<usize>, EmbeddingProvider)> {
+    let first = &args.database[0];
+    if !remote_storage::is_remote(first) && !first.exists() {
+        anyhow::bail!("Database file does not exist: {}", first.display());
+    }
+    let mut storage = create_storage(&backend.unwrap_or_else(|| StorageBackend::from_path(first)), first).await
+        .with_context(|| format!("Failed to open database {}", first.display()))?;
+    let meta = storage.get_meta_info().await?;
+
+    let model = match &args.embedding_model {
+        Some(model) => model.clone(),
+        None if meta.embedding_model == "unknown" => {
+            anyhow::bail!("{} has no recorded embedding model; pass --embedding-model", first.display())
+        }
+        None => meta.embedding_model.clone(),
+    };
+    let dimension = if model == meta.embedding_model {
+        storage::embedding_dimension(&mut *storage).await?
+    } else {
+        None
+    };
+
+    let endpoint = args.embedding_endpoint.clone();
+    let api_key = args.embedding_api_key.clone();
+    let provider = args.embedding_provider.unwrap_or_else(|| {
+        EmbeddingProvider::recorded(meta.embedding_provider.as_deref(), endpoint.as_deref(), api_key.as_deref())
+    });
+    if args.embedding_model.is_none() || args.embedding_provider.is_none() {
+        println!("🤖 Embedding questions with {} ({:?} provider)", model, provider);
+    }
+
+    let embedding_manager = create_embedder(provider, &model, args, first).await?;
+    Ok((embedding_manager, dimension, provider))
 }
 
-#[derive(serde::Deserialize)]
-struct ChatResponse {
-    choices: Vec<ChatChoice>,
+/// Embedder for `model` from `provider`, with the endpoint and key given for embeddings;
+/// `database` is named when a remote provider has no key
+async fn create_embedder(provider: EmbeddingProvider, model: &str, args: &Args, database: &Path) -> Result<EmbeddingManager> {
+    let endpoint = args.embedding_endpoint.clone();
+    let api_key = args.embedding_api_key.clone();
+    match provider {
+        EmbeddingProvider::Local => EmbeddingManager::new(model).await
+            .context("Failed to initialize local embedding manager"),
+        EmbeddingProvider::Remote => {
+            let api_key = api_key.ok_or_else(|| anyhow::anyhow!(
+                "{} was embedded by a remote provider; pass --embedding-api-key, or --embedding-endpoint for a compatible server",
+                database.display()
+            ))?;
+            EmbeddingManager::new_remote(api_key, model, endpoint).await
+                .context("Failed to initialize remote embedding manager")
+        }
+        EmbeddingProvider::Ollama => EmbeddingManager::new_ollama(model, endpoint).await
+            .context("Failed to initialize Ollama embedding manager"),
+        EmbeddingProvider::Compatible => {
+            let endpoint = endpoint.ok_or_else(|| anyhow::anyhow!(
+                "--embedding-endpoint is required for the compatible embedding provider, e.g. http://localhost:8080/v1/embeddings"
+            ))?;
+            EmbeddingManager::new_compatible(model, endpoint, api_key).await
+                .context("Failed to initialize remote embedding manager")
+        }
+    }
 }
 
 struct RagEngine {
-    storage: Box<dyn Storage>,
+    brains: BrainSet,
+    routing: RoutingMode,
+    search_mode: SearchMode,
     embedding_manager: EmbeddingManager,
-    llm_client: reqwest::Client,
-    endpoint: String,
-    api_key: String,
-    model: String,
+    /// Re-scores retrieved passages before the best are answered from
+    reranker: Option<CrossEncoder>,
+    /// How questions are rewritten into further searches before retrieval
+    query_expansion: QueryExpansion,
+    /// Picks the passages sent with a question from those retrieved
+    context: ContextBuilder,
+    /// Lays passages out by kind, with `--typed-context`
+    templates: Option<PassageTemplates>,
+    llm: LlmClient,
     max_results: usize,
+    /// Passages taken from each document at most
+    per_document: Option<usize>,
+    system_prompt: String,
+    answer_length: AnswerLength,
+    verify: bool,
+    /// Grounding score below which an answer is flagged
+    grounding_threshold: f64,
+    suggest: bool,
+    /// Label passages with their source dates so the model can caveat old information
+    dated_passages: bool,
+    /// Answer every question from notes on the whole corpus rather than retrieved passages
+    whole_corpus: bool,
+    /// Answer with the passages' sentences closest to the question instead of asking the LLM
+    no_llm: bool,
+    /// Sentences in an answer without the LLM
+    sentences: usize,
+    /// Instructions for answers written from notes on the whole corpus
+    corpus_prompt: String,
+    /// Follow-up questions suggested after the last answer, picked by number in the chat
+    suggestions: Vec<String>,
+    /// Document metadata filter set with `/filter`
+    filter: SearchFilter,
+    /// File the chat's questions are kept in, from the first database
+    history: Option<PathBuf>,
+    /// The last question answered in the chat, for `/copy` and `/savefile`
+    last_answer: Option<LastAnswer>,
+    /// Opened on the first `/copy` and kept, since on X11 the copied text is only
+    /// available while its owner is alive
+    clipboard: Option<arboard::Clipboard>,
+    /// Tokens and estimated dollars spent on LLM requests this session
+    spending: SpendingCap,
+    /// Applied to every answer before it is shown or returned
+    output_filters: OutputFilters,
+    /// Every answer is recorded here before it is shown or returned
+    audit: Option<AuditLog>,
+    /// Generation and retrieval settings recorded with audited answers
+    parameters: serde_json::Value,
+    /// How each question's passages were retrieved is recorded here, for `--replay`
+    traces: Option<TraceLog>,
+    /// How the passages of the last question were retrieved
+    last_trace: Option<RetrievalTrace>,
     verbose: bool,
 }
 
+/// A chat answer kept for `/copy` and `/savefile`
+struct LastAnswer {
+    question: String,
+    answer: String,
+    hits: Vec<BrainHit>,
+}
+
+/// Instructions used when no preset applies
+const DEFAULT_INSTRUCTIONS: &str = "You are a helpful AI assistant with access to a knowledge base. \
+    Use the following context to answer the user's question. If the context \
+    doesn't contain relevant information, say so politely.";
+
+/// Appended to every preset's instructions, since answers are verified against their citations
+const CITATION_INSTRUCTIONS: &str = "Cite the passages each statement relies on with their numbers \
+    in square brackets, e.g. [2].";
+
+/// Instructions for answers written from notes on every document
+const CORPUS_INSTRUCTIONS: &str = "You are a helpful AI assistant answering from notes taken on \
+    every document in a knowledge base, so you can aggregate, compare and summarize across all of \
+    them. Each note names the document it comes from in square brackets; name the documents your \
+    statements rely on the same way. If the notes don't cover the question, say so politely.";
+
 impl RagEngine {
     async fn new(args: Args) -> Result<Self> {
         // Process AI model selection and auto-configure endpoint/model
@@ -155,6 +729,8 @@ impl RagEngine {
                 
                 (endpoint, model)
             }
+            // Answers are extracted from the passages, so no endpoint is sent anything
+            None if args.no_llm => (args.endpoint.clone().unwrap_or_default(), args.model.clone()),
             None => {
                 // No AI model specified, require endpoint and use provided model
                 let endpoint = args.endpoint.clone()
@@ -163,21 +739,97 @@ impl RagEngine {
             }
         };
 
-        // Validate database exists
-        if !args.database.exists() {
-            anyhow::bail!("Database file does not exist: {}", args.database.display());
+        // Embed questions the way the first brain was indexed unless told otherwise, then
+        // open every brain, verifying they share that model
+        let backend = args.backend.as_ref().map(Backend::storage_backend);
+        let (mut embedding_manager, dimension, provider) = query_embedder(&args, backend.clone()).await?;
+        let embedding_model = embedding_manager.model_name().to_string();
+        let routing = args.routing.mode();
+        let mut brains = BrainSet::open(&args.database, backend, &embedding_model, routing).await
+            .context("Failed to open database")?;
+        for model in brains.collection_models() {
+            println!("🗂️  Embedding questions with {} too, for the collections embedded with it", model);
+            let embedder = create_embedder(provider, &model, &args, &args.database[0]).await?;
+            brains.add_collection_embedder(&model, embedder);
+        }
+        if let Some((_, document)) = brains.focus(args.document.as_deref()).await? {
+            println!("📄 Answering from {} only", document.file_path);
+        }
+        if !args.types.is_empty() {
+            println!("🔎 Answering from {} files only", args.types.join(", "));
         }
 
-        // Initialize storage
-        let storage = Box::new(DuckDBStorage::new(&args.database).await
-            .context("Failed to open database")?);
+        let preset = args.preset.or_else(|| brains.preset());
+        if let Some(preset) = preset {
+            println!("🎛️  Answering with the {} preset", preset.name());
+        }
+        let instructions = preset.map_or(DEFAULT_INSTRUCTIONS, Preset::system_prompt);
+        let style = AnswerStyle {
+            language: args.answer_language.clone(),
+            length: args.answer_length,
+            format: args.format,
+        };
+        let style_instructions = style.instructions();
+        let mut system_prompt = if style_instructions.is_empty() {
+            format!("{} {}", instructions, CITATION_INSTRUCTIONS)
+        } else {
+            format!("{} {} {}", instructions, CITATION_INSTRUCTIONS, style_instructions)
+        };
+        let corpus_prompt = if style_instructions.is_empty() {
+            CORPUS_INSTRUCTIONS.to_string()
+        } else {
+            format!("{} {}", CORPUS_INSTRUCTIONS, style_instructions)
+        };
+        if let Some(days) = args.stale_after {
+            system_prompt.push(' ');
+            system_prompt.push_str(&staleness_instructions(days, chrono::Local::now().date_naive()));
+        }
 
-        // Initialize embedding manager
-        let embedding_manager = EmbeddingManager::new(&args.embedding_model).await
-            .context("Failed to initialize embedding manager")?;
+        // Vectors of another length can't be compared with those stored in the brains; a
+        // remote model's length is only known from its first vector, which is checked then
+        if let Some(known) = embedding_manager.known_dimension() {
+            brains.verify_dimension(&embedding_model, known).await?;
+        } else if let Some(dimension) = dimension {
+            embedding_manager.expect_dimension(dimension);
+        }
+
+        let provider = args.provider
+            .or_else(|| args.ai_model.as_ref().and_then(AIModel::provider))
+            .unwrap_or_else(|| Provider::from_endpoint(&final_endpoint));
+        let api_key = match args.api_key.clone() {
+            Some(api_key) => api_key,
+            None if provider.requires_api_key() && !args.no_llm => {
+                anyhow::bail!("--api-key is required for the {:?} provider", provider)
+            }
+            None => String::new(),
+        };
+        let llm = LlmClient::new(final_endpoint, api_key, final_model)
+            .with_provider(provider)
+            .with_prompt_cache(!args.no_prompt_cache);
+        if !args.no_llm {
+            offline::check(&llm.endpoint, "The LLM API")?;
+        } else if args.query_expansion != QueryExpansion::Off {
+            anyhow::bail!("--query-expansion has the LLM rewrite questions, so it can't be used with --no-llm");
+        }
+        if args.no_llm && args.sentences == 0 {
+            anyhow::bail!("--sentences must be at least 1");
+        }
 
-        // Create HTTP client
-        let llm_client = reqwest::Client::new();
+        let price = match (args.input_price, args.output_price) {
+            (Some(input), Some(output)) => Some(ModelPrice { input, cached_input: input, output }),
+            _ => ModelPrice::for_model(&llm.model),
+        };
+        if args.max_session_cost.is_some() && price.is_none() {
+            println!("⚠️  No price is known for {}, so --max-session-cost can't be enforced; pass --input-price and --output-price", llm.model);
+        }
+        let spending = SpendingCap::new(price, args.max_session_cost);
+        if !(0.0..=1.0).contains(&args.grounding_threshold) {
+            anyhow::bail!("--grounding-threshold must be between 0 and 1, got {}", args.grounding_threshold);
+        }
+        let output_filters = match &args.output_filters {
+            Some(path) => OutputFilters::load(path)?,
+            None => OutputFilters::default(),
+        };
 
         // Validate results count
         let max_results = if args.results == 0 || args.results > 20 {
@@ -186,114 +838,439 @@ impl RagEngine {
         } else {
             args.results
         };
+        let reranker = args.rerank_model.as_deref().map(CrossEncoder::new).transpose()?;
+        let context = ContextBuilder::new(max_results, args.context_tokens, args.mmr_lambda)?;
+        // Remote models' tokenizers aren't available, so their passages are estimated
+        let tokenizer = args.context_tokenizer.clone()
+            .or_else(|| embedding_manager.known_dimension().map(|_| embedding_model.clone()));
+        let context = match tokenizer.as_deref().map(|name| (name, embedding_manager::load_tokenizer(name))) {
+            Some((_, Ok(tokenizer))) => context.with_tokenizer(tokenizer)?,
+            Some((name, Err(e))) if args.context_tokenizer.is_some() => {
+                return Err(e.context(format!("Failed to load the {} tokenizer", name)));
+            }
+            _ => {
+                println!("ℹ️  Estimating context tokens at {} characters each; pass --context-tokenizer to count them", document_processor::CHARS_PER_TOKEN);
+                context
+            }
+        };
+
+        let templates = match &args.passage_templates {
+            Some(path) => Some(PassageTemplates::load(path)?),
+            None => args.typed_context.then(PassageTemplates::default),
+        };
+
+        let audit = args.audit_log.as_deref().map(AuditLog::new);
+        if let Some(audit) = &audit {
+            println!("🧾 Recording every answer in the audit log {}", audit.path().display());
+        }
+        let parameters = serde_json::json!({
+            "databases": args.database.iter().map(|database| database.display().to_string()).collect::<Vec<_>>(),
+            "routing": value_name(&args.routing),
+            "embedding_model": embedding_model,
+            "search_mode": value_name(&args.search_mode),
+            "query_expansion": value_name(&args.query_expansion),
+            "rerank_model": args.rerank_model,
+            "results": max_results,
+            "per_document_limit": args.per_document_limit,
+            "context_tokens": args.context_tokens,
+            "mmr_lambda": args.mmr_lambda,
+            "preset": preset.map(Preset::name),
+            "answer_language": args.answer_language,
+            "answer_length": value_name(&args.answer_length),
+            "format": args.format.and_then(|format| value_name(&format)),
+            "stale_after": args.stale_after,
+            "max_tokens": args.answer_length.max_tokens(),
+            "temperature": ANSWER_TEMPERATURE,
+            "prompt_cache": !args.no_prompt_cache,
+            "typed_context": templates.is_some(),
+        });
 
         Ok(RagEngine {
-            storage,
+            brains,
+            routing,
+            search_mode: args.search_mode,
             embedding_manager,
-            llm_client,
-            endpoint: final_endpoint,
-            api_key: args.api_key,
-            model: final_model,
+            reranker,
+            query_expansion: args.query_expansion,
+            context,
+            templates,
+            llm,
             max_results,
+            per_document: args.per_document_limit.map(|limit| limit as usize),
+            system_prompt,
+            answer_length: args.answer_length,
+            verify: args.verify,
+            grounding_threshold: args.grounding_threshold,
+            suggest: args.suggest,
+            dated_passages: args.stale_after.is_some(),
+            whole_corpus: args.whole_corpus,
+            no_llm: args.no_llm,
+            sentences: args.sentences,
+            corpus_prompt,
+            suggestions: Vec::new(),
+            filter: SearchFilter {
+                file_types: args.types.iter().map(|value| storage::file_type(value)).collect(),
+                ..SearchFilter::default()
+            },
+            history: (!args.no_history).then(|| history_path(&args.database[0])),
+            last_answer: None,
+            clipboard: None,
+            spending,
+            output_filters,
+            audit,
+            parameters,
+            traces: (!args.no_trace).then(|| TraceLog::new(&trace_path(&args.database[0]))),
+            last_trace: None,
             verbose: args.verbose,
         })
     }
 
-    async fn search_similar_content(&mut self, query: &str) -> Result<Vec<String>> {
-        // Generate embedding for the query
-        let query_embedding = self.embedding_manager.generate_embeddings_batch(&[query.to_string()]).await
+    /// Retrieve the passages that best match the query across the configured brains, with
+    /// the tokens spent expanding it
+    async fn retrieve(&mut self, query: &str) -> Result<(Vec<BrainHit>, Option<(String, f64)>, TokenUsage)> {
+        let original = query;
+        // A `section:"Heading"` filter narrows retrieval instead of being embedded with the
+        // question
+        let (query, section) = SearchFilter::parse_section(query);
+        let expansion = match expansion::expand_query(&self.llm, &query, self.query_expansion).await {
+            Ok(expansion) => expansion,
+            Err(e) => {
+                log::warn!("Searching with the question alone: {:#}", e);
+                expansion::Expansion::default()
+            }
+        };
+        if self.verbose {
+            for variant in &expansion.variants {
+                println!("   {} Also searching for: {}", style("↪").dim(), variant);
+            }
+        }
+
+        // Generate embeddings for the query and its variants, the way the brains embed
+        // passages for a hypothetical answer
+        let prefixes = self.brains.prefixes();
+        let mut texts = vec![prefixes.query(&query)];
+        texts.extend(expansion.variants.iter().map(|variant| {
+            if expansion.passages { prefixes.document(variant) } else { prefixes.query(variant) }
+        }));
+        let query_embeddings = self.embedding_manager.generate_embeddings_batch(&texts).await
             .context("Failed to generate query embedding")?;
 
-        if query_embedding.is_empty() {
+        if query_embeddings.len() != texts.len() {
             anyhow::bail!("Failed to generate embedding for query");
         }
 
-        // Search for similar content in the database
-        let results = self.storage.search_similar(&query_embedding[0], self.max_results).await
+        // Search for similar content across the configured brains
+        let filter = SearchFilter { section, ..self.filter.clone() };
+        // The context builder picks the passages from a wider pool of candidates, as does the
+        // reranker, and limiting passages per document needs spares for those it drops
+        let candidates = self.max_results * context::CANDIDATE_POOL_FACTOR;
+        let keep = if self.per_document.is_some() { candidates * retrieval::PER_DOCUMENT_POOL_FACTOR } else { candidates };
+        let fetch = if self.reranker.is_some() { keep.max(RERANK_POOL) } else { keep };
+        let search = self.brains.search(&query, &query_embeddings[0], fetch, self.routing, self.search_mode, &filter).await
             .context("Failed to search similar content")?;
+        let routed_to = search.routed_to;
+        let mut rankings = vec![search.hits];
+        for (variant, embedding) in expansion.variants.iter().zip(&query_embeddings[1..]) {
+            let search = self.brains.search(variant, embedding, fetch, self.routing, self.search_mode, &filter).await
+                .context("Failed to search similar content")?;
+            rankings.push(search.hits);
+        }
+        let found = if rankings.len() > 1 { expansion::fuse_rankings(rankings, fetch) } else { rankings.remove(0) };
+        let mut candidates_traced: Vec<TracedHit> = found.iter().map(traced_hit).collect();
 
-        // Extract just the content from the results (ignore fragment_id and similarity_score)
-        let content: Vec<String> = results.into_iter()
-            .map(|(_, content, _)| content)
-            .collect();
+        let mut hits = match &mut self.reranker {
+            Some(reranker) => reranker.rerank(&query, found, |hit| hit.content.as_str(), keep)?
+                .into_iter()
+                .map(|(hit, score)| BrainHit { score, ..hit })
+                .collect(),
+            None => found,
+        };
+        if self.reranker.is_some() {
+            let scores: HashMap<(&str, &str), f64> = hits.iter().map(|hit| ((hit.brain.as_str(), hit.fragment_id.as_str()), hit.score)).collect();
+            for candidate in &mut candidates_traced {
+                candidate.rerank_score = scores.get(&(candidate.brain.as_str(), candidate.fragment_id.as_str())).copied();
+            }
+        }
+        if let Some(per_document) = self.per_document {
+            hits = retrieval::limit_per_document(hits, per_document, |hit| (hit.brain.clone(), hit.source.file_path.clone())).0;
+            hits.truncate(candidates);
+        }
 
-        Ok(content)
+        let vectors = self.brains.hit_vectors(&hits).await?;
+        let hits = match &self.templates {
+            // Measured as laid out; the number's few characters are left out
+            Some(templates) => context::order_by_kind(self.context.pack(hits, &vectors, |hit| templates.render(0, hit))),
+            None => self.context.pack(hits, &vectors, |hit| self.passage(hit)),
+        };
+
+        let settings = RETRIEVAL_SETTINGS.iter()
+            .map(|setting| (setting.to_string(), self.parameters[*setting].clone()))
+            .collect::<serde_json::Map<_, _>>();
+        self.last_trace = Some(RetrievalTrace {
+            question: original.to_string(),
+            query_embedding_hash: trace::embedding_hash(&query_embeddings[0]),
+            variants: expansion.variants.clone(),
+            filter: TracedFilter::new(&self.filter, self.brains.focused_document()),
+            settings: serde_json::Value::Object(settings),
+            routed_to: routed_to.as_ref().map(|(brain, _)| brain.clone()),
+            candidates: candidates_traced,
+            passages: hits.iter().map(traced_hit).collect(),
+        });
+        Ok((hits, routed_to, expansion.token_usage))
     }
 
-    async fn generate_response(&self, query: &str, context: &[String]) -> Result<String> {
-        // Prepare context for the LLM
-        let context_text = if context.is_empty() {
-            "No relevant documents found.".to_string()
-        } else {
-            context.join("\n\n")
+    /// Record how the last question's passages were retrieved, when traces are kept. A trace
+    /// that can't be written is only reported; the answer doesn't depend on it.
+    fn record_trace(&mut self) {
+        let (Some(traces), Some(trace)) = (&self.traces, self.last_trace.take()) else {
+            return;
         };
+        if let Err(e) = traces.append(trace) {
+            log::warn!("Failed to record the retrieval trace: {:#}", e);
+        }
+    }
 
-        let system_prompt = format!(
-            "You are a helpful AI assistant with access to a knowledge base. \
-            Use the following context to answer the user's question. If the context \
-            doesn't contain relevant information, say so politely.\n\nContext:\n{}",
-            context_text
-        );
-
-        let messages = vec![
-            ChatMessage {
-                role: "system".to_string(),
-                content: system_prompt,
-            },
-            ChatMessage {
-                role: "user".to_string(),
-                content: query.to_string(),
-            },
-        ];
+    async fn search_similar_content(&mut self, query: &str) -> Result<Vec<BrainHit>> {
+        let (hits, routed_to, token_usage) = self.retrieve(query).await?;
+        self.spending.record(token_usage);
+        self.record_trace();
 
-        let request = ChatRequest {
-            model: self.model.clone(),
-            messages,
-            max_tokens: Some(1000),
-            temperature: Some(0.7),
+        if let Some((brain, similarity)) = &routed_to {
+            println!("{} Routed to brain '{}' (centroid similarity {:.3})", style("🧭").dim(), brain, similarity);
+        }
+
+        if self.verbose {
+            for hit in &hits {
+                println!("   {} [{} v{}] {} ({:.3})", style("•").dim(), hit.brain, hit.version, hit.fragment_id, hit.score);
+            }
+        }
+
+        Ok(hits)
+    }
+
+    /// Answer one question without any terminal output, for `--output json`
+    async fn answer_structured(&mut self, query: &str) -> Result<StructuredAnswer> {
+        let (hits, _, mut token_usage) = self.retrieve(query).await?;
+        self.record_trace();
+        let context = self.passages(&hits);
+
+        let (messages, reply) = self.answer(query, &hits, &context).await?;
+        token_usage += reply.usage;
+        let filtered = self.filter_answer(query, &reply.content)?;
+        self.record_answer(query, &messages, &hits, &filtered, self.answer_mode())?;
+        if filtered.withheld.is_some() {
+            let mut answer = StructuredAnswer::new(filtered.text, &[], token_usage, None);
+            answer.withheld = filtered.withheld;
+            return Ok(answer);
+        }
+        let response = filtered.text;
+
+        let verification = if self.verify {
+            let report = verification::verify_answer(&self.llm, &response, &context).await?;
+            token_usage += report.token_usage;
+            Some(report)
+        } else {
+            None
         };
 
-        // Make API call to LLM
-        let response = self.llm_client
-            .post(&self.endpoint)
-            .header("Authorization", format!("Bearer {}", self.api_key))
-            .header("Content-Type", "application/json")
-            .json(&request)
-            .send()
-            .await
-            .context("Failed to send request to LLM API")?;
+        let follow_ups = if self.suggest {
+            let follow_ups = followups::suggest_follow_ups(&self.llm, query, &response, &context).await?;
+            token_usage += follow_ups.token_usage;
+            follow_ups.questions
+        } else {
+            Vec::new()
+        };
+
+        let mut answer = StructuredAnswer::new(response, &hits, token_usage, verification.as_ref());
+        answer.follow_ups = follow_ups;
+        Ok(answer)
+    }
+
+    /// Answer a question from notes taken on every document, for questions top-k retrieval
+    /// can't cover. `progress` is told how many documents and requests it takes.
+    async fn answer_corpus(&mut self, query: &str, progress: impl FnOnce(usize, usize)) -> Result<CorpusAnswer> {
+        let documents = self.brains.corpus().await?;
+        let batches = corpus::plan(&documents, corpus::BATCH_CHARS, self.brains.len() > 1);
+        progress(documents.len(), batches.len());
+        let mut answer = corpus::answer_over_corpus(&self.llm, query, &batches, &self.corpus_prompt, self.answer_length.max_tokens()).await?;
+        let filtered = self.filter_answer(query, &answer.answer)?;
+        if self.audit.is_some() {
+            let (prompt, fragments) = corpus_provenance(&answer, &batches, &documents)?;
+            self.append_audit(query, prompt, fragments, &filtered, "corpus")?;
+        }
+        if filtered.withheld.is_some() {
+            answer.sources.clear();
+        }
+        answer.answer = filtered.text;
+        answer.withheld = filtered.withheld;
+        Ok(answer)
+    }
 
-        if !response.status().is_success() {
-            let status = response.status();
-            let error_text = response.text().await.unwrap_or_default();
-            anyhow::bail!("LLM API error {}: {}", status, error_text);
+    /// Run an answer through the output filters. Withheld answers are logged with the
+    /// reason, which the reader isn't shown.
+    fn filter_answer(&self, query: &str, answer: &str) -> Result<FilteredAnswer> {
+        let filtered = self.output_filters.apply(query, answer)
+            .context("Failed to filter the answer")?;
+        if let Some(reason) = &filtered.withheld {
+            log::warn!("Withheld an answer to {:?}: {}", query, reason);
+        }
+        if filtered.redactions > 0 {
+            log::debug!("Redacted {} matches from the answer", filtered.redactions);
         }
+        Ok(filtered)
+    }
 
-        let chat_response: ChatResponse = response.json().await
-            .context("Failed to parse LLM response")?;
+    /// The retrieved passages as given to the model, dated when asked to caveat old sources
+    fn passages(&self, hits: &[BrainHit]) -> Vec<String> {
+        hits.iter().map(|hit| self.passage(hit)).collect()
+    }
 
-        if chat_response.choices.is_empty() {
-            anyhow::bail!("No response choices received from LLM");
+    fn passage(&self, hit: &BrainHit) -> String {
+        if self.dated_passages { dated_passage(hit) } else { hit.content.clone() }
+    }
+
+    /// Record an answer in the audit log, when there is one, with the messages it was
+    /// generated from and the passages retrieved for it
+    fn record_answer(&self, query: &str, messages: &[ChatMessage], hits: &[BrainHit], filtered: &FilteredAnswer, mode: &str) -> Result<()> {
+        if self.audit.is_none() {
+            return Ok(());
         }
+        let fragments = hits.iter()
+            .map(|hit| AuditedFragment {
+                brain: hit.brain.clone(),
+                fragment_id: hit.fragment_id.clone(),
+                version: hit.version,
+                score: Some(hit.score),
+                document_id: None,
+            })
+            .collect();
+        self.append_audit(query, serde_json::to_value(messages)?, fragments, filtered, mode)
+    }
+
+    /// Append an answer to the audit log, when there is one, with the settings in effect
+    fn append_audit(&self, query: &str, prompt: serde_json::Value, fragments: Vec<AuditedFragment>, filtered: &FilteredAnswer, mode: &str) -> Result<()> {
+        let Some(audit) = &self.audit else {
+            return Ok(());
+        };
+        let mut parameters = self.parameters.clone();
+        parameters["mode"] = serde_json::json!(mode);
+        parameters["filter"] = serde_json::json!(self.filter.describe());
+        audit.append(AuditEntry {
+            question: query.to_string(),
+            prompt,
+            fragments,
+            model: if self.no_llm { self.embedding_manager.model_name().to_string() } else { self.llm.model.clone() },
+            provider: if self.no_llm { "none".to_string() } else { value_name(&self.llm.provider).unwrap_or_default() },
+            endpoint: if self.no_llm { String::new() } else { self.llm.endpoint.clone() },
+            parameters,
+            answer: filtered.text.clone(),
+            withheld: filtered.withheld.clone(),
+        }).context("Failed to record the answer in the audit log")?;
+        Ok(())
+    }
+
+    /// Answer `query` from the retrieved passages, returning the messages the answer was
+    /// generated from: by the LLM, or with `--no-llm` from the passages' sentences closest to
+    /// the question, which sends no messages
+    async fn answer(&mut self, query: &str, hits: &[BrainHit], context: &[String]) -> Result<(Vec<ChatMessage>, ChatReply)> {
+        if self.no_llm {
+            let passages: Vec<&str> = hits.iter().map(|hit| hit.content.as_str()).collect();
+            let content = extractive::extract_answer(&mut self.embedding_manager, self.brains.prefixes(), query, &passages, self.sentences).await?;
+            return Ok((Vec::new(), ChatReply { content, usage: TokenUsage::default() }));
+        }
+        let messages = self.answer_messages(query, hits, context);
+        let reply = self.generate_response(messages.clone()).await?;
+        Ok((messages, reply))
+    }
+
+    /// How answers from retrieved passages are written, as recorded in the audit log
+    fn answer_mode(&self) -> &'static str {
+        if self.no_llm { "extractive" } else { "passages" }
+    }
+
+    /// The messages asking the model to answer `query` from the retrieved passages
+    fn answer_messages(&self, query: &str, hits: &[BrainHit], context: &[String]) -> Vec<ChatMessage> {
+        // Number the passages so the answer can cite them and citations can be verified
+        let context_text = if context.is_empty() {
+            "No relevant documents found.".to_string()
+        } else if let Some(templates) = &self.templates {
+            hits.iter()
+                .enumerate()
+                .map(|(i, hit)| templates.render(i + 1, hit))
+                .collect::<Vec<_>>()
+                .join("\n\n")
+        } else {
+            context.iter()
+                .enumerate()
+                .map(|(i, passage)| format!("[{}] {}", i + 1, passage))
+                .collect::<Vec<_>>()
+                .join("\n\n")
+        };
 
-        Ok(chat_response.choices[0].message.content.clone())
+        // The fixed instructions come first so every question shares a cached prefix, and
+        // the context is cached too for repeated questions over the same passages
+        vec![
+            ChatMessage::system(self.system_prompt.as_str()).cached(),
+            ChatMessage::system(format!("Context:\n{}", context_text)).cached(),
+            ChatMessage::user(query),
+        ]
+    }
+
+    async fn generate_response(&self, messages: Vec<ChatMessage>) -> Result<ChatReply> {
+        self.llm.complete(messages, self.answer_length.max_tokens(), ANSWER_TEMPERATURE).await
     }
 
     async fn chat_loop(&mut self) -> Result<()> {
-        let term = Term::stdout();
+        let config = Config::builder()
+            .max_history_size(HISTORY_SIZE)?
+            .history_ignore_dups(true)?
+            .build();
+        let mut editor = QuestionEditor::with_config(config).context("Failed to start line editing")?;
+        let completions = self.brains.completions().await?;
+        let completing = !completions.is_empty();
+        editor.set_helper(Some(QuestionHelper { completions }));
+        if let Some(path) = &self.history {
+            match editor.load_history(path) {
+                Ok(()) => {}
+                Err(ReadlineError::Io(e)) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => println!("⚠️  Couldn't load question history from {}: {}", path.display(), e),
+            }
+        }
         
         println!("🧠 {} - Conversational RAG", style("EatMyBrain").bold().cyan());
-        println!("💬 Type your questions or 'quit' to exit");
+        println!("💬 Type your questions or 'quit' to exit (↑/↓ for previous questions, Ctrl-R to search them)");
+        if completing {
+            println!("✨ Questions are completed from document titles, headings and names as you type (→ to accept, Tab for more)");
+        }
         println!("🔍 Retrieving {} similar documents per query", self.max_results);
+        if let Some(per_document) = self.per_document {
+            println!("📚 At most {} passages from each document", per_document);
+        }
+        if let Some(reranker) = &self.reranker {
+            println!("🎯 Reranking the top {} candidates with {}", self.max_results.max(RERANK_POOL), reranker.model_name());
+        }
+        if self.brains.len() > 1 {
+            let mode = match self.routing {
+                RoutingMode::Route => "routing",
+                RoutingMode::Federate => "federating",
+            };
+            println!("🧠 {} across brains: {}", mode, self.brains.names().join(", "));
+        }
         println!();
 
+        let prompt = format!("{} ", style("❯").bold().green());
         loop {
-            // Print prompt
-            print!("{} ", style("❯").bold().green());
-            io::stdout().flush().unwrap();
-
-            // Read user input
-            let input = term.read_line().context("Failed to read input")?;
+            // Read user input; Ctrl-C abandons the line and Ctrl-D leaves like `quit`
+            let input = match editor.readline(&prompt) {
+                Ok(input) => input,
+                Err(ReadlineError::Interrupted) => continue,
+                Err(ReadlineError::Eof) => {
+                    self.show_cost();
+                    println!("👋 Goodbye!");
+                    break;
+                }
+                Err(e) => return Err(e).context("Failed to read input"),
+            };
             let query = input.trim();
 
             // Handle special commands
@@ -302,52 +1279,471 @@ impl RagEngine {
             }
             
             if query.eq_ignore_ascii_case("quit") || query.eq_ignore_ascii_case("exit") {
+                self.show_cost();
                 println!("👋 Goodbye!");
                 break;
             }
 
+            // Saved as each question is asked, so an interrupted session keeps its history
+            let _ = editor.add_history_entry(query);
+            if let Some(path) = &self.history {
+                if let Err(e) = editor.append_history(path) {
+                    println!("⚠️  Couldn't save question history to {}: {}", path.display(), e);
+                    self.history = None;
+                }
+            }
+
             if query.eq_ignore_ascii_case("help") {
                 self.show_help();
                 continue;
             }
 
-            // Process the query
-            println!("{} Searching knowledge base...", style("🔍").dim());
-            
-            match self.search_similar_content(query).await {
-                Ok(context) => {
-                    if !context.is_empty() {
-                        println!("{} Found {} relevant documents", 
-                               style("📚").dim(), context.len());
-                    } else {
-                        println!("{} No relevant documents found for your query", style("💭").dim());
+            if let Some(document) = query.strip_prefix("/focus") {
+                self.focus(document.trim()).await;
+                continue;
+            }
+
+            if let Some(spec) = query.strip_prefix("/filter") {
+                self.set_filter(spec.trim());
+                continue;
+            }
+
+            if query == "/copy" {
+                self.copy_answer();
+                continue;
+            }
+
+            if let Some(file) = query.strip_prefix("/savefile") {
+                self.save_answer(file.trim());
+                continue;
+            }
+
+            if let Some(spec) = query.strip_prefix("/open") {
+                self.open_source(spec.trim()).await;
+                continue;
+            }
+
+            if query == "/cost" {
+                self.show_cost();
+                println!();
+                continue;
+            }
+
+            if query == "/good" || query == "/bad" {
+                let label = if query == "/good" { AnnotationLabel::Helpful } else { AnnotationLabel::Unhelpful };
+                self.rate_answer(label).await;
+                continue;
+            }
+
+            // Everything below sends LLM requests
+            if self.spending.reached() && !self.confirm_spending(&mut editor) {
+                continue;
+            }
+
+            if let Some(question) = query.strip_prefix("/corpus") {
+                match question.trim() {
+                    _ if self.no_llm => {
+                        println!("{} /corpus has the LLM read every document, so it isn't available with --no-llm", style("❌").red());
+                        println!();
                     }
-                    
-                    println!("{} Generating response...", style("🤔").dim());
-                    
-                    match self.generate_response(query, &context).await {
-                        Ok(response) => {
-                            println!();
-                            println!("{}", style(&response).white());
-                            println!();
+                    "" => {
+                        println!("{} Usage: /corpus <question>", style("❌").red());
+                        println!();
+                    }
+                    question => self.ask_corpus(question).await,
+                }
+                continue;
+            }
+
+            // A number picks one of the follow-up questions suggested after the last answer
+            let picked = query.parse::<usize>().ok()
+                .and_then(|n| n.checked_sub(1))
+                .and_then(|i| self.suggestions.get(i))
+                .cloned();
+            match picked {
+                Some(question) => {
+                    println!("{} {}", style("❯").dim(), question);
+                    self.ask(&question).await;
+                }
+                None => self.ask(query).await,
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Print the tokens and estimated dollars spent this session
+    fn show_cost(&self) {
+        let usage = self.spending.usage();
+        if usage.total_tokens == 0 {
+            return;
+        }
+        let spent = match self.spending.spent() {
+            Some(spent) => format!(", about ${:.4}", spent),
+            None => String::new(),
+        };
+        println!("{} Session: {} prompt and {} completion tokens{}", style("💰").dim(), usage.prompt_tokens, usage.completion_tokens, spent);
+    }
+
+    /// Ask whether to keep going once the session has spent `--max-session-cost`, allowing
+    /// another as much on yes
+    fn confirm_spending(&mut self, editor: &mut QuestionEditor) -> bool {
+        println!("{} This session has spent about ${:.4}, over the ${:.2} cap set with --max-session-cost",
+                 style("⚠️").yellow(), self.spending.spent().unwrap_or_default(), self.spending.limit().unwrap_or_default());
+        let answer = editor.readline("Continue sending requests? [y/N] ").unwrap_or_default();
+        if answer.trim().eq_ignore_ascii_case("y") || answer.trim().eq_ignore_ascii_case("yes") {
+            self.spending.confirm();
+            true
+        } else {
+            println!("{} Not sent; /cost shows the session's spending", style("🛑").dim());
+            println!();
+            false
+        }
+    }
+
+    /// Restrict retrieval to one document, or search everything again when `document` is empty
+    async fn focus(&mut self, document: &str) {
+        let document = (!document.is_empty()).then_some(document);
+        match self.brains.focus(document).await {
+            Ok(Some((brain, document))) => {
+                let location = if self.brains.len() > 1 { format!(" in {}", brain) } else { String::new() };
+                println!("{} Focused on {}{} ({} fragments); /focus alone to search everything again",
+                         style("📄").dim(), document.file_path, location, document.fragments);
+            }
+            Ok(None) => println!("{} Searching every document again", style("📚").dim()),
+            Err(e) => println!("{} {}", style("❌").red(), e),
+        }
+        println!();
+    }
+
+    /// Restrict retrieval to documents matching `spec`'s metadata filters, or search every
+    /// document again when `spec` is empty
+    fn set_filter(&mut self, spec: &str) {
+        match SearchFilter::parse_metadata(spec) {
+            Ok((_, rest)) if !rest.is_empty() => {
+                println!("{} Unknown filter '{}'; use collection:, tag:, type:, path:, since: or until:", style("❌").red(), rest);
+            }
+            Ok((filter, _)) => {
+                self.filter = filter;
+                let active = self.filter.describe();
+                if active.is_empty() {
+                    println!("{} Searching every document again", style("📚").dim());
+                } else {
+                    println!("{} Filtering by {}; /filter alone to search everything again", style("🔎").dim(), active.join(", "));
+                }
+            }
+            Err(e) => println!("{} {}", style("❌").red(), e),
+        }
+        println!();
+    }
+
+    /// Copy the last answer's text to the system clipboard
+    fn copy_answer(&mut self) {
+        let Some(last) = &self.last_answer else {
+            println!("{} No answer to copy yet", style("💭").dim());
+            println!();
+            return;
+        };
+        let copied = match &mut self.clipboard {
+            Some(clipboard) => clipboard.set_text(last.answer.as_str()),
+            None => arboard::Clipboard::new().and_then(|mut clipboard| {
+                clipboard.set_text(last.answer.as_str())?;
+                self.clipboard = Some(clipboard);
+                Ok(())
+            }),
+        };
+        match copied {
+            Ok(()) => println!("{} Copied the answer to the clipboard", style("📋").dim()),
+            Err(e) => println!("{} Couldn't copy to the clipboard: {}", style("❌").red(), e),
+        }
+        println!();
+    }
+
+    /// Write the last question, answer and sources to a Markdown file, appending when
+    /// the file already exists so answers can be collected in one notes file
+    fn save_answer(&self, file: &str) {
+        let Some(last) = &self.last_answer else {
+            println!("{} No answer to save yet", style("💭").dim());
+            println!();
+            return;
+        };
+        if file.is_empty() {
+            println!("{} Usage: /savefile <file.md>", style("❌").red());
+            println!();
+            return;
+        }
+
+        let path = Path::new(file);
+        let existed = path.exists();
+        let mut markdown = render_markdown(&last.question, &last.answer, &last.hits, self.brains.len() > 1);
+        if existed {
+            markdown.insert_str(0, "\n---\n\n");
+        }
+        let written = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .and_then(|mut out| out.write_all(markdown.as_bytes()));
+        match written {
+            Ok(()) if existed => println!("{} Appended the answer to {}", style("💾").dim(), path.display()),
+            Ok(()) => println!("{} Saved the answer to {}", style("💾").dim(), path.display()),
+            Err(e) => println!("{} Couldn't write {}: {}", style("❌").red(), path.display(), e),
+        }
+        println!();
+    }
+
+    /// Record a thumbs up or down on the passages behind the last answer, which later
+    /// searches rank higher or lower
+    async fn rate_answer(&mut self, label: AnnotationLabel) {
+        let Some(last) = &self.last_answer else {
+            println!("{} No answer to rate yet", style("💭").dim());
+            println!();
+            return;
+        };
+        if last.hits.is_empty() {
+            println!("{} The last answer wasn't drawn from retrieved passages", style("💭").dim());
+            println!();
+            return;
+        }
+        let author = std::env::var("USER").unwrap_or_else(|_| "unknown".to_string());
+        match self.brains.record_feedback(&last.hits, label, &author).await {
+            Ok(count) => println!("{} Marked {} passages {}", style(if label == AnnotationLabel::Helpful { "👍" } else { "👎" }).dim(), count, label.name()),
+            Err(e) => println!("{} {}", style("❌").red(), e),
+        }
+        println!();
+    }
+
+    /// Write the original of the last answer's source `spec` names by number, into the
+    /// directory after the number or the current one
+    async fn open_source(&mut self, spec: &str) {
+        let (number, dir) = spec.split_once(char::is_whitespace).unwrap_or((spec, "."));
+        let hit = number.parse::<usize>().ok()
+            .and_then(|n| n.checked_sub(1))
+            .and_then(|i| self.last_answer.as_ref()?.hits.get(i))
+            .cloned();
+        let Some(hit) = hit else {
+            println!("{} Usage: /open <n> [directory], where n is one of the last answer's sources", style("❌").red());
+            println!();
+            return;
+        };
+        match self.brains.extract_source(&hit, Path::new(dir.trim())).await {
+            Ok(path) => println!("{} Saved {} to {}", style("📄").dim(), hit.source.file_path, path.display()),
+            Err(e) => println!("{} {}", style("❌").red(), e),
+        }
+        println!();
+    }
+
+    /// Answer one query from the whole corpus, printing everything to the terminal
+    async fn ask_corpus(&mut self, query: &str) {
+        let progress = |documents, requests| {
+            println!("{} Reading {} documents in {} requests...", style("📖").dim(), documents, requests);
+        };
+        match self.answer_corpus(query, progress).await {
+            Ok(answer) => {
+                self.spending.record(answer.token_usage);
+                println!();
+                println!("{}", style(&answer.answer).white());
+                println!();
+                if !answer.sources.is_empty() {
+                    println!("{}", style("Sources:").dim());
+                    for source in &answer.sources {
+                        println!("{}", style(format!("  • {}", source)).dim());
+                    }
+                    println!();
+                }
+                self.last_answer = answer.withheld.is_none().then(|| LastAnswer {
+                    question: query.to_string(),
+                    answer: answer.answer,
+                    hits: Vec::new(),
+                });
+                self.suggestions.clear();
+            }
+            Err(e) => {
+                println!("{} Corpus Error: {}", style("❌").red(), e);
+                if self.verbose {
+                    println!("   Debug: {:?}", e);
+                }
+            }
+        }
+    }
+
+    /// Retrieve, answer and optionally verify one query, printing everything to the terminal
+    async fn ask(&mut self, query: &str) {
+        if self.whole_corpus {
+            self.ask_corpus(query).await;
+            return;
+        }
+        println!("{} Searching knowledge base...", style("🔍").dim());
+        
+        match self.search_similar_content(query).await {
+            Ok(hits) => {
+                let context = self.passages(&hits);
+                if !context.is_empty() {
+                    println!("{} Found {} relevant documents", 
+                           style("📚").dim(), context.len());
+                } else {
+                    println!("{} No relevant documents found for your query", style("💭").dim());
+                }
+                
+                println!("{} Generating response...", style("🤔").dim());
+                
+                match self.answer(query, &hits, &context).await {
+                    Ok((messages, reply)) => {
+                        self.spending.record(reply.usage);
+                        let filtered = match self.filter_answer(query, &reply.content) {
+                            Ok(filtered) => filtered,
+                            Err(e) => {
+                                println!("{} Output Filter Error: {:#}", style("❌").red(), e);
+                                return;
+                            }
+                        };
+                        // An answer that can't be recorded isn't shown either
+                        if let Err(e) = self.record_answer(query, &messages, &hits, &filtered, self.answer_mode()) {
+                            println!("{} Audit Error: {:#}", style("❌").red(), e);
+                            return;
+                        }
+                        let response = filtered.text;
+                        println!();
+                        println!("{}", style(&response).white());
+                        println!();
+                        // Nothing of a withheld answer is kept, nor checked, nor built upon
+                        if filtered.withheld.is_some() {
+                            self.last_answer = None;
+                            self.suggestions.clear();
+                            return;
+                        }
+                        if !hits.is_empty() {
+                            println!("{}", style(render_sources(&response, &hits, self.brains.len() > 1)).dim());
                         }
-                        Err(e) => {
-                            println!("{} LLM Error: {}", style("❌").red(), e);
-                            if self.verbose {
-                                println!("   Debug: {:?}", e);
+                        // Extracted sentences are the passages' own words
+                        if !hits.is_empty() && !self.no_llm {
+                            let grounding = verification::ground_answer(&response, &context, self.grounding_threshold);
+                            if grounding.below_threshold() {
+                                println!("{}", style(&grounding).yellow());
+                            } else {
+                                println!("{}", style(&grounding).dim());
+                            }
+                        }
+                        self.last_answer = Some(LastAnswer {
+                            question: query.to_string(),
+                            answer: response.clone(),
+                            hits: hits.clone(),
+                        });
+                        
+                        if self.verify {
+                            println!("{} Verifying citations...", style("🔎").dim());
+                            match verification::verify_answer(&self.llm, &response, &context).await {
+                                Ok(report) => {
+                                    self.spending.record(report.token_usage);
+                                    print!("{}", report);
+                                    if report.flagged() > 0 {
+                                        println!("{}", style("   Treat the flagged statements with caution.").yellow());
+                                    }
+                                    println!();
+                                }
+                                Err(e) => println!("{} Verification Error: {}", style("❌").red(), e),
+                            }
+                        }
+                        
+                        self.suggestions.clear();
+                        if self.suggest && !context.is_empty() {
+                            let suggested = followups::suggest_follow_ups(&self.llm, query, &response, &context).await;
+                            if let Ok(follow_ups) = &suggested {
+                                self.spending.record(follow_ups.token_usage);
+                            }
+                            match suggested {
+                                Ok(follow_ups) if !follow_ups.questions.is_empty() => {
+                                    println!("{}", style("💡 Follow-up questions (type a number to ask):").dim());
+                                    for (i, question) in follow_ups.questions.iter().enumerate() {
+                                        println!("   {}. {}", i + 1, question);
+                                    }
+                                    println!();
+                                    self.suggestions = follow_ups.questions;
+                                }
+                                Ok(_) => {}
+                                Err(e) => println!("{} Suggestion Error: {}", style("❌").red(), e),
                             }
                         }
                     }
+                    Err(e) => {
+                        let label = if self.no_llm { "Extraction Error" } else { "LLM Error" };
+                        println!("{} {}: {}", style("❌").red(), label, e);
+                        if self.verbose {
+                            println!("   Debug: {:?}", e);
+                        }
+                    }
+                }
+            }
+            Err(e) => {
+                println!("{} Search Error: {}", style("❌").red(), e);
+                if self.verbose {
+                    println!("   Debug: {:?}", e);
                 }
+            }
+        }
+    }
+
+    /// Retrieve the passages of the recorded turns `turn` names again, with the corpus and
+    /// settings as they are now, and show what changed: as text, or for `--output json` as
+    /// one array of the turns and their differences. Turns are replayed with the filters and
+    /// focus they were asked with.
+    async fn replay(&mut self, turn: &str, output: OutputFormat) -> Result<()> {
+        let traces = self.traces.as_ref().context("--replay needs the traces --no-trace turns off")?;
+        let path = traces.path().to_path_buf();
+        if !path.exists() {
+            anyhow::bail!("No retrieval traces have been recorded in {}", path.display());
+        }
+        let records = traces.select(turn)?;
+
+        let mut replays = Vec::new();
+        let mut unchanged = 0;
+        for record in &records {
+            self.filter = record.trace.filter.search_filter();
+            let replayed = match self.brains.focus(record.trace.filter.focus.as_deref()).await {
+                Ok(_) => self.retrieve(&record.trace.question).await,
+                Err(e) => Err(e),
+            };
+            let date = record.recorded_at.get(..10).unwrap_or(&record.recorded_at);
+            let (token_usage, trace) = match replayed.and_then(|(_, _, token_usage)| {
+                Ok((token_usage, self.last_trace.take().context("Nothing was retrieved")?))
+            }) {
+                Ok(replayed) => replayed,
                 Err(e) => {
-                    println!("{} Search Error: {}", style("❌").red(), e);
-                    if self.verbose {
-                        println!("   Debug: {:?}", e);
+                    if output == OutputFormat::Text {
+                        println!("{} Turn {} ({}): {}", style("❌").red(), record.sequence, date, e);
                     }
+                    replays.push(serde_json::json!({"sequence": record.sequence, "id": record.id, "question": record.trace.question, "error": format!("{:#}", e)}));
+                    continue;
                 }
+            };
+            self.spending.record(token_usage);
+
+            let diff = trace::diff(&record.trace, &trace);
+            if diff.unchanged() {
+                unchanged += 1;
             }
+            if output == OutputFormat::Text {
+                println!("{} Turn {} ({}): {}", style("🔁").dim(), record.sequence, date, style(&record.trace.question).bold());
+                if diff.unchanged() {
+                    println!("   ✅ Same passages in the same order");
+                } else {
+                    print!("{}", diff);
+                }
+                println!();
+            }
+            replays.push(serde_json::json!({
+                "sequence": record.sequence,
+                "id": record.id,
+                "question": record.trace.question,
+                "unchanged": diff.unchanged(),
+                "diff": diff,
+            }));
         }
 
+        match output {
+            OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&replays)?),
+            OutputFormat::Text => println!("📊 {} of {} turns retrieve the same passages as when they were asked", unchanged, records.len()),
+        }
         Ok(())
     }
 
@@ -356,6 +1752,18 @@ impl RagEngine {
         println!("{}", style("Available commands:").bold());
         println!("  help  - Show this help message");
         println!("  quit  - Exit the program");
+        println!("  /focus <document> - Only answer from one document, by id, file name or path");
+        println!("  /focus - Search every document again");
+        println!("  /filter collection:<name> tag:<tag> type:<ext> path:<glob> since:<date> until:<date> - Only answer from matching documents");
+        println!("  /filter - Clear the filter");
+        println!("  1, 2, 3 - Ask a follow-up question suggested after the last answer (with --suggest)");
+        println!("  /copy - Copy the last answer to the clipboard");
+        println!("  /savefile <file.md> - Save the last answer and its sources to a Markdown file");
+        println!("  /corpus <question> - Answer from every document rather than the top passages");
+        println!("  /good, /bad - Rate the last answer; its passages rank higher or lower in later searches");
+        println!("  /open <n> [directory] - Save the original document of the last answer's source [n]");
+        println!("  /cost - Show the tokens and estimated dollars spent this session");
+        println!("  ↑/↓, Ctrl-R - Recall or search previous questions");
         println!("  Any other text will be treated as a query");
         println!();
     }
@@ -363,7 +1771,9 @@ impl RagEngine {
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let args = Args::parse();
+    // Profile settings are filled in as flags, so those on the command line win
+    let (args, profile) = profiles::apply(std::env::args_os().collect(), &Args::command())?;
+    let args = Args::parse_from(args);
 
     // Initialize logging
     let log_level = if args.verbose { "debug" } else { "info" };
@@ -375,6 +1785,28 @@ async fn main() -> Result<()> {
         .format_timestamp(None)
         .init();
 
+    if let Some(output) = &args.export_audit {
+        let path = args.audit_log.as_deref().context("--export-audit requires --audit-log")?;
+        if !path.exists() {
+            anyhow::bail!("Audit log does not exist: {}", path.display());
+        }
+        let log = AuditLog::new(path);
+        let file = std::fs::File::create(output)
+            .with_context(|| format!("Failed to create {}", output.display()))?;
+        let count = log.export(&mut io::BufWriter::new(file))?;
+        println!("✅ Exported {} answers from {} to {}", count, path.display(), output.display());
+        return Ok(());
+    }
+
+    if offline::configure(args.strict_offline) {
+        log::info!("🔒 Strict offline mode: network access is disabled");
+    }
+    remote_storage::configure(args.remote_api_key.clone());
+    model_downloads::configure(args.model_mirror.clone(), args.model_checksums.clone())?;
+    if let Some(profile) = profile {
+        log::info!("🧩 Using profile {}", profile);
+    }
+
     // Validate arguments
     if args.results == 0 {
         anyhow::bail!("Results count must be at least 1");
@@ -384,20 +1816,57 @@ async fn main() -> Result<()> {
         anyhow::bail!("Results count cannot exceed 20");
     }
 
+    if args.output == OutputFormat::Json && args.question.is_none() && args.replay.is_none() {
+        anyhow::bail!("--output json requires --question or --replay");
+    }
+
+    let output = args.output;
+    let question = args.question.clone();
+    let replay = args.replay.clone();
+    let quiet = output == OutputFormat::Json;
+
     // Initialize RAG engine
-    println!("🚀 Initializing EatMyBrain RAG engine...");
-    println!("📊 Database: {}", args.database.display());
+    if !quiet {
+        println!("🚀 Initializing EatMyBrain RAG engine...");
+        for database in &args.database {
+            println!("📊 Database: {}", database.display());
+        }
+    }
 
     let mut rag_engine = RagEngine::new(args).await
         .context("Failed to initialize RAG engine")?;
 
-    println!("🌐 LLM Endpoint: {}", rag_engine.endpoint);
-    println!("🤖 Model: {}", rag_engine.model);
-    println!("✅ Ready!");
-    println!();
+    if !quiet && rag_engine.no_llm {
+        println!("📑 Answering with the retrieved passages' closest sentences, without an LLM");
+        println!("✅ Ready!");
+        println!();
+    } else if !quiet {
+        println!("🌐 LLM Endpoint: {}", rag_engine.llm.endpoint);
+        println!("🤖 Model: {}", rag_engine.llm.model);
+        println!("✅ Ready!");
+        println!();
+    }
 
-    // Start the chat loop
-    rag_engine.chat_loop().await?;
+    if let Some(turn) = replay {
+        return rag_engine.replay(&turn, output).await;
+    }
+
+    match question {
+        // One-shot question
+        Some(question) => match output {
+            OutputFormat::Json if rag_engine.whole_corpus => {
+                let answer = rag_engine.answer_corpus(&question, |_, _| {}).await?;
+                println!("{}", serde_json::to_string_pretty(&answer)?);
+            }
+            OutputFormat::Json => {
+                let answer = rag_engine.answer_structured(&question).await?;
+                println!("{}", serde_json::to_string_pretty(&answer)?);
+            }
+            OutputFormat::Text => rag_engine.ask(&question).await,
+        },
+        // Start the chat loop
+        None => rag_engine.chat_loop().await?,
+    }
 
     Ok(())
 }
\ No newline at end of file
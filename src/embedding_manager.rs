@@ -1,6 +1,14 @@
 use anyhow::{Context, Result};
-use fastembed::{EmbeddingModel, TextEmbedding, InitOptions};
-use log::{info, debug};
+use fastembed::{EmbeddingModel, TextEmbedding, InitOptions, OutputKey};
+use log::{info, debug, warn};
+use std::hash::{BuildHasher, Hasher};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tokenizers::Tokenizer;
+
+use crate::error::{self, PortableBrainsError};
+use crate::model_downloads;
+use crate::offline;
 
 #[derive(serde::Serialize)]
 struct OpenAIEmbeddingRequest {
@@ -11,6 +19,9 @@ struct OpenAIEmbeddingRequest {
 #[derive(serde::Deserialize)]
 struct OpenAIEmbeddingData {
     embedding: Vec<f64>,
+    /// Position of the input this vector belongs to; not every compatible server sends it
+    #[serde(default)]
+    index: Option<usize>,
 }
 
 #[derive(serde::Deserialize)]
@@ -18,40 +29,220 @@ struct OpenAIEmbeddingResponse {
     data: Vec<OpenAIEmbeddingData>,
 }
 
+/// Body of Ollama's `/api/embeddings`, which embeds one prompt per request
+#[derive(serde::Serialize)]
+struct OllamaEmbeddingRequest<'a> {
+    model: &'a str,
+    prompt: &'a str,
+}
+
+#[derive(serde::Deserialize)]
+struct OllamaEmbeddingResponse {
+    embedding: Vec<f64>,
+}
+
+/// Body of Ollama's batched `/api/embed`
+#[derive(serde::Serialize)]
+struct OllamaEmbedRequest<'a> {
+    model: &'a str,
+    input: &'a [&'a str],
+}
+
+#[derive(serde::Deserialize)]
+struct OllamaEmbedResponse {
+    embeddings: Vec<Vec<f64>>,
+}
+
+/// Where a local Ollama server answers embedding requests
+pub const OLLAMA_ENDPOINT: &str = "http://localhost:11434/api/embeddings";
+
 pub enum EmbeddingProvider {
-    Local(TextEmbedding),
+    Local(Box<TextEmbedding>),
+    /// OpenAI's `/v1/embeddings` or a server speaking the same API; self-hosted servers
+    /// often need no key
     Remote {
         client: reqwest::Client,
-        api_key: String,
+        api_key: Option<String>,
         model: String,
         endpoint: String,
     },
+    /// An Ollama server, through `/api/embeddings` or the batched `/api/embed`
+    Ollama {
+        client: reqwest::Client,
+        model: String,
+        endpoint: String,
+    },
+    /// Feature-hashed bag of words, for tests that must not download a model
+    Hashing { dimension: usize },
+}
+
+/// Model name prefix of the hashing embedder, followed by its dimension
+pub const HASHING_MODEL_PREFIX: &str = "portable-brains/hashing-";
+
+/// Text some models expect before each input, such as E5's `passage: ` and `query: `.
+/// Embedding documents and queries without them loses accuracy silently.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EmbeddingPrefixes {
+    /// Prepended to each fragment before it is embedded
+    pub document: String,
+    /// Prepended to each search query
+    pub query: String,
+}
+
+impl EmbeddingPrefixes {
+    /// The prefixes a model's authors ask for; none for models trained without them
+    pub fn for_model(model_name: &str) -> Self {
+        let name = model_name.to_lowercase();
+        let (document, query) = if name.starts_with("intfloat/") && name.contains("e5") {
+            ("passage: ", "query: ")
+        } else if name.starts_with("nomic-ai/nomic-embed-text") || name.starts_with("nomic-embed-text") {
+            ("search_document: ", "search_query: ")
+        } else {
+            ("", "")
+        };
+        Self { document: document.to_string(), query: query.to_string() }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.document.is_empty() && self.query.is_empty()
+    }
+
+    pub fn document(&self, text: &str) -> String {
+        format!("{}{}", self.document, text)
+    }
+
+    pub fn query(&self, text: &str) -> String {
+        format!("{}{}", self.query, text)
+    }
+}
+
+/// An embedding vector together with the model that produced it
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct EmbeddedText {
+    pub model: String,
+    pub dimension: usize,
+    pub embedding: Vec<f64>,
+}
+
+/// Why a request to a remote embedding server failed, which decides what happens next
+#[derive(Debug)]
+pub enum RemoteError {
+    /// Rate limited, timed out, unreachable or a server error; the same request may succeed later
+    Transient { message: String, retry_after: Option<Duration> },
+    /// The server refused this payload, typically as too large; smaller batches may pass
+    Rejected(String),
+    /// Retrying cannot help, e.g. a bad key or unknown model
+    Fatal(String),
+}
+
+impl RemoteError {
+    fn from_status(status: reqwest::StatusCode, message: String, retry_after: Option<Duration>) -> Self {
+        match status.as_u16() {
+            408 | 425 | 429 | 500..=599 => RemoteError::Transient { message, retry_after },
+            400 | 413 | 422 => RemoteError::Rejected(message),
+            _ => RemoteError::Fatal(message),
+        }
+    }
+}
+
+impl std::fmt::Display for RemoteError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RemoteError::Transient { message, .. } | RemoteError::Rejected(message) | RemoteError::Fatal(message) => {
+                write!(f, "{}", message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for RemoteError {}
+
+/// Type an embedding failure so callers can decide whether to go on: a server still busy
+/// after its retries may recover, a text it refused affects that text alone, and anything
+/// else (a bad key, a local model that won't run) would fail every later text too
+fn embedding_error(error: anyhow::Error) -> anyhow::Error {
+    let kind: fn(String) -> PortableBrainsError = match error.chain().find_map(|cause| cause.downcast_ref::<RemoteError>()) {
+        Some(RemoteError::Transient { .. }) => PortableBrainsError::EmbeddingUnavailable,
+        Some(RemoteError::Rejected(_)) => PortableBrainsError::ValidationError,
+        _ => PortableBrainsError::EmbeddingError,
+    };
+    error::typed(error, kind)
+}
+
+/// How often a transient remote failure is retried, waiting twice as long each time
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 5,
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(60),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Wait before retry number `attempt` (from 0): half the capped exponential delay, plus
+    /// `jitter` (0 to 1) of the other half, so clients that failed together retry apart
+    fn delay(&self, attempt: u32, jitter: f64) -> Duration {
+        let exponential = self.base_delay.saturating_mul(2u32.saturating_pow(attempt)).min(self.max_delay);
+        exponential / 2 + (exponential / 2).mul_f64(jitter.clamp(0.0, 1.0))
+    }
+}
+
+/// Spaces requests evenly to stay under a requests-per-minute quota
+#[derive(Debug)]
+struct RateLimiter {
+    interval: Duration,
+    next: Instant,
+}
+
+impl RateLimiter {
+    fn per_minute(requests: u32) -> Self {
+        Self {
+            interval: Duration::from_secs(60) / requests.max(1),
+            next: Instant::now(),
+        }
+    }
+
+    async fn wait(&mut self) {
+        let now = Instant::now();
+        if self.next > now {
+            tokio::time::sleep(self.next - now).await;
+        }
+        self.next = self.next.max(now) + self.interval;
+    }
 }
 
 /// Embedding manager supporting both local FastEmbed and remote API models
 pub struct EmbeddingManager {
     provider: EmbeddingProvider,
     model_name: String,
+    /// Length every generated vector must have, once known
+    expected_dimension: Option<usize>,
+    /// The local model's tokenizer, loaded on first use for late chunking
+    tokenizer: Option<Tokenizer>,
+    /// Retries of failed remote requests
+    retry: RetryPolicy,
+    /// Limit on remote requests per minute, when the provider imposes one
+    limiter: Option<RateLimiter>,
 }
 
 impl EmbeddingManager {
     pub async fn new(model_name: &str) -> Result<Self> {
+        if let Some(dimension) = model_name.strip_prefix(HASHING_MODEL_PREFIX).and_then(|n| n.parse().ok()).filter(|&n: &usize| n > 0) {
+            return Ok(Self::hashing(dimension));
+        }
+        
         info!("Initializing FastEmbed model: {}", model_name);
         
-        // Map model names to FastEmbed EmbeddingModel variants
-        let embedding_model = match model_name {
-            "BAAI/bge-small-en-v1.5" => EmbeddingModel::BGESmallENV15,
-            "BAAI/bge-base-en-v1.5" => EmbeddingModel::BGEBaseENV15,
-            "BAAI/bge-large-en-v1.5" => EmbeddingModel::BGELargeENV15,
-            "sentence-transformers/all-MiniLM-L6-v2" => EmbeddingModel::AllMiniLML6V2,
-            "sentence-transformers/all-MiniLM-L12-v2" => EmbeddingModel::AllMiniLML12V2,
-            "intfloat/multilingual-e5-large" => EmbeddingModel::MultilingualE5Large,
-            "intfloat/e5-large-v2" => EmbeddingModel::BGELargeENV15, // Fallback to similar model
-            _ => {
-                info!("Model '{}' not directly supported, using BGE-small-en-v1.5 as fallback", model_name);
-                EmbeddingModel::BGESmallENV15
-            }
-        };
+        let embedding_model = fastembed_model(model_name);
         
         info!("Loading model with download progress and Metal acceleration...");
         
@@ -75,6 +266,11 @@ impl EmbeddingManager {
             info!("ℹ️  Non-macOS platform detected, using default CPU execution");
         }
         
+        let info = TextEmbedding::get_model_info(&embedding_model)?;
+        if offline::is_enabled() && cached_model_file(&info.model_code, &info.model_file).is_none() {
+            return Err(offline::refused(&format!("Downloading embedding model {}", info.model_code)));
+        }
+        model_downloads::verify_model(model_name, &info.model_code, &info.model_file, &info.additional_files)?;
         let model = TextEmbedding::try_new(
             InitOptions::new(embedding_model).with_show_download_progress(true)
         ).context("Failed to initialize FastEmbed model")?;
@@ -82,16 +278,27 @@ impl EmbeddingManager {
         info!("FastEmbed model loaded successfully: {}", model_name);
         
         Ok(Self {
-            provider: EmbeddingProvider::Local(model),
+            provider: EmbeddingProvider::Local(Box::new(model)),
             model_name: model_name.to_string(),
+            expected_dimension: None,
+            tokenizer: None,
+            retry: RetryPolicy::default(),
+            limiter: None,
         })
     }
     
     pub async fn new_remote(api_key: String, model_name: &str, endpoint: Option<String>) -> Result<Self> {
         let endpoint = endpoint.unwrap_or_else(|| "https://api.openai.com/v1/embeddings".to_string());
+        Self::new_compatible(model_name, endpoint, Some(api_key)).await
+    }
+    
+    /// Embed with any server implementing OpenAI's `/v1/embeddings`, such as llama.cpp,
+    /// LM Studio, vLLM or text-embeddings-inference; the key is sent only when given
+    pub async fn new_compatible(model_name: &str, endpoint: String, api_key: Option<String>) -> Result<Self> {
         info!("Initializing remote embedding model: {} at {}", model_name, endpoint);
+        offline::check(&endpoint, "Remote embedding API")?;
         
-        let client = reqwest::Client::new();
+        let client = offline::client();
         
         Ok(Self {
             provider: EmbeddingProvider::Remote {
@@ -101,12 +308,67 @@ impl EmbeddingManager {
                 endpoint,
             },
             model_name: model_name.to_string(),
+            expected_dimension: None,
+            tokenizer: None,
+            retry: RetryPolicy::default(),
+            limiter: None,
+        })
+    }
+    
+    /// Embed with a model served by Ollama, at `http://localhost:11434/api/embeddings`
+    /// unless another endpoint is given. No key is needed.
+    pub async fn new_ollama(model_name: &str, endpoint: Option<String>) -> Result<Self> {
+        let endpoint = endpoint.unwrap_or_else(|| OLLAMA_ENDPOINT.to_string());
+        info!("Initializing Ollama embedding model: {} at {}", model_name, endpoint);
+        offline::check(&endpoint, "Ollama")?;
+        
+        Ok(Self {
+            provider: EmbeddingProvider::Ollama {
+                client: offline::client(),
+                model: model_name.to_string(),
+                endpoint,
+            },
+            model_name: model_name.to_string(),
+            expected_dimension: None,
+            tokenizer: None,
+            retry: RetryPolicy::default(),
+            limiter: None,
         })
     }
     
+    /// A deterministic embedder that hashes each word into one of `dimension` buckets.
+    /// Texts sharing words score as similar, and no model is downloaded, so retrieval tests
+    /// give the same rankings on every machine.
+    pub fn hashing(dimension: usize) -> Self {
+        Self {
+            provider: EmbeddingProvider::Hashing { dimension },
+            model_name: format!("{}{}", HASHING_MODEL_PREFIX, dimension),
+            expected_dimension: None,
+            tokenizer: None,
+            retry: RetryPolicy::default(),
+            limiter: None,
+        }
+    }
+    
+    /// Send remote requests no faster than `requests` a minute
+    pub fn with_requests_per_minute(mut self, requests: u32) -> Self {
+        self.limiter = Some(RateLimiter::per_minute(requests));
+        self
+    }
+    
+    /// Retry transient remote failures up to `max_retries` times before giving up
+    pub fn with_max_retries(mut self, max_retries: u32) -> Self {
+        self.retry.max_retries = max_retries;
+        self
+    }
+    
     pub async fn generate_embedding(&mut self, text: &str) -> Result<Vec<f64>> {
+        self.embed_one(text).await.map_err(embedding_error)
+    }
+    
+    async fn embed_one(&mut self, text: &str) -> Result<Vec<f64>> {
         if text.trim().is_empty() {
-            anyhow::bail!("Cannot generate embedding for empty text");
+            return Err(PortableBrainsError::ValidationError("Cannot generate embedding for empty text".to_string()).into());
         }
         
         debug!("Generating embedding for text of length: {}", text.len());
@@ -130,39 +392,32 @@ impl EmbeddingManager {
                     model: model.clone(),
                 };
                 
-                let response = client
-                    .post(endpoint.as_str())
-                    .header("Authorization", format!("Bearer {}", api_key))
-                    .header("Content-Type", "application/json")
-                    .json(&request)
-                    .send()
-                    .await
+                let openai_response = post_remote(client, endpoint, api_key.as_deref(), &request, &self.retry, &mut self.limiter).await
                     .context("Failed to send request to remote embedding API")?;
                 
-                if !response.status().is_success() {
-                    let error_text = response.text().await.unwrap_or_default();
-                    anyhow::bail!("Remote embedding API error: {}", error_text);
-                }
-                
-                let openai_response: OpenAIEmbeddingResponse = response
-                    .json()
-                    .await
-                    .context("Failed to parse remote embedding API response")?;
-                
                 if openai_response.data.is_empty() {
                     anyhow::bail!("No embeddings returned by remote API");
                 }
                 
-                openai_response.data[0].embedding.clone()
+                remote_vectors(openai_response, 1)?.remove(0)
             }
+            EmbeddingProvider::Ollama { client, model, endpoint } => {
+                ollama_vectors(client, endpoint, model, &[text], &self.retry, &mut self.limiter).await?.remove(0)
+            }
+            EmbeddingProvider::Hashing { dimension } => hashed_vector(text, *dimension),
         };
         
+        check_vectors(&self.model_name, std::slice::from_ref(&embedding), self.expected_dimension)?;
         debug!("Generated embedding with dimension: {}", embedding.len());
         Ok(embedding)
     }
 
     /// Generate embeddings for multiple texts in a single batch for improved performance
     pub async fn generate_embeddings_batch(&mut self, texts: &[String]) -> Result<Vec<Vec<f64>>> {
+        self.embed_batch(texts).await.map_err(embedding_error)
+    }
+    
+    async fn embed_batch(&mut self, texts: &[String]) -> Result<Vec<Vec<f64>>> {
         if texts.is_empty() {
             return Ok(Vec::new());
         }
@@ -181,7 +436,7 @@ impl EmbeddingManager {
         let valid_count = valid_texts.len();
         
         if valid_count == 0 {
-            anyhow::bail!("Cannot generate embeddings for empty texts");
+            return Err(PortableBrainsError::ValidationError("Cannot generate embeddings for empty texts".to_string()).into());
         }
         
         debug!("Generating embeddings for batch of {} texts", valid_count);
@@ -213,25 +468,9 @@ impl EmbeddingManager {
                     model: model.clone(),
                 };
                 
-                let response = client
-                    .post(endpoint.as_str())
-                    .header("Authorization", format!("Bearer {}", api_key))
-                    .header("Content-Type", "application/json")
-                    .json(&request)
-                    .send()
-                    .await
+                let openai_response = post_remote(client, endpoint, api_key.as_deref(), &request, &self.retry, &mut self.limiter).await
                     .context("Failed to send batch request to remote embedding API")?;
                 
-                if !response.status().is_success() {
-                    let error_text = response.text().await.unwrap_or_default();
-                    anyhow::bail!("Remote embedding API error: {}", error_text);
-                }
-                
-                let openai_response: OpenAIEmbeddingResponse = response
-                    .json()
-                    .await
-                    .context("Failed to parse remote embedding API response")?;
-                
                 if openai_response.data.len() != valid_count {
                     anyhow::bail!(
                         "Embedding count mismatch: expected {}, got {}", 
@@ -240,12 +479,18 @@ impl EmbeddingManager {
                     );
                 }
                 
-                openai_response.data.into_iter()
-                    .map(|data| data.embedding)
-                    .collect()
+                remote_vectors(openai_response, valid_count)?
             }
+            EmbeddingProvider::Ollama { client, model, endpoint } => {
+                ollama_vectors(client, endpoint, model, &valid_texts, &self.retry, &mut self.limiter).await?
+            }
+            EmbeddingProvider::Hashing { dimension } => valid_texts.iter()
+                .map(|text| hashed_vector(text, *dimension))
+                .collect(),
         };
         
+        check_vectors(&self.model_name, &embeddings, self.expected_dimension)?;
+        
         // Map embeddings back to their original positions
         let mut result = vec![Vec::new(); texts.len()];
         for (valid_idx, &embedding_idx) in valid_indices.iter().enumerate() {
@@ -259,10 +504,135 @@ impl EmbeddingManager {
         Ok(result)
     }
     
+    /// Whether [`Self::embed_late_chunks`] works with this manager: local models and the
+    /// hashing embedder expose token embeddings, remote servers return pooled vectors only
+    pub fn supports_late_chunking(&self) -> bool {
+        matches!(self.provider, EmbeddingProvider::Local(_) | EmbeddingProvider::Hashing { .. })
+    }
+    
+    /// Late chunking: embed the whole of `text` in one pass and mean-pool the token
+    /// embeddings inside each byte range of `spans`, so every chunk's vector carries context
+    /// from the sentences around it. A span holding no tokens, as past the model's input
+    /// limit, gets `None` and should be embedded on its own.
+    pub async fn embed_late_chunks(&mut self, text: &str, spans: &[(usize, usize)]) -> Result<Vec<Option<Vec<f64>>>> {
+        if text.trim().is_empty() {
+            anyhow::bail!("Cannot generate embedding for empty text");
+        }
+        
+        let tokens = match &mut self.provider {
+            EmbeddingProvider::Local(model) => {
+                if self.tokenizer.is_none() {
+                    self.tokenizer = Some(load_tokenizer(&self.model_name)?);
+                }
+                let tokenizer = self.tokenizer.as_ref().expect("the tokenizer was just loaded");
+                local_token_embeddings(model, tokenizer, text)?
+            }
+            EmbeddingProvider::Hashing { dimension } => hashed_token_embeddings(text, *dimension),
+            EmbeddingProvider::Remote { .. } | EmbeddingProvider::Ollama { .. } => anyhow::bail!(
+                "Late chunking needs token embeddings, which remote embedding APIs don't return; use a local model"
+            ),
+        };
+        
+        let vectors = pool_spans(&tokens, spans);
+        let pooled: Vec<Vec<f64>> = vectors.iter().flatten().cloned().collect();
+        check_vectors(&self.model_name, &pooled, self.expected_dimension)?;
+        debug!("Late chunked {} spans from {} tokens", pooled.len(), tokens.len());
+        Ok(vectors)
+    }
+    
+    /// Embed texts like [`Self::generate_embeddings_batch`], isolating the texts a remote
+    /// server fails on: a rejected batch is split in half until each text is tried alone, and
+    /// a batch still failing transiently after its retries fails as a whole. Failed texts get
+    /// their error instead of a vector; errors retrying cannot fix, such as a bad key, fail
+    /// the call.
+    pub async fn generate_embeddings_isolating(&mut self, texts: &[String]) -> Result<Vec<std::result::Result<Vec<f64>, String>>> {
+        let mut results = vec![Ok(Vec::new()); texts.len()];
+        // Ranges of texts still to embed, split in half whenever the server rejects one
+        let mut pending = Vec::new();
+        pending.push(0..texts.len());
+        
+        while let Some(range) = pending.pop() {
+            if texts[range.clone()].iter().all(|text| text.trim().is_empty()) {
+                continue;
+            }
+            let error = match self.embed_batch(&texts[range.clone()]).await {
+                Ok(embeddings) => {
+                    for (index, embedding) in range.zip(embeddings) {
+                        results[index] = Ok(embedding);
+                    }
+                    continue;
+                }
+                Err(error) => error,
+            };
+            
+            match error.chain().find_map(|cause| cause.downcast_ref::<RemoteError>()) {
+                Some(RemoteError::Rejected(_)) if range.len() > 1 => {
+                    let middle = range.start + range.len() / 2;
+                    debug!("Splitting rejected batch of {} texts", range.len());
+                    pending.push(middle..range.end);
+                    pending.push(range.start..middle);
+                }
+                Some(RemoteError::Rejected(_)) | Some(RemoteError::Transient { .. }) => {
+                    let message = format!("{:#}", error);
+                    for index in range {
+                        results[index] = Err(message.clone());
+                    }
+                }
+                _ => return Err(embedding_error(error)),
+            }
+        }
+        
+        Ok(results)
+    }
+    
+    /// Embed an arbitrary string with this manager's model, for debugging retrieval
+    /// or for external tools that need vectors consistent with a brain
+    pub async fn embed_text(&mut self, text: &str) -> Result<EmbeddedText> {
+        let embedding = self.generate_embedding(text).await?;
+        
+        Ok(EmbeddedText {
+            model: self.model_name.clone(),
+            dimension: embedding.len(),
+            embedding,
+        })
+    }
+    
     pub fn model_name(&self) -> &str {
         &self.model_name
     }
     
+    /// Short name of the provider, recorded in the database next to the model
+    pub fn provider_name(&self) -> &'static str {
+        match &self.provider {
+            EmbeddingProvider::Local(_) => "local",
+            EmbeddingProvider::Remote { .. } => "remote",
+            EmbeddingProvider::Ollama { .. } => "ollama",
+            EmbeddingProvider::Hashing { .. } => "hashing",
+        }
+    }
+    
+    /// Length of this manager's vectors when it is known without embedding anything, as for
+    /// local models; a remote model's is only known from its first response
+    pub fn known_dimension(&self) -> Option<usize> {
+        match &self.provider {
+            EmbeddingProvider::Local(_) => TextEmbedding::get_model_info(&fastembed_model(&self.model_name))
+                .ok()
+                .map(|info| info.dim),
+            EmbeddingProvider::Hashing { dimension } => Some(*dimension),
+            EmbeddingProvider::Remote { .. } | EmbeddingProvider::Ollama { .. } => None,
+        }
+    }
+    
+    /// Reject any later vector whose length differs from `dimension`, typically the
+    /// dimension already stored in the database
+    pub fn expect_dimension(&mut self, dimension: usize) {
+        self.expected_dimension = Some(dimension);
+    }
+    
+    pub fn expected_dimension(&self) -> Option<usize> {
+        self.expected_dimension
+    }
+    
     pub fn embedding_dimension(&self) -> usize {
         // Get dimension from the actual model
         // This is a rough estimate based on common model dimensions
@@ -273,15 +643,444 @@ impl EmbeddingManager {
             "sentence-transformers/all-MiniLM-L6-v2" => 384,
             "sentence-transformers/all-MiniLM-L12-v2" => 384,
             "intfloat/multilingual-e5-large" => 1024,
-            _ => 384, // Default fallback
+            _ => match &self.provider {
+                EmbeddingProvider::Hashing { dimension } => *dimension,
+                _ => 384, // Default fallback
+            },
+        }
+    }
+}
+
+/// The FastEmbed model for a model name, falling back to BGE-small-en-v1.5 for names it
+/// doesn't know
+fn fastembed_model(model_name: &str) -> EmbeddingModel {
+    match model_name {
+        "BAAI/bge-small-en-v1.5" => EmbeddingModel::BGESmallENV15,
+        "BAAI/bge-base-en-v1.5" => EmbeddingModel::BGEBaseENV15,
+        "BAAI/bge-large-en-v1.5" => EmbeddingModel::BGELargeENV15,
+        "sentence-transformers/all-MiniLM-L6-v2" => EmbeddingModel::AllMiniLML6V2,
+        "sentence-transformers/all-MiniLM-L12-v2" => EmbeddingModel::AllMiniLML12V2,
+        "intfloat/multilingual-e5-large" => EmbeddingModel::MultilingualE5Large,
+        "intfloat/e5-large-v2" => EmbeddingModel::BGELargeENV15, // Fallback to similar model
+        _ => {
+            info!("Model '{}' not directly supported, using BGE-small-en-v1.5 as fallback", model_name);
+            EmbeddingModel::BGESmallENV15
+        }
+    }
+}
+
+/// A file of a model repo in FastEmbed's model cache, if it has been downloaded
+pub fn cached_model_file(repo: &str, file: &str) -> Option<PathBuf> {
+    hf_hub::Cache::new(PathBuf::from(fastembed::get_cache_dir())).model(repo.to_string()).get(file)
+}
+
+/// The tokenizer of a local embedding model, from FastEmbed's model cache (downloaded on
+/// first use, except in strict offline mode), or a `tokenizer.json` file given by path
+pub fn load_tokenizer(model_name: &str) -> Result<Tokenizer> {
+    let path = Path::new(model_name);
+    let file = if path.is_file() {
+        path.to_path_buf()
+    } else {
+        if model_name.starts_with(HASHING_MODEL_PREFIX) {
+            anyhow::bail!("{} has no tokenizer", model_name);
+        }
+        let repo = TextEmbedding::get_model_info(&fastembed_model(model_name))?.model_code.clone();
+        if offline::is_enabled() {
+            return cached_model_file(&repo, "tokenizer.json")
+                .ok_or_else(|| offline::refused(&format!("Downloading the tokenizer of {}", repo)))
+                .and_then(|file| Tokenizer::from_file(&file)
+                    .map_err(|e| anyhow::anyhow!("Failed to load tokenizer {}: {}", file.display(), e)));
+        }
+        let api = hf_hub::api::sync::ApiBuilder::new()
+            .with_cache_dir(PathBuf::from(fastembed::get_cache_dir()))
+            .with_endpoint(model_downloads::endpoint())
+            .with_progress(false)
+            .build()
+            .context("Failed to set up the model download")?;
+        api.model(repo.clone()).get("tokenizer.json")
+            .with_context(|| format!("Failed to fetch the tokenizer of {}", repo))?
+    };
+    Tokenizer::from_file(&file)
+        .map_err(|e| anyhow::anyhow!("Failed to load tokenizer {}: {}", file.display(), e))
+}
+
+/// Unit vector counting the text's lowercased words by bucket. Each word's bucket and sign
+/// come from FNV-1a, which unlike the std hasher is fixed across Rust releases.
+fn hashed_vector(text: &str, dimension: usize) -> Vec<f64> {
+    let dimension = dimension.max(1);
+    let mut vector = vec![0.0; dimension];
+    for word in text.split(|c: char| !c.is_alphanumeric()).filter(|word| !word.is_empty()) {
+        let (bucket, sign) = hashed_word(word, dimension);
+        vector[bucket] += sign;
+    }
+    let norm = vector.iter().map(|x| x * x).sum::<f64>().sqrt();
+    if norm > 0.0 {
+        vector.iter_mut().for_each(|x| *x /= norm);
+    } else {
+        // Text with no words still needs a usable, non-zero vector
+        vector[0] = 1.0;
+    }
+    vector
+}
+
+/// Bucket and sign of a word in the hashing embedder's vectors
+fn hashed_word(word: &str, dimension: usize) -> (usize, f64) {
+    let hash = word.to_lowercase().bytes()
+        .fold(0xcbf29ce484222325u64, |hash, byte| (hash ^ byte as u64).wrapping_mul(0x100000001b3));
+    let sign = if hash >> 63 == 0 { 1.0 } else { -1.0 };
+    ((hash % dimension as u64) as usize, sign)
+}
+
+/// A token's byte range in the embedded text with its vector
+type TokenEmbedding = ((usize, usize), Vec<f64>);
+
+/// The hashing embedder's tokens: each word's byte range with its signed bucket
+fn hashed_token_embeddings(text: &str, dimension: usize) -> Vec<TokenEmbedding> {
+    let dimension = dimension.max(1);
+    let mut tokens = Vec::new();
+    let mut start = None;
+    for (index, c) in text.char_indices().chain(std::iter::once((text.len(), ' '))) {
+        match (start, c.is_alphanumeric()) {
+            (None, true) => start = Some(index),
+            (Some(begin), false) => {
+                let (bucket, sign) = hashed_word(&text[begin..index], dimension);
+                let mut vector = vec![0.0; dimension];
+                vector[bucket] = sign;
+                tokens.push(((begin, index), vector));
+                start = None;
+            }
+            _ => {}
+        }
+    }
+    tokens
+}
+
+/// Token embeddings of a local model, from its last hidden state, with each token's byte
+/// range in `text`. Special tokens are left out, as are tokens cut off by the model's input
+/// limit.
+fn local_token_embeddings(model: &mut TextEmbedding, tokenizer: &Tokenizer, text: &str) -> Result<Vec<TokenEmbedding>> {
+    let encoding = tokenizer.encode(text, true)
+        .map_err(|e| anyhow::anyhow!("Failed to tokenize text for late chunking: {}", e))?;
+    
+    let output = model.transform(vec![text], None)
+        .context("Failed to generate token embeddings with FastEmbed")?;
+    let precedence: &[OutputKey] = &[OutputKey::ByName("last_hidden_state"), OutputKey::OnlyOne];
+    output.export_with_transformer(|batches| {
+        let batch = batches.first()
+            .ok_or_else(|| anyhow::anyhow!("No token embeddings generated by FastEmbed"))?;
+        let hidden = batch.select_output(&precedence)?;
+        let &[_, length, dimension] = hidden.shape() else {
+            anyhow::bail!("Model output has shape {:?} rather than per-token embeddings", hidden.shape());
+        };
+        let values: Vec<f32> = hidden.iter().copied().collect();
+        
+        Ok(encoding.get_offsets().iter()
+            .zip(encoding.get_special_tokens_mask())
+            .take(length)
+            .enumerate()
+            .filter(|(_, (offsets, special))| **special == 0 && offsets.1 > offsets.0)
+            .map(|(position, (offsets, _))| {
+                let vector = values[position * dimension..(position + 1) * dimension].iter().map(|&x| x as f64).collect();
+                (*offsets, vector)
+            })
+            .collect())
+    })
+}
+
+/// Mean of the token vectors overlapping each byte range, scaled to unit length; `None` for
+/// a range no token overlaps
+fn pool_spans(tokens: &[TokenEmbedding], spans: &[(usize, usize)]) -> Vec<Option<Vec<f64>>> {
+    spans.iter()
+        .map(|&(start, end)| {
+            let mut inside = tokens.iter()
+                .filter(|((token_start, token_end), _)| *token_start < end && *token_end > start)
+                .map(|(_, vector)| vector)
+                .peekable();
+            let mut pooled = vec![0.0; inside.peek()?.len()];
+            for vector in inside {
+                pooled.iter_mut().zip(vector).for_each(|(sum, x)| *sum += x);
+            }
+            let norm = pooled.iter().map(|x| x * x).sum::<f64>().sqrt();
+            if norm > 0.0 {
+                pooled.iter_mut().for_each(|x| *x /= norm);
+            }
+            Some(pooled)
+        })
+        .collect()
+}
+
+/// Send a request made by `build`, waiting for the rate limiter before each attempt and
+/// retrying transient failures with exponential backoff, or as long as the server's
+/// `Retry-After` asks. `server` names the server in errors.
+async fn send_with_retries(
+    server: &str,
+    policy: &RetryPolicy,
+    limiter: &mut Option<RateLimiter>,
+    build: impl Fn() -> reqwest::RequestBuilder,
+) -> std::result::Result<reqwest::Response, RemoteError> {
+    let mut attempt = 0;
+    loop {
+        if let Some(limiter) = limiter.as_mut() {
+            limiter.wait().await;
+        }
+        
+        let error = match build().send().await {
+            Ok(response) if response.status().is_success() => return Ok(response),
+            Ok(response) => {
+                let status = response.status();
+                let retry_after = response.headers()
+                    .get(reqwest::header::RETRY_AFTER)
+                    .and_then(|value| value.to_str().ok())
+                    .and_then(|value| value.trim().parse().ok())
+                    .map(Duration::from_secs);
+                let error_text = response.text().await.unwrap_or_default();
+                RemoteError::from_status(status, format!("{} error ({}): {}", server, status, error_text), retry_after)
+            }
+            Err(e) if e.is_timeout() || e.is_connect() || e.is_request() => RemoteError::Transient {
+                message: format!("Failed to reach {}: {}", server, e),
+                retry_after: None,
+            },
+            Err(e) => RemoteError::Fatal(format!("Failed to reach {}: {}", server, e)),
+        };
+        
+        match error {
+            RemoteError::Transient { message, retry_after } if attempt < policy.max_retries => {
+                let delay = retry_after.unwrap_or_else(|| policy.delay(attempt, jitter()));
+                attempt += 1;
+                warn!("{}; retry {}/{} in {:.1}s", message, attempt, policy.max_retries, delay.as_secs_f64());
+                tokio::time::sleep(delay).await;
+            }
+            error => return Err(error),
+        }
+    }
+}
+
+/// A number from 0 to 1 that differs from call to call, from std's randomly keyed hasher
+fn jitter() -> f64 {
+    let hash = std::collections::hash_map::RandomState::new().build_hasher().finish();
+    (hash >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// Send an OpenAI-style embedding request, with the key as a bearer token when there is one
+async fn post_remote(
+    client: &reqwest::Client,
+    endpoint: &str,
+    api_key: Option<&str>,
+    request: &OpenAIEmbeddingRequest,
+    policy: &RetryPolicy,
+    limiter: &mut Option<RateLimiter>,
+) -> Result<OpenAIEmbeddingResponse> {
+    let build = || {
+        let builder = client
+            .post(endpoint)
+            .header("Content-Type", "application/json")
+            .json(request);
+        match api_key {
+            Some(api_key) => builder.header("Authorization", format!("Bearer {}", api_key)),
+            None => builder,
+        }
+    };
+    let response = send_with_retries("Remote embedding API", policy, limiter, build).await?;
+    
+    response
+        .json()
+        .await
+        .context("Failed to parse remote embedding API response")
+}
+
+/// Embed texts with an Ollama server: in one request through the batched `/api/embed`,
+/// otherwise one request per text through `/api/embeddings`
+async fn ollama_vectors(
+    client: &reqwest::Client,
+    endpoint: &str,
+    model: &str,
+    texts: &[&str],
+    policy: &RetryPolicy,
+    limiter: &mut Option<RateLimiter>,
+) -> Result<Vec<Vec<f64>>> {
+    let batched = endpoint.trim_end_matches('/').ends_with("/api/embed");
+    let server = format!("Ollama at {}", endpoint);
+    
+    if batched {
+        let body = serde_json::to_value(OllamaEmbedRequest { model, input: texts })?;
+        let response: OllamaEmbedResponse = send_with_retries(&server, policy, limiter, || client.post(endpoint).json(&body)).await?
+            .json()
+            .await
+            .context("Failed to parse Ollama embedding response")?;
+        if response.embeddings.len() != texts.len() {
+            anyhow::bail!("Embedding count mismatch: expected {}, got {}", texts.len(), response.embeddings.len());
         }
+        return Ok(response.embeddings);
     }
+    
+    let mut vectors = Vec::with_capacity(texts.len());
+    for text in texts {
+        let body = serde_json::to_value(OllamaEmbeddingRequest { model, prompt: text })?;
+        let response: OllamaEmbeddingResponse = send_with_retries(&server, policy, limiter, || client.post(endpoint).json(&body)).await?
+            .json()
+            .await
+            .context("Failed to parse Ollama embedding response")?;
+        vectors.push(response.embedding);
+    }
+    Ok(vectors)
+}
+
+/// Put a remote response's vectors back in input order, checking every input got one
+fn remote_vectors(response: OpenAIEmbeddingResponse, count: usize) -> Result<Vec<Vec<f64>>> {
+    if response.data.iter().all(|data| data.index.is_none()) {
+        return Ok(response.data.into_iter().map(|data| data.embedding).collect());
+    }
+    
+    let mut vectors = vec![None; count];
+    for data in response.data {
+        let index = data.index
+            .ok_or_else(|| anyhow::anyhow!("Remote embedding API response mixes indexed and unindexed vectors"))?;
+        match vectors.get_mut(index) {
+            Some(slot @ None) => *slot = Some(data.embedding),
+            Some(Some(_)) => anyhow::bail!("Remote embedding API returned two vectors for input {}", index),
+            None => anyhow::bail!("Remote embedding API returned a vector for input {} of a batch of {}", index, count),
+        }
+    }
+    vectors.into_iter()
+        .enumerate()
+        .map(|(index, vector)| vector.ok_or_else(|| anyhow::anyhow!("Remote embedding API returned no vector for input {}", index)))
+        .collect()
+}
+
+/// Check generated vectors are usable before they are stored: non-empty, finite, and all
+/// the same length as each other and as `expected`
+fn check_vectors(model: &str, vectors: &[Vec<f64>], expected: Option<usize>) -> Result<()> {
+    let Some(first) = vectors.first() else {
+        return Ok(());
+    };
+    let expected_len = expected.unwrap_or(first.len());
+    
+    for vector in vectors {
+        if vector.is_empty() {
+            anyhow::bail!("Embedding model {} returned an empty vector", model);
+        }
+        if vector.iter().any(|x| !x.is_finite()) {
+            anyhow::bail!("Embedding model {} returned a vector containing NaN or infinite values", model);
+        }
+        if vector.len() != expected_len {
+            match expected {
+                Some(expected) => anyhow::bail!(
+                    "Embedding dimension mismatch for model {}: the database stores {}-dimensional vectors but the model returned {}. \
+                     Check that --model and --endpoint point at the model the database was embedded with, \
+                     or pass a new --model to `portable-brains embed` to re-embed everything",
+                    model, expected, vector.len()
+                ),
+                None => anyhow::bail!(
+                    "Embedding model {} returned vectors of different lengths in one batch ({} and {})",
+                    model, first.len(), vector.len()
+                ),
+            }
+        }
+    }
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     
+    #[test]
+    fn test_check_vectors_dimension_guard() {
+        let vectors = vec![vec![0.1, 0.2, 0.3], vec![0.3, 0.2, 0.1]];
+        assert!(check_vectors("m", &vectors, None).is_ok());
+        assert!(check_vectors("m", &vectors, Some(3)).is_ok());
+        
+        let error = check_vectors("text-embedding-3-large", &vectors, Some(1536)).unwrap_err().to_string();
+        assert!(error.contains("text-embedding-3-large"));
+        assert!(error.contains("1536") && error.contains("returned 3"));
+        
+        assert!(check_vectors("m", &[vec![0.1, 0.2], vec![0.1]], None).is_err());
+        assert!(check_vectors("m", &[vec![f64::NAN]], None).is_err());
+    }
+    
+    #[test]
+    fn test_remote_vectors_reordered_by_index() {
+        let response: OpenAIEmbeddingResponse = serde_json::from_str(
+            r#"{"data": [{"index": 1, "embedding": [2.0]}, {"index": 0, "embedding": [1.0]}]}"#
+        ).unwrap();
+        assert_eq!(remote_vectors(response, 2).unwrap(), vec![vec![1.0], vec![2.0]]);
+        
+        let duplicate: OpenAIEmbeddingResponse = serde_json::from_str(
+            r#"{"data": [{"index": 0, "embedding": [2.0]}, {"index": 0, "embedding": [1.0]}]}"#
+        ).unwrap();
+        assert!(remote_vectors(duplicate, 2).is_err());
+    }
+    
+    #[test]
+    fn test_retry_delay_backs_off_with_jitter() {
+        let policy = RetryPolicy::default();
+        assert_eq!(policy.delay(0, 0.0), Duration::from_millis(250));
+        assert_eq!(policy.delay(0, 1.0), Duration::from_millis(500));
+        assert_eq!(policy.delay(3, 1.0), Duration::from_secs(4));
+        // Capped however many attempts were made
+        assert_eq!(policy.delay(40, 1.0), policy.max_delay);
+        assert!((0..100).map(|_| jitter()).all(|j| (0.0..1.0).contains(&j)));
+    }
+    
+    #[test]
+    fn test_remote_errors_classified_by_status() {
+        let classify = |status: u16| RemoteError::from_status(reqwest::StatusCode::from_u16(status).unwrap(), String::new(), None);
+        assert!(matches!(classify(429), RemoteError::Transient { .. }));
+        assert!(matches!(classify(503), RemoteError::Transient { .. }));
+        assert!(matches!(classify(413), RemoteError::Rejected(_)));
+        assert!(matches!(classify(401), RemoteError::Fatal(_)));
+        assert!(matches!(classify(404), RemoteError::Fatal(_)));
+        
+        // Past the embedding manager they surface typed, with their severity for the run
+        let severity = |status| error::severity(&embedding_error(anyhow::Error::new(classify(status)).context("Failed to send request")));
+        assert_eq!(severity(429), error::Severity::Retryable);
+        assert_eq!(severity(413), error::Severity::Skip);
+        assert_eq!(severity(401), error::Severity::Fatal);
+    }
+    
+    #[test]
+    fn test_prefixes_for_model() {
+        let e5 = EmbeddingPrefixes::for_model("intfloat/multilingual-e5-large");
+        assert_eq!(e5.document("Revenue grew"), "passage: Revenue grew");
+        assert_eq!(e5.query("revenue"), "query: revenue");
+        assert_eq!(EmbeddingPrefixes::for_model("nomic-ai/nomic-embed-text-v1").query, "search_query: ");
+        // Ollama's name for the same model
+        assert_eq!(EmbeddingPrefixes::for_model("nomic-embed-text:latest").document, "search_document: ");
+        assert!(EmbeddingPrefixes::for_model("BAAI/bge-small-en-v1.5").is_empty());
+    }
+    
+    #[tokio::test]
+    async fn test_hashing_embedder_is_deterministic() {
+        let mut manager = EmbeddingManager::hashing(64);
+        assert_eq!(manager.model_name(), "portable-brains/hashing-64");
+        
+        let first = manager.generate_embedding("Quarterly revenue grew").await.unwrap();
+        assert_eq!(first.len(), 64);
+        assert_eq!(first, manager.generate_embedding("quarterly REVENUE grew!").await.unwrap());
+        
+        let batch = manager.generate_embeddings_batch(&["revenue".to_string(), "holiday rota".to_string()]).await.unwrap();
+        let similarity = |a: &[f64], b: &[f64]| a.iter().zip(b).map(|(x, y)| x * y).sum::<f64>();
+        assert!(similarity(&first, &batch[0]) > similarity(&first, &batch[1]));
+    }
+    
+    #[tokio::test]
+    async fn test_late_chunks_pool_tokens_in_each_span() {
+        let mut manager = EmbeddingManager::hashing(64);
+        assert!(manager.supports_late_chunking());
+        
+        let text = "Backups run nightly. Restores are tested monthly.";
+        let vectors = manager.embed_late_chunks(text, &[(0, 20), (21, 49), (49, 49)]).await.unwrap();
+        // Hashed words carry no context, so pooling a span gives the span's own vector
+        assert_eq!(vectors[0].as_ref().unwrap(), &hashed_vector("Backups run nightly.", 64));
+        assert_eq!(vectors[1].as_ref().unwrap(), &hashed_vector("Restores are tested monthly.", 64));
+        assert!(vectors[2].is_none());
+        
+        let pooled = pool_spans(&[((0, 3), vec![1.0, 0.0]), ((4, 7), vec![0.0, 1.0])], &[(0, 7), (2, 3)]);
+        let both = pooled[0].as_ref().unwrap();
+        assert!((both[0] - both[1]).abs() < 1e-12 && (both[0] * both[0] * 2.0 - 1.0).abs() < 1e-12);
+        assert_eq!(pooled[1], Some(vec![1.0, 0.0]));
+    }
+    
     #[tokio::test]
     async fn test_embedding_generation() {
         // Note: These tests require model downloads, so they may be slow on first run
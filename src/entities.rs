@@ -0,0 +1,159 @@
+/// Capitalized words that start sentences or headings without naming anything; they are
+/// dropped from the front of a name ("The Atlas Project" is indexed as "Atlas Project")
+const LEADING_WORDS: &[&str] = &[
+    "a", "an", "the", "this", "that", "these", "those", "it", "its", "we", "our", "i", "you",
+    "your", "he", "she", "they", "their", "in", "on", "at", "for", "from", "to", "by", "with",
+    "and", "or", "but", "if", "when", "while", "after", "before", "as", "of", "all", "each",
+    "every", "some", "no", "not", "see", "note", "please", "who", "what", "where", "how", "why",
+    "then", "also", "later", "however", "finally",
+];
+
+/// Lower-case words allowed inside a name, e.g. "Bank of England"
+const CONNECTORS: &[&str] = &["of", "de", "van", "von", "der", "da", "di", "la", "&"];
+
+/// Questions answered by looking an entity up, e.g. "who is Maria Chen?"
+const LOOKUP_PREFIXES: &[&str] = &[
+    "who is ", "who's ", "who was ", "who are ", "who were ",
+    "what is ", "what's ", "what was ", "what are ", "what were ",
+    "tell me about ",
+];
+
+/// The form entities are indexed and looked up by: lower case with single spaces
+pub fn key(name: &str) -> String {
+    name.split_whitespace().collect::<Vec<_>>().join(" ").to_lowercase()
+}
+
+/// Whether `entity` (a key) names what `wanted` (a key) asks for: the same name, or a
+/// longer one starting or ending with it, so "chen" finds "maria chen"
+pub fn matches(entity: &str, wanted: &str) -> bool {
+    entity == wanted
+        || entity.strip_suffix(wanted).is_some_and(|rest| rest.ends_with(' '))
+        || entity.strip_prefix(wanted).is_some_and(|rest| rest.starts_with(' '))
+}
+
+/// A word that can only be a name: an acronym ("NASA"), or a product spelling with inner
+/// capitals or digits ("iPhone", "DuckDB", "GPT-4", "S3")
+fn is_distinctive(word: &str) -> bool {
+    let letters: Vec<char> = word.chars().filter(|c| c.is_alphabetic()).collect();
+    let acronym = (2..=6).contains(&letters.len()) && letters.iter().all(|c| c.is_uppercase());
+    let inner_capital = letters.iter().any(|c| c.is_lowercase()) && letters.iter().skip(1).any(|c| c.is_uppercase());
+    let numbered = word.chars().any(|c| c.is_ascii_digit()) && letters.iter().any(|c| c.is_uppercase());
+    acronym || inner_capital || numbered
+}
+
+/// Names of people, organisations, products and projects mentioned in `text`, in order of
+/// first mention, spelled as first mentioned. Names are runs of capitalized words; a
+/// single plain capitalized word is only taken mid-sentence, where capitals aren't
+/// grammatical.
+pub fn extract(text: &str) -> Vec<String> {
+    let mut names: Vec<String> = Vec::new();
+    let mut keys: Vec<String> = Vec::new();
+    let mut finish = |run: &mut Vec<&str>, starts_sentence: bool| {
+        // Leading filler and trailing connectors aren't part of the name
+        while run.first().is_some_and(|word| LEADING_WORDS.contains(&word.to_lowercase().as_str())) {
+            run.remove(0);
+        }
+        while run.last().is_some_and(|word| CONNECTORS.contains(word)) {
+            run.pop();
+        }
+        let keep = match run.as_slice() {
+            [] => false,
+            [word] => is_distinctive(word) || (!starts_sentence && word.chars().count() > 1),
+            _ => true,
+        };
+        if keep {
+            let name = run.join(" ");
+            let name_key = key(&name);
+            if !keys.contains(&name_key) {
+                keys.push(name_key);
+                names.push(name);
+            }
+        }
+        run.clear();
+    };
+
+    let mut run: Vec<&str> = Vec::new();
+    let mut run_starts_sentence = false;
+    let mut sentence_start = true;
+    for token in text.split_whitespace() {
+        let word = token.trim_matches(|c: char| !c.is_alphanumeric() && c != '&');
+        let ends_sentence = token.trim_end_matches([')', '"']).ends_with(['.', '!', '?', ':', ';']);
+        // Punctuation splits names
+        if token.starts_with(['(', '"']) {
+            finish(&mut run, run_starts_sentence);
+        }
+
+        let capitalized = word.chars().next().is_some_and(char::is_uppercase);
+        if capitalized || is_distinctive(word) {
+            if run.is_empty() {
+                run_starts_sentence = sentence_start;
+            }
+            run.push(word);
+        } else if !run.is_empty() && CONNECTORS.contains(&word) {
+            run.push(word);
+        } else {
+            finish(&mut run, run_starts_sentence);
+        }
+
+        if ends_sentence || token.ends_with([',', ')', '"']) {
+            finish(&mut run, run_starts_sentence);
+        }
+        sentence_start = ends_sentence;
+    }
+    finish(&mut run, run_starts_sentence);
+    names
+}
+
+/// The subject of a "who is X" or "what is X" question, without articles or punctuation
+pub fn lookup_subject(question: &str) -> Option<String> {
+    let trimmed = question.trim().trim_end_matches(['?', '.', '!']).trim();
+    let lower = trimmed.to_lowercase();
+    let prefix = LOOKUP_PREFIXES.iter().find(|prefix| lower.starts_with(*prefix))?;
+    let mut subject = trimmed[prefix.len()..].trim();
+    for article in ["the ", "a ", "an "] {
+        if subject.len() > article.len() && subject[..article.len()].eq_ignore_ascii_case(article) {
+            subject = subject[article.len()..].trim_start();
+            break;
+        }
+    }
+    (!subject.is_empty()).then(|| subject.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_extract_names() {
+        let text = "The Atlas Project was led by Maria Chen at the Bank of England. \
+                    It moved from MySQL to DuckDB in 2023, after GPT-4 reviewed the plan. \
+                    Later, Chen briefed NASA.";
+        assert_eq!(extract(text), vec![
+            "Atlas Project", "Maria Chen", "Bank of England", "MySQL", "DuckDB", "GPT-4", "Chen", "NASA",
+        ]);
+    }
+
+    #[test]
+    fn test_sentence_starts_are_not_names() {
+        assert!(extract("Backups run nightly. Restores are tested monthly.").is_empty());
+        assert_eq!(extract("Restores are tested by Priya."), vec!["Priya"]);
+        // The same name mentioned twice is listed once, spelled as first seen
+        assert_eq!(extract("We asked Priya. Then we asked PRIYA again."), vec!["Priya"]);
+    }
+
+    #[test]
+    fn test_matches_whole_words() {
+        assert!(matches("maria chen", "maria chen"));
+        assert!(matches("maria chen", "chen"));
+        assert!(matches("atlas project", "atlas"));
+        assert!(!matches("kitchen", "chen"));
+    }
+
+    #[test]
+    fn test_lookup_subject() {
+        assert_eq!(lookup_subject("Who is Maria Chen?").as_deref(), Some("Maria Chen"));
+        assert_eq!(lookup_subject("what is the Atlas Project").as_deref(), Some("Atlas Project"));
+        assert_eq!(lookup_subject("tell me about DuckDB.").as_deref(), Some("DuckDB"));
+        assert_eq!(lookup_subject("how do backups work?"), None);
+    }
+}
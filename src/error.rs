@@ -3,21 +3,98 @@ use std::fmt;
 #[derive(Debug)]
 pub enum PortableBrainsError {
     DatabaseError(String),
+    /// Another process or transaction holds the database, or its server is unreachable or
+    /// overloaded; the same call may succeed later
+    DatabaseBusy(String),
     DocumentProcessingError(String),
     EmbeddingError(String),
+    /// The embedding server is rate limiting, overloaded or unreachable, even after retries
+    EmbeddingUnavailable(String),
     ValidationError(String),
+    /// A content scanner flagged the file; the reason names the scanner
+    Quarantined(String),
+    /// The document is password-protected and no password given opens it
+    Encrypted(String),
+    /// Strict offline mode refused a network request; the message names what made it
+    NetworkRefused(String),
     IoError(std::io::Error),
 }
 
+/// What a failure means for the rest of a run that processes many files
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    /// Only the file being processed is affected; skip it and carry on
+    Skip,
+    /// A passing condition, such as a locked database; the same work may succeed if retried
+    Retryable,
+    /// Every later file would fail the same way, so the run should stop
+    Fatal,
+}
+
+impl PortableBrainsError {
+    pub fn severity(&self) -> Severity {
+        match self {
+            PortableBrainsError::DatabaseBusy(_) | PortableBrainsError::EmbeddingUnavailable(_) => Severity::Retryable,
+            PortableBrainsError::DatabaseError(_)
+            | PortableBrainsError::EmbeddingError(_)
+            | PortableBrainsError::NetworkRefused(_) => Severity::Fatal,
+            PortableBrainsError::IoError(err) => match err.kind() {
+                std::io::ErrorKind::Interrupted | std::io::ErrorKind::TimedOut | std::io::ErrorKind::WouldBlock => {
+                    Severity::Retryable
+                }
+                _ => Severity::Skip,
+            },
+            PortableBrainsError::DocumentProcessingError(_)
+            | PortableBrainsError::ValidationError(_)
+            | PortableBrainsError::Quarantined(_)
+            | PortableBrainsError::Encrypted(_) => Severity::Skip,
+        }
+    }
+
+    /// Short name of the kind of failure, for grouping failures in reports
+    pub fn kind(&self) -> &'static str {
+        match self {
+            PortableBrainsError::DatabaseError(_) => "database",
+            PortableBrainsError::DatabaseBusy(_) => "database busy",
+            PortableBrainsError::DocumentProcessingError(_) => "extraction",
+            PortableBrainsError::EmbeddingError(_) => "embedding",
+            PortableBrainsError::EmbeddingUnavailable(_) => "embedding server unavailable",
+            PortableBrainsError::ValidationError(_) => "validation",
+            PortableBrainsError::Quarantined(_) => "quarantined",
+            PortableBrainsError::Encrypted(_) => "encrypted",
+            PortableBrainsError::NetworkRefused(_) => "network refused",
+            PortableBrainsError::IoError(_) => "io",
+        }
+    }
+
+    /// Type a database failure, as busy when its message says the database is locked
+    fn database(message: String) -> Self {
+        let lowered = message.to_lowercase();
+        let busy = ["could not set lock", "conflicting lock", "database is locked", "write-write conflict"]
+            .iter()
+            .any(|sign| lowered.contains(sign));
+        if busy {
+            PortableBrainsError::DatabaseBusy(message)
+        } else {
+            PortableBrainsError::DatabaseError(message)
+        }
+    }
+}
+
 impl fmt::Display for PortableBrainsError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             PortableBrainsError::DatabaseError(msg) => write!(f, "Database error: {}", msg),
+            PortableBrainsError::DatabaseBusy(msg) => write!(f, "Database busy: {}", msg),
             PortableBrainsError::DocumentProcessingError(msg) => {
                 write!(f, "Document processing error: {}", msg)
             }
             PortableBrainsError::EmbeddingError(msg) => write!(f, "Embedding error: {}", msg),
+            PortableBrainsError::EmbeddingUnavailable(msg) => write!(f, "Embedding server unavailable: {}", msg),
             PortableBrainsError::ValidationError(msg) => write!(f, "Validation error: {}", msg),
+            PortableBrainsError::Quarantined(msg) => write!(f, "Quarantined by {}", msg),
+            PortableBrainsError::Encrypted(msg) => write!(f, "Encrypted: {}", msg),
+            PortableBrainsError::NetworkRefused(msg) => write!(f, "Network access refused: {}", msg),
             PortableBrainsError::IoError(err) => write!(f, "IO error: {}", err),
         }
     }
@@ -33,6 +110,66 @@ impl From<std::io::Error> for PortableBrainsError {
 
 impl From<duckdb::Error> for PortableBrainsError {
     fn from(err: duckdb::Error) -> Self {
-        PortableBrainsError::DatabaseError(err.to_string())
+        PortableBrainsError::database(err.to_string())
     }
-}
\ No newline at end of file
+}
+
+/// The typed error behind `error`, whether it was returned as is or with context added
+pub fn find(error: &anyhow::Error) -> Option<&PortableBrainsError> {
+    error.downcast_ref().or_else(|| error.chain().find_map(|cause| cause.downcast_ref()))
+}
+
+/// How a failure affects the rest of a run. Errors nothing has typed only fail the file
+/// they came from, as every failure did before errors were typed.
+pub fn severity(error: &anyhow::Error) -> Severity {
+    find(error).map_or(Severity::Skip, PortableBrainsError::severity)
+}
+
+/// Type a failure from a storage backend as a database error, unless the backend already
+/// typed it (an invalid fragment, for one)
+pub fn database(error: anyhow::Error) -> anyhow::Error {
+    typed(error, PortableBrainsError::database)
+}
+
+/// Type a failure to extract a document's text, which affects that document alone
+pub fn document_processing(error: anyhow::Error) -> anyhow::Error {
+    typed(error, PortableBrainsError::DocumentProcessingError)
+}
+
+/// Replace an untyped error by `kind` carrying its whole message; typed errors pass through
+pub fn typed(error: anyhow::Error, kind: impl FnOnce(String) -> PortableBrainsError) -> anyhow::Error {
+    if find(&error).is_some() {
+        return error;
+    }
+    kind(format!("{:#}", error)).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Context;
+
+    #[test]
+    fn test_severity_follows_the_typed_error_through_context() {
+        let quarantined: anyhow::Result<()> = Err(PortableBrainsError::Quarantined("clamav".to_string()).into());
+        let error = quarantined.context("Failed to scan").context("Failed to index report.pdf").unwrap_err();
+        assert_eq!(severity(&error), Severity::Skip);
+        assert_eq!(find(&error).map(PortableBrainsError::kind), Some("quarantined"));
+
+        let locked = database(anyhow::anyhow!("IO Error: Could not set lock on file \"brain.db\": Conflicting lock is held"));
+        assert_eq!(severity(&locked), Severity::Retryable);
+        assert!(locked.to_string().starts_with("Database busy: IO Error"));
+        assert_eq!(severity(&database(anyhow::anyhow!("Catalog Error: no such table"))), Severity::Fatal);
+
+        // Already typed errors keep their type, and untyped ones only skip the file
+        let invalid = database(PortableBrainsError::ValidationError("fragment is empty".to_string()).into());
+        assert!(matches!(invalid.downcast_ref(), Some(PortableBrainsError::ValidationError(_))));
+        assert_eq!(severity(&document_processing(anyhow::anyhow!("No text could be extracted from PDF"))), Severity::Skip);
+        assert_eq!(severity(&anyhow::anyhow!("Document already exists")), Severity::Skip);
+
+        let interrupted = std::io::Error::from(std::io::ErrorKind::Interrupted);
+        assert_eq!(PortableBrainsError::from(interrupted).severity(), Severity::Retryable);
+        let missing = std::io::Error::from(std::io::ErrorKind::NotFound);
+        assert_eq!(PortableBrainsError::from(missing).severity(), Severity::Skip);
+    }
+}
@@ -0,0 +1,175 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/// A query and what a good search should find for it, one line of an eval file
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EvalQuery {
+    pub query: String,
+    /// Documents answering the query, by file name or path
+    #[serde(default)]
+    pub documents: Vec<String>,
+    /// Fragments answering the query, by id; stable across runs with `index --deterministic`
+    #[serde(default)]
+    pub fragments: Vec<String>,
+}
+
+impl EvalQuery {
+    /// Whether a hit on `fragment_id` of the document at `file_path` finds the `target`th
+    /// expected document or fragment, counting documents first
+    fn finds(&self, target: usize, fragment_id: &str, file_path: &str) -> bool {
+        match self.documents.get(target) {
+            Some(document) => same_document(document, file_path),
+            None => self.fragments[target - self.documents.len()] == fragment_id,
+        }
+    }
+
+    fn targets(&self) -> usize {
+        self.documents.len() + self.fragments.len()
+    }
+}
+
+/// Whether `expected`, a file name or path, names the document at `file_path`
+fn same_document(expected: &str, file_path: &str) -> bool {
+    let file_path = file_path.replace('\\', "/");
+    let expected = expected.replace('\\', "/");
+    let expected = expected.trim_start_matches("./");
+    file_path == expected || file_path.ends_with(&format!("/{}", expected))
+}
+
+/// Read an eval file: one JSON object per line with a `query` and the `documents` or
+/// `fragments` it should find. Blank lines and lines starting with `#` are skipped.
+pub fn load_queries(path: &Path) -> Result<Vec<EvalQuery>> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    let mut queries = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let query: EvalQuery = serde_json::from_str(line)
+            .with_context(|| format!("{} line {} isn't an eval query", path.display(), number + 1))?;
+        if query.targets() == 0 {
+            anyhow::bail!("{} line {} names no documents or fragments to find", path.display(), number + 1);
+        }
+        queries.push(query);
+    }
+    if queries.is_empty() {
+        anyhow::bail!("{} holds no queries", path.display());
+    }
+    Ok(queries)
+}
+
+/// How well one ranking answered one query
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize)]
+pub struct QueryScores {
+    /// Share of the expected documents and fragments in the top k
+    pub recall: f64,
+    /// 1 / rank of the first hit finding one, or 0 when none is in the top k
+    pub reciprocal_rank: f64,
+    /// Discounted gain of the top k relative to a ranking with every expected one first
+    pub ndcg: f64,
+}
+
+/// Score the top `k` of `hits`, `(fragment id, file path)` pairs best first, against what
+/// `query` expects. Each expected document or fragment counts once, so a document's second
+/// fragment in the ranking gains nothing.
+pub fn score(query: &EvalQuery, hits: &[(String, String)], k: usize) -> QueryScores {
+    let targets = query.targets();
+    let mut found = vec![false; targets];
+    let mut scores = QueryScores::default();
+    let mut dcg = 0.0;
+
+    for (rank, (fragment_id, file_path)) in hits.iter().take(k).enumerate() {
+        let Some(target) = (0..targets).find(|&t| !found[t] && query.finds(t, fragment_id, file_path)) else {
+            continue;
+        };
+        found[target] = true;
+        dcg += 1.0 / (rank as f64 + 2.0).log2();
+        if scores.reciprocal_rank == 0.0 {
+            scores.reciprocal_rank = 1.0 / (rank as f64 + 1.0);
+        }
+    }
+
+    let ideal: f64 = (0..targets.min(k)).map(|rank| 1.0 / (rank as f64 + 2.0).log2()).sum();
+    scores.recall = found.iter().filter(|&&found| found).count() as f64 / targets as f64;
+    scores.ndcg = if ideal > 0.0 { dcg / ideal } else { 0.0 };
+    scores
+}
+
+/// Mean scores of one configuration over every query
+#[derive(Debug, Clone, Serialize)]
+pub struct ConfigurationReport {
+    /// Database and search settings, e.g. `brain-400.db, hybrid, reranked`
+    pub configuration: String,
+    pub recall: f64,
+    /// Mean reciprocal rank
+    pub mrr: f64,
+    pub ndcg: f64,
+    /// Queries none of whose expected documents or fragments made the top k
+    pub misses: Vec<String>,
+}
+
+impl ConfigurationReport {
+    /// Average the scores of each query, given in the same order as `queries`
+    pub fn new(configuration: String, queries: &[EvalQuery], scores: &[QueryScores]) -> Self {
+        let count = scores.len().max(1) as f64;
+        Self {
+            configuration,
+            recall: scores.iter().map(|s| s.recall).sum::<f64>() / count,
+            mrr: scores.iter().map(|s| s.reciprocal_rank).sum::<f64>() / count,
+            ndcg: scores.iter().map(|s| s.ndcg).sum::<f64>() / count,
+            misses: queries.iter()
+                .zip(scores)
+                .filter(|(_, scores)| scores.recall == 0.0)
+                .map(|(query, _)| query.query.clone())
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hits(hits: &[(&str, &str)]) -> Vec<(String, String)> {
+        hits.iter().map(|(id, path)| (id.to_string(), path.to_string())).collect()
+    }
+
+    #[test]
+    fn test_score() {
+        let query = EvalQuery {
+            query: "notice period".to_string(),
+            documents: vec!["contracts/lease.pdf".to_string(), "terms.docx".to_string()],
+            fragments: Vec::new(),
+        };
+        let ranking = hits(&[
+            ("f1", "/data/other.txt"),
+            ("f2", "/data/contracts/lease.pdf"),
+            // A second fragment of an already found document gains nothing
+            ("f3", "/data/contracts/lease.pdf"),
+            ("f4", "/data/terms.docx"),
+        ]);
+        let scores = score(&query, &ranking, 10);
+        assert_eq!(scores.recall, 1.0);
+        assert_eq!(scores.reciprocal_rank, 0.5);
+        let ideal = 1.0 + 1.0 / 3f64.log2();
+        assert!((scores.ndcg - (1.0 / 3f64.log2() + 1.0 / 5f64.log2()) / ideal).abs() < 1e-12);
+
+        // Only the top k count
+        let scores = score(&query, &ranking, 1);
+        assert_eq!(scores, QueryScores::default());
+
+        let query = EvalQuery { documents: Vec::new(), fragments: vec!["f4".to_string()], ..query };
+        assert_eq!(score(&query, &ranking, 10), QueryScores { recall: 1.0, reciprocal_rank: 0.25, ndcg: 1.0 / 5f64.log2() });
+    }
+
+    #[test]
+    fn test_same_document() {
+        assert!(same_document("lease.pdf", "/data/contracts/lease.pdf"));
+        assert!(same_document("./contracts/lease.pdf", "/data/contracts/lease.pdf"));
+        assert!(!same_document("ease.pdf", "/data/contracts/lease.pdf"));
+    }
+}
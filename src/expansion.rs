@@ -0,0 +1,151 @@
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+
+use crate::brains::BrainHit;
+use crate::hybrid::fuse_ranked;
+use crate::llm::{ChatMessage, LlmClient, TokenUsage};
+
+/// Most paraphrases searched alongside the question
+pub const MAX_PARAPHRASES: usize = 3;
+
+/// How a question is rewritten before retrieval, so passages phrased differently from it
+/// are found too
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum QueryExpansion {
+    /// Search with the question alone
+    #[default]
+    Off,
+    /// Also search with paraphrases of the question
+    Multi,
+    /// Also search with a hypothetical passage answering the question (HyDE)
+    Hyde,
+}
+
+/// Texts searched for besides the question itself
+#[derive(Debug, Clone, Default)]
+pub struct Expansion {
+    pub variants: Vec<String>,
+    /// Whether the variants read like passages rather than questions, and are embedded as such
+    pub passages: bool,
+    /// Tokens spent on the expansion request
+    pub token_usage: TokenUsage,
+}
+
+/// Pull the paraphrases out of the model's reply: a JSON array of strings, or failing that
+/// its non-empty lines stripped of list markers. The question itself and repeats are dropped.
+fn parse_paraphrases(text: &str, question: &str) -> Vec<String> {
+    let from_json = text.find('[')
+        .zip(text.rfind(']'))
+        .filter(|(start, end)| start < end)
+        .and_then(|(start, end)| serde_json::from_str::<Vec<String>>(&text[start..=end]).ok());
+
+    let candidates = from_json.unwrap_or_else(|| {
+        text.lines()
+            .map(|line| line.trim().trim_start_matches(|c: char| c.is_ascii_digit() || "-*•.) ".contains(c)))
+            .map(str::to_string)
+            .collect()
+    });
+
+    let mut paraphrases: Vec<String> = Vec::new();
+    for paraphrase in candidates {
+        let paraphrase = paraphrase.trim();
+        if !paraphrase.is_empty()
+            && !paraphrase.eq_ignore_ascii_case(question.trim())
+            && !paraphrases.iter().any(|p| p.eq_ignore_ascii_case(paraphrase))
+        {
+            paraphrases.push(paraphrase.to_string());
+        }
+    }
+    paraphrases.truncate(MAX_PARAPHRASES);
+    paraphrases
+}
+
+/// Ask the LLM for the texts `mode` searches with besides `question`: paraphrases of it, or
+/// a short passage that would answer it. Nothing is asked when expansion is off.
+pub async fn expand_query(llm: &LlmClient, question: &str, mode: QueryExpansion) -> Result<Expansion> {
+    let instructions = match mode {
+        QueryExpansion::Off => return Ok(Expansion::default()),
+        QueryExpansion::Multi => format!(
+            "You rewrite search questions. Give up to {} different phrasings of the user's \
+             question, using other words and synonyms a document answering it might use, each \
+             keeping the question's meaning. Reply with a JSON array of strings and nothing else.",
+            MAX_PARAPHRASES,
+        ),
+        QueryExpansion::Hyde => "Write a short passage, as it might appear in a document, that \
+            answers the user's question. Make up plausible details where you don't know them; the \
+            passage is only used to find real documents. Reply with the passage and nothing else."
+            .to_string(),
+    };
+    let messages = vec![
+        ChatMessage::system(instructions).cached(),
+        ChatMessage::user(question),
+    ];
+    let reply = llm.complete(messages, 300, 0.7).await
+        .context("Query expansion request failed")?;
+
+    let variants = match mode {
+        QueryExpansion::Hyde => Some(reply.content.trim().to_string())
+            .filter(|passage| !passage.is_empty())
+            .into_iter()
+            .collect(),
+        _ => parse_paraphrases(&reply.content, question),
+    };
+    Ok(Expansion {
+        variants,
+        passages: mode == QueryExpansion::Hyde,
+        token_usage: reply.usage,
+    })
+}
+
+/// Fuse best-first rankings of the question and its variants: each passage scores
+/// `1 / (RRF_K + rank)` summed over the rankings it appears in. Ties keep the order of the
+/// first ranking a passage appears in.
+pub fn fuse_rankings(rankings: Vec<Vec<BrainHit>>, limit: usize) -> Vec<BrainHit> {
+    fuse_ranked(rankings, |hit| (hit.brain.clone(), hit.fragment_id.clone())).into_iter()
+        .take(limit)
+        .map(|(hit, score)| BrainHit { score, ..hit })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::FragmentSource;
+
+    fn hit(id: &str) -> BrainHit {
+        BrainHit {
+            brain: "work".to_string(),
+            fragment_id: id.to_string(),
+            content: format!("content of {}", id),
+            score: 0.5,
+            normalized_score: 0.5,
+            source: FragmentSource::default(),
+            version: 1,
+        }
+    }
+
+    #[test]
+    fn test_parse_paraphrases() {
+        let paraphrases = parse_paraphrases(
+            "```json\n[\"How often are backups taken?\", \"how often are backups taken?\", \"When do backups run?\", \"Backup schedule\", \"Backup frequency\"]\n```",
+            "When do backups run?",
+        );
+        assert_eq!(paraphrases, vec!["How often are backups taken?", "Backup schedule", "Backup frequency"]);
+
+        let paraphrases = parse_paraphrases("1. Backup schedule\n- Backup frequency\n", "When do backups run?");
+        assert_eq!(paraphrases, vec!["Backup schedule", "Backup frequency"]);
+    }
+
+    #[test]
+    fn test_fuse_rankings() {
+        let fused = fuse_rankings(vec![
+            vec![hit("a"), hit("b"), hit("c")],
+            vec![hit("c"), hit("d")],
+            vec![hit("c"), hit("b")],
+        ], 3);
+        let ids: Vec<&str> = fused.iter().map(|hit| hit.fragment_id.as_str()).collect();
+        // Found by every variant, c ranks first; b beats a for being found twice
+        assert_eq!(ids, vec!["c", "b", "a"]);
+        assert!((fused[0].score - (1.0 / 63.0 + 2.0 / 61.0)).abs() < 1e-12);
+    }
+}
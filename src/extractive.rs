@@ -0,0 +1,111 @@
+use anyhow::{Context, Result};
+use std::collections::HashSet;
+
+use crate::brains::cosine_similarity;
+use crate::embedding_manager::{EmbeddingManager, EmbeddingPrefixes};
+use crate::verification::split_cited_sentences;
+
+/// Sentences an extractive answer is made of unless told otherwise
+pub const DEFAULT_SENTENCES: usize = 3;
+
+/// Sentences of fewer words are passed over: headings, list labels and pieces cut off at a
+/// fragment boundary say too little on their own
+const MIN_WORDS: usize = 4;
+
+/// Sentences embedded for one answer at most, taken from the best passages first
+const MAX_CANDIDATES: usize = 200;
+
+/// A sentence of a retrieved passage, with the passage's number (from 1) for citing it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Candidate {
+    pub passage: usize,
+    pub text: String,
+}
+
+/// The sentences of `passages` worth answering with, in passage order, each once
+pub fn candidates(passages: &[&str]) -> Vec<Candidate> {
+    let mut seen = HashSet::new();
+    let mut candidates = Vec::new();
+    for (i, passage) in passages.iter().enumerate() {
+        for sentence in split_cited_sentences(passage) {
+            let text = sentence.text.split_whitespace().collect::<Vec<_>>().join(" ");
+            if text.split(' ').count() < MIN_WORDS || !seen.insert(text.to_lowercase()) {
+                continue;
+            }
+            candidates.push(Candidate { passage: i + 1, text });
+            if candidates.len() == MAX_CANDIDATES {
+                return candidates;
+            }
+        }
+    }
+    candidates
+}
+
+/// The `count` candidates whose vectors are most similar to the question's, best first
+pub fn pick(question: &[f64], candidates: Vec<Candidate>, vectors: &[Vec<f64>], count: usize) -> Vec<(Candidate, f64)> {
+    let mut scored: Vec<(Candidate, f64)> = candidates.into_iter()
+        .zip(vectors)
+        .map(|(candidate, vector)| (candidate, cosine_similarity(question, vector)))
+        .collect();
+    scored.sort_by(|a, b| b.1.total_cmp(&a.1));
+    scored.truncate(count);
+    scored
+}
+
+/// The picked sentences as an answer, a line each, citing the passage each came from the way
+/// generated answers do so sources and citations are shown the same
+pub fn render(picked: &[(Candidate, f64)]) -> String {
+    picked.iter()
+        .map(|(candidate, _)| format!("{} [{}]", candidate.text, candidate.passage))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Answer `question` with the sentences of the retrieved passages closest to it in embedding
+/// space, without a language model. The question and the sentences are embedded the way the
+/// brains embed queries and fragments.
+pub async fn extract_answer(
+    embedder: &mut EmbeddingManager,
+    prefixes: &EmbeddingPrefixes,
+    question: &str,
+    passages: &[&str],
+    count: usize,
+) -> Result<String> {
+    let candidates = candidates(passages);
+    if candidates.is_empty() {
+        return Ok("No relevant sentences were found in the knowledge base.".to_string());
+    }
+    let mut texts = vec![prefixes.query(question)];
+    texts.extend(candidates.iter().map(|candidate| prefixes.document(&candidate.text)));
+    let vectors = embedder.generate_embeddings_batch(&texts).await
+        .context("Failed to embed the passages' sentences")?;
+    if vectors.len() != texts.len() {
+        anyhow::bail!("Failed to embed the passages' sentences");
+    }
+    Ok(render(&pick(&vectors[0], candidates, &vectors[1..], count)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_extractive_answer_cites_best_sentences() {
+        let passages = [
+            "Vacation. Employees get twenty vacation days a year. Unused days expire in March.",
+            "Employees get twenty vacation days a year. Managers approve leave requests in the HR portal.",
+        ];
+        let candidates = candidates(&passages);
+        // Short pieces are skipped and the repeated sentence is kept once, from its first passage
+        assert_eq!(candidates.len(), 3);
+        assert_eq!(candidates[2], Candidate { passage: 2, text: "Managers approve leave requests in the HR portal.".to_string() });
+
+        let vectors = vec![vec![1.0, 0.0], vec![0.0, 1.0], vec![0.6, 0.8]];
+        let picked = pick(&[0.0, 1.0], candidates, &vectors, 2);
+        assert_eq!(render(&picked), "Unused days expire in March. [1]\nManagers approve leave requests in the HR portal. [2]");
+
+        // The answer's markers read back as citations of the passages
+        let cited = split_cited_sentences(&render(&picked));
+        assert_eq!(cited[1].citations, vec![2]);
+    }
+}
@@ -0,0 +1,88 @@
+use anyhow::{Context, Result};
+
+use crate::llm::{ChatMessage, LlmClient, TokenUsage};
+
+/// Most follow-up questions suggested after an answer
+pub const MAX_FOLLOW_UPS: usize = 3;
+
+/// Questions suggested to continue the conversation after an answer
+#[derive(Debug, Clone, Default)]
+pub struct FollowUps {
+    pub questions: Vec<String>,
+    /// Tokens spent on the suggestion request
+    pub token_usage: TokenUsage,
+}
+
+/// Pull the suggested questions out of the model's reply: a JSON array of strings, or
+/// failing that the lines ending in a question mark, stripped of list markers
+fn parse_questions(text: &str) -> Vec<String> {
+    let from_json = text.find('[')
+        .zip(text.rfind(']'))
+        .filter(|(start, end)| start < end)
+        .and_then(|(start, end)| serde_json::from_str::<Vec<String>>(&text[start..=end]).ok());
+
+    let candidates = from_json.unwrap_or_else(|| {
+        text.lines()
+            .map(|line| line.trim().trim_start_matches(|c: char| c.is_ascii_digit() || "-*•.) ".contains(c)))
+            .filter(|line| line.ends_with('?'))
+            .map(str::to_string)
+            .collect()
+    });
+
+    let mut questions: Vec<String> = Vec::new();
+    for question in candidates {
+        let question = question.trim();
+        if !question.is_empty() && !questions.iter().any(|q| q.eq_ignore_ascii_case(question)) {
+            questions.push(question.to_string());
+        }
+    }
+    questions.truncate(MAX_FOLLOW_UPS);
+    questions
+}
+
+/// Ask the LLM for follow-up questions that the retrieved passages can answer, so users can
+/// explore a corpus they don't know
+pub async fn suggest_follow_ups(llm: &LlmClient, question: &str, answer: &str, passages: &[String]) -> Result<FollowUps> {
+    let context = passages.iter()
+        .enumerate()
+        .map(|(i, passage)| format!("[{}] {}", i + 1, passage))
+        .collect::<Vec<_>>()
+        .join("\n\n");
+
+    let messages = vec![
+        ChatMessage::system(
+            "You suggest follow-up questions a reader could ask next about the passages below. \
+             Each must be answerable from the passages alone, must not repeat the question already \
+             answered, and must be short and self-contained. \
+             Reply with a JSON array of two or three question strings and nothing else.",
+        ).cached(),
+        ChatMessage::system(format!("Passages:\n{}", context)).cached(),
+        ChatMessage::user(format!("Question: {}\n\nAnswer: {}", question, answer)),
+    ];
+    let reply = llm.complete(messages, 200, 0.7).await
+        .context("Follow-up suggestion request failed")?;
+
+    Ok(FollowUps {
+        questions: parse_questions(&reply.content),
+        token_usage: reply.usage,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_questions_from_json() {
+        let questions = parse_questions(
+            "```json\n[\"What drove costs?\", \"what drove costs?\", \"Who approved the budget?\", \"When?\", \"Why?\"]\n```"
+        );
+        assert_eq!(questions, vec!["What drove costs?", "Who approved the budget?", "When?"]);
+    }
+
+    #[test]
+    fn test_parse_questions_from_list() {
+        let questions = parse_questions("Here are some ideas:\n1. What drove costs?\n- Who approved the budget?\nThanks.");
+        assert_eq!(questions, vec!["What drove costs?", "Who approved the budget?"]);
+    }
+}
@@ -0,0 +1,211 @@
+use clap::ValueEnum;
+use std::collections::HashMap;
+use std::hash::Hash;
+
+use crate::storage::FragmentMatch;
+
+/// Constant damping the weight of top ranks in reciprocal rank fusion; 60 is the value from
+/// the original paper and works across very different score scales
+pub const RRF_K: f64 = 60.0;
+
+/// Candidates fetched from each ranking before fusion, so a fragment ranked well by only
+/// one of them can still make the cut
+pub const FUSION_POOL: usize = 50;
+
+/// How fragments are matched against a query
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum SearchMode {
+    /// Cosine similarity of embeddings
+    #[default]
+    Vector,
+    /// Vector and keyword rankings fused by reciprocal rank
+    Hybrid,
+    /// BM25 full-text ranking, for exact terms such as error codes and names
+    Keyword,
+}
+
+/// A fragment ranked by reciprocal rank fusion, with the scores it was fused from
+#[derive(Debug, Clone)]
+pub struct FusedHit {
+    /// The fragment, scored by reciprocal rank fusion
+    pub fragment: FragmentMatch,
+    /// Cosine similarity, when the fragment was among the vector candidates
+    pub dense_score: Option<f64>,
+    /// BM25 score, when the fragment was among the keyword candidates
+    pub sparse_score: Option<f64>,
+}
+
+/// Fuse any number of best-first rankings by reciprocal rank: each item, told apart by
+/// `key`, scores `1 / (RRF_K + rank)` summed over the rankings it appears in. Items are
+/// returned once, as first seen, with their fused score, best first; ties keep the order
+/// they were first seen in.
+pub fn fuse_ranked<T, K: Hash + Eq>(rankings: impl IntoIterator<Item = Vec<T>>, key: impl Fn(&T) -> K) -> Vec<(T, f64)> {
+    let mut fused: Vec<(T, f64)> = Vec::new();
+    let mut positions: HashMap<K, usize> = HashMap::new();
+    for ranking in rankings {
+        for (rank, item) in ranking.into_iter().enumerate() {
+            let contribution = 1.0 / (RRF_K + rank as f64 + 1.0);
+            match positions.get(&key(&item)) {
+                Some(&position) => fused[position].1 += contribution,
+                None => {
+                    positions.insert(key(&item), fused.len());
+                    fused.push((item, contribution));
+                }
+            }
+        }
+    }
+    fused.sort_by(|a, b| b.1.total_cmp(&a.1));
+    fused
+}
+
+/// Fuse a vector and a keyword ranking by reciprocal rank, keeping each fragment's score in
+/// both. Ties keep vector order.
+pub fn reciprocal_rank_fusion(dense: Vec<FragmentMatch>, sparse: Vec<FragmentMatch>, limit: usize) -> Vec<FusedHit> {
+    let dense_scores: HashMap<String, f64> = dense.iter().map(|fragment| (fragment.fragment_id.clone(), fragment.score)).collect();
+    let sparse_scores: HashMap<String, f64> = sparse.iter().map(|fragment| (fragment.fragment_id.clone(), fragment.score)).collect();
+
+    let mut fused = fuse_ranked([dense, sparse], |fragment| fragment.fragment_id.clone());
+    fused.truncate(limit);
+    fused.into_iter()
+        .map(|(fragment, score)| FusedHit {
+            dense_score: dense_scores.get(&fragment.fragment_id).copied(),
+            sparse_score: sparse_scores.get(&fragment.fragment_id).copied(),
+            fragment: FragmentMatch { score, ..fragment },
+        })
+        .collect()
+}
+
+/// Greedy maximal marginal relevance over candidates paired with their relevance: each pick
+/// maximises `lambda · relevance − (1 − lambda) · similarity to the closest pick so far`,
+/// among the candidates `admit` accepts next to the picks so far. Stops after `limit`
+/// picks or when no candidate is admitted. Ties go to the earlier candidate. Picks are
+/// returned with their relevance, in the order they were picked.
+pub fn select_mmr<T>(
+    candidates: Vec<(T, f64)>,
+    limit: usize,
+    lambda: f64,
+    similarity: impl Fn(&T, &T) -> f64,
+    admit: impl Fn(&T, &[(T, f64)]) -> bool,
+) -> Vec<(T, f64)> {
+    let mut remaining = candidates;
+    let mut picked: Vec<(T, f64)> = Vec::new();
+    while picked.len() < limit && !remaining.is_empty() {
+        let marginal = |(candidate, relevance): &(T, f64)| {
+            let redundancy = picked.iter().map(|(chosen, _)| similarity(candidate, chosen)).fold(0.0, f64::max);
+            lambda * relevance - (1.0 - lambda) * redundancy
+        };
+        let best = (0..remaining.len())
+            .filter(|&i| admit(&remaining[i].0, &picked))
+            .max_by(|&a, &b| marginal(&remaining[a]).total_cmp(&marginal(&remaining[b])).then(b.cmp(&a)));
+        let Some(best) = best else {
+            break;
+        };
+        picked.push(remaining.remove(best));
+    }
+    picked
+}
+
+/// Lowercased alphanumeric words, the terms BM25 matches on
+pub fn tokenize(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .collect()
+}
+
+/// Okapi BM25 scores of `(id, text)` documents for `query`, best first, leaving out
+/// documents that share no term with it. For backends without a full-text index.
+pub fn bm25<'a>(query: &str, documents: impl IntoIterator<Item = (&'a str, &'a str)>) -> Vec<(&'a str, f64)> {
+    const K1: f64 = 1.2;
+    const B: f64 = 0.75;
+
+    let mut terms = tokenize(query);
+    terms.sort();
+    terms.dedup();
+
+    let documents: Vec<(&str, Vec<String>)> = documents.into_iter()
+        .map(|(id, text)| (id, tokenize(text)))
+        .collect();
+    if terms.is_empty() || documents.is_empty() {
+        return Vec::new();
+    }
+    let count = documents.len() as f64;
+    let average_length = documents.iter().map(|(_, words)| words.len()).sum::<usize>() as f64 / count;
+
+    let idf: Vec<f64> = terms.iter()
+        .map(|term| {
+            let containing = documents.iter().filter(|(_, words)| words.contains(term)).count() as f64;
+            ((count - containing + 0.5) / (containing + 0.5) + 1.0).ln()
+        })
+        .collect();
+
+    let mut scored: Vec<(&str, f64)> = documents.iter()
+        .filter_map(|(id, words)| {
+            let length_norm = 1.0 - B + B * words.len() as f64 / average_length.max(1.0);
+            let score: f64 = terms.iter().zip(&idf)
+                .map(|(term, idf)| {
+                    let frequency = words.iter().filter(|word| *word == term).count() as f64;
+                    idf * frequency * (K1 + 1.0) / (frequency + K1 * length_norm)
+                })
+                .sum();
+            (score > 0.0).then_some((*id, score))
+        })
+        .collect();
+    scored.sort_by(|a, b| b.1.total_cmp(&a.1));
+    scored
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::FragmentSource;
+
+    fn hit(id: &str, score: f64) -> FragmentMatch {
+        FragmentMatch {
+            fragment_id: id.to_string(),
+            content: format!("content of {}", id),
+            score,
+            source: FragmentSource::default(),
+        }
+    }
+
+    #[test]
+    fn test_fusion_rewards_agreement() {
+        let dense = vec![hit("a", 0.9), hit("b", 0.8), hit("c", 0.7)];
+        let sparse = vec![hit("c", 12.0), hit("d", 9.0)];
+
+        let fused = reciprocal_rank_fusion(dense, sparse, 3);
+
+        assert_eq!(fused[0].fragment.fragment_id, "c");
+        assert_eq!(fused[0].dense_score, Some(0.7));
+        assert_eq!(fused[0].sparse_score, Some(12.0));
+        assert_eq!(fused[1].fragment.fragment_id, "a");
+        assert_eq!(fused.len(), 3);
+    }
+
+    #[test]
+    fn test_mmr_passes_over_near_duplicates() {
+        let candidates = vec![(vec![1.0, 0.0], 1.0), (vec![1.0, 0.01], 0.95), (vec![0.0, 1.0], 0.6)];
+        let similarity = |a: &Vec<f64>, b: &Vec<f64>| a.iter().zip(b).map(|(x, y)| x * y).sum::<f64>();
+        let picked = select_mmr(candidates.clone(), 2, 0.5, similarity, |_, _| true);
+        assert_eq!(picked.iter().map(|(_, relevance)| *relevance).collect::<Vec<_>>(), vec![1.0, 0.6]);
+
+        // Pure relevance ignores redundancy, and admission can end the picking early
+        let picked = select_mmr(candidates, 3, 1.0, similarity, |_, picked| picked.len() < 2);
+        assert_eq!(picked.iter().map(|(_, relevance)| *relevance).collect::<Vec<_>>(), vec![1.0, 0.95]);
+    }
+
+    #[test]
+    fn test_bm25_finds_exact_terms() {
+        let documents = [
+            ("error", "The upload failed with error E1042 after the retry limit."),
+            ("general", "Uploads can fail when the network drops; try again later."),
+            ("other", "Quarterly revenue grew by eight percent."),
+        ];
+        let scored = bm25("what does E1042 mean", documents);
+        assert_eq!(scored.len(), 1);
+        assert_eq!(scored[0].0, "error");
+
+        assert!(bm25("", documents).is_empty());
+    }
+}
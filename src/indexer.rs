@@ -0,0 +1,175 @@
+use anyhow::{Context, Result};
+use log::{info, warn};
+use std::path::Path;
+
+use crate::document_processor::{self, derive_title, pack_sections, ChunkingStrategy, DocumentProcessor};
+use crate::embedding_manager::{self, EmbeddingManager, EmbeddingPrefixes};
+use crate::error::PortableBrainsError;
+use crate::paths;
+use crate::presets;
+use crate::storage::{self, create_storage, Storage, StorageBackend};
+
+/// Fragments embedded per batch by `embed_pending`
+const EMBED_BATCH_SIZE: i32 = 50;
+
+/// Adds documents to a brain and embeds them, as `portable-brains index --embed` does for a
+/// directory: text is extracted and chunked with the database's preset and chunking strategy, and
+/// every fragment is embedded with the database's model and document prefix.
+pub struct Indexer {
+    storage: Box<dyn Storage>,
+    processor: DocumentProcessor,
+    embedding_manager: EmbeddingManager,
+}
+
+impl Indexer {
+    /// Open or create the database at `path`, choosing the backend from its extension
+    pub async fn open(path: &Path, embedding_manager: EmbeddingManager) -> Result<Self> {
+        let storage = create_storage(&StorageBackend::from_path(path), path).await
+            .with_context(|| format!("Failed to open database {}", path.display()))?;
+        Self::new(storage, embedding_manager).await
+    }
+
+    /// Index into an opened database. A new database records the embedding manager's model
+    /// and its documented prefixes; an existing one must have been indexed with that model.
+    pub async fn new(mut storage: Box<dyn Storage>, mut embedding_manager: EmbeddingManager) -> Result<Self> {
+        let model = embedding_manager.model_name().to_string();
+        storage.verify_or_set_model(&model, embedding_manager.known_dimension()).await
+            .context("Failed to verify embedding model")?;
+
+        // Prefixes can't change once vectors exist, so only a fresh database gets the model's
+        if storage::recorded_prefixes(&mut *storage).await?.is_none()
+            && storage::embedding_dimension(&mut *storage).await?.is_none()
+        {
+            storage::set_embedding_prefixes(&mut *storage, &EmbeddingPrefixes::for_model(&model)).await?;
+        }
+        if let Some(dimension) = storage::embedding_dimension(&mut *storage).await? {
+            embedding_manager.expect_dimension(dimension);
+        }
+
+        let processor = match presets::recorded_preset(&mut *storage).await? {
+            Some(preset) => {
+                let (chunk_size, overlap) = preset.chunking();
+                DocumentProcessor::new().with_chunking(chunk_size, overlap)
+            }
+            None => DocumentProcessor::new(),
+        };
+        let processor = match document_processor::recorded_chunking(&mut *storage).await? {
+            Some(ChunkingStrategy::Token) => processor.with_token_chunking(embedding_manager::load_tokenizer(&model)?)?,
+            Some(strategy) => processor.with_strategy(strategy),
+            None => processor,
+        };
+
+        Ok(Self { storage, processor, embedding_manager })
+    }
+
+    /// Extract and chunk documents with this processor instead, e.g. one with cleanup rules
+    pub fn with_processor(mut self, processor: DocumentProcessor) -> Self {
+        self.processor = processor;
+        self
+    }
+
+    /// Read, extract and store a document file without embedding it. Returns its id, or
+    /// `None` when the file, or an identical one at another path, is already indexed.
+    pub async fn add_file(&mut self, path: &Path) -> Result<Option<String>> {
+        if self.storage.document_exists(path).await? {
+            return Ok(None);
+        }
+        let data = std::fs::read(paths::io_path(path))
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        if let Some(original) = self.storage.find_duplicate(&storage::content_hash(&data)).await? {
+            info!("Skipping {}: identical to {}", path.display(), original);
+            return Ok(None);
+        }
+        self.add_document(path, &data).await.map(Some)
+    }
+
+    /// Extract and store a document whose bytes are already in memory, recorded under `path`
+    /// (whose extension selects the parser), without embedding it. Returns its id.
+    pub async fn add_document(&mut self, path: &Path, data: &[u8]) -> Result<String> {
+        let sections = self.processor.extract_sections_from_document(path, data)
+            .with_context(|| format!("Failed to extract text from {}", path.display()))?;
+        let fragments = self.processor.chunk_sections(&sections)?;
+
+        let document_id = self.storage.store_document(path, data).await?;
+        if !storage::embeddings_only(&mut *self.storage).await? {
+            self.storage.set_document_text(&document_id, &pack_sections(&sections)?).await?;
+            if let Some(title) = derive_title(&sections) {
+                self.storage.set_document_title(&document_id, &title).await?;
+            }
+        }
+
+        // Fragments the storage layer rejects as invalid are skipped, as they are by `index`
+        let mut stored = 0;
+        for (index, (content, meta)) in fragments.iter().enumerate() {
+            match self.storage.store_text_fragment(&document_id, stored, content, meta).await {
+                Ok(_) => stored += 1,
+                Err(e) => match e.downcast_ref::<PortableBrainsError>() {
+                    Some(PortableBrainsError::ValidationError(reason)) => {
+                        warn!("Skipping fragment {} of {}: {}", index, path.display(), reason);
+                    }
+                    _ => return Err(e.context(format!("Failed to store text fragment {}", index))),
+                },
+            }
+        }
+        storage::publish_document(&mut *self.storage, &document_id).await?;
+        Ok(document_id)
+    }
+
+    /// Embed every fragment still waiting for a vector. Returns the number embedded; fragments
+    /// the embedding server fails on are left for the next call.
+    pub async fn embed_pending(&mut self) -> Result<usize> {
+        let mut embedded: usize = 0;
+        loop {
+            let batch = storage::embed_fragment_batch(&mut *self.storage, &mut self.embedding_manager, EMBED_BATCH_SIZE).await?;
+            if batch.fragments == 0 {
+                storage::mark_embedded_documents(&mut *self.storage).await?;
+                let failed = self.storage.retry_embedding_failures().await?;
+                return Ok(embedded.saturating_sub(failed as usize));
+            }
+            embedded += batch.fragments as usize;
+        }
+    }
+
+    /// Add a document file and embed its fragments straight away. Returns its id, or `None`
+    /// when the file, or an identical one, is already indexed.
+    pub async fn index_file(&mut self, path: &Path) -> Result<Option<String>> {
+        let document_id = self.add_file(path).await?;
+        if document_id.is_some() {
+            self.embed_pending().await?;
+        }
+        Ok(document_id)
+    }
+
+    /// The underlying storage, e.g. to list or remove documents
+    pub fn storage(&mut self) -> &mut dyn Storage {
+        &mut *self.storage
+    }
+
+    /// Close the indexer, handing back its storage
+    pub fn into_storage(self) -> Box<dyn Storage> {
+        self.storage
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::SearchFilter;
+
+    #[tokio::test]
+    async fn test_indexer_stores_and_embeds() {
+        let storage = Box::new(crate::lancedb_storage::LanceDBStorage::new(Path::new("indexer")).await.unwrap());
+        let mut indexer = Indexer::new(storage, EmbeddingManager::hashing(64)).await.unwrap();
+
+        let text = "Invoices are due within thirty days.\n\nLate invoices accrue two percent interest per month.";
+        let document_id = indexer.add_document(Path::new("terms.txt"), text.as_bytes()).await.unwrap();
+        assert_eq!(indexer.embed_pending().await.unwrap(), 1);
+        assert_eq!(indexer.embed_pending().await.unwrap(), 0);
+
+        let storage = indexer.storage();
+        assert_eq!(storage.list_documents().await.unwrap()[0].id, document_id);
+        let embedding = storage.get_fragment_records(&document_id).await.unwrap()[0].embedding.clone().unwrap();
+        let hits = storage.search_similar(&embedding, 1, &SearchFilter::default()).await.unwrap();
+        assert_eq!(hits[0].source.filename, "terms.txt");
+    }
+}
@@ -0,0 +1,464 @@
+use anyhow::{Context, Result};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::task::JoinHandle;
+
+use crate::classifier::Classification;
+use crate::config::Routing;
+use crate::error::PortableBrainsError;
+use crate::quality::ExtractionQuality;
+use crate::storage::{self, content_hash, sidecar_path, FragmentMeta, Location, Storage, Structure};
+
+/// Documents written to a segment before it is sealed and handed to the committer
+const SEGMENT_DOCUMENTS: usize = 16;
+
+const OPEN_EXTENSION: &str = "open";
+const SEALED_EXTENSION: &str = "seg";
+
+/// A chunk of extracted text and where it came from
+#[derive(Debug, Clone)]
+pub struct StagedFragment {
+    pub content: String,
+    pub meta: FragmentMeta,
+}
+
+/// A document that has been extracted and chunked but not yet written to storage
+#[derive(Debug, Clone)]
+pub struct StagedDocument {
+    pub file_path: PathBuf,
+    pub priority: i32,
+    pub file_data: Vec<u8>,
+    pub fragments: Vec<StagedFragment>,
+    /// Category assigned by the classifier, if classification is enabled
+    pub category: Option<Classification>,
+    /// Collection and tags assigned by the routing rules
+    pub routing: Routing,
+    /// How cleanly the text was extracted; absent for records staged before scoring
+    pub quality: Option<ExtractionQuality>,
+    /// Title derived from the text; absent for records staged before titles were derived
+    pub title: Option<String>,
+    /// Extracted text as packed by `document_processor::pack_sections`, kept for re-chunking
+    pub text: Option<Vec<u8>>,
+}
+
+/// Record header; the raw file bytes follow it in the segment, then the packed text
+#[derive(Serialize, Deserialize)]
+struct RecordHeader {
+    #[serde(with = "crate::paths::lossless")]
+    file_path: PathBuf,
+    priority: i32,
+    fragments: Vec<String>,
+    /// Segment of each fragment, in the same order
+    #[serde(default)]
+    segments: Vec<u32>,
+    /// Section path of each fragment, in the same order
+    #[serde(default)]
+    sections: Vec<Option<String>>,
+    /// PDF page of each fragment, in the same order
+    #[serde(default)]
+    pages: Vec<Option<u32>>,
+    /// Block kind of each fragment kept whole, in the same order
+    #[serde(default)]
+    structures: Vec<Option<Structure>>,
+    /// Slide or sheet of each fragment, in the same order
+    #[serde(default)]
+    locations: Vec<Option<Location>>,
+    /// Byte range of each fragment in the extracted text, in the same order
+    #[serde(default)]
+    spans: Vec<Option<(usize, usize)>>,
+    #[serde(default)]
+    category: Option<(String, f64)>,
+    #[serde(default)]
+    collection: Option<String>,
+    #[serde(default)]
+    tags: Vec<String>,
+    #[serde(default)]
+    quality: Option<ExtractionQuality>,
+    #[serde(default)]
+    title: Option<String>,
+    data_len: u64,
+    #[serde(default)]
+    text_len: u64,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct CommitStats {
+    pub documents: usize,
+    pub fragments: usize,
+    pub skipped: usize,
+}
+
+/// Staging directory used for a database
+pub fn staging_dir(database: &Path) -> PathBuf {
+    sidecar_path(database, ".staging")
+}
+
+/// Whether a previous run left staged documents that were never committed
+pub fn has_pending(dir: &Path) -> Result<bool> {
+    if !dir.exists() {
+        return Ok(false);
+    }
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if is_segment(&path, SEALED_EXTENSION) || is_segment(&path, OPEN_EXTENSION) {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+fn is_segment(path: &Path, extension: &str) -> bool {
+    path.extension().and_then(|ext| ext.to_str()) == Some(extension)
+}
+
+/// Append-only staging area that extraction writes to at full speed.
+///
+/// Records go into an `.open` segment file; every `SEGMENT_DOCUMENTS` documents the
+/// segment is fsynced, renamed to `.seg` and sent to the committer. A segment is only
+/// deleted after all of its documents reached storage, so after a crash the remaining
+/// segments are simply replayed. A record cut short by the crash was never acknowledged
+/// and is dropped when the segment is read back.
+///
+/// Segments use a small length-prefixed format (u32 header length, JSON header, raw file
+/// bytes) rather than Arrow IPC, which would pull arrow into the build just for staging.
+pub struct IngestQueue {
+    dir: PathBuf,
+    current: Option<(PathBuf, BufWriter<File>)>,
+    documents_in_segment: usize,
+    next_sequence: u64,
+    sealed: UnboundedSender<PathBuf>,
+}
+
+impl IngestQueue {
+    /// Open the staging directory and return the queue, the receiving end for the
+    /// committer, and the number of segments left over from an earlier run.
+    ///
+    /// Leftover segments are queued for commit before anything staged by this run.
+    pub fn open(dir: &Path) -> Result<(Self, UnboundedReceiver<PathBuf>, usize)> {
+        fs::create_dir_all(dir)
+            .with_context(|| format!("Failed to create staging directory {}", dir.display()))?;
+
+        let mut pending = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if is_segment(&path, OPEN_EXTENSION) {
+                // Interrupted mid-segment: seal it as-is so its complete records are committed
+                let sealed = path.with_extension(SEALED_EXTENSION);
+                fs::rename(&path, &sealed)?;
+                pending.push(sealed);
+            } else if is_segment(&path, SEALED_EXTENSION) {
+                pending.push(path);
+            }
+        }
+        // Segment names start with a timestamp, so name order is staging order
+        pending.sort();
+
+        let (sender, receiver) = unbounded_channel();
+        let recovered = pending.len();
+        for segment in pending {
+            sender.send(segment)?;
+        }
+
+        let queue = IngestQueue {
+            dir: dir.to_path_buf(),
+            current: None,
+            documents_in_segment: 0,
+            next_sequence: 0,
+            sealed: sender,
+        };
+        Ok((queue, receiver, recovered))
+    }
+
+    /// Append a document to the current segment
+    pub fn stage(&mut self, document: &StagedDocument) -> Result<()> {
+        if self.current.is_none() {
+            let name = format!(
+                "segment-{:013}-{:06}.{}",
+                chrono::Utc::now().timestamp_millis(), self.next_sequence, OPEN_EXTENSION
+            );
+            self.next_sequence += 1;
+            let path = self.dir.join(name);
+            let file = OpenOptions::new().create_new(true).append(true).open(&path)
+                .with_context(|| format!("Failed to create staging segment {}", path.display()))?;
+            self.current = Some((path, BufWriter::new(file)));
+        }
+
+        let (_, writer) = self.current.as_mut().expect("segment opened above");
+        write_record(writer, document)?;
+        writer.flush()?;
+
+        self.documents_in_segment += 1;
+        if self.documents_in_segment >= SEGMENT_DOCUMENTS {
+            self.seal()?;
+        }
+        Ok(())
+    }
+
+    /// Make the current segment durable and hand it to the committer
+    fn seal(&mut self) -> Result<()> {
+        if let Some((path, writer)) = self.current.take() {
+            let file = writer.into_inner().map_err(|e| e.into_error())?;
+            file.sync_all()?;
+            let sealed = path.with_extension(SEALED_EXTENSION);
+            fs::rename(&path, &sealed)?;
+            debug!("Sealed staging segment {}", sealed.display());
+            self.sealed.send(sealed)
+                .map_err(|_| anyhow::anyhow!("Committer stopped before staging finished"))?;
+        }
+        self.documents_in_segment = 0;
+        Ok(())
+    }
+
+    /// Seal the last segment and close the queue so the committer can finish
+    pub fn finish(mut self) -> Result<()> {
+        self.seal()
+    }
+}
+
+fn write_record(writer: &mut impl Write, document: &StagedDocument) -> Result<()> {
+    let header = RecordHeader {
+        file_path: document.file_path.clone(),
+        priority: document.priority,
+        fragments: document.fragments.iter().map(|f| f.content.clone()).collect(),
+        segments: document.fragments.iter().map(|f| f.meta.segment).collect(),
+        sections: document.fragments.iter().map(|f| f.meta.section.clone()).collect(),
+        pages: document.fragments.iter().map(|f| f.meta.page).collect(),
+        structures: document.fragments.iter().map(|f| f.meta.structure).collect(),
+        locations: document.fragments.iter().map(|f| f.meta.location.clone()).collect(),
+        spans: document.fragments.iter().map(|f| f.meta.span).collect(),
+        category: document.category.as_ref().map(|c| (c.category.clone(), c.score)),
+        collection: document.routing.collection.clone(),
+        tags: document.routing.tags.clone(),
+        quality: document.quality.clone(),
+        title: document.title.clone(),
+        data_len: document.file_data.len() as u64,
+        text_len: document.text.as_ref().map_or(0, |text| text.len() as u64),
+    };
+    let header = serde_json::to_vec(&header)?;
+
+    writer.write_all(&(header.len() as u32).to_le_bytes())?;
+    writer.write_all(&header)?;
+    writer.write_all(&document.file_data)?;
+    if let Some(text) = &document.text {
+        writer.write_all(text)?;
+    }
+    Ok(())
+}
+
+/// Read the next record, or `None` at the end of the segment or at a truncated record
+fn read_record(reader: &mut impl Read) -> Result<Option<StagedDocument>> {
+    let mut length = [0u8; 4];
+    match reader.read_exact(&mut length) {
+        Ok(()) => {}
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+
+    let mut header = vec![0u8; u32::from_le_bytes(length) as usize];
+    if let Err(e) = reader.read_exact(&mut header) {
+        return if e.kind() == ErrorKind::UnexpectedEof { Ok(None) } else { Err(e.into()) };
+    }
+    let Ok(header) = serde_json::from_slice::<RecordHeader>(&header) else {
+        return Ok(None);
+    };
+
+    let mut file_data = vec![0u8; header.data_len as usize];
+    if let Err(e) = reader.read_exact(&mut file_data) {
+        return if e.kind() == ErrorKind::UnexpectedEof { Ok(None) } else { Err(e.into()) };
+    }
+
+    let mut text = vec![0u8; header.text_len as usize];
+    if let Err(e) = reader.read_exact(&mut text) {
+        return if e.kind() == ErrorKind::UnexpectedEof { Ok(None) } else { Err(e.into()) };
+    }
+
+    Ok(Some(StagedDocument {
+        file_path: header.file_path,
+        priority: header.priority,
+        file_data,
+        fragments: header.fragments.into_iter()
+            .enumerate()
+            .map(|(i, content)| StagedFragment {
+                content,
+                meta: FragmentMeta {
+                    segment: header.segments.get(i).copied().unwrap_or(0),
+                    section: header.sections.get(i).cloned().flatten(),
+                    page: header.pages.get(i).copied().flatten(),
+                    structure: header.structures.get(i).copied().flatten(),
+                    location: header.locations.get(i).cloned().flatten(),
+                    span: header.spans.get(i).copied().flatten(),
+                },
+            })
+            .collect(),
+        category: header.category.map(|(category, score)| Classification { category, score }),
+        routing: Routing {
+            collection: header.collection,
+            tags: header.tags,
+        },
+        quality: header.quality,
+        title: header.title,
+        text: (!text.is_empty()).then_some(text),
+    }))
+}
+
+fn read_segment(path: &Path) -> Result<Vec<StagedDocument>> {
+    let mut reader = BufReader::new(File::open(path)
+        .with_context(|| format!("Failed to open staging segment {}", path.display()))?);
+
+    let mut documents = Vec::new();
+    while let Some(document) = read_record(&mut reader)? {
+        documents.push(document);
+    }
+
+    // Anything left after the last complete record is a write cut short by a crash
+    let mut rest = Vec::new();
+    reader.read_to_end(&mut rest)?;
+    if !rest.is_empty() {
+        warn!("Dropping truncated record at the end of {}", path.display());
+    }
+
+    Ok(documents)
+}
+
+/// Write a staged document to storage. Returns the number of fragments stored, or
+/// `None` when the document is already present (e.g. a segment replayed after a crash)
+/// or an identical file is indexed at another path.
+pub async fn commit_document(storage: &mut dyn Storage, document: &StagedDocument) -> Result<Option<usize>> {
+    if storage.document_exists(&document.file_path).await? {
+        return Ok(None);
+    }
+    // The same file under two paths is stored and embedded once
+    if let Some(original) = storage.find_duplicate(&content_hash(&document.file_data)).await? {
+        info!("Skipping {}: identical to {}", document.file_path.display(), original);
+        return Ok(None);
+    }
+
+    let document_id = storage.store_document(&document.file_path, &document.file_data).await?;
+
+    if document.priority != 0 {
+        storage.set_document_priority(&document_id, document.priority).await?;
+    }
+
+    if let Some(classification) = &document.category {
+        storage.set_document_category(&document_id, &classification.category, classification.score).await?;
+    }
+
+    if document.routing != Routing::default() {
+        storage.set_document_collection(&document_id, document.routing.collection.as_deref(), &document.routing.tags).await?;
+    }
+
+    if let Some(quality) = &document.quality {
+        storage.set_document_quality(&document_id, quality.score, &quality.flags).await?;
+    }
+
+    // An embeddings-only database keeps nothing the text could be read back from
+    if !storage::embeddings_only(storage).await? {
+        if let Some(title) = &document.title {
+            storage.set_document_title(&document_id, title).await?;
+        }
+
+        if let Some(text) = &document.text {
+            storage.set_document_text(&document_id, text).await?;
+        }
+    }
+
+    // Fragments the storage layer rejects as invalid are skipped; anything else fails the document
+    let mut stored = 0;
+    for (index, fragment) in document.fragments.iter().enumerate() {
+        match storage.store_text_fragment(&document_id, stored as i32, &fragment.content, &fragment.meta).await {
+            Ok(_) => stored += 1,
+            Err(e) => match e.downcast_ref::<PortableBrainsError>() {
+                Some(PortableBrainsError::ValidationError(reason)) => {
+                    warn!("Skipping fragment {} of {}: {}", index, document.file_path.display(), reason);
+                }
+                _ => return Err(e.context(format!("Failed to store text fragment {}", index))),
+            },
+        }
+    }
+    // Until this point a crash leaves the document for `remove_interrupted` to clear away
+    storage::publish_document(storage, &document_id).await?;
+
+    Ok(Some(stored))
+}
+
+/// Run the background committer: move every sealed segment into storage, deleting it
+/// once committed, until the queue is finished. Hands the storage back when done.
+pub fn spawn_committer(
+    mut storage: Box<dyn Storage>,
+    mut segments: UnboundedReceiver<PathBuf>,
+) -> JoinHandle<Result<(Box<dyn Storage>, CommitStats)>> {
+    tokio::spawn(async move {
+        let mut stats = CommitStats::default();
+
+        while let Some(segment) = segments.recv().await {
+            for document in read_segment(&segment)? {
+                let committed = commit_document(&mut *storage, &document).await
+                    .with_context(|| format!("Failed to commit {}", document.file_path.display()))?;
+                match committed {
+                    Some(fragments) => {
+                        stats.documents += 1;
+                        stats.fragments += fragments;
+                    }
+                    None => stats.skipped += 1,
+                }
+            }
+
+            fs::remove_file(&segment)
+                .with_context(|| format!("Failed to remove committed segment {}", segment.display()))?;
+            info!("Committed staging segment {}", segment.display());
+        }
+
+        Ok((storage, stats))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn document(name: &str) -> StagedDocument {
+        StagedDocument {
+            file_path: PathBuf::from(name),
+            priority: 2,
+            file_data: b"raw bytes".to_vec(),
+            fragments: vec![
+                StagedFragment { content: "first".to_string(), meta: FragmentMeta::default() },
+                StagedFragment { content: "second".to_string(), meta: FragmentMeta { segment: 1, section: Some("Design > Security".to_string()), page: Some(7), location: Some(Location::Slide(3)), structure: Some(Structure::Code), span: Some((6, 12)) } },
+            ],
+            category: None,
+            routing: Routing {
+                collection: Some("finance".to_string()),
+                tags: vec!["billing".to_string()],
+            },
+            quality: None,
+            title: Some("Billing Runbook".to_string()),
+            text: Some(b"packed text".to_vec()),
+        }
+    }
+
+    #[test]
+    fn test_truncated_record_is_dropped() {
+        let mut buffer = Vec::new();
+        write_record(&mut buffer, &document("a.txt")).unwrap();
+        let complete = buffer.len();
+        write_record(&mut buffer, &document("b.txt")).unwrap();
+        buffer.truncate(complete + 10);
+
+        let mut reader = &buffer[..];
+        let first = read_record(&mut reader).unwrap().unwrap();
+        assert_eq!(first.file_path, PathBuf::from("a.txt"));
+        assert_eq!(first.fragments.len(), 2);
+        assert_eq!(first.fragments[1].meta.segment, 1);
+        assert_eq!(first.fragments[1].meta.section.as_deref(), Some("Design > Security"));
+        assert_eq!(first.fragments[1].meta.location, Some(Location::Slide(3)));
+        assert_eq!(first.file_data, b"raw bytes");
+        assert_eq!(first.text.as_deref(), Some(&b"packed text"[..]));
+        assert_eq!(first.routing.collection.as_deref(), Some("finance"));
+        assert_eq!(first.title.as_deref(), Some("Billing Runbook"));
+        assert!(read_record(&mut reader).unwrap().is_none());
+    }
+}
@@ -0,0 +1,422 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use utoipa::ToSchema;
+
+use crate::storage::sidecar_path;
+
+/// How often a running job looks for a cancellation request
+const CANCEL_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+/// Finished job records kept per database; older ones are pruned when a job is created
+const MAX_FINISHED_RECORDS: usize = 200;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum JobKind {
+    /// Extracting documents into the database (Phase 1, plus Phase 2 when requested)
+    Ingest,
+    /// Generating missing or stale embeddings (Phase 2)
+    Embed,
+}
+
+impl JobKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            JobKind::Ingest => "ingest",
+            JobKind::Embed => "embed",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum JobState {
+    Queued,
+    Running,
+    Completed,
+    Failed,
+    Cancelled,
+    /// The process running the job exited without finishing it
+    Interrupted,
+}
+
+impl JobState {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            JobState::Queued => "queued",
+            JobState::Running => "running",
+            JobState::Completed => "completed",
+            JobState::Failed => "failed",
+            JobState::Cancelled => "cancelled",
+            JobState::Interrupted => "interrupted",
+        }
+    }
+
+    pub fn is_finished(self) -> bool {
+        !matches!(self, JobState::Queued | JobState::Running)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ItemOutcome {
+    Pending,
+    Done,
+    /// Nothing to do, e.g. the document was already indexed
+    Skipped,
+    Failed,
+}
+
+/// One named unit of a job, such as a document sent to `POST /ingest`
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct JobItem {
+    pub name: String,
+    pub outcome: ItemOutcome,
+    /// What was done, or why the item failed
+    pub detail: Option<String>,
+}
+
+/// Persisted status of a long-running operation
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct JobRecord {
+    pub id: String,
+    pub kind: JobKind,
+    pub state: JobState,
+    /// Current phase, e.g. "extracting" or "embedding"
+    pub stage: Option<String>,
+    /// Units of the current stage done so far, out of `total`
+    pub processed: u64,
+    pub total: u64,
+    /// `processed` as a percentage of `total`
+    pub progress: f64,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub items: Vec<JobItem>,
+    pub error: Option<String>,
+    /// Tenant that submitted the job over the REST API; only it (and admin tokens) can see it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+    /// Process running the job
+    pub pid: u32,
+    pub created_at: String,
+    pub updated_at: String,
+}
+
+/// Job records of one database, kept as JSON files in `<database>.jobs`.
+///
+/// A job is cancelled by creating a marker file next to its record, so any process can
+/// cancel it without racing the runner's own writes.
+#[derive(Debug, Clone)]
+pub struct JobStore {
+    dir: PathBuf,
+}
+
+impl JobStore {
+    pub fn for_database(database: &Path) -> Self {
+        Self { dir: sidecar_path(database, ".jobs") }
+    }
+
+    /// Record a new queued job with the given items
+    pub fn create(&self, kind: JobKind, items: Vec<String>) -> Result<Job> {
+        self.create_for(kind, items, None)
+    }
+
+    /// Record a new queued job submitted on behalf of a tenant
+    pub fn create_for(&self, kind: JobKind, items: Vec<String>, tenant: Option<&str>) -> Result<Job> {
+        std::fs::create_dir_all(&self.dir)
+            .with_context(|| format!("Failed to create job directory {}", self.dir.display()))?;
+        self.prune()?;
+
+        let now = chrono::Utc::now().to_rfc3339();
+        let record = JobRecord {
+            id: uuid::Uuid::new_v4().to_string(),
+            kind,
+            state: JobState::Queued,
+            stage: None,
+            processed: 0,
+            total: items.len() as u64,
+            progress: 0.0,
+            items: items.into_iter()
+                .map(|name| JobItem { name, outcome: ItemOutcome::Pending, detail: None })
+                .collect(),
+            error: None,
+            tenant: tenant.map(str::to_string),
+            pid: std::process::id(),
+            created_at: now.clone(),
+            updated_at: now,
+        };
+        let mut job = Job {
+            store: self.clone(),
+            record,
+            last_cancel_check: None,
+            cancelled: false,
+        };
+        job.save()?;
+        Ok(job)
+    }
+
+    pub fn get(&self, id: &str) -> Result<Option<JobRecord>> {
+        // Ids are generated uuids; anything else can't name a record
+        if id.is_empty() || !id.chars().all(|c| c.is_ascii_hexdigit() || c == '-') {
+            return Ok(None);
+        }
+        let path = self.record_path(id);
+        if !path.exists() {
+            return Ok(None);
+        }
+        Ok(Some(with_liveness(load(&path)?)))
+    }
+
+    /// Every job, newest first
+    pub fn list(&self) -> Result<Vec<JobRecord>> {
+        if !self.dir.exists() {
+            return Ok(Vec::new());
+        }
+
+        let mut records = Vec::new();
+        for entry in std::fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == "json") {
+                match load(&path) {
+                    Ok(record) => records.push(with_liveness(record)),
+                    Err(e) => log::warn!("Skipping unreadable job record {}: {:#}", path.display(), e),
+                }
+            }
+        }
+        records.sort_by(|a, b| b.created_at.cmp(&a.created_at));
+        Ok(records)
+    }
+
+    /// Ask a queued or running job to stop. Returns the job, or `None` when there is no such job.
+    /// A job that has already finished is returned unchanged.
+    pub fn cancel(&self, id: &str) -> Result<Option<JobRecord>> {
+        let Some(record) = self.get(id)? else {
+            return Ok(None);
+        };
+        if !record.state.is_finished() {
+            std::fs::write(self.cancel_path(id), b"")
+                .with_context(|| format!("Failed to request cancellation of job {}", id))?;
+        }
+        Ok(Some(record))
+    }
+
+    fn record_path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", id))
+    }
+
+    fn cancel_path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{}.cancel", id))
+    }
+
+    /// Delete the oldest finished records beyond `MAX_FINISHED_RECORDS`
+    fn prune(&self) -> Result<()> {
+        let finished: Vec<JobRecord> = self.list()?.into_iter()
+            .filter(|record| record.state.is_finished())
+            .collect();
+        for record in finished.iter().skip(MAX_FINISHED_RECORDS) {
+            let _ = std::fs::remove_file(self.record_path(&record.id));
+            let _ = std::fs::remove_file(self.cancel_path(&record.id));
+        }
+        Ok(())
+    }
+}
+
+/// Handle held by whoever runs a job; every update is written through to its record
+pub struct Job {
+    store: JobStore,
+    record: JobRecord,
+    last_cancel_check: Option<Instant>,
+    cancelled: bool,
+}
+
+impl Job {
+    pub fn id(&self) -> &str {
+        &self.record.id
+    }
+
+    pub fn record(&self) -> &JobRecord {
+        &self.record
+    }
+
+    /// Start a stage of `total` units, marking the job running
+    pub fn set_stage(&mut self, stage: &str, total: u64) -> Result<()> {
+        self.record.state = JobState::Running;
+        self.record.stage = Some(stage.to_string());
+        self.record.processed = 0;
+        self.record.total = total;
+        self.save()
+    }
+
+    /// Count `units` of the current stage as done
+    pub fn advance(&mut self, units: u64) -> Result<()> {
+        self.record.processed += units;
+        self.save()
+    }
+
+    pub fn set_item(&mut self, index: usize, outcome: ItemOutcome, detail: Option<String>) -> Result<()> {
+        if let Some(item) = self.record.items.get_mut(index) {
+            item.outcome = outcome;
+            item.detail = detail;
+        }
+        self.save()
+    }
+
+    /// Record an item that wasn't known when the job was created, such as a file that
+    /// failed during a directory scan
+    pub fn add_item(&mut self, name: String, outcome: ItemOutcome, detail: Option<String>) -> Result<()> {
+        self.record.items.push(JobItem { name, outcome, detail });
+        self.save()
+    }
+
+    /// Whether cancellation was requested. Cheap to call often; the marker is only
+    /// looked for once per `CANCEL_CHECK_INTERVAL`.
+    pub fn is_cancelled(&mut self) -> bool {
+        if !self.cancelled && self.last_cancel_check.is_none_or(|at| at.elapsed() >= CANCEL_CHECK_INTERVAL) {
+            self.last_cancel_check = Some(Instant::now());
+            self.cancelled = self.store.cancel_path(&self.record.id).exists();
+        }
+        self.cancelled
+    }
+
+    /// Record how the job ended: cancelled if it was asked to stop, otherwise by `result`
+    pub fn finish<T>(mut self, result: &Result<T>) -> Result<()> {
+        self.record.state = match result {
+            Err(e) => {
+                self.record.error = Some(format!("{:#}", e));
+                JobState::Failed
+            }
+            Ok(_) if self.cancelled => JobState::Cancelled,
+            Ok(_) => JobState::Completed,
+        };
+        self.save()?;
+        let _ = std::fs::remove_file(self.store.cancel_path(&self.record.id));
+        Ok(())
+    }
+
+    fn save(&mut self) -> Result<()> {
+        let record = &mut self.record;
+        record.progress = if record.total == 0 {
+            if record.state == JobState::Completed { 100.0 } else { 0.0 }
+        } else {
+            (record.processed as f64 / record.total as f64 * 100.0).min(100.0)
+        };
+        record.updated_at = chrono::Utc::now().to_rfc3339();
+
+        // Write then rename so readers never see a half-written record
+        let path = self.store.record_path(&record.id);
+        let temp = path.with_extension("json.tmp");
+        std::fs::write(&temp, serde_json::to_vec_pretty(record)?)
+            .with_context(|| format!("Failed to write job record {}", temp.display()))?;
+        std::fs::rename(&temp, &path)
+            .with_context(|| format!("Failed to write job record {}", path.display()))?;
+        Ok(())
+    }
+}
+
+/// Items whose latest outcome across the ingest jobs `records`, newest first, is a failure;
+/// an item that failed once and was ingested by a later job isn't one
+pub fn failed_items(records: &[JobRecord]) -> Vec<JobItem> {
+    let mut seen = std::collections::HashSet::new();
+    let mut failed = Vec::new();
+    for record in records.iter().filter(|record| record.kind == JobKind::Ingest) {
+        for item in &record.items {
+            if item.outcome != ItemOutcome::Pending && seen.insert(item.name.clone()) && item.outcome == ItemOutcome::Failed {
+                failed.push(item.clone());
+            }
+        }
+    }
+    failed
+}
+
+fn load(path: &Path) -> Result<JobRecord> {
+    let data = std::fs::read(path)
+        .with_context(|| format!("Failed to read job record {}", path.display()))?;
+    serde_json::from_slice(&data)
+        .with_context(|| format!("Failed to parse job record {}", path.display()))
+}
+
+/// Report an unfinished job whose process has exited as interrupted
+fn with_liveness(mut record: JobRecord) -> JobRecord {
+    if !record.state.is_finished() && record.pid != std::process::id() && !process_alive(record.pid) {
+        record.state = JobState::Interrupted;
+    }
+    record
+}
+
+#[cfg(unix)]
+fn process_alive(pid: u32) -> bool {
+    // Signal 0 only checks the process exists; EPERM means it does but belongs to another user
+    let result = unsafe { libc::kill(pid as libc::pid_t, 0) };
+    result == 0 || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(not(unix))]
+fn process_alive(_pid: u32) -> bool {
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_store(name: &str) -> JobStore {
+        let dir = std::env::temp_dir().join(format!("pb-jobs-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        JobStore { dir }
+    }
+
+    #[test]
+    fn test_job_progress_is_persisted() {
+        let store = temp_store("progress");
+        let mut job = store.create(JobKind::Ingest, vec!["a.pdf".to_string(), "b.pdf".to_string()]).unwrap();
+        job.set_stage("extracting", 2).unwrap();
+        job.advance(1).unwrap();
+        job.set_item(0, ItemOutcome::Done, Some("12 fragments".to_string())).unwrap();
+
+        let record = store.get(job.id()).unwrap().unwrap();
+        assert_eq!(record.state, JobState::Running);
+        assert_eq!(record.progress, 50.0);
+        assert_eq!(record.items[0].outcome, ItemOutcome::Done);
+
+        let id = job.id().to_string();
+        job.finish(&Ok(())).unwrap();
+        assert_eq!(store.get(&id).unwrap().unwrap().state, JobState::Completed);
+        assert!(store.get("../etc/passwd").unwrap().is_none());
+        let _ = std::fs::remove_dir_all(&store.dir);
+    }
+
+    #[test]
+    fn test_failed_items_are_the_latest_failures() {
+        let store = temp_store("failed");
+        let mut job = store.create(JobKind::Ingest, Vec::new()).unwrap();
+        job.add_item("a.pdf".to_string(), ItemOutcome::Failed, Some("no text".to_string())).unwrap();
+        job.add_item("b.pdf".to_string(), ItemOutcome::Failed, Some("database is locked".to_string())).unwrap();
+        let older = store.get(job.id()).unwrap().unwrap();
+        let newer = JobRecord {
+            items: vec![JobItem { name: "b.pdf".to_string(), outcome: ItemOutcome::Done, detail: None }],
+            ..older.clone()
+        };
+
+        let failed = failed_items(&[newer, older]);
+        assert_eq!(failed.iter().map(|item| item.name.as_str()).collect::<Vec<_>>(), vec!["a.pdf"]);
+        assert_eq!(failed[0].detail.as_deref(), Some("no text"));
+        let _ = std::fs::remove_dir_all(&store.dir);
+    }
+
+    #[test]
+    fn test_cancellation_request() {
+        let store = temp_store("cancel");
+        let mut job = store.create(JobKind::Embed, Vec::new()).unwrap();
+        assert!(!job.is_cancelled());
+
+        store.cancel(job.id()).unwrap().unwrap();
+        job.last_cancel_check = None;
+        assert!(job.is_cancelled());
+
+        let id = job.id().to_string();
+        job.finish(&Ok(())).unwrap();
+        assert_eq!(store.get(&id).unwrap().unwrap().state, JobState::Cancelled);
+        assert_eq!(store.list().unwrap().len(), 1);
+        let _ = std::fs::remove_dir_all(&store.dir);
+    }
+}
@@ -1,11 +1,17 @@
 use anyhow::{Context, Result};
 use async_trait::async_trait;
 use std::path::Path;
-use uuid::Uuid;
+use std::sync::Arc;
 use log::{info, warn};
 use chrono;
 
-use crate::storage::{Storage, MetaInfo};
+use crate::annotations::{Annotation, AnnotationLabel};
+use crate::entities;
+use crate::hybrid::bm25;
+use crate::paths::StoredPath;
+use crate::storage::arrow::array::{Array, ArrayRef, BooleanArray, Float32Array, Int32Array, ListArray, RecordBatch, StringArray};
+use crate::storage::arrow::datatypes::Float32Type;
+use crate::storage::{check_dimension, chunk_hash, conform_fragment_batch, content_hash, decode_original, fragment_column, fragment_schema, modified_micros, parse_dimension, sort_file_types, sort_largest, sort_ranked, validate_fragment, CorpusStats, DocumentAttributes, DocumentInfo, DocumentSize, DocumentSummary, EntityCount, EntityMention, FragmentChanges, FragmentMatch, FragmentMeta, FragmentRecord, FragmentSource, IdScheme, IndexState, Location, OriginalsMode, PendingFragment, Storage, MetaInfo, SearchFilter, SimilarityMetric, Structure, DETERMINISTIC_IDS_KEY, DIMENSION_KEY, PROVIDER_KEY, SIMILARITY_METRIC_KEY, STORE_ORIGINALS_KEY};
 
 const DB_VERSION: &str = "1.0.0";
 
@@ -15,7 +21,29 @@ pub struct LanceDBStorage {
     metadata: std::collections::HashMap<String, String>,
     documents: std::collections::HashMap<String, (String, Vec<u8>)>, // id -> (path, data)
     fragments: std::collections::HashMap<String, (String, i32, String)>, // id -> (doc_id, order, content)
+    sections: std::collections::HashMap<String, String>, // fragment_id -> section path
+    pages: std::collections::HashMap<String, u32>, // fragment_id -> PDF page
+    structures: std::collections::HashMap<String, Structure>, // fragment_id -> block kept whole
+    locations: std::collections::HashMap<String, Location>, // fragment_id -> slide or sheet
+    texts: std::collections::HashMap<String, Vec<u8>>, // document_id -> compressed extracted text
+    encodings: std::collections::HashMap<String, &'static str>, // document_id -> how its original is encoded
     embeddings: std::collections::HashMap<String, Vec<f32>>, // fragment_id -> embedding_vector
+    priorities: std::collections::HashMap<String, i32>, // document_id -> embedding priority
+    categories: std::collections::HashMap<String, (String, f64)>, // document_id -> (category, score)
+    collections: std::collections::HashMap<String, (Option<String>, Vec<String>)>, // document_id -> (collection, tags)
+    quality: std::collections::HashMap<String, (f64, Vec<String>)>, // document_id -> (score, flags)
+    titles: std::collections::HashMap<String, String>, // document_id -> derived title
+    stale: std::collections::HashSet<String>, // fragment_ids with vectors from a previous model
+    tombstoned: std::collections::HashSet<String>, // document_ids whose source file is gone
+    states: std::collections::HashMap<String, IndexState>, // document_id -> how far indexing got
+    sources: std::collections::HashMap<String, (String, Option<i64>)>, // document_id -> (content hash, modified)
+    hits: std::collections::HashMap<String, u32>, // fragment_id -> times returned by search
+    failures: std::collections::HashMap<String, (String, i32)>, // fragment_id -> (embedding error, attempts)
+    set_aside: std::collections::HashSet<String>, // failed fragment_ids skipped until retried
+    annotations: Vec<Annotation>, // oldest first
+    ids: IdScheme,
+    originals: OriginalsMode,
+    metric: SimilarityMetric,
 }
 
 impl LanceDBStorage {
@@ -35,7 +63,29 @@ impl LanceDBStorage {
             metadata: std::collections::HashMap::new(),
             documents: std::collections::HashMap::new(),
             fragments: std::collections::HashMap::new(),
+            sections: std::collections::HashMap::new(),
+            pages: std::collections::HashMap::new(),
+            structures: std::collections::HashMap::new(),
+            locations: std::collections::HashMap::new(),
+            texts: std::collections::HashMap::new(),
+            encodings: std::collections::HashMap::new(),
             embeddings: std::collections::HashMap::new(),
+            priorities: std::collections::HashMap::new(),
+            categories: std::collections::HashMap::new(),
+            collections: std::collections::HashMap::new(),
+            quality: std::collections::HashMap::new(),
+            titles: std::collections::HashMap::new(),
+            stale: std::collections::HashSet::new(),
+            tombstoned: std::collections::HashSet::new(),
+            states: std::collections::HashMap::new(),
+            sources: std::collections::HashMap::new(),
+            hits: std::collections::HashMap::new(),
+            failures: std::collections::HashMap::new(),
+            set_aside: std::collections::HashSet::new(),
+            annotations: Vec::new(),
+            ids: IdScheme::Random,
+            originals: OriginalsMode::Full,
+            metric: SimilarityMetric::Cosine,
         };
         
         storage.initialize().await?;
@@ -46,6 +96,81 @@ impl LanceDBStorage {
     fn current_timestamp() -> String {
         chrono::Utc::now().to_rfc3339()
     }
+
+    /// A stored fragment as a search result with `score`
+    fn fragment_match(&self, fragment_id: &str, score: f64) -> FragmentMatch {
+        let (doc_id, order, content) = &self.fragments[fragment_id];
+        let file_path = self.documents.get(doc_id).map(|(path, _)| path.clone()).unwrap_or_default();
+        let filename = Path::new(&file_path)
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_else(|| "unknown".to_string());
+        FragmentMatch {
+            fragment_id: fragment_id.to_string(),
+            content: content.clone(),
+            score,
+            source: FragmentSource {
+                document_id: doc_id.clone(),
+                filename,
+                file_path,
+                title: self.titles.get(doc_id).cloned(),
+                order: *order,
+                section: self.sections.get(fragment_id).cloned(),
+                page: self.pages.get(fragment_id).copied(),
+                structure: self.structures.get(fragment_id).copied(),
+                location: self.locations.get(fragment_id).cloned(),
+                modified: self.sources.get(doc_id).and_then(|(_, modified)| *modified),
+                indexed: None,
+                annotations: self.annotations.iter()
+                    .filter(|annotation| annotation.target == fragment_id || annotation.target == *doc_id)
+                    .map(Annotation::summary)
+                    .collect(),
+            },
+        }
+    }
+
+    /// Whether a reviewer marked `target` wrong
+    fn marked_wrong(&self, target: &str) -> bool {
+        self.annotations.iter().any(|annotation| annotation.target == target && annotation.label == Some(AnnotationLabel::Wrong))
+    }
+
+    /// Whether a search with `filter` may return this fragment, whatever its vector
+    fn searchable(&self, fragment_id: &str, doc_id: &str, filter: &SearchFilter) -> bool {
+        !self.tombstoned.contains(doc_id)
+            && !self.marked_wrong(doc_id)
+            && !self.marked_wrong(fragment_id)
+            && (filter.categories.is_empty()
+                || self.categories.get(doc_id)
+                    .is_some_and(|(category, _)| filter.categories.contains(category)))
+            && (filter.collections.is_empty()
+                || self.collections.get(doc_id)
+                    .and_then(|(collection, _)| collection.as_ref())
+                    .is_some_and(|collection| filter.collections.contains(collection)))
+            && filter.document.as_ref().is_none_or(|document| document == doc_id)
+            && filter.section.as_ref().is_none_or(|wanted| {
+                self.sections.get(fragment_id)
+                    .is_some_and(|section| section.to_lowercase().contains(&wanted.to_lowercase()))
+            })
+            && filter.entity.as_ref().is_none_or(|wanted| {
+                let wanted = entities::key(wanted);
+                self.fragments.get(fragment_id).is_some_and(|(_, _, content)| {
+                    entities::extract(content).iter().any(|name| entities::matches(&entities::key(name), &wanted))
+                })
+            })
+            && filter.matches_document(
+                self.collections.get(doc_id).map_or(&[], |(_, tags)| tags.as_slice()),
+                self.documents.get(doc_id).map_or("", |(path, _)| path.as_str()),
+                self.sources.get(doc_id).and_then(|(_, modified)| *modified),
+            )
+    }
+
+    /// Entities named by each fragment of a searchable document, extracted afresh on every
+    /// lookup like BM25 scores: (document id, entity names)
+    fn fragment_entities(&self) -> impl Iterator<Item = (&String, Vec<String>)> {
+        self.fragments.values()
+            .filter(|(doc_id, _, _)| !self.tombstoned.contains(doc_id))
+            .map(|(doc_id, _, content)| (doc_id, entities::extract(content)))
+    }
 }
 
 #[async_trait]
@@ -58,7 +183,7 @@ impl Storage for LanceDBStorage {
         Ok(())
     }
 
-    async fn verify_or_set_model(&mut self, model_name: &str) -> Result<()> {
+    async fn verify_or_set_model(&mut self, model_name: &str, dimension: Option<usize>) -> Result<()> {
         // Check version
         if let Some(existing_version) = self.metadata.get("version") {
             if existing_version != DB_VERSION {
@@ -86,34 +211,423 @@ impl Storage for LanceDBStorage {
             info!("Set embedding model to {}", model_name);
         }
 
+        if let Some(dimension) = dimension {
+            check_dimension(self, model_name, dimension).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn enable_deterministic_ids(&mut self) -> Result<()> {
+        self.metadata.insert(DETERMINISTIC_IDS_KEY.to_string(), "true".to_string());
+        self.ids = IdScheme::Content;
+        Ok(())
+    }
+
+    async fn set_originals_mode(&mut self, mode: OriginalsMode) -> Result<()> {
+        self.metadata.insert(STORE_ORIGINALS_KEY.to_string(), mode.name().to_string());
+        self.originals = mode;
+        Ok(())
+    }
+
+    async fn set_similarity_metric(&mut self, metric: SimilarityMetric) -> Result<()> {
+        self.metadata.insert(SIMILARITY_METRIC_KEY.to_string(), metric.name().to_string());
+        self.metric = metric;
         Ok(())
     }
 
     async fn document_exists(&mut self, file_path: &Path) -> Result<bool> {
-        let path_str = file_path.to_string_lossy();
-        Ok(self.documents.values().any(|(path, _)| path == &path_str))
+        let path = StoredPath::new(file_path);
+        Ok(self.documents.values().any(|(key, _)| key == &path.key || key == &path.legacy_key))
+    }
+
+    async fn find_duplicate(&mut self, content_hash: &str) -> Result<Option<String>> {
+        Ok(self.sources.iter()
+            .filter(|(id, (hash, _))| hash == content_hash && !self.tombstoned.contains(*id))
+            .filter_map(|(id, _)| self.documents.get(id).map(|(path, _)| path.clone()))
+            .min())
     }
 
     async fn store_document(&mut self, file_path: &Path, file_data: &[u8]) -> Result<String> {
-        let document_id = Uuid::new_v4().to_string();
-        let path_str = file_path.to_string_lossy().to_string();
+        let path = StoredPath::new(file_path);
+        let document_id = self.ids.document_id(&path.filename, file_data);
         
-        self.documents.insert(document_id.clone(), (path_str, file_data.to_vec()));
+        let (blob, encoding) = self.originals.encode(file_data)?;
+        self.documents.insert(document_id.clone(), (path.key, blob));
+        match encoding {
+            Some(encoding) => self.encodings.insert(document_id.clone(), encoding),
+            None => self.encodings.remove(&document_id),
+        };
+        self.sources.insert(document_id.clone(), (content_hash(file_data), modified_micros(file_path)));
+        self.states.insert(document_id.clone(), IndexState::Stored);
         
         Ok(document_id)
     }
 
+    async fn set_index_state(&mut self, document_id: &str, state: IndexState) -> Result<()> {
+        if self.documents.contains_key(document_id) {
+            self.states.insert(document_id.to_string(), state);
+        }
+        Ok(())
+    }
+
+    async fn list_index_state(&mut self, state: IndexState) -> Result<Vec<String>> {
+        let mut ids: Vec<String> = self.states.iter()
+            .filter(|(_, document_state)| **document_state == state)
+            .map(|(id, _)| id.clone())
+            .collect();
+        ids.sort();
+        Ok(ids)
+    }
+
+    async fn set_document_source(&mut self, document_id: &str, content_hash: &str, modified: Option<i64>) -> Result<()> {
+        self.sources.insert(document_id.to_string(), (content_hash.to_string(), modified));
+        Ok(())
+    }
+
+    async fn set_document_priority(&mut self, document_id: &str, priority: i32) -> Result<()> {
+        self.priorities.insert(document_id.to_string(), priority);
+        Ok(())
+    }
+
+    async fn set_document_category(&mut self, document_id: &str, category: &str, score: f64) -> Result<()> {
+        self.categories.insert(document_id.to_string(), (category.to_string(), score));
+        Ok(())
+    }
+
+    async fn set_document_collection(&mut self, document_id: &str, collection: Option<&str>, tags: &[String]) -> Result<()> {
+        self.collections.insert(document_id.to_string(), (collection.map(str::to_string), tags.to_vec()));
+        Ok(())
+    }
+
+    async fn get_document_collection(&mut self, document_id: &str) -> Result<Option<String>> {
+        Ok(self.collections.get(document_id).and_then(|(collection, _)| collection.clone()))
+    }
+
+    async fn get_document_attributes(&mut self, document_id: &str) -> Result<Option<DocumentAttributes>> {
+        if !self.documents.contains_key(document_id) {
+            return Ok(None);
+        }
+        let (collection, tags) = self.collections.get(document_id).cloned().unwrap_or_default();
+        Ok(Some(DocumentAttributes {
+            priority: self.priorities.get(document_id).copied().unwrap_or(0),
+            category: self.categories.get(document_id).cloned(),
+            collection,
+            tags,
+        }))
+    }
+
+    async fn set_document_quality(&mut self, document_id: &str, score: f64, flags: &[String]) -> Result<()> {
+        self.quality.insert(document_id.to_string(), (score, flags.to_vec()));
+        Ok(())
+    }
+
+    async fn set_document_title(&mut self, document_id: &str, title: &str) -> Result<()> {
+        self.titles.insert(document_id.to_string(), title.to_string());
+        Ok(())
+    }
+
+    async fn tombstone_document(&mut self, document_id: &str, tombstoned: bool) -> Result<()> {
+        if tombstoned {
+            self.tombstoned.insert(document_id.to_string());
+        } else {
+            self.tombstoned.remove(document_id);
+        }
+        Ok(())
+    }
+
+    async fn remove_document(&mut self, document_id: &str) -> Result<()> {
+        let fragment_ids: Vec<String> = self.fragments.iter()
+            .filter(|(_, (doc_id, _, _))| doc_id == document_id)
+            .map(|(id, _)| id.clone())
+            .collect();
+        self.annotations.retain(|annotation| annotation.target != document_id && !fragment_ids.contains(&annotation.target));
+        for id in &fragment_ids {
+            self.fragments.remove(id);
+            self.sections.remove(id);
+            self.pages.remove(id);
+            self.structures.remove(id);
+            self.locations.remove(id);
+            self.embeddings.remove(id);
+            self.stale.remove(id);
+            self.hits.remove(id);
+        }
+        
+        self.documents.remove(document_id);
+        self.texts.remove(document_id);
+        self.encodings.remove(document_id);
+        self.priorities.remove(document_id);
+        self.categories.remove(document_id);
+        self.collections.remove(document_id);
+        self.quality.remove(document_id);
+        self.titles.remove(document_id);
+        self.tombstoned.remove(document_id);
+        self.sources.remove(document_id);
+        self.states.remove(document_id);
+        Ok(())
+    }
+
+    async fn list_documents(&mut self) -> Result<Vec<DocumentSummary>> {
+        let mut documents: Vec<DocumentSummary> = self.documents.iter()
+            .map(|(id, (path, _))| {
+                let quality = self.quality.get(id);
+                let source = self.sources.get(id);
+                DocumentSummary {
+                    id: id.clone(),
+                    filename: Path::new(path).file_name()
+                        .map(|name| name.to_string_lossy().to_string())
+                        .unwrap_or_else(|| "unknown".to_string()),
+                    file_path: path.clone(),
+                    fragments: self.fragments.values().filter(|(doc_id, _, _)| doc_id == id).count() as i32,
+                    quality_score: quality.map(|(score, _)| *score),
+                    quality_flags: quality.map(|(_, flags)| flags.clone()).unwrap_or_default(),
+                    tombstoned: self.tombstoned.contains(id),
+                    content_hash: source.map(|(hash, _)| hash.clone()),
+                    modified: source.and_then(|(_, modified)| *modified),
+                    title: self.titles.get(id).cloned(),
+                }
+            })
+            .collect();
+        documents.sort_by(|a, b| a.file_path.cmp(&b.file_path));
+        Ok(documents)
+    }
+
+    async fn get_document(&mut self, document_id: &str) -> Result<Option<DocumentInfo>> {
+        let Some((path, data)) = self.documents.get(document_id) else {
+            return Ok(None);
+        };
+        let file_data = decode_original(self.encodings.get(document_id).copied(), data.clone())?;
+        let path = Path::new(path);
+        Ok(Some(DocumentInfo {
+            id: document_id.to_string(),
+            filename: path.file_name()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_else(|| "unknown".to_string()),
+            file_path: path.to_string_lossy().to_string(),
+            file_type: path.extension()
+                .map(|ext| ext.to_string_lossy().to_lowercase())
+                .unwrap_or_else(|| "unknown".to_string()),
+            file_data,
+            created_at: None,
+        }))
+    }
+
+    async fn set_document_text(&mut self, document_id: &str, text: &[u8]) -> Result<()> {
+        self.texts.insert(document_id.to_string(), text.to_vec());
+        Ok(())
+    }
+
+    async fn get_document_text(&mut self, document_id: &str) -> Result<Option<Vec<u8>>> {
+        Ok(self.texts.get(document_id).cloned())
+    }
+
+    async fn replace_fragments(
+        &mut self,
+        document_id: &str,
+        fragments: &[(String, FragmentMeta)],
+    ) -> Result<FragmentChanges> {
+        // Existing fragments by text, lowest order popped first, so unchanged chunks keep
+        // their id and vector
+        let mut existing: Vec<(&String, &(String, i32, String))> = self.fragments.iter()
+            .filter(|(_, (doc_id, _, _))| doc_id == document_id)
+            .collect();
+        existing.sort_by_key(|(_, (_, order, _))| std::cmp::Reverse(*order));
+        let mut by_content: std::collections::HashMap<String, Vec<String>> = std::collections::HashMap::new();
+        for (id, (_, _, content)) in existing {
+            by_content.entry(content.clone()).or_default().push(id.clone());
+        }
+        
+        let mut changes = FragmentChanges::default();
+        let mut placements = Vec::new();
+        for (order, (content, meta)) in fragments.iter().enumerate() {
+            let reused = by_content.get_mut(content).and_then(|ids| ids.pop());
+            placements.push((order as i32, content, meta, reused));
+        }
+        
+        for id in by_content.into_values().flatten() {
+            self.fragments.remove(&id);
+            self.sections.remove(&id);
+            self.pages.remove(&id);
+            self.structures.remove(&id);
+            self.locations.remove(&id);
+            self.embeddings.remove(&id);
+            self.stale.remove(&id);
+            self.hits.remove(&id);
+            changes.removed += 1;
+        }
+        
+        for (order, content, meta, reused) in placements {
+            match reused {
+                Some(id) => {
+                    if let Some(fragment) = self.fragments.get_mut(&id) {
+                        fragment.1 = order;
+                    }
+                    match &meta.section {
+                        Some(section) => self.sections.insert(id.clone(), section.clone()),
+                        None => self.sections.remove(&id),
+                    };
+                    match meta.page {
+                        Some(page) => self.pages.insert(id.clone(), page),
+                        None => self.pages.remove(&id),
+                    };
+                    match meta.structure {
+                        Some(structure) => self.structures.insert(id.clone(), structure),
+                        None => self.structures.remove(&id),
+                    };
+                    match &meta.location {
+                        Some(location) => self.locations.insert(id, location.clone()),
+                        None => self.locations.remove(&id),
+                    };
+                    changes.kept += 1;
+                }
+                None => {
+                    self.store_text_fragment(document_id, order, content, meta).await?;
+                    changes.added += 1;
+                }
+            }
+        }
+        
+        Ok(changes)
+    }
+
+    async fn get_document_fragments(&mut self, document_id: &str, offset: i32, limit: i32) -> Result<Vec<String>> {
+        let mut fragments: Vec<&(String, i32, String)> = self.fragments.values()
+            .filter(|(doc_id, _, _)| doc_id == document_id)
+            .collect();
+        fragments.sort_by_key(|(_, order, _)| *order);
+        
+        Ok(fragments.into_iter()
+            .skip(offset.max(0) as usize)
+            .take(limit.max(0) as usize)
+            .map(|(_, _, content)| content.clone())
+            .collect())
+    }
+
+    async fn get_fragment_records(&mut self, document_id: &str) -> Result<Vec<FragmentRecord>> {
+        let mut fragments: Vec<FragmentRecord> = self.fragments.iter()
+            .filter(|(_, (doc_id, _, _))| doc_id == document_id)
+            .map(|(id, (_, order, content))| FragmentRecord {
+                id: id.clone(),
+                order: *order,
+                section: self.sections.get(id).cloned(),
+                content: content.clone(),
+                embedding: self.embeddings.get(id).map(|embedding| embedding.iter().map(|&x| x as f64).collect()),
+                stale: self.stale.contains(id),
+            })
+            .collect();
+        fragments.sort_by_key(|fragment| fragment.order);
+        Ok(fragments)
+    }
+
+    async fn read_fragment_batch(&mut self, after: Option<&str>, limit: usize) -> Result<Option<RecordBatch>> {
+        let mut ids: Vec<&String> = self.fragments.keys()
+            .filter(|id| after.is_none_or(|after| id.as_str() > after))
+            .collect();
+        ids.sort();
+        ids.truncate(limit);
+        if ids.is_empty() {
+            return Ok(None);
+        }
+        
+        let fragments: Vec<&(String, i32, String)> = ids.iter().map(|id| &self.fragments[*id]).collect();
+        let columns: Vec<ArrayRef> = vec![
+            Arc::new(StringArray::from_iter_values(ids.iter())),
+            Arc::new(StringArray::from_iter_values(fragments.iter().map(|(doc_id, _, _)| doc_id))),
+            Arc::new(Int32Array::from_iter_values(fragments.iter().map(|(_, order, _)| *order))),
+            // Segments aren't kept by the in-memory store
+            Arc::new(Int32Array::from(vec![0; ids.len()])),
+            Arc::new(ids.iter().map(|id| self.sections.get(*id)).collect::<StringArray>()),
+            Arc::new(ids.iter().map(|id| self.pages.get(*id).map(|&page| page as i32)).collect::<Int32Array>()),
+            Arc::new(ids.iter().map(|id| self.structures.get(*id).map(|s| s.as_str())).collect::<StringArray>()),
+            Arc::new(ids.iter().map(|id| self.locations.get(*id).map(Location::label)).collect::<StringArray>()),
+            Arc::new(StringArray::from_iter_values(fragments.iter().map(|(_, _, content)| content))),
+            Arc::new(ListArray::from_iter_primitive::<Float32Type, _, _>(
+                ids.iter().map(|id| self.embeddings.get(*id).map(|embedding| embedding.iter().copied().map(Some))),
+            )),
+            Arc::new(BooleanArray::from(ids.iter().map(|id| self.stale.contains(*id)).collect::<Vec<_>>())),
+        ];
+        Ok(Some(RecordBatch::try_new(fragment_schema(), columns)?))
+    }
+
+    async fn write_fragment_batch(&mut self, batch: &RecordBatch) -> Result<usize> {
+        let batch = conform_fragment_batch(batch)?;
+        let ids = fragment_column::<StringArray>(&batch, "id")?;
+        let document_ids = fragment_column::<StringArray>(&batch, "document_id")?;
+        let orders = fragment_column::<Int32Array>(&batch, "fragment_order")?;
+        let sections = fragment_column::<StringArray>(&batch, "section")?;
+        let pages = fragment_column::<Int32Array>(&batch, "page")?;
+        let structures = fragment_column::<StringArray>(&batch, "structure")?;
+        let locations = fragment_column::<StringArray>(&batch, "location")?;
+        let contents = fragment_column::<StringArray>(&batch, "content")?;
+        let embeddings = fragment_column::<ListArray>(&batch, "embedding")?;
+        let stale = fragment_column::<BooleanArray>(&batch, "stale")?;
+        
+        for row in 0..batch.num_rows() {
+            let id = ids.value(row).to_string();
+            let document_id = document_ids.value(row);
+            if !self.documents.contains_key(document_id) {
+                anyhow::bail!("Fragment {} belongs to unknown document {}", id, document_id);
+            }
+            if sections.is_valid(row) {
+                self.sections.insert(id.clone(), sections.value(row).to_string());
+            }
+            if pages.is_valid(row) {
+                self.pages.insert(id.clone(), pages.value(row) as u32);
+            }
+            let structure = structures.is_valid(row).then(|| structures.value(row)).and_then(Structure::parse);
+            if let Some(structure) = structure {
+                self.structures.insert(id.clone(), structure);
+            }
+            let location = locations.is_valid(row).then(|| locations.value(row)).and_then(Location::parse);
+            if let Some(location) = location {
+                self.locations.insert(id.clone(), location);
+            }
+            if embeddings.is_valid(row) {
+                let embedding = embeddings.value(row);
+                let values = embedding.as_any().downcast_ref::<Float32Array>()
+                    .ok_or_else(|| anyhow::anyhow!("Fragment {} has a malformed embedding", id))?;
+                self.embeddings.insert(id.clone(), values.values().to_vec());
+            }
+            if stale.value(row) {
+                self.stale.insert(id.clone());
+            }
+            self.fragments.insert(id, (document_id.to_string(), orders.value(row), contents.value(row).to_string()));
+        }
+        Ok(batch.num_rows())
+    }
+
     async fn store_text_fragment(
         &mut self,
         document_id: &str,
         order: i32,
         content: &str,
+        meta: &FragmentMeta,
     ) -> Result<String> {
-        let fragment_id = Uuid::new_v4().to_string();
+        // Segments aren't used by the in-memory store; the rest of the meta is kept
+        let content = validate_fragment(content)?;
+        let spanned = meta.span
+            .filter(|_| self.ids == IdScheme::Content)
+            .zip(self.sources.get(document_id))
+            .map(|(span, (hash, _))| IdScheme::span_fragment_id(hash, span))
+            .filter(|id| !self.fragments.contains_key(id));
+        let fragment_id = spanned.unwrap_or_else(|| self.ids.fragment_id(document_id, order, &content));
+        
+        if let Some(section) = &meta.section {
+            self.sections.insert(fragment_id.clone(), section.clone());
+        }
+        if let Some(page) = meta.page {
+            self.pages.insert(fragment_id.clone(), page);
+        }
+        if let Some(structure) = meta.structure {
+            self.structures.insert(fragment_id.clone(), structure);
+        }
+        if let Some(location) = &meta.location {
+            self.locations.insert(fragment_id.clone(), location.clone());
+        }
         
         self.fragments.insert(
             fragment_id.clone(), 
-            (document_id.to_string(), order, content.to_string())
+            (document_id.to_string(), order, content.into_owned())
         );
         
         Ok(fragment_id)
@@ -127,30 +641,219 @@ impl Storage for LanceDBStorage {
         // Store the embedding in our in-memory HashMap
         let embedding_f32: Vec<f32> = embedding.iter().map(|&x| x as f32).collect();
         self.embeddings.insert(fragment_id.to_string(), embedding_f32);
+        self.stale.remove(fragment_id);
+        self.failures.remove(fragment_id);
+        self.set_aside.remove(fragment_id);
+        Ok(())
+    }
+
+    async fn update_fragment_embeddings_batch(&mut self, embeddings: &[(String, Vec<f32>)]) -> Result<()> {
+        for (fragment_id, embedding) in embeddings {
+            self.embeddings.insert(fragment_id.clone(), embedding.clone());
+            self.stale.remove(fragment_id);
+            self.failures.remove(fragment_id);
+            self.set_aside.remove(fragment_id);
+        }
+        Ok(())
+    }
+
+    async fn clear_fragment_text(&mut self, fragment_ids: &[String]) -> Result<()> {
+        for fragment_id in fragment_ids {
+            if let Some((_, _, content)) = self.fragments.get_mut(fragment_id) {
+                content.clear();
+            }
+        }
         Ok(())
     }
 
-    async fn get_fragments_without_embeddings(&mut self, limit: i32) -> Result<Vec<(String, String)>> {
-        // Return only fragments that don't have embeddings yet
-        let fragments: Vec<(String, String)> = self.fragments
+    async fn get_pending_fragments(&mut self, limit: i32) -> Result<Vec<PendingFragment>> {
+        // Return fragments that need embeddings, highest priority first; missing vectors
+        // come before stale ones, which are replaced most-queried first
+        let mut pending: Vec<(&String, &(String, i32, String))> = self.fragments
             .iter()
-            .filter(|(id, _)| !self.embeddings.contains_key(*id))
+            .filter(|(id, _)| !self.embeddings.contains_key(*id) || self.stale.contains(*id))
+            .filter(|(id, _)| !self.set_aside.contains(*id))
+            .collect();
+        
+        pending.sort_by(|(id_a, (doc_a, order_a, _)), (id_b, (doc_b, order_b, _))| {
+            let priority_a = self.priorities.get(doc_a).copied().unwrap_or(0);
+            let priority_b = self.priorities.get(doc_b).copied().unwrap_or(0);
+            let hits_a = self.hits.get(*id_a).copied().unwrap_or(0);
+            let hits_b = self.hits.get(*id_b).copied().unwrap_or(0);
+            priority_b.cmp(&priority_a)
+                .then_with(|| self.stale.contains(*id_a).cmp(&self.stale.contains(*id_b)))
+                .then_with(|| hits_b.cmp(&hits_a))
+                .then_with(|| doc_a.cmp(doc_b))
+                .then_with(|| order_a.cmp(order_b))
+        });
+        
+        let fragments: Vec<PendingFragment> = pending
+            .into_iter()
             .take(limit as usize)
-            .map(|(id, (_, _, content))| (id.clone(), content.clone()))
+            .map(|(id, (document_id, _, content))| PendingFragment {
+                id: id.clone(),
+                content: content.clone(),
+                priority: self.priorities.get(document_id).copied().unwrap_or(0),
+                stale: self.stale.contains(id),
+                hits: self.hits.get(id).copied().unwrap_or(0),
+            })
             .collect();
             
         Ok(fragments)
     }
 
     async fn count_fragments_without_embeddings(&mut self) -> Result<i32> {
-        // Count only fragments that don't have embeddings yet
+        // Count fragments that don't have a current embedding yet
         let count = self.fragments
             .iter()
-            .filter(|(id, _)| !self.embeddings.contains_key(*id))
+            .filter(|(id, _)| !self.embeddings.contains_key(*id) || self.stale.contains(*id))
+            .filter(|(id, _)| !self.set_aside.contains(*id))
             .count();
         Ok(count as i32)
     }
 
+    async fn get_embeddings_by_chunk(&mut self, chunk_hashes: &[String]) -> Result<std::collections::HashMap<String, Vec<f32>>> {
+        let wanted: std::collections::HashSet<&str> = chunk_hashes.iter().map(String::as_str).collect();
+        let mut embeddings = std::collections::HashMap::new();
+        if wanted.is_empty() {
+            return Ok(embeddings);
+        }
+        for (id, (_, _, content)) in &self.fragments {
+            let Some(embedding) = self.embeddings.get(id).filter(|_| !self.stale.contains(id)) else {
+                continue;
+            };
+            let hash = chunk_hash(content);
+            if wanted.contains(hash.as_str()) {
+                embeddings.entry(hash).or_insert_with(|| embedding.clone());
+            }
+        }
+        Ok(embeddings)
+    }
+
+    async fn record_embedding_failures(&mut self, failures: &[(String, String)]) -> Result<()> {
+        for (fragment_id, error) in failures {
+            let failure = self.failures.entry(fragment_id.clone()).or_insert_with(|| (String::new(), 0));
+            failure.0 = error.clone();
+            failure.1 += 1;
+            self.set_aside.insert(fragment_id.clone());
+        }
+        Ok(())
+    }
+
+    async fn retry_embedding_failures(&mut self) -> Result<i32> {
+        // Failures of fragments removed since are dropped
+        self.failures.retain(|id, _| self.fragments.contains_key(id));
+        let released = self.set_aside.iter().filter(|id| self.fragments.contains_key(*id)).count();
+        self.set_aside.clear();
+        Ok(released as i32)
+    }
+
+    async fn count_embedding_failures(&mut self) -> Result<i32> {
+        let count = self.failures.keys().filter(|id| self.fragments.contains_key(*id)).count();
+        Ok(count as i32)
+    }
+
+    async fn build_vector_index(&mut self) -> Result<bool> {
+        // Vectors are held in memory and always scanned
+        Ok(false)
+    }
+
+    async fn mark_embeddings_stale(&mut self) -> Result<i32> {
+        let before = self.stale.len();
+        self.stale.extend(self.embeddings.keys().cloned());
+        Ok((self.stale.len() - before) as i32)
+    }
+
+    async fn count_stale_fragments(&mut self) -> Result<i32> {
+        Ok(self.stale.len() as i32)
+    }
+
+    async fn corpus_stats(&mut self, largest: usize) -> Result<CorpusStats> {
+        let mut fragments_per_document: std::collections::HashMap<&str, usize> = std::collections::HashMap::new();
+        let mut fragment_chars = 0;
+        for (doc_id, _, content) in self.fragments.values() {
+            *fragments_per_document.entry(doc_id.as_str()).or_default() += 1;
+            fragment_chars += content.chars().count() as u64;
+        }
+        
+        let mut file_types: std::collections::HashMap<String, usize> = std::collections::HashMap::new();
+        let mut sizes = Vec::new();
+        for (id, (path, data)) in &self.documents {
+            // Same file type as DuckDB records: the lowercased extension
+            let file_type = Path::new(path).extension()
+                .map(|ext| ext.to_string_lossy().to_lowercase())
+                .unwrap_or_else(|| "unknown".to_string());
+            *file_types.entry(file_type).or_default() += 1;
+            sizes.push(DocumentSize {
+                file_path: path.clone(),
+                bytes: data.len() as u64,
+                fragments: fragments_per_document.get(id.as_str()).copied().unwrap_or(0),
+            });
+        }
+        let mut file_types: Vec<(String, usize)> = file_types.into_iter().collect();
+        sort_file_types(&mut file_types);
+        sort_largest(&mut sizes);
+        let document_bytes = sizes.iter().map(|document| document.bytes).sum();
+        sizes.truncate(largest);
+        
+        Ok(CorpusStats {
+            file_types,
+            documents: self.documents.len(),
+            fragments: self.fragments.len(),
+            fragment_chars,
+            document_bytes,
+            largest: sizes,
+        })
+    }
+
+    async fn record_fragment_hits(&mut self, fragment_ids: &[String]) -> Result<()> {
+        for id in fragment_ids {
+            *self.hits.entry(id.clone()).or_insert(0) += 1;
+        }
+        Ok(())
+    }
+
+    async fn get_fragment_vectors(&mut self, fragment_ids: &[String]) -> Result<std::collections::HashMap<String, Vec<f64>>> {
+        Ok(fragment_ids.iter()
+            .filter_map(|id| self.embeddings.get(id).map(|embedding| (id.clone(), embedding.iter().map(|&x| x as f64).collect())))
+            .collect())
+    }
+
+    async fn get_fragment_embeddings(&mut self, limit: usize) -> Result<Vec<(String, String, Vec<f64>)>> {
+        let mut embeddings: Vec<(String, String, Vec<f64>)> = self.embeddings
+            .iter()
+            .filter(|(fragment_id, _)| !self.stale.contains(*fragment_id))
+            .filter_map(|(fragment_id, embedding)| {
+                let (document_id, _, _) = self.fragments.get(fragment_id)?;
+                let (path, _) = self.documents.get(document_id)?;
+                let filename = Path::new(path)
+                    .file_name()
+                    .map(|name| name.to_string_lossy().to_string())
+                    .unwrap_or_else(|| "unknown".to_string());
+                Some((fragment_id.clone(), filename, embedding.iter().map(|&x| x as f64).collect()))
+            })
+            .collect();
+        
+        embeddings.sort_by(|a, b| a.0.cmp(&b.0));
+        embeddings.truncate(limit);
+        Ok(embeddings)
+    }
+
+    async fn sample_fragments(&mut self, limit: usize) -> Result<Vec<(String, String, Vec<f64>)>> {
+        let mut samples: Vec<(String, String, Vec<f64>)> = self.embeddings
+            .iter()
+            .filter(|(fragment_id, _)| !self.stale.contains(*fragment_id))
+            .filter_map(|(fragment_id, embedding)| {
+                let (_, _, content) = self.fragments.get(fragment_id)?;
+                Some((fragment_id.clone(), content.clone(), embedding.iter().map(|&x| x as f64).collect()))
+            })
+            .collect();
+        
+        samples.sort_by(|a, b| a.0.cmp(&b.0));
+        samples.truncate(limit);
+        Ok(samples)
+    }
+
     async fn get_meta_info(&mut self) -> Result<MetaInfo> {
         let version = self.metadata.get("version").unwrap_or(&"unknown".to_string()).clone();
         let embedding_model = self.metadata.get("embedding_model").unwrap_or(&"unknown".to_string()).clone();
@@ -158,26 +861,133 @@ impl Storage for LanceDBStorage {
         Ok(MetaInfo {
             version,
             embedding_model,
+            embedding_dimension: parse_dimension(self.metadata.get(DIMENSION_KEY).map(String::as_str)),
+            embedding_provider: self.metadata.get(PROVIDER_KEY).filter(|provider| !provider.is_empty()).cloned(),
         })
     }
 
+    async fn get_meta_value(&mut self, key: &str) -> Result<Option<String>> {
+        Ok(self.metadata.get(key).cloned())
+    }
+
+    async fn set_meta_value(&mut self, key: &str, value: &str) -> Result<()> {
+        self.metadata.insert(key.to_string(), value.to_string());
+        Ok(())
+    }
+
+    async fn list_entities(&mut self, limit: usize) -> Result<Vec<EntityCount>> {
+        let mut counts: std::collections::HashMap<String, (EntityCount, std::collections::HashSet<&String>)> = std::collections::HashMap::new();
+        for (doc_id, names) in self.fragment_entities() {
+            for name in names {
+                let (count, documents) = counts.entry(entities::key(&name))
+                    .or_insert_with(|| (EntityCount { name, fragments: 0, documents: 0 }, Default::default()));
+                count.fragments += 1;
+                documents.insert(doc_id);
+            }
+        }
+        
+        let mut entities: Vec<EntityCount> = counts.into_values()
+            .map(|(count, documents)| EntityCount { documents: documents.len(), ..count })
+            .collect();
+        entities.sort_by(|a, b| b.fragments.cmp(&a.fragments).then_with(|| a.name.cmp(&b.name)));
+        entities.truncate(limit);
+        Ok(entities)
+    }
+
+    async fn find_entity(&mut self, entity: &str) -> Result<Vec<EntityMention>> {
+        let wanted = entities::key(entity);
+        let mut mentions: std::collections::HashMap<&String, EntityMention> = std::collections::HashMap::new();
+        for (doc_id, names) in self.fragment_entities() {
+            let matching: Vec<String> = names.into_iter()
+                .filter(|name| entities::matches(&entities::key(name), &wanted))
+                .collect();
+            if matching.is_empty() {
+                continue;
+            }
+            let mention = mentions.entry(doc_id).or_insert_with(|| EntityMention {
+                document_id: doc_id.clone(),
+                file_path: self.documents.get(doc_id).map(|(path, _)| path.clone()).unwrap_or_default(),
+                names: Vec::new(),
+                fragments: 0,
+            });
+            mention.fragments += 1;
+            for name in matching {
+                if !mention.names.contains(&name) {
+                    mention.names.push(name);
+                }
+            }
+        }
+        
+        let mut mentions: Vec<EntityMention> = mentions.into_values().collect();
+        mentions.iter_mut().for_each(|mention| mention.names.sort());
+        mentions.sort_by(|a, b| b.fragments.cmp(&a.fragments).then_with(|| a.file_path.cmp(&b.file_path)));
+        Ok(mentions)
+    }
+
+    async fn add_annotation(&mut self, annotation: &Annotation) -> Result<()> {
+        self.annotations.push(annotation.clone());
+        Ok(())
+    }
+
+    async fn list_annotations(&mut self, target: Option<&str>) -> Result<Vec<Annotation>> {
+        Ok(self.annotations.iter()
+            .filter(|annotation| target.is_none_or(|target| annotation.target == target))
+            .cloned()
+            .collect())
+    }
+
+    async fn remove_annotation(&mut self, annotation_id: &str) -> Result<bool> {
+        let before = self.annotations.len();
+        self.annotations.retain(|annotation| annotation.id != annotation_id);
+        Ok(self.annotations.len() < before)
+    }
+
     async fn search_similar(
         &mut self,
-        _query_embedding: &[f64],
+        query_embedding: &[f64],
         limit: usize,
-    ) -> Result<Vec<(String, String, f64)>> {
-        // In stub implementation, return fragments with dummy similarity scores
-        let results: Vec<(String, String, f64)> = self.fragments
+        filter: &SearchFilter,
+    ) -> Result<Vec<FragmentMatch>> {
+        // Brute-force comparison with every embedded fragment
+        let scored = self.fragments
             .iter()
-            .take(limit)
-            .enumerate()
-            .map(|(i, (id, (_, _, content)))| {
-                // Dummy similarity score that decreases with index
-                let similarity = 1.0 - (i as f64 * 0.1);
-                (id.clone(), content.clone(), similarity.max(0.0))
+            .filter(|(id, _)| self.stale.contains(*id) == filter.stale)
+            .filter(|(id, (doc_id, _, _))| self.searchable(id, doc_id, filter))
+            .filter_map(|(id, _)| {
+                let embedding = self.embeddings.get(id)?;
+                Some(self.fragment_match(id, self.metric.score(query_embedding, embedding)))
             })
             .collect();
-            
-        Ok(results)
+        
+        Ok(best_first(scored, limit, filter))
     }
-}
\ No newline at end of file
+
+    async fn search_keyword(
+        &mut self,
+        query: &str,
+        limit: usize,
+        filter: &SearchFilter,
+    ) -> Result<Vec<FragmentMatch>> {
+        // BM25 over the fragments the filter allows, scored afresh on every query
+        let candidates = self.fragments
+            .iter()
+            .filter(|(id, (doc_id, _, _))| self.searchable(id, doc_id, filter))
+            .map(|(id, (_, _, content))| (id.as_str(), content.as_str()));
+
+        let scored = bm25(query, candidates).into_iter()
+            .map(|(id, score)| self.fragment_match(id, score))
+            .collect();
+
+        Ok(best_first(scored, limit, filter))
+    }
+}
+
+/// The `limit` highest-scoring matches ranked after `filter.after`, in ranking order
+fn best_first(mut matches: Vec<FragmentMatch>, limit: usize, filter: &SearchFilter) -> Vec<FragmentMatch> {
+    if let Some(after) = &filter.after {
+        matches.retain(|fragment| after.precedes(fragment));
+    }
+    sort_ranked(&mut matches);
+    matches.truncate(limit);
+    matches
+}
@@ -1,4 +1,71 @@
+//! Portable Brains as a library: index documents into a portable vector database and search
+//! it from your own Rust service instead of shelling out to the CLI.
+//!
+//! ```no_run
+//! # async fn run() -> anyhow::Result<()> {
+//! use portable_brains::{EmbeddingManager, Indexer, Retriever};
+//! use std::path::Path;
+//!
+//! let embedding_manager = EmbeddingManager::new("BAAI/bge-small-en-v1.5").await?;
+//! let mut indexer = Indexer::open(Path::new("archive.db"), embedding_manager).await?;
+//! indexer.index_file(Path::new("docs/handbook.pdf")).await?;
+//! drop(indexer);
+//!
+//! let mut retriever = Retriever::open(Path::new("archive.db")).await?;
+//! for hit in retriever.search("how many vacation days", 5).await? {
+//!     println!("{:.3} {} — {}", hit.score, hit.source.citation(), hit.content);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Databases written through the library are ordinary Portable Brains databases, so the
+//! CLI, `serve` and `eatmybrain` can open them too. Lower-level pieces are re-exported for
+//! finer control: `DocumentProcessor` for extraction and chunking, `EmbeddingManager` for
+//! vectors, and the `Storage` trait with its backends.
+
+pub mod annotations;
+pub mod autocomplete;
 pub mod database;
 pub mod document_processor;
+pub mod duckdb_storage;
 pub mod embedding_manager;
-pub mod error;
\ No newline at end of file
+pub mod entities;
+pub mod error;
+pub mod hybrid;
+pub mod indexer;
+pub mod lancedb_storage;
+pub mod migrate;
+pub mod model_downloads;
+pub mod offline;
+pub mod package;
+pub mod passwords;
+pub mod paths;
+pub mod presets;
+pub mod remote_storage;
+pub mod reranker;
+pub mod retrieval;
+pub mod retrieval_pipeline;
+pub mod retriever;
+pub mod sharded_storage;
+pub mod static_site;
+pub mod storage;
+pub mod storage_thread;
+pub mod testing;
+
+pub use document_processor::{DocumentProcessor, Section};
+pub use duckdb_storage::DuckDBStorage;
+pub use embedding_manager::{EmbeddingManager, EmbeddingPrefixes};
+pub use error::{PortableBrainsError, Severity};
+pub use hybrid::SearchMode;
+pub use indexer::Indexer;
+pub use lancedb_storage::LanceDBStorage;
+pub use presets::Preset;
+pub use remote_storage::RemoteStorage;
+pub use retrieval::{SearchCursor, SearchHit, SearchPage};
+pub use retriever::Retriever;
+pub use sharded_storage::ShardedStorage;
+pub use storage::{
+    create_storage, DocumentSummary, FragmentMatch, FragmentMeta, FragmentSource, SearchFilter, Storage,
+    StorageBackend,
+};
@@ -0,0 +1,406 @@
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use serde_json::{json, Value};
+use std::hash::{Hash, Hasher};
+
+use crate::offline;
+
+/// Anthropic API version sent with every Messages request
+const ANTHROPIC_VERSION: &str = "2023-06-01";
+/// Most `cache_control` breakpoints Anthropic accepts in one request
+const MAX_CACHE_BREAKPOINTS: usize = 4;
+
+#[derive(serde::Serialize, serde::Deserialize, Clone)]
+pub struct ChatMessage {
+    pub role: String,
+    pub content: String,
+    /// The conversation up to and including this message is the same across requests, so
+    /// providers that support prompt caching may reuse it
+    #[serde(skip)]
+    pub cache: bool,
+}
+
+impl ChatMessage {
+    pub fn system(content: impl Into<String>) -> Self {
+        Self { role: "system".to_string(), content: content.into(), cache: false }
+    }
+
+    pub fn user(content: impl Into<String>) -> Self {
+        Self { role: "user".to_string(), content: content.into(), cache: false }
+    }
+
+    /// Mark the prompt up to this message as cacheable
+    pub fn cached(mut self) -> Self {
+        self.cache = true;
+        self
+    }
+}
+
+/// API flavour an endpoint speaks, which decides how requests are shaped, authenticated
+/// and parsed
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum Provider {
+    /// OpenAI's chat completions API
+    #[value(name = "openai")]
+    OpenAi,
+    /// Anthropic's Messages API
+    Anthropic,
+    /// Another server speaking the OpenAI chat completions format, e.g. Ollama or LM Studio
+    Custom,
+}
+
+impl Provider {
+    /// Guess the provider from the endpoint URL
+    pub fn from_endpoint(endpoint: &str) -> Self {
+        let path = endpoint.split('?').next().unwrap_or(endpoint).trim_end_matches('/');
+        if endpoint.contains("api.anthropic.com") || path.ends_with("/v1/messages") {
+            Provider::Anthropic
+        } else if endpoint.contains("api.openai.com") {
+            Provider::OpenAi
+        } else {
+            Provider::Custom
+        }
+    }
+
+    /// Hosted APIs reject unauthenticated requests; local servers often need no key
+    pub fn requires_api_key(self) -> bool {
+        self != Provider::Custom
+    }
+}
+
+#[derive(serde::Serialize)]
+struct ChatRequest {
+    model: String,
+    messages: Vec<ChatMessage>,
+    max_tokens: Option<u32>,
+    temperature: Option<f32>,
+    /// Routes requests sharing a cacheable prefix to the same OpenAI cache
+    #[serde(skip_serializing_if = "Option::is_none")]
+    prompt_cache_key: Option<String>,
+}
+
+#[derive(serde::Deserialize)]
+struct ChatChoice {
+    message: ChatMessage,
+}
+
+/// Token counts reported by the API for one request
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, Default)]
+pub struct TokenUsage {
+    #[serde(default)]
+    pub prompt_tokens: u32,
+    #[serde(default)]
+    pub completion_tokens: u32,
+    #[serde(default)]
+    pub total_tokens: u32,
+    /// Prompt tokens read from the provider's prompt cache, included in `prompt_tokens`
+    #[serde(default)]
+    pub cached_tokens: u32,
+    /// Prompt tokens written to the prompt cache, included in `prompt_tokens`
+    #[serde(default)]
+    pub cache_write_tokens: u32,
+}
+
+impl std::ops::AddAssign for TokenUsage {
+    fn add_assign(&mut self, other: Self) {
+        self.prompt_tokens += other.prompt_tokens;
+        self.completion_tokens += other.completion_tokens;
+        self.total_tokens += other.total_tokens;
+        self.cached_tokens += other.cached_tokens;
+        self.cache_write_tokens += other.cache_write_tokens;
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct ChatResponse {
+    choices: Vec<ChatChoice>,
+    #[serde(default)]
+    usage: Option<OpenAiUsage>,
+}
+
+#[derive(serde::Deserialize)]
+struct OpenAiUsage {
+    #[serde(flatten)]
+    tokens: TokenUsage,
+    #[serde(default)]
+    prompt_tokens_details: Option<PromptTokensDetails>,
+}
+
+#[derive(serde::Deserialize)]
+struct PromptTokensDetails {
+    #[serde(default)]
+    cached_tokens: u32,
+}
+
+#[derive(serde::Deserialize)]
+struct AnthropicResponse {
+    content: Vec<AnthropicBlock>,
+    #[serde(default)]
+    usage: Option<AnthropicUsage>,
+}
+
+#[derive(serde::Deserialize)]
+struct AnthropicBlock {
+    #[serde(rename = "type")]
+    kind: String,
+    #[serde(default)]
+    text: String,
+}
+
+#[derive(serde::Deserialize)]
+struct AnthropicUsage {
+    #[serde(default)]
+    input_tokens: u32,
+    #[serde(default)]
+    output_tokens: u32,
+    #[serde(default)]
+    cache_creation_input_tokens: u32,
+    #[serde(default)]
+    cache_read_input_tokens: u32,
+}
+
+impl From<AnthropicUsage> for TokenUsage {
+    fn from(usage: AnthropicUsage) -> Self {
+        // Anthropic counts cached prompt tokens apart from `input_tokens`
+        let prompt_tokens = usage.input_tokens + usage.cache_creation_input_tokens + usage.cache_read_input_tokens;
+        Self {
+            prompt_tokens,
+            completion_tokens: usage.output_tokens,
+            total_tokens: prompt_tokens + usage.output_tokens,
+            cached_tokens: usage.cache_read_input_tokens,
+            cache_write_tokens: usage.cache_creation_input_tokens,
+        }
+    }
+}
+
+/// Content of a completion and the tokens it used
+pub struct ChatReply {
+    pub content: String,
+    pub usage: TokenUsage,
+}
+
+/// Client for OpenAI's chat completions API, Anthropic's Messages API, or a server compatible
+/// with OpenAI's
+pub struct LlmClient {
+    client: reqwest::Client,
+    pub endpoint: String,
+    api_key: String,
+    pub model: String,
+    pub provider: Provider,
+    /// Mark cacheable messages for the provider's prompt cache
+    prompt_cache: bool,
+}
+
+impl LlmClient {
+    pub fn new(endpoint: String, api_key: String, model: String) -> Self {
+        Self {
+            client: offline::client(),
+            provider: Provider::from_endpoint(&endpoint),
+            endpoint,
+            api_key,
+            model,
+            prompt_cache: true,
+        }
+    }
+
+    /// Use this provider's request format instead of the one guessed from the endpoint
+    pub fn with_provider(mut self, provider: Provider) -> Self {
+        self.provider = provider;
+        self
+    }
+
+    /// Turn prompt caching hints on or off (on by default)
+    pub fn with_prompt_cache(mut self, enabled: bool) -> Self {
+        self.prompt_cache = enabled;
+        self
+    }
+
+    /// Send a conversation and return the first choice with the token usage reported by the API
+    pub async fn complete(&self, messages: Vec<ChatMessage>, max_tokens: u32, temperature: f32) -> Result<ChatReply> {
+        offline::check(&self.endpoint, "The LLM API")?;
+        let request = self.client.post(&self.endpoint)
+            .header("Content-Type", "application/json")
+            .json(&self.request_body(messages, max_tokens, temperature));
+        let request = match self.provider {
+            Provider::OpenAi => request.header("Authorization", format!("Bearer {}", self.api_key)),
+            Provider::Custom if self.api_key.is_empty() => request,
+            Provider::Custom => request.header("Authorization", format!("Bearer {}", self.api_key)),
+            Provider::Anthropic => request
+                .header("x-api-key", &self.api_key)
+                .header("anthropic-version", ANTHROPIC_VERSION),
+        };
+
+        let response = request
+            .send()
+            .await
+            .context("Failed to send request to LLM API")?;
+
+        if !response.status().is_success() {
+            let status = response.status();
+            let error_text = response.text().await.unwrap_or_default();
+            anyhow::bail!("LLM API error {}: {}", status, error_text);
+        }
+
+        match self.provider {
+            Provider::OpenAi | Provider::Custom => {
+                let chat_response: ChatResponse = response.json().await
+                    .context("Failed to parse LLM response")?;
+
+                let usage = chat_response.usage
+                    .map(|usage| TokenUsage {
+                        cached_tokens: usage.prompt_tokens_details.map_or(0, |details| details.cached_tokens),
+                        ..usage.tokens
+                    })
+                    .unwrap_or_default();
+                chat_response.choices.into_iter()
+                    .next()
+                    .map(|choice| ChatReply { content: choice.message.content, usage })
+                    .ok_or_else(|| anyhow::anyhow!("No response choices received from LLM"))
+            }
+            Provider::Anthropic => {
+                let message: AnthropicResponse = response.json().await
+                    .context("Failed to parse LLM response")?;
+
+                let content: String = message.content.into_iter()
+                    .filter(|block| block.kind == "text")
+                    .map(|block| block.text)
+                    .collect();
+                if content.is_empty() {
+                    anyhow::bail!("No text content received from LLM");
+                }
+                Ok(ChatReply { content, usage: message.usage.map(TokenUsage::from).unwrap_or_default() })
+            }
+        }
+    }
+
+    /// The JSON body for this client's provider. Cacheable messages become `cache_control`
+    /// breakpoints for Anthropic; OpenAI caches long prefixes by itself, and is given a
+    /// `prompt_cache_key` so requests sharing a prefix reach the same cache. Other servers
+    /// get the plain chat completions body, since they may reject fields they don't know.
+    fn request_body(&self, messages: Vec<ChatMessage>, max_tokens: u32, temperature: f32) -> Value {
+        match self.provider {
+            Provider::OpenAi | Provider::Custom => {
+                let prompt_cache_key = (self.provider == Provider::OpenAi && self.prompt_cache)
+                    .then(|| cache_key(&messages))
+                    .flatten();
+                let request = ChatRequest {
+                    model: self.model.clone(),
+                    messages,
+                    max_tokens: Some(max_tokens),
+                    temperature: Some(temperature),
+                    prompt_cache_key,
+                };
+                serde_json::to_value(request).unwrap_or_default()
+            }
+            Provider::Anthropic => {
+                // Only the last breakpoints are kept; each one caches everything before it
+                let mut breakpoints = messages.iter().filter(|message| message.cache).count()
+                    .saturating_sub(MAX_CACHE_BREAKPOINTS);
+                let mut system = Vec::new();
+                let mut turns = Vec::new();
+                for message in messages {
+                    let mut block = json!({ "type": "text", "text": message.content });
+                    if self.prompt_cache && message.cache {
+                        if breakpoints == 0 {
+                            block["cache_control"] = json!({ "type": "ephemeral" });
+                        } else {
+                            breakpoints -= 1;
+                        }
+                    }
+                    // The Messages API takes system prompts apart from the conversation
+                    match message.role.as_str() {
+                        "system" => system.push(block),
+                        _ => turns.push(json!({ "role": message.role, "content": [block] })),
+                    }
+                }
+
+                let mut body = json!({
+                    "model": self.model,
+                    "max_tokens": max_tokens,
+                    "temperature": temperature,
+                    "messages": turns,
+                });
+                if !system.is_empty() {
+                    body["system"] = Value::Array(system);
+                }
+                body
+            }
+        }
+    }
+}
+
+/// A key naming the cacheable prefix of a conversation, or `None` when nothing is cacheable
+fn cache_key(messages: &[ChatMessage]) -> Option<String> {
+    let prefix = messages.iter().rposition(|message| message.cache)?;
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    for message in &messages[..=prefix] {
+        message.role.hash(&mut hasher);
+        message.content.hash(&mut hasher);
+    }
+    Some(format!("portable-brains-{:016x}", hasher.finish()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn conversation() -> Vec<ChatMessage> {
+        vec![
+            ChatMessage::system("Answer from the context.").cached(),
+            ChatMessage::system("Context:\n[1] Revenue grew.").cached(),
+            ChatMessage::user("What did revenue do?"),
+        ]
+    }
+
+    #[test]
+    fn test_provider_from_endpoint() {
+        assert_eq!(Provider::from_endpoint("https://api.anthropic.com/v1/messages"), Provider::Anthropic);
+        assert_eq!(Provider::from_endpoint("http://proxy.local/v1/messages/"), Provider::Anthropic);
+        assert_eq!(Provider::from_endpoint("https://api.openai.com/v1/chat/completions"), Provider::OpenAi);
+        assert_eq!(Provider::from_endpoint("http://localhost:11434/v1/chat/completions"), Provider::Custom);
+    }
+
+    #[test]
+    fn test_anthropic_body_marks_cached_blocks() {
+        let client = LlmClient::new("https://api.anthropic.com/v1/messages".to_string(), "key".to_string(), "claude".to_string());
+        let body = client.request_body(conversation(), 100, 0.5);
+
+        let system = body["system"].as_array().unwrap();
+        assert_eq!(system.len(), 2);
+        assert_eq!(system[0]["cache_control"]["type"], "ephemeral");
+        assert_eq!(system[1]["text"], "Context:\n[1] Revenue grew.");
+        assert_eq!(body["messages"][0]["role"], "user");
+        assert!(body["messages"][0]["content"][0].get("cache_control").is_none());
+
+        let uncached = client.with_prompt_cache(false).request_body(conversation(), 100, 0.5);
+        assert!(uncached["system"][0].get("cache_control").is_none());
+    }
+
+    #[test]
+    fn test_openai_body_keys_the_cached_prefix() {
+        let client = LlmClient::new("https://api.openai.com/v1/chat/completions".to_string(), "key".to_string(), "gpt-4".to_string());
+        let body = client.request_body(conversation(), 100, 0.5);
+        assert_eq!(body["messages"].as_array().unwrap().len(), 3);
+        assert!(body["messages"][0].get("cache").is_none());
+
+        // A different question over the same context shares the key
+        let mut other = conversation();
+        other[2] = ChatMessage::user("Who wrote the report?");
+        assert_eq!(body["prompt_cache_key"], client.request_body(other, 100, 0.5)["prompt_cache_key"]);
+
+        let uncached = vec![ChatMessage::user("Hello")];
+        assert!(client.request_body(uncached, 100, 0.5).get("prompt_cache_key").is_none());
+    }
+
+    #[test]
+    fn test_custom_body_is_plain_chat_completions() {
+        let client = LlmClient::new("http://localhost:11434/v1/chat/completions".to_string(), String::new(), "llama3".to_string());
+        let body = client.request_body(conversation(), 100, 0.5);
+        assert_eq!(body["messages"][2]["content"], "What did revenue do?");
+        assert!(body.get("prompt_cache_key").is_none());
+
+        // An explicit provider wins over the endpoint
+        let anthropic = client.with_provider(Provider::Anthropic).request_body(conversation(), 100, 0.5);
+        assert_eq!(anthropic["system"].as_array().unwrap().len(), 2);
+    }
+}
@@ -1,6 +1,7 @@
 use anyhow::{anyhow, Context, Result};
-use clap::{Parser, ValueEnum};
+use clap::{CommandFactory, Parser, Subcommand, ValueEnum};
 // use log::{info, warn};
+use std::collections::{HashMap, HashSet};
 use std::path::{Path, PathBuf};
 
 mod database;
@@ -10,317 +11,4260 @@ mod duckdb_storage;
 mod lancedb_storage;
 mod embedding_manager;
 mod error;
+mod retrieval;
+mod viz;
+mod brains;
+mod sharded_storage;
+mod storage_thread;
+mod ingest_queue;
+mod classifier;
+mod config;
+mod throttle;
+mod passwords;
+mod paths;
+mod quality;
+mod scanner;
+mod server;
+mod jobs;
+mod tenants;
+mod discovery;
+mod presets;
+mod profiles;
+mod hybrid;
+mod drift;
+mod dashboard;
+mod mcp;
+mod retrieval_pipeline;
+mod migrate;
+mod entities;
+mod offline;
+mod model_downloads;
+mod reranker;
+mod annotations;
+mod static_site;
+mod package;
+mod remote_storage;
+mod progress;
+mod profiler;
+mod eval;
+mod brain_diff;
+mod llm;
+mod summary_tree;
+mod sources;
+mod autocomplete;
 
 // use database::Database;  // Not used with storage abstraction
-use document_processor::DocumentProcessor;
-use embedding_manager::EmbeddingManager;
-use storage::{Storage};
-use duckdb_storage::DuckDBStorage;
-use lancedb_storage::LanceDBStorage;
+use document_processor::{derive_title, join_sections, pack_sections, unpack_sections, ChunkingStrategy, DocumentProcessor, TextCleanup};
+use embedding_manager::{EmbeddingManager, EmbeddingPrefixes};
+use storage::{create_storage, SearchFilter, Storage, StorageBackend};
+use brains::{BrainHit, BrainSet, RoutingMode};
+use sharded_storage::{ShardManifest, ShardStrategy, ShardedStorage};
+use ingest_queue::{IngestQueue, StagedDocument, StagedFragment};
+use storage::{BulkAction, DocumentSummary, FragmentChanges, FragmentMeta, IndexState, OriginalsMode, SimilarityMetric};
+use classifier::ZeroShotClassifier;
+use config::{CleanupConfig, CollectionRouter, Config, LiveConfig, Routing};
+use scanner::ContentScanner;
+use throttle::{Throttle, ThrottleSettings};
+use jobs::{ItemOutcome, Job, JobKind, JobState, JobStore};
+use discovery::Discovery;
+use presets::Preset;
+use hybrid::SearchMode;
+use reranker::{CrossEncoder, RERANK_POOL};
+use annotations::{Annotation, AnnotationLabel};
+use error::{PortableBrainsError, Severity};
+use progress::{FileOutcome, Progress, ProgressMode};
+use profiler::StageTimings;
+use passwords::Passwords;
+use llm::{LlmClient, Provider};
+use summary_tree::{LlmSummarizer, TreeOptions};
 
 #[derive(Clone, ValueEnum)]
 enum Backend {
     Duckdb,
     Lancedb,
+    /// A brain on a Qdrant server; the database is its URL, e.g. http://localhost:6333/team
+    Remote,
+}
+
+#[derive(Clone, PartialEq, ValueEnum)]
+enum ShardBy {
+    /// Fixed number of shards chosen by a hash of the document path
+    Hash,
+    /// One shard per collection (the document's parent directory)
+    Collection,
 }
 
 #[derive(Clone, ValueEnum)]
 enum EmbeddingProvider {
+    /// FastEmbed models run in-process
     Local,
+    /// OpenAI's embeddings API, or --endpoint; needs --api-key
     Remote,
+    /// A local Ollama server (http://localhost:11434/api/embeddings unless --endpoint is given)
+    Ollama,
+    /// Any server implementing OpenAI's /v1/embeddings at --endpoint; --api-key is optional
+    Compatible,
 }
 
 #[derive(Parser)]
 #[command(name = "portable-brains")]
 #[command(about = "Portable Brains - Index documents with configurable storage backend")]
-struct Args {
-    /// Path to the database file (extension determines format: .db for DuckDB, .lancedb for LanceDB)
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+    
+    /// Enable verbose logging
+    #[arg(short, long, global = true)]
+    verbose: bool,
+    
+    /// Refuse all network access except to this machine: remote providers, model downloads
+    /// and URL ingestion fail instead (also set by PORTABLE_BRAINS_STRICT_OFFLINE=1)
+    #[arg(long, global = true)]
+    strict_offline: bool,
+    
+    /// API key sent to the Qdrant server of a remote brain (also set by QDRANT_API_KEY)
+    #[arg(long, global = true)]
+    remote_api_key: Option<String>,
+    
+    /// Download local embedding and reranker models from this mirror of the Hugging Face
+    /// hub, such as an internal artifact store (also set by PORTABLE_BRAINS_MODEL_MIRROR)
+    #[arg(long, global = true, value_name = "URL")]
+    model_mirror: Option<String>,
+    
+    /// TOML file of SHA-256 checksums that local model files must match before they are
+    /// loaded (also set by PORTABLE_BRAINS_MODEL_CHECKSUMS)
+    #[arg(long, global = true, value_name = "FILE")]
+    model_checksums: Option<PathBuf>,
+    
+    /// Profile of the config file whose settings fill in flags not given (default: the
+    /// file's `profile`, or one named `default`); also set by PORTABLE_BRAINS_PROFILE
+    #[arg(long, global = true)]
+    #[allow(dead_code)] // Read from the raw arguments by profiles::apply before parsing
+    profile: Option<String>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Extract and chunk documents into the database (Phase 1, no embeddings)
+    Index(IndexArgs),
+    /// Keep indexing a directory as files are added, deleted or renamed
+    Watch(WatchArgs),
+    /// Index again only the documents that failed in earlier runs
+    RetryFailed(RetryFailedArgs),
+    /// Generate embeddings for stored fragments that don't have one yet (Phase 2)
+    Embed(EmbedArgs),
+    /// Run a similarity search against the database
+    #[command(visible_alias = "query")]
+    Search(SearchArgs),
+    /// Score search against queries with known answers: recall@k, MRR and nDCG per configuration
+    Eval(EvalArgs),
+    /// Export a 2D projection of fragment embeddings for visual inspection
+    Viz(VizArgs),
+    /// Re-embed a sample of fragments and report how far the stored vectors have drifted
+    Drift(DriftArgs),
+    /// Report the documents, fragments and settings that differ between two databases
+    DiffDb(DiffDbArgs),
+    /// List indexed documents with their extraction quality
+    List(ListArgs),
+    /// List the people, products and projects most mentioned, or the documents naming one
+    Entities(EntitiesArgs),
+    /// Show the database's model, versions and document and fragment counts
+    Info(InfoArgs),
+    /// Report corpus analytics: file types, chunk lengths, embedding coverage and size
+    Stats(StatsArgs),
+    /// Delete a document and its fragments, by path or id, or every document matching --where
+    #[command(visible_alias = "delete")]
+    Remove(RemoveArgs),
+    /// Retag documents or move them to another collection, selected by --where
+    Reorganize(ReorganizeArgs),
+    /// Write stored original documents back out to a directory
+    Extract(ExtractArgs),
+    /// Label or comment on a fragment or document, list and remove annotations, or weigh their effect on ranking
+    Annotate(AnnotateArgs),
+    /// Write every document's fragments, with their ids and vectors, as JSON lines
+    Export(ExportArgs),
+    /// Load a bundle written by `export --format bundle` into a new database
+    Import(ImportArgs),
+    /// Copy a database, vectors included, into a new one that may use another backend
+    Migrate(MigrateArgs),
+    /// Pack a database into a single signed `.brain` file for distribution
+    Pack(PackArgs),
+    /// Verify a `.brain` file and extract its database
+    Unpack(UnpackArgs),
+    /// Check a `.brain` file's hashes and signature without extracting it
+    Verify(VerifyArgs),
+    /// Re-chunk stored documents from their saved text with new chunk settings
+    Rechunk(RechunkArgs),
+    /// Rebuild a DuckDB database's fragments in the smaller, faster compact layout
+    Compact(CompactArgs),
+    /// Build a tree of recursive summaries over the fragments, searched alongside them by eatmybrain
+    Summarize(SummarizeArgs),
+    /// Serve the database over a token-protected REST API
+    Serve(ServeArgs),
+    /// Print the REST API's OpenAPI spec as JSON, for generating client SDKs
+    Openapi,
+    /// Serve the database to MCP clients such as Claude Desktop or Cursor over stdio
+    Mcp(McpArgs),
+    /// List index and embed jobs run against a database, or cancel one
+    Jobs(JobsArgs),
+}
+
+#[derive(clap::Args)]
+struct StorageArgs {
+    /// Path to the database file (extension determines format: .db for DuckDB, .lancedb for LanceDB),
+    /// or the URL of a brain on a Qdrant server (http://localhost:6333/team)
     #[arg(short, long)]
     database: PathBuf,
     
-    /// Name of the embedding model
-    #[arg(short, long)]
-    model: String,
+    /// Storage backend to use
+    #[arg(short, long, value_enum, default_value = "duckdb")]
+    backend: Backend,
+}
+
+#[derive(clap::Args)]
+struct ThrottleArgs {
+    /// Lower the process's CPU and IO scheduling priority
+    #[arg(long)]
+    nice: bool,
+    
+    /// Restrict the process to this many CPU cores (Linux only)
+    #[arg(long)]
+    max_cores: Option<usize>,
+    
+    /// Cap document reads at this many megabytes per second
+    #[arg(long)]
+    io_limit: Option<f64>,
+    
+    /// Pause while the machine is running on battery power
+    #[arg(long)]
+    pause_on_battery: bool,
+}
+
+impl ThrottleArgs {
+    fn settings(&self) -> ThrottleSettings {
+        ThrottleSettings {
+            nice: self.nice,
+            max_cores: self.max_cores,
+            io_limit_mb: self.io_limit,
+            pause_on_battery: self.pause_on_battery,
+        }
+    }
+}
+
+#[derive(clap::Args)]
+struct EmbeddingProviderArgs {
+    /// Embedding provider to use
+    #[arg(short = 'p', long, value_enum, default_value = "local")]
+    embedding_provider: EmbeddingProvider,
+    
+    /// API key for remote embedding providers (required for remote, optional for compatible)
+    #[arg(long)]
+    api_key: Option<String>,
+    
+    /// Endpoint URL for remote embedding service (defaults to OpenAI for remote and to the
+    /// local server for ollama; required for compatible)
+    #[arg(long)]
+    endpoint: Option<String>,
+    
+    /// Send remote embedding requests no faster than this many a minute, to stay within the
+    /// provider's quota
+    #[arg(long)]
+    requests_per_minute: Option<u32>,
+    
+    /// Times a rate-limited or failed remote embedding request is retried, with exponential
+    /// backoff, before its fragments are set aside for the next run
+    #[arg(long, default_value_t = 5)]
+    max_retries: u32,
+}
+
+#[derive(clap::Args)]
+struct IndexArgs {
+    #[command(flatten)]
+    storage: StorageArgs,
+    
+    /// Name of the embedding model (recorded in the database for the embed phase)
+    #[arg(short, long)]
+    model: String,
+    
+    /// Directory containing documents to index (PDF, TXT, HTML, DOCX, PPTX, XLSX, Markdown, EPUB, CSV, TSV, EML)
+    #[arg(short, long, required_unless_present = "source")]
+    input_dir: Option<PathBuf>,
+    
+    /// Also index the documents of a source, synced incrementally: a directory as `dir:PATH`,
+    /// a git repository as `git:PATH`, a document's `https://` URL, or the documents an RSS
+    /// or Atom feed links to as `feed+https://...` (repeatable)
+    #[arg(long, value_name = "SPEC")]
+    source: Vec<String>,
+    
+    /// Also index documents in subdirectories of the input directory
+    #[arg(short, long)]
+    recursive: bool,
+    
+    /// Only index files matching this glob, relative to the input directory (repeatable)
+    #[arg(long)]
+    include: Vec<String>,
+    
+    /// Skip files and directories matching this glob, e.g. `node_modules/` (repeatable)
+    #[arg(long)]
+    exclude: Vec<String>,
+    
+    /// Embedding priority for documents indexed in this run (higher values are embedded first)
+    #[arg(long, default_value = "0")]
+    priority: i32,
+    
+    /// Collection for every document indexed in this run, overriding routing rules
+    #[arg(long)]
+    collection: Option<String>,
+    
+    /// Comma-separated tags for every document indexed in this run, added to those from
+    /// routing rules
+    #[arg(long, value_delimiter = ',')]
+    tags: Vec<String>,
+    
+    /// Also run the embed phase after extraction completes
+    #[arg(long)]
+    embed: bool,
+    
+    /// Re-extract documents whose file content changed since they were indexed, replacing
+    /// their fragments, and skip unchanged files instead of reporting them as existing
+    #[arg(long, conflicts_with = "staged")]
+    update: bool,
+    
+    /// Derive document and fragment ids from content and record the indexer version, so
+    /// indexing the same files again gives identical ids and exports
+    #[arg(long)]
+    deterministic: bool,
+    
+    /// How to keep each document's original file: as read, compressed with zstd, or not at
+    /// all (only its path and hash; extract and the original download then have nothing to
+    /// return). Recorded so later runs keep originals the same way.
+    #[arg(long, value_enum, value_name = "MODE")]
+    store_originals: Option<OriginalsMode>,
+    
+    /// Keep only vectors and minimal metadata: fragment text is dropped once embedded, and
+    /// no originals, extracted text or titles are stored, so searches return document
+    /// references only. Only for new databases; recorded so later runs keep to it.
+    #[arg(long, conflicts_with = "late_chunking")]
+    embeddings_only: bool,
+    
+    /// How searches compare vectors: cosine, or dot (inner product) and euclidean for models
+    /// trained for them. Recorded so every later search of the database uses it.
+    #[arg(long, value_enum, value_name = "METRIC")]
+    similarity: Option<SimilarityMetric>,
+    
+    /// How progress is reported: bars with throughput and ETA, plain lines, or
+    /// newline-delimited JSON events on standard output for scripts
+    #[arg(long, value_enum, default_value = "bars")]
+    progress: ProgressMode,
+    
+    /// Time the read, parse, chunk and store stages of every file, print the totals per
+    /// format and the slowest files at the end, and write the timings to FILE as folded
+    /// stacks for flamegraph tools
+    #[arg(long, value_name = "FILE")]
+    profile_stages: Option<PathBuf>,
+    
+    /// Text prepended to each fragment before embedding, e.g. "passage: " for E5 models
+    /// (default: the model's documented prefix; recorded so later runs match)
+    #[arg(long)]
+    document_prefix: Option<String>,
+    
+    /// Text prepended to each search query before embedding, e.g. "query: " for E5 models
+    /// (default: the model's documented prefix; recorded so searches match)
+    #[arg(long)]
+    query_prefix: Option<String>,
+    
+    /// Chunking, cleanup and search boosts tuned for a kind of corpus; recorded in the
+    /// database so later runs, searches and eatmybrain use it too
+    #[arg(long, value_enum)]
+    preset: Option<Preset>,
+    
+    /// How prose is split into fragments; `token` sizes fragments in tokens of the
+    /// embedding model's tokenizer. Recorded so later runs chunk the same way.
+    #[arg(long, value_enum)]
+    chunking: Option<ChunkingStrategy>,
+    
+    /// Target fragment length, in tokens with `--chunking token` and characters otherwise
+    /// (default: the preset's, or 800 characters)
+    #[arg(long)]
+    chunk_size: Option<usize>,
+    
+    /// Trailing context repeated at the start of the next fragment (default: the preset's,
+    /// or 100 characters)
+    #[arg(long)]
+    overlap: Option<usize>,
+    
+    /// Embed each section whole and pool its token embeddings per fragment, so fragments
+    /// keep context from the sentences around them (local models only). Recorded so the
+    /// embed phase keeps doing it.
+    #[arg(long)]
+    late_chunking: bool,
+    
+    /// Skip files larger than this many megabytes
+    #[arg(long, value_name = "MB", default_value_t = 50)]
+    max_file_size: u64,
+    
+    /// TOML file of `[[file]]` entries pairing a path glob with the password of the
+    /// encrypted PDFs it matches
+    #[arg(long, value_name = "FILE")]
+    password_file: Option<PathBuf>,
+    
+    /// Ask on the terminal for the password of an encrypted PDF no known password opens
+    #[arg(long)]
+    ask_password: bool,
+    
+    /// Split the database into this many hash shards (the database path must end in .shards)
+    #[arg(long)]
+    shards: Option<usize>,
+    
+    /// How documents are assigned to shards (the database path must end in .shards)
+    #[arg(long, value_enum)]
+    shard_by: Option<ShardBy>,
+    
+    /// Stage extracted documents in an append-only queue committed to storage in the background
+    #[arg(long)]
+    staged: bool,
+    
+    /// Embed each document's fragments as soon as it is extracted, overlapping extraction
+    /// with embedding instead of running the two phases one after the other (implies --embed)
+    #[arg(long, conflicts_with = "staged")]
+    single_pass: bool,
+    
+    /// Classify each document (invoice, contract, report, email, ...) by comparing its embedding with label descriptions
+    #[arg(long)]
+    classify: bool,
+    
+    /// File of `name: description` lines replacing the built-in classification labels
+    #[arg(long, requires = "classify")]
+    labels: Option<PathBuf>,
+    
+    /// TOML config file with collection routing rules and content scanning settings
+    #[arg(long)]
+    config: Option<PathBuf>,
+    
+    #[command(flatten)]
+    throttle: ThrottleArgs,
+    
+    #[command(flatten)]
+    provider: EmbeddingProviderArgs,
+}
+
+#[derive(clap::Args)]
+struct WatchArgs {
+    #[command(flatten)]
+    index: IndexArgs,
+    
+    /// Seconds between scans of the input directory
+    #[arg(long, default_value = "30")]
+    interval: u64,
+    
+    /// What to do with a document whose source file is deleted or renamed away
+    #[arg(long, value_enum, default_value = "keep")]
+    on_delete: DeletePolicy,
+}
+
+#[derive(clap::Args)]
+struct RetryFailedArgs {
+    /// Index options for the retry; only failed files under the input directory are retried,
+    /// e.g. with a larger --max-file-size
+    #[command(flatten)]
+    index: IndexArgs,
+    
+    /// List the files that would be retried without indexing them
+    #[arg(long)]
+    dry_run: bool,
+}
+
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+enum DeletePolicy {
+    /// Leave the document searchable
+    Keep,
+    /// Keep the document but exclude it from search, restoring it if the file comes back
+    Tombstone,
+    /// Delete the document and its fragments
+    Remove,
+}
+
+#[derive(clap::Args)]
+struct EmbedArgs {
+    #[command(flatten)]
+    storage: StorageArgs,
+    
+    /// Name of the embedding model (defaults to the model recorded in the database)
+    #[arg(short, long)]
+    model: Option<String>,
+    
+    /// Number of fragments to embed per batch
+    #[arg(long, default_value = "50")]
+    batch_size: i32,
+    
+    /// Stop after embedding this many fragments, to spread a re-embed over several runs
+    #[arg(long)]
+    max_fragments: Option<i32>,
+    
+    /// Embed this text with the database's model and print the vector as JSON instead of filling fragments
+    #[arg(long)]
+    text: Option<String>,
+    
+    /// Embed --text with the database's query prefix rather than its document prefix
+    #[arg(long, requires = "text")]
+    query: bool,
+    
+    /// How progress is reported: a bar with throughput and ETA, plain lines, or
+    /// newline-delimited JSON events on standard output for scripts
+    #[arg(long, value_enum, default_value = "bars")]
+    progress: ProgressMode,
+    
+    #[command(flatten)]
+    throttle: ThrottleArgs,
+    
+    #[command(flatten)]
+    provider: EmbeddingProviderArgs,
+}
+
+#[derive(clap::Args)]
+struct SearchArgs {
+    /// Database to search (repeat to federate across several databases; backend is inferred from each extension)
+    #[arg(short, long, required = true)]
+    database: Vec<PathBuf>,
+    
+    /// Storage backend to use (inferred from the file extension when omitted)
+    #[arg(short, long, value_enum)]
+    backend: Option<Backend>,
+    
+    /// Text to search for; `section:"Heading"` limits results to that section of DOCX/PDF documents
+    query: String,
+    
+    /// Number of results to return
+    #[arg(short = 'k', long, default_value = "5")]
+    limit: usize,
+    
+    /// Show query embedding statistics, per-candidate scores and why each result made the top-k
+    #[arg(long, conflicts_with = "cursor")]
+    explain: bool,
+    
+    /// Continue after the results of an earlier page, from the cursor it printed
+    #[arg(long)]
+    cursor: Option<String>,
+    
+    /// Rank by embedding similarity, BM25 keyword matching, or both fused (hybrid)
+    #[arg(long, value_enum, default_value_t = SearchMode::Vector)]
+    search_mode: SearchMode,
+    
+    /// Only return fragments from documents classified into this category (repeatable)
+    #[arg(long)]
+    category: Vec<String>,
+    
+    /// Only return fragments of this document, given by id, file name or path
+    #[arg(long)]
+    document: Option<String>,
+    
+    /// Rank only this document's fragments (by id, file name or path) and list the best
+    /// --limit in the order they appear in it, like a find-in-page by meaning
+    #[arg(long, value_name = "DOCUMENT", conflicts_with_all = ["document", "cursor", "per_document_limit"])]
+    within: Option<String>,
+    
+    /// Only return fragments mentioning this person, product or project. "who is X" and
+    /// "what is X" questions about a known entity are filtered this way automatically.
+    #[arg(long)]
+    entity: Option<String>,
+    
+    /// Only return fragments from documents in this collection (repeatable)
+    #[arg(long)]
+    collection: Vec<String>,
+    
+    /// Only return fragments from documents with this tag (repeatable; any tag matches)
+    #[arg(long)]
+    tag: Vec<String>,
+    
+    /// Only return fragments from documents of these file types, e.g. `--types pdf,docx`
+    /// (repeatable; any type matches)
+    #[arg(long, visible_alias = "types", value_delimiter = ',')]
+    file_type: Vec<String>,
+    
+    /// Only return fragments from documents whose path matches this glob; without a `/`
+    /// it's matched against the file name, e.g. "report-*.pdf"
+    #[arg(long)]
+    path: Option<String>,
+    
+    /// Only return fragments from documents modified on or after this date (YYYY-MM-DD)
+    #[arg(long)]
+    since: Option<String>,
+    
+    /// Only return fragments from documents modified on or before this date (YYYY-MM-DD)
+    #[arg(long)]
+    until: Option<String>,
+    
+    /// Cross-encoder model that re-scores the top 50 candidates and keeps the best --limit,
+    /// e.g. BAAI/bge-reranker-base
+    #[arg(long, conflicts_with = "cursor")]
+    rerank_model: Option<String>,
+    
+    /// Return at most this many fragments from each document, so a few long documents
+    /// don't fill the whole top-k; 1 collapses results to each document's best fragment
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..), conflicts_with = "cursor")]
+    per_document_limit: Option<u32>,
+    
+    /// With --per-document-limit, list the collapsed fragments under their document's result
+    #[arg(long, requires = "per_document_limit")]
+    expand: bool,
+    
+    /// TOML config file whose [retrieval] stages replace --search-mode, e.g. dense and bm25
+    /// retrieval fused by rrf, then rerank and mmr
+    #[arg(long)]
+    config: Option<PathBuf>,
+    
+    /// Name of the embedding model (defaults to the model recorded in the database)
+    #[arg(short, long)]
+    model: Option<String>,
+    
+    #[command(flatten)]
+    provider: EmbeddingProviderArgs,
+}
+
+#[derive(clap::Args)]
+struct EvalArgs {
+    /// Database to evaluate (repeat to compare databases indexed with other chunk sizes or
+    /// models; backend is inferred from each extension)
+    #[arg(short, long, required = true)]
+    database: Vec<PathBuf>,
+    
+    /// Storage backend to use (inferred from the file extension when omitted)
+    #[arg(short, long, value_enum)]
+    backend: Option<Backend>,
+    
+    /// JSON lines file of queries, each with the `documents` (file names or paths) or
+    /// `fragments` (ids) it should find
+    #[arg(short, long)]
+    queries: PathBuf,
+    
+    /// Number of results scored per query
+    #[arg(short = 'k', long, default_value = "10")]
+    limit: usize,
+    
+    /// Search mode to evaluate (repeatable; each is a configuration)
+    #[arg(long, value_enum, default_values_t = [SearchMode::Vector])]
+    search_mode: Vec<SearchMode>,
+    
+    /// TOML config file whose [retrieval] stages to evaluate (repeatable; each is a
+    /// configuration, in place of --search-mode)
+    #[arg(long)]
+    config: Vec<PathBuf>,
+    
+    /// Cross-encoder model to also evaluate every configuration reranked with
+    #[arg(long)]
+    rerank_model: Option<String>,
+    
+    /// Print the report as JSON
+    #[arg(long)]
+    json: bool,
+    
+    /// Name of the embedding model (defaults to the model recorded in each database)
+    #[arg(short, long)]
+    model: Option<String>,
+    
+    #[command(flatten)]
+    provider: EmbeddingProviderArgs,
+}
+
+#[derive(Clone, ValueEnum)]
+enum VizFormat {
+    /// Self-contained HTML scatter plot
+    Html,
+    /// fragment_id,document,x,y rows
+    Csv,
+    /// Full vectors as JSON lines for projecting with UMAP/t-SNE in Python
+    Raw,
+}
+
+#[derive(clap::Args)]
+struct VizArgs {
+    #[command(flatten)]
+    storage: StorageArgs,
+    
+    /// Output file to write
+    #[arg(short, long)]
+    output: PathBuf,
+    
+    /// Output format
+    #[arg(short, long, value_enum, default_value = "html")]
+    format: VizFormat,
+    
+    /// Maximum number of fragments to include
+    #[arg(long, default_value = "5000")]
+    sample: usize,
+}
+
+#[derive(clap::Args)]
+struct DriftArgs {
+    #[command(flatten)]
+    storage: StorageArgs,
+    
+    /// Number of embedded fragments to re-embed
+    #[arg(long, default_value = "200")]
+    sample: usize,
+    
+    /// Cosine deviation (1 - similarity) above which a fragment's vector counts as drifted
+    #[arg(long, default_value_t = drift::DEFAULT_THRESHOLD)]
+    threshold: f64,
+    
+    /// Name of the embedding model (defaults to the model recorded in the database)
+    #[arg(short, long)]
+    model: Option<String>,
+    
+    #[command(flatten)]
+    provider: EmbeddingProviderArgs,
+}
+
+#[derive(clap::Args)]
+struct DiffDbArgs {
+    /// Database before the update
+    old: PathBuf,
+    
+    /// Database after the update
+    new: PathBuf,
+    
+    /// Storage backend of both databases (inferred from each extension when omitted)
+    #[arg(short, long, value_enum)]
+    backend: Option<Backend>,
+    
+    /// Print the differences as JSON
+    #[arg(long)]
+    json: bool,
+}
+
+#[derive(clap::Args)]
+struct ListArgs {
+    #[command(flatten)]
+    storage: StorageArgs,
+    
+    /// Only show documents whose extraction looks garbled, worst first
+    #[arg(long)]
+    flagged: bool,
+}
+
+#[derive(clap::Args)]
+struct EntitiesArgs {
+    #[command(flatten)]
+    storage: StorageArgs,
+    
+    /// Entity to look up, e.g. "Maria Chen"; a surname or first word also matches
+    name: Option<String>,
+    
+    /// Number of entities to list
+    #[arg(short = 'k', long, default_value = "20")]
+    limit: usize,
+}
+
+#[derive(clap::Args)]
+struct InfoArgs {
+    #[command(flatten)]
+    storage: StorageArgs,
+}
+
+#[derive(clap::Args)]
+struct StatsArgs {
+    #[command(flatten)]
+    storage: StorageArgs,
+    
+    /// Number of largest documents to list
+    #[arg(long, default_value = "10")]
+    largest: usize,
+    
+    /// Print the statistics as JSON, for scripts
+    #[arg(long)]
+    json: bool,
+}
+
+#[derive(clap::Args)]
+struct RemoveArgs {
+    #[command(flatten)]
+    storage: StorageArgs,
+    
+    /// Path the document was indexed from, or its id as shown by `list`
+    #[arg(required_unless_present = "where_clause", conflicts_with = "where_clause")]
+    document: Option<String>,
+    
+    /// Delete every document matching this condition instead, e.g.
+    /// `file_type = "pptx" AND collection = "old"` (see `reorganize --help`)
+    #[arg(long = "where", value_name = "CONDITION")]
+    where_clause: Option<String>,
+    
+    /// With --where, list the documents that would be deleted without deleting them
+    #[arg(long, requires = "where_clause")]
+    dry_run: bool,
+}
+
+#[derive(clap::Args)]
+struct ReorganizeArgs {
+    #[command(flatten)]
+    storage: StorageArgs,
+    
+    /// Documents to change: conditions on file_type, collection, tag, category, path (a glob)
+    /// and id with `=` or `IN (...)`, and on modified with `<`, `<=`, `>` or `>=` a date,
+    /// joined by AND, e.g. `collection = "old" AND modified < "2020-01-01"`
+    #[arg(long = "where", value_name = "CONDITION")]
+    where_clause: String,
+    
+    /// Add this tag to each document (repeatable)
+    #[arg(long)]
+    add_tag: Vec<String>,
+    
+    /// Remove this tag from each document (repeatable)
+    #[arg(long)]
+    remove_tag: Vec<String>,
+    
+    /// Move the documents to this collection
+    #[arg(long, conflicts_with = "no_collection")]
+    collection: Option<String>,
+    
+    /// Take the documents out of their collection
+    #[arg(long)]
+    no_collection: bool,
+    
+    /// List the documents that would change without changing them
+    #[arg(long)]
+    dry_run: bool,
+}
+
+#[derive(clap::Args)]
+struct ExtractArgs {
+    #[command(flatten)]
+    storage: StorageArgs,
+    
+    /// Document to extract, by id, file name or the end of its path (repeat for several)
+    #[arg(long = "id", required_unless_present = "all")]
+    ids: Vec<String>,
+    
+    /// Extract every stored document
+    #[arg(long, conflicts_with = "ids")]
+    all: bool,
+    
+    /// Directory the originals are written to; created if needed
+    #[arg(short, long)]
+    out: PathBuf,
+}
+
+#[derive(clap::Args)]
+struct AnnotateArgs {
+    #[command(flatten)]
+    storage: StorageArgs,
+    
+    /// Fragment id as shown by `search`, or the file name, path or id of a document
+    target: Option<String>,
+    
+    /// Reviewer's verdict: authoritative results rank higher, outdated ones lower, and wrong
+    /// ones are never returned. Helpful and unhelpful are one user's thumbs up or down.
+    #[arg(short, long, value_enum)]
+    label: Option<AnnotationLabel>,
+    
+    /// Free-text note shown with search results
+    #[arg(short, long)]
+    note: Option<String>,
+    
+    /// Who is annotating (default: $USER)
+    #[arg(long)]
+    author: Option<String>,
+    
+    /// List the annotations on the target, or every annotation without one
+    #[arg(long, conflicts_with_all = ["label", "note", "remove"])]
+    list: bool,
+    
+    /// Delete the annotation with this id
+    #[arg(long, conflicts_with_all = ["target", "label", "note"])]
+    remove: Option<String>,
+    
+    /// Set how much labels move this database's rankings, as name=value (authoritative,
+    /// outdated, vote or max_votes); repeatable
+    #[arg(long, value_name = "NAME=VALUE", conflicts_with_all = ["target", "label", "note", "list", "remove"])]
+    weight: Vec<String>,
+}
+
+#[derive(clap::Args)]
+struct ExportArgs {
+    #[command(flatten)]
+    storage: StorageArgs,
+    
+    /// Output file to write, or directory for a bundle or static site
+    #[arg(short, long)]
+    output: PathBuf,
+    
+    /// Leave out fragment vectors
+    #[arg(long)]
+    no_embeddings: bool,
+    
+    /// Output format
+    #[arg(long, value_enum, default_value = "jsonl")]
+    format: ExportFormat,
+    
+    /// Write a directory holding a static search page instead, which embeds queries in the
+    /// browser and needs no server, e.g. to publish a small brain on GitHub Pages
+    #[arg(long, conflicts_with_all = ["format", "no_embeddings"])]
+    static_site: bool,
+    
+    /// transformers.js model the page embeds queries with; must produce the same vectors as
+    /// the database's model (default: its Xenova conversion)
+    #[arg(long, requires = "static_site")]
+    browser_model: Option<String>,
+}
+
+#[derive(Clone, ValueEnum)]
+enum ExportFormat {
+    /// One JSON object per document with its fragments
+    Jsonl,
+    /// One row per fragment, read from the database in Arrow batches
+    Parquet,
+    /// A directory holding the whole brain (meta values, documents with their original
+    /// files, fragments and vectors) that `import` loads into any backend
+    Bundle,
+}
+
+#[derive(clap::Args)]
+struct ImportArgs {
+    /// Bundle directory written by `export --format bundle`
+    input: PathBuf,
+    
+    #[command(flatten)]
+    storage: StorageArgs,
+}
+
+#[derive(clap::Args)]
+struct MigrateArgs {
+    /// Database to copy from (extension determines format: .db for DuckDB, .lancedb for LanceDB)
+    #[arg(long)]
+    from: PathBuf,
+    
+    /// New DuckDB database to copy into, a shard manifest or a remote brain URL
+    #[arg(long)]
+    to: PathBuf,
+}
+
+#[derive(clap::Args)]
+struct PackArgs {
+    #[command(flatten)]
+    storage: StorageArgs,
+    
+    /// Package file to write (default: the database's name with a .brain extension)
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+    
+    /// Name recorded in the manifest (default: the database's file name)
+    #[arg(long)]
+    name: Option<String>,
+    
+    /// Version of the brain recorded in the manifest, e.g. 2024.06
+    #[arg(long = "brain-version")]
+    version: Option<String>,
+    
+    /// Also store every document's original file in the package
+    #[arg(long)]
+    originals: bool,
+    
+    /// Sign the package with the ed25519 key in this file, generating one if it doesn't exist
+    #[arg(long)]
+    sign_key: Option<PathBuf>,
+}
+
+#[derive(clap::Args)]
+struct UnpackArgs {
+    /// Package written by `pack`
+    package: PathBuf,
+    
+    /// Database file to extract to (default: the package's name with a .db extension)
+    #[arg(short, long)]
+    database: Option<PathBuf>,
+    
+    /// Also extract the original files the package holds into this directory
+    #[arg(long)]
+    originals: Option<PathBuf>,
+    
+    /// Refuse the package unless it is signed by this public key (hex, or a file holding it)
+    #[arg(long)]
+    public_key: Option<String>,
+}
+
+#[derive(clap::Args)]
+struct VerifyArgs {
+    /// Package written by `pack`
+    package: PathBuf,
+    
+    /// Fail unless the package is signed by this public key (hex, or a file holding it)
+    #[arg(long)]
+    public_key: Option<String>,
+}
+
+#[derive(clap::Args)]
+struct ServeArgs {
+    #[command(flatten)]
+    storage: StorageArgs,
+    
+    /// Address to listen on
+    #[arg(long, default_value = "127.0.0.1:8080")]
+    bind: std::net::SocketAddr,
+    
+    /// Bearer token clients must send (defaults to the PORTABLE_BRAINS_TOKEN environment variable)
+    #[arg(long)]
+    token: Option<String>,
+    
+    /// Also run the embed phase for documents sent to POST /ingest, with the model recorded in the database
+    #[arg(long)]
+    embed: bool,
+    
+    /// Answer POST /search with the model recorded in the database (implied by --embed)
+    #[arg(long)]
+    search: bool,
+    
+    /// Searches whose results are kept in memory for repeated queries, least recently used
+    /// dropped first; 0 turns the cache off
+    #[arg(long, value_name = "N", default_value_t = server::DEFAULT_SEARCH_CACHE)]
+    cache_size: usize,
+    
+    /// TOML config file with collection routing rules, content scanning and cleanup settings for
+    /// ingested documents, extra access tokens and tenants; reloaded on SIGHUP or when it changes
+    #[arg(long)]
+    config: Option<PathBuf>,
+    
+    #[command(flatten)]
+    provider: EmbeddingProviderArgs,
+}
+
+#[derive(clap::Args)]
+struct RechunkArgs {
+    #[command(flatten)]
+    storage: StorageArgs,
+    
+    /// Target fragment length in characters (default: the recorded preset's, or 800)
+    #[arg(long)]
+    chunk_size: Option<usize>,
+    
+    /// Characters of trailing context repeated at the start of the next fragment
+    /// (default: the recorded preset's, or 100)
+    #[arg(long)]
+    overlap: Option<usize>,
+    
+    /// Chunking strategy (default: the recorded one, or sentence). With `token`, sizes are
+    /// in tokens and default to the character sizes divided by four.
+    #[arg(long, value_enum)]
+    chunking: Option<ChunkingStrategy>,
+    
+    /// TOML config file whose cleanup rules apply to documents indexed before their text
+    /// was saved, which are re-extracted from the stored original once
+    #[arg(long)]
+    config: Option<PathBuf>,
+}
+
+#[derive(clap::Args)]
+struct SummarizeArgs {
+    #[command(flatten)]
+    storage: StorageArgs,
+    
+    #[command(flatten)]
+    provider: EmbeddingProviderArgs,
+    
+    /// Chat completions endpoint of the model writing the summaries
+    #[arg(long, required_unless_present = "clear")]
+    llm_endpoint: Option<String>,
+    
+    /// Model writing the summaries
+    #[arg(long, default_value = "gpt-4")]
+    llm_model: String,
+    
+    /// API key for --llm-endpoint
+    #[arg(long)]
+    llm_api_key: Option<String>,
+    
+    /// API flavour of --llm-endpoint (default: guessed from the URL)
+    #[arg(long, value_enum)]
+    llm_provider: Option<Provider>,
+    
+    /// Passages summarized together, on average
+    #[arg(long, default_value_t = TreeOptions::default().cluster_size)]
+    cluster_size: usize,
+    
+    /// Levels of summaries to build above the fragments
+    #[arg(long, default_value_t = TreeOptions::default().levels)]
+    levels: u32,
+    
+    /// Fragments summarized, as a sample spread across documents when there are more
+    #[arg(long, default_value_t = TreeOptions::default().max_fragments)]
+    max_fragments: usize,
+    
+    /// Remove the summary tree instead of building one
+    #[arg(long)]
+    clear: bool,
+}
+
+#[derive(clap::Args)]
+struct CompactArgs {
+    /// Path to the DuckDB database file
+    #[arg(short, long)]
+    database: PathBuf,
+    
+    /// Return to the standard layout, whose vectors may have any length
+    #[arg(long)]
+    expand: bool,
+}
+
+#[derive(clap::Args)]
+struct McpArgs {
+    #[command(flatten)]
+    storage: StorageArgs,
+    
+    /// Name of the embedding model (defaults to the model recorded in the database)
+    #[arg(short, long)]
+    model: Option<String>,
+    
+    #[command(flatten)]
+    provider: EmbeddingProviderArgs,
+}
+
+#[derive(clap::Args)]
+struct JobsArgs {
+    /// Path to the database file
+    #[arg(short, long)]
+    database: PathBuf,
+    
+    /// Ask a queued or running job to stop after its current document or batch
+    #[arg(long)]
+    cancel: Option<String>,
+}
+
+impl IndexArgs {
+    fn discovery(&self) -> Discovery {
+        Discovery {
+            recursive: self.recursive,
+            include: self.include.clone(),
+            exclude: self.exclude.clone(),
+        }
+    }
+    
+    /// The connectors for `--source`, with downloads kept next to the database
+    fn open_sources(&self) -> Result<Vec<Box<dyn sources::Source>>> {
+        let context = sources::SourceContext {
+            discovery: self.discovery(),
+            cache_dir: storage::sidecar_path(&self.storage.database, ".sources"),
+        };
+        self.source.iter().map(|spec| sources::open(spec, &context)).collect()
+    }
+    
+    /// The input directory and sources, for messages
+    fn inputs(&self) -> String {
+        self.input_dir.iter()
+            .map(|dir| dir.display().to_string())
+            .chain(self.source.iter().cloned())
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+impl Backend {
+    fn storage_backend(&self) -> StorageBackend {
+        match self {
+            Backend::Duckdb => StorageBackend::DuckDB,
+            Backend::Lancedb => StorageBackend::LanceDB,
+            Backend::Remote => StorageBackend::Remote,
+        }
+    }
+}
+
+async fn open_storage(args: &StorageArgs) -> Result<Box<dyn Storage>> {
+    // A URL can only name a remote brain, whatever --backend says
+    let backend = match StorageBackend::from_path(&args.database) {
+        StorageBackend::Remote => StorageBackend::Remote,
+        _ => args.backend.storage_backend(),
+    };
+    if ShardedStorage::is_manifest(&args.database) {
+        println!("💾 Using sharded storage: {}", args.database.display());
+    } else {
+        println!("💾 Using {} backend: {}", backend.display_name(), args.database.display());
+    }
+    
+    create_storage(&backend, &args.database).await
+        .context("Failed to initialize storage backend")
+}
+
+async fn create_embedding_manager(model: &str, args: &EmbeddingProviderArgs) -> Result<EmbeddingManager> {
+    let manager = match args.embedding_provider {
+        EmbeddingProvider::Local => {
+            EmbeddingManager::new(model).await
+                .context("Failed to initialize local embedding manager")
+        },
+        EmbeddingProvider::Remote => {
+            let api_key = args.api_key.clone()
+                .ok_or_else(|| anyhow!("API key is required for remote embedding provider"))?;
+            
+            EmbeddingManager::new_remote(api_key, model, args.endpoint.clone()).await
+                .context("Failed to initialize remote embedding manager")
+        },
+        EmbeddingProvider::Ollama => {
+            EmbeddingManager::new_ollama(model, args.endpoint.clone()).await
+                .context("Failed to initialize Ollama embedding manager")
+        },
+        EmbeddingProvider::Compatible => {
+            let endpoint = args.endpoint.clone()
+                .ok_or_else(|| anyhow!("--endpoint is required for the compatible embedding provider, e.g. http://localhost:8080/v1/embeddings"))?;
+            
+            EmbeddingManager::new_compatible(model, endpoint, args.api_key.clone()).await
+                .context("Failed to initialize remote embedding manager")
+        },
+    }?.with_max_retries(args.max_retries);
+    
+    Ok(match args.requests_per_minute {
+        Some(requests) => manager.with_requests_per_minute(requests),
+        None => manager,
+    })
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    // Profile settings are filled in as flags, so those on the command line win
+    let (args, profile) = profiles::apply(std::env::args_os().collect(), &Cli::command())?;
+    let cli = Cli::parse_from(args);
+    
+    // Initialize logging with cleaner output
+    let log_level = if cli.verbose { "debug" } else { "info" };
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(log_level))
+        .filter_module("lopdf", log::LevelFilter::Warn)         // Suppress lopdf debug messages
+        .filter_module("duckdb", log::LevelFilter::Warn)        // Suppress duckdb debug messages  
+        .filter_module("ort", log::LevelFilter::Warn)           // Suppress ONNX runtime debug messages
+        .filter_module("html5ever", log::LevelFilter::Warn)     // Suppress HTML parser debug messages
+        .filter_module("selectors", log::LevelFilter::Warn)     // Suppress CSS selector debug messages
+        .filter_module("lancedb", log::LevelFilter::Warn)       // Suppress LanceDB debug messages
+        .format_target(false)                                   // Hide module names in output
+        .format_timestamp(None)                                 // Hide timestamps for cleaner output
+        .init();
+    
+    // Logged rather than printed, since `mcp` keeps stdout for the protocol
+    if offline::configure(cli.strict_offline) {
+        log::info!("🔒 Strict offline mode: network access is disabled");
+    }
+    remote_storage::configure(cli.remote_api_key.clone());
+    model_downloads::configure(cli.model_mirror.clone(), cli.model_checksums.clone())?;
+    if let Some(profile) = profile {
+        log::info!("🧩 Using profile {}", profile);
+    }
+    
+    match cli.command {
+        Command::Index(args) => run_index(args, cli.verbose).await,
+        Command::Watch(args) => run_watch(args, cli.verbose).await,
+        Command::RetryFailed(args) => run_retry_failed(args, cli.verbose).await,
+        Command::Embed(args) => run_embed(args).await,
+        Command::Search(args) => run_search(args).await,
+        Command::Eval(args) => run_eval(args).await,
+        Command::Viz(args) => run_viz(args).await,
+        Command::Drift(args) => run_drift(args).await,
+        Command::DiffDb(args) => run_diff_db(args).await,
+        Command::List(args) => run_list(args).await,
+        Command::Entities(args) => run_entities(args).await,
+        Command::Info(args) => run_info(args).await,
+        Command::Stats(args) => run_stats(args).await,
+        Command::Remove(args) => run_remove(args).await,
+        Command::Reorganize(args) => run_reorganize(args).await,
+        Command::Extract(args) => run_extract(args).await,
+        Command::Annotate(args) => run_annotate(args).await,
+        Command::Export(args) => run_export(args).await,
+        Command::Import(args) => run_import(args).await,
+        Command::Migrate(args) => run_migrate(args).await,
+        Command::Pack(args) => run_pack(args).await,
+        Command::Unpack(args) => run_unpack(args),
+        Command::Verify(args) => run_verify(args),
+        Command::Rechunk(args) => run_rechunk(args).await,
+        Command::Compact(args) => run_compact(args).await,
+        Command::Summarize(args) => run_summarize(args).await,
+        Command::Serve(args) => run_serve(args).await,
+        Command::Openapi => run_openapi(),
+        Command::Mcp(args) => run_mcp(args).await,
+        Command::Jobs(args) => run_jobs(args),
+    }
+}
+
+fn shard_strategy(args: &IndexArgs) -> Result<Option<ShardStrategy>> {
+    match (&args.shard_by, args.shards) {
+        (Some(ShardBy::Collection), None) => Ok(Some(ShardStrategy::Collection)),
+        (Some(ShardBy::Collection), Some(_)) => anyhow::bail!("--shards only applies to --shard-by hash"),
+        (Some(ShardBy::Hash), None) => anyhow::bail!("--shard-by hash requires --shards <N>"),
+        (_, Some(count)) => Ok(Some(ShardStrategy::Hash { count })),
+        (None, None) => Ok(None),
+    }
+}
+
+async fn run_index(args: IndexArgs, verbose: bool) -> Result<()> {
+    let mut job = JobStore::for_database(&args.storage.database).create(JobKind::Ingest, Vec::new())?;
+    let mut progress = Progress::new(args.progress).with_profiler(args.profile_stages.clone());
+    let result = index_directory(args, verbose, &mut job, &mut progress).await;
+    progress.summary();
+    job.finish(&result)?;
+    result
+}
+
+async fn index_directory(args: IndexArgs, verbose: bool, job: &mut Job, progress: &mut Progress) -> Result<()> {
+    println!("🧠 Portable Brains - Document Indexer");
+    println!("📋 Job {} (cancel with `portable-brains jobs --cancel`)", job.id());
+    if let Some(input_dir) = &args.input_dir {
+        println!("📁 Scanning directory: {}", input_dir.display());
+        
+        // Validate input directory exists
+        if !input_dir.exists() {
+            anyhow::bail!("Input directory does not exist: {}", input_dir.display());
+        }
+    }
+    let mut sources = args.open_sources()?;
+    
+    // Create the shard manifest before opening when sharding was requested
+    if let Some(strategy) = shard_strategy(&args)? {
+        ShardedStorage::create_manifest(&args.storage.database, &args.storage.backend.storage_backend(), strategy)?;
+    }
+    
+    // Initialize storage backend
+    let mut storage = open_storage(&args.storage).await?;
+    
+    // Record the embedding model so the embed phase knows which model to use
+    adopt_model(&mut *storage, &args.model, None).await?;
+    if let Some(path) = &args.config {
+        configure_collection_models(&mut *storage, &Config::load(path)?).await?;
+    }
+    recover_interrupted(&mut *storage).await?;
+    configure_prefixes(&mut *storage, &args).await?;
+    if args.deterministic {
+        enable_deterministic(&mut *storage).await?;
+    }
+    configure_embeddings_only(&mut *storage, &args).await?;
+    configure_originals(&mut *storage, args.store_originals).await?;
+    configure_similarity(&mut *storage, args.similarity).await?;
+    if args.late_chunking {
+        storage.set_meta_value(storage::LATE_CHUNKING_KEY, "true").await?;
+        println!("🧩 Late chunking: fragments are embedded in the context of their whole section");
+    }
+    let preset = resolve_preset(&mut *storage, args.preset).await?;
+    let chunking = resolve_chunking(&mut *storage, args.chunking).await?;
+    
+    let mut pipeline = build_pipeline(&args, preset, chunking).await?;
+    
+    // Commit documents staged by an interrupted run before indexing anything new
+    let staging = ingest_queue::staging_dir(&args.storage.database);
+    if !args.staged && ingest_queue::has_pending(&staging)? {
+        storage = run_staged(storage, &[], &mut pipeline, &staging, verbose, job, progress).await?;
+    }
+    
+    // Phase 1: Process all supported files and extract text (no embeddings yet)
+    let mut supported_files = match &args.input_dir {
+        Some(input_dir) => args.discovery().find_files(input_dir)?,
+        None => Vec::new(),
+    };
+    let mut replaces = HashMap::new();
+    if args.update && !supported_files.is_empty() {
+        (supported_files, replaces) = plan_update(&mut *storage, supported_files).await?;
+    }
+    // Documents gone from a source stay indexed, as those of deleted files do
+    let (source_files, source_replaces, synced) = sync_sources(&mut *storage, &mut sources, DeletePolicy::Keep).await?;
+    supported_files.extend(source_files);
+    replaces.extend(source_replaces);
+    println!("📂 Found {} documents to process", supported_files.len());
+    
+    if supported_files.is_empty() {
+        for pending in synced {
+            sources::commit(&mut *storage, pending).await?;
+        }
+        match &args.input_dir {
+            Some(input_dir) if args.source.is_empty() => println!("⚠️  No supported files found in directory: {}", input_dir.display()),
+            _ => println!("⚠️  No new or changed documents in {}", args.inputs()),
+        }
+        println!("📋 Supported formats: PDF, TXT, HTML, DOCX, PPTX, XLSX, Markdown, EPUB, CSV, TSV, EML");
+        return Ok(());
+    }
+    
+    println!("\n🚀 Phase 1: Extracting text from documents...");
+    job.set_stage("extracting", supported_files.len() as u64)?;
+    let mut single_pass = args.single_pass;
+    if single_pass && storage::late_chunking_enabled(&mut *storage).await? {
+        println!("⚠️  Late chunking embeds whole sections once they are extracted; indexing in two phases");
+        single_pass = false;
+    }
+    let mut embedder = None;
+    if args.staged {
+        storage = run_staged(storage, &supported_files, &mut pipeline, &staging, verbose, job, progress).await?;
+    } else if single_pass {
+        let embedding_manager = create_embedding_manager(&args.model, &args.provider).await?;
+        let (returned, embedding_manager) = run_single_pass(
+            storage, &supported_files, &replaces, &mut pipeline, embedding_manager, verbose, job, progress,
+        ).await?;
+        storage = returned;
+        embedder = Some(embedding_manager);
+    } else {
+        index_files(&supported_files, &replaces, &mut *storage, &mut pipeline, job, progress, verbose).await?;
+    }
+    for pending in synced {
+        sources::commit(&mut *storage, pending).await?;
+    }
+    
+    // After a single pass this only retries fragments that failed and builds the index
+    if args.embed || args.single_pass {
+        let mut embedding_manager = match (embedder, pipeline.classifier) {
+            (Some(embedding_manager), _) => embedding_manager,
+            (None, Some(classifier)) => classifier.into_embedding_manager(),
+            (None, None) => create_embedding_manager(&args.model, &args.provider).await?,
+        };
+        embed_pending_fragments(&mut *storage, &mut embedding_manager, 50, None, &mut pipeline.throttle, job, progress).await?;
+        embed_collection_spaces(&mut *storage, &args.provider, 50, None, &mut pipeline.throttle, job, progress).await?;
+    } else {
+        let pending = storage.count_fragments_without_embeddings().await?;
+        println!("\nℹ️  {} fragments are waiting for embeddings; run `portable-brains embed` to generate them", pending);
+    }
+    
+    if let Err(e) = dashboard::record_growth(&mut *storage, &args.storage.database).await {
+        println!("⚠️  Failed to record storage growth: {:#}", e);
+    }
+    refresh_autocomplete(&mut *storage).await;
+    println!("\n🎉 Indexing completed successfully!");
+    Ok(())
+}
+
+/// Rebuild the terms queries are completed with from the documents just indexed. Completions
+/// are a convenience, so failing to rebuild them is only reported.
+async fn refresh_autocomplete(storage: &mut dyn Storage) {
+    if let Err(e) = autocomplete::refresh(storage).await {
+        println!("⚠️  Failed to update query completions: {:#}", e);
+    }
+}
+
+/// Times a file failing with a retryable error, such as a locked database, is tried again
+/// before the run stops
+const RETRY_ATTEMPTS: u32 = 3;
+
+/// Extract each file into storage in turn. A file that can't be indexed is skipped, one
+/// hitting a passing condition is retried, and a failure every later file would share stops
+/// the run. What became of each file is reported to `progress`, which sums it up at the end.
+/// Files in `replaces` take the place of the outdated document indexed from them.
+async fn index_files(
+    files: &[PathBuf],
+    replaces: &HashMap<PathBuf, String>,
+    storage: &mut dyn Storage,
+    pipeline: &mut IngestPipeline,
+    job: &mut Job,
+    progress: &mut Progress,
+    verbose: bool,
+) -> Result<()> {
+    progress.start_phase("extract", "Extracting", files.len() as u64);
+    for (i, file_path) in files.iter().enumerate() {
+        if job.is_cancelled() {
+            progress.println(format!("⏹️  Cancelled after {} of {} documents", i, files.len()));
+            break;
+        }
+        
+        progress.start_file(file_path);
+        let outdated = replaces.get(file_path).map(String::as_str);
+        let mut retries = 0;
+        let result = loop {
+            let mut stages = StageTimings::default();
+            let attempt = process_document(file_path, storage, pipeline, outdated, &mut stages).await;
+            progress.record_stages(&stages);
+            match attempt {
+                Err(e) if error::severity(&e) == Severity::Retryable && retries < RETRY_ATTEMPTS => {
+                    retries += 1;
+                    progress.retry(file_path, &e.to_string(), retries, RETRY_ATTEMPTS);
+                    tokio::time::sleep(std::time::Duration::from_secs(1 << retries)).await;
+                    // Clear whatever the failed attempt half-wrote; if this fails too, so
+                    // does the next attempt
+                    let _ = storage::remove_interrupted(storage).await;
+                }
+                result => break result,
+            }
+        };
+        match result {
+            Ok(outcome) => progress.finish_file(file_path, outcome),
+            Err(e) => {
+                progress.finish_file(file_path, FileOutcome::failed(&e));
+                record_failure(job, file_path, &e)?;
+                if verbose {
+                    progress.suspend(|| eprintln!("   Error details: {:?}", e));
+                }
+                if error::severity(&e) != Severity::Skip {
+                    return Err(e.context(format!("Indexing stopped at {}", file_path.display())));
+                }
+                // Continue processing other files
+            }
+        }
+        job.advance(1)?;
+    }
+    progress.finish_phase();
+    Ok(())
+}
+
+/// Record a file that couldn't be indexed on the job, so `retry-failed` can find it later
+fn record_failure(job: &mut Job, file_path: &Path, e: &anyhow::Error) -> Result<()> {
+    let name = paths::normalize(file_path).display().to_string();
+    job.add_item(name, ItemOutcome::Failed, Some(format!("{:#}", e)))
+}
+
+/// Throttle, classifier, routing and scanning for an index or watch run
+async fn build_pipeline(args: &IndexArgs, preset: Option<Preset>, chunking: Chunking) -> Result<IngestPipeline> {
+    // Applied before any embedding model is loaded so its thread pool respects the core limit
+    let throttle = Throttle::new(&args.throttle.settings())?;
+    
+    // Classification embeds a short excerpt of every document with the index model
+    let classifier = if args.classify {
+        let labels = match &args.labels {
+            Some(path) => classifier::load_labels(path)?,
+            None => classifier::default_labels(),
+        };
+        println!("🏷️  Classifying documents into: {}", labels.iter().map(|l| l.name.as_str()).collect::<Vec<_>>().join(", "));
+        let embedding_manager = create_embedding_manager(&args.model, &args.provider).await?;
+        Some(ZeroShotClassifier::new(labels, embedding_manager).await?)
+    } else {
+        None
+    };
+    
+    let (processor, router, scanner) = configure_ingest(args.config.as_deref(), preset, &args.storage.database)?;
+    if args.tags.iter().any(|tag| tag.trim().is_empty()) {
+        anyhow::bail!("--tags can't contain an empty tag");
+    }
+    
+    let mut processor = chunking.apply(processor)?
+        .with_max_file_size(args.max_file_size as usize * 1024 * 1024);
+    if args.password_file.is_some() || args.ask_password {
+        let passwords = match &args.password_file {
+            Some(path) => Passwords::load(path)?,
+            None => Passwords::default(),
+        };
+        processor = processor.with_passwords(std::sync::Arc::new(passwords.with_prompt(args.ask_password)));
+    }
+    if args.chunk_size.is_some() || args.overlap.is_some() {
+        let (chunk_size, overlap, unit) = chunk_sizes(preset, chunking.strategy, args.chunk_size, args.overlap);
+        println!("✂️  Chunk size {} with {} {} of overlap", chunk_size, overlap, unit);
+        processor = processor.with_chunking(chunk_size, overlap);
+    }
+    
+    Ok(IngestPipeline {
+        processor,
+        preset,
+        chunking,
+        priority: args.priority,
+        assigned: Routing {
+            collection: args.collection.clone(),
+            tags: args.tags.iter().map(|tag| tag.trim().to_string()).collect(),
+        },
+        classifier,
+        router,
+        scanner,
+        throttle,
+    })
+}
+
+async fn run_watch(args: WatchArgs, verbose: bool) -> Result<()> {
+    let index = &args.index;
+    println!("🧠 Portable Brains - Watching {}", index.inputs());
+    
+    if let Some(input_dir) = index.input_dir.as_ref().filter(|dir| !dir.is_dir()) {
+        anyhow::bail!("Input directory does not exist: {}", input_dir.display());
+    }
+    let mut sources = index.open_sources()?;
+    if index.staged || index.single_pass || index.shards.is_some() || index.shard_by.is_some() {
+        anyhow::bail!("--staged, --single-pass and the sharding options aren't supported by watch; run `index` once first");
+    }
+    
+    let mut storage = open_storage(&index.storage).await?;
+    adopt_model(&mut *storage, &index.model, None).await?;
+    recover_interrupted(&mut *storage).await?;
+    configure_prefixes(&mut *storage, index).await?;
+    if index.deterministic {
+        enable_deterministic(&mut *storage).await?;
+    }
+    configure_embeddings_only(&mut *storage, index).await?;
+    configure_originals(&mut *storage, index.store_originals).await?;
+    configure_similarity(&mut *storage, index.similarity).await?;
+    if index.late_chunking {
+        storage.set_meta_value(storage::LATE_CHUNKING_KEY, "true").await?;
+        println!("🧩 Late chunking: fragments are embedded in the context of their whole section");
+    }
+    let preset = resolve_preset(&mut *storage, index.preset).await?;
+    let chunking = resolve_chunking(&mut *storage, index.chunking).await?;
+    let mut pipeline = build_pipeline(index, preset, chunking).await?;
+    
+    // The classifier's embedding manager doubles for the embed phase
+    let mut embedding_manager = if index.embed && pipeline.classifier.is_none() {
+        Some(create_embedding_manager(&index.model, &index.provider).await?)
+    } else {
+        None
+    };
+    
+    let deletions = match args.on_delete {
+        DeletePolicy::Keep => "kept searchable",
+        DeletePolicy::Tombstone => "tombstoned",
+        DeletePolicy::Remove => "removed",
+    };
+    println!("👀 Checking every {}s; documents whose files are deleted or renamed are {}", args.interval, deletions);
+    
+    // Cleanup, routing and scanning settings follow edits to the config file (or SIGHUP)
+    let mut live = index.config.as_deref().map(LiveConfig::watch).transpose()?;
+    
+    let discovery = index.discovery();
+    let jobs = JobStore::for_database(&index.storage.database);
+    loop {
+        if let Some(live) = &mut live {
+            if let Some(config) = live.changed() {
+                if let Err(e) = pipeline.reconfigure(&config, live.path(), &index.storage.database) {
+                    println!("⚠️  Keeping the previous ingest settings: {:#}", e);
+                }
+            }
+        }
+        
+        let (mut new_files, mut replaces) = match &index.input_dir {
+            Some(input_dir) => reconcile_directory(&mut *storage, input_dir, &discovery, args.on_delete, index.update).await?,
+            None => (Vec::new(), HashMap::new()),
+        };
+        // A source that can't be reached is tried again on the next scan
+        let synced = match sync_sources(&mut *storage, &mut sources, args.on_delete).await {
+            Ok((files, source_replaces, synced)) => {
+                new_files.extend(files);
+                replaces.extend(source_replaces);
+                synced
+            }
+            Err(e) => {
+                println!("⚠️  {:#}", e);
+                Vec::new()
+            }
+        };
+        
+        if new_files.is_empty() {
+            for pending in synced {
+                sources::commit(&mut *storage, pending).await?;
+            }
+        } else {
+            let mut job = jobs.create(JobKind::Ingest, Vec::new())?;
+            if replaces.is_empty() {
+                println!("\n📂 {} new documents (job {})", new_files.len(), job.id());
+            } else {
+                println!("\n📂 {} new and {} changed documents (job {})", new_files.len() - replaces.len(), replaces.len(), job.id());
+            }
+            
+            let mut progress = Progress::new(index.progress).with_profiler(index.profile_stages.clone());
+            let mut result = async {
+                job.set_stage("extracting", new_files.len() as u64)?;
+                index_files(&new_files, &replaces, &mut *storage, &mut pipeline, &mut job, &mut progress, verbose).await?;
+                for pending in synced {
+                    sources::commit(&mut *storage, pending).await?;
+                }
+                Ok::<_, anyhow::Error>(())
+            }.await;
+            
+            let embedder = match (&mut pipeline.classifier, &mut embedding_manager) {
+                (Some(classifier), _) if index.embed => Some(classifier.embedding_manager()),
+                (_, Some(embedding_manager)) => Some(embedding_manager),
+                _ => None,
+            };
+            if let (Ok(()), Some(embedder)) = (&result, embedder) {
+                result = embed_pending_fragments(&mut *storage, embedder, 50, None, &mut pipeline.throttle, &mut job, &mut progress).await;
+            }
+            progress.summary();
+            
+            // A failed pass is reported and retried on the next scan rather than ending the watch
+            if let Err(e) = &result {
+                println!("❌ {:#}", e);
+            }
+            job.finish(&result)?;
+            refresh_autocomplete(&mut *storage).await;
+        }
+        
+        tokio::select! {
+            _ = tokio::time::sleep(std::time::Duration::from_secs(args.interval)) => {}
+            _ = tokio::signal::ctrl_c() => {
+                println!("\n👋 Stopped watching {}", index.inputs());
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Index the files whose latest attempt, in any earlier ingest job, failed. Files outside the
+/// input directory, since deleted or indexed by now are left out.
+async fn run_retry_failed(args: RetryFailedArgs, verbose: bool) -> Result<()> {
+    let index = &args.index;
+    println!("🧠 Portable Brains - Retrying failed documents");
+    
+    let input_dir = index.input_dir.as_ref()
+        .ok_or_else(|| anyhow!("retry-failed retries files under --input-dir; sources retry a document when it changes"))?;
+    if !input_dir.is_dir() {
+        anyhow::bail!("Input directory does not exist: {}", input_dir.display());
+    }
+    if index.staged || index.single_pass || index.update || index.shards.is_some() || index.shard_by.is_some() {
+        anyhow::bail!("--staged, --single-pass, --update and the sharding options aren't supported by retry-failed");
+    }
+    
+    let jobs = JobStore::for_database(&index.storage.database);
+    let failed = jobs::failed_items(&jobs.list()?);
+    let mut storage = open_storage(&index.storage).await?;
+    let discovery = index.discovery();
+    let input_dir = paths::normalize(input_dir);
+    let mut files = Vec::new();
+    for item in failed {
+        let file_path = PathBuf::from(&item.name);
+        if !file_path.is_file() || !discovery.covers(&input_dir, &file_path) || storage.document_exists(&file_path).await? {
+            continue;
+        }
+        println!("   {} ({})", file_path.display(), item.detail.as_deref().unwrap_or("no details"));
+        files.push(file_path);
+    }
+    
+    if files.is_empty() {
+        println!("✅ No failed documents to retry under {}", input_dir.display());
+        return Ok(());
+    }
+    println!("📂 {} failed documents to retry", files.len());
+    if args.dry_run {
+        return Ok(());
+    }
+    
+    adopt_model(&mut *storage, &index.model, None).await?;
+    recover_interrupted(&mut *storage).await?;
+    configure_prefixes(&mut *storage, index).await?;
+    if index.deterministic {
+        enable_deterministic(&mut *storage).await?;
+    }
+    configure_embeddings_only(&mut *storage, index).await?;
+    configure_originals(&mut *storage, index.store_originals).await?;
+    configure_similarity(&mut *storage, index.similarity).await?;
+    if index.late_chunking {
+        storage.set_meta_value(storage::LATE_CHUNKING_KEY, "true").await?;
+    }
+    let preset = resolve_preset(&mut *storage, index.preset).await?;
+    let chunking = resolve_chunking(&mut *storage, index.chunking).await?;
+    let mut pipeline = build_pipeline(index, preset, chunking).await?;
+    
+    let mut job = jobs.create(JobKind::Ingest, Vec::new())?;
+    println!("📋 Job {} (cancel with `portable-brains jobs --cancel`)", job.id());
+    let mut progress = Progress::new(index.progress).with_profiler(index.profile_stages.clone());
+    let result = async {
+        job.set_stage("extracting", files.len() as u64)?;
+        index_files(&files, &HashMap::new(), &mut *storage, &mut pipeline, &mut job, &mut progress, verbose).await?;
+        if index.embed {
+            let mut embedding_manager = match pipeline.classifier.take() {
+                Some(classifier) => classifier.into_embedding_manager(),
+                None => create_embedding_manager(&index.model, &index.provider).await?,
+            };
+            embed_pending_fragments(&mut *storage, &mut embedding_manager, 50, None, &mut pipeline.throttle, &mut job, &mut progress).await?;
+        }
+        Ok(())
+    }.await;
+    progress.summary();
+    job.finish(&result)?;
+    result
+}
+
+/// Compare a watched directory with the documents indexed from it. Documents whose file
+/// is gone get the delete policy, tombstoned documents whose file is back are restored,
+/// and the files not indexed yet are returned. With `update`, files whose content changed
+/// are returned too, mapped to the document each replaces.
+async fn reconcile_directory(
+    storage: &mut dyn Storage,
+    dir: &Path,
+    discovery: &Discovery,
+    policy: DeletePolicy,
+    update: bool,
+) -> Result<(Vec<PathBuf>, HashMap<PathBuf, String>)> {
+    let dir_key = paths::StoredPath::new(dir).key;
+    let indexed: HashMap<String, DocumentSummary> = storage.list_documents().await?
+        .into_iter()
+        .filter(|document| discovery.covers(Path::new(&dir_key), Path::new(&document.file_path)))
+        .map(|document| (document.file_path.clone(), document))
+        .collect();
+    
+    let mut new_files = Vec::new();
+    let mut replaces = HashMap::new();
+    let mut present = HashSet::new();
+    let mut changed = false;
+    for file_path in discovery.find_files(dir)? {
+        let key = paths::StoredPath::new(&file_path).key;
+        match indexed.get(&key) {
+            Some(document) => {
+                if document.tombstoned {
+                    storage.tombstone_document(&document.id, false).await?;
+                    println!("♻️  {} is back; restored it to search", document.file_path);
+                    changed = true;
+                }
+                if update && document_changed(storage, &file_path, document).await? {
+                    replaces.insert(file_path.clone(), document.id.clone());
+                    new_files.push(file_path);
+                }
+            }
+            // Documents indexed before paths were normalized are found by their old spelling
+            None if storage.document_exists(&file_path).await? => {}
+            None => new_files.push(file_path),
+        }
+        present.insert(key);
+    }
+    
+    for document in indexed.values().filter(|document| !present.contains(&document.file_path)) {
+        changed |= apply_delete_policy(storage, document, policy).await?;
+    }
+    if changed {
+        storage::publish(storage).await?;
+    }
+    
+    Ok((new_files, replaces))
+}
+
+/// Apply a delete policy to a document whose file or source item is gone. Returns whether
+/// the document changed.
+async fn apply_delete_policy(storage: &mut dyn Storage, document: &DocumentSummary, policy: DeletePolicy) -> Result<bool> {
+    match policy {
+        DeletePolicy::Keep => Ok(false),
+        DeletePolicy::Tombstone if document.tombstoned => Ok(false),
+        DeletePolicy::Tombstone => {
+            storage.tombstone_document(&document.id, true).await?;
+            println!("🪦 {} was deleted or renamed; excluded it from search", document.file_path);
+            Ok(true)
+        }
+        DeletePolicy::Remove => {
+            storage.remove_document(&document.id).await?;
+            println!("🗑️  {} was deleted or renamed; removed it", document.file_path);
+            Ok(true)
+        }
+    }
+}
+
+/// Sync each source, returning the files to index, the changed ones mapped to the document
+/// each replaces, and the syncs to commit once they are indexed. Documents of items gone
+/// from a source get the delete policy, and tombstoned ones whose item is back are restored.
+async fn sync_sources(
+    storage: &mut dyn Storage,
+    sources: &mut [Box<dyn sources::Source>],
+    policy: DeletePolicy,
+) -> Result<(Vec<PathBuf>, HashMap<PathBuf, String>, Vec<sources::Pending>)> {
+    if sources.is_empty() {
+        return Ok((Vec::new(), HashMap::new(), Vec::new()));
+    }
+    let indexed: HashMap<String, DocumentSummary> = storage.list_documents().await?
+        .into_iter()
+        .map(|document| (document.file_path.clone(), document))
+        .collect();
+    
+    let mut files = Vec::new();
+    let mut synced = Vec::new();
+    let mut changed = false;
+    for source in sources.iter_mut() {
+        let pending = sources::sync(storage, source.as_mut()).await?;
+        if !pending.files.is_empty() || !pending.removed.is_empty() {
+            println!("🔌 {}: {} new or changed, {} gone", pending.source_id, pending.files.len(), pending.removed.len());
+        }
+        for file_path in &pending.files {
+            if let Some(document) = indexed.get(&paths::StoredPath::new(file_path).key).filter(|document| document.tombstoned) {
+                storage.tombstone_document(&document.id, false).await?;
+                println!("♻️  {} is back; restored it to search", document.file_path);
+                changed = true;
+            }
+        }
+        for file_path in &pending.removed {
+            if let Some(document) = indexed.get(&paths::StoredPath::new(file_path).key) {
+                changed |= apply_delete_policy(storage, document, policy).await?;
+            }
+        }
+        files.extend(pending.files.iter().cloned());
+        synced.push(pending);
+    }
+    if changed {
+        storage::publish(storage).await?;
+    }
+    
+    // A new version of an item is only indexed again when its content changed
+    let (files, replaces) = if files.is_empty() {
+        (files, HashMap::new())
+    } else {
+        plan_update(storage, files).await?
+    };
+    Ok((files, replaces, synced))
+}
+
+/// Split the files found by an `--update` run into those to index, new or changed, and
+/// map each changed file to the document it replaces
+async fn plan_update(storage: &mut dyn Storage, files: Vec<PathBuf>) -> Result<(Vec<PathBuf>, HashMap<PathBuf, String>)> {
+    let indexed: HashMap<String, DocumentSummary> = storage.list_documents().await?
+        .into_iter()
+        .map(|document| (document.file_path.clone(), document))
+        .collect();
+    
+    let mut pending = Vec::new();
+    let mut replaces = HashMap::new();
+    let mut unchanged = 0;
+    for file_path in files {
+        match indexed.get(&paths::StoredPath::new(&file_path).key) {
+            Some(document) => {
+                if document_changed(storage, &file_path, document).await? {
+                    replaces.insert(file_path.clone(), document.id.clone());
+                    pending.push(file_path);
+                } else {
+                    unchanged += 1;
+                }
+            }
+            // Documents indexed before paths were normalized can't be matched up; leave them be
+            None if storage.document_exists(&file_path).await? => unchanged += 1,
+            None => pending.push(file_path),
+        }
+    }
+    
+    println!("🔁 {} changed, {} new, {} unchanged", replaces.len(), pending.len() - replaces.len(), unchanged);
+    Ok((pending, replaces))
+}
+
+/// Whether a file's content differs from the document indexed from it. Files whose
+/// modification time matches the recorded one aren't read; a file that was only touched
+/// has its new time recorded, so the next check skips it too.
+async fn document_changed(storage: &mut dyn Storage, file_path: &Path, document: &DocumentSummary) -> Result<bool> {
+    let modified = storage::modified_micros(file_path);
+    if document.content_hash.is_some() && modified.is_some() && modified == document.modified {
+        return Ok(false);
+    }
+    
+    let data = std::fs::read(file_path)
+        .with_context(|| format!("Failed to read {}", file_path.display()))?;
+    let hash = storage::content_hash(&data);
+    let recorded = match &document.content_hash {
+        Some(recorded) => Some(recorded.clone()),
+        // Documents indexed before hashes were recorded are compared with their stored original
+        None => storage.get_document(&document.id).await?
+            .and_then(|original| original.file_data.map(|data| storage::content_hash(&data))),
+    };
+    if recorded.as_deref() != Some(hash.as_str()) {
+        return Ok(true);
+    }
+    
+    storage.set_document_source(&document.id, &hash, modified).await?;
+    Ok(false)
+}
+
+async fn run_embed(args: EmbedArgs) -> Result<()> {
+    if let Some(text) = &args.text {
+        return embed_text_to_json(&args, text).await;
+    }
+    
+    println!("🧠 Portable Brains - Embedding Generator");
+    
+    let mut storage = open_storage(&args.storage).await?;
+    
+    let model = resolve_model(&mut *storage, args.model).await?;
+    
+    let mut embedding_manager = create_embedding_manager(&model, &args.provider).await?;
+    adopt_model(&mut *storage, &model, embedding_manager.known_dimension()).await?;
+    println!("🤖 Embedding model: {}", model);
+    
+    let mut throttle = Throttle::new(&args.throttle.settings())?;
+    
+    let mut job = JobStore::for_database(&args.storage.database).create(JobKind::Embed, Vec::new())?;
+    println!("📋 Job {} (cancel with `portable-brains jobs --cancel`)", job.id());
+    let mut progress = Progress::new(args.progress);
+    let mut result = embed_pending_fragments(&mut *storage, &mut embedding_manager, args.batch_size, args.max_fragments, &mut throttle, &mut job, &mut progress).await;
+    if result.is_ok() {
+        result = embed_collection_spaces(&mut *storage, &args.provider, args.batch_size, args.max_fragments, &mut throttle, &mut job, &mut progress).await;
+    }
+    job.finish(&result)?;
+    result
+}
+
+/// Give the collections the config file names a model for that model
+async fn configure_collection_models(storage: &mut dyn Storage, config: &Config) -> Result<()> {
+    for (collection, settings) in &config.collections {
+        let Some(model) = &settings.embedding_model else {
+            continue;
+        };
+        storage.set_collection_model(collection, model).await
+            .with_context(|| format!("Failed to set the embedding model of collection {}", collection))?;
+        println!("🗂️  Collection {} is embedded with {}", collection, model);
+    }
+    Ok(())
+}
+
+/// Embed the fragments of each collection with a model of its own with that model, after
+/// the rest of the database
+async fn embed_collection_spaces(
+    storage: &mut dyn Storage,
+    provider: &EmbeddingProviderArgs,
+    batch_size: i32,
+    max_fragments: Option<i32>,
+    throttle: &mut Throttle,
+    job: &mut Job,
+    progress: &mut Progress,
+) -> Result<()> {
+    for (collection, model) in storage.collection_models() {
+        if job.is_cancelled() {
+            break;
+        }
+        let Some(space) = storage.collection_space(&collection) else {
+            continue;
+        };
+        if space.count_fragments_without_embeddings().await? == 0 && space.count_embedding_failures().await? == 0 {
+            continue;
+        }
+        println!("\n🗂️  Collection {}: embedding with {}", collection, model);
+        let mut embedding_manager = create_embedding_manager(&model, provider).await?;
+        adopt_model(space, &model, embedding_manager.known_dimension()).await?;
+        embed_pending_fragments(space, &mut embedding_manager, batch_size, max_fragments, throttle, job, progress).await?;
+    }
+    Ok(())
+}
+
+/// Record `model` as the database's embedding model. A database embedded with another
+/// model is upgraded rather than rejected: its vectors are marked stale and replaced by
+/// the embed phase. A known `dimension` must match the vectors already stored.
+async fn adopt_model(storage: &mut dyn Storage, model: &str, dimension: Option<usize>) -> Result<()> {
+    let recorded = storage.get_meta_info().await?.embedding_model;
+    if !recorded.is_empty() && recorded != "unknown" && recorded != model {
+        let stale = storage::upgrade_embedding_model(storage, model).await
+            .context("Failed to upgrade embedding model")?;
+        println!("🔄 Embedding model changed from {} to {}; {} vectors marked stale for re-embedding", recorded, model, stale);
+        println!("   Until they are re-embedded they remain searchable with --model {}", recorded);
+    }
+    
+    storage.verify_or_set_model(model, dimension).await
+        .context("Failed to verify embedding model")
+}
+
+/// Clear away documents a run died part way through writing, so this run indexes their
+/// files again from scratch
+async fn recover_interrupted(storage: &mut dyn Storage) -> Result<()> {
+    let removed = storage::remove_interrupted(storage).await?;
+    if !removed.is_empty() {
+        println!("🧹 Removed {} half-written documents left by an interrupted run; they are indexed again when their files are scanned", removed.len());
+        for path in &removed {
+            println!("   {}", path);
+        }
+    }
+    Ok(())
+}
+
+/// Derive ids from content from now on and record which portable-brains version indexed
+/// the database next to its model, warning when a different version indexed it before
+async fn enable_deterministic(storage: &mut dyn Storage) -> Result<()> {
+    let version = env!("CARGO_PKG_VERSION");
+    if let Some(recorded) = storage.get_meta_value(storage::INDEXER_VERSION_KEY).await?.filter(|recorded| recorded != version) {
+        println!("⚠️  Database was indexed by portable-brains {}; output from this version ({}) may differ", recorded, version);
+    }
+    storage.enable_deterministic_ids().await?;
+    storage.set_meta_value(storage::INDEXER_VERSION_KEY, version).await?;
+    println!("🔒 Deterministic mode: content-derived ids, files in name order (portable-brains {})", version);
+    Ok(())
+}
+
+/// Make a new database embeddings-only when `--embeddings-only` is given, keeping none of
+/// the originals, and refuse the options that would keep text in one that already is
+async fn configure_embeddings_only(storage: &mut dyn Storage, args: &IndexArgs) -> Result<()> {
+    let enabled = storage::embeddings_only(storage).await?;
+    if args.embeddings_only && !enabled {
+        if !storage.list_documents().await?.is_empty() {
+            anyhow::bail!("--embeddings-only only applies to new databases; this one already holds text");
+        }
+        storage.set_meta_value(storage::EMBEDDINGS_ONLY_KEY, "true").await?;
+        storage.set_originals_mode(OriginalsMode::None).await?;
+    } else if !enabled {
+        return Ok(());
+    }
+    
+    if args.store_originals.is_some_and(|mode| mode != OriginalsMode::None) {
+        anyhow::bail!("The database is embeddings-only, so it can't store originals");
+    }
+    if args.late_chunking {
+        anyhow::bail!("The database is embeddings-only; late chunking needs the text of whole sections");
+    }
+    println!("🙈 Embeddings-only: fragment text is dropped once embedded, and searches return document references only");
+    Ok(())
+}
+
+/// Record how originals are kept when `--store-originals` is given, and say so whenever
+/// they aren't kept as read
+async fn configure_originals(storage: &mut dyn Storage, mode: Option<OriginalsMode>) -> Result<()> {
+    let mode = match mode {
+        Some(mode) => {
+            storage.set_originals_mode(mode).await?;
+            mode
+        }
+        None => OriginalsMode::from_meta(storage.get_meta_value(storage::STORE_ORIGINALS_KEY).await?.as_deref()),
+    };
+    match mode {
+        OriginalsMode::Full => {}
+        OriginalsMode::Compressed => println!("🗜️  Originals are stored compressed with zstd"),
+        OriginalsMode::None => println!("🪶 Originals aren't stored; only their paths and hashes are kept"),
+    }
+    Ok(())
+}
+
+/// Record the similarity metric when `--similarity` gives one, and say so whenever it
+/// isn't cosine
+async fn configure_similarity(storage: &mut dyn Storage, metric: Option<SimilarityMetric>) -> Result<()> {
+    if let Some(metric) = metric {
+        storage.set_similarity_metric(metric).await?;
+    }
+    let metric = storage::similarity_metric(storage).await?;
+    if metric != SimilarityMetric::Cosine {
+        println!("📐 Vectors are compared by {} similarity", metric.name());
+    }
+    Ok(())
+}
+
+/// Record the embedding prefixes: those given, else those recorded by an earlier run, else
+/// the model's documented ones for a database that predates them and has no vectors yet
+async fn configure_prefixes(storage: &mut dyn Storage, args: &IndexArgs) -> Result<()> {
+    let base = match storage::recorded_prefixes(storage).await? {
+        Some(recorded) => recorded,
+        None if storage::embedding_dimension(storage).await?.is_some() => EmbeddingPrefixes::default(),
+        None => EmbeddingPrefixes::for_model(&args.model),
+    };
+    let prefixes = EmbeddingPrefixes {
+        document: args.document_prefix.clone().unwrap_or(base.document),
+        query: args.query_prefix.clone().unwrap_or(base.query),
+    };
+    storage::set_embedding_prefixes(storage, &prefixes).await?;
+    if !prefixes.is_empty() {
+        println!("🏷️  Embedding fragments as {:?} + text and queries as {:?} + text", prefixes.document, prefixes.query);
+    }
+    Ok(())
+}
+
+/// Record a requested preset, or fall back to the one recorded by an earlier run
+async fn resolve_preset(storage: &mut dyn Storage, requested: Option<Preset>) -> Result<Option<Preset>> {
+    let recorded = presets::recorded_preset(storage).await?;
+    let Some(preset) = requested else {
+        if let Some(preset) = recorded {
+            println!("🎛️  Using the {} preset recorded in the database", preset.name());
+        }
+        return Ok(recorded);
+    };
+    
+    if let Some(previous) = recorded.filter(|previous| *previous != preset) {
+        println!("⚠️  Database was indexed with the {} preset; documents indexed from now on use {}", previous.name(), preset.name());
+    }
+    storage.set_meta_value(presets::PRESET_KEY, preset.name()).await?;
+    let (chunk_size, overlap) = preset.chunking();
+    println!("🎛️  Preset {}: {}-character fragments with {} of overlap", preset.name(), chunk_size, overlap);
+    Ok(Some(preset))
+}
+
+/// How a run splits prose into fragments, with the tokenizer token chunking measures with
+#[derive(Clone, Default)]
+struct Chunking {
+    strategy: ChunkingStrategy,
+    tokenizer: Option<tokenizers::Tokenizer>,
+}
+
+impl Chunking {
+    /// Switch a processor to this strategy
+    fn apply(&self, processor: DocumentProcessor) -> Result<DocumentProcessor> {
+        match &self.tokenizer {
+            Some(tokenizer) => processor.with_token_chunking(tokenizer.clone()),
+            None => Ok(processor.with_strategy(self.strategy)),
+        }
+    }
+}
+
+/// Fragment length and overlap in `strategy`'s unit, which is also returned: those given,
+/// else the preset's or the defaults
+fn chunk_sizes(preset: Option<Preset>, strategy: ChunkingStrategy, chunk_size: Option<usize>, overlap: Option<usize>) -> (usize, usize, &'static str) {
+    // Token sizes default to the character sizes at the usual characters per token
+    let (default_size, default_overlap) = preset.map_or((800, 100), Preset::chunking);
+    let (scale, unit) = match strategy {
+        ChunkingStrategy::Token => (document_processor::CHARS_PER_TOKEN, "tokens"),
+        _ => (1, "characters"),
+    };
+    (chunk_size.unwrap_or(default_size / scale), overlap.unwrap_or(default_overlap / scale), unit)
+}
+
+/// Record a requested chunking strategy, or fall back to the one recorded by an earlier run.
+/// Token chunking loads the tokenizer of the database's embedding model.
+async fn resolve_chunking(storage: &mut dyn Storage, requested: Option<ChunkingStrategy>) -> Result<Chunking> {
+    let recorded = document_processor::recorded_chunking(storage).await?;
+    let strategy = match requested {
+        Some(strategy) => {
+            if let Some(previous) = recorded.filter(|previous| *previous != strategy) {
+                println!("⚠️  Database was chunked by {}; documents indexed from now on are chunked by {}", previous.name(), strategy.name());
+            }
+            storage.set_meta_value(document_processor::CHUNKING_KEY, strategy.name()).await?;
+            strategy
+        }
+        None => match recorded {
+            Some(strategy) => {
+                println!("✂️  Using the {} chunking recorded in the database", strategy.name());
+                strategy
+            }
+            None => return Ok(Chunking::default()),
+        },
+    };
+    
+    let tokenizer = match strategy {
+        ChunkingStrategy::Token => {
+            let model = resolve_model(storage, None).await?;
+            let tokenizer = embedding_manager::load_tokenizer(&model)?;
+            println!("✂️  Chunking by tokens of the {} tokenizer", model);
+            Some(tokenizer)
+        }
+        _ => {
+            if requested.is_some() {
+                println!("✂️  Chunking by {}", strategy.name());
+            }
+            None
+        }
+    };
+    Ok(Chunking { strategy, tokenizer })
+}
+
+/// Use the model recorded at index time unless one is given explicitly
+async fn resolve_model(storage: &mut dyn Storage, model: Option<String>) -> Result<String> {
+    if let Some(model) = model {
+        return Ok(model);
+    }
+    
+    let meta = storage.get_meta_info().await?;
+    if meta.embedding_model == "unknown" {
+        anyhow::bail!("Database has no recorded embedding model; pass --model");
+    }
+    Ok(meta.embedding_model)
+}
+
+/// The category and document metadata filters given to `search`
+fn search_filter(args: &SearchArgs) -> Result<SearchFilter> {
+    Ok(SearchFilter {
+        categories: args.category.iter().map(|c| c.to_lowercase()).collect(),
+        collections: args.collection.clone(),
+        tags: args.tag.clone(),
+        file_types: args.file_type.iter().map(|value| storage::file_type(value)).collect(),
+        path: args.path.clone(),
+        modified_after: args.since.as_deref().map(|date| storage::parse_date(date, false)).transpose()?,
+        modified_before: args.until.as_deref().map(|date| storage::parse_date(date, true)).transpose()?,
+        ..Default::default()
+    })
+}
+
+/// Storage arguments for `database`, with the backend inferred from its extension unless given
+fn inferred_storage_args(database: &Path, backend: &Option<Backend>) -> StorageArgs {
+    StorageArgs {
+        database: database.to_path_buf(),
+        backend: backend.clone().unwrap_or(match StorageBackend::from_path(database) {
+            StorageBackend::DuckDB => Backend::Duckdb,
+            StorageBackend::LanceDB => Backend::Lancedb,
+            StorageBackend::Remote => Backend::Remote,
+        }),
+    }
+}
+
+async fn run_search(args: SearchArgs) -> Result<()> {
+    if args.database.len() > 1 {
+        return run_federated_search(args).await;
+    }
+    let metadata = search_filter(&args)?;
+    
+    let mut storage = open_storage(&inferred_storage_args(&args.database[0], &args.backend)).await?;
+    if !storage.collection_models().is_empty() {
+        // Collections embedded with their own model are ranked like separate brains
+        drop(storage);
+        return run_federated_search(args).await;
+    }
+    let model = resolve_model(&mut *storage, args.model).await?;
+    let space = storage::query_space(&mut *storage, &model).await?;
+    if let Some(warning) = &space.warning {
+        println!("⚠️  {}", warning);
+    }
+    let version = storage::data_version(&mut *storage).await?;
+    let mut embedding_manager = create_embedding_manager(&model, &args.provider).await?;
+    
+    let document = match args.document.as_ref().or(args.within.as_ref()) {
+        Some(name_or_id) => {
+            let document = storage::find_document(&mut *storage, name_or_id).await?;
+            if args.within.is_some() {
+                println!("📄 Searching within {} ({} fragments)", document.file_path, document.fragments);
+            } else {
+                println!("📄 Searching {} only", document.file_path);
+            }
+            Some(document.id)
+        }
+        None => None,
+    };
+    
+    let entity = match args.entity {
+        Some(entity) => Some(entity),
+        None => match retrieval::entity_lookup(&mut *storage, &args.query).await? {
+            Some((entity, mentions)) => {
+                println!("🏷️  \"{}\" is mentioned in {} documents; searching the fragments that name it", entity, mentions.len());
+                Some(entity)
+            }
+            None => None,
+        },
+    };
+    
+    let pipeline = match &args.config {
+        Some(path) => Config::load_checked(path)?.retrieval.pipeline()?,
+        None => None,
+    };
+    
+    let (query, section) = SearchFilter::parse_section(&args.query);
+    let filter = SearchFilter { stale: space.stale, section, document, entity, ..metadata };
+    let cursor = args.cursor.as_deref().map(retrieval::SearchCursor::decode).transpose()?;
+    let mut reranker = args.rerank_model.as_deref().map(CrossEncoder::new).transpose()?;
+    // The reranker picks the results from a wider pool of candidates, and limiting results
+    // per document needs spares for those it collapses
+    let per_document = args.per_document_limit.map(|limit| limit as usize);
+    let keep = if per_document.is_some() { args.limit * retrieval::PER_DOCUMENT_POOL_FACTOR } else { args.limit };
+    let fetch = if reranker.is_some() { keep.max(RERANK_POOL) } else { keep };
+    let (hits, report, next) = if let Some(pipeline) = &pipeline {
+        println!("🧪 Retrieval pipeline: {}", pipeline.describe());
+        let (page, trace) = pipeline.search_page(
+            &mut *storage,
+            &mut embedding_manager,
+            &query,
+            fetch,
+            &filter,
+            cursor.as_ref(),
+        ).await?;
+        if args.explain {
+            for line in trace {
+                println!("   {}", line);
+            }
+        }
+        (page.hits, None, page.next)
+    } else if args.explain {
+        let (hits, report) = retrieval::search(
+            &mut *storage,
+            &mut embedding_manager,
+            &query,
+            fetch,
+            &filter,
+            args.search_mode,
+            true,
+        ).await?;
+        (hits, report, None)
+    } else {
+        let page = retrieval::search_page(
+            &mut *storage,
+            &mut embedding_manager,
+            &query,
+            fetch,
+            &filter,
+            args.search_mode,
+            cursor.as_ref(),
+        ).await?;
+        (page.hits, None, page.next)
+    };
+    let (hits, next) = match &mut reranker {
+        Some(reranker) => {
+            println!("🎯 Reranking {} candidates with {}", hits.len(), reranker.model_name());
+            let hits = reranker.rerank(&query, hits, |hit| hit.content.as_str(), keep)?
+                .into_iter()
+                .map(|(hit, score)| retrieval::SearchHit { score, ..hit })
+                .collect();
+            (hits, None)
+        }
+        None => (hits, next),
+    };
+    let (hits, collapsed) = match per_document {
+        Some(per_document) => {
+            let (mut hits, collapsed) = retrieval::limit_per_document(hits, per_document, |hit| hit.source.file_path.clone());
+            hits.truncate(args.limit);
+            (hits, collapsed)
+        }
+        None => (hits, Vec::new()),
+    };
+    
+    // Hit counts only steer re-embedding order after a model upgrade
+    let hit_ids: Vec<String> = hits.iter().map(|hit| hit.fragment_id.clone()).collect();
+    if let Err(e) = storage.record_fragment_hits(&hit_ids).await {
+        println!("⚠️  Failed to record search hits: {}", e);
+    }
+    
+    if let Some(report) = report {
+        println!();
+        print!("{}", report);
+    }
+    
+    println!();
+    println!("📌 Dataset version {}", version);
+    if args.search_mode != SearchMode::Keyword {
+        if let Some(coverage) = storage.vector_index_coverage().await?.filter(|coverage| coverage.unindexed > 0) {
+            println!("🧭 {} fragments searched through the HNSW index, {} embedded since it was updated scanned", coverage.indexed, coverage.unindexed);
+        }
+    }
+    if hits.is_empty() {
+        println!("💭 No matching fragments found");
+    }
+    if args.within.is_some() {
+        print_in_document_order(&hits);
+        return Ok(());
+    }
+    let first = cursor.map_or(1, |cursor| cursor.offset + 1);
+    for (i, hit) in hits.iter().enumerate() {
+        let preview: String = hit.content.chars().take(200).collect();
+        println!("{}. [{:.4}] {}", first + i, hit.score, hit.fragment_id);
+        println!("   📎 {}", hit.source.citation());
+        for note in &hit.source.annotations {
+            println!("   📝 {}", note.describe());
+        }
+        println!("   {}", preview);
+        // Collapsed fragments are listed under the document's first result only
+        if hits[..i].iter().all(|earlier| earlier.source.file_path != hit.source.file_path) {
+            let more: Vec<_> = collapsed.iter()
+                .filter(|other| other.source.file_path == hit.source.file_path)
+                .map(|other| (other.score, other.fragment_id.as_str(), &other.source, other.content.as_str()))
+                .collect();
+            print_collapsed(&more, args.expand);
+        }
+    }
+    if let Some(next) = next {
+        println!();
+        println!("➡️  More results: --cursor {}", next.encode());
+    }
+    
+    Ok(())
+}
+
+/// Print the hits of a `search --within` in the order they appear in their document, each
+/// with its score and rank among them
+fn print_in_document_order(hits: &[retrieval::SearchHit]) {
+    let mut ranked: Vec<(usize, &retrieval::SearchHit)> = hits.iter().enumerate().collect();
+    ranked.sort_by_key(|(_, hit)| hit.source.order);
+    for (rank, hit) in ranked {
+        let preview: String = hit.content.chars().take(200).collect();
+        println!("¶ {} [{:.4}] #{} {}", hit.source.order, hit.score, rank + 1, hit.fragment_id);
+        println!("   📎 {}", hit.source.citation());
+        for note in &hit.source.annotations {
+            println!("   📝 {}", note.describe());
+        }
+        println!("   {}", preview);
+    }
+}
+
+async fn run_eval(args: EvalArgs) -> Result<()> {
+    let queries = eval::load_queries(&args.queries)?;
+    let pipelines = args.config.iter()
+        .map(|path| {
+            let pipeline = Config::load_checked(path)?.retrieval.pipeline()?
+                .with_context(|| format!("{} has no [retrieval] stages to evaluate", path.display()))?;
+            Ok((path.display().to_string(), pipeline))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let mut reranker = args.rerank_model.as_deref().map(CrossEncoder::new).transpose()?;
+    if !args.json {
+        println!("🧪 Evaluating {} queries from {} at k={}", queries.len(), args.queries.display(), args.limit);
+    }
+    
+    let mut reports = Vec::new();
+    for database in &args.database {
+        let mut storage = open_storage(&inferred_storage_args(database, &args.backend)).await?;
+        if !storage.collection_models().is_empty() {
+            anyhow::bail!("{} embeds collections with models of their own, which eval doesn't support yet", database.display());
+        }
+        let model = resolve_model(&mut *storage, args.model.clone()).await?;
+        let space = storage::query_space(&mut *storage, &model).await?;
+        if let Some(warning) = &space.warning {
+            println!("⚠️  {}", warning);
+        }
+        let mut embedding_manager = create_embedding_manager(&model, &args.provider).await?;
+        let filter = SearchFilter { stale: space.stale, ..Default::default() };
+        
+        // Each search mode, or each pipeline when config files are given, is a configuration
+        let configurations: Vec<(String, SearchMode, Option<&retrieval_pipeline::RetrievalPipeline>)> = if pipelines.is_empty() {
+            args.search_mode.iter().map(|&mode| (format!("{:?}", mode).to_lowercase(), mode, None)).collect()
+        } else {
+            pipelines.iter().map(|(name, pipeline)| (name.clone(), SearchMode::default(), Some(pipeline))).collect()
+        };
+        let fetch = if reranker.is_some() { args.limit.max(RERANK_POOL) } else { args.limit };
+        for (name, mode, pipeline) in configurations {
+            let mut plain = Vec::with_capacity(queries.len());
+            let mut reranked = Vec::with_capacity(queries.len());
+            for query in &queries {
+                let hits = match pipeline {
+                    Some(pipeline) => pipeline.run(&mut *storage, &mut embedding_manager, &query.query, fetch, &filter).await?.0,
+                    None => retrieval::search(&mut *storage, &mut embedding_manager, &query.query, fetch, &filter, mode, false).await?.0,
+                };
+                let ranking = |hits: &[retrieval::SearchHit]| -> Vec<(String, String)> {
+                    hits.iter().map(|hit| (hit.fragment_id.clone(), hit.source.file_path.clone())).collect()
+                };
+                plain.push(eval::score(query, &ranking(&hits), args.limit));
+                if let Some(reranker) = &mut reranker {
+                    let hits: Vec<retrieval::SearchHit> = reranker.rerank(&query.query, hits, |hit| hit.content.as_str(), args.limit)?
+                        .into_iter()
+                        .map(|(hit, _)| hit)
+                        .collect();
+                    reranked.push(eval::score(query, &ranking(&hits), args.limit));
+                }
+            }
+            let configuration = format!("{}, {}", database.display(), name);
+            reports.push(eval::ConfigurationReport::new(configuration.clone(), &queries, &plain));
+            if let Some(reranker) = &reranker {
+                reports.push(eval::ConfigurationReport::new(
+                    format!("{}, reranked by {}", configuration, reranker.model_name()),
+                    &queries,
+                    &reranked,
+                ));
+            }
+        }
+    }
+    
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&serde_json::json!({
+            "queries": queries.len(),
+            "k": args.limit,
+            "configurations": reports,
+        }))?);
+        return Ok(());
+    }
+    
+    let width = reports.iter().map(|report| report.configuration.chars().count()).max().unwrap_or(0).max("configuration".len());
+    let recall = format!("recall@{}", args.limit);
+    let ndcg = format!("nDCG@{}", args.limit);
+    println!();
+    println!("{:<width$}  {:>9}  {:>6}  {:>7}  {:>6}", "configuration", recall, "MRR", ndcg, "misses", width = width);
+    for report in &reports {
+        println!(
+            "{:<width$}  {:>9.3}  {:>6.3}  {:>7.3}  {:>6}",
+            report.configuration, report.recall, report.mrr, report.ndcg, report.misses.len(),
+            width = width,
+        );
+    }
+    for report in reports.iter().filter(|report| !report.misses.is_empty()) {
+        println!("\n💭 Nothing expected in the top {} for {}:", args.limit, report.configuration);
+        for query in &report.misses {
+            println!("   {}", query);
+        }
+    }
+    Ok(())
+}
+
+async fn run_viz(args: VizArgs) -> Result<()> {
+    let mut storage = open_storage(&args.storage).await?;
+    
+    let points: Vec<viz::LabelledEmbedding> = storage.get_fragment_embeddings(args.sample).await?
+        .into_iter()
+        .map(|(fragment_id, label, embedding)| viz::LabelledEmbedding { fragment_id, label, embedding })
+        .collect();
+    
+    if points.is_empty() {
+        anyhow::bail!("No embedded fragments found; run `portable-brains embed` first");
+    }
+    println!("📊 Projecting {} fragment embeddings...", points.len());
+    
+    let output = match args.format {
+        VizFormat::Html => viz::to_html(&viz::project_2d(&points)?),
+        VizFormat::Csv => viz::to_csv(&viz::project_2d(&points)?),
+        VizFormat::Raw => viz::to_jsonl(&points)?,
+    };
+    
+    std::fs::write(&args.output, output)
+        .with_context(|| format!("Failed to write {}", args.output.display()))?;
+    println!("✅ Wrote {}", args.output.display());
+    
+    Ok(())
+}
+
+async fn run_drift(args: DriftArgs) -> Result<()> {
+    let mut storage = open_storage(&args.storage).await?;
+    let recorded = storage.get_meta_info().await?.embedding_model;
+    let model = resolve_model(&mut *storage, args.model).await?;
+    if model != recorded {
+        println!("⚠️  Database was embedded with {}; comparing against {} will show drift by design", recorded, model);
+    }
+    
+    let sample = storage.sample_fragments(args.sample).await?;
+    if sample.is_empty() {
+        anyhow::bail!("No embedded fragments found; run `portable-brains embed` first");
+    }
+    
+    // Re-embed exactly as the embed phase did, document prefix included
+    let prefixes = storage::embedding_prefixes(&mut *storage).await?;
+    let mut embedding_manager = create_embedding_manager(&model, &args.provider).await?;
+    println!("🧪 Re-embedding {} fragments with {}...", sample.len(), model);
+    let mut fresh = Vec::with_capacity(sample.len());
+    for batch in sample.chunks(50) {
+        let texts: Vec<String> = batch.iter().map(|(_, content, _)| prefixes.document(content)).collect();
+        fresh.extend(embedding_manager.generate_embeddings_batch(&texts).await
+            .context("Failed to re-embed sampled fragments")?);
+    }
+    
+    let stored: Vec<(String, Vec<f64>)> = sample.into_iter().map(|(id, _, embedding)| (id, embedding)).collect();
+    let report = drift::DriftReport::measure(&stored, &fresh)?;
+    println!("📐 Cosine deviation: mean {:.6}, p95 {:.6}, max {:.6}", report.mean(), report.percentile(0.95), report.max());
+    
+    let drifted = report.drifted(args.threshold);
+    if drifted.is_empty() {
+        println!("✅ Every sampled vector is within {} of what {} produces now", args.threshold, model);
+        return Ok(());
+    }
+    
+    println!("⚠️  {} of {} sampled fragments deviate by more than {}:", drifted.len(), stored.len(), args.threshold);
+    for (fragment_id, deviation) in drifted.iter().take(10) {
+        println!("   {:.6}  {}", deviation, fragment_id);
+    }
+    anyhow::bail!(
+        "Embedding drift detected: {} no longer reproduces the stored vectors. Restore the model files the \
+         database was embedded with, or re-index into a new database with the current ones",
+        model
+    )
+}
+
+async fn run_diff_db(args: DiffDbArgs) -> Result<()> {
+    let mut old = open_storage(&inferred_storage_args(&args.old, &args.backend)).await?;
+    let mut new = open_storage(&inferred_storage_args(&args.new, &args.backend)).await?;
+    let old_settings = brain_diff::settings(&mut *old).await?;
+    let new_settings = brain_diff::settings(&mut *new).await?;
+    let diff = brain_diff::BrainDiff::new(old.list_documents().await?, new.list_documents().await?, &old_settings, &new_settings);
+    
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&diff)?);
+        return Ok(());
+    }
+    
+    println!("🔍 {} → {}", args.old.display(), args.new.display());
+    if diff.is_empty() {
+        println!("✅ Same documents, fragment counts and settings");
+        return Ok(());
+    }
+    if !diff.settings.is_empty() {
+        println!("⚙️  Settings:");
+        for change in &diff.settings {
+            println!("   {}: {} → {}", change.key, change.old.as_deref().unwrap_or("(unset)"), change.new.as_deref().unwrap_or("(unset)"));
+        }
+    }
+    println!("📄 Documents: {} added, {} removed, {} changed, {} unchanged", diff.added.len(), diff.removed.len(), diff.changed.len(), diff.unchanged);
+    println!("🧩 Fragments: {} → {} ({:+})", diff.old_fragments, diff.new_fragments, diff.new_fragments - diff.old_fragments);
+    for document in &diff.added {
+        println!("   + {} ({} fragments)", document.file_path, document.fragments);
+    }
+    for document in &diff.removed {
+        println!("   - {} ({} fragments)", document.file_path, document.fragments);
+    }
+    for document in &diff.changed {
+        let changes: Vec<String> = document.changes.iter()
+            .map(|&change| match change {
+                "fragments" => format!("fragments {} → {}", document.old_fragments, document.new_fragments),
+                change => change.to_string(),
+            })
+            .collect();
+        println!("   ~ {} ({})", document.file_path, changes.join(", "));
+    }
+    Ok(())
+}
+
+async fn run_list(args: ListArgs) -> Result<()> {
+    let mut storage = open_storage(&args.storage).await?;
+    
+    let mut documents = storage.list_documents().await?;
+    let flagged = documents.iter().filter(|d| !d.quality_flags.is_empty()).count();
+    println!("📚 {} documents, {} flagged for extraction quality", documents.len(), flagged);
+    
+    if args.flagged {
+        documents.retain(|d| !d.quality_flags.is_empty());
+        documents.sort_by(|a, b| a.quality_score.unwrap_or(0.0).total_cmp(&b.quality_score.unwrap_or(0.0)));
+    }
+    
+    println!();
+    for document in &documents {
+        let marker = if document.tombstoned {
+            "🪦"
+        } else if document.quality_flags.is_empty() {
+            "✅"
+        } else {
+            "⚠️ "
+        };
+        let score = document.quality_score.map_or("  -  ".to_string(), |score| format!("{:.2}", score));
+        let name = match &document.title {
+            Some(title) => format!("{} “{}”", document.file_path, title),
+            None => document.file_path.clone(),
+        };
+        println!("{} [{}] {} ({} fragments) {}", marker, score, name, document.fragments, document.id);
+        if !document.quality_flags.is_empty() {
+            println!("   {}", document.quality_flags.join("; "));
+        }
+    }
+    
+    Ok(())
+}
+
+async fn run_entities(args: EntitiesArgs) -> Result<()> {
+    let mut storage = open_storage(&args.storage).await?;
+    
+    let Some(name) = &args.name else {
+        let entities = storage.list_entities(args.limit).await?;
+        if entities.is_empty() {
+            println!("💭 No entities found; index some documents first");
+        }
+        for entity in &entities {
+            println!("🏷️  {} ({} fragments in {} documents)", entity.name, entity.fragments, entity.documents);
+        }
+        return Ok(());
+    };
+    
+    let mentions = storage.find_entity(name).await?;
+    if mentions.is_empty() {
+        println!("💭 No document mentions \"{}\"", name);
+    }
+    for mention in &mentions {
+        println!("📄 {} ({} fragments) {}", mention.file_path, mention.fragments, mention.document_id);
+        println!("   as {}", mention.names.join(", "));
+    }
+    Ok(())
+}
+
+async fn run_info(args: InfoArgs) -> Result<()> {
+    let mut storage = open_storage(&args.storage).await?;
+    
+    let meta = storage.get_meta_info().await?;
+    let dimension = storage::embedding_dimension(&mut *storage).await?;
+    let documents = storage.list_documents().await?;
+    let fragments: i64 = documents.iter().map(|d| d.fragments as i64).sum();
+    let pending = storage.count_fragments_without_embeddings().await? as i64;
+    let stale = storage.count_stale_fragments().await? as i64;
+    
+    println!("🗂️  Schema version: {}", meta.version);
+    println!("📌 Dataset version: {}", storage::data_version(&mut *storage).await?);
+    match (dimension, &meta.embedding_provider) {
+        (Some(dimension), Some(provider)) => println!("🤖 Embedding model: {} ({} dimensions, {} provider)", meta.embedding_model, dimension, provider),
+        (Some(dimension), None) => println!("🤖 Embedding model: {} ({} dimensions)", meta.embedding_model, dimension),
+        (None, _) => println!("🤖 Embedding model: {}", meta.embedding_model),
+    }
+    if let Some(previous) = storage.get_meta_value(storage::PREVIOUS_MODEL_KEY).await?.filter(|_| stale > 0) {
+        println!("🔄 Upgrading from {}", previous);
+    }
+    let prefixes = storage::embedding_prefixes(&mut *storage).await?;
+    if !prefixes.is_empty() {
+        println!("🏷️  Prefixes: {:?} before fragments, {:?} before queries", prefixes.document, prefixes.query);
+    }
+    if let Some(dimension) = storage.get_meta_value(duckdb_storage::COMPACT_LAYOUT_KEY).await? {
+        println!("🗜️  Compact fragment layout (FLOAT[{}] vectors)", dimension);
+    }
+    let metric = storage::similarity_metric(&mut *storage).await?;
+    if metric != SimilarityMetric::Cosine {
+        println!("📐 Similarity metric: {}", metric.name());
+    }
+    match storage.vector_index_coverage().await? {
+        Some(coverage) if coverage.unindexed > 0 => println!(
+            "🧭 HNSW vector index over {} fragments ({} embedded since are scanned until the next embed)",
+            coverage.indexed, coverage.unindexed
+        ),
+        Some(coverage) => println!("🧭 HNSW vector index over {} fragments", coverage.indexed),
+        None if storage.get_meta_value(duckdb_storage::VECTOR_INDEX_KEY).await?.is_some() => println!("🧭 HNSW vector index"),
+        None => {}
+    }
+    if storage::IdScheme::from_meta(storage.get_meta_value(storage::DETERMINISTIC_IDS_KEY).await?.as_deref()) == storage::IdScheme::Content {
+        let version = storage.get_meta_value(storage::INDEXER_VERSION_KEY).await?;
+        println!("🔒 Deterministic ids (indexed by portable-brains {})", version.as_deref().unwrap_or("unknown"));
+    }
+    
+    let tombstoned = documents.iter().filter(|d| d.tombstoned).count();
+    let flagged = documents.iter().filter(|d| !d.quality_flags.is_empty()).count();
+    println!("📚 Documents: {} ({} tombstoned, {} flagged for extraction quality)", documents.len(), tombstoned, flagged);
+    let interrupted = storage.list_index_state(IndexState::Stored).await?.len();
+    if interrupted > 0 {
+        println!("🚧 {} documents were left half-written by an interrupted run; the next `index` clears them away and indexes them again", interrupted);
+    }
+    println!("🧩 Fragments: {} ({} embedded, {} waiting for a vector, {} stale)",
+             fragments, fragments - pending, pending - stale, stale);
+    if let Some(tree) = summary_tree::load(&mut *storage).await? {
+        println!("🌳 Summary tree: {} summaries in {} levels", tree.nodes.len(), tree.levels());
+    }
+    for (id, state) in sources::load_states(&mut *storage).await? {
+        let synced = state.synced_at
+            .and_then(chrono::DateTime::from_timestamp_micros)
+            .map(|time| time.format("%Y-%m-%d %H:%M").to_string())
+            .unwrap_or_else(|| "never".to_string());
+        println!("🔌 Source {}: {} documents, last synced {}", id, state.items.len(), synced);
+    }
+    
+    // Stale vectors belong to the model before the current one
+    let lineage = storage::model_lineage(&mut *storage).await?;
+    if lineage.len() > 1 || stale > 0 {
+        println!("🧬 Model lineage:");
+        let last = lineage.len().saturating_sub(1);
+        for (i, epoch) in lineage.iter().enumerate() {
+            let mut details = Vec::new();
+            if let Some(dimension) = epoch.dimension {
+                details.push(format!("{} dimensions", dimension));
+            }
+            if let Some(provider) = &epoch.provider {
+                details.push(format!("{} provider", provider));
+            }
+            let details = if details.is_empty() { String::new() } else { format!(" ({})", details.join(", ")) };
+            
+            let date = |time: &chrono::DateTime<chrono::Utc>| time.format("%Y-%m-%d").to_string();
+            let mut history = vec![format!("{} vectors stored", epoch.embedded)];
+            if let (Some(first), Some(latest)) = (&epoch.first_embedded, &epoch.last_embedded) {
+                history.push(format!("{} to {}", date(first), date(latest)));
+            }
+            if let Some(retired) = &epoch.retired {
+                history.push(format!("replaced {}", date(retired)));
+            }
+            
+            let live = if i == last {
+                format!("current, {} vectors", fragments - pending)
+            } else if i + 1 == last && stale > 0 {
+                format!("{} stale vectors still searchable with it", stale)
+            } else {
+                "no vectors left".to_string()
+            };
+            println!("   {}. {}{}: {}; {}", i + 1, epoch.model, details, history.join(", "), live);
+        }
+    }
+    
+    Ok(())
+}
+
+async fn run_stats(args: StatsArgs) -> Result<()> {
+    // JSON output goes to stdout alone, without the backend banner
+    let mut storage = if args.json {
+        create_storage(&args.storage.backend.storage_backend(), &args.storage.database).await
+            .context("Failed to initialize storage backend")?
+    } else {
+        open_storage(&args.storage).await?
+    };
+    
+    let meta = storage.get_meta_info().await?;
+    let dimension = storage::embedding_dimension(&mut *storage).await?;
+    let stats = storage.corpus_stats(args.largest).await?;
+    let pending = storage.count_fragments_without_embeddings().await? as usize;
+    let stale = storage.count_stale_fragments().await? as usize;
+    let size = disk_size(&args.storage.database);
+    
+    if args.json {
+        let report = serde_json::json!({
+            "database": args.storage.database.display().to_string(),
+            "size_bytes": size,
+            "embedding_model": meta.embedding_model,
+            "embedding_dimension": dimension,
+            "documents": stats.documents,
+            "file_types": stats.file_types.iter()
+                .map(|(file_type, documents)| serde_json::json!({ "file_type": file_type, "documents": documents }))
+                .collect::<Vec<_>>(),
+            "document_bytes": stats.document_bytes,
+            "fragments": stats.fragments,
+            "fragments_without_embeddings": pending.saturating_sub(stale),
+            "stale_fragments": stale,
+            "average_fragment_chars": stats.average_fragment_chars(),
+            "largest_documents": stats.largest,
+        });
+        println!("{}", serde_json::to_string_pretty(&report)?);
+        return Ok(());
+    }
+    
+    let mb = |bytes: u64| bytes as f64 / (1024.0 * 1024.0);
+    println!("💾 Database size: {:.1} MB", mb(size));
+    match dimension {
+        Some(dimension) => println!("🤖 Embedding model: {} ({} dimensions)", meta.embedding_model, dimension),
+        None => println!("🤖 Embedding model: {}", meta.embedding_model),
+    }
+    println!("📚 Documents: {} ({:.1} MB of originals)", stats.documents, mb(stats.document_bytes));
+    for (file_type, documents) in &stats.file_types {
+        println!("   {:<8} {}", file_type, documents);
+    }
+    println!("🧩 Fragments: {} ({} without a vector, {} stale)", stats.fragments, pending.saturating_sub(stale), stale);
+    println!("📏 Average fragment length: {:.0} characters", stats.average_fragment_chars());
+    if !stats.largest.is_empty() {
+        println!("🐘 Largest documents:");
+        for document in &stats.largest {
+            println!("   {:>9.2} MB {:>6} fragments  {}", mb(document.bytes), document.fragments, document.file_path);
+        }
+    }
+    
+    Ok(())
+}
+
+/// Bytes a database takes on disk, counting every file under it when it's a directory
+fn disk_size(path: &Path) -> u64 {
+    let Ok(metadata) = std::fs::metadata(path) else {
+        return 0;
+    };
+    if !metadata.is_dir() {
+        return metadata.len();
+    }
+    std::fs::read_dir(path)
+        .map(|entries| entries.flatten().map(|entry| disk_size(&entry.path())).sum())
+        .unwrap_or(0)
+}
+
+async fn run_remove(args: RemoveArgs) -> Result<()> {
+    let mut storage = open_storage(&args.storage).await?;
+    if let Some(condition) = &args.where_clause {
+        return run_bulk(&mut *storage, condition, &BulkAction::Delete, args.dry_run).await;
+    }
+    let name = args.document.unwrap_or_default();
+    
+    // Paths are matched the way they were stored, so `./docs/a.pdf` finds `docs/a.pdf`
+    let path = paths::StoredPath::new(Path::new(&name));
+    let document = storage.list_documents().await?
+        .into_iter()
+        .find(|d| d.id == name || d.file_path == path.key || d.file_path == path.legacy_key)
+        .ok_or_else(|| anyhow!("No document with path or id {}", name))?;
+    
+    storage.remove_document(&document.id).await?;
+    storage::publish(&mut *storage).await?;
+    println!("🗑️  Removed {} ({} fragments)", document.file_path, document.fragments);
+    
+    Ok(())
+}
+
+async fn run_reorganize(args: ReorganizeArgs) -> Result<()> {
+    let collection = match (args.collection, args.no_collection) {
+        (Some(collection), _) => Some(Some(collection)),
+        (None, true) => Some(None),
+        (None, false) => None,
+    };
+    if args.add_tag.is_empty() && args.remove_tag.is_empty() && collection.is_none() {
+        anyhow::bail!("Nothing to change: give --add-tag, --remove-tag, --collection or --no-collection");
+    }
+    // Stored tag lists are joined with commas by some backends
+    if args.add_tag.iter().any(|tag| tag.trim().is_empty() || tag.contains(',')) {
+        anyhow::bail!("Tags can't be empty or contain commas");
+    }
+    
+    let mut storage = open_storage(&args.storage).await?;
+    let action = BulkAction::Reorganize { add_tags: args.add_tag, remove_tags: args.remove_tag, collection };
+    run_bulk(&mut *storage, &args.where_clause, &action, args.dry_run).await
+}
+
+/// Delete or reorganize the documents matching a `--where` condition in one storage
+/// operation, or list them when `dry_run`
+async fn run_bulk(storage: &mut dyn Storage, condition: &str, action: &BulkAction, dry_run: bool) -> Result<()> {
+    let filter = SearchFilter::parse_where(condition)?;
+    println!("🔎 Documents with {}", filter.describe().join(", "));
+    let (done, would) = match action {
+        BulkAction::Delete => ("🗑️  Deleted", "would be deleted"),
+        BulkAction::Reorganize { .. } => ("🏷️  Updated", "would be updated"),
+    };
+    
+    if dry_run {
+        let documents = storage::matching_documents(storage, &filter).await?;
+        for document in &documents {
+            println!("   {} ({} fragments)", document.file_path, document.fragments);
+        }
+        println!("🧪 {} documents {}", documents.len(), would);
+        return Ok(());
+    }
+    
+    let count = storage.bulk_update(&filter, action).await?;
+    if count > 0 {
+        storage::publish(storage).await?;
+    }
+    println!("{} {} documents", done, count);
+    Ok(())
+}
+
+async fn run_extract(args: ExtractArgs) -> Result<()> {
+    let mut storage = open_storage(&args.storage).await?;
+    
+    let documents = if args.all {
+        storage.list_documents().await?
+    } else {
+        let mut documents = Vec::new();
+        for id in &args.ids {
+            documents.push(storage::find_document(&mut *storage, id).await?);
+        }
+        documents
+    };
+    
+    for document in &documents {
+        let path = storage::extract_document(&mut *storage, &document.id, &args.out).await?;
+        println!("📄 {} → {}", document.file_path, path.display());
+    }
+    println!("✅ Extracted {} documents to {}", documents.len(), args.out.display());
+    
+    Ok(())
+}
+
+async fn run_annotate(args: AnnotateArgs) -> Result<()> {
+    let mut storage = open_storage(&args.storage).await?;
+    
+    if let Some(id) = &args.remove {
+        if !storage.remove_annotation(id).await? {
+            anyhow::bail!("No annotation with id {}", id);
+        }
+        println!("🗑️  Removed annotation {}", id);
+        return Ok(());
+    }
+    
+    if !args.weight.is_empty() {
+        let mut weights = annotations::ranking_weights(&mut *storage).await?;
+        for spec in &args.weight {
+            weights.set(spec)?;
+        }
+        annotations::set_ranking_weights(&mut *storage, &weights).await?;
+        println!("⚖️  Ranking weights: {}", weights.describe());
+        return Ok(());
+    }
+    
+    let target = match &args.target {
+        Some(target) => Some(annotation_target(&mut *storage, target).await?),
+        None if args.list => None,
+        None => anyhow::bail!("Name the fragment or document to annotate, or use --list or --weight"),
+    };
+    
+    if args.list {
+        if target.is_none() {
+            println!("⚖️  Ranking weights: {}", annotations::ranking_weights(&mut *storage).await?.describe());
+        }
+        let annotations = storage.list_annotations(target.as_ref().map(|(id, _)| id.as_str())).await?;
+        if annotations.is_empty() {
+            println!("💭 No annotations found");
+        }
+        for annotation in &annotations {
+            let created = chrono::DateTime::from_timestamp_micros(annotation.created)
+                .map(|date| date.format("%Y-%m-%d").to_string())
+                .unwrap_or_default();
+            println!("📝 {} on {} {}", annotation.id, annotation.target, created);
+            println!("   {}", annotation.summary().describe());
+        }
+        return Ok(());
+    }
+    
+    let Some((target, description)) = target else {
+        unreachable!("a target is required unless listing");
+    };
+    if args.label.is_none() && args.note.is_none() {
+        anyhow::bail!("Give a --label, a --note or both");
+    }
+    let author = args.author.clone()
+        .or_else(|| std::env::var("USER").ok())
+        .unwrap_or_else(|| "unknown".to_string());
+    let annotation = Annotation::new(&target, args.label, args.note.clone(), &author);
+    storage.add_annotation(&annotation).await?;
+    println!("📝 Annotated {}: {}", description, annotation.summary().describe());
+    println!("   Annotation id: {}", annotation.id);
+    
+    Ok(())
+}
+
+/// The id of the fragment or document `target` names, with a description of it. Document
+/// names are tried first; anything else must be a stored fragment's id.
+async fn annotation_target(storage: &mut dyn Storage, target: &str) -> Result<(String, String)> {
+    use storage::arrow::array::{Array, StringArray};
+    
+    if let Ok(document) = storage::find_document(storage, target).await {
+        return Ok((document.id, document.file_path));
+    }
+    
+    let mut after: Option<String> = None;
+    while let Some(batch) = storage.read_fragment_batch(after.as_deref(), storage::FRAGMENT_BATCH_SIZE).await? {
+        let ids = storage::fragment_column::<StringArray>(&batch, "id")?;
+        if ids.iter().flatten().any(|id| id == target) {
+            return Ok((target.to_string(), format!("fragment {}", target)));
+        }
+        after = Some(ids.value(ids.len() - 1).to_string());
+    }
+    anyhow::bail!("No fragment or document with id or file name '{}'", target)
+}
+
+async fn run_export(args: ExportArgs) -> Result<()> {
+    let mut storage = open_storage(&args.storage).await?;
+    if args.static_site {
+        let meta = storage.get_meta_info().await?;
+        if meta.embedding_provider.as_deref().is_some_and(|provider| provider != "local") {
+            println!("⚠️  Vectors came from the {} provider; the page can only embed queries with a matching local model",
+                meta.embedding_provider.as_deref().unwrap_or_default());
+        }
+        let model = args.browser_model.unwrap_or_else(|| static_site::browser_model(&meta.embedding_model));
+        let stats = static_site::export_static_site(&mut *storage, &args.output, &model).await?;
+        println!("✅ Wrote a search page over {} fragments from {} documents to {} ({} KB of vectors, queries embedded with {})",
+            stats.fragments, stats.documents, args.output.display(), stats.vector_bytes / 1024, model);
+        return Ok(());
+    }
+    match args.format {
+        ExportFormat::Parquet => return export_parquet(&mut *storage, &args.output, args.no_embeddings).await,
+        ExportFormat::Bundle => {
+            if args.no_embeddings {
+                anyhow::bail!("Bundles always hold the vectors; use --format jsonl or parquet to leave them out");
+            }
+            let stats = migrate::export_bundle(&mut *storage, &args.output).await?;
+            println!("✅ Exported {} documents and {} fragments to {}", stats.documents, stats.fragments, args.output.display());
+            return Ok(());
+        }
+        ExportFormat::Jsonl => {}
+    }
+    
+    let documents = storage.list_documents().await?;
+    let mut annotated: HashMap<String, Vec<Annotation>> = HashMap::new();
+    for annotation in storage.list_annotations(None).await? {
+        annotated.entry(annotation.target.clone()).or_default().push(annotation);
+    }
+    let mut output = String::new();
+    let mut fragments = 0;
+    for document in &documents {
+        let mut records = storage.get_fragment_records(&document.id).await?;
+        if args.no_embeddings {
+            records.iter_mut().for_each(|record| record.embedding = None);
+        }
+        fragments += records.len();
+        
+        // Annotations on the document and on each of its fragments
+        let annotations: Vec<&Annotation> = std::iter::once(document.id.as_str())
+            .chain(records.iter().map(|record| record.id.as_str()))
+            .filter_map(|id| annotated.get(id))
+            .flatten()
+            .collect();
+        
+        let line = serde_json::json!({
+            "id": document.id,
+            "file_path": document.file_path,
+            "tombstoned": document.tombstoned,
+            "fragments": records,
+            "annotations": annotations,
+        });
+        output.push_str(&serde_json::to_string(&line)?);
+        output.push('\n');
+    }
+    
+    std::fs::write(&args.output, output)
+        .with_context(|| format!("Failed to write {}", args.output.display()))?;
+    println!("✅ Exported {} documents and {} fragments to {}", documents.len(), fragments, args.output.display());
+    
+    Ok(())
+}
+
+async fn run_import(args: ImportArgs) -> Result<()> {
+    let mut storage = open_storage(&args.storage).await?;
+    let stats = migrate::import_bundle(&args.input, &mut *storage).await
+        .with_context(|| format!("Failed to import {}", args.input.display()))?;
+    println!("✅ Imported {} documents and {} fragments from {}", stats.documents, stats.fragments, args.input.display());
+    Ok(())
+}
+
+async fn run_migrate(args: MigrateArgs) -> Result<()> {
+    if !remote_storage::is_remote(&args.from) && !args.from.exists() {
+        anyhow::bail!("Database not found: {}", args.from.display());
+    }
+    if args.to.exists() && !ShardedStorage::is_manifest(&args.to) {
+        anyhow::bail!("{} already exists; migrate copies into a new database", args.to.display());
+    }
+    // The LanceDB backend keeps everything in memory, so the copy would vanish on exit
+    let target_backend = if ShardedStorage::is_manifest(&args.to) && args.to.exists() {
+        ShardManifest::load(&args.to)?.backend.parse()?
+    } else {
+        StorageBackend::from_path(&args.to)
+    };
+    if matches!(target_backend, StorageBackend::LanceDB) {
+        anyhow::bail!("LanceDB databases aren't persisted yet, so they can't be migrated into; use a .db file");
+    }
+    
+    let mut source = create_storage(&StorageBackend::from_path(&args.from), &args.from).await
+        .context("Failed to open the source database")?;
+    let mut target = create_storage(&StorageBackend::from_path(&args.to), &args.to).await
+        .context("Failed to create the target database")?;
+    println!("🚚 Copying {} to {}", args.from.display(), args.to.display());
+    
+    let stats = migrate::copy_brain(&mut *source, &mut *target).await?;
+    println!("✅ Copied {} documents and {} fragments; vectors were carried over, nothing needs re-embedding",
+             stats.documents, stats.fragments);
+    Ok(())
+}
+
+async fn run_pack(args: PackArgs) -> Result<()> {
+    if ShardedStorage::is_manifest(&args.storage.database)
+        || !matches!(args.storage.backend.storage_backend(), StorageBackend::DuckDB)
+    {
+        anyhow::bail!("pack only applies to DuckDB database files; pack a sharded database's shards one at a time");
+    }
+    if !args.storage.database.exists() {
+        anyhow::bail!("Database not found: {}", args.storage.database.display());
+    }
+    let output = args.output.unwrap_or_else(|| args.storage.database.with_extension(package::PACKAGE_EXTENSION));
+    
+    let signing_key = match &args.sign_key {
+        Some(path) => {
+            let (key, created) = package::load_or_create_signing_key(path)?;
+            if created {
+                println!("🔑 Generated a new signing key in {}; keep it private", path.display());
+            }
+            println!("🔏 Signing with public key {}", package::public_key_hex(&key.verifying_key()));
+            Some(key)
+        }
+        None => None,
+    };
+    let options = package::PackOptions {
+        name: args.name,
+        version: args.version,
+        originals: args.originals,
+        signing_key,
+    };
+    
+    // Closing the database folds its write-ahead log into the file that is packed
+    drop(duckdb_storage::DuckDBStorage::new(&args.storage.database).await.context("Failed to open database")?);
+    let mut storage = open_storage(&args.storage).await?;
+    let manifest = package::pack(&args.storage.database, &mut *storage, &options, &output).await?;
+    println!("📦 Packed {} ({} documents, {} fragments, {}) into {}",
+             manifest.name, manifest.documents, manifest.fragments, manifest.embedding_model, output.display());
+    if options.signing_key.is_none() {
+        println!("ℹ️  The package is unsigned; pass --sign-key to let recipients check who packed it");
+    }
+    Ok(())
+}
+
+/// The trusted key given to `unpack` or `verify`
+fn trusted_key(public_key: Option<&str>) -> Result<Option<ed25519_dalek::VerifyingKey>> {
+    public_key.map(package::parse_public_key).transpose()
+}
+
+/// Describe a verified package
+fn print_package(verified: &package::Verified) {
+    let manifest = &verified.manifest;
+    println!("📦 {} {}", manifest.name, manifest.version.as_deref().unwrap_or("(unversioned)"));
+    println!("   {} documents, {} fragments, embedded with {}", manifest.documents, manifest.fragments, manifest.embedding_model);
+    println!("   Packed {} by portable-brains {}", manifest.created.format("%Y-%m-%d %H:%M UTC"), manifest.packed_by);
+    match &verified.signer {
+        Some(signer) => println!("🔏 Signed by {}", signer),
+        None => println!("⚠️  Unsigned: the contents are intact, but who packed them can't be checked"),
+    }
+}
+
+fn run_unpack(args: UnpackArgs) -> Result<()> {
+    let trusted = trusted_key(args.public_key.as_deref())?;
+    let database = args.database.unwrap_or_else(|| args.package.with_extension("db"));
+    let verified = package::unpack(&args.package, trusted.as_ref(), &database, args.originals.as_deref())
+        .with_context(|| format!("Failed to unpack {}", args.package.display()))?;
+    print_package(&verified);
+    println!("✅ Extracted the database to {}", database.display());
+    Ok(())
+}
+
+fn run_verify(args: VerifyArgs) -> Result<()> {
+    let trusted = trusted_key(args.public_key.as_deref())?;
+    let verified = package::verify_package(&args.package, trusted.as_ref())
+        .with_context(|| format!("{} failed verification", args.package.display()))?;
+    print_package(&verified);
+    println!("✅ Every file matches the manifest");
+    Ok(())
+}
+
+async fn run_summarize(args: SummarizeArgs) -> Result<()> {
+    println!("🧠 Portable Brains - Summarizing {}", args.storage.database.display());
+    let mut storage = open_storage(&args.storage).await?;
+    if args.clear {
+        summary_tree::clear(&mut *storage).await?;
+        println!("🗑️  Removed the summary tree");
+        return Ok(());
+    }
+    if storage::embeddings_only(&mut *storage).await? {
+        anyhow::bail!("The database is embeddings-only, so there is no text to summarize");
+    }
+    let pending = storage.count_fragments_without_embeddings().await?;
+    if pending > 0 {
+        println!("⚠️  {} fragments aren't embedded yet and are left out; run `embed` first to include them", pending);
+    }
+    
+    let model = resolve_model(&mut *storage, None).await?;
+    let mut embedding_manager = create_embedding_manager(&model, &args.provider).await?;
+    let endpoint = args.llm_endpoint.ok_or_else(|| anyhow!("--llm-endpoint is required"))?;
+    let provider = args.llm_provider.unwrap_or_else(|| Provider::from_endpoint(&endpoint));
+    let api_key = match args.llm_api_key {
+        Some(api_key) => api_key,
+        None if provider.requires_api_key() => anyhow::bail!("--llm-api-key is required for the {:?} provider", provider),
+        None => String::new(),
+    };
+    let llm = LlmClient::new(endpoint, api_key, args.llm_model).with_provider(provider);
+    offline::check(&llm.endpoint, "The LLM API")?;
+    println!("🤖 Summarizing with {}, embedding with {}", llm.model, model);
+    
+    let options = TreeOptions { cluster_size: args.cluster_size, levels: args.levels, max_fragments: args.max_fragments };
+    let mut summarizer = LlmSummarizer::new(&llm);
+    let tree = summary_tree::build(&mut *storage, &mut embedding_manager, &mut summarizer, &options, &mut |level, from, to| {
+        println!("🌳 Level {}: {} passages summarized into {}", level, from, to);
+    }).await?;
+    summary_tree::save(&mut *storage, &tree).await?;
+    println!("✅ {} summaries in {} levels ({} tokens)", tree.nodes.len(), tree.levels(), summarizer.usage.total_tokens);
+    Ok(())
+}
+
+async fn run_compact(args: CompactArgs) -> Result<()> {
+    if ShardedStorage::is_manifest(&args.database)
+        || !matches!(StorageBackend::from_path(&args.database), StorageBackend::DuckDB)
+    {
+        anyhow::bail!("compact only applies to DuckDB database files; compact a sharded database's shards one at a time");
+    }
+    if !args.database.exists() {
+        anyhow::bail!("Database not found: {}", args.database.display());
+    }
+    
+    let size_before = std::fs::metadata(&args.database)?.len();
+    {
+        let mut storage = duckdb_storage::DuckDBStorage::new(&args.database).await
+            .context("Failed to open database")?;
+        if args.expand {
+            if storage.compact_dimension().is_none() {
+                println!("ℹ️  {} already uses the standard layout", args.database.display());
+                return Ok(());
+            }
+            storage.expand()?;
+            println!("📦 Fragments rebuilt in the standard layout");
+        } else {
+            let dimension = storage.compact()?;
+            println!("🗜️  Fragments rebuilt in the compact layout ({}-dimensional FLOAT vectors)", dimension);
+        }
+    }
+    
+    let size_after = std::fs::metadata(&args.database)?.len();
+    println!("💾 Database size: {:.1} MB → {:.1} MB",
+             size_before as f64 / (1024.0 * 1024.0), size_after as f64 / (1024.0 * 1024.0));
+    Ok(())
+}
+
+/// Export every fragment to Parquet, paging through the database in Arrow batches
+async fn export_parquet(storage: &mut dyn Storage, output: &Path, no_embeddings: bool) -> Result<()> {
+    use storage::arrow::array::{new_null_array, Array, StringArray};
+    
+    let mut writer = duckdb_storage::ParquetWriter::new()?;
+    let mut after: Option<String> = None;
+    while let Some(mut batch) = storage.read_fragment_batch(after.as_deref(), storage::FRAGMENT_BATCH_SIZE).await? {
+        let ids = storage::fragment_column::<StringArray>(&batch, "id")?;
+        after = Some(ids.value(ids.len() - 1).to_string());
+        if no_embeddings {
+            let (index, field) = batch.schema().column_with_name("embedding")
+                .map(|(index, field)| (index, field.clone()))
+                .context("Fragment batch has no embedding column")?;
+            let mut columns = batch.columns().to_vec();
+            columns[index] = new_null_array(field.data_type(), batch.num_rows());
+            batch = storage::arrow::array::RecordBatch::try_new(batch.schema(), columns)?;
+        }
+        writer.append(&batch)?;
+    }
+    
+    let fragments = writer.finish(output)?;
+    println!("✅ Exported {} fragments to {}", fragments, output.display());
+    Ok(())
+}
+
+async fn run_rechunk(args: RechunkArgs) -> Result<()> {
+    println!("🧠 Portable Brains - Re-chunking {}", args.storage.database.display());
+    let mut storage = open_storage(&args.storage).await?;
+    if storage::embeddings_only(&mut *storage).await? {
+        anyhow::bail!("The database is embeddings-only, so there is no text to re-chunk");
+    }
+    let preset = resolve_preset(&mut *storage, None).await?;
+    let chunking = resolve_chunking(&mut *storage, args.chunking).await?;
     
-    /// Directory containing documents to index (PDF, TXT, HTML, DOCX, PPTX, XLSX)
-    #[arg(short, long)]
-    input_dir: PathBuf,
+    let (chunk_size, overlap, unit) = chunk_sizes(preset, chunking.strategy, args.chunk_size, args.overlap);
+    println!("✂️  Chunk size {} with {} {} of overlap", chunk_size, overlap, unit);
     
-    /// Storage backend to use
-    #[arg(short, long, value_enum, default_value = "duckdb")]
-    backend: Backend,
+    let config = args.config.as_deref().map(Config::load).transpose()?;
+    let cleanup = cleanup_rules(preset, config.as_ref().zip(args.config.as_deref()))?;
+    let processor = chunking.apply(document_processor(preset))?
+        .with_chunking(chunk_size, overlap)
+        .with_cleanup(cleanup);
     
-    /// Embedding provider to use
-    #[arg(short = 'p', long, value_enum, default_value = "local")]
-    embedding_provider: EmbeddingProvider,
+    let documents = storage.list_documents().await?;
     
-    /// API key for remote embedding providers (required for remote)
-    #[arg(long)]
-    api_key: Option<String>,
+    let mut totals = FragmentChanges::default();
+    let mut reextracted = 0;
+    let mut failed = 0;
+    for (i, document) in documents.iter().enumerate() {
+        match rechunk_document(&mut *storage, &processor, document).await {
+            Ok((changes, from_original)) => {
+                let note = if from_original { " (re-extracted from the stored original)" } else { "" };
+                println!("✅ [{}/{}] {}: {} kept, {} new, {} removed{}",
+                         i + 1, documents.len(), document.file_path,
+                         changes.kept, changes.added, changes.removed, note);
+                totals.kept += changes.kept;
+                totals.added += changes.added;
+                totals.removed += changes.removed;
+                reextracted += from_original as usize;
+            }
+            Err(e) => {
+                println!("❌ [{}/{}] {}: {:#}", i + 1, documents.len(), document.file_path, e);
+                failed += 1;
+            }
+        }
+    }
     
-    /// Endpoint URL for remote embedding service (defaults to OpenAI if not specified)
-    #[arg(long)]
-    endpoint: Option<String>,
+    println!();
+    println!("📊 Re-chunked {} documents: {} fragments kept, {} new, {} removed",
+             documents.len() - failed, totals.kept, totals.added, totals.removed);
+    if reextracted > 0 {
+        println!("📄 {} documents had no saved text and were re-extracted once", reextracted);
+    }
+    if failed > 0 {
+        println!("⚠️  {} documents failed and were left unchanged", failed);
+    }
+    if totals.added > 0 {
+        println!("💡 Run `portable-brains embed` to embed the {} new fragments", totals.added);
+    }
+    // Sections and titles may have changed with the chunking
+    refresh_autocomplete(&mut *storage).await;
     
-    /// Enable verbose logging
-    #[arg(short, long)]
-    verbose: bool,
+    Ok(())
 }
 
-async fn create_storage(backend: Backend, database_path: &Path) -> Result<Box<dyn Storage>> {
-    match backend {
-        Backend::Duckdb => {
-            let storage = DuckDBStorage::new(database_path).await?;
-            Ok(Box::new(storage))
+/// Re-chunk one document from its saved text, falling back to the stored original for
+/// documents indexed before text was saved. Returns the fragment changes and whether the
+/// original had to be parsed.
+async fn rechunk_document(
+    storage: &mut dyn Storage,
+    processor: &DocumentProcessor,
+    document: &DocumentSummary,
+) -> Result<(FragmentChanges, bool)> {
+    let (sections, from_original) = match storage.get_document_text(&document.id).await? {
+        Some(text) => (unpack_sections(&text)?, false),
+        None => {
+            let original = storage.get_document(&document.id).await?
+                .ok_or_else(|| anyhow!("Document {} disappeared", document.id))?;
+            let data = original.file_data.ok_or_else(|| {
+                anyhow!("{} has no saved text and its original wasn't stored", original.file_path)
+            })?;
+            let sections = processor.extract_sections_from_document(Path::new(&original.file_path), &data)
+                .context("Failed to extract text")?;
+            storage.set_document_text(&document.id, &pack_sections(&sections)?).await?;
+            (sections, true)
         }
-        Backend::Lancedb => {
-            let storage = LanceDBStorage::new(database_path).await?;
-            Ok(Box::new(storage))
+    };
+    
+    // Documents indexed before titles were derived get one
+    if document.title.is_none() {
+        if let Some(title) = derive_title(&sections) {
+            storage.set_document_title(&document.id, &title).await?;
         }
     }
+    
+    // Invalid chunks are skipped, as they are at index time
+    let fragments: Vec<(String, FragmentMeta)> = processor.chunk_sections(&sections)?
+        .into_iter()
+        .filter_map(|(content, meta)| match storage::validate_fragment(&content) {
+            Ok(content) => Some((content.into_owned(), meta)),
+            Err(e) => {
+                println!("⚠️  Skipping fragment of {}: {}", document.file_path, e);
+                None
+            }
+        })
+        .collect();
+    
+    let changes = storage.replace_fragments(&document.id, &fragments).await?;
+    if changes.added > 0 {
+        // New fragments wait for vectors again
+        storage::publish_document(storage, &document.id).await?;
+    } else if changes.removed > 0 {
+        storage::publish(storage).await?;
+    }
+    Ok((changes, from_original))
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    let args = Args::parse();
+async fn run_serve(args: ServeArgs) -> Result<()> {
+    let token = args.token
+        .or_else(|| std::env::var("PORTABLE_BRAINS_TOKEN").ok())
+        .filter(|token| !token.trim().is_empty());
     
-    // Initialize logging with cleaner output
-    let log_level = if args.verbose { "debug" } else { "info" };
-    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(log_level))
-        .filter_module("lopdf", log::LevelFilter::Warn)         // Suppress lopdf debug messages
-        .filter_module("duckdb", log::LevelFilter::Warn)        // Suppress duckdb debug messages  
-        .filter_module("ort", log::LevelFilter::Warn)           // Suppress ONNX runtime debug messages
-        .filter_module("html5ever", log::LevelFilter::Warn)     // Suppress HTML parser debug messages
-        .filter_module("selectors", log::LevelFilter::Warn)     // Suppress CSS selector debug messages
-        .filter_module("lancedb", log::LevelFilter::Warn)       // Suppress LanceDB debug messages
-        .format_target(false)                                   // Hide module names in output
-        .format_timestamp(None)                                 // Hide timestamps for cleaner output
-        .init();
+    // The config is reloaded on SIGHUP or when the file changes
+    let live = args.config.as_deref().map(LiveConfig::watch).transpose()?;
+    let config_tokens = live.as_ref().is_some_and(|live| {
+        let config = live.current();
+        !config.serve.tokens.is_empty() || !config.tenants.is_empty()
+    });
+    if token.is_none() && !config_tokens {
+        anyhow::bail!("serve requires an access token; pass --token, set PORTABLE_BRAINS_TOKEN or list [serve] or [tenants] tokens in --config");
+    }
+    
+    println!("🧠 Portable Brains - REST server");
+    let mut storage = open_storage(&args.storage).await?;
     
-    let backend_name = match args.backend {
-        Backend::Duckdb => "DuckDB",
-        Backend::Lancedb => "LanceDB",
+    let embedding_manager = if args.embed || args.search {
+        let model = resolve_model(&mut *storage, None).await?;
+        if args.embed {
+            println!("🤖 Embedding ingested documents and searches with {}", model);
+        } else {
+            println!("🤖 Embedding searches with {}", model);
+        }
+        Some(create_embedding_manager(&model, &args.provider).await?)
+    } else {
+        None
     };
+    if let Some(live) = &live {
+        let tenants = live.current().tenants.len();
+        if tenants > 0 {
+            println!("🏢 Serving {} tenants, each confined to its own collections", tenants);
+        }
+    }
     
-    println!("🧠 Portable Brains - Document Indexer");
-    println!("📁 Scanning directory: {}", args.input_dir.display());
-    println!("💾 Using {} backend: {}", backend_name, args.database.display());
+    let preset = resolve_preset(&mut *storage, None).await?;
+    let chunking = resolve_chunking(&mut *storage, None).await?;
+    let (processor, router, scanner) = match &live {
+        Some(live) => ingest_components(&live.current(), live.path(), preset, &args.storage.database)?,
+        None => configure_ingest(None, preset, &args.storage.database)?,
+    };
+    let pipeline = IngestPipeline {
+        processor: chunking.apply(processor)?,
+        preset,
+        chunking,
+        priority: 0,
+        assigned: Routing::default(),
+        classifier: None,
+        router,
+        scanner,
+        throttle: Throttle::new(&ThrottleSettings::default())?,
+    };
     
-    // Validate input directory exists
-    if !args.input_dir.exists() {
-        anyhow::bail!("Input directory does not exist: {}", args.input_dir.display());
-    }
+    let ingest = server::IngestSettings {
+        pipeline,
+        embedding_manager,
+        embed: args.embed,
+        upload_dir: storage::sidecar_path(&args.storage.database, ".uploads"),
+        config: live,
+        database: args.storage.database.clone(),
+    };
     
-    // Initialize storage backend
-    let mut storage = create_storage(args.backend.clone(), &args.database).await
+    let jobs = JobStore::for_database(&args.storage.database);
+    server::serve(storage, args.bind, token, jobs, args.cache_size, ingest).await
+}
+
+async fn run_mcp(args: McpArgs) -> Result<()> {
+    // stdout carries the protocol, so nothing is printed; logs go to stderr
+    let mut storage = create_storage(&args.storage.backend.storage_backend(), &args.storage.database).await
         .context("Failed to initialize storage backend")?;
+    let model = resolve_model(&mut *storage, args.model).await?;
+    let embedding_manager = create_embedding_manager(&model, &args.provider).await?;
+    log::info!("Serving {} over MCP with {}", args.storage.database.display(), model);
     
-    // Verify or set embedding model
-    storage.verify_or_set_model(&args.model).await
-        .context("Failed to verify embedding model")?;
+    mcp::McpServer::new(storage, embedding_manager).run().await
+}
+
+fn run_openapi() -> Result<()> {
+    use utoipa::OpenApi;
+    println!("{}", server::ApiDoc::openapi().to_pretty_json()?);
+    Ok(())
+}
+
+fn run_jobs(args: JobsArgs) -> Result<()> {
+    let store = JobStore::for_database(&args.database);
     
-    // Initialize embedding manager based on provider
-    let mut embedding_manager = match args.embedding_provider {
-        EmbeddingProvider::Local => {
-            EmbeddingManager::new(&args.model).await
-                .context("Failed to initialize local embedding manager")?
-        },
-        EmbeddingProvider::Remote => {
-            let api_key = args.api_key
-                .ok_or_else(|| anyhow::anyhow!("API key is required for remote embedding provider"))?;
-            
-            EmbeddingManager::new_remote(api_key, &args.model, args.endpoint).await
-                .context("Failed to initialize remote embedding manager")?
-        },
+    if let Some(id) = &args.cancel {
+        match store.cancel(id)? {
+            None => anyhow::bail!("No job {} for {}", id, args.database.display()),
+            Some(record) if record.state.is_finished() => println!("ℹ️  Job {} has already {}", id, record.state.as_str()),
+            Some(_) => println!("⏹️  Requested cancellation of job {}; it stops after its current document or batch", id),
+        }
+        return Ok(());
+    }
+    
+    let records = store.list()?;
+    if records.is_empty() {
+        println!("ℹ️  No jobs recorded for {}", args.database.display());
+        return Ok(());
+    }
+    for record in records {
+        let icon = match record.state {
+            JobState::Queued => "⏳",
+            JobState::Running => "🔄",
+            JobState::Completed => "✅",
+            JobState::Failed => "❌",
+            JobState::Cancelled => "⏹️ ",
+            JobState::Interrupted => "⚠️ ",
+        };
+        let stage = record.stage.as_deref().unwrap_or("-");
+        println!("{} {} {:<6} {:<11} {:>5.1}% {} {}/{}  {}",
+                 icon, record.created_at, record.kind.as_str(), record.state.as_str(),
+                 record.progress, stage, record.processed, record.total, record.id);
+        if let Some(error) = &record.error {
+            println!("     {}", error);
+        }
+    }
+    Ok(())
+}
+
+/// Query several databases concurrently and merge their results by normalized score
+async fn run_federated_search(args: SearchArgs) -> Result<()> {
+    if args.explain {
+        println!("ℹ️  --explain is only available when searching a single database with one embedding model");
+    }
+    if args.cursor.is_some() {
+        anyhow::bail!("--cursor is only available when searching a single database with one embedding model");
+    }
+    if args.within.is_some() {
+        anyhow::bail!("--within is only available when searching a single database with one embedding model");
+    }
+    
+    let backend = args.backend.as_ref().map(|b| b.storage_backend());
+    let metadata = search_filter(&args)?;
+    
+    // Every database must share the query model, so resolve it from the first one
+    let model = match args.model {
+        Some(model) => model,
+        None => {
+            let first = &args.database[0];
+            let mut storage = create_storage(&backend.clone().unwrap_or_else(|| StorageBackend::from_path(first)), first).await
+                .context("Failed to initialize storage backend")?;
+            resolve_model(&mut *storage, None).await?
+        }
     };
     
-    // Initialize document processor with memory-efficient sentence-based chunking
-    let document_processor = DocumentProcessor::with_limits(
-        800,        // chunk_size: Larger chunks for sentence-based approach
-        100,        // overlap: Reasonable overlap in characters  
-        50 * 1024 * 1024,  // max_file_size: 50MB per file (reduced from 100MB)
-        5_000_000,  // max_text_length: 5M characters (reduced from 10M)
-    );
+    let mut brains = BrainSet::open(&args.database, backend, &model, RoutingMode::Federate).await?;
+    if brains.len() > 1 {
+        println!("🔗 Federating search across {} databases: {}", brains.len(), brains.names().join(", "));
+    }
+    if let Some((brain, document)) = brains.focus(args.document.as_deref()).await? {
+        println!("📄 Searching {} in {} only", document.file_path, brain);
+    }
+    for collection_model in brains.collection_models() {
+        println!("🗂️  Searching collections embedded with {} with queries embedded by it", collection_model);
+        let embedder = create_embedding_manager(&collection_model, &args.provider).await?;
+        brains.add_collection_embedder(&collection_model, embedder);
+    }
     
-    // Phase 1: Process all supported files and extract text (no embeddings yet)
-    let supported_files = find_supported_files(&args.input_dir)?;
-    println!("📂 Found {} documents to process", supported_files.len());
+    let mut embedding_manager = create_embedding_manager(&model, &args.provider).await?;
+    let (query, section) = SearchFilter::parse_section(&args.query);
+    let query_embedding = embedding_manager.generate_embedding(&brains.prefixes().query(&query)).await
+        .context("Failed to generate query embedding")?;
     
-    if supported_files.is_empty() {
-        println!("⚠️  No supported files found in directory: {}", args.input_dir.display());
-        println!("📋 Supported formats: PDF, TXT, HTML, DOCX, PPTX, XLSX");
-        return Ok(());
+    let filter = SearchFilter { section, ..metadata };
+    let mut reranker = args.rerank_model.as_deref().map(CrossEncoder::new).transpose()?;
+    let per_document = args.per_document_limit.map(|limit| limit as usize);
+    let keep = if per_document.is_some() { args.limit * retrieval::PER_DOCUMENT_POOL_FACTOR } else { args.limit };
+    let fetch = if reranker.is_some() { keep.max(RERANK_POOL) } else { keep };
+    let mut search = brains.search(&query, &query_embedding, fetch, RoutingMode::Federate, args.search_mode, &filter).await?;
+    if let Some(reranker) = &mut reranker {
+        println!("🎯 Reranking {} candidates with {}", search.hits.len(), reranker.model_name());
+        search.hits = reranker.rerank(&query, search.hits, |hit| hit.content.as_str(), keep)?
+            .into_iter()
+            .map(|(hit, score)| BrainHit { score, ..hit })
+            .collect();
+    }
+    let mut collapsed = Vec::new();
+    if let Some(per_document) = per_document {
+        let (hits, rest) = retrieval::limit_per_document(search.hits, per_document, |hit| (hit.brain.clone(), hit.source.file_path.clone()));
+        search.hits = hits;
+        search.hits.truncate(args.limit);
+        collapsed = rest;
     }
     
-    println!("\n🚀 Phase 1: Extracting text from documents...");
-    for (i, file_path) in supported_files.iter().enumerate() {
-        let filename = file_path.file_name()
-            .and_then(|name| name.to_str())
-            .unwrap_or("unknown");
-        let extension = file_path.extension()
-            .and_then(|ext| ext.to_str())
-            .unwrap_or("unknown");
-        
-        print!("📝 [{}/{}] Processing {} ({})... ", 
-               i + 1, supported_files.len(), filename, extension.to_uppercase());
-        
-        match process_document(
-            file_path,
-            &mut *storage,
-            &document_processor,
-        ).await {
-            Ok(fragment_count) => {
-                println!("✅ Success! ({} fragments)", fragment_count);
-            },
-            Err(e) => {
-                println!("❌ Failed: {}", e);
-                if args.verbose {
-                    eprintln!("   Error details: {:?}", e);
-                }
-                // Continue processing other files
-            }
+    println!();
+    if search.hits.is_empty() {
+        println!("💭 No matching fragments found");
+    }
+    for (i, hit) in search.hits.iter().enumerate() {
+        let preview: String = hit.content.chars().take(200).collect();
+        println!("{}. [{:.4} normalized, {:.4} raw] {} / {}", i + 1, hit.normalized_score, hit.score, hit.brain, hit.fragment_id);
+        println!("   📎 {}", hit.source.citation());
+        for note in &hit.source.annotations {
+            println!("   📝 {}", note.describe());
+        }
+        println!("   {}", preview);
+        let same_document = |other: &BrainHit| other.brain == hit.brain && other.source.file_path == hit.source.file_path;
+        if !search.hits[..i].iter().any(same_document) {
+            let more: Vec<_> = collapsed.iter()
+                .filter(|other| same_document(other))
+                .map(|other| (other.score, other.fragment_id.as_str(), &other.source, other.content.as_str()))
+                .collect();
+            print_collapsed(&more, args.expand);
         }
     }
     
-    // Phase 2: Generate embeddings in batches
-    let total_fragments = storage.count_fragments_without_embeddings().await?;
+    Ok(())
+}
+
+/// Under a document's result, list the fragments of it that `--per-document-limit`
+/// collapsed, as (score, fragment id, source, content), with `--expand`, or say how many
+/// there were without
+fn print_collapsed(collapsed: &[(f64, &str, &storage::FragmentSource, &str)], expand: bool) {
+    if collapsed.is_empty() {
+        return;
+    }
+    if !expand {
+        println!("   ➕ {} more matching fragments in this document (--expand to list them)", collapsed.len());
+        return;
+    }
+    for (score, fragment_id, source, content) in collapsed {
+        let preview: String = content.chars().take(120).collect();
+        println!("   ↳ [{:.4}] {} ({})", score, fragment_id, source.citation());
+        println!("     {}", preview);
+    }
+}
+
+/// Print the embedding of arbitrary text as JSON, using the model and prefixes recorded in
+/// the database
+async fn embed_text_to_json(args: &EmbedArgs, text: &str) -> Result<()> {
+    // Status output is kept off stdout so the JSON can be piped to other tools
+    let mut storage = create_storage(&args.storage.backend.storage_backend(), &args.storage.database).await
+        .context("Failed to initialize storage backend")?;
+    let model = resolve_model(&mut *storage, args.model.clone()).await?;
+    let prefixes = storage::embedding_prefixes(&mut *storage).await?;
+    let text = if args.query { prefixes.query(text) } else { prefixes.document(text) };
+    
+    let mut embedding_manager = create_embedding_manager(&model, &args.provider).await?;
+    let embedded = embedding_manager.embed_text(&text).await
+        .context("Failed to embed text")?;
+    
+    println!("{}", serde_json::to_string(&embedded)?);
+    Ok(())
+}
+
+/// Phase 2: Generate embeddings in batches for every fragment missing one or holding a
+/// stale one, stopping after `max_fragments` when given
+async fn embed_pending_fragments(
+    storage: &mut dyn Storage,
+    embedding_manager: &mut EmbeddingManager,
+    batch_size: i32,
+    max_fragments: Option<i32>,
+    throttle: &mut Throttle,
+    job: &mut Job,
+    progress: &mut Progress,
+) -> Result<()> {
+    // Fragments that failed in an earlier run get another try
+    let retried = storage.retry_embedding_failures().await?;
+    if retried > 0 {
+        println!("🔁 Retrying {} fragments that failed to embed last time", retried);
+    }
+    let pending = storage.count_fragments_without_embeddings().await?;
+    let total_fragments = max_fragments.map_or(pending, |max| pending.min(max));
+    
+    // Vectors of another length would break cosine similarity against those already stored
+    if let Some(dimension) = storage::embedding_dimension(storage).await? {
+        embedding_manager.expect_dimension(dimension);
+    }
     
     if total_fragments > 0 {
         println!("\n🧠 Phase 2: Generating embeddings for {} text fragments...", total_fragments);
+        job.set_stage("embedding", total_fragments as u64)?;
+        progress.start_phase("embed", "Embedding", total_fragments as u64);
         
-        const EMBEDDING_BATCH_SIZE: i32 = 50;
         let mut processed = 0;
+        let mut reused = 0;
+        
+        if storage::late_chunking_enabled(storage).await? {
+            if embedding_manager.supports_late_chunking() {
+                processed = storage::embed_late_chunks(storage, embedding_manager, total_fragments).await?;
+                job.advance(processed as u64)?;
+                progress.advance(processed as u64);
+                progress.println(format!("🧩 Late chunked {} fragments in the context of their sections", processed));
+            } else {
+                progress.println("⚠️  Late chunking needs a local model; embedding fragments on their own");
+            }
+        }
         
-        loop {
-            let batch_processed = process_embedding_batch(
-                &mut *storage,
-                &mut embedding_manager,
-                EMBEDDING_BATCH_SIZE,
+        while processed < total_fragments {
+            if job.is_cancelled() {
+                break;
+            }
+            throttle.wait_for_power().await;
+            
+            let batch = storage::embed_fragment_batch(
+                storage,
+                embedding_manager,
+                batch_size.min(total_fragments - processed),
             ).await?;
+            let batch_processed = batch.fragments;
             
             if batch_processed == 0 {
                 break; // No more fragments to process
             }
             
             processed += batch_processed;
-            let percentage = (processed as f64 / total_fragments as f64) * 100.0;
-            print!("\r⚡ Generating embeddings: {}/{} ({:.1}%)", 
-                   processed, total_fragments, percentage);
-            std::io::Write::flush(&mut std::io::stdout()).unwrap();
+            reused += batch.reused;
+            job.advance(batch_processed as u64)?;
+            progress.advance(batch_processed as u64);
             
             // Small delay between batches to prevent memory buildup
             tokio::time::sleep(std::time::Duration::from_millis(100)).await;
         }
         
-        println!("\n✅ Completed all embeddings!");
+        progress.finish_phase();
+        
+        // Fragments set aside after failing go back in the queue for the next run
+        let failed = storage.retry_embedding_failures().await?;
+        if job.is_cancelled() {
+            println!("⏹️  Cancelled after embedding {} fragments; {} remain for a later run", processed, pending - processed);
+        } else if processed < pending {
+            println!("⏸️  Embedded {} fragments; {} remain for a later run", processed, pending - processed);
+        } else if failed == 0 {
+            println!("✅ Completed all embeddings!");
+        }
+        if reused > 0 {
+            println!("♻️  {} fragments reused the vector of an identical chunk", reused);
+        }
+        if failed > 0 {
+            println!("⚠️  {} fragments failed to embed and will be retried on the next run", failed);
+        }
     } else {
         println!("\nℹ️  All fragments already have embeddings");
     }
     
-    println!("\n� Indexing completed successfully!");
+    let embedded = storage::mark_embedded_documents(storage).await?;
+    if embedded > 0 {
+        println!("📗 {} documents are fully embedded", embedded);
+    }
+    
+    // Searches use an HNSW index once nothing is left to embed
+    if !job.is_cancelled() && storage.count_fragments_without_embeddings().await? == 0 && storage.build_vector_index().await? {
+        println!("🧭 HNSW vector index ready for similarity search");
+    }
+    
     Ok(())
 }
 
-fn find_supported_files(dir: &Path) -> Result<Vec<PathBuf>> {
-    let mut supported_files = Vec::new();
-    let supported_extensions = ["pdf", "txt", "text", "html", "htm", "docx", "pptx", "xlsx"];
+/// Document processor with memory-efficient sentence-based chunking, sized for the preset
+fn document_processor(preset: Option<Preset>) -> DocumentProcessor {
+    let (chunk_size, overlap) = preset.map_or((800, 100), Preset::chunking);
+    DocumentProcessor::with_limits(
+        chunk_size, // Larger chunks for sentence-based approach
+        overlap,    // Reasonable overlap in characters
+        50 * 1024 * 1024,  // max_file_size: 50MB per file (reduced from 100MB)
+        5_000_000,  // segment_length: longer extractions are chunked in further 5M character segments
+    )
+}
+
+/// Document processor, collection router and content scanner from an optional config file
+fn configure_ingest(
+    config_path: Option<&Path>,
+    preset: Option<Preset>,
+    database: &Path,
+) -> Result<(DocumentProcessor, Option<CollectionRouter>, Option<ContentScanner>)> {
+    let Some(path) = config_path else {
+        let processor = document_processor(preset).with_cleanup(cleanup_rules(preset, None)?);
+        return Ok((processor, None, None));
+    };
+    ingest_components(&Config::load(path)?, path, preset, database)
+}
+
+/// Build the configurable parts of the ingest pipeline from a loaded config file
+fn ingest_components(
+    config: &Config,
+    path: &Path,
+    preset: Option<Preset>,
+    database: &Path,
+) -> Result<(DocumentProcessor, Option<CollectionRouter>, Option<ContentScanner>)> {
+    let cleanup = cleanup_rules(preset, Some((config, path)))?;
+    let processor = document_processor(preset).with_cleanup(cleanup);
     
-    for entry in std::fs::read_dir(dir)? {
-        let entry = entry?;
-        let path = entry.path();
-        
-        if path.is_file() {
-            if let Some(extension) = path.extension() {
-                let ext_lower = extension.to_string_lossy().to_lowercase();
-                if supported_extensions.contains(&ext_lower.as_str()) {
-                    supported_files.push(path);
-                }
-            }
-        }
+    let router = CollectionRouter::new(config)?;
+    if router.is_empty() {
+        println!("⚠️  {} defines no collection routing rules", path.display());
+    }
+    
+    // Originals are scanned before they are parsed or stored, since the database is shared
+    let scanner = ContentScanner::from_config(&config.scan, database)?;
+    if let Some(scanner) = &scanner {
+        println!("🛡️  Scanning documents with {}; suspicious files go to {}",
+                 scanner.scanner_names().join(", "), scanner.quarantine_dir().display());
     }
     
-    Ok(supported_files)
+    Ok((processor, Some(router), scanner))
+}
+
+/// The preset's cleanup rules followed by those in the config file
+fn cleanup_rules(preset: Option<Preset>, config: Option<(&Config, &Path)>) -> Result<TextCleanup> {
+    let preset_cleanup = match preset {
+        Some(preset) => CleanupConfig::for_preset(preset)?,
+        None => CleanupConfig::default(),
+    };
+    let cleanup = match config {
+        Some((config, path)) => preset_cleanup.merged(&config.cleanup).compile()
+            .with_context(|| format!("Invalid [cleanup] settings in {}", path.display()))?,
+        None => preset_cleanup.compile()?,
+    };
+    if !cleanup.rules.is_empty() {
+        println!("🧹 Applying {} cleanup rule(s) to extracted text", cleanup.rules.len());
+    }
+    Ok(cleanup)
 }
 
 async fn process_document(
     file_path: &Path,
     storage: &mut dyn Storage,
-    processor: &DocumentProcessor,
-) -> Result<usize> {
+    pipeline: &mut IngestPipeline,
+    outdated: Option<&str>,
+    stages: &mut StageTimings,
+) -> Result<FileOutcome> {
     // Check if document already exists
-    if storage.document_exists(file_path).await? {
-        return Err(anyhow::anyhow!("Document already exists"));
+    if outdated.is_none() && storage.document_exists(file_path).await? {
+        return Ok(FileOutcome::Skipped("already indexed (use --update to re-index changed files)".to_string()));
     }
     
-    // Check file size before loading
-    let file_size = std::fs::metadata(file_path)?.len();
-    
-    if file_size > 100 * 1024 * 1024 {  // 100MB limit
-        return Err(anyhow::anyhow!("File too large ({:.1} MB)", file_size as f64 / (1024.0 * 1024.0)));
+    // The same file at another path is neither extracted nor embedded again
+    if outdated.is_none() {
+        let started = std::time::Instant::now();
+        let data = std::fs::read(paths::io_path(file_path)).map_err(PortableBrainsError::from).context("Failed to read file")?;
+        StageTimings::lap(&mut stages.read, started);
+        if let Some(original) = storage.find_duplicate(&storage::content_hash(&data)).await? {
+            return Ok(FileOutcome::Skipped(format!("identical to {}", original)));
+        }
     }
     
-    // Read and store the original file
-    let file_data = std::fs::read(file_path).context("Failed to read file")?;
-    let document_id = storage.store_document(file_path, &file_data).await?;
+    let document = pipeline.prepare(file_path, stages).await?;
     
-    // Extract text from document with memory limits
-    let text = processor.extract_text_from_document(file_path, &file_data)
-        .context("Failed to extract text")?;
+    // The outdated version and its fragments only go once the new one has extracted cleanly
+    let started = std::time::Instant::now();
+    if let Some(outdated) = outdated {
+        storage.remove_document(outdated).await?;
+        let committed = ingest_queue::commit_document(storage, &document).await?;
+        StageTimings::lap(&mut stages.store, started);
+        if committed.is_none() {
+            storage::publish(storage).await?;
+            return Ok(FileOutcome::Succeeded("removed, its new content is identical to another document".to_string()));
+        }
+        return Ok(FileOutcome::Succeeded(format!("updated, {}", describe_document(&document))));
+    }
+    ingest_queue::commit_document(storage, &document).await?;
+    StageTimings::lap(&mut stages.store, started);
+    
+    Ok(FileOutcome::Succeeded(describe_document(&document)))
+}
+
+/// Per-run settings applied to every document before it is committed or staged
+struct IngestPipeline {
+    processor: DocumentProcessor,
+    preset: Option<Preset>,
+    chunking: Chunking,
+    priority: i32,
+    /// Collection and tags given on the command line for every document
+    assigned: Routing,
+    classifier: Option<ZeroShotClassifier>,
+    router: Option<CollectionRouter>,
+    scanner: Option<ContentScanner>,
+    throttle: Throttle,
+}
+
+impl IngestPipeline {
+    /// Swap in the cleanup rules, routing rules and scanner from a reloaded config file.
+    /// Nothing changes if any of them fails to build.
+    fn reconfigure(&mut self, config: &Config, path: &Path, database: &Path) -> Result<()> {
+        let (processor, router, scanner) = ingest_components(config, path, self.preset, database)?;
+        self.processor = self.chunking.apply(processor)?;
+        self.router = router;
+        self.scanner = scanner;
+        Ok(())
+    }
     
-    // Free the file data from memory as soon as possible
-    drop(file_data);
+    /// Extract a document, then classify and route it when configured, and apply the
+    /// collection and tags given on the command line. The extraction stages are timed into
+    /// `stages`.
+    async fn prepare(&mut self, file_path: &Path, stages: &mut StageTimings) -> Result<StagedDocument> {
+        self.throttle.wait_for_power().await;
+        
+        let mut document = extract_document(file_path, &self.processor, self.priority, self.scanner.as_ref(), stages)?;
+        self.throttle.pace_io(document.file_data.len() as u64).await;
+        
+        if let Some(classifier) = &mut self.classifier {
+            document.category = classifier.classify(document.fragments.iter().map(|f| f.content.as_str())).await
+                .context("Failed to classify document")?;
+        }
+        
+        if let Some(router) = &self.router {
+            let category = document.category.as_ref().map(|c| c.category.as_str());
+            document.routing = router.route(file_path, category);
+        }
+        if let Some(collection) = &self.assigned.collection {
+            document.routing.collection = Some(collection.clone());
+        }
+        for tag in &self.assigned.tags {
+            if !document.routing.tags.contains(tag) {
+                document.routing.tags.push(tag.clone());
+            }
+        }
+        
+        Ok(document)
+    }
+}
+
+/// One-line summary of a prepared document for progress output
+fn describe_document(document: &StagedDocument) -> String {
+    let mut parts = vec![format!("{} fragments", document.fragments.len())];
+    let segments = document.fragments.iter().map(|f| f.meta.segment + 1).max().unwrap_or(0);
+    if segments > 1 {
+        parts.push(format!("{} segments", segments));
+    }
+    let mut sections: Vec<&str> = document.fragments.iter().filter_map(|f| f.meta.section.as_deref()).collect();
+    sections.dedup();
+    if !sections.is_empty() {
+        parts.push(format!("{} sections", sections.len()));
+    }
+    if let Some(classification) = &document.category {
+        parts.push(classification.category.clone());
+    }
+    if let Some(collection) = &document.routing.collection {
+        parts.push(format!("→ {}", collection));
+    }
+    if !document.routing.tags.is_empty() {
+        parts.push(format!("#{}", document.routing.tags.join(" #")));
+    }
+    if let Some(quality) = document.quality.as_ref().filter(|q| q.is_flagged()) {
+        parts.push(format!("⚠️  suspect extraction: {}", quality.flags.join("; ")));
+    }
+    parts.join(", ")
+}
+
+/// Extract and chunk a document in memory, ready to be committed or staged, adding the time
+/// reading, parsing and chunking it took to `stages`
+fn extract_document(
+    file_path: &Path,
+    processor: &DocumentProcessor,
+    priority: i32,
+    scanner: Option<&ContentScanner>,
+    stages: &mut StageTimings,
+) -> Result<StagedDocument> {
+    let started = std::time::Instant::now();
+    // Long Windows paths need the \\?\ prefix to be opened
+    let io_path = paths::io_path(file_path);
     
-    // Split text into semantic chunks  
-    let fragments = processor.chunk_text(&text)
-        .context("Failed to chunk text")?;
+    // Check file size before loading
+    let file_size = std::fs::metadata(&io_path).map_err(PortableBrainsError::from)?.len();
     
-    // Free the text from memory as soon as possible
-    drop(text);
+    if file_size > processor.max_file_size() as u64 {
+        return Err(PortableBrainsError::DocumentProcessingError(
+            format!("File too large ({:.1} MB)", file_size as f64 / (1024.0 * 1024.0))
+        ).into());
+    }
     
-    let fragment_count = fragments.len();
+    // Read the original file; it is stored alongside its fragments
+    let file_data = std::fs::read(&io_path).map_err(PortableBrainsError::from).context("Failed to read file")?;
+    let started = StageTimings::lap(&mut stages.read, started);
     
-    // Store all text fragments first (without embeddings) to free up memory immediately
-    for (order, fragment) in fragments.iter().enumerate() {
-        storage.store_text_fragment(&document_id, order as i32, fragment).await
-            .with_context(|| format!("Failed to store text fragment {}", order))?;
+    // Suspicious files are quarantined before any parser sees them
+    if let Some(scanner) = scanner {
+        scanner.check(file_path, &file_data)?;
     }
     
-    Ok(fragment_count)
+    // Extract text from document with memory limits, split at its headings where it has them
+    let sections = processor.extract_sections_from_document(file_path, &file_data)
+        .context("Failed to extract text")?;
+    let quality = quality::assess(&join_sections(&sections));
+    let title = derive_title(&sections);
+    let started = StageTimings::lap(&mut stages.parse, started);
+    
+    let fragments = processor.chunk_sections(&sections)?
+        .into_iter()
+        .map(|(content, meta)| StagedFragment { content, meta })
+        .collect();
+    
+    // Only the compressed copy of the text, kept for re-chunking, outlives extraction
+    let text = pack_sections(&sections)?;
+    drop(sections);
+    StageTimings::lap(&mut stages.chunk, started);
+    
+    Ok(StagedDocument {
+        file_path: file_path.to_path_buf(),
+        priority,
+        file_data,
+        fragments,
+        category: None,
+        routing: Routing::default(),
+        quality: Some(quality),
+        title,
+        text: Some(text),
+    })
 }
 
-/// Process embeddings in batches for fragments without embeddings using FastEmbed batch processing
-async fn process_embedding_batch(
-    storage: &mut dyn Storage,
-    embedding_manager: &mut EmbeddingManager,
-    batch_size: i32,
-) -> Result<i32> {
-    let fragments = storage.get_fragments_without_embeddings(batch_size).await?;
+/// Documents extracted ahead of the embedder in single-pass mode; extraction waits once this
+/// many are queued, so a slow embedding model doesn't let extracted text pile up in memory
+const SINGLE_PASS_QUEUE: usize = 4;
+
+/// Fragments embedded per request in single-pass mode
+const SINGLE_PASS_BATCH: i32 = 50;
+
+/// What the single-pass embedder stored
+#[derive(Default)]
+struct SinglePassStats {
+    documents: usize,
+    fragments: usize,
+    embedded: usize,
+    skipped: usize,
+}
+
+/// What the single-pass embedder hands back once the queue is drained
+type SinglePassOutcome = (Box<dyn Storage>, EmbeddingManager, SinglePassStats);
+
+/// Extract documents while a background task commits each one and embeds its fragments, so
+/// extraction of the next document overlaps embedding of the last. Storage and the
+/// embedding manager are owned by that task until it finishes and are then handed back.
+///
+/// Fragments are committed before they are embedded, so an interrupted run leaves at most
+/// the queued documents unextracted and the rest waiting for `embed`, as two phases would.
+#[allow(clippy::too_many_arguments)]
+async fn run_single_pass(
+    mut storage: Box<dyn Storage>,
+    files: &[PathBuf],
+    replaces: &HashMap<PathBuf, String>,
+    pipeline: &mut IngestPipeline,
+    embedding_manager: EmbeddingManager,
+    verbose: bool,
+    job: &mut Job,
+    progress: &mut Progress,
+) -> Result<(Box<dyn Storage>, EmbeddingManager)> {
+    // Skip known documents up front; the embedder owns storage from here on
+    progress.start_phase("extract", "Extracting", files.len() as u64);
+    let mut new_files = Vec::new();
+    for file_path in files {
+        if !replaces.contains_key(file_path) && storage.document_exists(file_path).await? {
+            progress.finish_file(file_path, FileOutcome::Skipped("already indexed".to_string()));
+        } else {
+            new_files.push(file_path);
+        }
+    }
     
-    if fragments.is_empty() {
-        return Ok(0);
+    let (documents, queued) = tokio::sync::mpsc::channel(SINGLE_PASS_QUEUE);
+    let embedder = spawn_single_pass_embedder(storage, embedding_manager, queued);
+    
+    let mut extracted = Ok(());
+    for (i, file_path) in new_files.iter().enumerate() {
+        if job.is_cancelled() {
+            progress.println(format!("⏹️  Cancelled after extracting {} of {} documents", i, new_files.len()));
+            break;
+        }
+        
+        progress.start_file(file_path);
+        let mut stages = StageTimings::default();
+        let prepared = pipeline.prepare(file_path, &mut stages).await;
+        progress.record_stages(&stages);
+        match prepared {
+            Ok(document) => {
+                let summary = describe_document(&document);
+                let outdated = replaces.get(*file_path).cloned();
+                // The embedder only hangs up when it failed; its error is reported below
+                if documents.send((document, outdated)).await.is_err() {
+                    progress.finish_file(file_path, FileOutcome::Failed { kind: "embedding", message: "the embedder stopped".to_string() });
+                    break;
+                }
+                progress.finish_file(file_path, FileOutcome::Succeeded(summary));
+            },
+            Err(e) => {
+                progress.finish_file(file_path, FileOutcome::failed(&e));
+                record_failure(job, file_path, &e)?;
+                if verbose {
+                    progress.suspend(|| eprintln!("   Error details: {:?}", e));
+                }
+                // Extraction doesn't touch the database, so nothing is retried; what would
+                // fail every later file stops extraction, and what is queued is still stored
+                if error::severity(&e) != Severity::Skip {
+                    extracted = Err(e.context(format!("Indexing stopped at {}", file_path.display())));
+                    break;
+                }
+            }
+        }
+        job.advance(1)?;
     }
+    progress.finish_phase();
     
-    // Extract texts and IDs separately for batch processing
-    let texts: Vec<String> = fragments.iter().map(|(_, content)| content.clone()).collect();
-    let fragment_ids: Vec<String> = fragments.iter().map(|(id, _)| id.clone()).collect();
+    // Hanging up lets the embedder drain the queue and stop
+    drop(documents);
+    if !new_files.is_empty() {
+        println!("⏳ Waiting for the embedder to finish...");
+    }
+    let (storage, embedding_manager, stats) = embedder.await.context("Embedder task panicked")??;
+    extracted?;
     
-    // Generate all embeddings in one batch call to FastEmbed
-    let embeddings = embedding_manager.generate_embeddings_batch(&texts).await
-        .context("Failed to generate batch embeddings")?;
+    println!(
+        "📥 Stored {} documents ({} fragments) and embedded {} fragments; {} were already present",
+        stats.documents, stats.fragments, stats.embedded, stats.skipped,
+    );
+    Ok((storage, embedding_manager))
+}
+
+/// Commit each queued document, replacing the outdated one it comes with, and embed the
+/// fragments waiting for a vector before taking the next
+fn spawn_single_pass_embedder(
+    mut storage: Box<dyn Storage>,
+    mut embedding_manager: EmbeddingManager,
+    mut documents: tokio::sync::mpsc::Receiver<(StagedDocument, Option<String>)>,
+) -> tokio::task::JoinHandle<Result<SinglePassOutcome>> {
+    tokio::spawn(async move {
+        // Vectors of another length would break cosine similarity against those already stored
+        if let Some(dimension) = storage::embedding_dimension(&mut *storage).await? {
+            embedding_manager.expect_dimension(dimension);
+        }
+        
+        let mut stats = SinglePassStats::default();
+        while let Some((document, outdated)) = documents.recv().await {
+            if let Some(outdated) = &outdated {
+                storage.remove_document(outdated).await?;
+            }
+            let committed = ingest_queue::commit_document(&mut *storage, &document).await
+                .with_context(|| format!("Failed to store {}", document.file_path.display()))?;
+            let Some(fragments) = committed else {
+                if outdated.is_some() {
+                    storage::publish(&mut *storage).await?;
+                }
+                stats.skipped += 1;
+                continue;
+            };
+            stats.documents += 1;
+            stats.fragments += fragments;
+            
+            loop {
+                let batch = storage::embed_fragment_batch(&mut *storage, &mut embedding_manager, SINGLE_PASS_BATCH).await
+                    .with_context(|| format!("Failed to embed {}", document.file_path.display()))?;
+                if batch.fragments == 0 {
+                    break;
+                }
+                stats.embedded += batch.fragments as usize;
+            }
+        }
+        
+        Ok((storage, embedding_manager, stats))
+    })
+}
+
+/// Extract documents into the staging queue while a background task commits them to storage.
+///
+/// Segments left by an interrupted run are committed first. Storage is owned by the
+/// committer until it finishes and is then handed back.
+async fn run_staged(
+    mut storage: Box<dyn Storage>,
+    files: &[PathBuf],
+    pipeline: &mut IngestPipeline,
+    staging: &Path,
+    verbose: bool,
+    job: &mut Job,
+    progress: &mut Progress,
+) -> Result<Box<dyn Storage>> {
+    // Skip known documents up front; the committer owns storage from here on
+    progress.start_phase("stage", "Staging", files.len() as u64);
+    let mut new_files = Vec::new();
+    for file_path in files {
+        if storage.document_exists(file_path).await? {
+            progress.finish_file(file_path, FileOutcome::Skipped("already indexed".to_string()));
+        } else {
+            new_files.push(file_path);
+        }
+    }
     
-    if embeddings.len() != fragment_ids.len() {
-        anyhow::bail!("Embedding count mismatch: expected {}, got {}", fragment_ids.len(), embeddings.len());
+    let (mut queue, segments, recovered) = IngestQueue::open(staging)?;
+    if recovered > 0 {
+        progress.println(format!("♻️  Committing {} staged segment(s) left by an interrupted run", recovered));
     }
+    let committer = ingest_queue::spawn_committer(storage, segments);
     
-    // Store all embeddings in the database
-    for (fragment_id, embedding) in fragment_ids.iter().zip(embeddings.iter()) {
-        if embedding.is_empty() {
-            continue;
+    let mut staged = Ok(());
+    for (i, file_path) in new_files.iter().enumerate() {
+        if job.is_cancelled() {
+            progress.println(format!("⏹️  Cancelled after staging {} of {} documents", i, new_files.len()));
+            break;
         }
         
-        storage.update_fragment_embedding(fragment_id, embedding).await
-            .with_context(|| format!("Failed to update embedding for fragment {}", fragment_id))?;
+        progress.start_file(file_path);
+        let mut stages = StageTimings::default();
+        let prepared = pipeline.prepare(file_path, &mut stages).await;
+        match prepared {
+            Ok(document) => {
+                let started = std::time::Instant::now();
+                let staged_document = queue.stage(&document);
+                StageTimings::lap(&mut stages.store, started);
+                progress.record_stages(&stages);
+                if let Err(e) = staged_document {
+                    progress.finish_file(file_path, FileOutcome::failed(&e));
+                    staged = Err(e);
+                    break;
+                }
+                progress.finish_file(file_path, FileOutcome::Succeeded(format!("staged, {}", describe_document(&document))));
+            },
+            Err(e) => {
+                progress.record_stages(&stages);
+                progress.finish_file(file_path, FileOutcome::failed(&e));
+                record_failure(job, file_path, &e)?;
+                if verbose {
+                    progress.suspend(|| eprintln!("   Error details: {:?}", e));
+                }
+                // Staging never touches the database, so nothing is retried; what would
+                // fail every later file stops staging, and what is staged is still committed
+                if error::severity(&e) != Severity::Skip {
+                    staged = Err(e.context(format!("Staging stopped at {}", file_path.display())));
+                    break;
+                }
+            }
+        }
+        job.advance(1)?;
     }
+    progress.finish_phase();
     
-    Ok(fragments.len() as i32)
-}
\ No newline at end of file
+    // Closing the queue lets the committer drain the remaining segments and stop
+    let finished = staged.and_then(|()| queue.finish());
+    if !new_files.is_empty() || recovered > 0 {
+        println!("⏳ Waiting for background commit to finish...");
+    }
+    let (storage, stats) = committer.await.context("Committer task panicked")??;
+    finished?;
+    
+    println!("📥 Committed {} documents ({} fragments); {} were already present", stats.documents, stats.fragments, stats.skipped);
+    // Only succeeds once every segment has been committed and removed
+    let _ = std::fs::remove_dir(staging);
+    
+    Ok(storage)
+}
@@ -0,0 +1,279 @@
+use anyhow::{Context, Result};
+use serde_json::{json, Value};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+use crate::embedding_manager::EmbeddingManager;
+use crate::hybrid::SearchMode;
+use crate::retrieval;
+use crate::storage::{self, SearchFilter, Storage};
+
+/// Protocol revisions the server speaks, newest first
+const PROTOCOL_VERSIONS: &[&str] = &["2025-06-18", "2025-03-26", "2024-11-05"];
+/// Most fragments one `search_brain` call can return
+const MAX_TOP_K: usize = 50;
+/// Fragments read from storage at a time while assembling a document's text
+const TEXT_PAGE_FRAGMENTS: i32 = 100;
+/// Longest document text `get_document` returns, so one call can't flood the agent's context
+const MAX_DOCUMENT_CHARS: usize = 200_000;
+
+// JSON-RPC error codes
+const PARSE_ERROR: i64 = -32700;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+
+/// A Model Context Protocol server over one database, answering JSON-RPC messages read
+/// one per line from stdin on stdout. Agents such as Claude Desktop or Cursor launch it
+/// as a subprocess and call its tools to search the brain.
+pub struct McpServer {
+    storage: Box<dyn Storage>,
+    embedding_manager: EmbeddingManager,
+}
+
+impl McpServer {
+    pub fn new(storage: Box<dyn Storage>, embedding_manager: EmbeddingManager) -> Self {
+        Self { storage, embedding_manager }
+    }
+
+    /// Serve until stdin closes. Nothing else may write to stdout meanwhile; logs go to stderr.
+    pub async fn run(mut self) -> Result<()> {
+        let mut lines = BufReader::new(tokio::io::stdin()).lines();
+        let mut stdout = tokio::io::stdout();
+        while let Some(line) = lines.next_line().await.context("Failed to read from stdin")? {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let response = match serde_json::from_str::<Value>(&line) {
+                Ok(message) => self.handle(message).await,
+                Err(e) => Some(error(Value::Null, PARSE_ERROR, &e.to_string())),
+            };
+            if let Some(response) = response {
+                let mut line = serde_json::to_vec(&response)?;
+                line.push(b'\n');
+                stdout.write_all(&line).await.context("Failed to write to stdout")?;
+                stdout.flush().await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Answer one message; notifications, which carry no id, get no answer
+    pub async fn handle(&mut self, message: Value) -> Option<Value> {
+        let id = message.get("id").cloned()?;
+        let params = message.get("params").cloned().unwrap_or(Value::Null);
+        let result = match message["method"].as_str().unwrap_or_default() {
+            "initialize" => initialize(&params),
+            "ping" => json!({}),
+            "tools/list" => json!({ "tools": tools() }),
+            "tools/call" => {
+                let name = params["name"].as_str().unwrap_or_default();
+                if !tools().iter().any(|tool| tool["name"] == name) {
+                    return Some(error(id, INVALID_PARAMS, &format!("Unknown tool: {}", name)));
+                }
+                // Failures are reported to the agent as the tool's output, so it can react
+                let (text, is_error) = match self.call_tool(name, &params["arguments"]).await {
+                    Ok(text) => (text, false),
+                    Err(e) => (format!("{:#}", e), true),
+                };
+                json!({ "content": [{ "type": "text", "text": text }], "isError": is_error })
+            }
+            method => return Some(error(id, METHOD_NOT_FOUND, &format!("Unknown method: {}", method))),
+        };
+        Some(json!({ "jsonrpc": "2.0", "id": id, "result": result }))
+    }
+
+    async fn call_tool(&mut self, name: &str, arguments: &Value) -> Result<String> {
+        match name {
+            "search_brain" => {
+                let query = arguments["query"].as_str().context("query is required")?;
+                let top_k = arguments["top_k"].as_u64().map_or(5, |k| k as usize).clamp(1, MAX_TOP_K);
+                let mode = match arguments["mode"].as_str() {
+                    None | Some("vector") => SearchMode::Vector,
+                    Some("hybrid") => SearchMode::Hybrid,
+                    Some("keyword") => SearchMode::Keyword,
+                    Some(other) => anyhow::bail!("Unknown search mode '{}'; use vector, hybrid or keyword", other),
+                };
+                self.search(query, top_k, mode).await
+            }
+            "get_document" => {
+                let id = arguments["id"].as_str().context("id is required")?;
+                self.document_text(id).await
+            }
+            "list_documents" => self.list_documents().await,
+            _ => unreachable!("tools are checked before they are called"),
+        }
+    }
+
+    async fn search(&mut self, text: &str, top_k: usize, mode: SearchMode) -> Result<String> {
+        let (query, section) = SearchFilter::parse_section(text);
+        anyhow::ensure!(!query.trim().is_empty(), "query is empty");
+        let space = storage::query_space(&mut *self.storage, self.embedding_manager.model_name()).await?;
+        let filter = SearchFilter { stale: space.stale, section, ..Default::default() };
+        let page = retrieval::search_page(&mut *self.storage, &mut self.embedding_manager, &query, top_k, &filter, mode, None).await?;
+
+        let mut out = String::new();
+        if let Some(warning) = space.warning {
+            out.push_str(&format!("Warning: {}\n\n", warning));
+        }
+        if page.hits.is_empty() {
+            out.push_str("No matching fragments found.");
+        }
+        for (i, hit) in page.hits.iter().enumerate() {
+            out.push_str(&format!(
+                "{}. {} (score {:.3}, path {})\n{}\n\n",
+                i + 1, hit.source.citation(), hit.score, hit.source.file_path, hit.content.trim(),
+            ));
+        }
+        Ok(out.trim_end().to_string())
+    }
+
+    async fn document_text(&mut self, name_or_id: &str) -> Result<String> {
+        let document = storage::find_document(&mut *self.storage, name_or_id).await?;
+        let mut text = format!("# {} ({})\n\n", document.filename, document.file_path);
+        let mut offset = 0;
+        loop {
+            let page = self.storage.get_document_fragments(&document.id, offset, TEXT_PAGE_FRAGMENTS).await?;
+            for fragment in &page {
+                text.push_str(fragment);
+                text.push_str("\n\n");
+            }
+            if page.len() < TEXT_PAGE_FRAGMENTS as usize || text.len() > MAX_DOCUMENT_CHARS {
+                break;
+            }
+            offset += TEXT_PAGE_FRAGMENTS;
+        }
+
+        if let Some((cut, _)) = text.char_indices().nth(MAX_DOCUMENT_CHARS) {
+            text.truncate(cut);
+            text.push_str("\n\n[Truncated; use search_brain to find the rest]");
+        }
+        Ok(text.trim_end().to_string())
+    }
+
+    async fn list_documents(&mut self) -> Result<String> {
+        let documents = self.storage.list_documents().await?;
+        if documents.is_empty() {
+            return Ok("The database has no documents.".to_string());
+        }
+        Ok(documents.iter()
+            .map(|document| {
+                let gone = if document.tombstoned { ", source file gone" } else { "" };
+                format!("{}  {}  ({} fragments{})", document.id, document.file_path, document.fragments, gone)
+            })
+            .collect::<Vec<_>>()
+            .join("\n"))
+    }
+}
+
+fn error(id: Value, code: i64, message: &str) -> Value {
+    json!({ "jsonrpc": "2.0", "id": id, "error": { "code": code, "message": message } })
+}
+
+/// Agree on the client's protocol revision when it is one the server speaks, otherwise
+/// offer the newest
+fn initialize(params: &Value) -> Value {
+    let requested = params["protocolVersion"].as_str().unwrap_or_default();
+    let version = PROTOCOL_VERSIONS.iter().find(|&&version| version == requested).unwrap_or(&PROTOCOL_VERSIONS[0]);
+    json!({
+        "protocolVersion": version,
+        "capabilities": { "tools": {} },
+        "serverInfo": { "name": "portable-brains", "version": env!("CARGO_PKG_VERSION") },
+        "instructions": "Search a Portable Brains document database. Use search_brain to find passages, \
+                         cite them by the file and page given, and get_document to read a whole document.",
+    })
+}
+
+fn tools() -> Vec<Value> {
+    vec![
+        json!({
+            "name": "search_brain",
+            "description": "Search the indexed documents for passages relevant to a query. Returns the best \
+                            matching fragments with their source file, page and section.",
+            "inputSchema": {
+                "type": "object",
+                "properties": {
+                    "query": { "type": "string", "description": "What to search for; may contain section:\"Heading\" to search one section" },
+                    "top_k": { "type": "integer", "description": "Number of fragments to return", "default": 5, "minimum": 1, "maximum": MAX_TOP_K },
+                    "mode": { "type": "string", "enum": ["vector", "hybrid", "keyword"], "description": "vector (meaning), keyword (exact terms such as names and codes) or hybrid (both)", "default": "vector" },
+                },
+                "required": ["query"],
+            },
+        }),
+        json!({
+            "name": "get_document",
+            "description": "Read the extracted text of one document, by the id from list_documents or its file name or path.",
+            "inputSchema": {
+                "type": "object",
+                "properties": {
+                    "id": { "type": "string", "description": "Document id, file name or path" },
+                },
+                "required": ["id"],
+            },
+        }),
+        json!({
+            "name": "list_documents",
+            "description": "List the indexed documents with their ids, paths and fragment counts.",
+            "inputSchema": { "type": "object", "properties": {} },
+        }),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lancedb_storage::LanceDBStorage;
+    use crate::storage::FragmentMeta;
+    use std::path::Path;
+
+    async fn server() -> McpServer {
+        let mut storage = LanceDBStorage::new(Path::new("mcp")).await.unwrap();
+        let mut embedding_manager = EmbeddingManager::hashing(32);
+        storage.verify_or_set_model(embedding_manager.model_name(), Some(32)).await.unwrap();
+        for (path, text) in [("handbook.txt", "Staff get 25 vacation days"), ("expenses.txt", "Receipts are due monthly")] {
+            let document = storage.store_document(Path::new(path), text.as_bytes()).await.unwrap();
+            let id = storage.store_text_fragment(&document, 0, text, &FragmentMeta::default()).await.unwrap();
+            let embedding = embedding_manager.generate_embedding(text).await.unwrap();
+            storage.update_fragment_embedding(&id, &embedding).await.unwrap();
+        }
+        McpServer::new(Box::new(storage), embedding_manager)
+    }
+
+    fn call(id: u64, name: &str, arguments: Value) -> Value {
+        json!({ "jsonrpc": "2.0", "id": id, "method": "tools/call", "params": { "name": name, "arguments": arguments } })
+    }
+
+    #[tokio::test]
+    async fn test_handshake_and_tool_list() {
+        let mut server = server().await;
+        let response = server.handle(json!({
+            "jsonrpc": "2.0", "id": 1, "method": "initialize",
+            "params": { "protocolVersion": "2024-11-05", "capabilities": {}, "clientInfo": { "name": "test" } },
+        })).await.unwrap();
+        assert_eq!(response["result"]["protocolVersion"], "2024-11-05");
+        assert!(server.handle(json!({ "jsonrpc": "2.0", "method": "notifications/initialized" })).await.is_none());
+
+        let response = server.handle(json!({ "jsonrpc": "2.0", "id": 2, "method": "tools/list" })).await.unwrap();
+        let names: Vec<&str> = response["result"]["tools"].as_array().unwrap().iter().map(|tool| tool["name"].as_str().unwrap()).collect();
+        assert_eq!(names, vec!["search_brain", "get_document", "list_documents"]);
+
+        let response = server.handle(json!({ "jsonrpc": "2.0", "id": 3, "method": "resources/list" })).await.unwrap();
+        assert_eq!(response["error"]["code"], METHOD_NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_tools_read_the_brain() {
+        let mut server = server().await;
+        let response = server.handle(call(1, "search_brain", json!({ "query": "vacation days", "top_k": 1, "mode": "keyword" }))).await.unwrap();
+        let text = response["result"]["content"][0]["text"].as_str().unwrap();
+        assert!(text.starts_with("1. handbook.txt") && text.contains("25 vacation days"), "{}", text);
+
+        let response = server.handle(call(2, "get_document", json!({ "id": "expenses.txt" }))).await.unwrap();
+        assert!(response["result"]["content"][0]["text"].as_str().unwrap().contains("Receipts are due monthly"));
+
+        let response = server.handle(call(3, "list_documents", json!({}))).await.unwrap();
+        assert_eq!(response["result"]["content"][0]["text"].as_str().unwrap().lines().count(), 2);
+
+        // Tool failures are the tool's output, not protocol errors
+        let response = server.handle(call(4, "get_document", json!({ "id": "missing.pdf" }))).await.unwrap();
+        assert_eq!(response["result"]["isError"], true);
+    }
+}
@@ -0,0 +1,446 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::annotations::{Annotation, RANKING_KEY};
+use crate::document_processor::CHUNKING_KEY;
+use crate::duckdb_storage::{ParquetReader, ParquetWriter};
+use crate::presets::PRESET_KEY;
+use crate::storage::arrow::array::{Array, RecordBatch, StringArray};
+use crate::storage::{
+    conform_fragment_batch, fragment_column, mark_embedded_documents, parse_dimension, DocumentAttributes, DocumentSummary,
+    IdScheme, IndexState, OriginalsMode, SimilarityMetric, Storage, DETERMINISTIC_IDS_KEY, DIMENSION_KEY, DOCUMENT_PREFIX_KEY, FRAGMENT_BATCH_SIZE,
+    INDEXER_VERSION_KEY, LATE_CHUNKING_KEY, MODEL_LINEAGE_KEY, PREVIOUS_MODEL_KEY, PROVIDER_KEY, QUERY_PREFIX_KEY,
+    SIMILARITY_METRIC_KEY, STORE_ORIGINALS_KEY,
+};
+
+/// Version of the bundle layout, bumped when an older `import` could no longer read it
+pub const BUNDLE_FORMAT: u32 = 1;
+
+/// The bundle's manifest: meta values and every document's attributes
+pub const BUNDLE_MANIFEST: &str = "brain.json";
+
+/// Every fragment with its vector, in `fragment_schema`'s columns
+const BUNDLE_FRAGMENTS: &str = "fragments.parquet";
+
+/// Original files, named by their document's position in the manifest
+const BUNDLE_ORIGINALS: &str = "originals";
+
+/// Meta values describing the brain itself rather than how one backend lays it out, so
+/// they are carried over to the copy. Schema versions, layouts and vector indexes belong
+/// to the database they were recorded in.
+const PORTABLE_META_KEYS: &[&str] = &[
+    "embedding_model",
+    PREVIOUS_MODEL_KEY,
+    DIMENSION_KEY,
+    PROVIDER_KEY,
+    MODEL_LINEAGE_KEY,
+    DETERMINISTIC_IDS_KEY,
+    INDEXER_VERSION_KEY,
+    DOCUMENT_PREFIX_KEY,
+    QUERY_PREFIX_KEY,
+    PRESET_KEY,
+    CHUNKING_KEY,
+    LATE_CHUNKING_KEY,
+    STORE_ORIGINALS_KEY,
+    SIMILARITY_METRIC_KEY,
+    RANKING_KEY,
+];
+
+/// A document as carried between backends, without its bytes
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct DocumentRecord {
+    pub id: String,
+    pub file_path: String,
+    pub content_hash: Option<String>,
+    pub modified: Option<i64>,
+    pub tombstoned: bool,
+    pub quality_score: Option<f64>,
+    #[serde(default)]
+    pub quality_flags: Vec<String>,
+    #[serde(default)]
+    pub title: Option<String>,
+    #[serde(default)]
+    pub attributes: DocumentAttributes,
+    /// The compressed extracted text was saved too, so the copy can be re-chunked
+    #[serde(default)]
+    pub has_text: bool,
+    /// Indexed with `--store-originals none`, so there are no original bytes to carry
+    #[serde(default)]
+    pub without_original: bool,
+}
+
+/// `brain.json` in a bundle directory
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BundleManifest {
+    pub format: u32,
+    pub meta: BTreeMap<String, String>,
+    pub documents: Vec<DocumentRecord>,
+    /// Reviewers' annotations, targeting the source's document and fragment ids
+    #[serde(default)]
+    pub annotations: Vec<Annotation>,
+}
+
+/// What a copy carried over
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct CopyStats {
+    pub documents: usize,
+    pub fragments: usize,
+}
+
+/// The portable meta values recorded in `storage`
+async fn read_meta(storage: &mut dyn Storage) -> Result<BTreeMap<String, String>> {
+    let mut meta = BTreeMap::new();
+    for key in PORTABLE_META_KEYS {
+        if let Some(value) = storage.get_meta_value(key).await? {
+            meta.insert(key.to_string(), value);
+        }
+    }
+    Ok(meta)
+}
+
+/// Record `meta` in an empty target, checking the model the way indexing would first so
+/// the target's own schema version is set too
+async fn write_meta(target: &mut dyn Storage, meta: &BTreeMap<String, String>) -> Result<()> {
+    if !target.list_documents().await?.is_empty() {
+        anyhow::bail!("The target database already has documents; copy into a new database");
+    }
+    if let Some(model) = meta.get("embedding_model") {
+        let dimension = parse_dimension(meta.get(DIMENSION_KEY).map(String::as_str));
+        target.verify_or_set_model(model, dimension).await
+            .context("The target database was indexed with another model")?;
+    }
+    for (key, value) in meta {
+        target.set_meta_value(key, value).await?;
+    }
+    // New documents need the source's ids scheme, not just its meta value
+    if IdScheme::from_meta(meta.get(DETERMINISTIC_IDS_KEY).map(String::as_str)) == IdScheme::Content {
+        target.enable_deterministic_ids().await?;
+    }
+    let originals = OriginalsMode::from_meta(meta.get(STORE_ORIGINALS_KEY).map(String::as_str));
+    if originals != OriginalsMode::Full {
+        target.set_originals_mode(originals).await?;
+    }
+    let metric = SimilarityMetric::from_meta(meta.get(SIMILARITY_METRIC_KEY).map(String::as_str));
+    if metric != SimilarityMetric::Cosine {
+        target.set_similarity_metric(metric).await?;
+    }
+    Ok(())
+}
+
+async fn document_record(source: &mut dyn Storage, summary: &DocumentSummary, has_text: bool, has_original: bool) -> Result<DocumentRecord> {
+    Ok(DocumentRecord {
+        id: summary.id.clone(),
+        file_path: summary.file_path.clone(),
+        content_hash: summary.content_hash.clone(),
+        modified: summary.modified,
+        tombstoned: summary.tombstoned,
+        quality_score: summary.quality_score,
+        quality_flags: summary.quality_flags.clone(),
+        title: summary.title.clone(),
+        attributes: source.get_document_attributes(&summary.id).await?.unwrap_or_default(),
+        has_text,
+        without_original: !has_original,
+    })
+}
+
+/// Store a document in `target` with everything recorded about it, returning its id there.
+/// A document without its original is stored as the target stores documents indexed with
+/// `--store-originals none`.
+async fn restore_document(target: &mut dyn Storage, record: &DocumentRecord, data: Option<&[u8]>, text: Option<&[u8]>) -> Result<String> {
+    let id = match data {
+        Some(data) => target.store_document(Path::new(&record.file_path), data).await,
+        None => {
+            let mode = OriginalsMode::from_meta(target.get_meta_value(STORE_ORIGINALS_KEY).await?.as_deref());
+            target.set_originals_mode(OriginalsMode::None).await?;
+            // Nothing of the stand-in is kept; it only keeps content-derived ids apart
+            let stand_in = record.content_hash.clone().unwrap_or_else(|| record.file_path.clone());
+            let id = target.store_document(Path::new(&record.file_path), stand_in.as_bytes()).await;
+            target.set_originals_mode(mode).await?;
+            id
+        }
+    }.with_context(|| format!("Failed to store {}", record.file_path))?;
+    if let Some(hash) = &record.content_hash {
+        target.set_document_source(&id, hash, record.modified).await?;
+    }
+    let attributes = &record.attributes;
+    if attributes.priority != 0 {
+        target.set_document_priority(&id, attributes.priority).await?;
+    }
+    if let Some((category, score)) = &attributes.category {
+        target.set_document_category(&id, category, *score).await?;
+    }
+    if attributes.collection.is_some() || !attributes.tags.is_empty() {
+        target.set_document_collection(&id, attributes.collection.as_deref(), &attributes.tags).await?;
+    }
+    if let Some(score) = record.quality_score {
+        target.set_document_quality(&id, score, &record.quality_flags).await?;
+    }
+    if let Some(title) = &record.title {
+        target.set_document_title(&id, title).await?;
+    }
+    if record.tombstoned {
+        target.tombstone_document(&id, true).await?;
+    }
+    if let Some(text) = text {
+        target.set_document_text(&id, text).await?;
+    }
+    Ok(id)
+}
+
+/// Point a batch's fragments at their documents' ids in the target. Fragment ids are kept,
+/// so citations saved against the source still resolve.
+fn remap_documents(batch: &RecordBatch, ids: &HashMap<String, String>) -> Result<RecordBatch> {
+    let batch = conform_fragment_batch(batch)?;
+    let document_ids = fragment_column::<StringArray>(&batch, "document_id")?;
+    let remapped = (0..batch.num_rows())
+        .map(|row| {
+            let id = document_ids.value(row);
+            ids.get(id).cloned().ok_or_else(|| anyhow::anyhow!("Fragment of unknown document {}", id))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut columns = batch.columns().to_vec();
+    columns[1] = Arc::new(StringArray::from(remapped));
+    Ok(RecordBatch::try_new(batch.schema(), columns)?)
+}
+
+/// Add `annotations` to `target`, pointing those on documents at the documents' ids in the
+/// target; fragment ids are kept, so annotations on fragments need no change
+async fn restore_annotations(target: &mut dyn Storage, annotations: &[Annotation], ids: &HashMap<String, String>) -> Result<()> {
+    for annotation in annotations {
+        let target_id = ids.get(&annotation.target).unwrap_or(&annotation.target);
+        target.add_annotation(&Annotation { target: target_id.clone(), ..annotation.clone() }).await
+            .with_context(|| format!("Failed to copy annotation {}", annotation.id))?;
+    }
+    Ok(())
+}
+
+/// Mark the copied documents as having all their fragments, and those with every vector
+/// as embedded, once the fragments are written
+async fn finish_documents(target: &mut dyn Storage, ids: &HashMap<String, String>) -> Result<()> {
+    for id in ids.values() {
+        target.set_index_state(id, IndexState::Chunked).await?;
+    }
+    mark_embedded_documents(target).await?;
+    Ok(())
+}
+
+/// The id of a batch's last fragment, where the next batch read starts after
+fn last_id(batch: &RecordBatch) -> Result<String> {
+    let ids = fragment_column::<StringArray>(batch, "id")?;
+    Ok(ids.value(ids.len() - 1).to_string())
+}
+
+/// Copy every document, fragment and vector from `source` into the empty `target`, which
+/// may use another backend. Vectors are copied as they are, so nothing is re-embedded.
+pub async fn copy_brain(source: &mut dyn Storage, target: &mut dyn Storage) -> Result<CopyStats> {
+    write_meta(target, &read_meta(source).await?).await?;
+
+    let mut stats = CopyStats::default();
+    let mut ids = HashMap::new();
+    for summary in source.list_documents().await? {
+        let document = source.get_document(&summary.id).await?
+            .with_context(|| format!("Document {} disappeared during the copy", summary.id))?;
+        let text = source.get_document_text(&summary.id).await?;
+        let record = document_record(source, &summary, text.is_some(), document.file_data.is_some()).await?;
+        let id = restore_document(target, &record, document.file_data.as_deref(), text.as_deref()).await?;
+        ids.insert(summary.id, id);
+        stats.documents += 1;
+    }
+
+    let mut after: Option<String> = None;
+    while let Some(batch) = source.read_fragment_batch(after.as_deref(), FRAGMENT_BATCH_SIZE).await? {
+        after = Some(last_id(&batch)?);
+        stats.fragments += target.write_fragment_batch(&remap_documents(&batch, &ids)?).await?;
+    }
+    finish_documents(target, &ids).await?;
+    restore_annotations(target, &source.list_annotations(None).await?, &ids).await?;
+    Ok(stats)
+}
+
+/// Write `source` to a bundle directory: `brain.json` with the meta values and documents,
+/// their original files and saved text under `originals/`, and the fragments with their
+/// vectors in `fragments.parquet`
+pub async fn export_bundle(source: &mut dyn Storage, output: &Path) -> Result<CopyStats> {
+    let originals = output.join(BUNDLE_ORIGINALS);
+    std::fs::create_dir_all(&originals)
+        .with_context(|| format!("Failed to create {}", originals.display()))?;
+
+    let mut stats = CopyStats::default();
+    let mut documents = Vec::new();
+    for (i, summary) in source.list_documents().await?.iter().enumerate() {
+        let document = source.get_document(&summary.id).await?
+            .with_context(|| format!("Document {} disappeared during the export", summary.id))?;
+        let text = source.get_document_text(&summary.id).await?;
+        if let Some(data) = &document.file_data {
+            std::fs::write(originals.join(i.to_string()), data)?;
+        }
+        if let Some(text) = &text {
+            std::fs::write(originals.join(format!("{}.text", i)), text)?;
+        }
+        documents.push(document_record(source, summary, text.is_some(), document.file_data.is_some()).await?);
+        stats.documents += 1;
+    }
+
+    let mut writer = ParquetWriter::new()?;
+    let mut after: Option<String> = None;
+    while let Some(batch) = source.read_fragment_batch(after.as_deref(), FRAGMENT_BATCH_SIZE).await? {
+        after = Some(last_id(&batch)?);
+        writer.append(&batch)?;
+    }
+    stats.fragments = writer.finish(&output.join(BUNDLE_FRAGMENTS))?;
+
+    let manifest = BundleManifest {
+        format: BUNDLE_FORMAT,
+        meta: read_meta(source).await?,
+        documents,
+        annotations: source.list_annotations(None).await?,
+    };
+    let path = output.join(BUNDLE_MANIFEST);
+    std::fs::write(&path, serde_json::to_string_pretty(&manifest)?)
+        .with_context(|| format!("Failed to write {}", path.display()))?;
+    Ok(stats)
+}
+
+/// Load a bundle written by `export_bundle` into the empty `target`
+pub async fn import_bundle(input: &Path, target: &mut dyn Storage) -> Result<CopyStats> {
+    let path = input.join(BUNDLE_MANIFEST);
+    let manifest: BundleManifest = serde_json::from_str(
+        &std::fs::read_to_string(&path).with_context(|| format!("Failed to read {}", path.display()))?,
+    ).with_context(|| format!("Failed to parse {}", path.display()))?;
+    if manifest.format > BUNDLE_FORMAT {
+        anyhow::bail!("{} is bundle format {}; this version reads up to {}", input.display(), manifest.format, BUNDLE_FORMAT);
+    }
+    write_meta(target, &manifest.meta).await?;
+
+    let originals = input.join(BUNDLE_ORIGINALS);
+    let mut stats = CopyStats::default();
+    let mut ids = HashMap::new();
+    for (i, record) in manifest.documents.iter().enumerate() {
+        let data = if record.without_original {
+            None
+        } else {
+            Some(std::fs::read(originals.join(i.to_string()))
+                .with_context(|| format!("Failed to read the original of {}", record.file_path))?)
+        };
+        let text = if record.has_text {
+            Some(std::fs::read(originals.join(format!("{}.text", i)))
+                .with_context(|| format!("Failed to read the saved text of {}", record.file_path))?)
+        } else {
+            None
+        };
+        let id = restore_document(target, record, data.as_deref(), text.as_deref()).await?;
+        ids.insert(record.id.clone(), id);
+        stats.documents += 1;
+    }
+
+    let reader = ParquetReader::open(&input.join(BUNDLE_FRAGMENTS))?;
+    let mut after: Option<String> = None;
+    while let Some(batch) = reader.read_batch(after.as_deref(), FRAGMENT_BATCH_SIZE)? {
+        after = Some(last_id(&batch)?);
+        stats.fragments += target.write_fragment_batch(&remap_documents(&batch, &ids)?).await?;
+    }
+    finish_documents(target, &ids).await?;
+    restore_annotations(target, &manifest.annotations, &ids).await?;
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::annotations::AnnotationLabel;
+    use crate::lancedb_storage::LanceDBStorage;
+    use crate::storage::FragmentMeta;
+
+    async fn source() -> (LanceDBStorage, String) {
+        let mut storage = LanceDBStorage::new(Path::new("migrate-source")).await.unwrap();
+        storage.verify_or_set_model("portable-brains/hashing-4", Some(2)).await.unwrap();
+        storage.set_meta_value(PRESET_KEY, "legal").await.unwrap();
+        let document = storage.store_document(Path::new("contracts/lease.txt"), b"lease").await.unwrap();
+        storage.set_document_priority(&document, 3).await.unwrap();
+        storage.set_document_category(&document, "contract", 0.9).await.unwrap();
+        storage.set_document_collection(&document, Some("legal"), &["signed".to_string()]).await.unwrap();
+        storage.set_document_text(&document, b"compressed").await.unwrap();
+        let meta = FragmentMeta { section: Some("Term".to_string()), ..FragmentMeta::default() };
+        let first = storage.store_text_fragment(&document, 0, "The lease runs five years", &meta).await.unwrap();
+        storage.update_fragment_embedding(&first, &[0.5, 0.25]).await.unwrap();
+        storage.store_text_fragment(&document, 1, "Rent is due monthly", &FragmentMeta::default()).await.unwrap();
+        (storage, document)
+    }
+
+    #[tokio::test]
+    async fn test_copy_keeps_documents_fragments_and_vectors() {
+        let (mut source, document) = source().await;
+        let mut target = LanceDBStorage::new(Path::new("migrate-target")).await.unwrap();
+
+        let stats = copy_brain(&mut source, &mut target).await.unwrap();
+        assert_eq!(stats, CopyStats { documents: 1, fragments: 2 });
+        assert_eq!(target.get_meta_value(PRESET_KEY).await.unwrap().as_deref(), Some("legal"));
+        assert_eq!(target.get_meta_info().await.unwrap().embedding_model, "portable-brains/hashing-4");
+
+        // Document ids are the target's own; fragment ids and vectors are carried over
+        let copy = target.list_documents().await.unwrap().remove(0);
+        assert_eq!(copy.file_path, source.list_documents().await.unwrap()[0].file_path);
+        let attributes = target.get_document_attributes(&copy.id).await.unwrap().unwrap();
+        assert_eq!(attributes, source.get_document_attributes(&document).await.unwrap().unwrap());
+        assert_eq!(target.get_document_text(&copy.id).await.unwrap().as_deref(), Some(&b"compressed"[..]));
+
+        let original = serde_json::to_value(source.get_fragment_records(&document).await.unwrap()).unwrap();
+        let copied = serde_json::to_value(target.get_fragment_records(&copy.id).await.unwrap()).unwrap();
+        assert_eq!(copied, original);
+        assert_eq!(target.count_fragments_without_embeddings().await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_copy_keeps_annotations() {
+        let (mut source, document) = source().await;
+        let fragment = source.get_fragment_records(&document).await.unwrap().remove(0).id;
+        source.add_annotation(&Annotation::new(&document, Some(AnnotationLabel::Outdated), None, "alice")).await.unwrap();
+        source.add_annotation(&Annotation::new(&fragment, None, Some("check the renewal clause".to_string()), "bob")).await.unwrap();
+        let mut target = LanceDBStorage::new(Path::new("migrate-annotations")).await.unwrap();
+
+        copy_brain(&mut source, &mut target).await.unwrap();
+        let copy = target.list_documents().await.unwrap().remove(0);
+        let on_document = target.list_annotations(Some(&copy.id)).await.unwrap();
+        assert_eq!(on_document[0].label, Some(AnnotationLabel::Outdated));
+        let on_fragment = target.list_annotations(Some(&fragment)).await.unwrap();
+        assert_eq!(on_fragment[0].note.as_deref(), Some("check the renewal clause"));
+    }
+
+    #[tokio::test]
+    async fn test_copy_refuses_a_target_with_documents() {
+        let (mut source, _) = source().await;
+        let mut target = LanceDBStorage::new(Path::new("migrate-busy")).await.unwrap();
+        target.store_document(Path::new("notes.txt"), b"notes").await.unwrap();
+        assert!(copy_brain(&mut source, &mut target).await.is_err());
+    }
+
+    #[test]
+    fn test_remap_rejects_unknown_documents() {
+        use crate::storage::arrow::array::{new_null_array, ArrayRef, BooleanArray, Int32Array};
+        use crate::storage::fragment_schema;
+
+        let schema = fragment_schema();
+        let columns: Vec<ArrayRef> = vec![
+            Arc::new(StringArray::from(vec!["f1"])),
+            Arc::new(StringArray::from(vec!["old"])),
+            Arc::new(Int32Array::from(vec![0])),
+            Arc::new(Int32Array::from(vec![0])),
+            new_null_array(schema.field(4).data_type(), 1),
+            new_null_array(schema.field(5).data_type(), 1),
+            new_null_array(schema.field(6).data_type(), 1),
+            Arc::new(StringArray::from(vec!["text"])),
+            new_null_array(schema.field(8).data_type(), 1),
+            Arc::new(BooleanArray::from(vec![false])),
+        ];
+        let batch = RecordBatch::try_new(schema, columns).unwrap();
+
+        let ids = HashMap::from([("old".to_string(), "new".to_string())]);
+        let remapped = remap_documents(&batch, &ids).unwrap();
+        assert_eq!(fragment_column::<StringArray>(&remapped, "document_id").unwrap().value(0), "new");
+        assert!(remap_documents(&batch, &HashMap::new()).is_err());
+    }
+}
@@ -0,0 +1,197 @@
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use crate::offline;
+
+/// Environment variable giving a mirror to download local models from, like `--model-mirror`
+pub const MODEL_MIRROR_VAR: &str = "PORTABLE_BRAINS_MODEL_MIRROR";
+
+/// Environment variable naming a checksum file for local models, like `--model-checksums`
+pub const MODEL_CHECKSUMS_VAR: &str = "PORTABLE_BRAINS_MODEL_CHECKSUMS";
+
+/// Files FastEmbed reads a model's tokenizer from, next to its ONNX files
+const TOKENIZER_FILES: &[&str] = &["tokenizer.json", "config.json", "special_tokens_map.json", "tokenizer_config.json"];
+
+static PINS: OnceLock<ModelPins> = OnceLock::new();
+
+/// A checksum file, e.g.
+///
+/// ```toml
+/// [models."BAAI/bge-small-en-v1.5"]
+/// "model_optimized.onnx" = "5f3a…"
+/// "tokenizer.json" = "d241…"
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ChecksumFile {
+    #[serde(default)]
+    models: BTreeMap<String, BTreeMap<String, String>>,
+}
+
+/// Expected SHA-256 of each file of each local model, by the model's name or its repo
+#[derive(Debug, Default)]
+pub struct ModelPins {
+    source: PathBuf,
+    models: BTreeMap<String, BTreeMap<String, String>>,
+}
+
+impl ModelPins {
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read model checksums {}", path.display()))?;
+        Self::parse(path, &text).with_context(|| format!("Invalid model checksums {}", path.display()))
+    }
+
+    fn parse(path: &Path, text: &str) -> Result<Self> {
+        let file: ChecksumFile = toml::from_str(text)?;
+        let mut models = BTreeMap::new();
+        for (model, files) in file.models {
+            let mut checksums = BTreeMap::new();
+            for (file, checksum) in files {
+                let checksum = checksum.trim().to_lowercase();
+                if checksum.len() != 64 || !checksum.chars().all(|c| c.is_ascii_hexdigit()) {
+                    anyhow::bail!("The checksum of {} in {} is not a SHA-256 hex digest", file, model);
+                }
+                checksums.insert(file, checksum);
+            }
+            models.insert(model, checksums);
+        }
+        Ok(Self { source: path.to_path_buf(), models })
+    }
+
+    /// Check each of `files` against the checksums pinned for `model` (or its `repo`). `file`
+    /// gives where a file of the repo is on disk, downloading it if need be.
+    fn verify(&self, model: &str, repo: &str, files: &[String], file: impl Fn(&str) -> Result<PathBuf>) -> Result<()> {
+        let pinned = self.models.get(model).or_else(|| self.models.get(repo));
+        let mut unpinned = Vec::new();
+        for name in files {
+            let path = file(name)?;
+            let actual = sha256_file(&path)?;
+            match pinned.and_then(|pinned| pinned.get(name)) {
+                Some(expected) if *expected == actual => {}
+                Some(expected) => anyhow::bail!(
+                    "{} of model {} doesn't match its pinned checksum in {} (expected {}, found {}). Delete {} to download it again.",
+                    name, model, self.source.display(), expected, actual, path.display()
+                ),
+                None => unpinned.push(format!("{:?} = \"{}\"", name, actual)),
+            }
+        }
+        if !unpinned.is_empty() {
+            // The downloaded files' checksums, to be compared with the artifact store's before
+            // they are added
+            anyhow::bail!(
+                "{} pins no checksums for these files of model {}. Their checksums as downloaded are:\n[models.{:?}]\n{}",
+                self.source.display(), model, model, unpinned.join("\n")
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Download local models from `mirror` for the rest of the process, and check them against
+/// the checksums in `checksums` before they are loaded. Either falls back to its environment
+/// variable.
+pub fn configure(mirror: Option<String>, checksums: Option<PathBuf>) -> Result<()> {
+    let mirror = mirror.or_else(|| std::env::var(MODEL_MIRROR_VAR).ok()).filter(|mirror| !mirror.is_empty());
+    if let Some(mirror) = mirror {
+        let mirror = mirror.trim_end_matches('/').to_string();
+        reqwest::Url::parse(&mirror).with_context(|| format!("Invalid model mirror {}", mirror))?;
+        // hf-hub, and FastEmbed through it, then download from the mirror
+        std::env::set_var("HF_ENDPOINT", &mirror);
+        log::info!("📦 Downloading models from {}", mirror);
+    }
+    let checksums = checksums.or_else(|| std::env::var_os(MODEL_CHECKSUMS_VAR).map(PathBuf::from));
+    if let Some(path) = checksums {
+        let _ = PINS.set(ModelPins::load(&path)?);
+    }
+    Ok(())
+}
+
+/// Where models are downloaded from: the mirror, or Hugging Face
+pub fn endpoint() -> String {
+    std::env::var("HF_ENDPOINT").unwrap_or_else(|_| "https://huggingface.co".to_string())
+}
+
+/// When checksums are pinned, fetch the files a FastEmbed model loads (`model_file`, its
+/// `additional_files` and its tokenizer) into the model cache and check them, so a model that
+/// doesn't match is never loaded. Without pins nothing is done and FastEmbed downloads as usual.
+pub fn verify_model(model: &str, repo: &str, model_file: &str, additional_files: &[String]) -> Result<()> {
+    let Some(pins) = PINS.get() else {
+        return Ok(());
+    };
+    let mut files = vec![model_file.to_string()];
+    files.extend(additional_files.iter().cloned());
+    files.extend(TOKENIZER_FILES.iter().map(|file| file.to_string()));
+
+    let cache = PathBuf::from(fastembed::get_cache_dir());
+    let api = hf_hub::api::sync::ApiBuilder::new()
+        .with_cache_dir(cache.clone())
+        .with_endpoint(endpoint())
+        .with_progress(true)
+        .build()
+        .context("Failed to set up the model download")?;
+    let repository = api.model(repo.to_string());
+    pins.verify(model, repo, &files, |file| {
+        if let Some(path) = hf_hub::Cache::new(cache.clone()).model(repo.to_string()).get(file) {
+            return Ok(path);
+        }
+        if offline::is_enabled() {
+            return Err(offline::refused(&format!("Downloading {} of model {}", file, repo)));
+        }
+        repository.get(file).with_context(|| format!("Failed to download {} of model {} from {}", file, repo, endpoint()))
+    })
+}
+
+fn sha256_file(path: &Path) -> Result<String> {
+    let mut reader = std::fs::File::open(path)
+        .with_context(|| format!("Failed to open {}", path.display()))?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; 1 << 20];
+    loop {
+        let read = reader.read(&mut buffer).with_context(|| format!("Failed to read {}", path.display()))?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_model_files_checked_against_pins() {
+        let dir = std::env::temp_dir().join(format!("pb-model-pins-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("model.onnx"), b"weights").unwrap();
+        std::fs::write(dir.join("tokenizer.json"), b"{}").unwrap();
+        let weights = sha256_file(&dir.join("model.onnx")).unwrap();
+        let file = |name: &str| -> Result<PathBuf> { Ok(dir.join(name)) };
+        let files = vec!["model.onnx".to_string(), "tokenizer.json".to_string()];
+
+        let pins = ModelPins::parse(Path::new("pins.toml"), &format!(
+            "[models.\"Qdrant/bge-small\"]\n\"model.onnx\" = \"{}\"\n\"tokenizer.json\" = \"{}\"",
+            weights.to_uppercase(), sha256_file(&dir.join("tokenizer.json")).unwrap()
+        )).unwrap();
+        // Pins are found by repo as well as by model name
+        pins.verify("BAAI/bge-small-en-v1.5", "Qdrant/bge-small", &files, file).unwrap();
+
+        std::fs::write(dir.join("model.onnx"), b"tampered").unwrap();
+        let error = pins.verify("BAAI/bge-small-en-v1.5", "Qdrant/bge-small", &files, file).unwrap_err().to_string();
+        assert!(error.contains("model.onnx of model BAAI/bge-small-en-v1.5 doesn't match"));
+
+        // Unpinned files fail with their checksums, ready to be pinned
+        let error = pins.verify("other", "Other/repo", &files, file).unwrap_err().to_string();
+        assert!(error.contains("[models.\"other\"]\n\"model.onnx\" = \""));
+
+        assert!(ModelPins::parse(Path::new("pins.toml"), "[models.m]\n\"a.onnx\" = \"abc\"").is_err());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
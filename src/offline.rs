@@ -0,0 +1,202 @@
+use anyhow::{Context, Result};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::error::PortableBrainsError;
+
+/// Environment variable that turns strict offline mode on like `--strict-offline`, e.g. for
+/// every run on an air-gapped machine
+pub const STRICT_OFFLINE_VAR: &str = "PORTABLE_BRAINS_STRICT_OFFLINE";
+
+/// Redirects an HTTP client follows before giving up, as reqwest's default policy does
+const MAX_REDIRECTS: usize = 10;
+
+static STRICT_OFFLINE: AtomicBool = AtomicBool::new(false);
+
+/// Refuse network access for the rest of the process. Requests to servers on this machine,
+/// such as Ollama on localhost, are still allowed.
+pub fn enable() {
+    STRICT_OFFLINE.store(true, Ordering::SeqCst);
+    // hf-hub, and FastEmbed through it, then only read models from the local cache
+    std::env::set_var("HF_HUB_OFFLINE", "1");
+}
+
+/// Turn strict offline mode on when `flag` is set or `STRICT_OFFLINE_VAR` is `1` or `true`.
+/// Returns whether it is on.
+pub fn configure(flag: bool) -> bool {
+    let from_env = std::env::var(STRICT_OFFLINE_VAR).is_ok_and(|value| value == "1" || value.eq_ignore_ascii_case("true"));
+    if flag || from_env {
+        enable();
+    }
+    is_enabled()
+}
+
+pub fn is_enabled() -> bool {
+    STRICT_OFFLINE.load(Ordering::SeqCst)
+}
+
+/// The error for something that needed the network in strict offline mode; `what` names it
+pub fn refused(what: &str) -> anyhow::Error {
+    PortableBrainsError::NetworkRefused(format!("{} needs network access, which strict offline mode forbids", what)).into()
+}
+
+/// Whether `url` is on this machine: its host is `localhost` or a loopback address
+fn is_local(url: &str) -> bool {
+    reqwest::Url::parse(url).ok()
+        .and_then(|url| url.host_str().map(|host| {
+            host.eq_ignore_ascii_case("localhost")
+                || host.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>().is_ok_and(|ip| ip.is_loopback())
+        }))
+        .unwrap_or(false)
+}
+
+/// Fail when strict offline mode is on and `url` isn't on this machine. `what` names what
+/// wanted to connect, for the error.
+pub fn check(url: &str, what: &str) -> Result<()> {
+    if !is_enabled() || is_local(url) {
+        Ok(())
+    } else {
+        Err(refused(&format!("{} ({})", what, url)))
+    }
+}
+
+/// The HTTP client every request goes through. In strict offline mode it also refuses
+/// redirects leaving this machine, so a local server can't forward a request elsewhere.
+pub fn client() -> reqwest::Client {
+    let policy = reqwest::redirect::Policy::custom(|attempt| {
+        if attempt.previous().len() >= MAX_REDIRECTS {
+            attempt.error("too many redirects")
+        } else if let Err(e) = check(attempt.url().as_str(), "A redirect") {
+            attempt.error(e.to_string())
+        } else {
+            attempt.follow()
+        }
+    });
+    reqwest::Client::builder()
+        .redirect(policy)
+        .build()
+        .expect("Failed to build HTTP client")
+}
+
+/// Fetch `url` for an API caller, connecting only to public addresses so a caller can't
+/// reach services on this machine or its network, such as a cloud metadata endpoint.
+/// Redirects are followed here rather than by reqwest: each hop's host is resolved, checked
+/// and pinned, so it can't resolve to another address between the check and the request.
+pub async fn get_public(url: &str) -> Result<reqwest::Response> {
+    let mut url = reqwest::Url::parse(url).with_context(|| format!("Invalid URL {}", url))?;
+    for _ in 0..=MAX_REDIRECTS {
+        check(url.as_str(), "A redirect")?;
+        let host = url.host_str().ok_or_else(|| anyhow::anyhow!("{} has no host", url))?.to_string();
+        let addrs = public_addrs(&host, url.port_or_known_default().unwrap_or(80)).await?;
+        let client = reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .resolve_to_addrs(&host, &addrs)
+            .build()
+            .context("Failed to build HTTP client")?;
+        let response = client.get(url.clone()).send().await?;
+        let location = response.headers().get(reqwest::header::LOCATION).and_then(|value| value.to_str().ok());
+        match location {
+            Some(location) if response.status().is_redirection() => {
+                url = url.join(location).with_context(|| format!("Invalid redirect to {}", location))?;
+            }
+            _ => return Ok(response),
+        }
+    }
+    anyhow::bail!("Too many redirects")
+}
+
+/// Addresses `host` resolves to, failing unless all of them are public
+async fn public_addrs(host: &str, port: u16) -> Result<Vec<SocketAddr>> {
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host, port)).await
+        .with_context(|| format!("Failed to resolve {}", host))?
+        .collect();
+    if let Some(addr) = addrs.iter().find(|addr| !is_public(addr.ip())) {
+        return Err(PortableBrainsError::NetworkRefused(format!("{} resolves to {}, which is not a public address", host, addr.ip())).into());
+    }
+    if addrs.is_empty() {
+        anyhow::bail!("{} has no address", host);
+    }
+    Ok(addrs)
+}
+
+/// Whether `ip` is routable on the internet: not loopback, private, link-local, shared,
+/// multicast or reserved for documentation and benchmarks
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_v4(ip),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public_v4(ip),
+            None => is_public_v6(ip),
+        },
+    }
+}
+
+fn is_public_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || ip.is_multicast()
+        || a == 0
+        // Carrier-grade NAT, benchmarking and the reserved 240.0.0.0/4
+        || (a == 100 && (64..128).contains(&b))
+        || (a == 198 && (b == 18 || b == 19))
+        || a >= 240)
+}
+
+fn is_public_v6(ip: Ipv6Addr) -> bool {
+    let segments = ip.segments();
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_multicast()
+        || ip.is_unique_local()
+        || ip.is_unicast_link_local()
+        // Documentation, and NAT64 which can reach IPv4 addresses
+        || (segments[0] == 0x2001 && segments[1] == 0xdb8)
+        || (segments[0] == 0x64 && segments[1] == 0xff9b))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_only_this_machine_is_local() {
+        for local in ["http://localhost:11434/api/embeddings", "http://127.0.0.1:8080/v1", "http://[::1]:8000/"] {
+            assert!(is_local(local), "{}", local);
+        }
+        for remote in ["https://api.openai.com/v1/embeddings", "http://10.0.0.5:11434/", "not a url"] {
+            assert!(!is_local(remote), "{}", remote);
+        }
+    }
+
+    #[test]
+    fn test_only_public_addresses_are_public() {
+        for public in ["93.184.216.34", "2606:2800:220:1:248:1893:25c8:1946", "::ffff:93.184.216.34"] {
+            assert!(is_public(public.parse().unwrap()), "{}", public);
+        }
+        for private in ["127.0.0.1", "10.1.2.3", "172.16.0.1", "192.168.1.1", "169.254.169.254", "100.64.0.1",
+                        "0.0.0.0", "::1", "fd00::1", "fe80::1", "::ffff:127.0.0.1", "::ffff:169.254.169.254"] {
+            assert!(!is_public(private.parse().unwrap()), "{}", private);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_private_hosts_are_refused() {
+        for url in ["http://169.254.169.254/latest/meta-data/", "http://[::1]:8080/", "http://localhost/"] {
+            let error = get_public(url).await.unwrap_err();
+            assert!(matches!(error.downcast_ref(), Some(PortableBrainsError::NetworkRefused(_))), "{}", url);
+        }
+    }
+
+    #[test]
+    fn test_refusal_names_the_request() {
+        let error = refused("Remote embedding API (https://api.openai.com/v1/embeddings)");
+        assert!(matches!(error.downcast_ref(), Some(PortableBrainsError::NetworkRefused(_))));
+        assert!(error.to_string().contains("Remote embedding API (https://api.openai.com/v1/embeddings) needs network access"));
+    }
+}
@@ -0,0 +1,221 @@
+use anyhow::{Context, Result};
+use regex::Regex;
+use serde::Deserialize;
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+/// Environment variable the banned-topics hook finds the question in
+pub const QUESTION_VAR: &str = "PORTABLE_BRAINS_QUESTION";
+
+/// Said instead of an answer the banned-topics hook withholds, unless the file gives its own
+const DEFAULT_REFUSAL: &str = "I can't help with that question.";
+
+/// Text replacing a redacted match, unless a rule gives its own
+const DEFAULT_REDACTION: &str = "[redacted]";
+
+/// Filters applied to answers before they are shown or returned, read from the TOML file
+/// given to `eatmybrain --output-filters`
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct OutputFilterConfig {
+    /// Answers are cut at the first of these, e.g. a runaway `\nQuestion:` turn
+    #[serde(default)]
+    stop: Vec<String>,
+    /// Regex redactions, applied in order
+    #[serde(default)]
+    redact: Vec<RedactionConfig>,
+    #[serde(default)]
+    banned_topics: Option<TopicHookConfig>,
+}
+
+/// A `[[redact]]` entry
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RedactionConfig {
+    pattern: String,
+    /// Replacement text; `$1` or `${name}` insert capture groups
+    #[serde(default = "default_redaction")]
+    replace: String,
+}
+
+fn default_redaction() -> String {
+    DEFAULT_REDACTION.to_string()
+}
+
+/// The `[banned_topics]` table
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TopicHookConfig {
+    /// Classifier run on every answer, e.g. `python moderate.py`
+    command: String,
+    #[serde(default)]
+    refusal: Option<String>,
+}
+
+/// Runs an external classifier on an answer, written to its stdin with the question in
+/// `QUESTION_VAR`. The answer is withheld when the command exits non-zero or prints
+/// anything to stdout; the first line printed, such as the topic found, is the reason.
+#[derive(Debug)]
+struct TopicHook {
+    program: String,
+    args: Vec<String>,
+    refusal: String,
+}
+
+impl TopicHook {
+    fn new(config: &TopicHookConfig) -> Result<Self> {
+        let mut parts = config.command.split_whitespace().map(str::to_string);
+        let program = parts.next()
+            .ok_or_else(|| anyhow::anyhow!("banned_topics command is empty"))?;
+        Ok(Self {
+            program,
+            args: parts.collect(),
+            refusal: config.refusal.clone().unwrap_or_else(|| DEFAULT_REFUSAL.to_string()),
+        })
+    }
+
+    /// Why the answer must be withheld, or `None` when it may be shown
+    fn check(&self, question: &str, answer: &str) -> Result<Option<String>> {
+        let mut child = Command::new(&self.program)
+            .args(&self.args)
+            .env(QUESTION_VAR, question)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .with_context(|| format!("Failed to run banned-topics hook {}", self.program))?;
+        // A hook that exits without reading its input still decides by its status
+        if let Some(mut stdin) = child.stdin.take() {
+            let _ = stdin.write_all(answer.as_bytes());
+        }
+        let output = child.wait_with_output()
+            .with_context(|| format!("Failed to run banned-topics hook {}", self.program))?;
+
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let report = stdout.lines().next().unwrap_or("").trim().to_string();
+        if !output.status.success() || !report.is_empty() {
+            return Ok(Some(if report.is_empty() { format!("{} exited with {}", self.program, output.status) } else { report }));
+        }
+        Ok(None)
+    }
+}
+
+/// An answer after the output filters ran
+#[derive(Debug, Clone, PartialEq)]
+pub struct FilteredAnswer {
+    /// The answer to show: cut and redacted, or the refusal when it was withheld
+    pub text: String,
+    /// Matches replaced by the redaction rules
+    pub redactions: usize,
+    /// Why the banned-topics hook withheld the answer
+    pub withheld: Option<String>,
+}
+
+/// Stop sequences, redactions and a banned-topics hook applied to every answer, for
+/// deployments where what the model writes must be checked before anyone reads it
+#[derive(Debug, Default)]
+pub struct OutputFilters {
+    stop: Vec<String>,
+    redactions: Vec<(Regex, String)>,
+    topic_hook: Option<TopicHook>,
+}
+
+impl OutputFilters {
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read output filters {}", path.display()))?;
+        let config: OutputFilterConfig = toml::from_str(&text)
+            .with_context(|| format!("Invalid output filters {}", path.display()))?;
+        Self::new(&config)
+    }
+
+    fn new(config: &OutputFilterConfig) -> Result<Self> {
+        if config.stop.iter().any(String::is_empty) {
+            anyhow::bail!("Stop sequences must not be empty");
+        }
+        let mut redactions = Vec::new();
+        for (i, rule) in config.redact.iter().enumerate() {
+            let regex = Regex::new(&rule.pattern)
+                .with_context(|| format!("Invalid pattern in redaction rule {}", i + 1))?;
+            redactions.push((regex, rule.replace.clone()));
+        }
+        Ok(Self {
+            stop: config.stop.clone(),
+            redactions,
+            topic_hook: config.banned_topics.as_ref().map(TopicHook::new).transpose()?,
+        })
+    }
+
+    /// Cut `answer` at the first stop sequence, redact it, and withhold it when the
+    /// banned-topics hook objects. The hook sees the redacted answer; if it can't be run the
+    /// answer is withheld by the error rather than shown unchecked.
+    pub fn apply(&self, question: &str, answer: &str) -> Result<FilteredAnswer> {
+        let end = self.stop.iter()
+            .filter_map(|stop| answer.find(stop.as_str()))
+            .min()
+            .unwrap_or(answer.len());
+        let mut text = answer[..end].trim_end().to_string();
+
+        let mut redactions = 0;
+        for (regex, replace) in &self.redactions {
+            let found = regex.find_iter(&text).count();
+            if found > 0 {
+                redactions += found;
+                text = regex.replace_all(&text, replace.as_str()).into_owned();
+            }
+        }
+
+        let mut withheld = None;
+        if let Some(hook) = &self.topic_hook {
+            withheld = hook.check(question, &text)?;
+            if withheld.is_some() {
+                text = hook.refusal.clone();
+            }
+        }
+        Ok(FilteredAnswer { text, redactions, withheld })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filters(toml: &str) -> OutputFilters {
+        OutputFilters::new(&toml::from_str(toml).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_stop_and_redact() {
+        let filters = filters(r####"
+            stop = ["\nQuestion:", "###"]
+            [[redact]]
+            pattern = '\b\d{3}-\d{2}-\d{4}\b'
+            [[redact]]
+            pattern = '(?i)project (\w+)'
+            replace = 'project X'
+        "####);
+        let filtered = filters.apply("q", "SSN 123-45-6789 on Project Falcon [1].\nQuestion: and more?").unwrap();
+        assert_eq!(filtered.text, "SSN [redacted] on project X [1].");
+        assert_eq!(filtered.redactions, 2);
+        assert_eq!(filtered.withheld, None);
+
+        assert!(OutputFilters::new(&toml::from_str("[[redact]]\npattern = '('").unwrap()).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_banned_topics_hook() {
+        let filters = filters(r#"
+            [banned_topics]
+            command = "grep -o -m1 -i diagnosis"
+            refusal = "Please ask your doctor."
+        "#);
+        let filtered = filters.apply("q", "The diagnosis is likely flu.").unwrap();
+        assert_eq!(filtered.text, "Please ask your doctor.");
+        assert_eq!(filtered.withheld.as_deref(), Some("diagnosis"));
+
+        // A hook exiting cleanly without printing anything lets the answer through
+        let filters = self::filters("[banned_topics]\ncommand = \"true\"");
+        assert_eq!(filters.apply("q", "Revenue grew [1].").unwrap().withheld, None);
+    }
+}
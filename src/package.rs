@@ -0,0 +1,387 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::Path;
+use zip::write::FileOptions;
+use zip::{ZipArchive, ZipWriter};
+
+use crate::storage::Storage;
+
+/// Version of the package layout, bumped when an older `unpack` could no longer read it
+pub const PACKAGE_FORMAT: u32 = 1;
+
+/// Extension of packaged brains
+pub const PACKAGE_EXTENSION: &str = "brain";
+
+/// The manifest entry: what the package holds, with a hash of every other entry
+const PACKAGE_MANIFEST: &str = "manifest.json";
+
+/// The signature entry: an ed25519 signature over the manifest's bytes, and the key that
+/// made it
+const PACKAGE_SIGNATURE: &str = "signature.json";
+
+/// Entry holding the database file
+const PACKAGE_DATABASE: &str = "database.db";
+
+/// Directory of the entries holding original documents
+const PACKAGE_ORIGINALS: &str = "originals/";
+
+/// `manifest.json` in a package
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct PackageManifest {
+    pub format: u32,
+    pub name: String,
+    /// Version of the brain given by whoever packed it, e.g. `2024.06` or `1.2.0`
+    pub version: Option<String>,
+    pub created: DateTime<Utc>,
+    /// portable-brains version that packed it
+    pub packed_by: String,
+    pub embedding_model: String,
+    pub documents: usize,
+    pub fragments: usize,
+    /// Every entry besides the manifest and signature, database first
+    pub files: Vec<PackagedFile>,
+}
+
+/// An entry of a package with its size and hex SHA-256
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct PackagedFile {
+    pub path: String,
+    pub size: u64,
+    pub sha256: String,
+}
+
+/// `signature.json` in a signed package
+#[derive(Debug, Serialize, Deserialize)]
+struct PackageSignature {
+    /// Hex ed25519 public key
+    public_key: String,
+    /// Hex ed25519 signature of `manifest.json`
+    signature: String,
+}
+
+/// How to pack a brain
+#[derive(Debug, Clone, Default)]
+pub struct PackOptions {
+    /// Name recorded in the manifest (default: the database's file name)
+    pub name: Option<String>,
+    pub version: Option<String>,
+    /// Also store every document's original file outside the database
+    pub originals: bool,
+    /// Sign the manifest with this key
+    pub signing_key: Option<SigningKey>,
+}
+
+/// What `verify_package` found: the manifest, and the public key of a valid signature
+#[derive(Debug)]
+pub struct Verified {
+    pub manifest: PackageManifest,
+    pub signer: Option<String>,
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex<const N: usize>(text: &str) -> Result<[u8; N]> {
+    let text = text.trim();
+    if text.len() != N * 2 || !text.is_ascii() {
+        anyhow::bail!("Expected {} hex digits, got {:?}", N * 2, text);
+    }
+    let mut bytes = [0u8; N];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&text[i * 2..i * 2 + 2], 16)
+            .with_context(|| format!("{:?} is not hex", text))?;
+    }
+    Ok(bytes)
+}
+
+/// Hex of an ed25519 public key, as printed and passed to `--public-key`
+pub fn public_key_hex(key: &VerifyingKey) -> String {
+    to_hex(key.as_bytes())
+}
+
+/// Parse a public key given as hex, or as the path of a file holding it
+pub fn parse_public_key(value: &str) -> Result<VerifyingKey> {
+    let text = match std::fs::read_to_string(value) {
+        Ok(text) => text,
+        Err(_) => value.to_string(),
+    };
+    VerifyingKey::from_bytes(&from_hex::<32>(&text)?).context("Not a valid ed25519 public key")
+}
+
+/// The signing key stored in `path` as 64 hex digits, generating and saving a new one
+/// (readable only by its owner) when the file doesn't exist. The flag is true for a new key.
+pub fn load_or_create_signing_key(path: &Path) -> Result<(SigningKey, bool)> {
+    if path.exists() {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read signing key {}", path.display()))?;
+        let seed = from_hex::<32>(&text).with_context(|| format!("{} is not a signing key", path.display()))?;
+        return Ok((SigningKey::from_bytes(&seed), false));
+    }
+
+    let mut seed = [0u8; 32];
+    getrandom::getrandom(&mut seed).map_err(|e| anyhow::anyhow!("Failed to generate a signing key: {}", e))?;
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(path)
+        .with_context(|| format!("Failed to create signing key {}", path.display()))?;
+    writeln!(file, "{}", to_hex(&seed))?;
+    Ok((SigningKey::from_bytes(&seed), true))
+}
+
+/// Copy `reader` into the zip entry `name`, hashing it on the way
+fn add_entry(zip: &mut ZipWriter<File>, name: &str, reader: &mut impl Read) -> Result<PackagedFile> {
+    let options = FileOptions::default().large_file(true);
+    zip.start_file(name, options)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; 1 << 20];
+    let mut size = 0;
+    loop {
+        let read = reader.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+        zip.write_all(&buffer[..read])?;
+        size += read as u64;
+    }
+    Ok(PackagedFile { path: name.to_string(), size, sha256: format!("{:x}", hasher.finalize()) })
+}
+
+/// Pack the database file at `database`, whose contents `storage` reads, into a single
+/// `.brain` archive at `output`: the database, a manifest recording the model, counts and
+/// a SHA-256 of every entry, optionally each document's original file, and, with a
+/// signing key, an ed25519 signature of the manifest. The database must not be written
+/// to while it is packed.
+pub async fn pack(database: &Path, storage: &mut dyn Storage, options: &PackOptions, output: &Path) -> Result<PackageManifest> {
+    if output.exists() {
+        anyhow::bail!("{} already exists", output.display());
+    }
+
+    let documents = storage.list_documents().await?;
+    let meta = storage.get_meta_info().await?;
+    let mut zip = ZipWriter::new(File::create(output)
+        .with_context(|| format!("Failed to create {}", output.display()))?);
+
+    let mut database_file = File::open(database)
+        .with_context(|| format!("Failed to open {}", database.display()))?;
+    let mut files = vec![add_entry(&mut zip, PACKAGE_DATABASE, &mut database_file)?];
+
+    if options.originals {
+        for (i, summary) in documents.iter().enumerate() {
+            let document = storage.get_document(&summary.id).await?
+                .with_context(|| format!("Document {} disappeared while packing", summary.id))?;
+            // Documents indexed with `--store-originals none` have nothing to pack
+            let Some(data) = document.file_data else {
+                continue;
+            };
+            // Numbered so documents sharing a file name don't collide
+            let name = format!("{}{}-{}", PACKAGE_ORIGINALS, i, summary.filename);
+            files.push(add_entry(&mut zip, &name, &mut data.as_slice())?);
+        }
+    }
+
+    let manifest = PackageManifest {
+        format: PACKAGE_FORMAT,
+        name: options.name.clone().unwrap_or_else(|| {
+            database.file_stem().map_or_else(|| "brain".to_string(), |stem| stem.to_string_lossy().to_string())
+        }),
+        version: options.version.clone(),
+        created: Utc::now(),
+        packed_by: env!("CARGO_PKG_VERSION").to_string(),
+        embedding_model: meta.embedding_model,
+        documents: documents.len(),
+        fragments: documents.iter().map(|document| document.fragments.max(0) as usize).sum(),
+        files,
+    };
+    let manifest_bytes = serde_json::to_vec_pretty(&manifest)?;
+    zip.start_file(PACKAGE_MANIFEST, FileOptions::default())?;
+    zip.write_all(&manifest_bytes)?;
+
+    if let Some(key) = &options.signing_key {
+        let signature = PackageSignature {
+            public_key: public_key_hex(&key.verifying_key()),
+            signature: to_hex(&key.sign(&manifest_bytes).to_bytes()),
+        };
+        zip.start_file(PACKAGE_SIGNATURE, FileOptions::default())?;
+        zip.write_all(&serde_json::to_vec_pretty(&signature)?)?;
+    }
+
+    zip.finish()?;
+    Ok(manifest)
+}
+
+fn read_entry(archive: &mut ZipArchive<File>, name: &str) -> Result<Option<Vec<u8>>> {
+    let mut entry = match archive.by_name(name) {
+        Ok(entry) => entry,
+        Err(zip::result::ZipError::FileNotFound) => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let mut bytes = Vec::new();
+    entry.read_to_end(&mut bytes)?;
+    Ok(Some(bytes))
+}
+
+/// Check a package before trusting it: its manifest is readable, every entry is listed and
+/// matches its recorded size and hash, and a signature, when present, is valid. With
+/// `trusted`, the package must be signed by that key.
+pub fn verify_package(package: &Path, trusted: Option<&VerifyingKey>) -> Result<Verified> {
+    let mut archive = ZipArchive::new(File::open(package)
+        .with_context(|| format!("Failed to open {}", package.display()))?)
+        .with_context(|| format!("{} is not a brain package", package.display()))?;
+
+    let manifest_bytes = read_entry(&mut archive, PACKAGE_MANIFEST)?
+        .with_context(|| format!("{} has no {}", package.display(), PACKAGE_MANIFEST))?;
+    let manifest: PackageManifest = serde_json::from_slice(&manifest_bytes)
+        .with_context(|| format!("Failed to parse the manifest of {}", package.display()))?;
+    if manifest.format > PACKAGE_FORMAT {
+        anyhow::bail!("{} is package format {}; this version reads up to {}", package.display(), manifest.format, PACKAGE_FORMAT);
+    }
+
+    for file in &manifest.files {
+        let mut entry = archive.by_name(&file.path)
+            .with_context(|| format!("{} is listed in the manifest but missing", file.path))?;
+        let mut hasher = Sha256::new();
+        let size = std::io::copy(&mut entry, &mut hasher)?;
+        if size != file.size || format!("{:x}", hasher.finalize()) != file.sha256 {
+            anyhow::bail!("{} does not match the manifest; the package is corrupt or was modified", file.path);
+        }
+    }
+    for i in 0..archive.len() {
+        let name = archive.by_index(i)?.name().to_string();
+        if name != PACKAGE_MANIFEST && name != PACKAGE_SIGNATURE && !manifest.files.iter().any(|file| file.path == name) {
+            anyhow::bail!("{} is not listed in the manifest; the package was modified", name);
+        }
+    }
+
+    let signer = match read_entry(&mut archive, PACKAGE_SIGNATURE)? {
+        Some(bytes) => {
+            let signature: PackageSignature = serde_json::from_slice(&bytes).context("Failed to parse the signature")?;
+            let key = VerifyingKey::from_bytes(&from_hex::<32>(&signature.public_key)?)
+                .context("The signature's public key is invalid")?;
+            let signature_bytes = Signature::from_bytes(&from_hex::<64>(&signature.signature)?);
+            key.verify_strict(&manifest_bytes, &signature_bytes)
+                .context("The signature does not match the manifest; the package was modified")?;
+            Some(key)
+        }
+        None => None,
+    };
+
+    if let Some(trusted) = trusted {
+        match &signer {
+            Some(key) if key == trusted => {}
+            Some(key) => anyhow::bail!("Signed by {}, not by the trusted key {}", public_key_hex(key), public_key_hex(trusted)),
+            None => anyhow::bail!("The package is not signed"),
+        }
+    }
+
+    Ok(Verified { manifest, signer: signer.as_ref().map(public_key_hex) })
+}
+
+/// Verify a package, then extract its database to `database`, which must not exist, and
+/// its original files, if it holds any, into the directory `originals`
+pub fn unpack(package: &Path, trusted: Option<&VerifyingKey>, database: &Path, originals: Option<&Path>) -> Result<Verified> {
+    let verified = verify_package(package, trusted)?;
+    if database.exists() {
+        anyhow::bail!("{} already exists; unpack into a new database", database.display());
+    }
+
+    let mut archive = ZipArchive::new(File::open(package)?)?;
+    if let Some(parent) = database.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+        std::fs::create_dir_all(parent)?;
+    }
+    let mut file = File::create(database).with_context(|| format!("Failed to create {}", database.display()))?;
+    std::io::copy(&mut archive.by_name(PACKAGE_DATABASE)?, &mut file)?;
+
+    if let Some(directory) = originals {
+        std::fs::create_dir_all(directory)?;
+        for packaged in &verified.manifest.files {
+            let Some(name) = packaged.path.strip_prefix(PACKAGE_ORIGINALS) else {
+                continue;
+            };
+            // Names come from a manifest that may be hostile; keep them inside the directory
+            let name = Path::new(name).file_name()
+                .with_context(|| format!("{} is not a file name", packaged.path))?;
+            let mut file = File::create(directory.join(name))?;
+            std::io::copy(&mut archive.by_name(&packaged.path)?, &mut file)?;
+        }
+    }
+    Ok(verified)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lancedb_storage::LanceDBStorage;
+    use crate::storage::FragmentMeta;
+
+    #[tokio::test]
+    async fn test_packages_verify_and_detect_tampering() {
+        let dir = std::env::temp_dir().join(format!("pb-package-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let database = dir.join("handbook.db");
+        std::fs::write(&database, b"database bytes").unwrap();
+
+        let mut storage = LanceDBStorage::new(Path::new("package")).await.unwrap();
+        storage.verify_or_set_model("portable-brains/hashing-4", Some(4)).await.unwrap();
+        let document = storage.store_document(Path::new("docs/leave.txt"), b"leave policy").await.unwrap();
+        storage.store_text_fragment(&document, 0, "Staff get 25 days", &FragmentMeta::default()).await.unwrap();
+
+        let (key, created) = load_or_create_signing_key(&dir.join("signing.key")).unwrap();
+        assert!(created);
+        assert_eq!(load_or_create_signing_key(&dir.join("signing.key")).unwrap().0.to_bytes(), key.to_bytes());
+        let options = PackOptions {
+            version: Some("1.0".to_string()),
+            originals: true,
+            signing_key: Some(key.clone()),
+            ..PackOptions::default()
+        };
+        let package = dir.join("handbook.brain");
+        let manifest = pack(&database, &mut storage, &options, &package).await.unwrap();
+        assert_eq!((manifest.name.as_str(), manifest.documents, manifest.fragments), ("handbook", 1, 1));
+        assert_eq!(manifest.files[1].path, "originals/0-leave.txt");
+
+        let verified = verify_package(&package, Some(&key.verifying_key())).unwrap();
+        assert_eq!(verified.manifest, manifest);
+        assert_eq!(verified.signer, Some(public_key_hex(&key.verifying_key())));
+        let stranger = SigningKey::from_bytes(&[7; 32]).verifying_key();
+        assert!(verify_package(&package, Some(&stranger)).is_err());
+        assert_eq!(parse_public_key(&public_key_hex(&key.verifying_key())).unwrap(), key.verifying_key());
+
+        let restored = dir.join("restored/handbook.db");
+        unpack(&package, None, &restored, Some(&dir.join("originals"))).unwrap();
+        assert_eq!(std::fs::read(&restored).unwrap(), b"database bytes");
+        assert_eq!(std::fs::read(dir.join("originals/0-leave.txt")).unwrap(), b"leave policy");
+
+        // Swapping the database for another breaks its recorded hash
+        let mut tampered = ZipWriter::new(File::create(dir.join("tampered.brain")).unwrap());
+        let mut archive = ZipArchive::new(File::open(&package).unwrap()).unwrap();
+        for i in 0..archive.len() {
+            let mut entry = archive.by_index(i).unwrap();
+            let name = entry.name().to_string();
+            let mut bytes = Vec::new();
+            entry.read_to_end(&mut bytes).unwrap();
+            if name == PACKAGE_DATABASE {
+                bytes = b"other bytes".to_vec();
+            }
+            tampered.start_file(name, FileOptions::default()).unwrap();
+            tampered.write_all(&bytes).unwrap();
+        }
+        tampered.finish().unwrap();
+        let error = verify_package(&dir.join("tampered.brain"), None).unwrap_err().to_string();
+        assert!(error.contains("does not match the manifest"), "{}", error);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,127 @@
+use anyhow::{Context, Result};
+use globset::{GlobBuilder, GlobMatcher};
+use serde::Deserialize;
+use std::path::Path;
+use std::sync::Mutex;
+
+/// A password manifest, e.g.
+///
+/// ```toml
+/// [[file]]
+/// glob = "**/finance/*.pdf"
+/// password = "q3-board"
+/// ```
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Manifest {
+    #[serde(default)]
+    file: Vec<ManifestEntry>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ManifestEntry {
+    glob: String,
+    password: String,
+}
+
+/// Passwords tried on encrypted PDFs: those a manifest gives for the file's path, in
+/// manifest order, then any typed at the prompt earlier in the run
+#[derive(Debug, Default)]
+pub struct Passwords {
+    entries: Vec<(GlobMatcher, String)>,
+    /// Ask on the terminal when no known password opens a file
+    prompt: bool,
+    /// Passwords typed at the prompt, tried on later files too so a batch sharing one
+    /// password is only asked about once
+    typed: Mutex<Vec<String>>,
+}
+
+impl Passwords {
+    /// Read a password manifest, a TOML file of `[[file]]` entries pairing a path glob with
+    /// a password
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read password file {}", path.display()))?;
+        Self::parse(&text).with_context(|| format!("Invalid password file {}", path.display()))
+    }
+
+    fn parse(text: &str) -> Result<Self> {
+        let manifest: Manifest = toml::from_str(text)?;
+        let mut entries = Vec::new();
+        for (i, entry) in manifest.file.into_iter().enumerate() {
+            // `*` stays within one path component; use `**` to cross directories
+            let glob = GlobBuilder::new(&entry.glob).literal_separator(true).build()
+                .with_context(|| format!("Invalid glob in password entry {}", i + 1))?;
+            entries.push((glob.compile_matcher(), entry.password));
+        }
+        Ok(Self { entries, ..Self::default() })
+    }
+
+    /// Also ask for a password on the terminal when none of the known ones open a file
+    pub fn with_prompt(mut self, prompt: bool) -> Self {
+        self.prompt = prompt;
+        self
+    }
+
+    /// Passwords to try on `path` before asking: the manifest's for it, then those typed so far
+    pub fn candidates(&self, path: &Path) -> Vec<String> {
+        let mut candidates: Vec<String> = self.entries.iter()
+            .filter(|(glob, _)| glob.is_match(path))
+            .map(|(_, password)| password.clone())
+            .collect();
+        for password in self.typed.lock().unwrap().iter() {
+            if !candidates.contains(password) {
+                candidates.push(password.clone());
+            }
+        }
+        candidates
+    }
+
+    /// Ask for the password of `path` on the terminal, or `None` when prompting is off, there
+    /// is no terminal, or nothing is typed (skipping the file)
+    pub fn ask(&self, path: &Path) -> Option<String> {
+        if !self.prompt {
+            return None;
+        }
+        let term = console::Term::stderr();
+        if !term.is_term() {
+            return None;
+        }
+        // Held while asking so parallel workers take turns at the prompt
+        let mut typed = self.typed.lock().unwrap();
+        term.write_line(&format!("🔒 {} is encrypted. Password (empty to skip):", path.display())).ok()?;
+        let password = term.read_secure_line().ok().filter(|password| !password.is_empty())?;
+        typed.push(password.clone());
+        Some(password)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_manifest_passwords_follow_globs_in_order() {
+        let passwords = Passwords::parse(r#"
+            [[file]]
+            glob = "**/finance/*.pdf"
+            password = "q3-board"
+
+            [[file]]
+            glob = "**/*.pdf"
+            password = "shared"
+        "#).unwrap();
+
+        assert_eq!(passwords.candidates(Path::new("docs/finance/q3.pdf")), vec!["q3-board", "shared"]);
+        assert_eq!(passwords.candidates(Path::new("docs/finance/2024/q3.pdf")), vec!["shared"]);
+        assert!(passwords.candidates(Path::new("docs/finance/q3.docx")).is_empty());
+
+        // Without a prompt nothing is asked, and typed passwords join the candidates
+        assert_eq!(passwords.ask(Path::new("docs/q3.docx")), None);
+        passwords.typed.lock().unwrap().push("typed".to_string());
+        assert_eq!(passwords.candidates(Path::new("notes.pdf")), vec!["shared", "typed"]);
+
+        assert!(Passwords::parse("[[file]]\nglob = \"[\"\npassword = \"x\"").is_err());
+    }
+}
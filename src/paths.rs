@@ -0,0 +1,227 @@
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+/// A document path in the forms storage needs.
+///
+/// `key` is what `documents.file_path` holds and what duplicates are detected by. It is
+/// the normalized path itself when that is valid Unicode; anything else is escaped
+/// (`\xNN` bytes on Unix, `\u{NNNN}` unpaired surrogates on Windows) rather than
+/// replaced, so distinct files never share a key. `raw` holds the exact OS bytes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StoredPath {
+    pub key: String,
+    pub raw: Vec<u8>,
+    /// File name for display
+    pub filename: String,
+    /// The path as given, spelled the way documents indexed before normalization were stored
+    pub legacy_key: String,
+}
+
+impl StoredPath {
+    pub fn new(path: &Path) -> Self {
+        let normalized = normalize(path);
+        Self {
+            key: lossless_string(&normalized),
+            raw: raw_bytes(&normalized),
+            filename: normalized.file_name()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_else(|| "unknown".to_string()),
+            legacy_key: path.to_string_lossy().to_string(),
+        }
+    }
+}
+
+/// Resolve a path to one spelling per file, so the same document isn't indexed twice
+/// through relative paths, `..`, symlinks or a Windows `\\?\` prefix.
+pub fn normalize(path: &Path) -> PathBuf {
+    let resolved = std::fs::canonicalize(path)
+        .or_else(|_| std::path::absolute(path))
+        .unwrap_or_else(|_| path.to_path_buf());
+    strip_verbatim(resolved)
+}
+
+/// Path to use for file system calls; on Windows, long paths get the `\\?\` prefix
+/// that lifts the 260 character `MAX_PATH` limit.
+pub fn io_path(path: &Path) -> PathBuf {
+    #[cfg(windows)]
+    {
+        use std::path::{Component, Prefix};
+
+        if path.as_os_str().len() >= 260 {
+            let mut components = path.components();
+            if let Some(Component::Prefix(prefix)) = components.next() {
+                match prefix.kind() {
+                    Prefix::Disk(_) => {
+                        let mut prefixed = OsString::from(r"\\?\");
+                        prefixed.push(path.as_os_str());
+                        return PathBuf::from(prefixed);
+                    }
+                    // \\server\share\... → \\?\UNC\server\share\...
+                    Prefix::UNC(server, share) => {
+                        let mut prefixed = OsString::from(r"\\?\UNC\");
+                        prefixed.push(server);
+                        prefixed.push(r"\");
+                        prefixed.push(share);
+                        prefixed.push(components.as_path().as_os_str());
+                        return PathBuf::from(prefixed);
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+    path.to_path_buf()
+}
+
+/// Drop the verbatim prefix `canonicalize` adds on Windows so stored keys use the
+/// ordinary spelling (`C:\docs\a.pdf`, `\\server\share\a.pdf`).
+fn strip_verbatim(path: PathBuf) -> PathBuf {
+    let Some(text) = path.to_str() else {
+        return path;
+    };
+    if let Some(unc) = text.strip_prefix(r"\\?\UNC\") {
+        return PathBuf::from(format!(r"\\{}", unc));
+    }
+    match text.strip_prefix(r"\\?\") {
+        // Only drive paths; other verbatim forms (\\?\Volume{..}) have no ordinary spelling
+        Some(rest) if rest.as_bytes().get(1) == Some(&b':') => PathBuf::from(rest),
+        _ => path,
+    }
+}
+
+/// Exact OS representation of a path: bytes on Unix, UTF-16LE code units on Windows
+pub fn raw_bytes(path: &Path) -> Vec<u8> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        path.as_os_str().as_bytes().to_vec()
+    }
+    #[cfg(windows)]
+    {
+        use std::os::windows::ffi::OsStrExt;
+        path.as_os_str().encode_wide().flat_map(|unit| unit.to_le_bytes()).collect()
+    }
+}
+
+/// Rebuild a path from `raw_bytes`
+pub fn from_raw_bytes(raw: &[u8]) -> PathBuf {
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStringExt;
+        PathBuf::from(OsString::from_vec(raw.to_vec()))
+    }
+    #[cfg(windows)]
+    {
+        use std::os::windows::ffi::OsStringExt;
+        let units: Vec<u16> = raw.chunks_exact(2).map(|pair| u16::from_le_bytes([pair[0], pair[1]])).collect();
+        PathBuf::from(OsString::from_wide(&units))
+    }
+}
+
+/// The path as a string, escaping what isn't valid Unicode instead of replacing it
+pub fn lossless_string(path: &Path) -> String {
+    if let Some(text) = path.to_str() {
+        return text.to_string();
+    }
+
+    let mut escaped = String::new();
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        for chunk in path.as_os_str().as_bytes().utf8_chunks() {
+            escaped.push_str(chunk.valid());
+            for byte in chunk.invalid() {
+                escaped.push_str(&format!("\\x{:02X}", byte));
+            }
+        }
+    }
+    #[cfg(windows)]
+    {
+        use std::os::windows::ffi::OsStrExt;
+        for unit in char::decode_utf16(path.as_os_str().encode_wide()) {
+            match unit {
+                Ok(c) => escaped.push(c),
+                Err(e) => escaped.push_str(&format!("\\u{{{:04X}}}", e.unpaired_surrogate())),
+            }
+        }
+    }
+    escaped
+}
+
+/// Serde support for paths that may not be valid Unicode: valid paths are written as
+/// strings, anything else as its raw bytes.
+pub mod lossless {
+    use super::{from_raw_bytes, raw_bytes};
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use std::path::{Path, PathBuf};
+
+    #[derive(Serialize, Deserialize)]
+    #[serde(untagged)]
+    enum Repr {
+        Text(String),
+        Raw(Vec<u8>),
+    }
+
+    pub fn serialize<S: Serializer>(path: &Path, serializer: S) -> Result<S::Ok, S::Error> {
+        match path.to_str() {
+            Some(text) => Repr::Text(text.to_string()),
+            None => Repr::Raw(raw_bytes(path)),
+        }.serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<PathBuf, D::Error> {
+        Ok(match Repr::deserialize(deserializer)? {
+            Repr::Text(text) => PathBuf::from(text),
+            Repr::Raw(raw) => from_raw_bytes(&raw),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_strip_verbatim() {
+        assert_eq!(strip_verbatim(PathBuf::from(r"\\?\C:\docs\a.pdf")), PathBuf::from(r"C:\docs\a.pdf"));
+        assert_eq!(strip_verbatim(PathBuf::from(r"\\?\UNC\server\share\a.pdf")), PathBuf::from(r"\\server\share\a.pdf"));
+        assert_eq!(strip_verbatim(PathBuf::from("/home/me/a.pdf")), PathBuf::from("/home/me/a.pdf"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_non_utf8_paths_are_lossless() {
+        use std::os::unix::ffi::OsStringExt;
+
+        let latin1 = PathBuf::from(OsString::from_vec(b"/docs/caf\xe9.txt".to_vec()));
+        let other = PathBuf::from(OsString::from_vec(b"/docs/caf\xe8.txt".to_vec()));
+
+        // Both would be "/docs/caf\u{FFFD}.txt" with to_string_lossy
+        assert_eq!(lossless_string(&latin1), "/docs/caf\\xE9.txt");
+        assert_ne!(lossless_string(&latin1), lossless_string(&other));
+        assert_eq!(from_raw_bytes(&raw_bytes(&latin1)), latin1);
+
+        #[derive(serde::Serialize, serde::Deserialize)]
+        struct Record {
+            #[serde(with = "lossless")]
+            path: PathBuf,
+        }
+        let json = serde_json::to_string(&Record { path: latin1.clone() }).unwrap();
+        let record: Record = serde_json::from_str(&json).unwrap();
+        assert_eq!(record.path, latin1);
+    }
+
+    #[test]
+    fn test_normalize_relative_spellings() {
+        let dir = std::env::temp_dir().join(format!("pb-paths-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        std::fs::write(dir.join("a.txt"), "a").unwrap();
+
+        let direct = StoredPath::new(&dir.join("a.txt"));
+        let indirect = StoredPath::new(&dir.join("sub").join("..").join(".").join("a.txt"));
+        assert_eq!(direct.key, indirect.key);
+        assert_eq!(direct.filename, "a.txt");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
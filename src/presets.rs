@@ -0,0 +1,236 @@
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use log::warn;
+use regex::Regex;
+
+use crate::storage::{FragmentMatch, Storage};
+
+/// Meta key recording the preset a database was indexed with
+pub const PRESET_KEY: &str = "preset";
+
+/// Settings tuned for a kind of corpus, chosen with `--preset`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Preset {
+    /// Source code and technical docs: whitespace kept, larger chunks, definitions boosted
+    Code,
+    /// Academic papers: hyphenation repaired, reference lists demoted
+    Papers,
+    /// Email: quoted replies and signatures stripped, small chunks
+    Email,
+    /// Contracts and statutes: page furniture stripped, long chunks, definitions boosted
+    Legal,
+}
+
+/// A score adjustment for fragments whose text matches a pattern
+#[derive(Debug, Clone, Copy)]
+pub struct BoostRule {
+    pub pattern: &'static str,
+    /// Added to the fragment's similarity; negative values demote
+    pub weight: f64,
+}
+
+impl Preset {
+    pub fn name(self) -> &'static str {
+        match self {
+            Preset::Code => "code",
+            Preset::Papers => "papers",
+            Preset::Email => "email",
+            Preset::Legal => "legal",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        <Self as ValueEnum>::from_str(name, true).ok()
+    }
+
+    /// Target fragment length and overlap in characters
+    pub fn chunking(self) -> (usize, usize) {
+        match self {
+            Preset::Code => (1200, 200),
+            Preset::Papers => (1000, 150),
+            Preset::Email => (500, 50),
+            Preset::Legal => (1500, 250),
+        }
+    }
+
+    /// Cleanup rules in the syntax of the config file's `[cleanup]` section
+    pub fn cleanup_toml(self) -> &'static str {
+        match self {
+            Preset::Code => r#"
+                # Indentation carries meaning in code
+                collapse_whitespace = false
+            "#,
+            Preset::Papers => r#"
+                remove = ['[\x{AD}]']
+
+                # Rejoin words hyphenated across lines
+                [[rules]]
+                pattern = '(\w)-\n(\w)'
+                replace = '$1$2'
+                formats = ["pdf"]
+            "#,
+            Preset::Email => r#"
+                # Quoted replies repeat earlier messages
+                [[rules]]
+                pattern = '(?m)^>.*$'
+
+                [[rules]]
+                pattern = '(?m)^On .{1,200} wrote:\s*$'
+
+                # Everything after the signature delimiter
+                [[rules]]
+                pattern = '(?s)\n-- \n.*'
+            "#,
+            Preset::Legal => r#"
+                # Page numbers and running footers
+                [[rules]]
+                pattern = '(?m)^\s*Page \d+ of \d+\s*$'
+
+                [[rules]]
+                pattern = '(\w)-\n(\w)'
+                replace = '$1$2'
+                formats = ["pdf"]
+            "#,
+        }
+    }
+
+    /// Search-time score adjustments
+    pub fn boosts(self) -> &'static [BoostRule] {
+        match self {
+            Preset::Code => &[
+                BoostRule { pattern: r"\b(fn|def|class|struct|interface|impl|func|function)\s+\w+", weight: 0.02 },
+            ],
+            Preset::Papers => &[
+                BoostRule { pattern: r"(?i)\b(abstract|we propose|in conclusion|our results)\b", weight: 0.02 },
+                // Bibliography entries match almost any query on their titles alone
+                BoostRule { pattern: r"(?i)\bet al\.,? \(?(19|20)\d\d", weight: -0.05 },
+            ],
+            Preset::Email => &[
+                BoostRule { pattern: r"(?i)\b(unsubscribe|sent from my)\b", weight: -0.05 },
+            ],
+            Preset::Legal => &[
+                BoostRule { pattern: r#"(?i)["“][^"”]{1,60}["”] (means|shall mean)\b"#, weight: 0.03 },
+                BoostRule { pattern: r"(?i)\b(shall|must not|is liable)\b", weight: 0.01 },
+            ],
+        }
+    }
+
+    /// System prompt used by eatmybrain to answer from this kind of corpus
+    pub fn system_prompt(self) -> &'static str {
+        match self {
+            Preset::Code => "You are a helpful assistant answering questions about a codebase and its \
+                documentation. Use the following context to answer. Quote identifiers and code \
+                exactly as they appear, and say so if the context doesn't show the relevant code.",
+            Preset::Papers => "You are a research assistant answering from a collection of papers. Use \
+                the following context to answer. Distinguish the authors' findings from the work \
+                they cite, and say so if the papers don't address the question.",
+            Preset::Email => "You are an assistant answering questions about an email archive. Use the \
+                following context to answer. Say who said what and when where the messages show \
+                it, and say so if the messages don't cover the question.",
+            Preset::Legal => "You are an assistant answering questions about legal documents. Use the \
+                following context to answer. Quote the operative wording of clauses rather than \
+                paraphrasing obligations, name the clause or section where it is shown, and say so \
+                if the documents don't address the question. This is not legal advice.",
+        }
+    }
+}
+
+/// The preset recorded in a database by `index --preset`
+pub async fn recorded_preset(storage: &mut dyn Storage) -> Result<Option<Preset>> {
+    let Some(name) = storage.get_meta_value(PRESET_KEY).await? else {
+        return Ok(None);
+    };
+    let preset = Preset::from_name(&name);
+    if preset.is_none() {
+        warn!("Ignoring unknown preset '{}' recorded in the database", name);
+    }
+    Ok(preset)
+}
+
+/// Compiled boosts for the preset recorded in a database, when it has any
+pub async fn recorded_booster(storage: &mut dyn Storage) -> Result<Option<Booster>> {
+    match recorded_preset(storage).await? {
+        Some(preset) if !preset.boosts().is_empty() => Booster::new(preset).map(Some),
+        _ => Ok(None),
+    }
+}
+
+/// A preset's boost rules, compiled
+pub struct Booster {
+    rules: Vec<(Regex, f64)>,
+}
+
+impl Booster {
+    pub fn new(preset: Preset) -> Result<Self> {
+        let rules = preset.boosts().iter()
+            .map(|rule| {
+                let regex = Regex::new(rule.pattern)
+                    .with_context(|| format!("Invalid boost pattern in the {} preset", preset.name()))?;
+                Ok((regex, rule.weight))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self { rules })
+    }
+
+    /// Total adjustment for a fragment; each rule counts once however often it matches
+    pub fn delta(&self, content: &str) -> f64 {
+        self.rules.iter()
+            .filter(|(regex, _)| regex.is_match(content))
+            .map(|(_, weight)| weight)
+            .sum()
+    }
+
+    /// Re-rank similarity search results by boosted score, best first, returning each with
+    /// its score replaced by the boosted one and the delta applied. Ties keep their original
+    /// order.
+    pub fn rerank(&self, results: Vec<FragmentMatch>) -> Vec<(FragmentMatch, f64)> {
+        let mut ranked: Vec<_> = results.into_iter()
+            .map(|fragment| {
+                let delta = self.delta(&fragment.content);
+                (FragmentMatch { score: fragment.score + delta, ..fragment }, delta)
+            })
+            .collect();
+        ranked.sort_by(|a, b| b.0.score.total_cmp(&a.0.score));
+        ranked
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::FragmentSource;
+
+    fn fragment(id: &str, content: &str, score: f64) -> FragmentMatch {
+        FragmentMatch {
+            fragment_id: id.to_string(),
+            content: content.to_string(),
+            score,
+            source: FragmentSource::default(),
+        }
+    }
+
+    #[test]
+    fn test_presets_round_trip_and_compile() {
+        for preset in Preset::value_variants() {
+            assert_eq!(Preset::from_name(preset.name()), Some(*preset));
+            Booster::new(*preset).unwrap();
+        }
+        assert_eq!(Preset::from_name("Papers"), Some(Preset::Papers));
+        assert_eq!(Preset::from_name("novels"), None);
+    }
+
+    #[test]
+    fn test_boosts_demote_reference_lists() {
+        let booster = Booster::new(Preset::Papers).unwrap();
+        assert!(booster.delta("Smith et al. (2019) Attention is all you need. NeurIPS.") < 0.0);
+        assert!(booster.delta("In conclusion, the method halves training time.") > 0.0);
+        assert_eq!(booster.delta("The dataset has 10k images."), 0.0);
+
+        let ranked = booster.rerank(vec![
+            fragment("ref", "Vaswani et al. (2017) Attention is all you need.", 0.80),
+            fragment("body", "Attention replaces recurrence in our model.", 0.78),
+        ]);
+        assert_eq!(ranked[0].0.fragment_id, "body");
+        assert!((ranked[1].0.score - 0.75).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,191 @@
+use anyhow::{Context, Result};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// Files listed by name in the profile summary
+const SLOWEST_FILES: usize = 10;
+
+/// Time spent on each stage of indexing one file
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct StageTimings {
+    /// Reading the original from disk, including the duplicate check
+    pub read: Duration,
+    /// Extracting its text, scanning it first when a scanner is configured
+    pub parse: Duration,
+    /// Splitting the text into fragments and packing it for re-chunking
+    pub chunk: Duration,
+    /// Writing the document to storage, or to the staging queue with `--staged`
+    pub store: Duration,
+}
+
+impl StageTimings {
+    /// Each stage with its name, in pipeline order
+    pub fn stages(&self) -> [(&'static str, Duration); 4] {
+        [("read", self.read), ("parse", self.parse), ("chunk", self.chunk), ("store", self.store)]
+    }
+
+    pub fn total(&self) -> Duration {
+        self.read + self.parse + self.chunk + self.store
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.total().is_zero()
+    }
+
+    /// Add the time since `started` to a stage, returning now for timing the next one
+    pub fn lap(stage: &mut Duration, started: Instant) -> Instant {
+        let now = Instant::now();
+        *stage += now - started;
+        now
+    }
+
+    /// The stages in milliseconds, for JSON progress events
+    pub fn to_json(self) -> Value {
+        let mut stages = json!({});
+        for (name, duration) in self.stages() {
+            stages[format!("{}_ms", name)] = json!(duration.as_secs_f64() * 1000.0);
+        }
+        stages
+    }
+
+    /// Add another attempt's or file's timings, stage by stage
+    pub fn add(&mut self, other: &StageTimings) {
+        self.read += other.read;
+        self.parse += other.parse;
+        self.chunk += other.chunk;
+        self.store += other.store;
+    }
+}
+
+/// Per-file stage timings collected over a run with `--profile-stages`, summed up by format
+/// and written as folded stacks for flamegraph tools
+#[derive(Debug)]
+pub struct Profiler {
+    output: PathBuf,
+    files: Vec<(PathBuf, StageTimings)>,
+}
+
+impl Profiler {
+    /// A profiler writing its folded stacks to `output`
+    pub fn new(output: PathBuf) -> Self {
+        Self { output, files: Vec::new() }
+    }
+
+    pub fn record(&mut self, path: &Path, timings: StageTimings) {
+        self.files.push((path.to_path_buf(), timings));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+
+    /// One `index;<format>;<file>;<stage> <microseconds>` line per stage of each file, the
+    /// folded format `inferno-flamegraph` and `flamegraph.pl` render
+    pub fn folded(&self) -> String {
+        let mut folded = String::new();
+        for (path, timings) in &self.files {
+            // Semicolons separate frames and the last space the count
+            let file = path.display().to_string().replace(';', "_");
+            for (stage, duration) in timings.stages() {
+                let micros = duration.as_micros();
+                if micros > 0 {
+                    folded.push_str(&format!("index;{};{};{} {}\n", format(path), file, stage, micros));
+                }
+            }
+        }
+        folded
+    }
+
+    /// Write the folded stacks to the output file
+    pub fn write(&self) -> Result<()> {
+        std::fs::write(&self.output, self.folded())
+            .with_context(|| format!("Failed to write the profile to {}", self.output.display()))
+    }
+
+    /// Stage totals per format, slowest format first, then the slowest files with the stage
+    /// that took them longest
+    pub fn summary(&self) -> String {
+        let mut formats: BTreeMap<String, (usize, StageTimings)> = BTreeMap::new();
+        let mut overall = StageTimings::default();
+        for (path, timings) in &self.files {
+            let entry = formats.entry(format(path)).or_default();
+            entry.0 += 1;
+            entry.1.add(timings);
+            overall.add(timings);
+        }
+        let mut formats: Vec<(String, (usize, StageTimings))> = formats.into_iter().collect();
+        formats.sort_by_key(|(_, (_, timings))| std::cmp::Reverse(timings.total()));
+
+        let mut summary = format!("\n⏱️  Stage timings for {} files ({}):\n", self.files.len(), seconds(overall.total()));
+        summary.push_str(&format!("   {:<8} {:>6} {:>9} {:>9} {:>9} {:>9} {:>9}\n", "format", "files", "read", "parse", "chunk", "store", "total"));
+        for (format, (files, timings)) in &formats {
+            summary.push_str(&format!("   {:<8} {:>6}", format, files));
+            for (_, duration) in timings.stages() {
+                summary.push_str(&format!(" {:>9}", seconds(duration)));
+            }
+            summary.push_str(&format!(" {:>9}\n", seconds(timings.total())));
+        }
+
+        let mut slowest: Vec<&(PathBuf, StageTimings)> = self.files.iter().collect();
+        slowest.sort_by_key(|(_, timings)| std::cmp::Reverse(timings.total()));
+        summary.push_str("   Slowest files:\n");
+        for (path, timings) in slowest.into_iter().take(SLOWEST_FILES) {
+            let (stage, duration) = timings.stages().into_iter().max_by_key(|(_, duration)| *duration).unwrap_or(("read", Duration::ZERO));
+            summary.push_str(&format!("   {:>9}  {} ({} {})\n", seconds(timings.total()), path.display(), stage, seconds(duration)));
+        }
+        summary
+    }
+
+    pub fn output(&self) -> &Path {
+        &self.output
+    }
+}
+
+/// A file's format as profiled: its extension in lower case
+fn format(path: &Path) -> String {
+    path.extension()
+        .map(|extension| extension.to_string_lossy().to_lowercase())
+        .unwrap_or_else(|| "none".to_string())
+}
+
+fn seconds(duration: Duration) -> String {
+    format!("{:.3}s", duration.as_secs_f64())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn timings(read: u64, parse: u64, chunk: u64, store: u64) -> StageTimings {
+        StageTimings {
+            read: Duration::from_millis(read),
+            parse: Duration::from_millis(parse),
+            chunk: Duration::from_millis(chunk),
+            store: Duration::from_millis(store),
+        }
+    }
+
+    #[test]
+    fn test_profile_folds_and_sums_by_format() {
+        let mut profiler = Profiler::new(PathBuf::from("profile.folded"));
+        profiler.record(Path::new("docs/a;b.PDF"), timings(1, 40, 2, 0));
+        profiler.record(Path::new("docs/notes.md"), timings(1, 1, 1, 1));
+        profiler.record(Path::new("docs/big.pdf"), timings(2, 900, 10, 5));
+
+        let folded = profiler.folded();
+        assert!(folded.starts_with("index;pdf;docs/a_b.PDF;read 1000\nindex;pdf;docs/a_b.PDF;parse 40000\n"));
+        assert!(!folded.contains("a_b.PDF;store"));
+        assert_eq!(folded.lines().count(), 11);
+
+        let summary = profiler.summary();
+        let pdf = summary.find("   pdf ").unwrap();
+        assert!(pdf < summary.find("   md ").unwrap());
+        assert!(summary.contains("0.940s"));
+        assert!(summary.contains("0.917s  docs/big.pdf (parse 0.900s)"));
+
+        assert_eq!(timings(1, 2, 3, 4).to_json()["parse_ms"], json!(2.0));
+        assert!(StageTimings::default().is_empty());
+    }
+}
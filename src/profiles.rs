@@ -0,0 +1,312 @@
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+/// Config file profiles are read from when `--config` isn't given, in the current directory
+pub const CONFIG_FILE: &str = "portablebrains.toml";
+
+/// Environment variable naming the config file profiles are read from when `--config`
+/// isn't given
+pub const CONFIG_VAR: &str = "PORTABLE_BRAINS_CONFIG";
+
+/// Environment variable naming the profile used when `--profile` isn't given
+pub const PROFILE_VAR: &str = "PORTABLE_BRAINS_PROFILE";
+
+/// Profile used when neither `--profile` nor the config file's `profile` names one
+const DEFAULT_PROFILE: &str = "default";
+
+/// Defaults for command-line flags, a `[profiles.<name>]` section of the config file. Flags
+/// given on the command line always win.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    /// Database to open; relative paths are relative to the config file
+    pub database: Option<PathBuf>,
+    /// Storage backend, `duckdb` or `lancedb`
+    pub backend: Option<String>,
+    /// Embedding model: `--model` of portable-brains, `--embedding-model` of eatmybrain
+    pub embedding_model: Option<String>,
+    /// `local`, `remote`, `ollama` or `compatible`
+    pub embedding_provider: Option<String>,
+    pub embedding_endpoint: Option<String>,
+    pub embedding_api_key: Option<String>,
+    pub requests_per_minute: Option<u32>,
+    /// Target fragment length when indexing and re-chunking
+    pub chunk_size: Option<usize>,
+    pub overlap: Option<usize>,
+    /// Largest file indexed, in megabytes
+    pub max_file_size: Option<u64>,
+    /// eatmybrain's language model
+    #[serde(default)]
+    pub llm: LlmProfile,
+}
+
+/// The `[profiles.<name>.llm]` table: eatmybrain's language model settings
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LlmProfile {
+    pub endpoint: Option<String>,
+    pub api_key: Option<String>,
+    /// API format: `openai`, `anthropic` or `custom`
+    pub provider: Option<String>,
+    pub model: Option<String>,
+    /// One of `--ai-model`'s presets, e.g. `claude3-haiku`
+    pub ai_model: Option<String>,
+    /// Passages retrieved per question
+    pub results: Option<usize>,
+}
+
+impl Profile {
+    /// The profile's settings as values of `program`'s long flags. `base` is the config
+    /// file's directory, which relative database paths are resolved against.
+    fn flags(&self, program: &str, base: &Path) -> Vec<(&'static str, OsString)> {
+        let number = |n: Option<usize>| n.map(|n| n.to_string());
+        let values: Vec<(&'static str, Option<String>)> = if program == "eatmybrain" {
+            vec![
+                ("backend", self.backend.clone()),
+                ("embedding-model", self.embedding_model.clone()),
+                ("embedding-provider", self.embedding_provider.clone()),
+                ("embedding-endpoint", self.embedding_endpoint.clone()),
+                ("embedding-api-key", self.embedding_api_key.clone()),
+                ("endpoint", self.llm.endpoint.clone()),
+                ("api-key", self.llm.api_key.clone()),
+                ("provider", self.llm.provider.clone()),
+                ("model", self.llm.model.clone()),
+                ("ai-model", self.llm.ai_model.clone()),
+                ("results", number(self.llm.results)),
+            ]
+        } else {
+            vec![
+                ("backend", self.backend.clone()),
+                ("model", self.embedding_model.clone()),
+                ("embedding-provider", self.embedding_provider.clone()),
+                ("endpoint", self.embedding_endpoint.clone()),
+                ("api-key", self.embedding_api_key.clone()),
+                ("requests-per-minute", self.requests_per_minute.map(|n| n.to_string())),
+                ("chunk-size", number(self.chunk_size)),
+                ("overlap", number(self.overlap)),
+                ("max-file-size", self.max_file_size.map(|n| n.to_string())),
+            ]
+        };
+
+        let database = self.database.as_ref().map(|path| base.join(path).into_os_string());
+        std::iter::once(("database", database))
+            .chain(values.into_iter().map(|(flag, value)| (flag, value.map(OsString::from))))
+            .filter_map(|(flag, value)| value.map(|value| (flag, value)))
+            .collect()
+    }
+}
+
+/// The parts of a config file that select and hold profiles; its other sections are read
+/// by the commands using them
+#[derive(Debug, Default, Deserialize)]
+struct ProfileFile {
+    /// Profile used when `--profile` isn't given
+    profile: Option<String>,
+    #[serde(default)]
+    profiles: BTreeMap<String, Profile>,
+}
+
+/// Pick the profile named `requested`, else the file's own choice, else one named
+/// `default`. A named profile that doesn't exist is an error.
+pub fn select<'a>(
+    profiles: &'a BTreeMap<String, Profile>,
+    requested: Option<&str>,
+    chosen: Option<&str>,
+) -> Result<Option<(&'a str, &'a Profile)>> {
+    let Some(name) = requested.or(chosen) else {
+        return Ok(profiles.get_key_value(DEFAULT_PROFILE).map(|(name, profile)| (name.as_str(), profile)));
+    };
+    profiles.get_key_value(name)
+        .map(|(name, profile)| Some((name.as_str(), profile)))
+        .ok_or_else(|| {
+            let known: Vec<&str> = profiles.keys().map(String::as_str).collect();
+            anyhow::anyhow!("No profile named '{}' (available: {})", name,
+                            if known.is_empty() { "none".to_string() } else { known.join(", ") })
+        })
+}
+
+/// The value of `--<name> <value>` or `--<name>=<value>` in `args`, before any `--`
+fn flag_value(args: &[OsString], name: &str) -> Option<OsString> {
+    let flag = format!("--{}", name);
+    let prefix = format!("--{}=", name);
+    let mut args = args.iter().take_while(|arg| *arg != "--");
+    while let Some(arg) = args.next() {
+        let text = arg.to_string_lossy();
+        if text == flag {
+            return args.next().cloned();
+        }
+        if let Some(value) = text.strip_prefix(&prefix) {
+            return Some(value.into());
+        }
+    }
+    None
+}
+
+/// Whether `args` already give the flag, by its long or short name
+fn gives(args: &[OsString], long: &str, short: Option<char>) -> bool {
+    let flag = format!("--{}", long);
+    let prefix = format!("--{}=", long);
+    args.iter()
+        .take_while(|arg| *arg != "--")
+        .map(|arg| arg.to_string_lossy())
+        .any(|arg| {
+            arg == flag || arg.starts_with(&prefix)
+                || short.is_some_and(|short| !arg.starts_with("--") && arg.starts_with(&format!("-{}", short)))
+        })
+}
+
+/// Fill in the flags `args` leave out from the selected profile, so config values apply
+/// and flags given always override them. Profiles come from `--config`, else the file
+/// `CONFIG_VAR` names, else `CONFIG_FILE` in the current directory; `--profile` or
+/// `PROFILE_VAR` picks one. `command` is the program's clap command: flags go to the
+/// subcommand named in `args`, or to the program itself when it has no subcommands, and
+/// only flags it accepts are added. Returns the arguments and the profile applied, e.g.
+/// `work (portablebrains.toml)`.
+pub fn apply(args: Vec<OsString>, command: &clap::Command) -> Result<(Vec<OsString>, Option<String>)> {
+    let requested = flag_value(&args, "profile")
+        .or_else(|| std::env::var_os(PROFILE_VAR))
+        .map(|name| name.to_string_lossy().into_owned());
+    let path = flag_value(&args, "config").map(PathBuf::from)
+        .or_else(|| std::env::var_os(CONFIG_VAR).map(PathBuf::from))
+        .or_else(|| Some(PathBuf::from(CONFIG_FILE)).filter(|path| path.is_file()));
+    let Some(path) = path else {
+        if let Some(name) = requested {
+            anyhow::bail!("Profile '{}' needs a config file: pass --config or create {}", name, CONFIG_FILE);
+        }
+        return Ok((args, None));
+    };
+
+    let text = std::fs::read_to_string(&path)
+        .with_context(|| format!("Failed to read config file {}", path.display()))?;
+    let file: ProfileFile = toml::from_str(&text)
+        .with_context(|| format!("Invalid config file {}", path.display()))?;
+    let Some((name, profile)) = select(&file.profiles, requested.as_deref(), file.profile.as_deref())
+        .with_context(|| format!("In config file {}", path.display()))? else {
+        return Ok((args, None));
+    };
+    let applied = Some(format!("{} ({})", name, path.display()));
+
+    // Flags are inserted right after the (sub)command's name, before any nested subcommand
+    let (target, start) = if command.get_subcommands().next().is_none() {
+        (command, 1)
+    } else {
+        let mut skip_value = false;
+        let found = args.iter().enumerate().skip(1).find_map(|(i, arg)| {
+            let text = arg.to_string_lossy();
+            if std::mem::take(&mut skip_value) {
+                return None;
+            }
+            if text == "--profile" {
+                skip_value = true;
+                return None;
+            }
+            if text.starts_with('-') {
+                return None;
+            }
+            Some(command.find_subcommand(arg).map(|subcommand| (subcommand, i + 1)))
+        });
+        match found.flatten() {
+            Some(found) => found,
+            // Help, version or a mistyped subcommand, which clap reports
+            None => return Ok((args, applied)),
+        }
+    };
+
+    let base = path.parent().unwrap_or(Path::new(""));
+    let given = &args[start..];
+    let mut defaults = Vec::new();
+    for (long, value) in profile.flags(command.get_name(), base) {
+        let Some(arg) = target.get_arguments().find(|arg| arg.get_long() == Some(long)) else {
+            continue;
+        };
+        if gives(given, long, arg.get_short()) {
+            continue;
+        }
+        let mut flag = OsString::from(format!("--{}=", long));
+        flag.push(&value);
+        defaults.push(flag);
+    }
+
+    let mut args = args;
+    args.splice(start..start, defaults);
+    Ok((args, applied))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::{Arg, ArgAction, Command};
+
+    fn program() -> Command {
+        Command::new("portable-brains")
+            .arg(Arg::new("profile").long("profile").global(true))
+            .subcommand(Command::new("index")
+                .arg(Arg::new("database").short('d').long("database"))
+                .arg(Arg::new("model").short('m').long("model"))
+                .arg(Arg::new("chunk-size").long("chunk-size"))
+                .arg(Arg::new("config").long("config")))
+            .subcommand(Command::new("info")
+                .arg(Arg::new("database").short('d').long("database"))
+                .arg(Arg::new("verbose").long("verbose").action(ArgAction::SetTrue))
+                .arg(Arg::new("config").long("config")))
+    }
+
+    fn args(list: &[&str]) -> Vec<OsString> {
+        list.iter().map(OsString::from).collect()
+    }
+
+    fn config(text: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("pb-profiles-{}-{}", std::process::id(), text.len()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(CONFIG_FILE);
+        std::fs::write(&path, text).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_profile_fills_missing_flags_only() {
+        let path = config("[profiles.default]\ndatabase = \"notes.db\"\nembedding_model = \"BAAI/bge-small-en-v1.5\"\nchunk_size = 600\n");
+        let config = path.to_string_lossy().into_owned();
+        let (applied, profile) = apply(args(&["portable-brains", "index", "-m", "other", "--config", &config]), &program()).unwrap();
+        assert_eq!(profile, Some(format!("default ({})", config)));
+        let database = format!("--database={}", path.parent().unwrap().join("notes.db").display());
+        assert_eq!(applied, args(&["portable-brains", "index", &database, "--chunk-size=600", "-m", "other", "--config", &config]));
+
+        // Only flags the subcommand accepts are added
+        let (applied, _) = apply(args(&["portable-brains", "info", "--config", &config]), &program()).unwrap();
+        assert_eq!(applied, args(&["portable-brains", "info", &database, "--config", &config]));
+    }
+
+    #[test]
+    fn test_named_profile_must_exist() {
+        let path = config("profile = \"work\"\n[profiles.work]\nbackend = \"lancedb\"\n[profiles.home]\nchunk_size = 400\n");
+        let profiles: ProfileFile = toml::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(select(&profiles.profiles, None, profiles.profile.as_deref()).unwrap().unwrap().0, "work");
+        assert_eq!(select(&profiles.profiles, Some("home"), Some("work")).unwrap().unwrap().0, "home");
+        let error = select(&profiles.profiles, Some("travel"), None).unwrap_err().to_string();
+        assert!(error.contains("travel") && error.contains("home, work"));
+    }
+
+    #[test]
+    fn test_eatmybrain_flags() {
+        let profile = Profile {
+            embedding_model: Some("nomic-embed-text".to_string()),
+            embedding_provider: Some("ollama".to_string()),
+            llm: LlmProfile { model: Some("llama3".to_string()), results: Some(8), ..LlmProfile::default() },
+            ..Profile::default()
+        };
+        let flags: Vec<(&str, String)> = profile.flags("eatmybrain", Path::new(""))
+            .into_iter()
+            .map(|(flag, value)| (flag, value.to_string_lossy().into_owned()))
+            .collect();
+        assert_eq!(flags, vec![
+            ("embedding-model", "nomic-embed-text".to_string()),
+            ("embedding-provider", "ollama".to_string()),
+            ("model", "llama3".to_string()),
+            ("results", "8".to_string()),
+        ]);
+    }
+}
@@ -0,0 +1,440 @@
+use clap::ValueEnum;
+use console::Term;
+use indicatif::{HumanDuration, MultiProgress, ProgressBar, ProgressStyle};
+use serde_json::{json, Value};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::error::{self, PortableBrainsError};
+use crate::profiler::{Profiler, StageTimings};
+
+/// Shortest time between two progress lines in plain mode
+const PLAIN_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How index, watch and embed runs report their progress, chosen with `--progress`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum ProgressMode {
+    /// Progress bars with throughput and ETA; plain lines when not writing to a terminal
+    #[default]
+    Bars,
+    /// A line per file, and an embedding progress line every few seconds
+    Plain,
+    /// Newline-delimited JSON events on standard output, for scripts wrapping a run
+    Json,
+}
+
+/// What became of one file
+#[derive(Debug, Clone, PartialEq)]
+pub enum FileOutcome {
+    /// Indexed or staged, with a description of what was stored
+    Succeeded(String),
+    /// Left alone, with why
+    Skipped(String),
+    /// Failed, with the kind of failure and its message
+    Failed { kind: &'static str, message: String },
+}
+
+impl FileOutcome {
+    /// The outcome of a file that failed with `e`
+    pub fn failed(e: &anyhow::Error) -> Self {
+        let typed = error::find(e);
+        FileOutcome::Failed {
+            kind: typed.map_or("other", PortableBrainsError::kind),
+            message: typed.map_or_else(|| format!("{:#}", e), ToString::to_string),
+        }
+    }
+
+    fn status(&self) -> &'static str {
+        match self {
+            FileOutcome::Succeeded(_) => "succeeded",
+            FileOutcome::Skipped(_) => "skipped",
+            FileOutcome::Failed { .. } => "failed",
+        }
+    }
+}
+
+/// The event reporting a file's outcome in JSON mode, with the time each stage took when
+/// they were timed
+fn file_event(path: &Path, outcome: &FileOutcome, elapsed: Duration, stages: &StageTimings) -> Value {
+    let mut event = json!({
+        "event": "file",
+        "path": path.display().to_string(),
+        "status": outcome.status(),
+        "elapsed_ms": elapsed.as_millis() as u64,
+    });
+    if !stages.is_empty() {
+        event["stages"] = stages.to_json();
+    }
+    match outcome {
+        FileOutcome::Succeeded(detail) | FileOutcome::Skipped(detail) => event["detail"] = json!(detail),
+        FileOutcome::Failed { kind, message } => {
+            event["kind"] = json!(kind);
+            event["detail"] = json!(message);
+        }
+    }
+    event
+}
+
+/// Reports the phases of a run as bars, lines or JSON events, and sums up what became of
+/// each file at the end
+pub struct Progress {
+    mode: ProgressMode,
+    bars: MultiProgress,
+    /// Files or fragments of the current phase
+    overall: Option<ProgressBar>,
+    /// The file being worked on
+    current: Option<ProgressBar>,
+    phase: &'static str,
+    total: u64,
+    done: u64,
+    phase_started: Instant,
+    /// Whether `start_file` began the file now being finished
+    started: bool,
+    file_started: Instant,
+    /// Stage timings reported for the file being worked on
+    stages: StageTimings,
+    /// Collects every file's stage timings with `--profile-stages`
+    profiler: Option<Profiler>,
+    /// When plain mode last printed a progress line
+    reported: Instant,
+    succeeded: usize,
+    skipped: Vec<(PathBuf, String)>,
+    failed: Vec<(PathBuf, &'static str, String)>,
+}
+
+impl Progress {
+    pub fn new(mode: ProgressMode) -> Self {
+        // Bars redrawn into a log file or pipe would only garble it
+        let mode = match mode {
+            ProgressMode::Bars if !Term::stderr().is_term() => ProgressMode::Plain,
+            mode => mode,
+        };
+        let now = Instant::now();
+        Self {
+            mode,
+            bars: MultiProgress::new(),
+            overall: None,
+            current: None,
+            phase: "",
+            total: 0,
+            done: 0,
+            phase_started: now,
+            started: false,
+            file_started: now,
+            stages: StageTimings::default(),
+            profiler: None,
+            reported: now,
+            succeeded: 0,
+            skipped: Vec::new(),
+            failed: Vec::new(),
+        }
+    }
+
+    /// Collect the stage timings of every file, summed up by `summary` and written to `output`
+    /// as folded stacks
+    pub fn with_profiler(mut self, output: Option<PathBuf>) -> Self {
+        self.profiler = output.map(Profiler::new);
+        self
+    }
+
+    /// Record how long the stages of the file being worked on took; a file tried again
+    /// adds up its attempts
+    pub fn record_stages(&mut self, stages: &StageTimings) {
+        self.stages.add(stages);
+    }
+
+    pub fn is_json(&self) -> bool {
+        self.mode == ProgressMode::Json
+    }
+
+    fn emit(&self, event: Value) {
+        println!("{}", event);
+    }
+
+    /// Print a line of the run's output without tearing the bars
+    pub fn println(&self, line: impl AsRef<str>) {
+        match self.mode {
+            ProgressMode::Bars if self.overall.is_some() => {
+                let _ = self.bars.println(line.as_ref());
+            }
+            ProgressMode::Json => self.emit(json!({"event": "message", "text": line.as_ref()})),
+            _ => println!("{}", line.as_ref()),
+        }
+    }
+
+    /// Run `f`, which writes to the terminal itself, with the bars out of the way
+    pub fn suspend<R>(&self, f: impl FnOnce() -> R) -> R {
+        self.bars.suspend(f)
+    }
+
+    /// Begin a phase of `total` files or fragments; `phase` names it in JSON events and
+    /// `label` on its bar
+    pub fn start_phase(&mut self, phase: &'static str, label: &str, total: u64) {
+        self.finish_phase();
+        self.phase = phase;
+        self.total = total;
+        self.done = 0;
+        self.phase_started = Instant::now();
+        self.reported = self.phase_started;
+        match self.mode {
+            ProgressMode::Bars => {
+                let bar = self.bars.add(ProgressBar::new(total));
+                bar.set_style(
+                    ProgressStyle::with_template("{prefix:>12.bold} [{wide_bar:.cyan/blue}] {human_pos}/{human_len} ({per_sec}, ETA {eta})")
+                        .expect("valid progress template")
+                        .progress_chars("=> "),
+                );
+                bar.set_prefix(label.to_string());
+                self.overall = Some(bar);
+            }
+            ProgressMode::Plain => {}
+            ProgressMode::Json => self.emit(json!({"event": "phase", "phase": phase, "total": total})),
+        }
+    }
+
+    /// Begin work on a file
+    pub fn start_file(&mut self, path: &Path) {
+        self.started = true;
+        self.file_started = Instant::now();
+        self.stages = StageTimings::default();
+        let name = file_name(path);
+        match self.mode {
+            ProgressMode::Bars => {
+                let bar = self.bars.add(ProgressBar::new_spinner());
+                bar.set_style(ProgressStyle::with_template("  {spinner} {msg} ({elapsed})").expect("valid progress template"));
+                bar.set_message(name);
+                bar.enable_steady_tick(Duration::from_millis(120));
+                self.current = Some(bar);
+            }
+            ProgressMode::Plain => {
+                print!("📝 [{}/{}] {}... ", self.done + 1, self.total, name);
+                let _ = std::io::stdout().flush();
+            }
+            ProgressMode::Json => self.emit(json!({"event": "file_started", "path": path.display().to_string()})),
+        }
+    }
+
+    /// Note that the current file is being tried again after `reason`
+    pub fn retry(&mut self, path: &Path, reason: &str, attempt: u32, attempts: u32) {
+        match self.mode {
+            ProgressMode::Bars => {
+                if let Some(bar) = &self.current {
+                    bar.set_message(format!("{} ⏳ {}; retrying ({}/{})", file_name(path), reason, attempt, attempts));
+                }
+            }
+            ProgressMode::Plain => {
+                print!("⏳ {}; retrying ({}/{})... ", reason, attempt, attempts);
+                let _ = std::io::stdout().flush();
+            }
+            ProgressMode::Json => self.emit(json!({
+                "event": "retry",
+                "path": path.display().to_string(),
+                "reason": reason,
+                "attempt": attempt,
+                "attempts": attempts,
+            })),
+        }
+    }
+
+    /// Record what became of a file, started or not, and count it towards the phase
+    pub fn finish_file(&mut self, path: &Path, outcome: FileOutcome) {
+        let elapsed = if self.started { self.file_started.elapsed() } else { Duration::ZERO };
+        self.done += 1;
+        let line = match &outcome {
+            FileOutcome::Succeeded(detail) => format!("✅ {} ({})", file_name(path), detail),
+            FileOutcome::Skipped(reason) => format!("⏭️  {}: {}", file_name(path), reason),
+            FileOutcome::Failed { message, .. } => format!("❌ {}: {}", file_name(path), message),
+        };
+        match self.mode {
+            ProgressMode::Bars => {
+                if let Some(bar) = self.current.take() {
+                    bar.finish_and_clear();
+                    self.bars.remove(&bar);
+                }
+                self.println(line);
+                if let Some(bar) = &self.overall {
+                    bar.inc(1);
+                }
+            }
+            // A started file's name is already on the line
+            ProgressMode::Plain if self.started => match &outcome {
+                FileOutcome::Succeeded(detail) => println!("✅ Success! ({})", detail),
+                FileOutcome::Skipped(reason) => println!("⏭️  Skipped: {}", reason),
+                FileOutcome::Failed { message, .. } => println!("❌ Failed: {}", message),
+            },
+            ProgressMode::Plain => println!("{}", line),
+            ProgressMode::Json => self.emit(file_event(path, &outcome, elapsed, &self.stages)),
+        }
+        self.started = false;
+        let stages = std::mem::take(&mut self.stages);
+        if let Some(profiler) = self.profiler.as_mut().filter(|_| !stages.is_empty()) {
+            profiler.record(path, stages);
+        }
+        match outcome {
+            FileOutcome::Succeeded(_) => self.succeeded += 1,
+            FileOutcome::Skipped(reason) => self.skipped.push((path.to_path_buf(), reason)),
+            FileOutcome::Failed { kind, message } => self.failed.push((path.to_path_buf(), kind, message)),
+        }
+    }
+
+    /// Count `count` more fragments of the phase as done
+    pub fn advance(&mut self, count: u64) {
+        self.done += count;
+        let elapsed = self.phase_started.elapsed();
+        let per_sec = self.done as f64 / elapsed.as_secs_f64().max(0.001);
+        let eta = (per_sec > 0.0).then(|| Duration::from_secs_f64(self.total.saturating_sub(self.done) as f64 / per_sec));
+        match self.mode {
+            ProgressMode::Bars => {
+                if let Some(bar) = &self.overall {
+                    bar.inc(count);
+                }
+            }
+            ProgressMode::Plain => {
+                if self.reported.elapsed() >= PLAIN_INTERVAL || self.done >= self.total {
+                    self.reported = Instant::now();
+                    let eta = eta.map_or_else(|| "unknown".to_string(), |eta| HumanDuration(eta).to_string());
+                    println!(
+                        "⚡ {}: {}/{} ({:.1}%, {:.1}/s, ETA {})",
+                        self.phase, self.done, self.total,
+                        self.done as f64 * 100.0 / self.total.max(1) as f64, per_sec, eta,
+                    );
+                }
+            }
+            ProgressMode::Json => self.emit(json!({
+                "event": "progress",
+                "phase": self.phase,
+                "done": self.done,
+                "total": self.total,
+                "per_sec": per_sec,
+                "eta_secs": eta.map(|eta| eta.as_secs()),
+            })),
+        }
+    }
+
+    /// End the current phase, clearing its bars
+    pub fn finish_phase(&mut self) {
+        if self.phase.is_empty() {
+            return;
+        }
+        if let Some(bar) = self.current.take() {
+            bar.finish_and_clear();
+        }
+        if let Some(bar) = self.overall.take() {
+            bar.finish_and_clear();
+            let _ = self.bars.clear();
+        }
+        if self.mode == ProgressMode::Json {
+            self.emit(json!({
+                "event": "phase_finished",
+                "phase": self.phase,
+                "done": self.done,
+                "elapsed_ms": self.phase_started.elapsed().as_millis() as u64,
+            }));
+        }
+        self.phase = "";
+    }
+
+    /// Sum up what became of the files: a table of how many succeeded, were skipped and
+    /// failed, then the skipped and failed ones with why, failures grouped by kind. Nothing
+    /// is printed when no file was handled.
+    pub fn summary(&mut self) {
+        self.finish_phase();
+        self.profile();
+        let handled = self.succeeded + self.skipped.len() + self.failed.len();
+        if handled == 0 {
+            return;
+        }
+        if self.mode == ProgressMode::Json {
+            self.emit(json!({
+                "event": "summary",
+                "succeeded": self.succeeded,
+                "skipped": self.skipped.iter()
+                    .map(|(path, reason)| json!({"path": path.display().to_string(), "reason": reason}))
+                    .collect::<Vec<_>>(),
+                "failed": self.failed.iter()
+                    .map(|(path, kind, message)| json!({"path": path.display().to_string(), "kind": kind, "message": message}))
+                    .collect::<Vec<_>>(),
+            }));
+            return;
+        }
+
+        println!("\n📋 {} documents:", handled);
+        println!("   {:<10} {:>6}", "succeeded", self.succeeded);
+        println!("   {:<10} {:>6}", "skipped", self.skipped.len());
+        println!("   {:<10} {:>6}", "failed", self.failed.len());
+        if !self.skipped.is_empty() {
+            println!("   Skipped:");
+            for (path, reason) in &self.skipped {
+                println!("      {}: {}", path.display(), reason);
+            }
+        }
+        let mut kinds: Vec<&str> = self.failed.iter().map(|(_, kind, _)| *kind).collect();
+        kinds.sort_unstable();
+        kinds.dedup();
+        for kind in kinds {
+            let failed: Vec<_> = self.failed.iter().filter(|(_, k, _)| *k == kind).collect();
+            println!("   Failed, {} ({}):", kind, failed.len());
+            for (path, _, message) in failed {
+                println!("      {}: {}", path.display(), message);
+            }
+        }
+    }
+
+    /// Print the stage timings collected since the last summary and write them as folded
+    /// stacks, replacing the file a previous summary wrote
+    fn profile(&mut self) {
+        let Some(profiler) = self.profiler.as_mut().filter(|profiler| !profiler.is_empty()) else {
+            return;
+        };
+        let written = profiler.write();
+        let output = profiler.output().display().to_string();
+        let summary = profiler.summary();
+        *profiler = Profiler::new(profiler.output().to_path_buf());
+        match self.mode {
+            ProgressMode::Json => {}
+            _ => print!("{}", summary),
+        }
+        match written {
+            Ok(()) => self.println(format!("🔥 Stage timings written to {} as folded stacks (render with inferno-flamegraph or flamegraph.pl)", output)),
+            Err(e) => self.println(format!("⚠️  {:#}", e)),
+        }
+    }
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_else(|| path.display().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_file_events() {
+        let path = Path::new("docs/report.pdf");
+        let event = file_event(path, &FileOutcome::Succeeded("12 fragments".to_string()), Duration::from_millis(40), &StageTimings::default());
+        assert_eq!(event, json!({"event": "file", "path": "docs/report.pdf", "status": "succeeded", "detail": "12 fragments", "elapsed_ms": 40}));
+        let stages = StageTimings { parse: Duration::from_millis(30), ..StageTimings::default() };
+        let event = file_event(path, &FileOutcome::Succeeded("12 fragments".to_string()), Duration::from_millis(40), &stages);
+        assert_eq!(event["stages"]["parse_ms"], json!(30.0));
+
+        let error = anyhow::Error::from(PortableBrainsError::DocumentProcessingError("no text".to_string()));
+        let outcome = FileOutcome::failed(&error.context("Failed to extract text"));
+        let event = file_event(path, &outcome, Duration::ZERO, &StageTimings::default());
+        assert_eq!(event["status"], "failed");
+        assert_eq!(event["kind"], "extraction");
+    }
+
+    #[test]
+    fn test_outcomes_are_counted() {
+        let mut progress = Progress::new(ProgressMode::Plain);
+        progress.start_phase("extract", "Extracting", 3);
+        progress.finish_file(Path::new("a.txt"), FileOutcome::Succeeded("1 fragments".to_string()));
+        progress.finish_file(Path::new("b.txt"), FileOutcome::Skipped("already indexed".to_string()));
+        progress.finish_file(Path::new("c.txt"), FileOutcome::Failed { kind: "extraction", message: "no text".to_string() });
+        assert_eq!((progress.done, progress.succeeded, progress.skipped.len(), progress.failed.len()), (3, 1, 1, 1));
+    }
+}
@@ -0,0 +1,134 @@
+use serde::{Deserialize, Serialize};
+
+/// Share of common words below which extracted text is probably not language
+const MIN_DICTIONARY_RATIO: f64 = 0.1;
+/// Share of control or replacement characters above which the text is probably a binary mis-parse
+const MAX_CONTROL_DENSITY: f64 = 0.01;
+/// Average sentence length, in words, above which punctuation was probably lost
+const MAX_SENTENCE_WORDS: f64 = 100.0;
+/// Texts with fewer words than this are too short to judge by word statistics
+const MIN_WORDS: usize = 30;
+
+/// Frequent English words; clean prose is typically 40-50% these, bad OCR almost none
+const COMMON_WORDS: &[&str] = &[
+    "a", "about", "after", "all", "also", "an", "and", "any", "are", "as", "at", "be",
+    "because", "been", "but", "by", "can", "could", "do", "each", "for", "from", "had",
+    "has", "have", "he", "her", "his", "how", "i", "if", "in", "into", "is", "it", "its",
+    "may", "more", "most", "must", "new", "no", "not", "of", "on", "one", "only", "or",
+    "other", "our", "out", "over", "shall", "she", "should", "so", "some", "such", "than",
+    "that", "the", "their", "them", "then", "there", "these", "they", "this", "those",
+    "through", "to", "two", "under", "up", "upon", "us", "was", "we", "were", "what",
+    "when", "where", "which", "while", "who", "will", "with", "would", "you", "your",
+];
+
+/// Heuristic measures of how well text was extracted from a document
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ExtractionQuality {
+    /// Share of words that are common English words
+    pub dictionary_ratio: f64,
+    /// Share of characters that are control characters or U+FFFD replacements
+    pub control_density: f64,
+    /// Average number of words per sentence
+    pub avg_sentence_words: f64,
+    /// Overall score from 0 (garbage) to 1 (clean prose)
+    pub score: f64,
+    /// Why the extraction looks wrong; empty when it looks fine
+    pub flags: Vec<String>,
+}
+
+impl ExtractionQuality {
+    pub fn is_flagged(&self) -> bool {
+        !self.flags.is_empty()
+    }
+}
+
+/// Score extracted text for signs of bad OCR or a binary file parsed as text.
+///
+/// The dictionary check is tuned for English, so documents in other languages can be
+/// flagged for it; the flag reasons say which check fired.
+pub fn assess(text: &str) -> ExtractionQuality {
+    let characters = text.chars().count();
+    if text.trim().is_empty() {
+        return ExtractionQuality {
+            dictionary_ratio: 0.0,
+            control_density: 0.0,
+            avg_sentence_words: 0.0,
+            score: 0.0,
+            flags: vec!["no text extracted".to_string()],
+        };
+    }
+
+    let control = text.chars()
+        .filter(|&c| (c.is_control() && !matches!(c, '\n' | '\r' | '\t')) || c == '\u{FFFD}')
+        .count();
+    let control_density = control as f64 / characters as f64;
+
+    let words: Vec<String> = text
+        .split(|c: char| !c.is_alphanumeric() && c != '\'')
+        .filter(|token| token.chars().any(char::is_alphabetic))
+        .map(str::to_lowercase)
+        .collect();
+    let common = words.iter().filter(|word| COMMON_WORDS.contains(&word.as_str())).count();
+    let dictionary_ratio = if words.is_empty() { 0.0 } else { common as f64 / words.len() as f64 };
+
+    // Sentences end at terminal punctuation or a blank line, so headings and list items count too
+    let sentences = text
+        .split(['.', '!', '?'])
+        .flat_map(|part| part.split("\n\n"))
+        .filter(|part| part.chars().any(char::is_alphabetic))
+        .count()
+        .max(1);
+    let avg_sentence_words = words.len() as f64 / sentences as f64;
+
+    let judged_by_words = words.len() >= MIN_WORDS;
+    let mut flags = Vec::new();
+    if judged_by_words && dictionary_ratio < MIN_DICTIONARY_RATIO {
+        flags.push(format!("only {:.0}% dictionary words", dictionary_ratio * 100.0));
+    }
+    if control_density > MAX_CONTROL_DENSITY {
+        flags.push(format!("{:.1}% control characters", control_density * 100.0));
+    }
+    if judged_by_words && avg_sentence_words > MAX_SENTENCE_WORDS {
+        flags.push(format!("average sentence of {:.0} words", avg_sentence_words));
+    }
+
+    let dictionary_score = if judged_by_words { (dictionary_ratio / (MIN_DICTIONARY_RATIO * 3.0)).min(1.0) } else { 1.0 };
+    let control_score = (1.0 - control_density / (MAX_CONTROL_DENSITY * 5.0)).max(0.0);
+    let sentence_score = if judged_by_words { (MAX_SENTENCE_WORDS / 2.0 / avg_sentence_words).min(1.0) } else { 1.0 };
+
+    ExtractionQuality {
+        dictionary_ratio,
+        control_density,
+        avg_sentence_words,
+        score: dictionary_score * control_score * sentence_score,
+        flags,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_clean_prose_is_not_flagged() {
+        let text = "The committee reviewed the budget for the coming year. It found that most of the \
+                    spending was in line with the plan, and it recommended that the board approve it. \
+                    Two items were deferred until the next meeting, when more information will be available.";
+        let quality = assess(text);
+        assert!(!quality.is_flagged(), "{:?}", quality.flags);
+        assert!(quality.score > 0.9);
+    }
+
+    #[test]
+    fn test_garbage_extraction_is_flagged() {
+        let ocr = "Tlie cornrnittee rcvicwcd tbe buclgct fcr tbc corning ycar lt fouud tbat rnost ".repeat(5);
+        let quality = assess(&ocr);
+        assert!(quality.flags.iter().any(|f| f.contains("dictionary words")));
+        assert!(quality.score < 0.5);
+
+        let binary = "PK\u{3}\u{4}\u{14}\u{0}\u{6}\u{0}\u{8}\u{0}\u{FFFD}\u{FFFD}word/document.xml\u{FFFD}";
+        assert!(assess(binary).flags.iter().any(|f| f.contains("control characters")));
+
+        assert_eq!(assess("  \n ").flags, vec!["no text extracted"]);
+    }
+}
@@ -0,0 +1,1624 @@
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use log::info;
+use reqwest::{Method, StatusCode};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::sync::{Arc, OnceLock};
+use uuid::Uuid;
+
+use crate::annotations::{Annotation, AnnotationLabel};
+use crate::entities;
+use crate::error::PortableBrainsError;
+use crate::hybrid::bm25;
+use crate::offline;
+use crate::paths::StoredPath;
+use crate::storage::arrow::array::{Array, ArrayRef, BooleanArray, Float32Array, Int32Array, ListArray, RecordBatch, StringArray};
+use crate::storage::arrow::datatypes::Float32Type;
+use crate::storage::{check_dimension, chunk_hash, conform_fragment_batch, content_hash, decode_original, fragment_column, fragment_schema, modified_micros, parse_dimension, sort_file_types, sort_largest, sort_ranked, validate_fragment, CorpusStats, DocumentAttributes, DocumentInfo, DocumentSize, DocumentSummary, EntityCount, EntityMention, FragmentChanges, FragmentMatch, FragmentMeta, FragmentRecord, FragmentSource, IdScheme, IndexState, Location, MetaInfo, OriginalsMode, PendingFragment, SearchFilter, SimilarityMetric, Storage, Structure, DETERMINISTIC_IDS_KEY, DIMENSION_KEY, PROVIDER_KEY, SIMILARITY_METRIC_KEY, STORE_ORIGINALS_KEY};
+
+const DB_VERSION: &str = "1.0.0";
+
+/// Environment variable holding the server's API key when `--remote-api-key` isn't given
+pub const API_KEY_VAR: &str = "QDRANT_API_KEY";
+
+/// Points fetched or written per request
+const PAGE_SIZE: usize = 1000;
+
+/// Namespace of the ids of points whose records have no UUID of their own, such as meta keys
+const POINT_NAMESPACE: Uuid = Uuid::from_u128(0x5d2c_8f41_a7e3_4b90_9c16_e04b_73a8_21f5);
+
+/// Kinds of record held in a brain's collection, told apart by their `kind` field
+const DOCUMENT: &str = "document";
+const FRAGMENT: &str = "fragment";
+const META: &str = "meta";
+const ANNOTATION: &str = "annotation";
+
+/// Payload fields of a brain's collection looked up by value
+const RECORD_INDEXES: &[(&str, &str)] = &[
+    ("kind", "keyword"),
+    ("id", "keyword"),
+    ("document_id", "keyword"),
+    ("file_path", "keyword"),
+    ("content_hash", "keyword"),
+    ("chunk_hash", "keyword"),
+    ("state", "keyword"),
+    ("target", "keyword"),
+    ("stale", "bool"),
+    ("set_aside", "bool"),
+];
+
+/// Payload fields of a vector collection that searches filter on
+const VECTOR_INDEXES: &[(&str, &str)] = &[("document_id", "keyword"), ("stale", "bool")];
+
+/// Document payload fields too large to fetch when only a document's attributes are wanted
+const DOCUMENT_BLOBS: &[&str] = &["original", "text"];
+
+static API_KEY: OnceLock<String> = OnceLock::new();
+
+/// Send `key` to remote storage servers for the rest of the process; without one, the key
+/// in `QDRANT_API_KEY` is sent, if set
+pub fn configure(key: Option<String>) {
+    if let Some(key) = key {
+        let _ = API_KEY.set(key);
+    }
+}
+
+/// Whether `database` names a remote brain (`http://` or `https://`) rather than a file
+pub fn is_remote(database: &Path) -> bool {
+    database.to_str().is_some_and(|url| url.starts_with("http://") || url.starts_with("https://"))
+}
+
+/// Name of the remote brain at `database`, the last segment of its URL
+pub fn brain_name(database: &Path) -> Option<String> {
+    split_url(database.to_str()?).map(|(_, name)| name)
+}
+
+/// Split a remote brain's URL into the server's address and the brain's name, e.g.
+/// `http://qdrant:6333/team` into `http://qdrant:6333` and `team`
+fn split_url(url: &str) -> Option<(String, String)> {
+    let mut server = reqwest::Url::parse(url).ok().filter(|url| matches!(url.scheme(), "http" | "https"))?;
+    let mut segments: Vec<String> = server.path_segments()?
+        .filter(|segment| !segment.is_empty())
+        .map(str::to_string)
+        .collect();
+    let name = segments.pop()?;
+    server.set_path(&segments.join("/"));
+    server.set_query(None);
+    server.set_fragment(None);
+    Some((server.as_str().trim_end_matches('/').to_string(), name))
+}
+
+/// Id of the point holding the record `id` of `kind`: the record's own id when it is a UUID,
+/// as document and fragment ids are, so points page in the order of their records' ids
+fn point_id(kind: &str, id: &str) -> String {
+    match Uuid::parse_str(id) {
+        Ok(uuid) if kind != META => uuid.hyphenated().to_string(),
+        _ => Uuid::new_v5(&POINT_NAMESPACE, format!("{}\0{}", kind, id).as_bytes()).to_string(),
+    }
+}
+
+/// Condition that `key` equals `value`
+fn field(key: &str, value: impl Serialize) -> Value {
+    json!({"key": key, "match": {"value": value}})
+}
+
+/// Condition that `key` is one of `values`
+fn any_of(key: &str, values: &[String]) -> Value {
+    json!({"key": key, "match": {"any": values}})
+}
+
+/// Condition that `key` is unset
+fn unset(key: &str) -> Value {
+    json!({"is_empty": {"key": key}})
+}
+
+/// Filter for records of `kind` meeting every condition in `must`
+fn of_kind(kind: &str, mut must: Vec<Value>) -> Value {
+    must.insert(0, field("kind", kind));
+    json!({"must": must})
+}
+
+/// Filter for fragments with no current vector that aren't set aside
+fn pending_fragments() -> Value {
+    json!({
+        "must": [field("kind", FRAGMENT)],
+        "should": [unset("dimension"), field("stale", true)],
+        "must_not": [field("set_aside", true)],
+    })
+}
+
+/// Filter for fragments with a current vector
+fn embedded_fragments() -> Value {
+    json!({
+        "must": [field("kind", FRAGMENT)],
+        "must_not": [unset("dimension"), field("stale", true)],
+    })
+}
+
+fn filename(file_path: &str) -> String {
+    Path::new(file_path)
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+/// A point as the server returns it
+#[derive(Debug, Deserialize)]
+struct Point {
+    #[serde(default)]
+    payload: Value,
+    #[serde(default)]
+    vector: Option<Vec<f32>>,
+    #[serde(default)]
+    score: f64,
+}
+
+impl Point {
+    /// Id of the record the point holds
+    fn record_id(&self) -> Option<String> {
+        self.payload.get("id").and_then(Value::as_str).map(str::to_string)
+    }
+
+    fn parse<T: DeserializeOwned>(self) -> Result<T> {
+        serde_json::from_value(self.payload).context("Malformed record in remote brain")
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ScrollPage {
+    points: Vec<Point>,
+    next_page_offset: Option<Value>,
+}
+
+/// A document's record; fields left out of a partial read take their defaults
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+struct DocumentPayload {
+    id: String,
+    file_path: String,
+    /// The stored original as encoded by `OriginalsMode::encode`, in base64
+    original: String,
+    encoding: Option<String>,
+    /// Length of the stored original
+    bytes: u64,
+    content_hash: Option<String>,
+    modified: Option<i64>,
+    created_at: String,
+    state: Option<String>,
+    priority: i32,
+    category: Option<String>,
+    category_score: Option<f64>,
+    collection: Option<String>,
+    tags: Vec<String>,
+    quality_score: Option<f64>,
+    quality_flags: Vec<String>,
+    title: Option<String>,
+    tombstoned: bool,
+    /// Compressed extracted text, in base64
+    text: Option<String>,
+}
+
+impl DocumentPayload {
+    /// When the document was indexed, in microseconds since the Unix epoch
+    fn indexed(&self) -> Option<i64> {
+        chrono::DateTime::parse_from_rfc3339(&self.created_at).ok().map(|created| created.timestamp_micros())
+    }
+}
+
+/// A fragment's record; its vector is held in the vector collection of its length
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+struct FragmentPayload {
+    id: String,
+    document_id: String,
+    order: i32,
+    segment: u32,
+    section: Option<String>,
+    page: Option<u32>,
+    structure: Option<Structure>,
+    location: Option<Location>,
+    content: String,
+    chars: u64,
+    chunk_hash: String,
+    /// Length of the fragment's vector; `None` until the embed phase has run
+    dimension: Option<usize>,
+    stale: bool,
+    hits: u32,
+    failure: Option<String>,
+    attempts: i32,
+    set_aside: bool,
+}
+
+/// What a search needs besides the fragments themselves to filter and cite them
+struct SearchContext {
+    documents: HashMap<String, DocumentPayload>,
+    annotations: Vec<Annotation>,
+}
+
+impl SearchContext {
+    /// Whether a reviewer marked `target` wrong
+    fn marked_wrong(&self, target: &str) -> bool {
+        self.annotations.iter().any(|annotation| annotation.target == target && annotation.label == Some(AnnotationLabel::Wrong))
+    }
+
+    /// Ids of the documents a search with `filter` may return fragments of
+    fn searchable_documents(&self, filter: &SearchFilter) -> Vec<String> {
+        let mut ids: Vec<String> = self.documents.values()
+            .filter(|document| {
+                !document.tombstoned
+                    && !self.marked_wrong(&document.id)
+                    && (filter.categories.is_empty()
+                        || document.category.as_ref().is_some_and(|category| filter.categories.contains(category)))
+                    && (filter.collections.is_empty()
+                        || document.collection.as_ref().is_some_and(|collection| filter.collections.contains(collection)))
+                    && filter.document.as_ref().is_none_or(|wanted| *wanted == document.id)
+                    && filter.matches_document(&document.tags, &document.file_path, document.modified.or(document.indexed()))
+            })
+            .map(|document| document.id.clone())
+            .collect();
+        ids.sort();
+        ids
+    }
+
+    /// Whether a search with `filter` may return this fragment of a searchable document
+    fn searchable(&self, fragment: &FragmentPayload, filter: &SearchFilter) -> bool {
+        !self.marked_wrong(&fragment.id)
+            && filter.section.as_ref().is_none_or(|wanted| {
+                fragment.section.as_ref().is_some_and(|section| section.to_lowercase().contains(&wanted.to_lowercase()))
+            })
+            && filter.entity.as_ref().is_none_or(|wanted| {
+                let wanted = entities::key(wanted);
+                entities::extract(&fragment.content).iter().any(|name| entities::matches(&entities::key(name), &wanted))
+            })
+    }
+
+    /// A fragment as a search result with `score`
+    fn fragment_match(&self, fragment: &FragmentPayload, score: f64) -> FragmentMatch {
+        let document = self.documents.get(&fragment.document_id);
+        let file_path = document.map(|document| document.file_path.clone()).unwrap_or_default();
+        FragmentMatch {
+            fragment_id: fragment.id.clone(),
+            content: fragment.content.clone(),
+            score,
+            source: FragmentSource {
+                document_id: fragment.document_id.clone(),
+                filename: filename(&file_path),
+                file_path,
+                title: document.and_then(|document| document.title.clone()),
+                order: fragment.order,
+                section: fragment.section.clone(),
+                page: fragment.page,
+                structure: fragment.structure,
+                location: fragment.location.clone(),
+                modified: document.and_then(|document| document.modified),
+                indexed: document.and_then(DocumentPayload::indexed),
+                annotations: self.annotations.iter()
+                    .filter(|annotation| annotation.target == fragment.id || annotation.target == fragment.document_id)
+                    .map(Annotation::summary)
+                    .collect(),
+            },
+        }
+    }
+}
+
+/// A brain held by a Qdrant server, so several indexers can feed one brain and chats can
+/// query it over the network. Documents, fragments, metadata and annotations are points
+/// without vectors in the collection named after the brain; fragment vectors are kept in a
+/// collection per vector length, `<name>_vectors_<length>`, so vectors a model upgrade left
+/// stale stay searchable until they are replaced.
+pub struct RemoteStorage {
+    server: String,
+    name: String,
+    client: reqwest::Client,
+    api_key: Option<String>,
+    /// Vector lengths whose collection is known to exist
+    dimensions: HashSet<usize>,
+    ids: IdScheme,
+    originals: OriginalsMode,
+    metric: SimilarityMetric,
+}
+
+impl RemoteStorage {
+    /// Connect to the brain at `url`, `http(s)://<server>/<name>`, creating it on the
+    /// server if it doesn't exist yet
+    pub async fn connect(url: &str) -> Result<Self> {
+        let (server, name) = split_url(url).ok_or_else(|| anyhow::anyhow!(
+            "Remote brains are named by a URL ending in the brain's name, e.g. http://localhost:6333/team; got {}",
+            url
+        ))?;
+        if !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+            anyhow::bail!("Remote brain names may only use letters, digits, '-' and '_'; got '{}'", name);
+        }
+        offline::check(&server, "Remote storage")?;
+
+        let mut storage = RemoteStorage {
+            server,
+            name,
+            client: offline::client(),
+            api_key: API_KEY.get().cloned().or_else(|| std::env::var(API_KEY_VAR).ok()).filter(|key| !key.is_empty()),
+            dimensions: HashSet::new(),
+            ids: IdScheme::Random,
+            originals: OriginalsMode::Full,
+            metric: SimilarityMetric::Cosine,
+        };
+        storage.initialize().await?;
+        Ok(storage)
+    }
+
+    fn vectors(&self, dimension: usize) -> String {
+        format!("{}_vectors_{}", self.name, dimension)
+    }
+
+    /// Make a request of the server, returning the `result` of its reply, or `None` when
+    /// what the request names doesn't exist. An unreachable or overloaded server fails as
+    /// busy, since the same request may succeed later.
+    async fn send(&self, method: Method, path: &str, body: Option<Value>) -> Result<Option<Value>> {
+        let mut request = self.client.request(method, format!("{}/{}", self.server, path));
+        if let Some(key) = &self.api_key {
+            request = request.header("api-key", key);
+        }
+        if let Some(body) = &body {
+            request = request.json(body);
+        }
+
+        let response = match request.send().await {
+            Ok(response) => response,
+            Err(e) if e.is_connect() || e.is_timeout() => {
+                return Err(PortableBrainsError::DatabaseBusy(format!("Qdrant at {} is unreachable: {}", self.server, e)).into());
+            }
+            Err(e) => {
+                return Err(PortableBrainsError::DatabaseError(format!("Request to Qdrant at {} failed: {}", self.server, e)).into());
+            }
+        };
+        let status = response.status();
+        if status == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        let reply: Value = response.json().await.unwrap_or(Value::Null);
+        if !status.is_success() {
+            let reason = reply.pointer("/status/error").and_then(Value::as_str)
+                .unwrap_or_else(|| status.canonical_reason().unwrap_or("request failed"));
+            let message = format!("Qdrant at {} answered {} to {}: {}", self.server, status.as_u16(), path, reason);
+            let error = match status.as_u16() {
+                429 | 502 | 503 | 504 => PortableBrainsError::DatabaseBusy(message),
+                _ => PortableBrainsError::DatabaseError(message),
+            };
+            return Err(error.into());
+        }
+        Ok(Some(reply.get("result").cloned().unwrap_or(Value::Null)))
+    }
+
+    /// Make a request of something that must exist
+    async fn call(&self, method: Method, path: &str, body: Option<Value>) -> Result<Value> {
+        self.send(method, path, body).await?.ok_or_else(|| {
+            PortableBrainsError::DatabaseError(format!("Qdrant at {} has no {}", self.server, path)).into()
+        })
+    }
+
+    /// Create `collection` with `vectors` unless it exists, indexing the payload `indexes`
+    async fn ensure_collection(&self, collection: &str, vectors: Value, indexes: &[(&str, &str)]) -> Result<()> {
+        let path = format!("collections/{}", collection);
+        if self.send(Method::GET, &path, None).await?.is_some() {
+            return Ok(());
+        }
+        if let Err(e) = self.call(Method::PUT, &path, Some(json!({"vectors": vectors}))).await {
+            // Another indexer may have created it first
+            return match self.send(Method::GET, &path, None).await? {
+                Some(_) => Ok(()),
+                None => Err(e),
+            };
+        }
+        for (field_name, schema) in indexes {
+            self.call(
+                Method::PUT,
+                &format!("{}/index?wait=true", path),
+                Some(json!({"field_name": field_name, "field_schema": schema})),
+            ).await?;
+        }
+        info!("Created Qdrant collection {}", collection);
+        Ok(())
+    }
+
+    /// Create the collection for vectors of `dimension` unless it exists
+    async fn ensure_vectors(&mut self, dimension: usize) -> Result<()> {
+        if self.dimensions.contains(&dimension) {
+            return Ok(());
+        }
+        let distance = match self.metric {
+            SimilarityMetric::Cosine => "Cosine",
+            SimilarityMetric::Dot => "Dot",
+            SimilarityMetric::Euclidean => "Euclid",
+        };
+        self.ensure_collection(&self.vectors(dimension), json!({"size": dimension, "distance": distance}), VECTOR_INDEXES).await?;
+        self.dimensions.insert(dimension);
+        Ok(())
+    }
+
+    /// Lengths of the vectors the brain holds, one collection each
+    async fn vector_lengths(&self) -> Result<Vec<usize>> {
+        let result = self.call(Method::GET, "collections", None).await?;
+        let prefix = format!("{}_vectors_", self.name);
+        Ok(result["collections"].as_array().into_iter().flatten()
+            .filter_map(|collection| collection["name"].as_str()?.strip_prefix(&prefix)?.parse().ok())
+            .collect())
+    }
+
+    /// Points of `collection` matching `filter` in id order, from the point `from` on and
+    /// at most `limit` of them
+    async fn scroll(&self, collection: &str, filter: Value, with_payload: Value, from: Option<String>, limit: Option<usize>) -> Result<Vec<Point>> {
+        let mut points = Vec::new();
+        let mut offset = from.map(Value::from);
+        loop {
+            let page = limit.map_or(PAGE_SIZE, |limit| limit.saturating_sub(points.len()).min(PAGE_SIZE));
+            if page == 0 {
+                break;
+            }
+            let body = json!({"filter": filter, "limit": page, "offset": offset, "with_payload": with_payload, "with_vector": false});
+            let Some(result) = self.send(Method::POST, &format!("collections/{}/points/scroll", collection), Some(body)).await? else {
+                break;
+            };
+            let page: ScrollPage = serde_json::from_value(result).context("Malformed scroll reply from Qdrant")?;
+            points.extend(page.points);
+            offset = page.next_page_offset.filter(|offset| !offset.is_null());
+            if offset.is_none() {
+                break;
+            }
+        }
+        Ok(points)
+    }
+
+    /// The points of `collection` with these ids that exist
+    async fn retrieve(&self, collection: &str, point_ids: &[String], with_payload: Value, with_vector: bool) -> Result<Vec<Point>> {
+        let mut points = Vec::new();
+        for ids in point_ids.chunks(PAGE_SIZE) {
+            let body = json!({"ids": ids, "with_payload": with_payload, "with_vector": with_vector});
+            if let Some(result) = self.send(Method::POST, &format!("collections/{}/points", collection), Some(body)).await? {
+                points.extend(serde_json::from_value::<Vec<Point>>(result).context("Malformed points from Qdrant")?);
+            }
+        }
+        Ok(points)
+    }
+
+    async fn count(&self, filter: Value) -> Result<i32> {
+        let path = format!("collections/{}/points/count", self.name);
+        let result = self.call(Method::POST, &path, Some(json!({"filter": filter, "exact": true}))).await?;
+        Ok(result["count"].as_u64().unwrap_or(0) as i32)
+    }
+
+    async fn upsert(&self, collection: &str, points: Vec<Value>) -> Result<()> {
+        for batch in points.chunks(PAGE_SIZE) {
+            let path = format!("collections/{}/points?wait=true", collection);
+            self.call(Method::PUT, &path, Some(json!({"points": batch}))).await?;
+        }
+        Ok(())
+    }
+
+    /// Set `payload` fields of the points of `collection` that `selector` picks, either
+    /// `{"points": [...]}` or `{"filter": {...}}`
+    async fn set_payload(&self, collection: &str, mut selector: Value, payload: Value) -> Result<()> {
+        selector["payload"] = payload;
+        self.call(Method::POST, &format!("collections/{}/points/payload?wait=true", collection), Some(selector)).await?;
+        Ok(())
+    }
+
+    /// Set fields of the document `document_id`; nothing happens when it doesn't exist
+    async fn set_document(&self, document_id: &str, payload: Value) -> Result<()> {
+        let filter = of_kind(DOCUMENT, vec![field("id", document_id)]);
+        self.set_payload(&self.name, json!({"filter": filter}), payload).await
+    }
+
+    /// Delete the points of `collection` that `selector` picks; a missing collection has
+    /// none to delete
+    async fn delete(&self, collection: &str, selector: Value) -> Result<()> {
+        self.send(Method::POST, &format!("collections/{}/points/delete?wait=true", collection), Some(selector)).await?;
+        Ok(())
+    }
+
+    /// Delete fragments together with their vectors
+    async fn delete_fragments(&self, fragment_ids: &[String]) -> Result<()> {
+        if fragment_ids.is_empty() {
+            return Ok(());
+        }
+        let points: Vec<String> = fragment_ids.iter().map(|id| point_id(FRAGMENT, id)).collect();
+        for dimension in self.vector_lengths().await? {
+            self.delete(&self.vectors(dimension), json!({"points": points})).await?;
+        }
+        self.delete(&self.name, json!({"points": points})).await
+    }
+
+    /// A record of `kind` as a point without a vector
+    fn record_point(kind: &str, id: &str, record: &impl Serialize) -> Result<Value> {
+        let mut payload = serde_json::to_value(record)?;
+        payload["kind"] = json!(kind);
+        Ok(json!({"id": point_id(kind, id), "vector": {}, "payload": payload}))
+    }
+
+    /// Every record of `kind` meeting `conditions`, with the payload `fields`
+    async fn records<T: DeserializeOwned>(&self, kind: &str, conditions: Vec<Value>, fields: Value) -> Result<Vec<T>> {
+        self.scroll(&self.name, of_kind(kind, conditions), fields, None, None).await?
+            .into_iter()
+            .map(Point::parse)
+            .collect()
+    }
+
+    /// The records of `kind` with these ids that exist, with the payload `fields`
+    async fn records_by_id<T: DeserializeOwned>(&self, kind: &str, ids: &[String], fields: Value) -> Result<Vec<T>> {
+        let point_ids: Vec<String> = ids.iter().map(|id| point_id(kind, id)).collect();
+        self.retrieve(&self.name, &point_ids, fields, false).await?
+            .into_iter()
+            .map(Point::parse)
+            .collect()
+    }
+
+    async fn document(&self, document_id: &str, fields: Value) -> Result<Option<DocumentPayload>> {
+        Ok(self.records_by_id(DOCUMENT, &[document_id.to_string()], fields).await?.pop())
+    }
+
+    async fn fragments_by_id(&self, fragment_ids: &[String], fields: Value) -> Result<HashMap<String, FragmentPayload>> {
+        let fragments: Vec<FragmentPayload> = self.records_by_id(FRAGMENT, fragment_ids, fields).await?;
+        Ok(fragments.into_iter().map(|fragment| (fragment.id.clone(), fragment)).collect())
+    }
+
+    /// Every document without its original or text, by id
+    async fn catalog(&self) -> Result<HashMap<String, DocumentPayload>> {
+        let documents: Vec<DocumentPayload> = self.records(DOCUMENT, Vec::new(), json!({"exclude": DOCUMENT_BLOBS})).await?;
+        Ok(documents.into_iter().map(|document| (document.id.clone(), document)).collect())
+    }
+
+    /// Vectors of the embedded `fragments`, by fragment id
+    async fn vectors_of(&self, fragments: &[FragmentPayload]) -> Result<HashMap<String, Vec<f32>>> {
+        let mut by_length: HashMap<usize, Vec<String>> = HashMap::new();
+        for fragment in fragments {
+            if let Some(dimension) = fragment.dimension {
+                by_length.entry(dimension).or_default().push(point_id(FRAGMENT, &fragment.id));
+            }
+        }
+        let mut vectors = HashMap::new();
+        for (dimension, point_ids) in by_length {
+            for point in self.retrieve(&self.vectors(dimension), &point_ids, json!(["id"]), true).await? {
+                if let (Some(id), Some(vector)) = (point.record_id(), point.vector) {
+                    vectors.insert(id, vector);
+                }
+            }
+        }
+        Ok(vectors)
+    }
+
+    async fn search_context(&self) -> Result<SearchContext> {
+        Ok(SearchContext {
+            documents: self.catalog().await?,
+            annotations: self.annotations(None).await?,
+        })
+    }
+
+    async fn annotations(&self, target: Option<&str>) -> Result<Vec<Annotation>> {
+        let conditions = target.map(|target| vec![field("target", target)]).unwrap_or_default();
+        let mut annotations: Vec<Annotation> = self.records(ANNOTATION, conditions, json!(true)).await?;
+        annotations.sort_by(|a, b| a.created.cmp(&b.created).then_with(|| a.id.cmp(&b.id)));
+        Ok(annotations)
+    }
+
+    /// Fragments naming entities, with the entities each names, skipping tombstoned documents
+    async fn fragment_entities(&self) -> Result<Vec<(String, Vec<String>)>> {
+        let tombstoned: Vec<String> = self.records::<DocumentPayload>(DOCUMENT, vec![field("tombstoned", true)], json!(["id"])).await?
+            .into_iter()
+            .map(|document| document.id)
+            .collect();
+        let mut filter = of_kind(FRAGMENT, Vec::new());
+        if !tombstoned.is_empty() {
+            filter["must_not"] = json!([any_of("document_id", &tombstoned)]);
+        }
+        let fragments = self.scroll(&self.name, filter, json!(["document_id", "content"]), None, None).await?;
+        fragments.into_iter()
+            .map(|point| {
+                let fragment: FragmentPayload = point.parse()?;
+                Ok((fragment.document_id, entities::extract(&fragment.content)))
+            })
+            .collect()
+    }
+
+    async fn get_meta(&self, key: &str) -> Result<Option<String>> {
+        let points = self.retrieve(&self.name, &[point_id(META, key)], json!(["value"]), false).await?;
+        Ok(points.into_iter().next().and_then(|point| point.payload["value"].as_str().map(str::to_string)))
+    }
+
+    async fn set_meta(&self, key: &str, value: &str) -> Result<()> {
+        let point = Self::record_point(META, key, &json!({"key": key, "value": value}))?;
+        self.upsert(&self.name, vec![point]).await
+    }
+}
+
+#[async_trait]
+impl Storage for RemoteStorage {
+    async fn initialize(&mut self) -> Result<()> {
+        self.ensure_collection(&self.name, json!({}), RECORD_INDEXES).await?;
+        if self.get_meta("version").await?.is_none() {
+            self.set_meta("version", DB_VERSION).await?;
+        }
+        self.ids = IdScheme::from_meta(self.get_meta(DETERMINISTIC_IDS_KEY).await?.as_deref());
+        self.originals = OriginalsMode::from_meta(self.get_meta(STORE_ORIGINALS_KEY).await?.as_deref());
+        self.metric = SimilarityMetric::from_meta(self.get_meta(SIMILARITY_METRIC_KEY).await?.as_deref());
+
+        info!("Remote storage initialized: brain {} on {}", self.name, self.server);
+        Ok(())
+    }
+
+    async fn verify_or_set_model(&mut self, model_name: &str, dimension: Option<usize>) -> Result<()> {
+        match self.get_meta("version").await? {
+            Some(existing_version) if existing_version != DB_VERSION => {
+                anyhow::bail!(
+                    "Database version mismatch. Expected: {}, Found: {}",
+                    DB_VERSION, existing_version
+                );
+            }
+            Some(_) => {}
+            None => {
+                self.set_meta("version", DB_VERSION).await?;
+                info!("Set database version to {}", DB_VERSION);
+            }
+        }
+
+        match self.get_meta("embedding_model").await? {
+            Some(existing_model) if existing_model != model_name => {
+                anyhow::bail!(
+                    "Embedding model mismatch. Expected: {}, Found: {}",
+                    model_name, existing_model
+                );
+            }
+            Some(_) => info!("Verified embedding model: {}", model_name),
+            None => {
+                self.set_meta("embedding_model", model_name).await?;
+                info!("Set embedding model to {}", model_name);
+            }
+        }
+
+        if let Some(dimension) = dimension {
+            check_dimension(self, model_name, dimension).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn enable_deterministic_ids(&mut self) -> Result<()> {
+        self.set_meta(DETERMINISTIC_IDS_KEY, "true").await?;
+        self.ids = IdScheme::Content;
+        Ok(())
+    }
+
+    async fn set_originals_mode(&mut self, mode: OriginalsMode) -> Result<()> {
+        self.set_meta(STORE_ORIGINALS_KEY, mode.name()).await?;
+        self.originals = mode;
+        Ok(())
+    }
+
+    async fn set_similarity_metric(&mut self, metric: SimilarityMetric) -> Result<()> {
+        // Qdrant fixes a collection's distance when it is created
+        if metric != self.metric && !self.vector_lengths().await?.is_empty() {
+            anyhow::bail!(
+                "Brain {} already holds vectors compared by {}; the metric of a remote brain can only be chosen before anything is embedded",
+                self.name, self.metric.name()
+            );
+        }
+        self.set_meta(SIMILARITY_METRIC_KEY, metric.name()).await?;
+        self.metric = metric;
+        Ok(())
+    }
+
+    async fn document_exists(&mut self, file_path: &Path) -> Result<bool> {
+        let path = StoredPath::new(file_path);
+        let keys = [path.key, path.legacy_key];
+        Ok(self.count(of_kind(DOCUMENT, vec![any_of("file_path", &keys)])).await? > 0)
+    }
+
+    async fn find_duplicate(&mut self, content_hash: &str) -> Result<Option<String>> {
+        let documents: Vec<DocumentPayload> = self.records(
+            DOCUMENT,
+            vec![field("content_hash", content_hash), field("tombstoned", false)],
+            json!(["file_path"]),
+        ).await?;
+        Ok(documents.into_iter().map(|document| document.file_path).min())
+    }
+
+    async fn store_document(&mut self, file_path: &Path, file_data: &[u8]) -> Result<String> {
+        let path = StoredPath::new(file_path);
+        let document_id = self.ids.document_id(&path.filename, file_data);
+
+        let (blob, encoding) = self.originals.encode(file_data)?;
+        let document = DocumentPayload {
+            id: document_id.clone(),
+            file_path: path.key,
+            bytes: blob.len() as u64,
+            original: BASE64.encode(&blob),
+            encoding: encoding.map(str::to_string),
+            content_hash: Some(content_hash(file_data)),
+            modified: modified_micros(file_path),
+            created_at: chrono::Utc::now().to_rfc3339(),
+            state: Some(IndexState::Stored.name().to_string()),
+            ..DocumentPayload::default()
+        };
+        self.upsert(&self.name, vec![Self::record_point(DOCUMENT, &document_id, &document)?]).await?;
+
+        Ok(document_id)
+    }
+
+    async fn set_index_state(&mut self, document_id: &str, state: IndexState) -> Result<()> {
+        self.set_document(document_id, json!({"state": state.name()})).await
+    }
+
+    async fn list_index_state(&mut self, state: IndexState) -> Result<Vec<String>> {
+        let documents: Vec<DocumentPayload> = self.records(DOCUMENT, vec![field("state", state.name())], json!(["id"])).await?;
+        let mut ids: Vec<String> = documents.into_iter().map(|document| document.id).collect();
+        ids.sort();
+        Ok(ids)
+    }
+
+    async fn set_document_source(&mut self, document_id: &str, content_hash: &str, modified: Option<i64>) -> Result<()> {
+        self.set_document(document_id, json!({"content_hash": content_hash, "modified": modified})).await
+    }
+
+    async fn set_document_priority(&mut self, document_id: &str, priority: i32) -> Result<()> {
+        self.set_document(document_id, json!({"priority": priority})).await
+    }
+
+    async fn set_document_category(&mut self, document_id: &str, category: &str, score: f64) -> Result<()> {
+        self.set_document(document_id, json!({"category": category, "category_score": score})).await
+    }
+
+    async fn set_document_collection(&mut self, document_id: &str, collection: Option<&str>, tags: &[String]) -> Result<()> {
+        self.set_document(document_id, json!({"collection": collection, "tags": tags})).await
+    }
+
+    async fn get_document_collection(&mut self, document_id: &str) -> Result<Option<String>> {
+        Ok(self.document(document_id, json!(["collection"])).await?.and_then(|document| document.collection))
+    }
+
+    async fn get_document_attributes(&mut self, document_id: &str) -> Result<Option<DocumentAttributes>> {
+        let fields = json!(["priority", "category", "category_score", "collection", "tags"]);
+        Ok(self.document(document_id, fields).await?.map(|document| DocumentAttributes {
+            priority: document.priority,
+            category: document.category.map(|category| (category, document.category_score.unwrap_or(0.0))),
+            collection: document.collection,
+            tags: document.tags,
+        }))
+    }
+
+    async fn set_document_quality(&mut self, document_id: &str, score: f64, flags: &[String]) -> Result<()> {
+        self.set_document(document_id, json!({"quality_score": score, "quality_flags": flags})).await
+    }
+
+    async fn set_document_title(&mut self, document_id: &str, title: &str) -> Result<()> {
+        self.set_document(document_id, json!({"title": title})).await
+    }
+
+    async fn tombstone_document(&mut self, document_id: &str, tombstoned: bool) -> Result<()> {
+        self.set_document(document_id, json!({"tombstoned": tombstoned})).await
+    }
+
+    async fn remove_document(&mut self, document_id: &str) -> Result<()> {
+        let fragments: Vec<FragmentPayload> = self.records(FRAGMENT, vec![field("document_id", document_id)], json!(["id"])).await?;
+        let mut targets: Vec<String> = fragments.into_iter().map(|fragment| fragment.id).collect();
+        self.delete_fragments(&targets).await?;
+
+        targets.push(document_id.to_string());
+        self.delete(&self.name, json!({"filter": of_kind(ANNOTATION, vec![any_of("target", &targets)])})).await?;
+        self.delete(&self.name, json!({"points": [point_id(DOCUMENT, document_id)]})).await
+    }
+
+    async fn list_documents(&mut self) -> Result<Vec<DocumentSummary>> {
+        let mut fragments_per_document: HashMap<String, i32> = HashMap::new();
+        for fragment in self.records::<FragmentPayload>(FRAGMENT, Vec::new(), json!(["document_id"])).await? {
+            *fragments_per_document.entry(fragment.document_id).or_default() += 1;
+        }
+
+        let mut documents: Vec<DocumentSummary> = self.catalog().await?.into_values()
+            .map(|document| DocumentSummary {
+                filename: filename(&document.file_path),
+                fragments: fragments_per_document.get(&document.id).copied().unwrap_or(0),
+                id: document.id,
+                file_path: document.file_path,
+                quality_score: document.quality_score,
+                quality_flags: document.quality_flags,
+                tombstoned: document.tombstoned,
+                content_hash: document.content_hash,
+                modified: document.modified,
+                title: document.title,
+            })
+            .collect();
+        documents.sort_by(|a, b| a.file_path.cmp(&b.file_path));
+        Ok(documents)
+    }
+
+    async fn get_document(&mut self, document_id: &str) -> Result<Option<DocumentInfo>> {
+        let Some(document) = self.document(document_id, json!({"exclude": ["text"]})).await? else {
+            return Ok(None);
+        };
+        let blob = BASE64.decode(&document.original).context("Malformed original in remote brain")?;
+        let file_data = decode_original(document.encoding.as_deref(), blob)?;
+        let path = Path::new(&document.file_path);
+        Ok(Some(DocumentInfo {
+            id: document.id.clone(),
+            filename: filename(&document.file_path),
+            file_path: document.file_path.clone(),
+            file_type: path.extension()
+                .map(|ext| ext.to_string_lossy().to_lowercase())
+                .unwrap_or_else(|| "unknown".to_string()),
+            file_data,
+            created_at: Some(document.created_at),
+        }))
+    }
+
+    async fn set_document_text(&mut self, document_id: &str, text: &[u8]) -> Result<()> {
+        self.set_document(document_id, json!({"text": BASE64.encode(text)})).await
+    }
+
+    async fn get_document_text(&mut self, document_id: &str) -> Result<Option<Vec<u8>>> {
+        let Some(text) = self.document(document_id, json!(["text"])).await?.and_then(|document| document.text) else {
+            return Ok(None);
+        };
+        Ok(Some(BASE64.decode(text).context("Malformed text in remote brain")?))
+    }
+
+    async fn replace_fragments(
+        &mut self,
+        document_id: &str,
+        fragments: &[(String, FragmentMeta)],
+    ) -> Result<FragmentChanges> {
+        // Existing fragments by text, lowest order popped first, so unchanged chunks keep
+        // their id and vector
+        let mut existing: Vec<FragmentPayload> = self.records(
+            FRAGMENT,
+            vec![field("document_id", document_id)],
+            json!(["id", "order", "content"]),
+        ).await?;
+        existing.sort_by_key(|fragment| std::cmp::Reverse(fragment.order));
+        let mut by_content: HashMap<String, Vec<String>> = HashMap::new();
+        for fragment in existing {
+            by_content.entry(fragment.content).or_default().push(fragment.id);
+        }
+
+        let mut changes = FragmentChanges::default();
+        let mut placements = Vec::new();
+        for (order, (content, meta)) in fragments.iter().enumerate() {
+            let reused = by_content.get_mut(content).and_then(|ids| ids.pop());
+            placements.push((order as i32, content, meta, reused));
+        }
+
+        let removed: Vec<String> = by_content.into_values().flatten().collect();
+        self.delete_fragments(&removed).await?;
+        changes.removed = removed.len();
+
+        for (order, content, meta, reused) in placements {
+            match reused {
+                Some(id) => {
+                    let placement = json!({
+                        "order": order,
+                        "segment": meta.segment,
+                        "section": meta.section,
+                        "page": meta.page,
+                        "structure": meta.structure,
+                        "location": meta.location,
+                    });
+                    self.set_payload(&self.name, json!({"points": [point_id(FRAGMENT, &id)]}), placement).await?;
+                    changes.kept += 1;
+                }
+                None => {
+                    self.store_text_fragment(document_id, order, content, meta).await?;
+                    changes.added += 1;
+                }
+            }
+        }
+
+        Ok(changes)
+    }
+
+    async fn get_document_fragments(&mut self, document_id: &str, offset: i32, limit: i32) -> Result<Vec<String>> {
+        let mut fragments: Vec<FragmentPayload> = self.records(
+            FRAGMENT,
+            vec![field("document_id", document_id)],
+            json!(["order", "content"]),
+        ).await?;
+        fragments.sort_by_key(|fragment| fragment.order);
+
+        Ok(fragments.into_iter()
+            .skip(offset.max(0) as usize)
+            .take(limit.max(0) as usize)
+            .map(|fragment| fragment.content)
+            .collect())
+    }
+
+    async fn get_fragment_records(&mut self, document_id: &str) -> Result<Vec<FragmentRecord>> {
+        let fragments: Vec<FragmentPayload> = self.records(
+            FRAGMENT,
+            vec![field("document_id", document_id)],
+            json!(["id", "order", "section", "content", "dimension", "stale"]),
+        ).await?;
+        let mut vectors = self.vectors_of(&fragments).await?;
+
+        let mut records: Vec<FragmentRecord> = fragments.into_iter()
+            .map(|fragment| FragmentRecord {
+                embedding: vectors.remove(&fragment.id).map(|vector| vector.into_iter().map(f64::from).collect()),
+                id: fragment.id,
+                order: fragment.order,
+                section: fragment.section,
+                content: fragment.content,
+                stale: fragment.stale,
+            })
+            .collect();
+        records.sort_by_key(|record| record.order);
+        Ok(records)
+    }
+
+    async fn read_fragment_batch(&mut self, after: Option<&str>, limit: usize) -> Result<Option<RecordBatch>> {
+        // Scrolling starts at the point given, so one more is read in case it is `after`
+        let from = after.map(|after| point_id(FRAGMENT, after));
+        let points = self.scroll(&self.name, of_kind(FRAGMENT, Vec::new()), json!(true), from, Some(limit + 1)).await?;
+        let mut fragments: Vec<FragmentPayload> = points.into_iter().map(Point::parse).collect::<Result<_>>()?;
+        if after.is_some_and(|after| fragments.first().is_some_and(|first| first.id == after)) {
+            fragments.remove(0);
+        }
+        fragments.truncate(limit);
+        if fragments.is_empty() {
+            return Ok(None);
+        }
+
+        let vectors = self.vectors_of(&fragments).await?;
+        let columns: Vec<ArrayRef> = vec![
+            Arc::new(StringArray::from_iter_values(fragments.iter().map(|fragment| &fragment.id))),
+            Arc::new(StringArray::from_iter_values(fragments.iter().map(|fragment| &fragment.document_id))),
+            Arc::new(Int32Array::from_iter_values(fragments.iter().map(|fragment| fragment.order))),
+            Arc::new(Int32Array::from_iter_values(fragments.iter().map(|fragment| fragment.segment as i32))),
+            Arc::new(fragments.iter().map(|fragment| fragment.section.as_deref()).collect::<StringArray>()),
+            Arc::new(fragments.iter().map(|fragment| fragment.page.map(|page| page as i32)).collect::<Int32Array>()),
+            Arc::new(fragments.iter().map(|fragment| fragment.structure.map(|s| s.as_str())).collect::<StringArray>()),
+            Arc::new(fragments.iter().map(|fragment| fragment.location.as_ref().map(Location::label)).collect::<StringArray>()),
+            Arc::new(StringArray::from_iter_values(fragments.iter().map(|fragment| &fragment.content))),
+            Arc::new(ListArray::from_iter_primitive::<Float32Type, _, _>(
+                fragments.iter().map(|fragment| vectors.get(&fragment.id).map(|vector| vector.iter().copied().map(Some))),
+            )),
+            Arc::new(BooleanArray::from(fragments.iter().map(|fragment| fragment.stale).collect::<Vec<_>>())),
+        ];
+        Ok(Some(RecordBatch::try_new(fragment_schema(), columns)?))
+    }
+
+    async fn write_fragment_batch(&mut self, batch: &RecordBatch) -> Result<usize> {
+        let batch = conform_fragment_batch(batch)?;
+        let ids = fragment_column::<StringArray>(&batch, "id")?;
+        let document_ids = fragment_column::<StringArray>(&batch, "document_id")?;
+        let orders = fragment_column::<Int32Array>(&batch, "fragment_order")?;
+        let segments = fragment_column::<Int32Array>(&batch, "segment")?;
+        let sections = fragment_column::<StringArray>(&batch, "section")?;
+        let pages = fragment_column::<Int32Array>(&batch, "page")?;
+        let structures = fragment_column::<StringArray>(&batch, "structure")?;
+        let locations = fragment_column::<StringArray>(&batch, "location")?;
+        let contents = fragment_column::<StringArray>(&batch, "content")?;
+        let embeddings = fragment_column::<ListArray>(&batch, "embedding")?;
+        let stale = fragment_column::<BooleanArray>(&batch, "stale")?;
+
+        let mut wanted: Vec<String> = document_ids.iter().flatten().map(str::to_string).collect();
+        wanted.sort();
+        wanted.dedup();
+        let known: HashSet<String> = self.records_by_id::<DocumentPayload>(DOCUMENT, &wanted, json!(["id"])).await?
+            .into_iter()
+            .map(|document| document.id)
+            .collect();
+
+        let mut records = Vec::new();
+        let mut vectors: HashMap<usize, Vec<Value>> = HashMap::new();
+        for row in 0..batch.num_rows() {
+            let id = ids.value(row).to_string();
+            let document_id = document_ids.value(row);
+            if !known.contains(document_id) {
+                anyhow::bail!("Fragment {} belongs to unknown document {}", id, document_id);
+            }
+            let content = contents.value(row).to_string();
+            let mut fragment = FragmentPayload {
+                id: id.clone(),
+                document_id: document_id.to_string(),
+                order: orders.value(row),
+                segment: segments.value(row).max(0) as u32,
+                section: sections.is_valid(row).then(|| sections.value(row).to_string()),
+                page: pages.is_valid(row).then(|| pages.value(row) as u32),
+                structure: structures.is_valid(row).then(|| structures.value(row)).and_then(Structure::parse),
+                location: locations.is_valid(row).then(|| locations.value(row)).and_then(Location::parse),
+                chars: content.chars().count() as u64,
+                chunk_hash: chunk_hash(&content),
+                content,
+                stale: stale.value(row),
+                ..FragmentPayload::default()
+            };
+            if embeddings.is_valid(row) {
+                let embedding = embeddings.value(row);
+                let values = embedding.as_any().downcast_ref::<Float32Array>()
+                    .ok_or_else(|| anyhow::anyhow!("Fragment {} has a malformed embedding", id))?;
+                fragment.dimension = Some(values.len());
+                vectors.entry(values.len()).or_default().push(json!({
+                    "id": point_id(FRAGMENT, &id),
+                    "vector": values.values().to_vec(),
+                    "payload": {"id": id, "document_id": document_id, "stale": fragment.stale},
+                }));
+            }
+            records.push(Self::record_point(FRAGMENT, &id, &fragment)?);
+        }
+
+        for (dimension, points) in vectors {
+            self.ensure_vectors(dimension).await?;
+            self.upsert(&self.vectors(dimension), points).await?;
+        }
+        self.upsert(&self.name, records).await?;
+        Ok(batch.num_rows())
+    }
+
+    async fn store_text_fragment(
+        &mut self,
+        document_id: &str,
+        order: i32,
+        content: &str,
+        meta: &FragmentMeta,
+    ) -> Result<String> {
+        let content = validate_fragment(content)?;
+        let mut spanned = None;
+        if let Some(span) = meta.span.filter(|_| self.ids == IdScheme::Content) {
+            if let Some(hash) = self.document(document_id, json!(["content_hash"])).await?.and_then(|document| document.content_hash) {
+                let id = IdScheme::span_fragment_id(&hash, span);
+                if self.fragments_by_id(std::slice::from_ref(&id), json!(["id"])).await?.is_empty() {
+                    spanned = Some(id);
+                }
+            }
+        }
+        let fragment_id = spanned.unwrap_or_else(|| self.ids.fragment_id(document_id, order, &content));
+
+        let fragment = FragmentPayload {
+            id: fragment_id.clone(),
+            document_id: document_id.to_string(),
+            order,
+            segment: meta.segment,
+            section: meta.section.clone(),
+            page: meta.page,
+            structure: meta.structure,
+            location: meta.location.clone(),
+            chars: content.chars().count() as u64,
+            chunk_hash: chunk_hash(&content),
+            content: content.into_owned(),
+            ..FragmentPayload::default()
+        };
+        self.upsert(&self.name, vec![Self::record_point(FRAGMENT, &fragment_id, &fragment)?]).await?;
+
+        Ok(fragment_id)
+    }
+
+    async fn update_fragment_embedding(
+        &mut self,
+        fragment_id: &str,
+        embedding: &[f64],
+    ) -> Result<()> {
+        let embedding: Vec<f32> = embedding.iter().map(|&x| x as f32).collect();
+        self.update_fragment_embeddings_batch(&[(fragment_id.to_string(), embedding)]).await
+    }
+
+    async fn update_fragment_embeddings_batch(&mut self, embeddings: &[(String, Vec<f32>)]) -> Result<()> {
+        if embeddings.is_empty() {
+            return Ok(());
+        }
+        let ids: Vec<String> = embeddings.iter().map(|(id, _)| id.clone()).collect();
+        let fragments = self.fragments_by_id(&ids, json!(["id", "document_id", "dimension"])).await?;
+
+        // Vectors go to the collection of their length; a vector of another length than
+        // the one it replaces leaves that one behind, to be deleted
+        let mut vectors: HashMap<usize, Vec<Value>> = HashMap::new();
+        let mut replaced: HashMap<usize, Vec<String>> = HashMap::new();
+        for (fragment_id, embedding) in embeddings {
+            // Fragments removed since they were read have no record to update
+            let Some(fragment) = fragments.get(fragment_id) else {
+                continue;
+            };
+            let point = point_id(FRAGMENT, fragment_id);
+            if let Some(previous) = fragment.dimension.filter(|&previous| previous != embedding.len()) {
+                replaced.entry(previous).or_default().push(point.clone());
+            }
+            vectors.entry(embedding.len()).or_default().push(json!({
+                "id": point,
+                "vector": embedding,
+                "payload": {"id": fragment_id, "document_id": fragment.document_id, "stale": false},
+            }));
+        }
+
+        for (dimension, points) in vectors {
+            self.ensure_vectors(dimension).await?;
+            let point_ids: Vec<Value> = points.iter().map(|point| point["id"].clone()).collect();
+            self.upsert(&self.vectors(dimension), points).await?;
+            let embedded = json!({"dimension": dimension, "stale": false, "failure": null, "attempts": 0, "set_aside": false});
+            self.set_payload(&self.name, json!({"points": point_ids}), embedded).await?;
+        }
+        for (dimension, point_ids) in replaced {
+            self.delete(&self.vectors(dimension), json!({"points": point_ids})).await?;
+        }
+        Ok(())
+    }
+
+    async fn clear_fragment_text(&mut self, fragment_ids: &[String]) -> Result<()> {
+        if fragment_ids.is_empty() {
+            return Ok(());
+        }
+        // The chunk hash stays in the payload, so identical chunks still reuse the vectors
+        let point_ids: Vec<String> = fragment_ids.iter().map(|id| point_id(FRAGMENT, id)).collect();
+        self.set_payload(&self.name, json!({"points": point_ids}), json!({"content": ""})).await
+    }
+
+    async fn get_pending_fragments(&mut self, limit: i32) -> Result<Vec<PendingFragment>> {
+        // Highest priority first; missing vectors come before stale ones, which are
+        // replaced most-queried first
+        let fields = json!(["id", "document_id", "order", "content", "stale", "hits"]);
+        let mut pending: Vec<FragmentPayload> = self.scroll(&self.name, pending_fragments(), fields, None, None).await?
+            .into_iter()
+            .map(Point::parse)
+            .collect::<Result<_>>()?;
+        let priorities: HashMap<String, i32> = self.records::<DocumentPayload>(DOCUMENT, Vec::new(), json!(["id", "priority"])).await?
+            .into_iter()
+            .map(|document| (document.id, document.priority))
+            .collect();
+
+        pending.sort_by(|a, b| {
+            let priority_a = priorities.get(&a.document_id).copied().unwrap_or(0);
+            let priority_b = priorities.get(&b.document_id).copied().unwrap_or(0);
+            priority_b.cmp(&priority_a)
+                .then_with(|| a.stale.cmp(&b.stale))
+                .then_with(|| b.hits.cmp(&a.hits))
+                .then_with(|| a.document_id.cmp(&b.document_id))
+                .then_with(|| a.order.cmp(&b.order))
+        });
+
+        Ok(pending.into_iter()
+            .take(limit.max(0) as usize)
+            .map(|fragment| PendingFragment {
+                priority: priorities.get(&fragment.document_id).copied().unwrap_or(0),
+                id: fragment.id,
+                content: fragment.content,
+                stale: fragment.stale,
+                hits: fragment.hits,
+            })
+            .collect())
+    }
+
+    async fn count_fragments_without_embeddings(&mut self) -> Result<i32> {
+        self.count(pending_fragments()).await
+    }
+
+    async fn get_embeddings_by_chunk(&mut self, chunk_hashes: &[String]) -> Result<HashMap<String, Vec<f32>>> {
+        let mut embeddings = HashMap::new();
+        if chunk_hashes.is_empty() {
+            return Ok(embeddings);
+        }
+        let mut filter = embedded_fragments();
+        filter["must"].as_array_mut().expect("filter has conditions").push(any_of("chunk_hash", chunk_hashes));
+        let fragments: Vec<FragmentPayload> = self.scroll(&self.name, filter, json!(["id", "chunk_hash", "dimension"]), None, None).await?
+            .into_iter()
+            .map(Point::parse)
+            .collect::<Result<_>>()?;
+
+        let mut vectors = self.vectors_of(&fragments).await?;
+        for fragment in fragments {
+            if let Some(vector) = vectors.remove(&fragment.id) {
+                embeddings.entry(fragment.chunk_hash).or_insert(vector);
+            }
+        }
+        Ok(embeddings)
+    }
+
+    async fn record_embedding_failures(&mut self, failures: &[(String, String)]) -> Result<()> {
+        let ids: Vec<String> = failures.iter().map(|(id, _)| id.clone()).collect();
+        let fragments = self.fragments_by_id(&ids, json!(["id", "attempts"])).await?;
+        for (fragment_id, error) in failures {
+            let Some(fragment) = fragments.get(fragment_id) else {
+                continue;
+            };
+            let failure = json!({"failure": error, "attempts": fragment.attempts + 1, "set_aside": true});
+            self.set_payload(&self.name, json!({"points": [point_id(FRAGMENT, fragment_id)]}), failure).await?;
+        }
+        Ok(())
+    }
+
+    async fn retry_embedding_failures(&mut self) -> Result<i32> {
+        let set_aside = of_kind(FRAGMENT, vec![field("set_aside", true)]);
+        let released = self.count(set_aside.clone()).await?;
+        if released > 0 {
+            self.set_payload(&self.name, json!({"filter": set_aside}), json!({"set_aside": false})).await?;
+        }
+        Ok(released)
+    }
+
+    async fn count_embedding_failures(&mut self) -> Result<i32> {
+        self.count(of_kind(FRAGMENT, vec![json!({"key": "attempts", "range": {"gt": 0}})])).await
+    }
+
+    async fn build_vector_index(&mut self) -> Result<bool> {
+        // Qdrant keeps an HNSW index of every vector collection up to date by itself
+        Ok(true)
+    }
+
+    async fn mark_embeddings_stale(&mut self) -> Result<i32> {
+        let marked = self.count(embedded_fragments()).await?;
+        if marked > 0 {
+            self.set_payload(&self.name, json!({"filter": embedded_fragments()}), json!({"stale": true})).await?;
+            for dimension in self.vector_lengths().await? {
+                let current = json!({"must": [field("stale", false)]});
+                self.set_payload(&self.vectors(dimension), json!({"filter": current}), json!({"stale": true})).await?;
+            }
+        }
+        Ok(marked)
+    }
+
+    async fn count_stale_fragments(&mut self) -> Result<i32> {
+        self.count(of_kind(FRAGMENT, vec![field("stale", true)])).await
+    }
+
+    async fn corpus_stats(&mut self, largest: usize) -> Result<CorpusStats> {
+        let fragments: Vec<FragmentPayload> = self.records(FRAGMENT, Vec::new(), json!(["document_id", "chars"])).await?;
+        let mut fragments_per_document: HashMap<&str, usize> = HashMap::new();
+        let mut fragment_chars = 0;
+        for fragment in &fragments {
+            *fragments_per_document.entry(fragment.document_id.as_str()).or_default() += 1;
+            fragment_chars += fragment.chars;
+        }
+
+        let documents: Vec<DocumentPayload> = self.records(DOCUMENT, Vec::new(), json!(["id", "file_path", "bytes"])).await?;
+        let mut file_types: HashMap<String, usize> = HashMap::new();
+        let mut sizes = Vec::new();
+        for document in &documents {
+            // Same file type as DuckDB records: the lowercased extension
+            let file_type = Path::new(&document.file_path).extension()
+                .map(|ext| ext.to_string_lossy().to_lowercase())
+                .unwrap_or_else(|| "unknown".to_string());
+            *file_types.entry(file_type).or_default() += 1;
+            sizes.push(DocumentSize {
+                file_path: document.file_path.clone(),
+                bytes: document.bytes,
+                fragments: fragments_per_document.get(document.id.as_str()).copied().unwrap_or(0),
+            });
+        }
+        let mut file_types: Vec<(String, usize)> = file_types.into_iter().collect();
+        sort_file_types(&mut file_types);
+        sort_largest(&mut sizes);
+        let document_bytes = sizes.iter().map(|document| document.bytes).sum();
+        sizes.truncate(largest);
+
+        Ok(CorpusStats {
+            file_types,
+            documents: documents.len(),
+            fragments: fragments.len(),
+            fragment_chars,
+            document_bytes,
+            largest: sizes,
+        })
+    }
+
+    async fn record_fragment_hits(&mut self, fragment_ids: &[String]) -> Result<()> {
+        let mut hits: HashMap<&String, u32> = HashMap::new();
+        for id in fragment_ids {
+            *hits.entry(id).or_default() += 1;
+        }
+        let fragments = self.fragments_by_id(fragment_ids, json!(["id", "hits"])).await?;
+        for (id, count) in hits {
+            let Some(fragment) = fragments.get(id) else {
+                continue;
+            };
+            let payload = json!({"hits": fragment.hits + count});
+            self.set_payload(&self.name, json!({"points": [point_id(FRAGMENT, id)]}), payload).await?;
+        }
+        Ok(())
+    }
+
+    async fn get_fragment_vectors(&mut self, fragment_ids: &[String]) -> Result<HashMap<String, Vec<f64>>> {
+        let fragments: Vec<FragmentPayload> = self.fragments_by_id(fragment_ids, json!(["id", "dimension"])).await?.into_values().collect();
+        Ok(self.vectors_of(&fragments).await?
+            .into_iter()
+            .map(|(id, vector)| (id, vector.into_iter().map(f64::from).collect()))
+            .collect())
+    }
+
+    async fn get_fragment_embeddings(&mut self, limit: usize) -> Result<Vec<(String, String, Vec<f64>)>> {
+        let fields = json!(["id", "document_id", "dimension"]);
+        let fragments: Vec<FragmentPayload> = self.scroll(&self.name, embedded_fragments(), fields, None, Some(limit)).await?
+            .into_iter()
+            .map(Point::parse)
+            .collect::<Result<_>>()?;
+        let mut document_ids: Vec<String> = fragments.iter().map(|fragment| fragment.document_id.clone()).collect();
+        document_ids.sort();
+        document_ids.dedup();
+        let filenames: HashMap<String, String> = self.records_by_id::<DocumentPayload>(DOCUMENT, &document_ids, json!(["id", "file_path"])).await?
+            .into_iter()
+            .map(|document| (document.id, filename(&document.file_path)))
+            .collect();
+
+        let mut vectors = self.vectors_of(&fragments).await?;
+        let mut embeddings: Vec<(String, String, Vec<f64>)> = fragments.into_iter()
+            .filter_map(|fragment| {
+                let filename = filenames.get(&fragment.document_id)?.clone();
+                let vector = vectors.remove(&fragment.id)?;
+                Some((fragment.id, filename, vector.into_iter().map(f64::from).collect()))
+            })
+            .collect();
+        embeddings.sort_by(|a, b| a.0.cmp(&b.0));
+        Ok(embeddings)
+    }
+
+    async fn sample_fragments(&mut self, limit: usize) -> Result<Vec<(String, String, Vec<f64>)>> {
+        let fields = json!(["id", "content", "dimension"]);
+        let fragments: Vec<FragmentPayload> = self.scroll(&self.name, embedded_fragments(), fields, None, Some(limit)).await?
+            .into_iter()
+            .map(Point::parse)
+            .collect::<Result<_>>()?;
+
+        let mut vectors = self.vectors_of(&fragments).await?;
+        let mut samples: Vec<(String, String, Vec<f64>)> = fragments.into_iter()
+            .filter_map(|fragment| {
+                let vector = vectors.remove(&fragment.id)?;
+                Some((fragment.id, fragment.content, vector.into_iter().map(f64::from).collect()))
+            })
+            .collect();
+        samples.sort_by(|a, b| a.0.cmp(&b.0));
+        Ok(samples)
+    }
+
+    async fn get_meta_info(&mut self) -> Result<MetaInfo> {
+        let meta: HashMap<String, String> = self.scroll(&self.name, of_kind(META, Vec::new()), json!(["key", "value"]), None, None).await?
+            .into_iter()
+            .filter_map(|point| Some((point.payload["key"].as_str()?.to_string(), point.payload["value"].as_str()?.to_string())))
+            .collect();
+
+        Ok(MetaInfo {
+            version: meta.get("version").cloned().unwrap_or_else(|| "unknown".to_string()),
+            embedding_model: meta.get("embedding_model").cloned().unwrap_or_else(|| "unknown".to_string()),
+            embedding_dimension: parse_dimension(meta.get(DIMENSION_KEY).map(String::as_str)),
+            embedding_provider: meta.get(PROVIDER_KEY).filter(|provider| !provider.is_empty()).cloned(),
+        })
+    }
+
+    async fn get_meta_value(&mut self, key: &str) -> Result<Option<String>> {
+        self.get_meta(key).await
+    }
+
+    async fn set_meta_value(&mut self, key: &str, value: &str) -> Result<()> {
+        self.set_meta(key, value).await
+    }
+
+    async fn list_entities(&mut self, limit: usize) -> Result<Vec<EntityCount>> {
+        let mut counts: HashMap<String, (EntityCount, HashSet<String>)> = HashMap::new();
+        for (doc_id, names) in self.fragment_entities().await? {
+            for name in names {
+                let (count, documents) = counts.entry(entities::key(&name))
+                    .or_insert_with(|| (EntityCount { name, fragments: 0, documents: 0 }, Default::default()));
+                count.fragments += 1;
+                documents.insert(doc_id.clone());
+            }
+        }
+
+        let mut entities: Vec<EntityCount> = counts.into_values()
+            .map(|(count, documents)| EntityCount { documents: documents.len(), ..count })
+            .collect();
+        entities.sort_by(|a, b| b.fragments.cmp(&a.fragments).then_with(|| a.name.cmp(&b.name)));
+        entities.truncate(limit);
+        Ok(entities)
+    }
+
+    async fn find_entity(&mut self, entity: &str) -> Result<Vec<EntityMention>> {
+        let wanted = entities::key(entity);
+        let documents = self.catalog().await?;
+        let mut mentions: HashMap<String, EntityMention> = HashMap::new();
+        for (doc_id, names) in self.fragment_entities().await? {
+            let matching: Vec<String> = names.into_iter()
+                .filter(|name| entities::matches(&entities::key(name), &wanted))
+                .collect();
+            if matching.is_empty() {
+                continue;
+            }
+            let mention = mentions.entry(doc_id.clone()).or_insert_with(|| EntityMention {
+                file_path: documents.get(&doc_id).map(|document| document.file_path.clone()).unwrap_or_default(),
+                document_id: doc_id,
+                names: Vec::new(),
+                fragments: 0,
+            });
+            mention.fragments += 1;
+            for name in matching {
+                if !mention.names.contains(&name) {
+                    mention.names.push(name);
+                }
+            }
+        }
+
+        let mut mentions: Vec<EntityMention> = mentions.into_values().collect();
+        mentions.iter_mut().for_each(|mention| mention.names.sort());
+        mentions.sort_by(|a, b| b.fragments.cmp(&a.fragments).then_with(|| a.file_path.cmp(&b.file_path)));
+        Ok(mentions)
+    }
+
+    async fn add_annotation(&mut self, annotation: &Annotation) -> Result<()> {
+        let point = Self::record_point(ANNOTATION, &annotation.id, annotation)?;
+        self.upsert(&self.name, vec![point]).await
+    }
+
+    async fn list_annotations(&mut self, target: Option<&str>) -> Result<Vec<Annotation>> {
+        self.annotations(target).await
+    }
+
+    async fn remove_annotation(&mut self, annotation_id: &str) -> Result<bool> {
+        let filter = of_kind(ANNOTATION, vec![field("id", annotation_id)]);
+        if self.count(filter.clone()).await? == 0 {
+            return Ok(false);
+        }
+        self.delete(&self.name, json!({"filter": filter})).await?;
+        Ok(true)
+    }
+
+    async fn search_similar(
+        &mut self,
+        query_embedding: &[f64],
+        limit: usize,
+        filter: &SearchFilter,
+    ) -> Result<Vec<FragmentMatch>> {
+        let context = self.search_context().await?;
+        let documents = context.searchable_documents(filter);
+        if documents.is_empty() || limit == 0 {
+            return Ok(Vec::new());
+        }
+
+        // The server ranks vectors of searchable documents; fragment-level filters and
+        // the page position are applied here, fetching further pages until enough pass
+        let path = format!("collections/{}/points/search", self.vectors(query_embedding.len()));
+        let restrict = json!({"must": [field("stale", filter.stale), any_of("document_id", &documents)]});
+        let page = (limit * 4).max(64);
+        let mut matches = Vec::new();
+        let mut offset = 0;
+        loop {
+            let body = json!({
+                "vector": query_embedding,
+                "filter": restrict,
+                "limit": page,
+                "offset": offset,
+                "with_payload": ["id"],
+            });
+            // No collection of the query's length means nothing was embedded with it
+            let Some(result) = self.send(Method::POST, &path, Some(body)).await? else {
+                break;
+            };
+            let hits: Vec<Point> = serde_json::from_value(result).context("Malformed search reply from Qdrant")?;
+            let ids: Vec<String> = hits.iter().filter_map(Point::record_id).collect();
+            let fragments = self.fragments_by_id(&ids, json!(true)).await?;
+            for hit in &hits {
+                let Some(fragment) = hit.record_id().and_then(|id| fragments.get(&id)) else {
+                    continue;
+                };
+                if !context.searchable(fragment, filter) {
+                    continue;
+                }
+                // Qdrant scores Euclidean searches by the distance itself
+                let score = match self.metric {
+                    SimilarityMetric::Euclidean => SimilarityMetric::from_distance(hit.score),
+                    _ => hit.score,
+                };
+                let found = context.fragment_match(fragment, score);
+                if filter.after.as_ref().is_none_or(|after| after.precedes(&found)) {
+                    matches.push(found);
+                }
+            }
+            if hits.len() < page || matches.len() >= limit {
+                break;
+            }
+            offset += page;
+        }
+
+        sort_ranked(&mut matches);
+        matches.truncate(limit);
+        Ok(matches)
+    }
+
+    async fn search_keyword(
+        &mut self,
+        query: &str,
+        limit: usize,
+        filter: &SearchFilter,
+    ) -> Result<Vec<FragmentMatch>> {
+        // BM25 over the fragments the filter allows, scored afresh on every query, here
+        // rather than on the server
+        let context = self.search_context().await?;
+        let documents = context.searchable_documents(filter);
+        if documents.is_empty() {
+            return Ok(Vec::new());
+        }
+        let fragments: Vec<FragmentPayload> = self.records(FRAGMENT, vec![any_of("document_id", &documents)], json!(true)).await?
+            .into_iter()
+            .filter(|fragment| context.searchable(fragment, filter))
+            .collect();
+        let by_id: HashMap<&str, &FragmentPayload> = fragments.iter().map(|fragment| (fragment.id.as_str(), fragment)).collect();
+
+        let candidates = fragments.iter().map(|fragment| (fragment.id.as_str(), fragment.content.as_str()));
+        let mut matches: Vec<FragmentMatch> = bm25(query, candidates).into_iter()
+            .map(|(id, score)| context.fragment_match(by_id[id], score))
+            .filter(|found| filter.after.as_ref().is_none_or(|after| after.precedes(found)))
+            .collect();
+
+        sort_ranked(&mut matches);
+        matches.truncate(limit);
+        Ok(matches)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{sidecar_path, StorageBackend};
+
+    #[test]
+    fn test_remote_brains_are_named_by_url() {
+        assert_eq!(split_url("http://localhost:6333/team"), Some(("http://localhost:6333".to_string(), "team".to_string())));
+        assert_eq!(
+            split_url("https://qdrant.example.com/proxy/handbook/"),
+            Some(("https://qdrant.example.com/proxy".to_string(), "handbook".to_string()))
+        );
+        assert_eq!(split_url("http://localhost:6333"), None);
+        assert_eq!(split_url("brain.db"), None);
+
+        assert!(is_remote(Path::new("http://localhost:6333/team")));
+        assert!(!is_remote(Path::new("team.db")));
+        assert_eq!(brain_name(Path::new("http://localhost:6333/team")).as_deref(), Some("team"));
+        assert!(matches!(StorageBackend::from_path(Path::new("http://localhost:6333/team")), StorageBackend::Remote));
+
+        // Files kept beside a remote brain are named after it in the current directory
+        assert_eq!(sidecar_path(Path::new("http://localhost:6333/team"), ".jobs"), Path::new("team.jobs"));
+        assert_eq!(sidecar_path(Path::new("data/brain.db"), ".jobs"), Path::new("data/brain.db.jobs"));
+
+        // Fragments keep their UUIDs as point ids, so scrolling pages through them in id
+        // order; meta keys get stable ids of their own
+        let fragment = "0b9c1f7e-3d2a-4c55-9e1b-7a6f2d8c4e01";
+        assert_eq!(point_id(FRAGMENT, fragment), fragment);
+        assert_eq!(point_id(META, "version"), point_id(META, "version"));
+        assert_ne!(point_id(META, "version"), point_id(META, "embedding_model"));
+        assert!(Uuid::parse_str(&point_id(META, "version")).is_ok());
+    }
+
+    #[test]
+    fn test_search_context_applies_document_and_fragment_filters() {
+        let document = |id: &str, path: &str, tombstoned: bool| DocumentPayload {
+            id: id.to_string(),
+            file_path: path.to_string(),
+            collection: Some("policies".to_string()),
+            tombstoned,
+            ..DocumentPayload::default()
+        };
+        let context = SearchContext {
+            documents: [
+                document("a", "docs/leave.md", false),
+                document("b", "docs/travel.pdf", false),
+                document("c", "docs/old.md", true),
+            ].into_iter().map(|document| (document.id.clone(), document)).collect(),
+            annotations: vec![Annotation::new("b", Some(AnnotationLabel::Wrong), None, "reviewer")],
+        };
+
+        // Tombstoned documents and ones marked wrong are never searched
+        assert_eq!(context.searchable_documents(&SearchFilter::default()), vec!["a".to_string()]);
+        let other_collection = SearchFilter { collections: vec!["engineering".to_string()], ..SearchFilter::default() };
+        assert!(context.searchable_documents(&other_collection).is_empty());
+
+        let fragment = FragmentPayload {
+            id: "f".to_string(),
+            document_id: "a".to_string(),
+            section: Some("Annual Leave > Carry-over".to_string()),
+            content: "Maria Chen approves carry-over requests.".to_string(),
+            ..FragmentPayload::default()
+        };
+        let in_section = SearchFilter { section: Some("carry-over".to_string()), ..SearchFilter::default() };
+        assert!(context.searchable(&fragment, &in_section));
+        let naming = SearchFilter { entity: Some("chen".to_string()), ..SearchFilter::default() };
+        assert!(context.searchable(&fragment, &naming));
+        let elsewhere = SearchFilter { section: Some("travel".to_string()), ..SearchFilter::default() };
+        assert!(!context.searchable(&fragment, &elsewhere));
+
+        let found = context.fragment_match(&fragment, 0.5);
+        assert_eq!((found.source.filename.as_str(), found.source.file_path.as_str()), ("leave.md", "docs/leave.md"));
+    }
+}
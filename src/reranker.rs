@@ -0,0 +1,87 @@
+use anyhow::{Context, Result};
+use fastembed::{RerankInitOptions, RerankerModel, TextRerank};
+use log::info;
+use std::str::FromStr;
+
+use crate::embedding_manager::cached_model_file;
+use crate::model_downloads;
+use crate::offline;
+
+/// Candidates retrieved for a cross-encoder to re-score, when fewer results are asked for
+pub const RERANK_POOL: usize = 50;
+
+/// A cross-encoder reranking model. It reads the query and a passage together, which judges
+/// relevance more precisely than comparing separately computed embeddings but is too slow to
+/// run over a whole database, so it only re-scores the top candidates of a search.
+pub struct CrossEncoder {
+    model: TextRerank,
+    model_name: String,
+}
+
+impl CrossEncoder {
+    /// Load one of FastEmbed's reranker models by name, e.g. `BAAI/bge-reranker-base`
+    pub fn new(model_name: &str) -> Result<Self> {
+        let model = RerankerModel::from_str(model_name).map_err(|e| {
+            let supported: Vec<String> = TextRerank::list_supported_models().into_iter().map(|m| m.model_code).collect();
+            anyhow::anyhow!("{} (supported: {})", e, supported.join(", "))
+        })?;
+        let info = TextRerank::get_model_info(&model);
+        if offline::is_enabled() && cached_model_file(&info.model_code, &info.model_file).is_none() {
+            return Err(offline::refused(&format!("Downloading reranker model {}", info.model_code)));
+        }
+        model_downloads::verify_model(model_name, &info.model_code, &info.model_file, &info.additional_files)?;
+
+        info!("Loading reranker model: {}", model_name);
+        let model = TextRerank::try_new(RerankInitOptions::new(model).with_show_download_progress(true))
+            .context("Failed to initialize reranker model")?;
+        Ok(Self { model, model_name: model_name.to_string() })
+    }
+
+    pub fn model_name(&self) -> &str {
+        &self.model_name
+    }
+
+    /// The best `limit` of `candidates` for `query` by the model's relevance score, best
+    /// first, with their scores. `text` gives the passage of a candidate.
+    pub fn rerank<T>(&mut self, query: &str, candidates: Vec<T>, text: impl Fn(&T) -> &str, limit: usize) -> Result<Vec<(T, f64)>> {
+        if candidates.is_empty() {
+            return Ok(Vec::new());
+        }
+        let passages: Vec<&str> = candidates.iter().map(text).collect();
+        let scores: Vec<(usize, f64)> = self.model.rerank(query, passages, false, None)
+            .context("Failed to rerank search results")?
+            .into_iter()
+            .map(|result| (result.index, result.score as f64))
+            .collect();
+        Ok(best_scored(candidates, scores, limit))
+    }
+}
+
+/// The best `limit` candidates by `scores`, pairs of candidate index and score; ties keep
+/// the candidates' order
+fn best_scored<T>(candidates: Vec<T>, mut scores: Vec<(usize, f64)>, limit: usize) -> Vec<(T, f64)> {
+    scores.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+    let mut candidates: Vec<Option<T>> = candidates.into_iter().map(Some).collect();
+    scores.into_iter()
+        .filter_map(|(index, score)| candidates.get_mut(index).and_then(Option::take).map(|candidate| (candidate, score)))
+        .take(limit)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_best_scored_reorders_and_cuts() {
+        let candidates = vec!["close but off-topic", "exact answer", "related", "unrelated"];
+        let scores = vec![(0, 0.2), (1, 3.5), (2, 0.2), (3, -4.0)];
+        assert_eq!(best_scored(candidates, scores, 3), vec![("exact answer", 3.5), ("close but off-topic", 0.2), ("related", 0.2)]);
+    }
+
+    #[test]
+    fn test_unknown_model_lists_supported_ones() {
+        let error = CrossEncoder::new("not-a-reranker").err().unwrap().to_string();
+        assert!(error.contains("not-a-reranker"));
+    }
+}
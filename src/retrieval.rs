@@ -0,0 +1,535 @@
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::Hash;
+
+use crate::annotations;
+use crate::embedding_manager::EmbeddingManager;
+use crate::entities;
+use crate::hybrid::{FusedHit, SearchMode};
+use crate::presets;
+use crate::storage::{self, EntityMention, FragmentMatch, FragmentSource, RankPosition, SearchFilter, Storage};
+
+/// A single search hit returned to callers
+#[derive(Debug, Clone)]
+pub struct SearchHit {
+    pub fragment_id: String,
+    pub content: String,
+    pub score: f64,
+    /// Where the fragment came from, for citing it
+    pub source: FragmentSource,
+}
+
+impl From<FragmentMatch> for SearchHit {
+    fn from(fragment: FragmentMatch) -> Self {
+        Self {
+            fragment_id: fragment.fragment_id,
+            content: fragment.content,
+            score: fragment.score,
+            source: fragment.source,
+        }
+    }
+}
+
+/// Where the next page of a search starts: the number of results already returned and the
+/// ranking position of the last of them
+#[derive(Debug, Clone, PartialEq)]
+pub struct SearchCursor {
+    pub offset: usize,
+    pub after: RankPosition,
+}
+
+impl SearchCursor {
+    /// Opaque token for clients to send back for the next page
+    pub fn encode(&self) -> String {
+        let path: String = self.after.file_path.bytes().map(|byte| format!("{:02x}", byte)).collect();
+        format!("{}.{:x}.{}.{}", self.offset, self.after.score.to_bits(), self.after.order, path)
+    }
+
+    pub fn decode(token: &str) -> Result<Self> {
+        let invalid = || anyhow::anyhow!("Invalid search cursor '{}'", token);
+        let mut parts = token.trim().splitn(4, '.');
+        let mut next = || parts.next().ok_or_else(invalid);
+        let offset = next()?.parse().map_err(|_| invalid())?;
+        let score = u64::from_str_radix(next()?, 16).map(f64::from_bits).map_err(|_| invalid())?;
+        let order = next()?.parse().map_err(|_| invalid())?;
+        let path = next()?;
+        let bytes = (0..path.len()).step_by(2)
+            .map(|i| path.get(i..i + 2).and_then(|hex| u8::from_str_radix(hex, 16).ok()))
+            .collect::<Option<Vec<u8>>>()
+            .ok_or_else(invalid)?;
+        let file_path = String::from_utf8(bytes).map_err(|_| invalid())?;
+        Ok(Self { offset, after: RankPosition { score, file_path, order } })
+    }
+}
+
+/// One page of search results, with the cursor for the next page when there is one
+#[derive(Debug, Clone)]
+pub struct SearchPage {
+    pub hits: Vec<SearchHit>,
+    pub next: Option<SearchCursor>,
+}
+
+/// How a single candidate was scored and why it did or didn't make the final top-k
+#[derive(Debug, Clone)]
+pub struct ExplainedCandidate {
+    pub rank: usize,
+    pub fragment_id: String,
+    pub dense_score: Option<f64>,
+    pub sparse_score: Option<f64>,
+    pub rerank_delta: Option<f64>,
+    pub final_score: f64,
+    pub selected: bool,
+    pub reason: String,
+}
+
+/// Diagnostic report produced by `search --explain`
+#[derive(Debug, Clone)]
+pub struct ExplainReport {
+    pub query: String,
+    pub mode: SearchMode,
+    pub query_dimension: usize,
+    pub query_norm: f64,
+    pub limit: usize,
+    pub candidate_pool: usize,
+    pub filters: Vec<String>,
+    pub candidates: Vec<ExplainedCandidate>,
+}
+
+/// Candidates fetched per result when results are limited per document, so the top-k can
+/// still be filled once a document's extra fragments are collapsed
+pub const PER_DOCUMENT_POOL_FACTOR: usize = 4;
+
+/// Number of candidates fetched when explaining, so results just below the cutoff are visible too
+fn explain_pool_size(limit: usize) -> usize {
+    (limit * 3).max(limit + 10)
+}
+
+/// Run a search in the given mode, optionally collecting an explanation of the ranking.
+/// Vector results are re-ranked by the boosts of the database's preset and by reviewers'
+/// labels; both are tuned for similarity scores, so keyword and hybrid rankings are left as
+/// they are.
+pub async fn search(
+    storage: &mut dyn Storage,
+    embedding_manager: &mut EmbeddingManager,
+    query: &str,
+    limit: usize,
+    filter: &SearchFilter,
+    mode: SearchMode,
+    explain: bool,
+) -> Result<(Vec<SearchHit>, Option<ExplainReport>)> {
+    // Keyword search never looks at vectors, so it doesn't need the model either
+    let query_embedding = if mode == SearchMode::Keyword {
+        Vec::new()
+    } else {
+        let prefixes = storage::embedding_prefixes(storage).await?;
+        let embedding = embedding_manager.generate_embedding(&prefixes.query(query)).await
+            .context("Failed to generate query embedding")?;
+        // Stale vectors are the previous model's, whose length isn't recorded
+        if !filter.stale {
+            storage::check_dimension(storage, embedding_manager.model_name(), embedding.len()).await?;
+        }
+        embedding
+    };
+
+    let (hits, pool_size, explained) = match mode {
+        SearchMode::Vector => vector_search(storage, &query_embedding, limit, filter, explain).await?,
+        SearchMode::Hybrid | SearchMode::Keyword => {
+            let pool_size = if explain { explain_pool_size(limit) } else { limit };
+            let candidates = if mode == SearchMode::Hybrid {
+                storage.search_hybrid(query, &query_embedding, pool_size, filter).await
+                    .context("Failed to run hybrid search")?
+            } else {
+                storage.search_keyword(query, pool_size, filter).await
+                    .context("Failed to run keyword search")?
+                    .into_iter()
+                    .map(|fragment| FusedHit {
+                        dense_score: None,
+                        sparse_score: Some(fragment.score),
+                        fragment,
+                    })
+                    .collect()
+            };
+
+            let explained = explain.then(|| explain_fused(&candidates, limit, mode));
+            let hits = candidates.into_iter()
+                .take(limit)
+                .map(|hit| SearchHit::from(hit.fragment))
+                .collect();
+            (hits, pool_size, explained)
+        }
+    };
+
+    let report = explained.map(|candidates| ExplainReport {
+        query: query.to_string(),
+        mode,
+        query_dimension: query_embedding.len(),
+        query_norm: vector_norm(&query_embedding),
+        limit,
+        candidate_pool: pool_size,
+        filters: filter.describe(),
+        candidates,
+    });
+
+    Ok((hits, report))
+}
+
+/// One page of `limit` results, continuing after `cursor` (or from the top without one).
+/// Plain vector and keyword rankings resume in the storage layer after the cursor's
+/// position, so earlier pages aren't fetched again. Boosted, labelled and fused scores
+/// depend on the whole candidate pool, so those rankings are fetched to the end of the page and the
+/// results of earlier pages skipped.
+pub async fn search_page(
+    storage: &mut dyn Storage,
+    embedding_manager: &mut EmbeddingManager,
+    query: &str,
+    limit: usize,
+    filter: &SearchFilter,
+    mode: SearchMode,
+    cursor: Option<&SearchCursor>,
+) -> Result<SearchPage> {
+    let offset = cursor.map_or(0, |cursor| cursor.offset);
+    let resumable = match mode {
+        SearchMode::Vector => presets::recorded_booster(storage).await?.is_none() && annotations::ranking_boost(storage).await?.is_none(),
+        SearchMode::Keyword => true,
+        SearchMode::Hybrid => false,
+    };
+    let (filter, skip) = match cursor {
+        Some(cursor) if resumable => (SearchFilter { after: Some(cursor.after.clone()), ..filter.clone() }, 0),
+        _ => (filter.clone(), offset),
+    };
+
+    // One result beyond the page tells whether there is another
+    let (hits, _) = search(storage, embedding_manager, query, skip + limit + 1, &filter, mode, false).await?;
+    let mut hits: Vec<SearchHit> = hits.into_iter().skip(skip).collect();
+    let more = hits.len() > limit;
+    hits.truncate(limit);
+
+    let next = hits.last().filter(|_| more).map(|hit| SearchCursor {
+        offset: offset + hits.len(),
+        after: RankPosition {
+            score: hit.score,
+            file_path: hit.source.file_path.clone(),
+            order: hit.source.order,
+        },
+    });
+    Ok(SearchPage { hits, next })
+}
+
+/// Dense similarity search with preset boosts, returning the hits, the candidate pool size
+/// and, when explaining, the annotated candidates
+async fn vector_search(
+    storage: &mut dyn Storage,
+    query_embedding: &[f64],
+    limit: usize,
+    filter: &SearchFilter,
+    explain: bool,
+) -> Result<(Vec<SearchHit>, usize, Option<Vec<ExplainedCandidate>>)> {
+    // Boosts, reviewers' labels and feedback can lift candidates from below the cutoff, so they need
+    // the wider pool too
+    let booster = presets::recorded_booster(storage).await?;
+    let labelled = annotations::ranking_boost(storage).await?;
+    let pool_size = if explain || booster.is_some() || labelled.is_some() { explain_pool_size(limit) } else { limit };
+    let candidates = storage.search_similar(query_embedding, pool_size, filter).await
+        .context("Failed to search similar content")?;
+
+    // (fragment with final score, boost and label delta)
+    let mut candidates: Vec<(FragmentMatch, Option<f64>)> = match &booster {
+        Some(booster) => booster.rerank(candidates).into_iter()
+            .map(|(fragment, delta)| (fragment, Some(delta)))
+            .collect(),
+        None => candidates.into_iter()
+            .map(|fragment| (fragment, None))
+            .collect(),
+    };
+    if let Some(weights) = labelled {
+        for (fragment, delta) in candidates.iter_mut() {
+            let label = weights.delta(&fragment.source.annotations);
+            fragment.score += label;
+            *delta = Some(delta.unwrap_or(0.0) + label);
+        }
+        candidates.sort_by(|a, b| b.0.score.total_cmp(&a.0.score));
+    }
+
+    let explained = explain.then(|| {
+        let scores: Vec<(String, f64, Option<f64>)> = candidates.iter()
+            .map(|(fragment, delta)| (fragment.fragment_id.clone(), fragment.score - delta.unwrap_or(0.0), *delta))
+            .collect();
+        explain_candidates(&scores, limit)
+    });
+
+    let hits: Vec<SearchHit> = candidates.into_iter()
+        .take(limit)
+        .map(|(fragment, _)| SearchHit::from(fragment))
+        .collect();
+
+    Ok((hits, pool_size, explained))
+}
+
+/// The entity a "who is X" or "what is X" question asks about, with the documents mentioning
+/// it, when the entity index knows it. Searches for it can then keep to fragments naming it,
+/// which embeddings alone tend to confuse with similar names.
+pub async fn entity_lookup(storage: &mut dyn Storage, question: &str) -> Result<Option<(String, Vec<EntityMention>)>> {
+    let Some(subject) = entities::lookup_subject(question) else {
+        return Ok(None);
+    };
+    let mentions = storage.find_entity(&subject).await?;
+    Ok((!mentions.is_empty()).then_some((subject, mentions)))
+}
+
+/// Keep at most `per_document` of the ranked `hits` from each document, as told apart by
+/// `document`, so a long document matching well can't fill the whole top-k. Returns the
+/// kept hits and, still in ranking order, the ones collapsed into them.
+pub fn limit_per_document<T, K: Eq + Hash>(hits: Vec<T>, per_document: usize, document: impl Fn(&T) -> K) -> (Vec<T>, Vec<T>) {
+    let mut seen: HashMap<K, usize> = HashMap::new();
+    hits.into_iter().partition(|hit| {
+        let count = seen.entry(document(hit)).or_insert(0);
+        *count += 1;
+        *count <= per_document
+    })
+}
+
+pub fn vector_norm(vector: &[f64]) -> f64 {
+    vector.iter().map(|x| x * x).sum::<f64>().sqrt()
+}
+
+/// Annotate (fragment_id, dense score, rerank delta) candidates, ranked by final score, with
+/// the reason they were kept or cut
+pub fn explain_candidates(candidates: &[(String, f64, Option<f64>)], limit: usize) -> Vec<ExplainedCandidate> {
+    let final_score = |(_, dense, delta): &(String, f64, Option<f64>)| dense + delta.unwrap_or(0.0);
+    let cutoff = candidates.get(limit.saturating_sub(1)).map(final_score);
+
+    candidates.iter()
+        .enumerate()
+        .map(|(i, candidate)| {
+            let (fragment_id, dense_score, rerank_delta) = candidate;
+            let score = final_score(candidate);
+            let rank = i + 1;
+            let selected = rank <= limit;
+            let scored_by = if rerank_delta.is_some_and(|delta| delta != 0.0) { "boosted score" } else { "dense score" };
+            let reason = cutoff_reason(rank, limit, score, cutoff, scored_by);
+
+            ExplainedCandidate {
+                rank,
+                fragment_id: fragment_id.clone(),
+                dense_score: Some(*dense_score),
+                sparse_score: None,
+                rerank_delta: *rerank_delta,
+                final_score: score,
+                selected,
+                reason,
+            }
+        })
+        .collect()
+}
+
+/// Annotate keyword or hybrid candidates, ranked by final score, with the reason they were
+/// kept or cut
+pub fn explain_fused(candidates: &[FusedHit], limit: usize, mode: SearchMode) -> Vec<ExplainedCandidate> {
+    let cutoff = candidates.get(limit.saturating_sub(1)).map(|hit| hit.fragment.score);
+    let scored_by = if mode == SearchMode::Keyword { "BM25 score" } else { "fused rank" };
+
+    candidates.iter()
+        .enumerate()
+        .map(|(i, hit)| {
+            let rank = i + 1;
+            ExplainedCandidate {
+                rank,
+                fragment_id: hit.fragment.fragment_id.clone(),
+                dense_score: hit.dense_score,
+                sparse_score: hit.sparse_score,
+                rerank_delta: None,
+                final_score: hit.fragment.score,
+                selected: rank <= limit,
+                reason: cutoff_reason(rank, limit, hit.fragment.score, cutoff, scored_by),
+            }
+        })
+        .collect()
+}
+
+fn cutoff_reason(rank: usize, limit: usize, score: f64, cutoff: Option<f64>, scored_by: &str) -> String {
+    if rank <= limit {
+        format!("rank {} within top-{} by {}", rank, limit, scored_by)
+    } else {
+        match cutoff {
+            Some(cutoff) => format!(
+                "below top-{} cutoff: {:.4} < {:.4} (behind by {:.4})",
+                limit, score, cutoff, cutoff - score
+            ),
+            None => format!("outside top-{}", limit),
+        }
+    }
+}
+
+impl fmt::Display for ExplainReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "🔬 Explain: \"{}\"", self.query)?;
+        match self.mode {
+            SearchMode::Vector => writeln!(f, "   Search mode: vector")?,
+            SearchMode::Hybrid => writeln!(f, "   Search mode: hybrid (vector and BM25, reciprocal rank fusion)")?,
+            SearchMode::Keyword => writeln!(f, "   Search mode: keyword (BM25)")?,
+        }
+        if self.mode != SearchMode::Keyword {
+            writeln!(f, "   Query embedding: {} dimensions, L2 norm {:.4}", self.query_dimension, self.query_norm)?;
+        }
+        writeln!(f, "   Candidate pool: {} fetched for top-{}", self.candidate_pool, self.limit)?;
+        if self.filters.is_empty() {
+            writeln!(f, "   Filters: none applied")?;
+        } else {
+            for filter in &self.filters {
+                writeln!(f, "   Filter: {}", filter)?;
+            }
+        }
+        writeln!(f)?;
+        writeln!(f, "   {:>4}  {:>8}  {:>8}  {:>8}  {:>8}  {:<36}  reason", "rank", "dense", "sparse", "rerank", "final", "fragment")?;
+
+        for candidate in &self.candidates {
+            let dense = candidate.dense_score
+                .map(|s| format!("{:.4}", s))
+                .unwrap_or_else(|| "-".to_string());
+            let sparse = candidate.sparse_score
+                .map(|s| format!("{:.4}", s))
+                .unwrap_or_else(|| "-".to_string());
+            let rerank = candidate.rerank_delta
+                .map(|d| format!("{:+.4}", d))
+                .unwrap_or_else(|| "-".to_string());
+            let marker = if candidate.selected { "✓" } else { " " };
+
+            writeln!(
+                f,
+                " {} {:>4}  {:>8}  {:>8}  {:>8}  {:>8.4}  {:<36}  {}",
+                marker,
+                candidate.rank,
+                dense,
+                sparse,
+                rerank,
+                candidate.final_score,
+                candidate.fragment_id,
+                candidate.reason,
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_limit_per_document_collapses_extra_fragments() {
+        let hits = vec![("a.pdf", 1), ("a.pdf", 2), ("b.pdf", 3), ("a.pdf", 4), ("b.pdf", 5), ("c.pdf", 6)];
+
+        let (kept, collapsed) = limit_per_document(hits.clone(), 1, |hit| hit.0);
+        assert_eq!(kept, vec![("a.pdf", 1), ("b.pdf", 3), ("c.pdf", 6)]);
+        assert_eq!(collapsed, vec![("a.pdf", 2), ("a.pdf", 4), ("b.pdf", 5)]);
+
+        let (kept, collapsed) = limit_per_document(hits, 2, |hit| hit.0);
+        assert_eq!(kept, vec![("a.pdf", 1), ("a.pdf", 2), ("b.pdf", 3), ("b.pdf", 5), ("c.pdf", 6)]);
+        assert_eq!(collapsed, vec![("a.pdf", 4)]);
+    }
+
+    #[test]
+    fn test_explain_marks_cutoff() {
+        let candidates = vec![
+            ("a".to_string(), 0.9, None),
+            ("b".to_string(), 0.8, None),
+            ("c".to_string(), 0.5, None),
+        ];
+
+        let explained = explain_candidates(&candidates, 2);
+
+        assert!(explained[0].selected);
+        assert!(explained[1].selected);
+        assert!(!explained[2].selected);
+        assert!(explained[2].reason.contains("below top-2 cutoff"));
+    }
+
+    #[test]
+    fn test_explain_reports_boosts() {
+        let candidates = vec![
+            ("b".to_string(), 0.78, Some(0.02)),
+            ("a".to_string(), 0.85, Some(-0.05)),
+        ];
+
+        let explained = explain_candidates(&candidates, 1);
+
+        assert!(explained[0].reason.contains("by boosted score"));
+        assert!((explained[0].final_score - 0.80).abs() < 1e-9);
+        assert_eq!(explained[1].rerank_delta, Some(-0.05));
+        assert!(!explained[1].selected);
+    }
+
+    fn fragment(id: &str, score: f64) -> FragmentMatch {
+        FragmentMatch {
+            fragment_id: id.to_string(),
+            content: String::new(),
+            score,
+            source: FragmentSource::default(),
+        }
+    }
+
+    #[test]
+    fn test_explain_fused_shows_missing_scores() {
+        let candidates = vec![
+            FusedHit { fragment: fragment("a", 0.032), dense_score: Some(0.7), sparse_score: Some(4.2) },
+            FusedHit { fragment: fragment("b", 0.016), dense_score: None, sparse_score: Some(6.1) },
+        ];
+
+        let explained = explain_fused(&candidates, 1, SearchMode::Hybrid);
+
+        assert!(explained[0].reason.contains("by fused rank"));
+        assert_eq!(explained[1].dense_score, None);
+        assert!(explained[1].reason.contains("below top-1 cutoff"));
+    }
+
+    #[test]
+    fn test_cursor_round_trip() {
+        let cursor = SearchCursor {
+            offset: 20,
+            after: RankPosition { score: 0.8123456789, file_path: "docs/Q3 report.v2.pdf".to_string(), order: 7 },
+        };
+        assert_eq!(SearchCursor::decode(&cursor.encode()).unwrap(), cursor);
+        assert!(SearchCursor::decode("20.zz.7.").is_err());
+        assert!(SearchCursor::decode("20").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_pages_continue_without_gaps() {
+        let mut storage = crate::lancedb_storage::LanceDBStorage::new(std::path::Path::new("pages")).await.unwrap();
+        let mut embedding_manager = EmbeddingManager::hashing(32);
+        for (path, text) in [("b.txt", "apple pie"), ("a.txt", "apple pie"), ("c.txt", "apple tart"), ("d.txt", "pear tart"), ("e.txt", "plum jam")] {
+            let document = storage.store_document(std::path::Path::new(path), text.as_bytes()).await.unwrap();
+            let id = storage.store_text_fragment(&document, 0, text, &storage::FragmentMeta::default()).await.unwrap();
+            let embedding = embedding_manager.generate_embedding(text).await.unwrap();
+            storage.update_fragment_embedding(&id, &embedding).await.unwrap();
+        }
+        let filter = SearchFilter::default();
+        for mode in [SearchMode::Vector, SearchMode::Hybrid] {
+            let (all, _) = search(&mut storage, &mut embedding_manager, "apple pie", 10, &filter, mode, false).await.unwrap();
+            let mut paged = Vec::new();
+            let mut cursor: Option<SearchCursor> = None;
+            loop {
+                let page = search_page(&mut storage, &mut embedding_manager, "apple pie", 2, &filter, mode, cursor.as_ref()).await.unwrap();
+                assert!(page.hits.len() <= 2);
+                paged.extend(page.hits);
+                match page.next {
+                    Some(next) => cursor = Some(SearchCursor::decode(&next.encode()).unwrap()),
+                    None => break,
+                }
+            }
+            let ids = |hits: &[SearchHit]| hits.iter().map(|hit| hit.fragment_id.clone()).collect::<Vec<_>>();
+            assert_eq!(ids(&paged), ids(&all));
+            // Equal scores are ordered by path
+            if mode == SearchMode::Vector {
+                assert!(paged[0].source.file_path.ends_with("a.txt") && paged[1].source.file_path.ends_with("b.txt"));
+            }
+        }
+    }
+
+    #[test]
+    fn test_vector_norm() {
+        assert!((vector_norm(&[3.0, 4.0]) - 5.0).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,295 @@
+use anyhow::{Context, Result};
+use serde::Deserialize;
+
+use crate::annotations;
+use crate::embedding_manager::EmbeddingManager;
+use crate::hybrid::{fuse_ranked, select_mmr};
+use crate::presets;
+use crate::retrieval::{SearchCursor, SearchHit, SearchPage};
+use crate::storage::{self, FragmentMatch, RankPosition, SearchFilter, SimilarityMetric, Storage};
+
+/// Weight of relevance against novelty in MMR when a stage doesn't give one
+const DEFAULT_MMR_LAMBDA: f64 = 0.7;
+
+/// One step of a retrieval pipeline, a `[[retrieval.stages]]` table of the config file.
+///
+/// Retrieval stages (`dense`, `bm25`) each add a ranking of candidates; `rrf` fuses every
+/// ranking added so far into one. `rerank` and `mmr` reorder and cut that one ranking.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "stage", rename_all = "lowercase", deny_unknown_fields)]
+pub enum Stage {
+    /// The `k` fragments most similar to the query embedding
+    Dense { k: usize },
+    /// The `k` fragments ranked highest by BM25
+    Bm25 { k: usize },
+    /// Fuse the rankings so far by reciprocal rank
+    Rrf,
+    /// Re-score candidates by similarity to the query, by the database's metric, plus the
+    /// preset's boosts, and keep the `top`
+    Rerank { top: usize },
+    /// Keep `top` candidates chosen by maximal marginal relevance, trading relevance
+    /// against similarity to those already chosen by `lambda` (1.0 is pure relevance)
+    Mmr {
+        top: usize,
+        #[serde(default = "default_mmr_lambda")]
+        lambda: f64,
+    },
+}
+
+fn default_mmr_lambda() -> f64 {
+    DEFAULT_MMR_LAMBDA
+}
+
+impl Stage {
+    fn describe(&self) -> String {
+        match self {
+            Stage::Dense { k } => format!("dense k={}", k),
+            Stage::Bm25 { k } => format!("bm25 k={}", k),
+            Stage::Rrf => "rrf".to_string(),
+            Stage::Rerank { top } => format!("rerank top={}", top),
+            Stage::Mmr { top, lambda } => format!("mmr top={} lambda={}", top, lambda),
+        }
+    }
+}
+
+/// A checked sequence of stages, run in place of a fixed search mode
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetrievalPipeline {
+    stages: Vec<Stage>,
+}
+
+impl RetrievalPipeline {
+    /// Check the stages fit together: they start by retrieving, `rrf` has at least two
+    /// rankings to fuse, and `rerank`, `mmr` and the end see exactly one
+    pub fn new(stages: Vec<Stage>) -> Result<Self> {
+        let mut rankings = 0;
+        for (i, stage) in stages.iter().enumerate() {
+            let position = || format!("retrieval stage {} ({})", i + 1, stage.describe());
+            match stage {
+                Stage::Dense { k } | Stage::Bm25 { k } => {
+                    anyhow::ensure!(*k > 0, "{}: k must be at least 1", position());
+                    rankings += 1;
+                }
+                Stage::Rrf => {
+                    anyhow::ensure!(rankings >= 2, "{}: needs at least two rankings to fuse", position());
+                    rankings = 1;
+                }
+                Stage::Rerank { top } | Stage::Mmr { top, .. } => {
+                    anyhow::ensure!(*top > 0, "{}: top must be at least 1", position());
+                    anyhow::ensure!(rankings == 1, "{}: needs exactly one ranking; fuse them with rrf first", position());
+                }
+            }
+            if let Stage::Mmr { lambda, .. } = stage {
+                anyhow::ensure!((0.0..=1.0).contains(lambda), "{}: lambda must be between 0 and 1", position());
+            }
+        }
+        anyhow::ensure!(rankings == 1, "Retrieval stages must end with one ranking; fuse several with rrf");
+        Ok(Self { stages })
+    }
+
+    /// The stages as a one-line summary, e.g. `dense k=50 → bm25 k=50 → rrf`
+    pub fn describe(&self) -> String {
+        self.stages.iter().map(Stage::describe).collect::<Vec<_>>().join(" → ")
+    }
+
+    fn needs_embedding(&self) -> bool {
+        self.stages.iter().any(|stage| !matches!(stage, Stage::Bm25 { .. } | Stage::Rrf))
+    }
+
+    /// Run the stages for `query` and return the best `limit` fragments, with a line per
+    /// stage saying how many candidates it left
+    pub async fn run(
+        &self,
+        storage: &mut dyn Storage,
+        embedding_manager: &mut EmbeddingManager,
+        query: &str,
+        limit: usize,
+        filter: &SearchFilter,
+    ) -> Result<(Vec<SearchHit>, Vec<String>)> {
+        let query_embedding = if self.needs_embedding() {
+            let prefixes = storage::embedding_prefixes(storage).await?;
+            let embedding = embedding_manager.generate_embedding(&prefixes.query(query)).await
+                .context("Failed to generate query embedding")?;
+            if !filter.stale {
+                storage::check_dimension(storage, embedding_manager.model_name(), embedding.len()).await?;
+            }
+            embedding
+        } else {
+            Vec::new()
+        };
+        let metric = storage::similarity_metric(storage).await?;
+        // Scores of fused rankings depend on every candidate, so rankings aren't resumed
+        let filter = &SearchFilter { after: None, ..filter.clone() };
+
+        let mut rankings: Vec<Vec<FragmentMatch>> = Vec::new();
+        let mut trace = Vec::new();
+        for stage in &self.stages {
+            match stage {
+                Stage::Dense { k } => {
+                    rankings.push(storage.search_similar(&query_embedding, *k, filter).await
+                        .context("Failed to search similar content")?);
+                }
+                Stage::Bm25 { k } => {
+                    rankings.push(storage.search_keyword(query, *k, filter).await
+                        .context("Failed to run keyword search")?);
+                }
+                Stage::Rrf => {
+                    let fused = fuse(std::mem::take(&mut rankings));
+                    rankings.push(fused);
+                }
+                Stage::Rerank { top } => {
+                    let candidates = rankings.pop().unwrap_or_default();
+                    rankings.push(rerank(storage, metric, &query_embedding, candidates, *top).await?);
+                }
+                Stage::Mmr { top, lambda } => {
+                    let candidates = rankings.pop().unwrap_or_default();
+                    rankings.push(mmr(storage, metric, &query_embedding, candidates, *top, *lambda).await?);
+                }
+            }
+            let candidates = rankings.last().map_or(0, Vec::len);
+            trace.push(format!("{}: {} candidates", stage.describe(), candidates));
+        }
+
+        let hits = rankings.pop().unwrap_or_default().into_iter()
+            .take(limit)
+            .map(SearchHit::from)
+            .collect();
+        Ok((hits, trace))
+    }
+
+    /// One page of `limit` results after `cursor`. The stages rank the whole candidate
+    /// pool, so results of earlier pages are fetched again and skipped.
+    pub async fn search_page(
+        &self,
+        storage: &mut dyn Storage,
+        embedding_manager: &mut EmbeddingManager,
+        query: &str,
+        limit: usize,
+        filter: &SearchFilter,
+        cursor: Option<&SearchCursor>,
+    ) -> Result<(SearchPage, Vec<String>)> {
+        let offset = cursor.map_or(0, |cursor| cursor.offset);
+        let (hits, trace) = self.run(storage, embedding_manager, query, offset + limit + 1, filter).await?;
+        let mut hits: Vec<SearchHit> = hits.into_iter().skip(offset).collect();
+        let more = hits.len() > limit;
+        hits.truncate(limit);
+
+        let next = hits.last().filter(|_| more).map(|hit| SearchCursor {
+            offset: offset + hits.len(),
+            after: RankPosition { score: hit.score, file_path: hit.source.file_path.clone(), order: hit.source.order },
+        });
+        Ok((SearchPage { hits, next }, trace))
+    }
+}
+
+/// Reciprocal rank fusion of any number of best-first rankings; ties keep first-seen order
+fn fuse(rankings: Vec<Vec<FragmentMatch>>) -> Vec<FragmentMatch> {
+    fuse_ranked(rankings, |fragment| fragment.fragment_id.clone()).into_iter()
+        .map(|(fragment, score)| FragmentMatch { score, ..fragment })
+        .collect()
+}
+
+/// Candidates with their stored vectors; those without one (not embedded yet) are dropped,
+/// since they can't be compared
+async fn with_vectors(storage: &mut dyn Storage, candidates: Vec<FragmentMatch>) -> Result<Vec<(FragmentMatch, Vec<f64>)>> {
+    let ids: Vec<String> = candidates.iter().map(|fragment| fragment.fragment_id.clone()).collect();
+    let mut vectors = storage.get_fragment_vectors(&ids).await?;
+    Ok(candidates.into_iter()
+        .filter_map(|fragment| vectors.remove(&fragment.fragment_id).map(|vector| (fragment, vector)))
+        .collect())
+}
+
+async fn rerank(storage: &mut dyn Storage, metric: SimilarityMetric, query_embedding: &[f64], candidates: Vec<FragmentMatch>, top: usize) -> Result<Vec<FragmentMatch>> {
+    let mut rescored: Vec<FragmentMatch> = with_vectors(storage, candidates).await?.into_iter()
+        .map(|(fragment, vector)| FragmentMatch { score: metric.score(query_embedding, &vector), ..fragment })
+        .collect();
+    rescored.sort_by(|a, b| b.score.total_cmp(&a.score));
+    if let Some(booster) = presets::recorded_booster(storage).await? {
+        rescored = booster.rerank(rescored).into_iter().map(|(fragment, _)| fragment).collect();
+    }
+    if let Some(weights) = annotations::ranking_boost(storage).await? {
+        rescored = annotations::rerank(rescored, &weights).into_iter().map(|(fragment, _)| fragment).collect();
+    }
+    rescored.truncate(top);
+    Ok(rescored)
+}
+
+/// Greedy maximal marginal relevance: each pick maximises `lambda · relevance − (1 − lambda)
+/// · similarity to the closest pick so far`, both by `metric`. Picks keep their relevance as
+/// their score.
+async fn mmr(storage: &mut dyn Storage, metric: SimilarityMetric, query_embedding: &[f64], candidates: Vec<FragmentMatch>, top: usize, lambda: f64) -> Result<Vec<FragmentMatch>> {
+    let candidates: Vec<((FragmentMatch, Vec<f64>), f64)> = with_vectors(storage, candidates).await?.into_iter()
+        .map(|(fragment, vector)| {
+            let relevance = metric.score(query_embedding, &vector);
+            ((fragment, vector), relevance)
+        })
+        .collect();
+    let picked = select_mmr(candidates, top, lambda, |(_, a), (_, b)| metric.score(a, b), |_, _| true);
+    Ok(picked.into_iter().map(|((fragment, _), relevance)| FragmentMatch { score: relevance, ..fragment }).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lancedb_storage::LanceDBStorage;
+    use crate::storage::FragmentMeta;
+    use std::path::Path;
+
+    #[test]
+    fn test_stages_parse_and_check() {
+        #[derive(Deserialize)]
+        struct Retrieval {
+            stages: Vec<Stage>,
+        }
+        let retrieval: Retrieval = toml::from_str(r#"
+            stages = [
+                { stage = "dense", k = 50 },
+                { stage = "bm25", k = 50 },
+                { stage = "rrf" },
+                { stage = "rerank", top = 20 },
+                { stage = "mmr", top = 8 },
+            ]
+        "#).unwrap();
+        let pipeline = RetrievalPipeline::new(retrieval.stages).unwrap();
+        assert_eq!(pipeline.describe(), "dense k=50 → bm25 k=50 → rrf → rerank top=20 → mmr top=8 lambda=0.7");
+
+        assert!(RetrievalPipeline::new(vec![Stage::Dense { k: 10 }, Stage::Bm25 { k: 10 }]).is_err());
+        assert!(RetrievalPipeline::new(vec![Stage::Dense { k: 10 }, Stage::Rrf]).is_err());
+        assert!(RetrievalPipeline::new(vec![Stage::Rerank { top: 5 }]).is_err());
+        assert!(RetrievalPipeline::new(vec![Stage::Dense { k: 10 }, Stage::Mmr { top: 5, lambda: 1.5 }]).is_err());
+    }
+
+    #[test]
+    fn test_fusion_rewards_agreement() {
+        let fragment = |id: &str| FragmentMatch { fragment_id: id.to_string(), content: String::new(), score: 0.0, source: Default::default() };
+        let fused = fuse(vec![
+            vec![fragment("a"), fragment("b")],
+            vec![fragment("b"), fragment("c")],
+            vec![fragment("c"), fragment("b")],
+        ]);
+        assert_eq!(fused.iter().map(|f| f.fragment_id.as_str()).collect::<Vec<_>>(), vec!["b", "c", "a"]);
+    }
+
+    #[tokio::test]
+    async fn test_mmr_skips_near_duplicates() {
+        let mut storage = LanceDBStorage::new(Path::new("mmr")).await.unwrap();
+        let mut embedding_manager = EmbeddingManager::hashing(64);
+        storage.verify_or_set_model(embedding_manager.model_name(), Some(64)).await.unwrap();
+        for (path, text) in [("a.txt", "apple pie recipe"), ("b.txt", "apple pie recipe"), ("c.txt", "apple tart with pears")] {
+            let document = storage.store_document(Path::new(path), text.as_bytes()).await.unwrap();
+            let id = storage.store_text_fragment(&document, 0, text, &FragmentMeta::default()).await.unwrap();
+            let embedding = embedding_manager.generate_embedding(text).await.unwrap();
+            storage.update_fragment_embedding(&id, &embedding).await.unwrap();
+        }
+
+        let paths = |hits: &[SearchHit]| hits.iter().map(|hit| hit.source.file_path.clone()).collect::<Vec<_>>();
+        let dense = RetrievalPipeline::new(vec![Stage::Dense { k: 3 }]).unwrap();
+        let (hits, _) = dense.run(&mut storage, &mut embedding_manager, "apple pie", 2, &SearchFilter::default()).await.unwrap();
+        assert!(paths(&hits).iter().all(|path| !path.ends_with("c.txt")));
+
+        let diverse = RetrievalPipeline::new(vec![Stage::Dense { k: 3 }, Stage::Mmr { top: 2, lambda: 0.5 }]).unwrap();
+        let (hits, trace) = diverse.run(&mut storage, &mut embedding_manager, "apple pie", 2, &SearchFilter::default()).await.unwrap();
+        assert!(paths(&hits)[1].ends_with("c.txt"));
+        assert_eq!(trace, vec!["dense k=3: 3 candidates", "mmr top=2 lambda=0.5: 2 candidates"]);
+    }
+}
@@ -0,0 +1,128 @@
+use anyhow::{Context, Result};
+use log::warn;
+use std::path::Path;
+
+use crate::embedding_manager::EmbeddingManager;
+use crate::hybrid::SearchMode;
+use crate::retrieval::{self, SearchCursor, SearchHit, SearchPage};
+use crate::storage::{self, create_storage, SearchFilter, Storage, StorageBackend};
+
+/// Searches a brain the way `portable-brains search` does: queries are embedded with the
+/// database's query prefix, the preset's boosts apply, and while a model upgrade is in
+/// progress the vectors matching the embedding manager's model are searched.
+pub struct Retriever {
+    storage: Box<dyn Storage>,
+    embedding_manager: EmbeddingManager,
+    mode: SearchMode,
+    /// Search the previous model's stale vectors
+    stale: bool,
+}
+
+impl Retriever {
+    /// Open the database at `path`, choosing the backend from its extension and embedding
+    /// queries with the model recorded in it
+    pub async fn open(path: &Path) -> Result<Self> {
+        let mut storage = create_storage(&StorageBackend::from_path(path), path).await
+            .with_context(|| format!("Failed to open database {}", path.display()))?;
+        let model = storage.get_meta_info().await?.embedding_model;
+        if model == "unknown" {
+            anyhow::bail!("{} has no recorded embedding model", path.display());
+        }
+        let embedding_manager = EmbeddingManager::new(&model).await
+            .context("Failed to initialize embedding manager")?;
+        Self::new(storage, embedding_manager).await
+    }
+
+    /// Search an opened database, embedding queries with `embedding_manager`, which must
+    /// use the model the database was indexed with (or is being upgraded from)
+    pub async fn new(mut storage: Box<dyn Storage>, embedding_manager: EmbeddingManager) -> Result<Self> {
+        let space = storage::query_space(&mut *storage, embedding_manager.model_name()).await?;
+        if let Some(warning) = &space.warning {
+            warn!("{}", warning);
+        }
+        Ok(Self { storage, embedding_manager, mode: SearchMode::Vector, stale: space.stale })
+    }
+
+    /// Rank fragments by keyword or hybrid search instead of vector similarity
+    pub fn with_mode(mut self, mode: SearchMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// The `limit` fragments that best match `query`, best first. Accepts the same
+    /// `section:"Heading"` filter as `portable-brains search`.
+    pub async fn search(&mut self, query: &str, limit: usize) -> Result<Vec<SearchHit>> {
+        let (query, section) = SearchFilter::parse_section(query);
+        self.search_filtered(&query, limit, SearchFilter { section, ..SearchFilter::default() }).await
+    }
+
+    /// Like `search`, restricted by `filter`, e.g. to one document or collection
+    pub async fn search_filtered(&mut self, query: &str, limit: usize, filter: SearchFilter) -> Result<Vec<SearchHit>> {
+        let filter = SearchFilter { stale: self.stale, ..filter };
+        let (hits, _) = retrieval::search(
+            &mut *self.storage,
+            &mut self.embedding_manager,
+            query,
+            limit,
+            &filter,
+            self.mode,
+            false,
+        ).await?;
+
+        self.record_hits(&hits).await;
+        Ok(hits)
+    }
+
+    /// A page of up to `limit` results, continuing after `cursor` from the previous page's
+    /// `next`. Pages follow the same order as `search` and neither skip nor repeat results.
+    pub async fn search_page(&mut self, query: &str, limit: usize, cursor: Option<&SearchCursor>) -> Result<SearchPage> {
+        let (query, section) = SearchFilter::parse_section(query);
+        let filter = SearchFilter { section, stale: self.stale, ..SearchFilter::default() };
+        let page = retrieval::search_page(
+            &mut *self.storage,
+            &mut self.embedding_manager,
+            &query,
+            limit,
+            &filter,
+            self.mode,
+            cursor,
+        ).await?;
+        self.record_hits(&page.hits).await;
+        Ok(page)
+    }
+
+    /// Hit counts only steer re-embedding order after a model upgrade, so failures are logged
+    async fn record_hits(&mut self, hits: &[SearchHit]) {
+        let hit_ids: Vec<String> = hits.iter().map(|hit| hit.fragment_id.clone()).collect();
+        if let Err(e) = self.storage.record_fragment_hits(&hit_ids).await {
+            warn!("Failed to record search hits: {}", e);
+        }
+    }
+
+    /// The underlying storage, e.g. to fetch a hit's document
+    pub fn storage(&mut self) -> &mut dyn Storage {
+        &mut *self.storage
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::indexer::Indexer;
+
+    #[tokio::test]
+    async fn test_retriever_finds_indexed_text() {
+        let storage = Box::new(crate::lancedb_storage::LanceDBStorage::new(Path::new("retriever")).await.unwrap());
+        let mut indexer = Indexer::new(storage, EmbeddingManager::hashing(64)).await.unwrap();
+        indexer.add_document(Path::new("pets.txt"), b"Cats sleep for most of the day.").await.unwrap();
+        indexer.add_document(Path::new("tax.txt"), b"Tax returns are due in April.").await.unwrap();
+        indexer.embed_pending().await.unwrap();
+
+        let mut retriever = Retriever::new(indexer.into_storage(), EmbeddingManager::hashing(64)).await.unwrap();
+        let hits = retriever.search("when are tax returns due", 1).await.unwrap();
+        assert_eq!(hits[0].source.filename, "tax.txt");
+
+        let mut retriever = retriever.with_mode(SearchMode::Keyword);
+        assert_eq!(retriever.search("cats", 5).await.unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,270 @@
+use anyhow::{Context, Result};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::config::ScanConfig;
+use crate::error::PortableBrainsError;
+use crate::storage::sidecar_path;
+
+/// Outcome of scanning one file
+#[derive(Debug, Clone, PartialEq)]
+pub enum Verdict {
+    Clean,
+    Suspicious(String),
+}
+
+/// Inspects a document's original bytes before it is parsed or stored
+pub trait Scanner: Send + Sync {
+    fn name(&self) -> &str;
+    fn scan(&self, path: &Path, data: &[u8]) -> Result<Verdict>;
+}
+
+/// Accepts only files whose content is one of the allowed types and agrees with their extension
+pub struct TypeAllowlist {
+    allowed: Vec<&'static str>,
+}
+
+impl TypeAllowlist {
+    pub fn new(types: &[String]) -> Result<Self> {
+        let mut allowed = Vec::new();
+        for name in types {
+            let canonical = match name.to_lowercase().as_str() {
+                "pdf" => "pdf",
+                "docx" => "docx",
+                "pptx" => "pptx",
+                "xlsx" => "xlsx",
+                "epub" => "epub",
+                "html" | "htm" => "html",
+                "text" | "txt" => "text",
+                other => anyhow::bail!("Unknown document type '{}' in allow_types", other),
+            };
+            allowed.push(canonical);
+        }
+        Ok(Self { allowed })
+    }
+}
+
+impl Scanner for TypeAllowlist {
+    fn name(&self) -> &str {
+        "type allowlist"
+    }
+
+    fn scan(&self, path: &Path, data: &[u8]) -> Result<Verdict> {
+        let detected = sniff_type(data);
+        if !self.allowed.contains(&detected) {
+            return Ok(Verdict::Suspicious(format!("content is {}, which is not an allowed type", detected)));
+        }
+
+        let extension = path.extension()
+            .and_then(|ext| ext.to_str())
+            .map(|ext| ext.to_lowercase())
+            .unwrap_or_default();
+        let expected = match extension.as_str() {
+            "pdf" => "pdf",
+            "docx" => "docx",
+            "pptx" => "pptx",
+            "xlsx" => "xlsx",
+            "epub" => "epub",
+            "html" | "htm" => "html",
+            _ => "text",
+        };
+        // HTML is text, so either spelling of a text file is fine
+        let agrees = detected == expected || matches!((expected, detected), ("text", "html") | ("html", "text"));
+        if !agrees {
+            return Ok(Verdict::Suspicious(format!("extension .{} but content is {}", extension, detected)));
+        }
+        Ok(Verdict::Clean)
+    }
+}
+
+/// Runs an external scanner; a file is suspicious when the command exits non-zero or
+/// prints anything to stdout (yara prints matching rules, `clamscan --infected` infections)
+pub struct CommandScanner {
+    program: String,
+    args: Vec<String>,
+}
+
+impl CommandScanner {
+    pub fn new(command: &str) -> Result<Self> {
+        let mut parts = command.split_whitespace().map(str::to_string);
+        let program = parts.next()
+            .ok_or_else(|| anyhow::anyhow!("Scan command is empty"))?;
+        let mut args: Vec<String> = parts.collect();
+        if !args.iter().any(|arg| arg.contains("{}")) {
+            args.push("{}".to_string());
+        }
+        Ok(Self { program, args })
+    }
+}
+
+impl Scanner for CommandScanner {
+    fn name(&self) -> &str {
+        &self.program
+    }
+
+    fn scan(&self, path: &Path, _data: &[u8]) -> Result<Verdict> {
+        let path_text = path.to_string_lossy();
+        let output = Command::new(&self.program)
+            .args(self.args.iter().map(|arg| arg.replace("{}", &path_text)))
+            .output()
+            .with_context(|| format!("Failed to run scanner {}", self.program))?;
+
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let report = stdout.lines().next().unwrap_or("").trim().to_string();
+        if !output.status.success() || !report.is_empty() {
+            let reason = if report.is_empty() { format!("exited with {}", output.status) } else { report };
+            return Ok(Verdict::Suspicious(reason));
+        }
+        Ok(Verdict::Clean)
+    }
+}
+
+/// Runs every configured scanner over a document and quarantines it on the first hit
+pub struct ContentScanner {
+    scanners: Vec<Box<dyn Scanner>>,
+    quarantine_dir: PathBuf,
+}
+
+impl ContentScanner {
+    /// Build the scanners a config asks for, or `None` when it configures none
+    pub fn from_config(config: &ScanConfig, database: &Path) -> Result<Option<Self>> {
+        let mut scanners: Vec<Box<dyn Scanner>> = Vec::new();
+        if let Some(types) = &config.allow_types {
+            scanners.push(Box::new(TypeAllowlist::new(types)?));
+        }
+        if let Some(command) = &config.command {
+            scanners.push(Box::new(CommandScanner::new(command)?));
+        }
+        if scanners.is_empty() {
+            return Ok(None);
+        }
+
+        let quarantine_dir = config.quarantine_dir.clone()
+            .unwrap_or_else(|| sidecar_path(database, ".quarantine"));
+        Ok(Some(Self { scanners, quarantine_dir }))
+    }
+
+    pub fn scanner_names(&self) -> Vec<&str> {
+        self.scanners.iter().map(|s| s.name()).collect()
+    }
+
+    pub fn quarantine_dir(&self) -> &Path {
+        &self.quarantine_dir
+    }
+
+    /// Scan a document's bytes. A suspicious file is copied to the quarantine directory,
+    /// logged, and rejected with `PortableBrainsError::Quarantined`.
+    pub fn check(&self, path: &Path, data: &[u8]) -> Result<()> {
+        for scanner in &self.scanners {
+            if let Verdict::Suspicious(reason) = scanner.scan(path, data)? {
+                let reason = format!("{}: {}", scanner.name(), reason);
+                self.quarantine(path, data, &reason)?;
+                return Err(PortableBrainsError::Quarantined(reason).into());
+            }
+        }
+        Ok(())
+    }
+
+    fn quarantine(&self, path: &Path, data: &[u8], reason: &str) -> Result<()> {
+        std::fs::create_dir_all(&self.quarantine_dir)
+            .with_context(|| format!("Failed to create quarantine directory {}", self.quarantine_dir.display()))?;
+
+        // Never overwrite an earlier quarantined file of the same name
+        let name = path.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_else(|| "unknown".to_string());
+        let mut target = self.quarantine_dir.join(&name);
+        let mut n = 1;
+        while target.exists() {
+            target = self.quarantine_dir.join(format!("{}.{}", name, n));
+            n += 1;
+        }
+        std::fs::write(&target, data)
+            .with_context(|| format!("Failed to quarantine {}", path.display()))?;
+
+        let mut log = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.quarantine_dir.join("quarantine.log"))
+            .context("Failed to open quarantine log")?;
+        writeln!(log, "{}\t{}\t{}\t{}", chrono::Utc::now().to_rfc3339(), path.display(), target.display(), reason)?;
+        Ok(())
+    }
+}
+
+/// Identify a file's type from its leading bytes
+pub fn sniff_type(data: &[u8]) -> &'static str {
+    let head = &data[..data.len().min(1024)];
+
+    if head.starts_with(b"MZ")
+        || head.starts_with(b"\x7fELF")
+        || [b"\xfe\xed\xfa\xce", b"\xfe\xed\xfa\xcf", b"\xce\xfa\xed\xfe", b"\xcf\xfa\xed\xfe", b"\xca\xfe\xba\xbe"]
+            .iter()
+            .any(|magic| head.starts_with(*magic))
+    {
+        return "executable";
+    }
+    if head.starts_with(b"#!") {
+        return "script";
+    }
+    // PDF readers accept junk before the header, so look a little way in
+    if head.windows(5).any(|w| w == b"%PDF-") {
+        return "pdf";
+    }
+    if data.starts_with(b"PK\x03\x04") {
+        // Office Open XML packages are zips whose part names give the document kind; an
+        // EPUB's first entry is a `mimetype` file naming it
+        let contains = |needle: &[u8]| data.windows(needle.len()).any(|w| w == needle);
+        return if head.windows(20).any(|w| w == b"application/epub+zip") {
+            "epub"
+        } else if contains(b"word/") {
+            "docx"
+        } else if contains(b"ppt/") {
+            "pptx"
+        } else if contains(b"xl/") {
+            "xlsx"
+        } else {
+            "zip"
+        };
+    }
+
+    let Ok(text) = std::str::from_utf8(head).or_else(|e| {
+        // The 1 KB window may cut a multi-byte character
+        if e.error_len().is_none() { std::str::from_utf8(&head[..e.valid_up_to()]) } else { Err(e) }
+    }) else {
+        return "binary";
+    };
+    if text.chars().any(|c| c.is_control() && !c.is_whitespace()) {
+        return "binary";
+    }
+    let lower = text.trim_start().to_lowercase();
+    if lower.starts_with("<!doctype html") || lower.starts_with("<html") || lower.contains("<body") {
+        return "html";
+    }
+    "text"
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sniff_type() {
+        assert_eq!(sniff_type(b"%PDF-1.7\n..."), "pdf");
+        assert_eq!(sniff_type(b"MZ\x90\x00\x03"), "executable");
+        assert_eq!(sniff_type(b"PK\x03\x04....word/document.xml"), "docx");
+        assert_eq!(sniff_type(b"PK\x03\x04....mimetypeapplication/epub+zipPK\x03\x04"), "epub");
+        assert_eq!(sniff_type(b"<!DOCTYPE html><html><body>hi</body></html>"), "html");
+        assert_eq!(sniff_type("Plain notes, café".as_bytes()), "text");
+        assert_eq!(sniff_type(b"\x00\x01\x02\x03"), "binary");
+    }
+
+    #[test]
+    fn test_allowlist_rejects_disguised_files() {
+        let allowlist = TypeAllowlist::new(&["pdf".to_string(), "txt".to_string()]).unwrap();
+        assert_eq!(allowlist.scan(Path::new("a.pdf"), b"%PDF-1.4").unwrap(), Verdict::Clean);
+        assert!(matches!(allowlist.scan(Path::new("invoice.pdf"), b"MZ\x90\x00"), Ok(Verdict::Suspicious(_))));
+        assert!(matches!(allowlist.scan(Path::new("notes.txt"), b"%PDF-1.4"), Ok(Verdict::Suspicious(_))));
+        assert!(TypeAllowlist::new(&["exe".to_string()]).is_err());
+    }
+}
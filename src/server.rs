@@ -0,0 +1,1511 @@
+use anyhow::{Context, Result};
+use axum::body::{Body, Bytes};
+use axum::extract::{DefaultBodyLimit, Extension, FromRequest, Multipart, Path, Query, Request, State};
+use axum::http::{header, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::{Html, IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use futures::stream;
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{mpsc, watch, Mutex};
+use utoipa::openapi::security::{HttpAuthScheme, HttpBuilder, SecurityScheme};
+use utoipa::{Modify, OpenApi, ToSchema};
+use utoipa_swagger_ui::SwaggerUi;
+
+use crate::autocomplete::{self, PrefixIndex, Suggestion, Term, TermKind};
+use crate::config::{mime_type, Config, LiveConfig};
+use crate::dashboard::{self, GrowthSample, IndexHealth, JobSummary, QueryCount};
+use crate::embedding_manager::EmbeddingManager;
+use crate::hybrid::SearchMode;
+use crate::jobs::{ItemOutcome, Job, JobKind, JobRecord, JobStore};
+use crate::offline;
+use crate::profiler::StageTimings;
+use crate::retrieval::{self, SearchCursor};
+use crate::storage::{self, SearchFilter, Storage};
+use crate::tenants::{self, Access, Caller, TenantUsage};
+use crate::{ingest_queue, IngestPipeline};
+
+/// Size of the pieces an original is written to the response in
+const STREAM_CHUNK_BYTES: usize = 64 * 1024;
+/// Types browsers render as pages that could run script on the server's origin; originals
+/// of these open in a sandbox
+const ACTIVE_CONTENT_TYPES: &[&str] = &[
+    "text/html", "application/xhtml+xml", "image/svg+xml", "text/xml", "application/xml",
+    "text/javascript", "application/javascript",
+];
+/// Fragments read from storage at a time while streaming a document's text
+const TEXT_PAGE_FRAGMENTS: i32 = 100;
+/// Largest `POST /ingest` request body, across all of its files
+const MAX_UPLOAD_BYTES: usize = 512 * 1024 * 1024;
+/// Largest document fetched from a URL, matching the indexer's file size limit
+pub(crate) const MAX_DOWNLOAD_BYTES: u64 = 100 * 1024 * 1024;
+/// Fragments embedded per storage lock while an ingest job runs its embed phase
+const EMBED_BATCH_SIZE: i32 = 50;
+/// Most fragments one `POST /search` can return
+const MAX_SEARCH_LIMIT: usize = 100;
+/// Pause before a warm-up searches, so a burst of keystrokes only searches for the last one
+const WARM_DEBOUNCE: Duration = Duration::from_millis(150);
+/// How long a warmed-up search can answer `POST /search`
+const WARM_TTL: Duration = Duration::from_secs(60);
+/// Warmed-up searches kept at once
+const WARM_CAPACITY: usize = 256;
+/// Searches whose results `serve` keeps for repeated queries, unless `--cache-size` says otherwise
+pub const DEFAULT_SEARCH_CACHE: usize = 1024;
+
+/// Shared by every request; storage calls are serialized through the mutex
+pub struct ServerState {
+    storage: Mutex<Box<dyn Storage>>,
+    /// Accepted bearer tokens and their tenants; replaced when the config file is reloaded
+    access: RwLock<Access>,
+    /// Embeds search queries and, with `--embed`, ingested documents. Lock it before storage.
+    embedding_manager: Option<Mutex<EmbeddingManager>>,
+    jobs: JobStore,
+    /// Database being served, whose size the dashboard reports
+    database: PathBuf,
+    upload_dir: PathBuf,
+    queue: mpsc::UnboundedSender<IngestJob>,
+    /// Searches run ahead by `POST /search/warm`; never held across an await
+    warm: std::sync::Mutex<WarmCache>,
+    /// Results of recent searches, reused while the dataset version is unchanged; never
+    /// held across an await
+    cache: std::sync::Mutex<SearchCache>,
+}
+
+/// How documents sent to `POST /ingest` are indexed
+pub struct IngestSettings {
+    pub pipeline: IngestPipeline,
+    /// Answers `POST /search`, and embeds ingested documents when `embed` is set
+    pub embedding_manager: Option<EmbeddingManager>,
+    /// Run the embed phase after each job; otherwise fragments wait for `portable-brains embed`
+    pub embed: bool,
+    /// Uploads and downloads are kept here until their job has indexed them
+    pub upload_dir: PathBuf,
+    /// Config file whose reloads are applied before the next job starts
+    pub config: Option<LiveConfig>,
+    /// Database being served, for the default quarantine directory of a reloaded scanner
+    pub database: PathBuf,
+}
+
+/// OpenAPI description of the REST API, generated from the handlers' annotations
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "Portable Brains", description = "REST API over a Portable Brains database"),
+    paths(list_documents, document_original, document_file, document_text, search, warm_search, suggest, ingest, index, list_jobs, job_status, cancel_job, usage, stats, cache_stats),
+    components(schemas(IndexHealth, JobSummary, GrowthSample, QueryCount, ErrorBody, DocumentEntry, SearchRequest, ApiSearchMode, WarmRequest, SearchResults, SearchResult, Suggestion, TermKind, IngestUpload, IngestUrls, JobRecord, TenantUsage, CacheStats)),
+    modifiers(&BearerAuth),
+    security(("bearer" = [])),
+)]
+pub struct ApiDoc;
+
+struct BearerAuth;
+
+impl Modify for BearerAuth {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme("bearer", SecurityScheme::Http(HttpBuilder::new().scheme(HttpAuthScheme::Bearer).build()));
+    }
+}
+
+/// API routes behind bearer token authentication, plus the spec at `/openapi.json` and
+/// Swagger UI at `/docs`, which are open so clients can discover the API, and the health
+/// dashboard at `/dashboard`, which asks for a token before reading `/stats`
+pub fn router(state: Arc<ServerState>) -> Router {
+    let api = Router::new()
+        .route("/documents", get(list_documents))
+        .route("/documents/{id}/original", get(document_original))
+        .route("/documents/{id}/file", get(document_file))
+        .route("/documents/{id}/text", get(document_text))
+        .route("/search", post(search))
+        .route("/search/warm", post(warm_search))
+        .route("/suggest", get(suggest))
+        .route("/ingest", post(ingest).layer(DefaultBodyLimit::max(MAX_UPLOAD_BYTES)))
+        .route("/index", post(index).layer(DefaultBodyLimit::max(MAX_UPLOAD_BYTES)))
+        .route("/jobs", get(list_jobs))
+        .route("/jobs/{id}", get(job_status))
+        .route("/jobs/{id}/cancel", post(cancel_job))
+        .route("/usage", get(usage))
+        .route("/stats", get(stats))
+        .route("/stats/cache", get(cache_stats))
+        .layer(middleware::from_fn_with_state(state.clone(), require_token))
+        .with_state(state);
+
+    Router::new()
+        .merge(SwaggerUi::new("/docs").url("/openapi.json", ApiDoc::openapi()))
+        .route("/dashboard", get(|| async { Html(dashboard::PAGE) }))
+        .merge(api)
+}
+
+/// Serve the REST API on `bind` until the process is stopped. `token` is accepted along
+/// with the config file's `[serve]` and `[tenants]` tokens, which follow reloads.
+pub async fn serve(
+    storage: Box<dyn Storage>,
+    bind: SocketAddr,
+    token: Option<String>,
+    jobs: JobStore,
+    search_cache: usize,
+    mut ingest: IngestSettings,
+) -> Result<()> {
+    let (queue, queued) = mpsc::unbounded_channel();
+    let state = Arc::new(ServerState {
+        storage: Mutex::new(storage),
+        access: RwLock::new(Access::new(token.as_deref(), ingest.config.as_ref().map(|live| live.current()).as_deref())),
+        embedding_manager: ingest.embedding_manager.take().map(Mutex::new),
+        jobs,
+        database: ingest.database.clone(),
+        upload_dir: ingest.upload_dir.clone(),
+        queue,
+        warm: std::sync::Mutex::new(WarmCache::default()),
+        cache: std::sync::Mutex::new(SearchCache::new(search_cache)),
+    });
+    if let Some(live) = &ingest.config {
+        tokio::spawn(follow_token_changes(state.clone(), token, live.subscribe()));
+    }
+    tokio::spawn(run_ingest_worker(state.clone(), ingest, queued));
+
+    let listener = tokio::net::TcpListener::bind(bind).await
+        .with_context(|| format!("Failed to listen on {}", bind))?;
+    println!("🌐 Serving on http://{}", listener.local_addr()?);
+
+    axum::serve(listener, router(state)).await
+        .context("Server failed")
+}
+
+/// Body of every error response
+#[derive(Serialize, ToSchema)]
+pub struct ErrorBody {
+    pub error: String,
+}
+
+fn error_response(status: StatusCode, message: &str) -> Response {
+    (status, Json(ErrorBody { error: message.to_string() })).into_response()
+}
+
+enum ApiError {
+    NotFound(&'static str),
+    BadRequest(String),
+    Conflict(String),
+    /// Only admin tokens may make the request
+    AdminOnly,
+    /// The server wasn't started with what the request needs
+    Unavailable(&'static str),
+    Internal(anyhow::Error),
+}
+
+impl From<anyhow::Error> for ApiError {
+    fn from(err: anyhow::Error) -> Self {
+        ApiError::Internal(err)
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        match self {
+            ApiError::NotFound(what) => error_response(StatusCode::NOT_FOUND, &format!("{} not found", what)),
+            ApiError::BadRequest(message) => error_response(StatusCode::BAD_REQUEST, &message),
+            ApiError::Conflict(message) => error_response(StatusCode::CONFLICT, &message),
+            ApiError::AdminOnly => error_response(StatusCode::FORBIDDEN, "needs an admin token"),
+            ApiError::Unavailable(message) => error_response(StatusCode::SERVICE_UNAVAILABLE, message),
+            // Details stay in the server log rather than going to clients
+            ApiError::Internal(err) => {
+                error!("Request failed: {:?}", err);
+                error_response(StatusCode::INTERNAL_SERVER_ERROR, "internal error")
+            }
+        }
+    }
+}
+
+/// Swap the accepted tokens and tenants whenever the config is reloaded; requests already
+/// past authentication are unaffected
+async fn follow_token_changes(state: Arc<ServerState>, token: Option<String>, mut updates: watch::Receiver<Arc<Config>>) {
+    while updates.changed().await.is_ok() {
+        let access = Access::new(token.as_deref(), Some(&updates.borrow_and_update()));
+        if access.is_empty() {
+            warn!("The reloaded config leaves no access tokens; every request will be refused");
+        }
+        *state.access.write().unwrap_or_else(|poisoned| poisoned.into_inner()) = access;
+        // A tenant's collections may have changed, and cached results are scoped by them
+        state.cache.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).clear();
+    }
+}
+
+/// Authenticate the bearer token and hand the handler who it belongs to
+async fn require_token(State(state): State<Arc<ServerState>>, mut request: Request, next: Next) -> Response {
+    let caller = request.headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .and_then(|presented| state.access.read().unwrap_or_else(|poisoned| poisoned.into_inner()).authenticate(presented));
+
+    match caller {
+        Some(caller) => {
+            request.extensions_mut().insert(caller);
+            next.run(request).await
+        }
+        None => {
+            let mut response = error_response(StatusCode::UNAUTHORIZED, "missing or invalid bearer token");
+            response.headers_mut().insert(header::WWW_AUTHENTICATE, header::HeaderValue::from_static("Bearer"));
+            response
+        }
+    }
+}
+
+/// Compare tokens without leaking how long a matching prefix was
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Fail with 404 unless the document is in one of the caller's collections, so tenants
+/// can't tell other tenants' documents from missing ones
+async fn check_document_access(state: &ServerState, caller: &Caller, id: &str) -> Result<(), ApiError> {
+    if let Caller::Tenant(_) = caller {
+        let collection = state.storage.lock().await.get_document_collection(id).await?;
+        if !caller.can_read(collection.as_deref()) {
+            return Err(ApiError::NotFound("document"));
+        }
+    }
+    Ok(())
+}
+
+/// A stored document, as listed by `GET /documents`
+#[derive(Serialize, ToSchema)]
+pub struct DocumentEntry {
+    pub id: String,
+    pub filename: String,
+    pub file_path: String,
+    /// Title derived from the document's text, for documents indexed since titles were added
+    pub title: Option<String>,
+    pub fragments: i32,
+    /// Collection the document was routed to, if any
+    pub collection: Option<String>,
+    /// The source file is gone; the document is excluded from search
+    pub tombstoned: bool,
+    /// Modification time of the source file when it was indexed, in microseconds since the
+    /// Unix epoch
+    pub modified: Option<i64>,
+}
+
+/// Documents the caller may read, ordered by path
+#[utoipa::path(
+    get,
+    path = "/documents",
+    responses(
+        (status = 200, description = "Stored documents; a tenant only gets its own collections'", body = Vec<DocumentEntry>),
+        (status = 401, description = "Missing or invalid bearer token", body = ErrorBody),
+    ),
+)]
+async fn list_documents(
+    State(state): State<Arc<ServerState>>,
+    Extension(caller): Extension<Caller>,
+) -> Result<Json<Vec<DocumentEntry>>, ApiError> {
+    let mut storage = state.storage.lock().await;
+    let mut entries = Vec::new();
+    for document in storage.list_documents().await? {
+        let collection = storage.get_document_collection(&document.id).await?;
+        if !caller.can_read(collection.as_deref()) {
+            continue;
+        }
+        entries.push(DocumentEntry {
+            id: document.id,
+            filename: document.filename,
+            file_path: document.file_path,
+            title: document.title,
+            fragments: document.fragments,
+            collection,
+            tombstoned: document.tombstoned,
+            modified: document.modified,
+        });
+    }
+    Ok(Json(entries))
+}
+
+/// The stored original file, opened inline so citation links can point into it. Types a
+/// browser would run script in, such as HTML, are sandboxed.
+#[utoipa::path(
+    get,
+    path = "/documents/{id}/original",
+    params(("id" = String, Path, description = "Document id, as shown by `portable-brains list`")),
+    responses(
+        (status = 200, description = "The original file, with its MIME type and file name", content_type = "application/octet-stream"),
+        (status = 401, description = "Missing or invalid bearer token", body = ErrorBody),
+        (status = 404, description = "No document with this id, or its original wasn't stored", body = ErrorBody),
+    ),
+)]
+async fn document_original(
+    State(state): State<Arc<ServerState>>,
+    Extension(caller): Extension<Caller>,
+    Path(id): Path<String>,
+) -> Result<Response, ApiError> {
+    check_document_access(&state, &caller, &id).await?;
+    let document = state.storage.lock().await.get_document(&id).await?
+        .ok_or(ApiError::NotFound("document"))?;
+
+    // Backends hand the blob over whole; it is streamed out as slices of that one buffer
+    // rather than copied into a response body
+    let data = Bytes::from(document.file_data.ok_or(ApiError::NotFound("original"))?);
+    let length = data.len();
+    let chunks = (0..length).step_by(STREAM_CHUNK_BYTES)
+        .map(move |start| Ok::<_, std::io::Error>(data.slice(start..(start + STREAM_CHUNK_BYTES).min(length))));
+
+    let content_type = mime_type(std::path::Path::new(&document.filename));
+    let mut response = (
+        [
+            (header::CONTENT_TYPE, content_type.to_string()),
+            (header::CONTENT_LENGTH, length.to_string()),
+            (header::CONTENT_DISPOSITION, format!("inline; filename=\"{}\"", header_filename(&document.filename))),
+            (header::X_CONTENT_TYPE_OPTIONS, "nosniff".to_string()),
+        ],
+        Body::from_stream(stream::iter(chunks)),
+    ).into_response();
+    // A sandboxed page gets a unique origin, so its scripts can't reach the API
+    if is_active_content(content_type) {
+        response.headers_mut().insert(header::CONTENT_SECURITY_POLICY, header::HeaderValue::from_static("sandbox"));
+    }
+    Ok(response)
+}
+
+/// The stored original file; the same as `GET /documents/{id}/original`
+#[utoipa::path(
+    get,
+    path = "/documents/{id}/file",
+    params(("id" = String, Path, description = "Document id, as listed by `GET /documents`")),
+    responses(
+        (status = 200, description = "The original file, with its MIME type and file name", content_type = "application/octet-stream"),
+        (status = 401, description = "Missing or invalid bearer token", body = ErrorBody),
+        (status = 404, description = "No document with this id, or its original wasn't stored", body = ErrorBody),
+    ),
+)]
+async fn document_file(
+    state: State<Arc<ServerState>>,
+    caller: Extension<Caller>,
+    id: Path<String>,
+) -> Result<Response, ApiError> {
+    document_original(state, caller, id).await
+}
+
+/// The document's extracted text, one fragment per paragraph
+#[utoipa::path(
+    get,
+    path = "/documents/{id}/text",
+    params(("id" = String, Path, description = "Document id, as shown by `portable-brains list`")),
+    responses(
+        (status = 200, description = "Extracted text; neighbouring fragments overlap", body = String, content_type = "text/plain"),
+        (status = 401, description = "Missing or invalid bearer token", body = ErrorBody),
+        (status = 404, description = "No document with this id", body = ErrorBody),
+    ),
+)]
+async fn document_text(
+    State(state): State<Arc<ServerState>>,
+    Extension(caller): Extension<Caller>,
+    Path(id): Path<String>,
+) -> Result<Response, ApiError> {
+    check_document_access(&state, &caller, &id).await?;
+    let exists = {
+        let mut storage = state.storage.lock().await;
+        !storage.get_document_fragments(&id, 0, 1).await?.is_empty() || storage.get_document(&id).await?.is_some()
+    };
+    if !exists {
+        return Err(ApiError::NotFound("document"));
+    }
+
+    // Read a page of fragments per chunk so long documents are never held in memory whole
+    let pages = stream::unfold(Some(0), move |offset| {
+        let state = state.clone();
+        let id = id.clone();
+        async move {
+            let offset = offset?;
+            let page = match state.storage.lock().await.get_document_fragments(&id, offset, TEXT_PAGE_FRAGMENTS).await {
+                Ok(page) => page,
+                Err(err) => return Some((Err(std::io::Error::other(err.to_string())), None)),
+            };
+            if page.is_empty() {
+                return None;
+            }
+            let next = (page.len() as i32 == TEXT_PAGE_FRAGMENTS).then_some(offset + TEXT_PAGE_FRAGMENTS);
+            let text: String = page.into_iter().map(|fragment| fragment + "\n\n").collect();
+            Some((Ok(Bytes::from(text)), next))
+        }
+    });
+
+    Ok((
+        [(header::CONTENT_TYPE, "text/plain; charset=utf-8")],
+        Body::from_stream(pages),
+    ).into_response())
+}
+
+/// JSON body accepted by `POST /search`
+#[derive(Deserialize, ToSchema)]
+pub struct SearchRequest {
+    /// Search text; may contain `section:"Heading"` to search one section
+    pub query: String,
+    /// Fragments to return, at most 100
+    #[serde(default = "default_search_limit")]
+    pub limit: usize,
+    /// `next_cursor` from the previous page, to continue where it stopped
+    #[serde(default)]
+    pub cursor: Option<String>,
+    #[serde(default)]
+    pub mode: ApiSearchMode,
+    /// Only search documents in one of these collections
+    #[serde(default)]
+    pub collections: Vec<String>,
+    /// Only search documents classified into one of these categories
+    #[serde(default)]
+    pub categories: Vec<String>,
+    /// Only search the document with this id
+    #[serde(default)]
+    pub document: Option<String>,
+    /// Only return fragments mentioning this person, product or project
+    #[serde(default)]
+    pub entity: Option<String>,
+}
+
+impl SearchRequest {
+    /// Whether the search is the plain vector search `POST /search/warm` runs ahead of time
+    fn is_plain(&self) -> bool {
+        self.mode == ApiSearchMode::Vector
+            && self.collections.is_empty()
+            && self.categories.is_empty()
+            && self.document.is_none()
+            && self.entity.is_none()
+    }
+
+    fn filter(&self) -> SearchFilter {
+        SearchFilter {
+            collections: self.collections.clone(),
+            categories: self.categories.clone(),
+            document: self.document.clone(),
+            entity: self.entity.clone(),
+            ..Default::default()
+        }
+    }
+}
+
+/// How `POST /search` matches fragments against the query
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ApiSearchMode {
+    /// Cosine similarity of embeddings
+    #[default]
+    Vector,
+    /// Vector and keyword rankings fused by reciprocal rank
+    Hybrid,
+    /// BM25 full-text ranking, for exact terms such as error codes and names
+    Keyword,
+}
+
+impl From<ApiSearchMode> for SearchMode {
+    fn from(mode: ApiSearchMode) -> Self {
+        match mode {
+            ApiSearchMode::Vector => SearchMode::Vector,
+            ApiSearchMode::Hybrid => SearchMode::Hybrid,
+            ApiSearchMode::Keyword => SearchMode::Keyword,
+        }
+    }
+}
+
+fn default_search_limit() -> usize {
+    10
+}
+
+/// Fragments most similar to a query, best first
+#[derive(Clone, Serialize, ToSchema)]
+pub struct SearchResults {
+    pub results: Vec<SearchResult>,
+    /// Set when the database's vectors and the query don't share a model
+    pub warning: Option<String>,
+    /// Pass back as `cursor` to get the next page; absent on the last page
+    pub next_cursor: Option<String>,
+    /// Dataset version searched. Ingests publish new versions as each document is committed
+    /// and each batch embedded, so the same search at the same version gets the same results.
+    pub version: u64,
+}
+
+#[derive(Clone, Serialize, ToSchema)]
+pub struct SearchResult {
+    pub fragment_id: String,
+    pub content: String,
+    pub score: f64,
+    /// Where the fragment came from, e.g. `report.pdf, p. 12, Results, fragment 31`
+    pub citation: String,
+    /// The original opened at the fragment, e.g. `/documents/{id}/original#page=12` for a
+    /// PDF or `…#:~:text=…` for HTML; absent for other types and for PDF fragments without
+    /// a page
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub link: Option<String>,
+}
+
+/// Vector, hybrid or keyword search over the fragments the caller may read, optionally
+/// narrowed to collections, categories or one document. A plain vector search warmed up
+/// by `POST /search/warm` for the same query is answered without searching again.
+#[utoipa::path(
+    post,
+    path = "/search",
+    request_body = SearchRequest,
+    responses(
+        (status = 200, description = "Matching fragments; a tenant only gets its own collections'", body = SearchResults),
+        (status = 400, description = "Empty query or invalid cursor", body = ErrorBody),
+        (status = 401, description = "Missing or invalid bearer token", body = ErrorBody),
+        (status = 503, description = "The server was started without `--search` or `--embed`", body = ErrorBody),
+    ),
+)]
+async fn search(
+    State(state): State<Arc<ServerState>>,
+    Extension(caller): Extension<Caller>,
+    Json(request): Json<SearchRequest>,
+) -> Result<Json<SearchResults>, ApiError> {
+    let key = WarmKey::new(&caller, &request.query, request.limit);
+    let cursor = request.cursor.as_deref()
+        .map(SearchCursor::decode)
+        .transpose()
+        .map_err(|e| ApiError::BadRequest(e.to_string()))?;
+    // Results searched before an ingest published more are searched again
+    let version = storage::data_version(&mut **state.storage.lock().await).await?;
+    let cache_key = CacheKey::new(&key, &request);
+    let cached = state.cache.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).get(&cache_key, version);
+    // Warm-ups only ever fetch the first page of a plain search
+    let warmed = match (&cached, &cursor) {
+        (None, None) if request.is_plain() => state.warm.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).get(&key)
+            .filter(|results| results.version == version),
+        _ => None,
+    };
+    let results = match cached.or(warmed) {
+        Some(results) => results,
+        None => {
+            let results = run_search(&state, &caller, &key.query, key.limit, request.mode.into(), request.filter(), cursor).await?;
+            state.cache.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).insert(cache_key, results.clone());
+            results
+        }
+    };
+
+    let mut storage = state.storage.lock().await;
+    let hit_ids: Vec<String> = results.results.iter().map(|hit| hit.fragment_id.clone()).collect();
+    if let Err(e) = storage.record_fragment_hits(&hit_ids).await {
+        warn!("Failed to record search hits: {:#}", e);
+    }
+    // Count each search once, not once per page
+    if request.cursor.is_none() {
+        if let Err(e) = dashboard::record_query(&mut **storage, &key.query).await {
+            warn!("Failed to record search query: {:#}", e);
+        }
+    }
+    if let Some(tenant) = caller.tenant_id() {
+        tenants::record_usage(&mut **storage, tenant, |usage| {
+            usage.searches += 1;
+            usage.fragments_returned += hit_ids.len() as u64;
+        }).await?;
+    }
+    Ok(Json(results))
+}
+
+/// Search within `filter` and the caller's collections, from `cursor` on
+async fn run_search(
+    state: &ServerState,
+    caller: &Caller,
+    text: &str,
+    limit: usize,
+    mode: SearchMode,
+    filter: SearchFilter,
+    cursor: Option<SearchCursor>,
+) -> Result<SearchResults, ApiError> {
+    let embedding_manager = state.embedding_manager.as_ref()
+        .ok_or(ApiError::Unavailable("search needs an embedding model; start the server with --search"))?;
+    let (query, section) = SearchFilter::parse_section(text);
+    if query.trim().is_empty() {
+        return Err(ApiError::BadRequest("query is empty".to_string()));
+    }
+
+    let mut embedding_manager = embedding_manager.lock().await;
+    // Holding the storage for the whole search keeps ingests from committing part way through
+    let mut storage = state.storage.lock().await;
+    let version = storage::data_version(&mut **storage).await?;
+    let space = storage::query_space(&mut **storage, embedding_manager.model_name()).await?;
+    let Some(filter) = caller.scope(SearchFilter { stale: space.stale, section, ..filter }) else {
+        return Ok(SearchResults { results: Vec::new(), warning: space.warning, next_cursor: None, version });
+    };
+    let page = retrieval::search_page(&mut **storage, &mut embedding_manager, &query, limit, &filter, mode, cursor.as_ref()).await?;
+
+    Ok(SearchResults {
+        results: page.hits.into_iter()
+            .map(|hit| SearchResult {
+                citation: hit.source.citation(),
+                link: hit.source.anchor(&hit.content)
+                    .map(|anchor| format!("/documents/{}/original#{}", hit.source.document_id, anchor)),
+                fragment_id: hit.fragment_id,
+                content: hit.content,
+                score: hit.score,
+            })
+            .collect(),
+        warning: space.warning,
+        next_cursor: page.next.map(|next| next.encode()),
+        version,
+    })
+}
+
+/// JSON body accepted by `POST /search/warm`
+#[derive(Deserialize, ToSchema)]
+pub struct WarmRequest {
+    /// The query as typed so far
+    pub query: String,
+    /// The `limit` the final `POST /search` will ask for
+    #[serde(default = "default_search_limit")]
+    pub limit: usize,
+    /// Identifies the input box being typed in; a newer warm-up cancels the session's previous one
+    #[serde(default)]
+    pub session: Option<String>,
+}
+
+/// Identifies a search whose results can be reused: who asked, what, and how many
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct WarmKey {
+    tenant: Option<String>,
+    query: String,
+    limit: usize,
+}
+
+impl WarmKey {
+    fn new(caller: &Caller, query: &str, limit: usize) -> Self {
+        Self {
+            tenant: caller.tenant_id().map(str::to_string),
+            query: query.split_whitespace().collect::<Vec<_>>().join(" "),
+            limit: limit.clamp(1, MAX_SEARCH_LIMIT),
+        }
+    }
+}
+
+#[derive(Default)]
+struct WarmCache {
+    results: HashMap<WarmKey, (Instant, SearchResults)>,
+    /// The warm-up each session is running; a newer one from the session aborts it
+    running: HashMap<(Option<String>, String), tokio::task::AbortHandle>,
+}
+
+impl WarmCache {
+    fn get(&self, key: &WarmKey) -> Option<SearchResults> {
+        self.results.get(key)
+            .filter(|(warmed_at, _)| warmed_at.elapsed() < WARM_TTL)
+            .map(|(_, results)| results.clone())
+    }
+
+    fn insert(&mut self, key: WarmKey, results: SearchResults) {
+        self.results.retain(|_, (warmed_at, _)| warmed_at.elapsed() < WARM_TTL);
+        if self.results.len() >= WARM_CAPACITY {
+            let oldest = self.results.iter().min_by_key(|(_, (warmed_at, _))| *warmed_at).map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                self.results.remove(&oldest);
+            }
+        }
+        self.results.insert(key, (Instant::now(), results));
+    }
+}
+
+/// Identifies a `POST /search` request whose results can be reused: the warm-up key plus
+/// everything else the request narrows the search by
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct CacheKey {
+    search: WarmKey,
+    mode: ApiSearchMode,
+    collections: Vec<String>,
+    categories: Vec<String>,
+    document: Option<String>,
+    entity: Option<String>,
+    cursor: Option<String>,
+}
+
+impl CacheKey {
+    fn new(search: &WarmKey, request: &SearchRequest) -> Self {
+        Self {
+            search: search.clone(),
+            mode: request.mode,
+            collections: request.collections.clone(),
+            categories: request.categories.clone(),
+            document: request.document.clone(),
+            entity: request.entity.clone(),
+            cursor: request.cursor.clone(),
+        }
+    }
+}
+
+/// Least recently used search results, each valid for the dataset version it was searched
+/// at. Popular queries are answered without embedding them or reading the fragments again.
+struct SearchCache {
+    capacity: usize,
+    entries: HashMap<CacheKey, (u64, SearchResults)>,
+    /// Keys by the tick they were last used at, least recent first
+    recency: BTreeMap<u64, CacheKey>,
+    tick: u64,
+    hits: u64,
+    misses: u64,
+    evictions: u64,
+}
+
+impl SearchCache {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: HashMap::new(),
+            recency: BTreeMap::new(),
+            tick: 0,
+            hits: 0,
+            misses: 0,
+            evictions: 0,
+        }
+    }
+
+    /// Cached results for `key` if they were searched at `version`; older ones are dropped
+    fn get(&mut self, key: &CacheKey, version: u64) -> Option<SearchResults> {
+        if self.capacity == 0 {
+            return None;
+        }
+        let Some((used, results)) = self.entries.get_mut(key) else {
+            self.misses += 1;
+            return None;
+        };
+        if results.version != version {
+            self.recency.remove(used);
+            self.entries.remove(key);
+            self.misses += 1;
+            return None;
+        }
+        self.recency.remove(used);
+        self.tick += 1;
+        *used = self.tick;
+        self.recency.insert(self.tick, key.clone());
+        self.hits += 1;
+        Some(results.clone())
+    }
+
+    fn insert(&mut self, key: CacheKey, results: SearchResults) {
+        if self.capacity == 0 {
+            return;
+        }
+        if let Some((used, _)) = self.entries.remove(&key) {
+            self.recency.remove(&used);
+        }
+        while self.entries.len() >= self.capacity {
+            let Some((_, oldest)) = self.recency.pop_first() else {
+                break;
+            };
+            self.entries.remove(&oldest);
+            self.evictions += 1;
+        }
+        self.tick += 1;
+        self.recency.insert(self.tick, key.clone());
+        self.entries.insert(key, (self.tick, results));
+    }
+
+    fn clear(&mut self) {
+        self.entries.clear();
+        self.recency.clear();
+    }
+
+    fn stats(&self) -> CacheStats {
+        let lookups = self.hits + self.misses;
+        CacheStats {
+            capacity: self.capacity,
+            entries: self.entries.len(),
+            fragments: self.entries.values().map(|(_, results)| results.results.len()).sum(),
+            hits: self.hits,
+            misses: self.misses,
+            evictions: self.evictions,
+            hit_rate: if lookups == 0 { 0.0 } else { self.hits as f64 / lookups as f64 },
+        }
+    }
+}
+
+/// How well `POST /search` is served from the search cache since the server started
+#[derive(Serialize, ToSchema)]
+pub struct CacheStats {
+    /// Searches kept at most (`--cache-size`); 0 when the cache is off
+    pub capacity: usize,
+    /// Searches kept now
+    pub entries: usize,
+    /// Fragments across the kept searches
+    pub fragments: usize,
+    /// Searches answered from the cache
+    pub hits: u64,
+    /// Searches that went to storage, including those whose cached results were outdated
+    pub misses: u64,
+    /// Searches dropped, least recently used first, to make room for newer ones
+    pub evictions: u64,
+    /// Share of searches answered from the cache
+    pub hit_rate: f64,
+}
+
+/// Search cache metrics
+#[utoipa::path(
+    get,
+    path = "/stats/cache",
+    responses(
+        (status = 200, description = "Hits, misses and size of the search cache", body = CacheStats),
+        (status = 401, description = "Missing or invalid bearer token", body = ErrorBody),
+        (status = 403, description = "A tenant token; the cache serves every tenant", body = ErrorBody),
+    ),
+)]
+async fn cache_stats(
+    State(state): State<Arc<ServerState>>,
+    Extension(caller): Extension<Caller>,
+) -> Result<Json<CacheStats>, ApiError> {
+    if caller.tenant_id().is_some() {
+        return Err(ApiError::AdminOnly);
+    }
+    Ok(Json(state.cache.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).stats()))
+}
+
+/// Run a search ahead of time while the user is still typing, so the `POST /search` sent
+/// when they submit is answered at once. Send the query as it changes; a warm-up is
+/// dropped when a newer one arrives for the same session before it finishes.
+#[utoipa::path(
+    post,
+    path = "/search/warm",
+    request_body = WarmRequest,
+    responses(
+        (status = 202, description = "Warm-up scheduled"),
+        (status = 401, description = "Missing or invalid bearer token", body = ErrorBody),
+        (status = 503, description = "The server was started without `--search` or `--embed`", body = ErrorBody),
+    ),
+)]
+async fn warm_search(
+    State(state): State<Arc<ServerState>>,
+    Extension(caller): Extension<Caller>,
+    Json(request): Json<WarmRequest>,
+) -> Result<StatusCode, ApiError> {
+    if state.embedding_manager.is_none() {
+        return Err(ApiError::Unavailable("search needs an embedding model; start the server with --search"));
+    }
+    let key = WarmKey::new(&caller, &request.query, request.limit);
+    let session = (key.tenant.clone(), request.session.unwrap_or_default());
+
+    let task_state = state.clone();
+    let task = tokio::spawn(async move {
+        tokio::time::sleep(WARM_DEBOUNCE).await;
+        if task_state.warm.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).get(&key).is_some() {
+            return;
+        }
+        // Aborting the task drops any lock it holds, so a superseded warm-up stops at its
+        // next await. Errors are left for the real search to report.
+        if let Ok(results) = run_search(&task_state, &caller, &key.query, key.limit, SearchMode::Vector, SearchFilter::default(), None).await {
+            task_state.warm.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).insert(key, results);
+        }
+    });
+
+    let mut warm = state.warm.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    warm.running.retain(|_, running| !running.is_finished());
+    if let Some(previous) = warm.running.insert(session, task.abort_handle()) {
+        previous.abort();
+    }
+    Ok(StatusCode::ACCEPTED)
+}
+
+/// Multipart form accepted by `POST /ingest`
+#[derive(ToSchema)]
+#[allow(dead_code)] // Documents the form; the handler streams its parts instead
+struct IngestUpload {
+    /// One part per document; any field name is accepted and the part's file name is kept
+    #[schema(value_type = Vec<String>, format = Binary)]
+    files: Vec<Vec<u8>>,
+}
+
+/// JSON body accepted by `POST /ingest`
+#[derive(Deserialize, ToSchema)]
+pub struct IngestUrls {
+    /// http(s) URLs the server downloads and indexes
+    pub urls: Vec<String>,
+}
+
+enum IngestSource {
+    File(PathBuf),
+    Url(String),
+}
+
+struct IngestJob {
+    job: Job,
+    caller: Caller,
+    dir: PathBuf,
+    sources: Vec<IngestSource>,
+}
+
+/// Queue documents for indexing
+#[utoipa::path(
+    post,
+    path = "/ingest",
+    request_body(
+        description = "Documents to upload as multipart parts, or URLs for the server to fetch",
+        content((IngestUpload = "multipart/form-data"), (IngestUrls = "application/json")),
+    ),
+    responses(
+        (status = 202, description = "Job queued; poll `Location` for its status", body = JobRecord),
+        (status = 400, description = "No documents, a malformed body or an invalid URL", body = ErrorBody),
+        (status = 401, description = "Missing or invalid bearer token", body = ErrorBody),
+    ),
+)]
+async fn ingest(
+    State(state): State<Arc<ServerState>>,
+    Extension(caller): Extension<Caller>,
+    request: Request,
+) -> Result<Response, ApiError> {
+    let dir = state.upload_dir.join(uuid::Uuid::new_v4().to_string());
+
+    let is_multipart = request.headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("multipart/form-data"));
+
+    let sources = if is_multipart {
+        let multipart = Multipart::from_request(request, &()).await
+            .map_err(|e| ApiError::BadRequest(e.body_text()))?;
+        let saved = save_uploads(multipart, &dir).await;
+        if saved.is_err() {
+            let _ = tokio::fs::remove_dir_all(&dir).await;
+        }
+        saved?
+    } else {
+        let Json(body) = Json::<IngestUrls>::from_request(request, &()).await
+            .map_err(|e| ApiError::BadRequest(e.body_text()))?;
+        for url in &body.urls {
+            match reqwest::Url::parse(url) {
+                Ok(parsed) if matches!(parsed.scheme(), "http" | "https") => {}
+                _ => return Err(ApiError::BadRequest(format!("not an http(s) URL: {}", url))),
+            }
+        }
+        body.urls.into_iter().map(IngestSource::Url).collect()
+    };
+    if sources.is_empty() {
+        return Err(ApiError::BadRequest("no files or URLs to ingest".to_string()));
+    }
+
+    let names = sources.iter().map(|source| match source {
+        IngestSource::File(path) => path.file_name().unwrap_or_default().to_string_lossy().to_string(),
+        IngestSource::Url(url) => url.clone(),
+    }).collect();
+    let job = state.jobs.create_for(JobKind::Ingest, names, caller.tenant_id())?;
+    let location = format!("/jobs/{}", job.id());
+    let record = job.record().clone();
+    state.queue.send(IngestJob { job, caller, dir, sources })
+        .map_err(|_| anyhow::anyhow!("Ingest worker has stopped"))?;
+
+    Ok((
+        StatusCode::ACCEPTED,
+        [(header::LOCATION, location)],
+        Json(record),
+    ).into_response())
+}
+
+/// Queue documents for indexing; the same as `POST /ingest`
+#[utoipa::path(
+    post,
+    path = "/index",
+    request_body(
+        description = "Documents to upload as multipart parts, or URLs for the server to fetch",
+        content((IngestUpload = "multipart/form-data"), (IngestUrls = "application/json")),
+    ),
+    responses(
+        (status = 202, description = "Job queued; poll `Location` for its status", body = JobRecord),
+        (status = 400, description = "No documents, a malformed body or an invalid URL", body = ErrorBody),
+        (status = 401, description = "Missing or invalid bearer token", body = ErrorBody),
+    ),
+)]
+async fn index(
+    state: State<Arc<ServerState>>,
+    caller: Extension<Caller>,
+    request: Request,
+) -> Result<Response, ApiError> {
+    ingest(state, caller, request).await
+}
+
+/// Write every file part of an upload into `dir`, streaming rather than buffering them
+async fn save_uploads(mut multipart: Multipart, dir: &std::path::Path) -> Result<Vec<IngestSource>, ApiError> {
+    let mut sources = Vec::new();
+    while let Some(mut field) = multipart.next_field().await.map_err(|e| ApiError::BadRequest(e.body_text()))? {
+        // Plain form fields carry no document
+        let Some(name) = field.file_name().map(upload_filename) else {
+            continue;
+        };
+
+        tokio::fs::create_dir_all(dir).await
+            .with_context(|| format!("Failed to create upload directory {}", dir.display()))?;
+        let mut path = dir.join(&name);
+        let mut n = 1;
+        while path.exists() {
+            path = dir.join(format!("{}-{}", n, name));
+            n += 1;
+        }
+
+        let mut file = tokio::fs::File::create(&path).await
+            .with_context(|| format!("Failed to create {}", path.display()))?;
+        while let Some(chunk) = field.chunk().await.map_err(|e| ApiError::BadRequest(e.body_text()))? {
+            file.write_all(&chunk).await.context("Failed to write upload")?;
+        }
+        file.flush().await.context("Failed to write upload")?;
+        sources.push(IngestSource::File(path));
+    }
+    Ok(sources)
+}
+
+/// Recent jobs, newest first
+#[utoipa::path(
+    get,
+    path = "/jobs",
+    responses(
+        (status = 200, description = "Jobs run against this database, by the server or the CLI; a tenant only sees its own", body = Vec<JobRecord>),
+        (status = 401, description = "Missing or invalid bearer token", body = ErrorBody),
+    ),
+)]
+async fn list_jobs(
+    State(state): State<Arc<ServerState>>,
+    Extension(caller): Extension<Caller>,
+) -> Result<Json<Vec<JobRecord>>, ApiError> {
+    Ok(Json(state.jobs.list()?.into_iter().filter(|job| caller.can_see(job)).collect()))
+}
+
+/// Status of a job
+#[utoipa::path(
+    get,
+    path = "/jobs/{id}",
+    params(("id" = String, Path, description = "Job id, as returned by `POST /ingest` or shown by `portable-brains jobs`")),
+    responses(
+        (status = 200, description = "The job's state and progress", body = JobRecord),
+        (status = 401, description = "Missing or invalid bearer token", body = ErrorBody),
+        (status = 404, description = "No job with this id", body = ErrorBody),
+    ),
+)]
+async fn job_status(
+    State(state): State<Arc<ServerState>>,
+    Extension(caller): Extension<Caller>,
+    Path(id): Path<String>,
+) -> Result<Json<JobRecord>, ApiError> {
+    state.jobs.get(&id)?
+        .filter(|job| caller.can_see(job))
+        .map(Json)
+        .ok_or(ApiError::NotFound("job"))
+}
+
+/// Ask a queued or running job to stop
+#[utoipa::path(
+    post,
+    path = "/jobs/{id}/cancel",
+    params(("id" = String, Path, description = "Job id")),
+    responses(
+        (status = 202, description = "Cancellation requested; the job stops after its current document or batch", body = JobRecord),
+        (status = 401, description = "Missing or invalid bearer token", body = ErrorBody),
+        (status = 404, description = "No job with this id", body = ErrorBody),
+        (status = 409, description = "The job has already finished", body = ErrorBody),
+    ),
+)]
+async fn cancel_job(
+    State(state): State<Arc<ServerState>>,
+    Extension(caller): Extension<Caller>,
+    Path(id): Path<String>,
+) -> Result<Response, ApiError> {
+    if !state.jobs.get(&id)?.is_some_and(|job| caller.can_see(&job)) {
+        return Err(ApiError::NotFound("job"));
+    }
+    let record = state.jobs.cancel(&id)?.ok_or(ApiError::NotFound("job"))?;
+    if record.state.is_finished() {
+        return Err(ApiError::Conflict(format!("job is already {}", record.state.as_str())));
+    }
+    Ok((StatusCode::ACCEPTED, Json(record)).into_response())
+}
+
+/// Query parameters of `GET /suggest`
+#[derive(Deserialize)]
+struct SuggestParams {
+    q: String,
+    limit: Option<usize>,
+}
+
+/// Completions of a partly typed query from the corpus's document titles, headings and most
+/// frequent entities, as stored by the last index run or ingest job
+#[utoipa::path(
+    get,
+    path = "/suggest",
+    params(
+        ("q" = String, Query, description = "The query typed so far"),
+        ("limit" = Option<usize>, Query, description = "Most completions returned, 8 by default"),
+    ),
+    responses(
+        (status = 200, description = "Completions, best first; a tenant is only offered terms from its own collections", body = Vec<Suggestion>),
+        (status = 401, description = "Missing or invalid bearer token", body = ErrorBody),
+    ),
+)]
+async fn suggest(
+    State(state): State<Arc<ServerState>>,
+    Extension(caller): Extension<Caller>,
+    Query(params): Query<SuggestParams>,
+) -> Result<Json<Vec<Suggestion>>, ApiError> {
+    let index = PrefixIndex::load(&mut **state.storage.lock().await).await?;
+    let limit = params.limit.unwrap_or(autocomplete::DEFAULT_SUGGESTIONS);
+    let visible = |term: &Term| {
+        caller.tenant_id().is_none() || term.collections.iter().any(|collection| caller.can_read(Some(collection)))
+    };
+    Ok(Json(index.suggest(&params.q, limit, visible)))
+}
+
+/// Usage accounted to tenants
+#[utoipa::path(
+    get,
+    path = "/usage",
+    responses(
+        (status = 200, description = "The calling tenant's usage, or every configured tenant's for an admin token", body = Vec<TenantUsage>),
+        (status = 401, description = "Missing or invalid bearer token", body = ErrorBody),
+    ),
+)]
+async fn usage(
+    State(state): State<Arc<ServerState>>,
+    Extension(caller): Extension<Caller>,
+) -> Result<Json<Vec<TenantUsage>>, ApiError> {
+    let tenants = match caller.tenant_id() {
+        Some(tenant) => vec![tenant.to_string()],
+        None => state.access.read().unwrap_or_else(|poisoned| poisoned.into_inner()).tenant_ids(),
+    };
+    let mut storage = state.storage.lock().await;
+    let mut usage = Vec::new();
+    for tenant in tenants {
+        usage.push(tenants::load_usage(&mut **storage, &tenant).await?);
+    }
+    Ok(Json(usage))
+}
+
+/// Health of the database for the dashboard: embedding coverage, failures, recent ingest
+/// jobs, growth and the most asked queries
+#[utoipa::path(
+    get,
+    path = "/stats",
+    responses(
+        (status = 200, description = "Health figures for the whole database", body = IndexHealth),
+        (status = 401, description = "Missing or invalid bearer token", body = ErrorBody),
+        (status = 403, description = "A tenant token; the figures span every tenant", body = ErrorBody),
+    ),
+)]
+async fn stats(
+    State(state): State<Arc<ServerState>>,
+    Extension(caller): Extension<Caller>,
+) -> Result<Json<IndexHealth>, ApiError> {
+    if caller.tenant_id().is_some() {
+        return Err(ApiError::AdminOnly);
+    }
+    let mut storage = state.storage.lock().await;
+    Ok(Json(dashboard::index_health(&mut **storage, &state.jobs, &state.database).await?))
+}
+
+/// Run queued ingest jobs one at a time through the indexing pipeline
+async fn run_ingest_worker(
+    state: Arc<ServerState>,
+    mut settings: IngestSettings,
+    mut queued: mpsc::UnboundedReceiver<IngestJob>,
+) {
+    while let Some(IngestJob { mut job, caller, dir, sources }) = queued.recv().await {
+        // A reload takes effect between jobs, so a running job keeps consistent settings
+        if let Some(live) = &mut settings.config {
+            if let Some(config) = live.changed() {
+                if let Err(e) = settings.pipeline.reconfigure(&config, live.path(), &settings.database) {
+                    warn!("Keeping the previous ingest settings: {:#}", e);
+                }
+            }
+        }
+
+        let result = run_ingest_job(&state, &mut settings, &mut job, &caller, &dir, &sources).await;
+        if let Err(e) = dashboard::record_growth(&mut **state.storage.lock().await, &state.database).await {
+            warn!("Failed to record storage growth: {:#}", e);
+        }
+        if let Err(e) = autocomplete::refresh(&mut **state.storage.lock().await).await {
+            warn!("Failed to update query completions: {:#}", e);
+        }
+        // Warmed-up searches predate the new documents
+        state.warm.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).results.clear();
+        // The originals are in the database now, or failed to get there
+        let _ = tokio::fs::remove_dir_all(&dir).await;
+
+        if let Err(e) = &result {
+            warn!("Ingest job {} failed: {:?}", job.id(), e);
+        }
+        let id = job.id().to_string();
+        if let Err(e) = job.finish(&result) {
+            error!("Failed to record the end of job {}: {:?}", id, e);
+        }
+    }
+}
+
+/// Phase 1 for each document, then Phase 2 for everything awaiting embeddings. Storage is
+/// locked per document and per batch so the API keeps serving reads meanwhile, and
+/// cancellation is honoured between them.
+async fn run_ingest_job(
+    state: &ServerState,
+    settings: &mut IngestSettings,
+    job: &mut Job,
+    caller: &Caller,
+    dir: &std::path::Path,
+    sources: &[IngestSource],
+) -> Result<()> {
+    job.set_stage("extracting", sources.len() as u64)?;
+    for (index, source) in sources.iter().enumerate() {
+        if job.is_cancelled() {
+            return Ok(());
+        }
+        let (outcome, detail) = match ingest_source(state, settings, caller, dir, source).await {
+            Ok(Some(fragments)) => (ItemOutcome::Done, Some(format!("{} fragments", fragments))),
+            Ok(None) => (ItemOutcome::Skipped, Some("already indexed".to_string())),
+            Err(e) => (ItemOutcome::Failed, Some(format!("{:#}", e))),
+        };
+        job.set_item(index, outcome, detail)?;
+        job.advance(1)?;
+    }
+
+    if let Some(embedding_manager) = state.embedding_manager.as_ref().filter(|_| settings.embed) {
+        let pending = state.storage.lock().await.count_fragments_without_embeddings().await?;
+        job.set_stage("embedding", pending as u64)?;
+
+        if let Some(dimension) = storage::embedding_dimension(&mut **state.storage.lock().await).await? {
+            embedding_manager.lock().await.expect_dimension(dimension);
+        }
+        while !job.is_cancelled() {
+            settings.pipeline.throttle.wait_for_power().await;
+            // Searches take the model between batches
+            let mut embedding_manager = embedding_manager.lock().await;
+            let embedded = storage::embed_fragment_batch(&mut **state.storage.lock().await, &mut embedding_manager, EMBED_BATCH_SIZE).await?;
+            if embedded.fragments == 0 {
+                break;
+            }
+            job.advance(embedded.fragments as u64)?;
+        }
+        // Fragments that failed to embed are tried again by the next ingest
+        state.storage.lock().await.retry_embedding_failures().await?;
+    }
+    Ok(())
+}
+
+/// Extract one document, then commit it into the caller's collections. Returns its fragment
+/// count, or `None` when it was already indexed.
+async fn ingest_source(
+    state: &ServerState,
+    settings: &mut IngestSettings,
+    caller: &Caller,
+    dir: &std::path::Path,
+    source: &IngestSource,
+) -> Result<Option<usize>> {
+    let path = match source {
+        IngestSource::File(path) => path.clone(),
+        IngestSource::Url(url) => download(url, dir).await?,
+    };
+    let mut document = settings.pipeline.prepare(&path, &mut StageTimings::default()).await?;
+    document.routing.collection = caller.place(document.routing.collection.take());
+
+    let mut storage = state.storage.lock().await;
+    let committed = ingest_queue::commit_document(&mut **storage, &document).await?;
+    if let (Some(tenant), Some(_)) = (caller.tenant_id(), committed) {
+        tenants::record_usage(&mut **storage, tenant, |usage| {
+            usage.documents_ingested += 1;
+            usage.bytes_ingested += document.file_data.len() as u64;
+        }).await?;
+    }
+    Ok(committed)
+}
+
+/// Fetch a URL into `dir`, named after the last segment of its path. Only public addresses
+/// are fetched, as callers could otherwise read back services on the server's network.
+async fn download(url: &str, dir: &std::path::Path) -> Result<PathBuf> {
+    offline::check(url, "Ingesting a URL")?;
+    let mut response = offline::get_public(url).await
+        .with_context(|| format!("Failed to fetch {}", url))?
+        .error_for_status()
+        .with_context(|| format!("Failed to fetch {}", url))?;
+    if response.content_length().is_some_and(|length| length > MAX_DOWNLOAD_BYTES) {
+        anyhow::bail!("Document too large ({:.1} MB)", response.content_length().unwrap_or(0) as f64 / (1024.0 * 1024.0));
+    }
+
+    let content_type = response.headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("");
+    let name = url_filename(response.url(), content_type);
+
+    tokio::fs::create_dir_all(dir).await
+        .with_context(|| format!("Failed to create upload directory {}", dir.display()))?;
+    let mut path = dir.join(&name);
+    let mut n = 1;
+    while path.exists() {
+        path = dir.join(format!("{}-{}", n, name));
+        n += 1;
+    }
+
+    let mut file = tokio::fs::File::create(&path).await
+        .with_context(|| format!("Failed to create {}", path.display()))?;
+    let mut written = 0u64;
+    while let Some(chunk) = response.chunk().await.with_context(|| format!("Failed to fetch {}", url))? {
+        written += chunk.len() as u64;
+        if written > MAX_DOWNLOAD_BYTES {
+            anyhow::bail!("Document too large (over {} MB)", MAX_DOWNLOAD_BYTES / (1024 * 1024));
+        }
+        file.write_all(&chunk).await.context("Failed to write download")?;
+    }
+    file.flush().await.context("Failed to write download")?;
+    Ok(path)
+}
+
+/// Name for a downloaded document: the URL's last path segment, given an extension from
+/// the content type when it has none, since the extension selects the extractor
+pub(crate) fn url_filename(url: &reqwest::Url, content_type: &str) -> String {
+    let segment = url.path_segments()
+        .and_then(|mut segments| segments.rfind(|segment| !segment.is_empty()))
+        .unwrap_or("download");
+    let mut name = upload_filename(segment);
+
+    if std::path::Path::new(&name).extension().is_none() {
+        let mime = content_type.split(';').next().unwrap_or("").trim();
+        let extension = match mime {
+            "application/pdf" => "pdf",
+            "text/html" => "html",
+            "application/vnd.openxmlformats-officedocument.wordprocessingml.document" => "docx",
+            "application/vnd.openxmlformats-officedocument.presentationml.presentation" => "pptx",
+            "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet" => "xlsx",
+            "text/markdown" => "md",
+            "application/epub+zip" => "epub",
+            "text/csv" => "csv",
+            "text/tab-separated-values" => "tsv",
+            "message/rfc822" => "eml",
+            _ => "txt",
+        };
+        name = format!("{}.{}", name, extension);
+    }
+    name
+}
+
+/// Client-supplied file name reduced to a single safe path component
+fn upload_filename(name: &str) -> String {
+    let base = name.rsplit(['/', '\\']).next().unwrap_or("");
+    let cleaned: String = base.chars()
+        .map(|c| if c.is_control() || matches!(c, ':' | '*' | '?' | '"' | '<' | '>' | '|') { '_' } else { c })
+        .collect();
+    match cleaned.trim() {
+        "" | "." | ".." => "upload".to_string(),
+        trimmed => trimmed.to_string(),
+    }
+}
+
+/// Whether a browser would run script in an original of type `mime`. PDFs are left out,
+/// as browsers' built-in viewers won't load in a sandboxed page.
+fn is_active_content(mime: &str) -> bool {
+    ACTIVE_CONTENT_TYPES.contains(&mime)
+}
+
+/// File name safe to quote in a Content-Disposition header
+fn header_filename(name: &str) -> String {
+    name.chars()
+        .map(|c| if (c.is_ascii_graphic() && c != '"' && c != '\\') || c == ' ' { c } else { '_' })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_constant_time_eq() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secret2"));
+    }
+
+    #[test]
+    fn test_active_originals_are_sandboxed() {
+        assert!(is_active_content(mime_type(std::path::Path::new("guide.HTML"))));
+        assert!(is_active_content(mime_type(std::path::Path::new("page.htm"))));
+        assert!(!is_active_content(mime_type(std::path::Path::new("annual.pdf"))));
+        assert!(!is_active_content(mime_type(std::path::Path::new("notes.txt"))));
+    }
+
+    #[test]
+    fn test_openapi_spec_covers_routes() {
+        let spec: serde_json::Value = serde_json::from_str(&ApiDoc::openapi().to_json().unwrap()).unwrap();
+        assert!(spec["openapi"].as_str().unwrap().starts_with("3.1"));
+        assert!(spec["paths"]["/documents"]["get"].is_object());
+        assert!(spec["paths"]["/documents/{id}/original"]["get"].is_object());
+        assert!(spec["paths"]["/documents/{id}/file"]["get"].is_object());
+        assert!(spec["paths"]["/documents/{id}/text"]["get"].is_object());
+        assert!(spec["paths"]["/search"]["post"]["requestBody"].is_object());
+        assert!(spec["paths"]["/search/warm"]["post"].is_object());
+        assert!(spec["paths"]["/suggest"]["get"]["parameters"].is_array());
+        assert!(spec["paths"]["/usage"]["get"].is_object());
+        assert!(spec["paths"]["/stats"]["get"].is_object());
+        assert!(spec["paths"]["/stats/cache"]["get"].is_object());
+        assert!(spec["paths"]["/ingest"]["post"]["requestBody"]["content"]["multipart/form-data"].is_object());
+        assert!(spec["paths"]["/index"]["post"]["requestBody"]["content"]["multipart/form-data"].is_object());
+        assert!(spec["components"]["schemas"]["ApiSearchMode"].is_object());
+        assert!(spec["paths"]["/jobs/{id}"]["get"].is_object());
+        assert!(spec["paths"]["/jobs/{id}/cancel"]["post"].is_object());
+        assert!(spec["components"]["securitySchemes"]["bearer"].is_object());
+    }
+
+    #[test]
+    fn test_warmed_searches_match_the_submitted_query() {
+        let key = WarmKey::new(&Caller::Admin, "  revenue   growth ", 500);
+        assert!(key == WarmKey::new(&Caller::Admin, "revenue growth", MAX_SEARCH_LIMIT));
+        assert!(key != WarmKey::new(&Caller::Admin, "revenue growth", 10));
+
+        let mut cache = WarmCache::default();
+        let results = SearchResults { results: Vec::new(), warning: Some("stale".to_string()), next_cursor: None, version: 3 };
+        cache.insert(key.clone(), results);
+        assert_eq!(cache.get(&key).unwrap().warning.as_deref(), Some("stale"));
+        assert!(cache.get(&WarmKey::new(&Caller::Admin, "revenue", 10)).is_none());
+    }
+
+    #[test]
+    fn test_search_cache_evicts_least_recently_used() {
+        let request = |query: &str| SearchRequest {
+            query: query.to_string(),
+            limit: 10,
+            cursor: None,
+            mode: ApiSearchMode::Vector,
+            collections: Vec::new(),
+            categories: Vec::new(),
+            document: None,
+            entity: None,
+        };
+        let key = |query: &str| CacheKey::new(&WarmKey::new(&Caller::Admin, query, 10), &request(query));
+        let results = |version| SearchResults { results: Vec::new(), warning: None, next_cursor: None, version };
+
+        let mut cache = SearchCache::new(2);
+        cache.insert(key("revenue"), results(1));
+        cache.insert(key("costs"), results(1));
+        assert!(cache.get(&key("revenue"), 1).is_some());
+        cache.insert(key("margins"), results(1));
+        assert!(cache.get(&key("costs"), 1).is_none());
+        assert!(cache.get(&key("revenue"), 1).is_some());
+        // Results from before an ingest are searched again
+        assert!(cache.get(&key("margins"), 2).is_none());
+        assert!(cache.get(&key("margins"), 1).is_none());
+
+        let keyword = CacheKey { mode: ApiSearchMode::Keyword, ..key("revenue") };
+        assert!(cache.get(&keyword, 1).is_none());
+
+        let stats = cache.stats();
+        assert_eq!((stats.entries, stats.hits, stats.misses, stats.evictions), (1, 2, 4, 1));
+        assert!(SearchCache::new(0).get(&key("revenue"), 1).is_none());
+    }
+
+    #[test]
+    fn test_upload_filenames_stay_in_upload_dir() {
+        assert_eq!(upload_filename("report.pdf"), "report.pdf");
+        assert_eq!(upload_filename("../../etc/passwd"), "passwd");
+        assert_eq!(upload_filename("C:\\Users\\me\\notes.txt"), "notes.txt");
+        assert_eq!(upload_filename(".."), "upload");
+
+        let url = reqwest::Url::parse("https://example.com/files/q3-report.pdf?download=1").unwrap();
+        assert_eq!(url_filename(&url, "application/octet-stream"), "q3-report.pdf");
+        let url = reqwest::Url::parse("https://example.com/wiki/Page/").unwrap();
+        assert_eq!(url_filename(&url, "text/html; charset=utf-8"), "Page.html");
+    }
+
+    #[test]
+    fn test_header_filename() {
+        assert_eq!(header_filename("Q3 report.pdf"), "Q3 report.pdf");
+        assert_eq!(header_filename("a\"b\\c\r\né.pdf"), "a_b_c___.pdf");
+    }
+}
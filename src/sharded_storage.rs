@@ -0,0 +1,1144 @@
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use futures::future::join_all;
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::annotations::Annotation;
+use crate::embedding_manager::EmbeddingPrefixes;
+use crate::entities;
+use crate::paths;
+use crate::storage;
+use crate::storage::arrow::array::{RecordBatch, StringArray, UInt32Array};
+use crate::storage::{conform_fragment_batch, fragment_column, open_backend, sort_ranked, BulkAction, CorpusStats, DocumentAttributes, DocumentInfo, DocumentSummary, EntityCount, EntityMention, FragmentChanges, FragmentMatch, FragmentMeta, FragmentRecord, FragmentSource, IndexState, MetaInfo, OriginalsMode, PendingFragment, SearchFilter, SimilarityMetric, Storage, StorageBackend, VectorIndexCoverage};
+
+/// File extension that marks a database path as a shard manifest
+pub const MANIFEST_EXTENSION: &str = "shards";
+
+/// Meta keys describing the database's embedding model, which shards with a model of their
+/// own keep for theirs
+const MODEL_KEYS: &[&str] = &[
+    "embedding_model",
+    storage::DIMENSION_KEY,
+    storage::PROVIDER_KEY,
+    storage::PREVIOUS_MODEL_KEY,
+    storage::MODEL_LINEAGE_KEY,
+    storage::DOCUMENT_PREFIX_KEY,
+    storage::QUERY_PREFIX_KEY,
+];
+
+const MANIFEST_VERSION: u32 = 1;
+
+/// How documents are assigned to shards
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum ShardStrategy {
+    /// A fixed number of shards, chosen by a stable hash of the document path
+    Hash { count: usize },
+    /// One shard per collection, taken from the name of the document's parent directory
+    Collection,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ShardEntry {
+    /// Shard key: the shard number for hash sharding, the collection name otherwise
+    pub key: String,
+    /// Shard database file, relative to the manifest
+    pub file: String,
+}
+
+/// Manifest describing a brain split across several database files
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ShardManifest {
+    pub version: u32,
+    pub backend: String,
+    pub strategy: ShardStrategy,
+    #[serde(default)]
+    pub embedding_model: Option<String>,
+    /// Collections embedded with a model of their own instead of `embedding_model`, by
+    /// shard key
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub collection_models: BTreeMap<String, String>,
+    /// Shards created later derive their ids from content too
+    #[serde(default)]
+    pub deterministic_ids: bool,
+    /// How shards created later store originals
+    #[serde(default)]
+    pub store_originals: OriginalsMode,
+    /// How shards created later compare vectors
+    #[serde(default)]
+    pub similarity_metric: SimilarityMetric,
+    #[serde(default)]
+    pub shards: Vec<ShardEntry>,
+}
+
+impl ShardManifest {
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read shard manifest {}", path.display()))?;
+        let manifest: ShardManifest = serde_json::from_str(&text)
+            .with_context(|| format!("Invalid shard manifest {}", path.display()))?;
+        if manifest.version > MANIFEST_VERSION {
+            anyhow::bail!(
+                "Shard manifest {} has version {}, this build supports up to {}",
+                path.display(), manifest.version, MANIFEST_VERSION
+            );
+        }
+        Ok(manifest)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let text = serde_json::to_string_pretty(self)?;
+        std::fs::write(path, text)
+            .with_context(|| format!("Failed to write shard manifest {}", path.display()))
+    }
+}
+
+struct Shard {
+    key: String,
+    storage: Box<dyn Storage>,
+}
+
+/// Storage facade that spreads documents over several backend files and fans queries out to all of them.
+///
+/// Document and fragment IDs handed out by this storage are prefixed with the shard index
+/// (`"{index}:{id}"`) so later calls can be routed back to the shard that owns them.
+pub struct ShardedStorage {
+    manifest_path: PathBuf,
+    manifest: ShardManifest,
+    backend: StorageBackend,
+    shards: Vec<Shard>,
+}
+
+impl ShardedStorage {
+    pub fn is_manifest(path: &Path) -> bool {
+        path.extension()
+            .and_then(|ext| ext.to_str())
+            .map(|ext| ext.eq_ignore_ascii_case(MANIFEST_EXTENSION))
+            .unwrap_or(false)
+    }
+
+    /// Write a new manifest, or check an existing one uses the same layout
+    pub fn create_manifest(path: &Path, backend: &StorageBackend, strategy: ShardStrategy) -> Result<()> {
+        if matches!(backend, StorageBackend::Remote) {
+            anyhow::bail!("Remote brains can't be sharded; the server holds the whole brain");
+        }
+        if !Self::is_manifest(path) {
+            anyhow::bail!(
+                "Sharded databases must use the .{} extension: {}",
+                MANIFEST_EXTENSION, path.display()
+            );
+        }
+
+        if path.exists() {
+            let existing = ShardManifest::load(path)?;
+            if existing.strategy != strategy || existing.backend != backend.as_str() {
+                anyhow::bail!(
+                    "{} already uses {:?} sharding on {}; refusing to change it to {:?} on {}",
+                    path.display(), existing.strategy, existing.backend, strategy, backend.as_str()
+                );
+            }
+            return Ok(());
+        }
+
+        if let ShardStrategy::Hash { count } = strategy {
+            if count == 0 {
+                anyhow::bail!("Shard count must be at least 1");
+            }
+        }
+
+        ShardManifest {
+            version: MANIFEST_VERSION,
+            backend: backend.as_str().to_string(),
+            strategy,
+            embedding_model: None,
+            collection_models: BTreeMap::new(),
+            deterministic_ids: false,
+            store_originals: OriginalsMode::Full,
+            similarity_metric: SimilarityMetric::Cosine,
+            shards: Vec::new(),
+        }
+        .save(path)
+    }
+
+    pub async fn open(manifest_path: &Path) -> Result<Self> {
+        let manifest = ShardManifest::load(manifest_path)?;
+        let backend: StorageBackend = manifest.backend.parse()
+            .with_context(|| format!("Invalid shard manifest {}", manifest_path.display()))?;
+
+        let mut storage = ShardedStorage {
+            manifest_path: manifest_path.to_path_buf(),
+            manifest,
+            backend,
+            shards: Vec::new(),
+        };
+
+        for entry in storage.manifest.shards.clone() {
+            let shard_storage = Self::open_shard_file(storage.backend.clone(), storage.shard_path(&entry.file)).await?;
+            storage.shards.push(Shard { key: entry.key, storage: shard_storage });
+        }
+
+        info!("Opened {} shard(s) from {}", storage.shards.len(), manifest_path.display());
+        Ok(storage)
+    }
+
+    fn shard_path(&self, file: &str) -> PathBuf {
+        self.manifest_path
+            .parent()
+            .map(|dir| dir.join(file))
+            .unwrap_or_else(|| PathBuf::from(file))
+    }
+
+    /// Open a shard file; takes owned arguments so the future doesn't borrow `self`
+    async fn open_shard_file(backend: StorageBackend, path: PathBuf) -> Result<Box<dyn Storage>> {
+        open_backend(&backend, &path).await
+            .with_context(|| format!("Failed to open shard {}", path.display()))
+    }
+
+    /// Shard key for a document path under the manifest's strategy
+    fn shard_key(&self, file_path: &Path) -> String {
+        // Normalized so every spelling of a path lands in the same shard
+        let file_path = paths::normalize(file_path);
+        match self.manifest.strategy {
+            ShardStrategy::Hash { count } => {
+                let hash = fnv1a(&paths::raw_bytes(&file_path));
+                (hash % count as u64).to_string()
+            }
+            ShardStrategy::Collection => file_path.parent()
+                .and_then(|dir| dir.file_name())
+                .map(|name| sanitize_key(&name.to_string_lossy()))
+                .filter(|name| !name.is_empty())
+                .unwrap_or_else(|| "default".to_string()),
+        }
+    }
+
+    fn shard_mut(&mut self, index: usize) -> Result<&mut Box<dyn Storage>> {
+        self.shards.get_mut(index)
+            .map(|shard| &mut shard.storage)
+            .ok_or_else(|| anyhow::anyhow!("No shard {} in {}", index, self.manifest_path.display()))
+    }
+
+    fn find_shard(&self, key: &str) -> Option<usize> {
+        self.shards.iter().position(|shard| shard.key == key)
+    }
+
+    /// Shards embedded with the database's model, with their indexes
+    fn shared_shards(&mut self) -> impl Iterator<Item = (usize, &mut Shard)> + '_ {
+        let own = &self.manifest.collection_models;
+        self.shards.iter_mut()
+            .enumerate()
+            .filter(move |(_, shard)| !own.contains_key(&shard.key))
+    }
+
+    /// Index of the shard holding a collection with a model of its own
+    fn own_model_shard(&self, collection: &str) -> Option<usize> {
+        let key = sanitize_key(collection);
+        self.manifest.collection_models.contains_key(&key)
+            .then(|| self.find_shard(&key))
+            .flatten()
+    }
+
+    /// Index of the shard for `key`, creating the shard file and recording it in the manifest if needed
+    async fn shard_for_key(&mut self, key: &str) -> Result<usize> {
+        if let Some(index) = self.find_shard(key) {
+            return Ok(index);
+        }
+
+        let stem = self.manifest_path.file_stem()
+            .map(|s| s.to_string_lossy().to_string())
+            .unwrap_or_else(|| "brain".to_string());
+        let extension = match self.backend {
+            StorageBackend::DuckDB => "db",
+            StorageBackend::LanceDB => "lancedb",
+            StorageBackend::Remote => anyhow::bail!("Shards can't be remote brains"),
+        };
+        let file = match self.manifest.strategy {
+            ShardStrategy::Hash { .. } => format!("{}-shard-{:03}.{}", stem, key.parse::<usize>().unwrap_or(0), extension),
+            ShardStrategy::Collection => format!("{}-{}.{}", stem, key, extension),
+        };
+
+        let mut storage = Self::open_shard_file(self.backend.clone(), self.shard_path(&file)).await?;
+        match (self.manifest.collection_models.get(key), &self.manifest.embedding_model) {
+            (Some(model), _) => {
+                storage.verify_or_set_model(model, None).await?;
+                storage::set_embedding_prefixes(&mut *storage, &EmbeddingPrefixes::for_model(model)).await?;
+            }
+            (None, Some(model)) => storage.verify_or_set_model(model, None).await?,
+            (None, None) => {}
+        }
+        if self.manifest.deterministic_ids {
+            storage.enable_deterministic_ids().await?;
+        }
+        if self.manifest.store_originals != OriginalsMode::Full {
+            storage.set_originals_mode(self.manifest.store_originals).await?;
+        }
+        if self.manifest.similarity_metric != SimilarityMetric::Cosine {
+            storage.set_similarity_metric(self.manifest.similarity_metric).await?;
+        }
+
+        info!("Created shard '{}' at {}", key, file);
+        self.manifest.shards.push(ShardEntry { key: key.to_string(), file });
+        self.manifest.save(&self.manifest_path)?;
+        self.shards.push(Shard { key: key.to_string(), storage });
+
+        Ok(self.shards.len() - 1)
+    }
+}
+
+/// Split a sharded ID back into its shard index and the backend's own ID
+fn split_id(id: &str) -> Result<(usize, &str)> {
+    let (index, inner) = id.split_once(':')
+        .ok_or_else(|| anyhow::anyhow!("ID '{}' is not a sharded ID", id))?;
+    let index = index.parse()
+        .with_context(|| format!("ID '{}' has an invalid shard prefix", id))?;
+    Ok((index, inner))
+}
+
+fn join_id(index: usize, id: &str) -> String {
+    format!("{}:{}", index, id)
+}
+
+/// A shard's search result with its fragment and document ids made global
+fn shard_match(index: usize, hit: FragmentMatch) -> FragmentMatch {
+    FragmentMatch {
+        fragment_id: join_id(index, &hit.fragment_id),
+        source: FragmentSource { document_id: join_id(index, &hit.source.document_id), ..hit.source },
+        ..hit
+    }
+}
+
+/// A fragment batch with its ids and document ids replaced
+fn with_ids(batch: &RecordBatch, ids: Vec<String>, document_ids: Vec<String>) -> Result<RecordBatch> {
+    let mut columns = batch.columns().to_vec();
+    columns[0] = Arc::new(StringArray::from(ids));
+    columns[1] = Arc::new(StringArray::from(document_ids));
+    Ok(RecordBatch::try_new(batch.schema(), columns)?)
+}
+
+/// The filter to search shard `index` with, or `None` when a document filter names a
+/// document in another shard
+fn shard_filter(index: usize, filter: &SearchFilter) -> Result<Option<SearchFilter>> {
+    let Some(document_id) = &filter.document else {
+        return Ok(Some(filter.clone()));
+    };
+    let (shard, id) = split_id(document_id)?;
+    Ok((shard == index).then(|| SearchFilter { document: Some(id.to_string()), ..filter.clone() }))
+}
+
+/// 64-bit FNV-1a, stable across builds unlike `DefaultHasher`, so documents keep their shard
+fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+fn sanitize_key(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c.to_ascii_lowercase() } else { '_' })
+        .collect()
+}
+
+#[async_trait]
+impl Storage for ShardedStorage {
+    async fn initialize(&mut self) -> Result<()> {
+        for shard in &mut self.shards {
+            shard.storage.initialize().await?;
+        }
+        Ok(())
+    }
+
+    async fn verify_or_set_model(&mut self, model_name: &str, dimension: Option<usize>) -> Result<()> {
+        match &self.manifest.embedding_model {
+            Some(existing) if existing != model_name => {
+                anyhow::bail!(
+                    "Embedding model mismatch! Sharded database uses '{}' but '{}' was specified.",
+                    existing, model_name
+                );
+            }
+            Some(_) => {}
+            None => {
+                self.manifest.embedding_model = Some(model_name.to_string());
+                self.manifest.save(&self.manifest_path)?;
+            }
+        }
+
+        for (_, shard) in self.shared_shards() {
+            shard.storage.verify_or_set_model(model_name, dimension).await?;
+        }
+        Ok(())
+    }
+
+    fn collection_models(&self) -> Vec<(String, String)> {
+        self.shards.iter()
+            .filter_map(|shard| self.manifest.collection_models.get(&shard.key).map(|model| (shard.key.clone(), model.clone())))
+            .collect()
+    }
+
+    async fn set_collection_model(&mut self, collection: &str, model: &str) -> Result<()> {
+        if self.manifest.strategy != ShardStrategy::Collection {
+            anyhow::bail!(
+                "{} is sharded by hash; only brains sharded by collection can embed a collection with its own model",
+                self.manifest_path.display()
+            );
+        }
+        let key = sanitize_key(collection);
+        let shared = self.manifest.embedding_model.as_deref() == Some(model);
+        if self.manifest.collection_models.get(&key).map(String::as_str) == Some(model)
+            || (shared && !self.manifest.collection_models.contains_key(&key)) {
+            return Ok(());
+        }
+
+        // A shard that already exists has its vectors replaced by the embed phase
+        if let Some(index) = self.find_shard(&key) {
+            let shard = &mut *self.shards[index].storage;
+            let recorded = shard.get_meta_info().await?.embedding_model;
+            if !recorded.is_empty() && recorded != "unknown" && recorded != model {
+                let stale = storage::upgrade_embedding_model(shard, model).await?;
+                info!("Collection '{}' moves from {} to {}; {} vectors marked stale", key, recorded, model, stale);
+            }
+            shard.verify_or_set_model(model, None).await?;
+            if storage::embedding_dimension(shard).await?.is_none() {
+                // Back in the shared space, it's queried with the database's prefixes
+                let prefixes = match self.shared_shards().next().filter(|_| shared) {
+                    Some((_, other)) => storage::embedding_prefixes(&mut *other.storage).await?,
+                    None => EmbeddingPrefixes::for_model(model),
+                };
+                storage::set_embedding_prefixes(&mut *self.shards[index].storage, &prefixes).await?;
+            }
+        }
+
+        if shared {
+            self.manifest.collection_models.remove(&key);
+        } else {
+            self.manifest.collection_models.insert(key, model.to_string());
+        }
+        self.manifest.save(&self.manifest_path)
+    }
+
+    fn collection_space(&mut self, collection: &str) -> Option<&mut dyn Storage> {
+        let index = self.own_model_shard(collection)?;
+        Some(&mut *self.shards[index].storage)
+    }
+
+    async fn search_collection(
+        &mut self,
+        collection: &str,
+        query_embedding: &[f64],
+        limit: usize,
+        filter: &SearchFilter,
+    ) -> Result<Vec<FragmentMatch>> {
+        let index = self.own_model_shard(collection)
+            .ok_or_else(|| anyhow::anyhow!("Collection {} has no embedding model of its own", collection))?;
+        let Some(filter) = shard_filter(index, filter)? else {
+            return Ok(Vec::new());
+        };
+        let hits = self.shards[index].storage.search_similar(query_embedding, limit, &filter).await
+            .with_context(|| format!("Failed to search collection {}", collection))?;
+        Ok(hits.into_iter().map(|hit| shard_match(index, hit)).collect())
+    }
+
+    async fn enable_deterministic_ids(&mut self) -> Result<()> {
+        if !self.manifest.deterministic_ids {
+            self.manifest.deterministic_ids = true;
+            self.manifest.save(&self.manifest_path)?;
+        }
+        for shard in &mut self.shards {
+            shard.storage.enable_deterministic_ids().await?;
+        }
+        Ok(())
+    }
+
+    async fn set_originals_mode(&mut self, mode: OriginalsMode) -> Result<()> {
+        if self.manifest.store_originals != mode {
+            self.manifest.store_originals = mode;
+            self.manifest.save(&self.manifest_path)?;
+        }
+        for shard in &mut self.shards {
+            shard.storage.set_originals_mode(mode).await?;
+        }
+        Ok(())
+    }
+
+    async fn set_similarity_metric(&mut self, metric: SimilarityMetric) -> Result<()> {
+        for shard in &mut self.shards {
+            shard.storage.set_similarity_metric(metric).await?;
+        }
+        if self.manifest.similarity_metric != metric {
+            self.manifest.similarity_metric = metric;
+            self.manifest.save(&self.manifest_path)?;
+        }
+        Ok(())
+    }
+
+    async fn document_exists(&mut self, file_path: &Path) -> Result<bool> {
+        let key = self.shard_key(file_path);
+        match self.find_shard(&key) {
+            Some(index) => self.shards[index].storage.document_exists(file_path).await,
+            None => Ok(false),
+        }
+    }
+
+    async fn find_duplicate(&mut self, content_hash: &str) -> Result<Option<String>> {
+        for shard in &mut self.shards {
+            if let Some(path) = shard.storage.find_duplicate(content_hash).await? {
+                return Ok(Some(path));
+            }
+        }
+        Ok(None)
+    }
+
+    async fn store_document(&mut self, file_path: &Path, file_data: &[u8]) -> Result<String> {
+        let key = self.shard_key(file_path);
+        let index = self.shard_for_key(&key).await?;
+        let id = self.shards[index].storage.store_document(file_path, file_data).await?;
+        Ok(join_id(index, &id))
+    }
+
+    async fn set_index_state(&mut self, document_id: &str, state: IndexState) -> Result<()> {
+        let (index, id) = split_id(document_id)?;
+        self.shard_mut(index)?.set_index_state(id, state).await
+    }
+
+    async fn list_index_state(&mut self, state: IndexState) -> Result<Vec<String>> {
+        let mut ids = Vec::new();
+        for (index, shard) in self.shards.iter_mut().enumerate() {
+            ids.extend(shard.storage.list_index_state(state).await?.iter().map(|id| join_id(index, id)));
+        }
+        Ok(ids)
+    }
+
+    async fn set_document_source(&mut self, document_id: &str, content_hash: &str, modified: Option<i64>) -> Result<()> {
+        let (index, id) = split_id(document_id)?;
+        self.shard_mut(index)?.set_document_source(id, content_hash, modified).await
+    }
+
+    async fn set_document_priority(&mut self, document_id: &str, priority: i32) -> Result<()> {
+        let (index, id) = split_id(document_id)?;
+        self.shard_mut(index)?.set_document_priority(id, priority).await
+    }
+
+    async fn set_document_category(&mut self, document_id: &str, category: &str, score: f64) -> Result<()> {
+        let (index, id) = split_id(document_id)?;
+        self.shard_mut(index)?.set_document_category(id, category, score).await
+    }
+
+    async fn set_document_collection(&mut self, document_id: &str, collection: Option<&str>, tags: &[String]) -> Result<()> {
+        let (index, id) = split_id(document_id)?;
+        self.shard_mut(index)?.set_document_collection(id, collection, tags).await
+    }
+
+    async fn get_document_collection(&mut self, document_id: &str) -> Result<Option<String>> {
+        let (index, id) = split_id(document_id)?;
+        self.shard_mut(index)?.get_document_collection(id).await
+    }
+
+    async fn get_document_attributes(&mut self, document_id: &str) -> Result<Option<DocumentAttributes>> {
+        let (index, id) = split_id(document_id)?;
+        self.shard_mut(index)?.get_document_attributes(id).await
+    }
+
+    async fn set_document_quality(&mut self, document_id: &str, score: f64, flags: &[String]) -> Result<()> {
+        let (index, id) = split_id(document_id)?;
+        self.shard_mut(index)?.set_document_quality(id, score, flags).await
+    }
+
+    async fn set_document_title(&mut self, document_id: &str, title: &str) -> Result<()> {
+        let (index, id) = split_id(document_id)?;
+        self.shard_mut(index)?.set_document_title(id, title).await
+    }
+
+    async fn tombstone_document(&mut self, document_id: &str, tombstoned: bool) -> Result<()> {
+        let (index, id) = split_id(document_id)?;
+        self.shard_mut(index)?.tombstone_document(id, tombstoned).await
+    }
+
+    async fn remove_document(&mut self, document_id: &str) -> Result<()> {
+        let (index, id) = split_id(document_id)?;
+        self.shard_mut(index)?.remove_document(id).await
+    }
+
+    async fn bulk_update(&mut self, filter: &SearchFilter, action: &BulkAction) -> Result<usize> {
+        let mut total = 0;
+        for (index, shard) in self.shards.iter_mut().enumerate() {
+            if let Some(filter) = shard_filter(index, filter)? {
+                total += shard.storage.bulk_update(&filter, action).await?;
+            }
+        }
+        Ok(total)
+    }
+
+    async fn set_document_text(&mut self, document_id: &str, text: &[u8]) -> Result<()> {
+        let (index, id) = split_id(document_id)?;
+        self.shard_mut(index)?.set_document_text(id, text).await
+    }
+
+    async fn get_document_text(&mut self, document_id: &str) -> Result<Option<Vec<u8>>> {
+        let (index, id) = split_id(document_id)?;
+        self.shard_mut(index)?.get_document_text(id).await
+    }
+
+    async fn replace_fragments(
+        &mut self,
+        document_id: &str,
+        fragments: &[(String, FragmentMeta)],
+    ) -> Result<FragmentChanges> {
+        let (index, id) = split_id(document_id)?;
+        self.shard_mut(index)?.replace_fragments(id, fragments).await
+    }
+
+    async fn list_documents(&mut self) -> Result<Vec<DocumentSummary>> {
+        let mut documents = Vec::new();
+        for (index, shard) in self.shards.iter_mut().enumerate() {
+            documents.extend(shard.storage.list_documents().await?.into_iter().map(|document| DocumentSummary {
+                id: join_id(index, &document.id),
+                ..document
+            }));
+        }
+        documents.sort_by(|a, b| a.file_path.cmp(&b.file_path));
+        Ok(documents)
+    }
+
+    async fn get_document(&mut self, document_id: &str) -> Result<Option<DocumentInfo>> {
+        let (index, id) = split_id(document_id)?;
+        Ok(self.shard_mut(index)?.get_document(id).await?.map(|document| DocumentInfo {
+            id: document_id.to_string(),
+            ..document
+        }))
+    }
+
+    async fn get_document_fragments(&mut self, document_id: &str, offset: i32, limit: i32) -> Result<Vec<String>> {
+        let (index, id) = split_id(document_id)?;
+        self.shard_mut(index)?.get_document_fragments(id, offset, limit).await
+    }
+
+    async fn get_fragment_records(&mut self, document_id: &str) -> Result<Vec<FragmentRecord>> {
+        let (index, id) = split_id(document_id)?;
+        let fragments = self.shard_mut(index)?.get_fragment_records(id).await?;
+        Ok(fragments.into_iter()
+            .map(|fragment| FragmentRecord { id: join_id(index, &fragment.id), ..fragment })
+            .collect())
+    }
+
+    async fn read_fragment_batch(&mut self, after: Option<&str>, limit: usize) -> Result<Option<RecordBatch>> {
+        // Shards are read in turn, so a batch holds fragments of one shard
+        let (start, mut inner) = match after {
+            Some(after) => split_id(after).map(|(index, id)| (index, Some(id)))?,
+            None => (0, None),
+        };
+        for index in start..self.shards.len() {
+            if let Some(batch) = self.shards[index].storage.read_fragment_batch(inner.take(), limit).await? {
+                let ids = fragment_column::<StringArray>(&batch, "id")?.iter()
+                    .map(|id| join_id(index, id.unwrap_or_default()))
+                    .collect();
+                let document_ids = fragment_column::<StringArray>(&batch, "document_id")?.iter()
+                    .map(|id| join_id(index, id.unwrap_or_default()))
+                    .collect();
+                return with_ids(&batch, ids, document_ids).map(Some);
+            }
+        }
+        Ok(None)
+    }
+
+    async fn write_fragment_batch(&mut self, batch: &RecordBatch) -> Result<usize> {
+        let batch = conform_fragment_batch(batch)?;
+        let ids = fragment_column::<StringArray>(&batch, "id")?;
+        let document_ids = fragment_column::<StringArray>(&batch, "document_id")?;
+        
+        // Rows go to the shard that owns their document, without their shard prefixes
+        let mut rows: Vec<Vec<u32>> = vec![Vec::new(); self.shards.len()];
+        for row in 0..batch.num_rows() {
+            let (index, _) = split_id(document_ids.value(row))?;
+            rows.get_mut(index)
+                .ok_or_else(|| anyhow::anyhow!("No shard {} in {}", index, self.manifest_path.display()))?
+                .push(row as u32);
+        }
+        
+        let mut inserted = 0;
+        for (index, rows) in rows.into_iter().enumerate().filter(|(_, rows)| !rows.is_empty()) {
+            let strip = |array: &StringArray| -> Vec<String> {
+                rows.iter()
+                    .map(|&row| {
+                        let id = array.value(row as usize);
+                        split_id(id).map_or(id, |(_, inner)| inner).to_string()
+                    })
+                    .collect()
+            };
+            let (shard_ids, shard_document_ids) = (strip(ids), strip(document_ids));
+            let selected = crate::storage::arrow::compute::take_record_batch(&batch, &UInt32Array::from(rows))?;
+            let shard_batch = with_ids(&selected, shard_ids, shard_document_ids)?;
+            inserted += self.shards[index].storage.write_fragment_batch(&shard_batch).await?;
+        }
+        Ok(inserted)
+    }
+
+    async fn store_text_fragment(
+        &mut self,
+        document_id: &str,
+        order: i32,
+        content: &str,
+        meta: &FragmentMeta,
+    ) -> Result<String> {
+        let (index, id) = split_id(document_id)?;
+        let fragment_id = self.shard_mut(index)?.store_text_fragment(id, order, content, meta).await?;
+        Ok(join_id(index, &fragment_id))
+    }
+
+    async fn update_fragment_embedding(
+        &mut self,
+        fragment_id: &str,
+        embedding: &[f64],
+    ) -> Result<()> {
+        let (index, id) = split_id(fragment_id)?;
+        self.shard_mut(index)?.update_fragment_embedding(id, embedding).await
+    }
+
+    async fn update_fragment_embeddings_batch(&mut self, embeddings: &[(String, Vec<f32>)]) -> Result<()> {
+        let mut by_shard: Vec<Vec<(String, Vec<f32>)>> = vec![Vec::new(); self.shards.len()];
+        for (fragment_id, embedding) in embeddings {
+            let (index, id) = split_id(fragment_id)?;
+            by_shard.get_mut(index)
+                .ok_or_else(|| anyhow::anyhow!("No shard {} in {}", index, self.manifest_path.display()))?
+                .push((id.to_string(), embedding.clone()));
+        }
+        for (shard, embeddings) in self.shards.iter_mut().zip(by_shard) {
+            if !embeddings.is_empty() {
+                shard.storage.update_fragment_embeddings_batch(&embeddings).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn clear_fragment_text(&mut self, fragment_ids: &[String]) -> Result<()> {
+        let mut by_shard: Vec<Vec<String>> = vec![Vec::new(); self.shards.len()];
+        for fragment_id in fragment_ids {
+            let (index, id) = split_id(fragment_id)?;
+            if let Some(ids) = by_shard.get_mut(index) {
+                ids.push(id.to_string());
+            }
+        }
+        for (shard, ids) in self.shards.iter_mut().zip(by_shard) {
+            if !ids.is_empty() {
+                shard.storage.clear_fragment_text(&ids).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn get_pending_fragments(&mut self, limit: i32) -> Result<Vec<PendingFragment>> {
+        // Any shard may hold the most urgent fragments, so take the head of every shard's
+        // queue and merge them; the stable sort keeps each shard's own tie-breaks
+        let mut fragments = Vec::new();
+        for (index, shard) in self.shared_shards() {
+            let batch = shard.storage.get_pending_fragments(limit).await?;
+            fragments.extend(batch.into_iter().map(|fragment| PendingFragment {
+                id: join_id(index, &fragment.id),
+                ..fragment
+            }));
+        }
+        fragments.sort_by_key(PendingFragment::queue_key);
+        fragments.truncate(limit.max(0) as usize);
+        Ok(fragments)
+    }
+
+    async fn count_fragments_without_embeddings(&mut self) -> Result<i32> {
+        let mut total = 0;
+        for (_, shard) in self.shared_shards() {
+            total += shard.storage.count_fragments_without_embeddings().await?;
+        }
+        Ok(total)
+    }
+
+    async fn get_embeddings_by_chunk(&mut self, chunk_hashes: &[String]) -> Result<HashMap<String, Vec<f32>>> {
+        // The shared shards have one model, so a chunk embedded in one serves the others
+        let mut embeddings = HashMap::new();
+        for (_, shard) in self.shared_shards() {
+            for (hash, embedding) in shard.storage.get_embeddings_by_chunk(chunk_hashes).await? {
+                embeddings.entry(hash).or_insert(embedding);
+            }
+        }
+        Ok(embeddings)
+    }
+
+    async fn record_embedding_failures(&mut self, failures: &[(String, String)]) -> Result<()> {
+        let mut by_shard: Vec<Vec<(String, String)>> = vec![Vec::new(); self.shards.len()];
+        for (fragment_id, error) in failures {
+            let (index, id) = split_id(fragment_id)?;
+            if let Some(failures) = by_shard.get_mut(index) {
+                failures.push((id.to_string(), error.clone()));
+            }
+        }
+        for (shard, failures) in self.shards.iter_mut().zip(by_shard) {
+            if !failures.is_empty() {
+                shard.storage.record_embedding_failures(&failures).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn retry_embedding_failures(&mut self) -> Result<i32> {
+        let mut total = 0;
+        for shard in &mut self.shards {
+            total += shard.storage.retry_embedding_failures().await?;
+        }
+        Ok(total)
+    }
+
+    async fn count_embedding_failures(&mut self) -> Result<i32> {
+        let mut total = 0;
+        for shard in &mut self.shards {
+            total += shard.storage.count_embedding_failures().await?;
+        }
+        Ok(total)
+    }
+
+    async fn build_vector_index(&mut self) -> Result<bool> {
+        let mut indexed = !self.shards.is_empty();
+        for shard in &mut self.shards {
+            indexed &= shard.storage.build_vector_index().await?;
+        }
+        Ok(indexed)
+    }
+
+    async fn vector_index_coverage(&mut self) -> Result<Option<VectorIndexCoverage>> {
+        // Summed over the shards with an index; the others are scanned whole
+        let mut total: Option<VectorIndexCoverage> = None;
+        for shard in &mut self.shards {
+            if let Some(coverage) = shard.storage.vector_index_coverage().await? {
+                let sum = total.get_or_insert_with(VectorIndexCoverage::default);
+                sum.indexed += coverage.indexed;
+                sum.unindexed += coverage.unindexed;
+            }
+        }
+        Ok(total)
+    }
+
+    async fn mark_embeddings_stale(&mut self) -> Result<i32> {
+        let mut total = 0;
+        for (_, shard) in self.shared_shards() {
+            total += shard.storage.mark_embeddings_stale().await?;
+        }
+        Ok(total)
+    }
+
+    async fn count_stale_fragments(&mut self) -> Result<i32> {
+        let mut total = 0;
+        for (_, shard) in self.shared_shards() {
+            total += shard.storage.count_stale_fragments().await?;
+        }
+        Ok(total)
+    }
+
+    async fn corpus_stats(&mut self, largest: usize) -> Result<CorpusStats> {
+        let mut stats = CorpusStats::default();
+        for shard in &mut self.shards {
+            stats.merge(shard.storage.corpus_stats(largest).await?, largest);
+        }
+        Ok(stats)
+    }
+
+    async fn record_fragment_hits(&mut self, fragment_ids: &[String]) -> Result<()> {
+        let mut by_shard: Vec<Vec<String>> = vec![Vec::new(); self.shards.len()];
+        for fragment_id in fragment_ids {
+            let (index, id) = split_id(fragment_id)?;
+            if let Some(ids) = by_shard.get_mut(index) {
+                ids.push(id.to_string());
+            }
+        }
+        for (shard, ids) in self.shards.iter_mut().zip(by_shard) {
+            shard.storage.record_fragment_hits(&ids).await?;
+        }
+        Ok(())
+    }
+
+    async fn get_fragment_vectors(&mut self, fragment_ids: &[String]) -> Result<HashMap<String, Vec<f64>>> {
+        let mut by_shard: Vec<Vec<String>> = vec![Vec::new(); self.shards.len()];
+        for fragment_id in fragment_ids {
+            let (index, id) = split_id(fragment_id)?;
+            if let Some(ids) = by_shard.get_mut(index) {
+                ids.push(id.to_string());
+            }
+        }
+        let mut vectors = HashMap::new();
+        for (index, (shard, ids)) in self.shards.iter_mut().zip(by_shard).enumerate() {
+            if !ids.is_empty() {
+                for (id, vector) in shard.storage.get_fragment_vectors(&ids).await? {
+                    vectors.insert(join_id(index, &id), vector);
+                }
+            }
+        }
+        Ok(vectors)
+    }
+
+    async fn get_fragment_embeddings(&mut self, limit: usize) -> Result<Vec<(String, String, Vec<f64>)>> {
+        // Sample evenly across shards so the picture isn't dominated by the first one
+        let per_shard = limit.div_ceil(self.shared_shards().count().max(1));
+        let mut samples = Vec::new();
+        for (index, shard) in self.shared_shards() {
+            let batch = shard.storage.get_fragment_embeddings(per_shard).await?;
+            samples.extend(batch.into_iter().map(|(id, filename, embedding)| (join_id(index, &id), filename, embedding)));
+        }
+        samples.truncate(limit);
+        Ok(samples)
+    }
+
+    async fn sample_fragments(&mut self, limit: usize) -> Result<Vec<(String, String, Vec<f64>)>> {
+        let per_shard = limit.div_ceil(self.shared_shards().count().max(1));
+        let mut samples = Vec::new();
+        for (index, shard) in self.shared_shards() {
+            let batch = shard.storage.sample_fragments(per_shard).await?;
+            samples.extend(batch.into_iter().map(|(id, content, embedding)| (join_id(index, &id), content, embedding)));
+        }
+        samples.truncate(limit);
+        Ok(samples)
+    }
+
+    async fn get_meta_info(&mut self) -> Result<MetaInfo> {
+        let first = self.shared_shards().next();
+        match first {
+            Some((_, shard)) => shard.storage.get_meta_info().await,
+            None => Ok(MetaInfo {
+                version: MANIFEST_VERSION.to_string(),
+                embedding_model: self.manifest.embedding_model.clone().unwrap_or_default(),
+                embedding_dimension: None,
+                embedding_provider: None,
+            }),
+        }
+    }
+
+    async fn get_meta_value(&mut self, key: &str) -> Result<Option<String>> {
+        if key == "embedding_model" {
+            return Ok(self.manifest.embedding_model.clone());
+        }
+        let metric = self.manifest.similarity_metric;
+        let first = self.shared_shards().next();
+        match first {
+            Some((_, shard)) => shard.storage.get_meta_value(key).await,
+            // Until a shard exists the metric chosen for them is only in the manifest
+            None if key == storage::SIMILARITY_METRIC_KEY && metric != SimilarityMetric::Cosine => {
+                Ok(Some(metric.name().to_string()))
+            }
+            None => Ok(None),
+        }
+    }
+
+    async fn set_meta_value(&mut self, key: &str, value: &str) -> Result<()> {
+        // The manifest's model is what shards created later are checked against
+        if key == "embedding_model" {
+            self.manifest.embedding_model = Some(value.to_string());
+            self.manifest.save(&self.manifest_path)?;
+        }
+        let own = &self.manifest.collection_models;
+        for shard in &mut self.shards {
+            if MODEL_KEYS.contains(&key) && own.contains_key(&shard.key) {
+                continue;
+            }
+            shard.storage.set_meta_value(key, value).await?;
+        }
+        Ok(())
+    }
+
+    async fn list_entities(&mut self, limit: usize) -> Result<Vec<EntityCount>> {
+        // Every shard's counts are needed; an entity can be common only across shards
+        let mut counts: HashMap<String, EntityCount> = HashMap::new();
+        for shard in &mut self.shards {
+            for count in shard.storage.list_entities(usize::MAX).await? {
+                match counts.get_mut(&entities::key(&count.name)) {
+                    Some(total) => {
+                        total.fragments += count.fragments;
+                        total.documents += count.documents;
+                    }
+                    None => {
+                        counts.insert(entities::key(&count.name), count);
+                    }
+                }
+            }
+        }
+        
+        let mut entities: Vec<EntityCount> = counts.into_values().collect();
+        entities.sort_by(|a, b| b.fragments.cmp(&a.fragments).then_with(|| a.name.cmp(&b.name)));
+        entities.truncate(limit);
+        Ok(entities)
+    }
+
+    async fn find_entity(&mut self, entity: &str) -> Result<Vec<EntityMention>> {
+        let mut mentions = Vec::new();
+        for (index, shard) in self.shards.iter_mut().enumerate() {
+            mentions.extend(shard.storage.find_entity(entity).await?.into_iter().map(|mention| EntityMention {
+                document_id: join_id(index, &mention.document_id),
+                ..mention
+            }));
+        }
+        mentions.sort_by(|a, b| b.fragments.cmp(&a.fragments).then_with(|| a.file_path.cmp(&b.file_path)));
+        Ok(mentions)
+    }
+
+    async fn add_annotation(&mut self, annotation: &Annotation) -> Result<()> {
+        let (index, target) = split_id(&annotation.target)?;
+        let annotation = Annotation { target: target.to_string(), ..annotation.clone() };
+        self.shard_mut(index)?.add_annotation(&annotation).await
+    }
+
+    async fn list_annotations(&mut self, target: Option<&str>) -> Result<Vec<Annotation>> {
+        let target = target.map(split_id).transpose()?;
+        let mut annotations = Vec::new();
+        for (index, shard) in self.shards.iter_mut().enumerate() {
+            if target.is_some_and(|(wanted, _)| wanted != index) {
+                continue;
+            }
+            let listed = shard.storage.list_annotations(target.map(|(_, id)| id)).await?;
+            annotations.extend(listed.into_iter().map(|annotation| Annotation {
+                id: join_id(index, &annotation.id),
+                target: join_id(index, &annotation.target),
+                ..annotation
+            }));
+        }
+        annotations.sort_by(|a, b| a.created.cmp(&b.created).then_with(|| a.id.cmp(&b.id)));
+        Ok(annotations)
+    }
+
+    async fn remove_annotation(&mut self, annotation_id: &str) -> Result<bool> {
+        let (index, id) = split_id(annotation_id)?;
+        self.shard_mut(index)?.remove_annotation(id).await
+    }
+
+    async fn search_similar(
+        &mut self,
+        query_embedding: &[f64],
+        limit: usize,
+        filter: &SearchFilter,
+    ) -> Result<Vec<FragmentMatch>> {
+        // Collections with a model of their own are searched with their own queries
+        let mut searches = Vec::new();
+        for (index, shard) in self.shared_shards() {
+            if let Some(filter) = shard_filter(index, filter)? {
+                searches.push(async move { (index, shard.storage.search_similar(query_embedding, limit, &filter).await) });
+            }
+        }
+
+        // The shared shards have one backend and model, so raw scores can be merged directly
+        let mut results = Vec::new();
+        for (index, outcome) in join_all(searches).await {
+            let hits = outcome.with_context(|| format!("Failed to search shard {}", index))?;
+            results.extend(hits.into_iter().map(|hit| shard_match(index, hit)));
+        }
+
+        sort_ranked(&mut results);
+        results.truncate(limit);
+        Ok(results)
+    }
+
+    async fn search_keyword(
+        &mut self,
+        query: &str,
+        limit: usize,
+        filter: &SearchFilter,
+    ) -> Result<Vec<FragmentMatch>> {
+        let mut searches = Vec::new();
+        for (index, shard) in self.shards.iter_mut().enumerate() {
+            if let Some(filter) = shard_filter(index, filter)? {
+                searches.push(async move { (index, shard.storage.search_keyword(query, limit, &filter).await) });
+            }
+        }
+
+        // Each shard weighs terms by its own document frequencies; documents are spread by a
+        // hash of their path, so the shards' statistics are close enough to merge on
+        let mut results = Vec::new();
+        for (index, outcome) in join_all(searches).await {
+            let hits = outcome.with_context(|| format!("Failed to search shard {}", index))?;
+            results.extend(hits.into_iter().map(|hit| shard_match(index, hit)));
+        }
+
+        sort_ranked(&mut results);
+        results.truncate(limit);
+        Ok(results)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sharded_ids_round_trip() {
+        let id = join_id(3, "abc-123");
+        assert_eq!(split_id(&id).unwrap(), (3, "abc-123"));
+        assert!(split_id("no-prefix").is_err());
+    }
+
+    #[test]
+    fn test_document_filter_picks_one_shard() {
+        let filter = SearchFilter { document: Some(join_id(1, "doc")), ..SearchFilter::default() };
+        assert!(shard_filter(0, &filter).unwrap().is_none());
+        assert_eq!(shard_filter(1, &filter).unwrap().unwrap().document.as_deref(), Some("doc"));
+        assert!(shard_filter(0, &SearchFilter::default()).unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_collection_with_its_own_model() {
+        let dir = std::env::temp_dir().join(format!("pb-shards-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let manifest = dir.join("brain.shards");
+        ShardedStorage::create_manifest(&manifest, &StorageBackend::LanceDB, ShardStrategy::Collection).unwrap();
+        let mut storage = ShardedStorage::open(&manifest).await.unwrap();
+        storage.verify_or_set_model("general-model", None).await.unwrap();
+        storage.set_collection_model("code", "code-model").await.unwrap();
+
+        let docs = storage.store_document(Path::new("/data/docs/guide.md"), b"guide").await.unwrap();
+        storage.store_text_fragment(&docs, 0, "Install the agent", &FragmentMeta::default()).await.unwrap();
+        let code = storage.store_document(Path::new("/data/code/main.rs"), b"fn main() {}").await.unwrap();
+        storage.store_text_fragment(&code, 0, "fn main() {}", &FragmentMeta::default()).await.unwrap();
+
+        // The code collection is embedded on its own, with its own model
+        assert_eq!(storage.collection_models(), vec![("code".to_string(), "code-model".to_string())]);
+        assert_eq!(storage.count_fragments_without_embeddings().await.unwrap(), 1);
+        assert_eq!(storage.get_meta_info().await.unwrap().embedding_model, "general-model");
+        let space = storage.collection_space("code").unwrap();
+        assert_eq!(space.get_meta_info().await.unwrap().embedding_model, "code-model");
+        assert_eq!(space.count_fragments_without_embeddings().await.unwrap(), 1);
+        assert!(storage.collection_space("docs").is_none());
+
+        // Switching the model of the shared shards leaves it alone
+        storage.set_meta_value("embedding_model", "other-model").await.unwrap();
+        let space = storage.collection_space("code").unwrap();
+        assert_eq!(space.get_meta_info().await.unwrap().embedding_model, "code-model");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_pending_fragments_merged_across_shards() {
+        let dir = std::env::temp_dir().join(format!("pb-shards-queue-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let manifest = dir.join("brain.shards");
+        ShardedStorage::create_manifest(&manifest, &StorageBackend::LanceDB, ShardStrategy::Collection).unwrap();
+        let mut storage = ShardedStorage::open(&manifest).await.unwrap();
+        storage.verify_or_set_model("general-model", None).await.unwrap();
+
+        let archive = storage.store_document(Path::new("/data/archive/minutes.md"), b"minutes").await.unwrap();
+        storage.store_text_fragment(&archive, 0, "Minutes from 2019", &FragmentMeta::default()).await.unwrap();
+        storage.store_text_fragment(&archive, 1, "Minutes from 2020", &FragmentMeta::default()).await.unwrap();
+        let urgent = storage.store_document(Path::new("/data/urgent/runbook.md"), b"runbook").await.unwrap();
+        storage.store_text_fragment(&urgent, 0, "Outage runbook", &FragmentMeta::default()).await.unwrap();
+        storage.set_document_priority(&urgent, 5).await.unwrap();
+
+        // Whichever shard is read first, the urgent fragment heads the merged queue
+        let pending = storage.get_fragments_without_embeddings(2).await.unwrap();
+        let contents: Vec<&str> = pending.iter().map(|(_, content)| content.as_str()).collect();
+        assert_eq!(contents, vec!["Outage runbook", "Minutes from 2019"]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_hash_is_stable() {
+        // Shard assignment must not change between builds
+        assert_eq!(fnv1a(b"docs/report.pdf"), fnv1a(b"docs/report.pdf"));
+        assert_eq!(fnv1a(b""), 0xcbf29ce484222325);
+    }
+}
@@ -0,0 +1,582 @@
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use quick_xml::events::Event;
+use quick_xml::Reader as XmlReader;
+use reqwest::header::{CONTENT_TYPE, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::discovery::Discovery;
+use crate::document_processor::xml_attribute;
+use crate::offline;
+use crate::paths::{self, StoredPath};
+use crate::server::{url_filename, MAX_DOWNLOAD_BYTES};
+use crate::storage::{self, Storage};
+
+/// Meta key holding the sync state of every source indexed into a database, as JSON keyed
+/// by source id
+pub const SOURCES_KEY: &str = "sources";
+
+/// Where a source has got to: what the connector needs to find only what changed next time
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SyncState {
+    /// The connector's position in the source, e.g. the last commit synced from a repository
+    #[serde(default)]
+    pub cursor: Option<String>,
+    /// Items synced so far, by item key
+    #[serde(default)]
+    pub items: BTreeMap<String, SyncedItem>,
+    /// When the source was last synced, in microseconds since the Unix epoch
+    #[serde(default)]
+    pub synced_at: Option<i64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SyncedItem {
+    /// Version the connector saw, e.g. an ETag or commit
+    pub version: String,
+    /// File the item was indexed from
+    pub path: PathBuf,
+}
+
+/// A document new or changed in a source, saved as a local file to be indexed
+#[derive(Debug, Clone, PartialEq)]
+pub struct SourceItem {
+    /// Stays the same across versions of the item, e.g. its URL
+    pub key: String,
+    pub version: String,
+    pub path: PathBuf,
+}
+
+/// What changed in a source since its sync state
+#[derive(Debug, Default)]
+pub struct SyncBatch {
+    pub changed: Vec<SourceItem>,
+    /// Keys of items gone from the source
+    pub removed: Vec<String>,
+    /// Position to resume from next time
+    pub cursor: Option<String>,
+}
+
+/// Somewhere documents come from. A connector only reports what changed since the state
+/// recorded by its last sync; indexing, replacing changed documents and applying watch's
+/// delete policy are the same for every source.
+#[async_trait]
+pub trait Source: Send {
+    /// Identifies the source across runs, so its sync state can be found again
+    fn id(&self) -> &str;
+
+    /// Find the items changed since `state`, saving each where it can be indexed from
+    async fn sync(&mut self, state: &SyncState) -> Result<SyncBatch>;
+}
+
+/// Settings shared by every connector
+pub struct SourceContext {
+    /// Which files of a directory or repository are indexed
+    pub discovery: Discovery,
+    /// Where downloaded documents are kept between runs
+    pub cache_dir: PathBuf,
+}
+
+/// A kind of source, chosen by the prefix of its spec
+struct Connector {
+    prefix: &'static str,
+    open: fn(&str, &SourceContext) -> Result<Box<dyn Source>>,
+}
+
+/// Every connector, tried in order; a new one only needs an entry here
+const CONNECTORS: &[Connector] = &[
+    Connector { prefix: "dir:", open: DirectorySource::open },
+    Connector { prefix: "git:", open: GitSource::open },
+    Connector { prefix: "feed+", open: FeedSource::open },
+    Connector { prefix: "http://", open: UrlSource::open },
+    Connector { prefix: "https://", open: UrlSource::open },
+];
+
+/// Open the source a spec such as `git:../notes` or `feed+https://example.com/rss` names
+pub fn open(spec: &str, context: &SourceContext) -> Result<Box<dyn Source>> {
+    let connector = CONNECTORS.iter()
+        .find(|connector| spec.starts_with(connector.prefix))
+        .with_context(|| format!(
+            "No connector for source {}; sources start with {}",
+            spec,
+            CONNECTORS.iter().map(|connector| connector.prefix).collect::<Vec<_>>().join(", "),
+        ))?;
+    (connector.open)(spec, context)
+}
+
+/// Sync states of the sources indexed into a database, by source id
+pub async fn load_states(storage: &mut dyn Storage) -> Result<BTreeMap<String, SyncState>> {
+    match storage.get_meta_value(SOURCES_KEY).await? {
+        Some(json) if !json.is_empty() => serde_json::from_str(&json).context("Invalid source sync state in the meta table"),
+        _ => Ok(BTreeMap::new()),
+    }
+}
+
+/// The outcome of syncing a source, recorded by `commit` once its files are indexed
+pub struct Pending {
+    pub source_id: String,
+    /// Files to index, new or changed
+    pub files: Vec<PathBuf>,
+    /// Files of the items gone from the source
+    pub removed: Vec<PathBuf>,
+    state: SyncState,
+}
+
+/// Ask a source what changed since it was last committed
+pub async fn sync(storage: &mut dyn Storage, source: &mut dyn Source) -> Result<Pending> {
+    let mut state = load_states(storage).await?.remove(source.id()).unwrap_or_default();
+    let batch = source.sync(&state).await
+        .with_context(|| format!("Failed to sync {}", source.id()))?;
+
+    let mut files = Vec::with_capacity(batch.changed.len());
+    for item in batch.changed {
+        files.push(item.path.clone());
+        state.items.insert(item.key, SyncedItem { version: item.version, path: item.path });
+    }
+    let removed = batch.removed.iter()
+        .filter_map(|key| state.items.remove(key))
+        .map(|item| item.path)
+        .collect();
+    state.cursor = batch.cursor;
+    state.synced_at = Some(chrono::Utc::now().timestamp_micros());
+    Ok(Pending { source_id: source.id().to_string(), files, removed, state })
+}
+
+/// Record a sync, so the next one starts from it. Until then the same changes are found
+/// again, so a run that stops early doesn't lose them.
+pub async fn commit(storage: &mut dyn Storage, pending: Pending) -> Result<()> {
+    let mut states = load_states(storage).await?;
+    states.insert(pending.source_id, pending.state);
+    storage.set_meta_value(SOURCES_KEY, &serde_json::to_string(&states)?).await
+}
+
+/// Documents in a directory, found the way `--input-dir` finds them. Files are versioned by
+/// modification time and size.
+struct DirectorySource {
+    id: String,
+    dir: PathBuf,
+    discovery: Discovery,
+}
+
+impl DirectorySource {
+    fn open(spec: &str, context: &SourceContext) -> Result<Box<dyn Source>> {
+        let dir = paths::normalize(Path::new(&spec["dir:".len()..]));
+        if !dir.is_dir() {
+            anyhow::bail!("Source directory does not exist: {}", dir.display());
+        }
+        Ok(Box::new(Self {
+            id: format!("dir:{}", StoredPath::new(&dir).key),
+            dir,
+            discovery: context.discovery.clone(),
+        }))
+    }
+}
+
+#[async_trait]
+impl Source for DirectorySource {
+    fn id(&self) -> &str {
+        &self.id
+    }
+
+    async fn sync(&mut self, state: &SyncState) -> Result<SyncBatch> {
+        let mut batch = SyncBatch::default();
+        let mut present = HashSet::new();
+        for path in self.discovery.find_files(&self.dir)? {
+            let key = StoredPath::new(&path).key;
+            let size = std::fs::metadata(&path).map(|metadata| metadata.len()).unwrap_or(0);
+            let version = format!("{}:{}", storage::modified_micros(&path).unwrap_or(0), size);
+            if state.items.get(&key).map(|item| &item.version) != Some(&version) {
+                batch.changed.push(SourceItem { key: key.clone(), version, path });
+            }
+            present.insert(key);
+        }
+        batch.removed = state.items.keys().filter(|key| !present.contains(*key)).cloned().collect();
+        Ok(batch)
+    }
+}
+
+/// The checked-out files of a local git repository. Each sync diffs the commit last synced
+/// with `HEAD`, so only files changed by new commits are read.
+struct GitSource {
+    id: String,
+    repo: PathBuf,
+    discovery: Discovery,
+}
+
+impl GitSource {
+    fn open(spec: &str, context: &SourceContext) -> Result<Box<dyn Source>> {
+        let repo = paths::normalize(Path::new(&spec["git:".len()..]));
+        git(&repo, &["rev-parse", "--git-dir"])?;
+        Ok(Box::new(Self {
+            id: format!("git:{}", StoredPath::new(&repo).key),
+            repo,
+            // The whole tree is tracked, with the include and exclude globs applied
+            discovery: Discovery { recursive: true, ..context.discovery.clone() },
+        }))
+    }
+
+    /// Files changed between two commits, and those deleted, relative to the repository
+    fn diff(&self, from: &str, to: &str) -> Result<(Vec<String>, Vec<String>)> {
+        let output = git(&self.repo, &["diff", "--name-status", "-z", "--no-renames", from, to])?;
+        let fields: Vec<String> = output.split(|&b| b == 0)
+            .filter(|field| !field.is_empty())
+            .map(|field| String::from_utf8_lossy(field).into_owned())
+            .collect();
+        let mut changed = Vec::new();
+        let mut deleted = Vec::new();
+        for pair in fields.chunks(2) {
+            if let [status, name] = pair {
+                if status.starts_with('D') {
+                    deleted.push(name.clone());
+                } else {
+                    changed.push(name.clone());
+                }
+            }
+        }
+        Ok((changed, deleted))
+    }
+}
+
+#[async_trait]
+impl Source for GitSource {
+    fn id(&self) -> &str {
+        &self.id
+    }
+
+    async fn sync(&mut self, state: &SyncState) -> Result<SyncBatch> {
+        let head = String::from_utf8_lossy(&git(&self.repo, &["rev-parse", "HEAD"])?).trim().to_string();
+        let mut batch = SyncBatch { cursor: Some(head.clone()), ..Default::default() };
+        if state.cursor.as_deref() == Some(head.as_str()) {
+            return Ok(batch);
+        }
+
+        // The last commit synced can be gone after a force push; then every file is listed
+        let known = state.cursor.as_deref()
+            .filter(|cursor| git(&self.repo, &["cat-file", "-e", &format!("{}^{{commit}}", cursor)]).is_ok());
+        let (changed, deleted) = match known {
+            Some(cursor) => self.diff(cursor, &head)?,
+            None => {
+                let listed = git(&self.repo, &["ls-tree", "-r", "-z", "--name-only", "HEAD"])?;
+                let names: Vec<String> = listed.split(|&b| b == 0)
+                    .filter(|name| !name.is_empty())
+                    .map(|name| String::from_utf8_lossy(name).into_owned())
+                    .collect();
+                (names, Vec::new())
+            }
+        };
+
+        let indexable: HashSet<String> = self.discovery.find_files(&self.repo)?
+            .iter()
+            .map(|path| StoredPath::new(path).key)
+            .collect();
+        let mut present = HashSet::new();
+        for name in changed {
+            let path = self.repo.join(&name);
+            let key = StoredPath::new(&path).key;
+            if indexable.contains(&key) {
+                present.insert(key.clone());
+                batch.changed.push(SourceItem { key, version: head.clone(), path });
+            }
+        }
+        batch.removed = deleted.iter().map(|name| StoredPath::new(&self.repo.join(name)).key).collect();
+        if known.is_none() {
+            batch.removed.extend(state.items.keys().filter(|key| !present.contains(*key)).cloned());
+        }
+        Ok(batch)
+    }
+}
+
+fn git(repo: &Path, args: &[&str]) -> Result<Vec<u8>> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(repo)
+        .args(args)
+        .output()
+        .context("Failed to run git")?;
+    if !output.status.success() {
+        anyhow::bail!("git {} failed in {}: {}", args.join(" "), repo.display(), String::from_utf8_lossy(&output.stderr).trim());
+    }
+    Ok(output.stdout)
+}
+
+/// A single document at a URL, fetched again only when the server says it changed
+struct UrlSource {
+    url: String,
+    cache_dir: PathBuf,
+    client: reqwest::Client,
+}
+
+impl UrlSource {
+    fn open(spec: &str, context: &SourceContext) -> Result<Box<dyn Source>> {
+        reqwest::Url::parse(spec).with_context(|| format!("Invalid source URL {}", spec))?;
+        Ok(Box::new(Self {
+            url: spec.to_string(),
+            cache_dir: context.cache_dir.clone(),
+            client: offline::client(),
+        }))
+    }
+}
+
+#[async_trait]
+impl Source for UrlSource {
+    fn id(&self) -> &str {
+        &self.url
+    }
+
+    async fn sync(&mut self, state: &SyncState) -> Result<SyncBatch> {
+        let previous = state.items.get(&self.url).map(|item| item.version.as_str());
+        let mut batch = SyncBatch::default();
+        if let Some((version, path)) = fetch(&self.client, &self.url, &self.cache_dir, previous).await? {
+            batch.changed.push(SourceItem { key: self.url.clone(), version, path });
+        }
+        Ok(batch)
+    }
+}
+
+/// The documents an RSS or Atom feed links to. Entries are versioned by their update time,
+/// and those that drop off the end of the feed stay indexed.
+struct FeedSource {
+    id: String,
+    url: String,
+    cache_dir: PathBuf,
+    client: reqwest::Client,
+}
+
+impl FeedSource {
+    fn open(spec: &str, context: &SourceContext) -> Result<Box<dyn Source>> {
+        let url = &spec["feed+".len()..];
+        reqwest::Url::parse(url).with_context(|| format!("Invalid feed URL {}", url))?;
+        Ok(Box::new(Self {
+            id: spec.to_string(),
+            url: url.to_string(),
+            cache_dir: context.cache_dir.clone(),
+            client: offline::client(),
+        }))
+    }
+}
+
+#[async_trait]
+impl Source for FeedSource {
+    fn id(&self) -> &str {
+        &self.id
+    }
+
+    async fn sync(&mut self, state: &SyncState) -> Result<SyncBatch> {
+        offline::check(&self.url, "A feed source")?;
+        let xml = self.client.get(&self.url).send().await
+            .and_then(|response| response.error_for_status())
+            .with_context(|| format!("Failed to fetch {}", self.url))?
+            .text().await
+            .with_context(|| format!("Failed to fetch {}", self.url))?;
+
+        let mut batch = SyncBatch::default();
+        for entry in parse_feed(&xml)? {
+            if state.items.get(&entry.link).is_some_and(|item| item.version == entry.version) {
+                continue;
+            }
+            // Entries are fetched whole each time their version changes
+            if let Some((_, path)) = fetch(&self.client, &entry.link, &self.cache_dir, None).await? {
+                batch.changed.push(SourceItem { key: entry.link, version: entry.version, path });
+            }
+        }
+        Ok(batch)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct FeedEntry {
+    link: String,
+    /// Update or publication time, or the entry's id when it has neither
+    version: String,
+}
+
+/// What an RSS `<item>` or Atom `<entry>` has said so far
+#[derive(Default)]
+struct FeedFields {
+    link: Option<String>,
+    id: Option<String>,
+    date: Option<String>,
+}
+
+impl FeedFields {
+    /// Take the text of one of the entry's elements
+    fn read(&mut self, element: &[u8], text: String) {
+        if text.is_empty() {
+            return;
+        }
+        match element {
+            b"link" => { self.link.get_or_insert(text); }
+            b"guid" | b"id" => { self.id.get_or_insert(text); }
+            b"updated" | b"pubDate" | b"published" => { self.date.get_or_insert(text); }
+            _ => {}
+        }
+    }
+}
+
+/// The entries of an RSS or Atom feed that link to a document
+fn parse_feed(xml: &str) -> Result<Vec<FeedEntry>> {
+    let mut reader = XmlReader::from_str(xml);
+    let mut buf = Vec::new();
+    let mut entries = Vec::new();
+    let mut entry: Option<FeedFields> = None;
+    // Element of the current entry whose text is being read
+    let mut element: Option<Vec<u8>> = None;
+    loop {
+        match reader.read_event_into(&mut buf) {
+            Ok(Event::Start(e)) => match e.local_name().as_ref() {
+                b"item" | b"entry" => entry = Some(FeedFields::default()),
+                b"link" if xml_attribute(&e, b"href").is_some() => {
+                    if let (Some(fields), Some(href)) = (entry.as_mut(), alternate_href(&e)) {
+                        fields.link.get_or_insert(href);
+                    }
+                }
+                name => element = Some(name.to_vec()),
+            },
+            Ok(Event::Empty(e)) if e.local_name().as_ref() == b"link" => {
+                if let (Some(fields), Some(href)) = (entry.as_mut(), alternate_href(&e)) {
+                    fields.link.get_or_insert(href);
+                }
+            }
+            Ok(Event::Text(text)) => {
+                if let (Some(fields), Some(name)) = (entry.as_mut(), &element) {
+                    fields.read(name, text.unescape().map(|text| text.trim().to_string()).unwrap_or_default());
+                }
+            }
+            Ok(Event::CData(text)) => {
+                if let (Some(fields), Some(name)) = (entry.as_mut(), &element) {
+                    fields.read(name, String::from_utf8_lossy(&text.into_inner()).trim().to_string());
+                }
+            }
+            Ok(Event::End(e)) => match e.local_name().as_ref() {
+                b"item" | b"entry" => {
+                    if let Some(FeedFields { link: Some(link), id, date }) = entry.take() {
+                        let version = date.or(id).unwrap_or_else(|| link.clone());
+                        entries.push(FeedEntry { link, version });
+                    }
+                }
+                _ => element = None,
+            },
+            Ok(Event::Eof) => break,
+            Err(e) => return Err(e).context("Invalid RSS or Atom feed"),
+            _ => {}
+        }
+        buf.clear();
+    }
+    Ok(entries)
+}
+
+/// The target of an Atom `<link>` pointing at the entry itself rather than, say, its comments
+fn alternate_href(element: &quick_xml::events::BytesStart) -> Option<String> {
+    if xml_attribute(element, b"rel").is_some_and(|rel| rel != "alternate") {
+        return None;
+    }
+    xml_attribute(element, b"href")
+}
+
+/// Download `url` into its own directory under `cache_dir`, named the way `serve` names
+/// downloaded documents. Returns its version and path, or `None` when it hasn't changed
+/// since the version `previous` of an earlier fetch.
+async fn fetch(client: &reqwest::Client, url: &str, cache_dir: &Path, previous: Option<&str>) -> Result<Option<(String, PathBuf)>> {
+    offline::check(url, "A URL source")?;
+    let mut request = client.get(url);
+    match previous.and_then(|version| version.split_once(':')) {
+        Some(("etag", tag)) => request = request.header(IF_NONE_MATCH, tag),
+        Some(("modified", date)) => request = request.header(IF_MODIFIED_SINCE, date),
+        _ => {}
+    }
+    let response = request.send().await.with_context(|| format!("Failed to fetch {}", url))?;
+    if response.status() == reqwest::StatusCode::NOT_MODIFIED {
+        return Ok(None);
+    }
+    let mut response = response.error_for_status().with_context(|| format!("Failed to fetch {}", url))?;
+    if response.content_length().is_some_and(|length| length > MAX_DOWNLOAD_BYTES) {
+        anyhow::bail!("{} is too large ({:.1} MB)", url, response.content_length().unwrap_or(0) as f64 / (1024.0 * 1024.0));
+    }
+
+    let header = |name: reqwest::header::HeaderName| response.headers().get(name).and_then(|value| value.to_str().ok()).map(str::to_string);
+    let tagged = header(ETAG).map(|tag| format!("etag:{}", tag))
+        .or_else(|| header(LAST_MODIFIED).map(|date| format!("modified:{}", date)));
+    let name = url_filename(response.url(), &header(CONTENT_TYPE).unwrap_or_default());
+    // The length header is only a hint, so count what actually arrives
+    let mut body = Vec::new();
+    while let Some(chunk) = response.chunk().await.with_context(|| format!("Failed to fetch {}", url))? {
+        if (body.len() + chunk.len()) as u64 > MAX_DOWNLOAD_BYTES {
+            anyhow::bail!("{} is too large (over {} MB)", url, MAX_DOWNLOAD_BYTES / (1024 * 1024));
+        }
+        body.extend_from_slice(&chunk);
+    }
+    // Servers that don't tag their responses are compared by content
+    let version = tagged.unwrap_or_else(|| format!("sha256:{}", storage::content_hash(&body)));
+    if previous == Some(version.as_str()) {
+        return Ok(None);
+    }
+
+    let dir = cache_dir.join(&storage::content_hash(url.as_bytes())[..16]);
+    std::fs::create_dir_all(&dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+    let path = dir.join(name);
+    std::fs::write(&path, &body).with_context(|| format!("Failed to write {}", path.display()))?;
+    Ok(Some((version, path)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lancedb_storage::LanceDBStorage;
+
+    #[test]
+    fn test_parse_rss_and_atom() {
+        let rss = r#"<rss><channel><title>News</title><link>https://example.com/</link>
+            <item><title>Q3</title><link>https://example.com/q3.pdf</link><pubDate>Tue, 01 Oct 2024 09:00:00 GMT</pubDate></item>
+            <item><link><![CDATA[https://example.com/memo.html]]></link><guid>memo-7</guid></item>
+            <item><title>No link</title></item>
+        </channel></rss>"#;
+        assert_eq!(parse_feed(rss).unwrap(), vec![
+            FeedEntry { link: "https://example.com/q3.pdf".into(), version: "Tue, 01 Oct 2024 09:00:00 GMT".into() },
+            FeedEntry { link: "https://example.com/memo.html".into(), version: "memo-7".into() },
+        ]);
+
+        let atom = r#"<feed xmlns="http://www.w3.org/2005/Atom"><link href="https://example.com/"/>
+            <entry><id>urn:1</id><link rel="replies" href="https://example.com/1#comments"/>
+            <link rel="alternate" href="https://example.com/1"/><updated>2024-10-01T09:00:00Z</updated></entry>
+        </feed>"#;
+        assert_eq!(parse_feed(atom).unwrap(), vec![
+            FeedEntry { link: "https://example.com/1".into(), version: "2024-10-01T09:00:00Z".into() },
+        ]);
+    }
+
+    #[tokio::test]
+    async fn test_directory_source_syncs_changes_only() {
+        let dir = std::env::temp_dir().join(format!("pb-sources-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("a.txt"), "alpha").unwrap();
+        std::fs::write(dir.join("b.txt"), "beta").unwrap();
+
+        let mut storage = LanceDBStorage::new(Path::new("sources")).await.unwrap();
+        let context = SourceContext { discovery: Discovery::default(), cache_dir: dir.join("cache") };
+        let mut source = open(&format!("dir:{}", dir.display()), &context).unwrap();
+        let pending = sync(&mut storage, source.as_mut()).await.unwrap();
+        assert_eq!(pending.files.len(), 2);
+        commit(&mut storage, pending).await.unwrap();
+
+        // Nothing changed, so nothing is returned
+        let pending = sync(&mut storage, source.as_mut()).await.unwrap();
+        assert!(pending.files.is_empty() && pending.removed.is_empty());
+        commit(&mut storage, pending).await.unwrap();
+
+        std::fs::write(dir.join("a.txt"), "alpha, revised").unwrap();
+        std::fs::remove_file(dir.join("b.txt")).unwrap();
+        let pending = sync(&mut storage, source.as_mut()).await.unwrap();
+        assert_eq!(pending.files, vec![paths::normalize(&dir.join("a.txt"))]);
+        assert_eq!(pending.removed, vec![paths::normalize(&dir.join("b.txt"))]);
+        commit(&mut storage, pending).await.unwrap();
+
+        let states = load_states(&mut storage).await.unwrap();
+        assert_eq!(states[source.id()].items.len(), 1);
+        assert!(open("s3://bucket/reports", &context).is_err());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,133 @@
+use crate::llm::TokenUsage;
+
+/// What a model costs, in US dollars per million tokens
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ModelPrice {
+    pub input: f64,
+    /// Prompt tokens read from the provider's prompt cache
+    pub cached_input: f64,
+    pub output: f64,
+}
+
+/// List prices of the hosted models eatmybrain knows, matched against the start of the
+/// model name so dated snapshots (`claude-3-haiku-20240307`) find theirs
+const PRICES: &[(&str, ModelPrice)] = &[
+    ("gpt-4o-mini", ModelPrice { input: 0.15, cached_input: 0.075, output: 0.6 }),
+    ("gpt-4o", ModelPrice { input: 2.5, cached_input: 1.25, output: 10.0 }),
+    ("gpt-4-turbo", ModelPrice { input: 10.0, cached_input: 10.0, output: 30.0 }),
+    ("gpt-4", ModelPrice { input: 30.0, cached_input: 30.0, output: 60.0 }),
+    ("gpt-3.5-turbo", ModelPrice { input: 0.5, cached_input: 0.5, output: 1.5 }),
+    ("claude-3-opus", ModelPrice { input: 15.0, cached_input: 1.5, output: 75.0 }),
+    ("claude-3-5-sonnet", ModelPrice { input: 3.0, cached_input: 0.3, output: 15.0 }),
+    ("claude-3-sonnet", ModelPrice { input: 3.0, cached_input: 0.3, output: 15.0 }),
+    ("claude-3-5-haiku", ModelPrice { input: 0.8, cached_input: 0.08, output: 4.0 }),
+    ("claude-3-haiku", ModelPrice { input: 0.25, cached_input: 0.03, output: 1.25 }),
+];
+
+impl ModelPrice {
+    /// The list price of `model`, from the entry matching the longest start of its name
+    pub fn for_model(model: &str) -> Option<Self> {
+        PRICES.iter()
+            .filter(|(prefix, _)| model.starts_with(prefix))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, price)| *price)
+    }
+
+    /// Estimated dollars spent on `usage`. Cache writes are counted at the input price,
+    /// which some providers charge a little more for.
+    pub fn cost(&self, usage: &TokenUsage) -> f64 {
+        let uncached = usage.prompt_tokens.saturating_sub(usage.cached_tokens);
+        (uncached as f64 * self.input
+            + usage.cached_tokens as f64 * self.cached_input
+            + usage.completion_tokens as f64 * self.output)
+            / 1_000_000.0
+    }
+}
+
+/// Tokens and estimated dollars spent in a chat session, with the cap after which the
+/// user is asked before more requests are made
+#[derive(Debug, Clone)]
+pub struct SpendingCap {
+    price: Option<ModelPrice>,
+    limit: Option<f64>,
+    /// Spending at which confirmation is asked for next
+    next_check: f64,
+    usage: TokenUsage,
+    spent: f64,
+}
+
+impl SpendingCap {
+    pub fn new(price: Option<ModelPrice>, limit: Option<f64>) -> Self {
+        Self { price, limit, next_check: limit.unwrap_or(f64::INFINITY), usage: TokenUsage::default(), spent: 0.0 }
+    }
+
+    /// Count the tokens of a finished request
+    pub fn record(&mut self, usage: TokenUsage) {
+        self.usage += usage;
+        if let Some(price) = &self.price {
+            self.spent += price.cost(&usage);
+        }
+    }
+
+    pub fn usage(&self) -> TokenUsage {
+        self.usage
+    }
+
+    /// Estimated dollars spent so far, when the model's price is known
+    pub fn spent(&self) -> Option<f64> {
+        self.price.map(|_| self.spent)
+    }
+
+    pub fn limit(&self) -> Option<f64> {
+        self.limit
+    }
+
+    /// Whether spending has reached the cap since it was last confirmed
+    pub fn reached(&self) -> bool {
+        self.price.is_some() && self.spent >= self.next_check
+    }
+
+    /// Allow another cap's worth of spending before asking again
+    pub fn confirm(&mut self) {
+        if let Some(limit) = self.limit {
+            self.next_check = self.spent + limit;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_spending_cap_asks_again_after_each_cap() {
+        let price = ModelPrice::for_model("claude-3-haiku-20240307").unwrap();
+        assert_eq!(price.input, 0.25);
+        assert_eq!(ModelPrice::for_model("gpt-4o-mini-2024-07-18").unwrap().output, 0.6);
+        assert_eq!(ModelPrice::for_model("llama3"), None);
+
+        let usage = TokenUsage {
+            prompt_tokens: 1_000_000,
+            completion_tokens: 100_000,
+            total_tokens: 1_100_000,
+            cached_tokens: 500_000,
+            cache_write_tokens: 0,
+        };
+        assert!((price.cost(&usage) - (0.125 + 0.015 + 0.125)).abs() < 1e-9);
+
+        let mut cap = SpendingCap::new(Some(price), Some(0.5));
+        cap.record(usage);
+        assert!(!cap.reached());
+        cap.record(usage);
+        assert!(cap.reached());
+        cap.confirm();
+        assert!(!cap.reached());
+        assert_eq!(cap.usage().prompt_tokens, 2_000_000);
+
+        // Without a price nothing can be estimated, so the cap never trips
+        let mut unpriced = SpendingCap::new(None, Some(0.01));
+        unpriced.record(usage);
+        assert_eq!(unpriced.spent(), None);
+        assert!(!unpriced.reached());
+    }
+}
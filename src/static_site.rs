@@ -0,0 +1,303 @@
+use anyhow::{Context, Result};
+use std::collections::HashSet;
+use std::path::Path;
+
+use crate::annotations::AnnotationLabel;
+use crate::autocomplete;
+use crate::storage::{self, Storage};
+
+/// The page, loading `SITE_DATA` and searching it in the browser
+const SITE_PAGE: &str = "index.html";
+
+/// A script defining the fragments and their quantized vectors, so the page also works
+/// opened straight from disk, where browsers refuse to fetch neighbouring files
+const SITE_DATA: &str = "brain.js";
+
+/// Where the browser loads transformers.js, which runs the embedding model with ONNX
+/// Runtime compiled to WebAssembly
+const TRANSFORMERS_JS: &str = "https://cdn.jsdelivr.net/npm/@huggingface/transformers@3";
+
+/// What `export_static_site` wrote
+#[derive(Debug, Default, PartialEq)]
+pub struct SiteStats {
+    pub documents: usize,
+    pub fragments: usize,
+    /// Size of the quantized vectors, in bytes
+    pub vector_bytes: usize,
+}
+
+/// The transformers.js conversion of `model` on the Hugging Face hub, which by convention
+/// keeps the model's name under the Xenova account
+pub fn browser_model(model: &str) -> String {
+    format!("Xenova/{}", model.rsplit('/').next().unwrap_or(model))
+}
+
+/// How token embeddings are pooled into one vector, matching FastEmbed: BGE models use the
+/// CLS token, the others the mean
+fn pooling(model: &str) -> &'static str {
+    if model.to_lowercase().contains("bge") { "cls" } else { "mean" }
+}
+
+/// Quantize a vector to signed bytes scaled by its largest component, returning the bytes
+/// and the factor that turns their dot product with a unit-length query into the cosine
+/// similarity
+fn quantize(vector: &[f64]) -> (Vec<i8>, f32) {
+    let largest = vector.iter().fold(0.0f64, |largest, x| largest.max(x.abs()));
+    let norm = vector.iter().map(|x| x * x).sum::<f64>().sqrt();
+    if largest == 0.0 || norm == 0.0 {
+        return (vec![0; vector.len()], 0.0);
+    }
+    let bytes = vector.iter().map(|x| (x / largest * 127.0).round() as i8).collect();
+    (bytes, (largest / 127.0 / norm) as f32)
+}
+
+/// Standard base64 with padding
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk.iter().enumerate().fold(0u32, |n, (i, byte)| n | (*byte as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i)) as usize & 63] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+/// Write a static search site for `storage` into the directory `output`: an HTML page that
+/// embeds queries in the browser with `model` (a transformers.js model id) and ranks every
+/// fragment by cosine similarity against int8-quantized vectors, falling back to keyword
+/// matching when the model can't be loaded, and completes queries with the titles, headings
+/// and frequent entities of the documents it holds. Tombstoned documents, fragments without
+/// a current vector and anything marked wrong are left out.
+pub async fn export_static_site(storage: &mut dyn Storage, output: &Path, model: &str) -> Result<SiteStats> {
+    std::fs::create_dir_all(output)
+        .with_context(|| format!("Failed to create {}", output.display()))?;
+
+    let excluded: HashSet<String> = storage.list_annotations(None).await?.into_iter()
+        .filter(|annotation| annotation.label == Some(AnnotationLabel::Wrong))
+        .map(|annotation| annotation.target)
+        .collect();
+    let prefixes = storage::embedding_prefixes(storage).await?;
+
+    let mut stats = SiteStats::default();
+    let mut documents = Vec::new();
+    let mut fragments = Vec::new();
+    let mut vectors: Vec<u8> = Vec::new();
+    let mut dimension = None;
+    for document in storage.list_documents().await? {
+        if document.tombstoned || excluded.contains(&document.id) {
+            continue;
+        }
+        let mut kept = 0;
+        for record in storage.get_fragment_records(&document.id).await? {
+            let Some(embedding) = record.embedding.filter(|_| !record.stale && !excluded.contains(&record.id)) else {
+                continue;
+            };
+            if *dimension.get_or_insert(embedding.len()) != embedding.len() {
+                anyhow::bail!("Fragment {} has {} dimensions, expected {}", record.id, embedding.len(), dimension.unwrap_or_default());
+            }
+            let (bytes, scale) = quantize(&embedding);
+            vectors.extend(bytes.iter().map(|byte| *byte as u8));
+
+            let mut citation = vec![match &document.title {
+                Some(title) => format!("{} ({})", title, document.file_path),
+                None => document.file_path.clone(),
+            }];
+            citation.extend(record.section.clone());
+            citation.push(format!("fragment {}", record.order));
+            fragments.push(serde_json::json!({
+                "id": record.id,
+                "document": documents.len(),
+                "citation": citation.join(", "),
+                "content": record.content,
+                "scale": scale,
+            }));
+            kept += 1;
+        }
+        if kept > 0 {
+            documents.push(document.file_path);
+        }
+    }
+
+    // Terms of the excluded documents would complete to queries finding nothing
+    let terms: Vec<String> = autocomplete::collect_terms(storage, &excluded).await?.into_iter()
+        .map(|term| term.text)
+        .collect();
+
+    let brain = serde_json::json!({
+        "model": model,
+        "pooling": pooling(model),
+        "query_prefix": prefixes.query,
+        "dimension": dimension.unwrap_or(0),
+        "documents": documents,
+        "fragments": fragments,
+        "terms": terms,
+    });
+    let data = format!("window.BRAIN = {};\nwindow.VECTORS = \"{}\";\n", serde_json::to_string(&brain)?, base64(&vectors));
+    std::fs::write(output.join(SITE_DATA), data)
+        .with_context(|| format!("Failed to write {}", output.join(SITE_DATA).display()))?;
+    std::fs::write(output.join(SITE_PAGE), PAGE.replace("{TRANSFORMERS_JS}", TRANSFORMERS_JS))
+        .with_context(|| format!("Failed to write {}", output.join(SITE_PAGE).display()))?;
+
+    stats.documents = documents.len();
+    stats.fragments = fragments.len();
+    stats.vector_bytes = vectors.len();
+    Ok(stats)
+}
+
+/// The search page. Vectors are decoded once; each query is embedded with the same prefix
+/// and pooling as the database, normalized, and scored against every fragment. As a query is
+/// typed, its last words are completed from the terms the way `PrefixIndex` does.
+const PAGE: &str = r##"<!DOCTYPE html>
+<html><head><meta charset="utf-8"><title>Portable Brains search</title>
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<style>
+body { font-family: system-ui, sans-serif; margin: 2em auto; max-width: 50em; padding: 0 1em; color: #222; }
+input { width: 100%; font-size: 1.2em; padding: 0.4em; box-sizing: border-box; }
+#status { color: #666; font-size: 0.9em; }
+.hit { border-bottom: 1px solid #eee; padding: 0.8em 0; }
+.cite { color: #36c; font-size: 0.9em; }
+.score { color: #999; font-size: 0.8em; float: right; }
+</style>
+<script src="brain.js"></script></head>
+<body>
+<h1>Portable Brains search</h1>
+<input id="query" placeholder="Ask a question…" list="suggestions" autocomplete="off" autofocus>
+<datalist id="suggestions"></datalist>
+<p id="status">Loading…</p>
+<div id="results"></div>
+<script type="module">
+const escape = (text) => String(text ?? "").replace(/[&<>"]/g, (c) => ({"&": "&amp;", "<": "&lt;", ">": "&gt;", "\"": "&quot;"})[c]);
+const status = document.getElementById("status");
+const brain = window.BRAIN, d = brain.dimension;
+const raw = atob(window.VECTORS), vectors = new Int8Array(raw.length);
+for (let i = 0; i < raw.length; i++) vectors[i] = raw.charCodeAt(i) << 24 >> 24;
+
+let embed = null;
+async function loadModel() {
+  status.textContent = `${brain.fragments.length} fragments; loading ${brain.model}…`;
+  try {
+    const { pipeline } = await import("{TRANSFORMERS_JS}");
+    const extractor = await pipeline("feature-extraction", brain.model);
+    embed = async (text) => (await extractor(brain.query_prefix + text, { pooling: brain.pooling, normalize: true })).data;
+    status.textContent = `${brain.fragments.length} fragments, semantic search with ${brain.model}`;
+  } catch (e) {
+    status.textContent = `${brain.fragments.length} fragments, keyword search (couldn't load ${brain.model}: ${e.message})`;
+  }
+}
+
+function similarity(query) {
+  return brain.fragments.map((fragment, i) => {
+    let dot = 0;
+    for (let j = 0, offset = i * d; j < d; j++) dot += vectors[offset + j] * query[j];
+    return dot * fragment.scale;
+  });
+}
+
+function keywords(text) {
+  const terms = text.toLowerCase().match(/\p{L}[\p{L}\p{N}]*|\p{N}+/gu) || [];
+  return brain.fragments.map((fragment) => {
+    const content = fragment.content.toLowerCase();
+    return terms.length ? terms.filter((term) => content.includes(term)).length / terms.length : 0;
+  });
+}
+
+async function search() {
+  const text = document.getElementById("query").value.trim();
+  if (!text) return;
+  const scores = embed ? similarity(await embed(text)) : keywords(text);
+  const top = scores.map((score, i) => [score, i]).filter(([score]) => score > 0)
+    .sort((a, b) => b[0] - a[0]).slice(0, 10);
+  document.getElementById("results").innerHTML = top.length ? top.map(([score, i]) => {
+    const fragment = brain.fragments[i];
+    return `<div class="hit"><span class="score">${score.toFixed(3)}</span>` +
+      `<div class="cite">${escape(fragment.citation)}</div>${escape(fragment.content.slice(0, 400))}</div>`;
+  }).join("") : "<p>No matching fragments</p>";
+}
+
+const words = brain.terms.map((term) => [...term.matchAll(/(?:^|\s)(?=\S)/g)].map((m) => m.index + m[0].length));
+function complete() {
+  const text = document.getElementById("query").value, lower = text.toLowerCase();
+  const list = document.getElementById("suggestions");
+  for (const { index: start } of text.matchAll(/(?<!\S)\S/g)) {
+    const typed = lower.slice(start);
+    if (typed.trimEnd().length < 2) break;
+    const found = [];
+    brain.terms.forEach((term, i) => {
+      const word = words[i].find((w) => term.length - w > typed.length && term.slice(w).toLowerCase().startsWith(typed));
+      if (word !== undefined) found.push([word > 0, i, text.slice(0, start) + term.slice(word)]);
+    });
+    if (found.length) {
+      found.sort((a, b) => a[0] - b[0] || a[1] - b[1]);
+      list.innerHTML = found.slice(0, 8).map(([, , query]) => `<option value="${escape(query)}">`).join("");
+      return;
+    }
+  }
+  list.innerHTML = "";
+}
+
+document.getElementById("query").addEventListener("input", complete);
+document.getElementById("query").addEventListener("keydown", (event) => { if (event.key === "Enter") search(); });
+loadModel();
+</script>
+</body></html>
+"##;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lancedb_storage::LanceDBStorage;
+    use crate::storage::FragmentMeta;
+
+    #[test]
+    fn test_quantized_vectors_keep_cosine() {
+        let vector = [0.3, -0.2, 0.05, 0.9];
+        let query = [0.5, -0.1, 0.2, 0.8];
+        let norm = |v: &[f64]| v.iter().map(|x| x * x).sum::<f64>().sqrt();
+        let cosine = vector.iter().zip(&query).map(|(a, b)| a * b).sum::<f64>() / (norm(&vector) * norm(&query));
+
+        let (bytes, scale) = quantize(&vector);
+        let unit: Vec<f64> = query.iter().map(|x| x / norm(&query)).collect();
+        let approximate = bytes.iter().zip(&unit).map(|(byte, q)| *byte as f64 * q).sum::<f64>() * scale as f64;
+        assert!((approximate - cosine).abs() < 0.01, "{} vs {}", approximate, cosine);
+        assert_eq!(quantize(&[0.0, 0.0]), (vec![0, 0], 0.0));
+    }
+
+    #[test]
+    fn test_base64_and_model_names() {
+        assert_eq!(base64(b"brain"), "YnJhaW4=");
+        assert_eq!(base64(&[0xff, 0x80, 0x01]), "/4AB");
+        assert_eq!(browser_model("BAAI/bge-small-en-v1.5"), "Xenova/bge-small-en-v1.5");
+        assert_eq!(pooling("sentence-transformers/all-MiniLM-L6-v2"), "mean");
+    }
+
+    #[tokio::test]
+    async fn test_site_keeps_searchable_fragments() {
+        let mut storage = LanceDBStorage::new(Path::new("site")).await.unwrap();
+        let kept = storage.store_document(Path::new("kept.txt"), b"kept").await.unwrap();
+        let embedded = storage.store_text_fragment(&kept, 0, "Backups run nightly", &FragmentMeta::default()).await.unwrap();
+        storage.store_text_fragment(&kept, 1, "Not embedded yet", &FragmentMeta::default()).await.unwrap();
+        storage.update_fragment_embeddings_batch(&[(embedded, vec![0.6f32, 0.8])]).await.unwrap();
+        let gone = storage.store_document(Path::new("gone.txt"), b"gone").await.unwrap();
+        let tombstoned = storage.store_text_fragment(&gone, 0, "Deleted notes", &FragmentMeta::default()).await.unwrap();
+        storage.update_fragment_embeddings_batch(&[(tombstoned, vec![1.0f32, 0.0])]).await.unwrap();
+        storage.tombstone_document(&gone, true).await.unwrap();
+        storage.set_document_title(&kept, "Backup Schedule").await.unwrap();
+        storage.set_document_title(&gone, "Retired Notes").await.unwrap();
+
+        let output = std::env::temp_dir().join(format!("pb-site-{}", std::process::id()));
+        let stats = export_static_site(&mut storage, &output, "Xenova/bge-small-en-v1.5").await.unwrap();
+        assert_eq!(stats, SiteStats { documents: 1, fragments: 1, vector_bytes: 2 });
+
+        let data = std::fs::read_to_string(output.join(SITE_DATA)).unwrap();
+        assert!(data.contains("Backups run nightly") && !data.contains("Deleted notes") && !data.contains("Not embedded"));
+        assert!(data.contains("\"terms\":[\"Backup Schedule\"]"));
+        assert!(std::fs::read_to_string(output.join(SITE_PAGE)).unwrap().contains(TRANSFORMERS_JS));
+        let _ = std::fs::remove_dir_all(&output);
+    }
+}
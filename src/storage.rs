@@ -1,7 +1,34 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
 use async_trait::async_trait;
-use std::path::Path;
+use clap::ValueEnum;
+use log::warn;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::time::UNIX_EPOCH;
+use uuid::Uuid;
+
+use crate::annotations::{Annotation, AnnotationNote};
+use crate::document_processor::unpack_sections;
+use crate::duckdb_storage::DuckDBStorage;
+use crate::embedding_manager::{EmbeddingManager, EmbeddingPrefixes};
+use crate::error::{self, PortableBrainsError};
+use crate::hybrid::{reciprocal_rank_fusion, FusedHit, FUSION_POOL};
+use crate::lancedb_storage::LanceDBStorage;
+use crate::remote_storage::{self, RemoteStorage};
+use crate::sharded_storage::ShardedStorage;
+use crate::storage_thread::StorageThread;
+
+/// Arrow as linked by DuckDB, so fragment batches pass in and out of it without conversion
+pub use duckdb::arrow;
+use arrow::array::{Array, RecordBatch};
+use arrow::datatypes::{DataType, Field, Schema, SchemaRef};
+
+/// Fragments per batch when fragments are read in bulk
+pub const FRAGMENT_BATCH_SIZE: usize = 8192;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct DocumentInfo {
@@ -9,45 +36,1815 @@ pub struct DocumentInfo {
     pub filename: String,
     pub file_path: String,
     pub file_type: String,
-    pub file_data: Vec<u8>,
+    /// The original bytes; `None` for documents indexed with `--store-originals none`
+    pub file_data: Option<Vec<u8>>,
+    pub created_at: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct FragmentInfo {
+    pub id: String,
+    pub document_id: String,
+    pub fragment_order: i32,
+    pub content: String,
+    pub embedding: Option<Vec<f64>>,
     pub created_at: Option<String>,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
-pub struct FragmentInfo {
-    pub id: String,
-    pub document_id: String,
-    pub fragment_order: i32,
-    pub content: String,
-    pub embedding: Option<Vec<f64>>,
-    pub created_at: Option<String>,
+/// Longest fragment accepted, in bytes; chunks are normally under a kilobyte, so anything
+/// this large means chunking failed
+pub const MAX_FRAGMENT_BYTES: usize = 64 * 1024;
+
+/// Check fragment text before a backend stores it, returning the text to store.
+///
+/// NUL characters are stripped, since they truncate or corrupt text in some backends.
+/// Empty, whitespace-only and oversized fragments are rejected with
+/// `PortableBrainsError::ValidationError` so callers can skip them and carry on.
+pub fn validate_fragment(content: &str) -> Result<Cow<'_, str>> {
+    let content = if content.contains('\0') {
+        Cow::Owned(content.replace('\0', ""))
+    } else {
+        Cow::Borrowed(content)
+    };
+
+    if content.trim().is_empty() {
+        return Err(PortableBrainsError::ValidationError("fragment is empty or whitespace only".to_string()).into());
+    }
+    if content.len() > MAX_FRAGMENT_BYTES {
+        return Err(PortableBrainsError::ValidationError(format!(
+            "fragment is {} bytes, over the {} byte limit",
+            content.len(), MAX_FRAGMENT_BYTES
+        )).into());
+    }
+    Ok(content)
+}
+
+/// A stored document as shown by `list`
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DocumentSummary {
+    pub id: String,
+    pub filename: String,
+    pub file_path: String,
+    pub fragments: i32,
+    /// Extraction quality score, for documents indexed since scoring was added
+    pub quality_score: Option<f64>,
+    /// Why the extraction looks garbled; empty when it looks fine
+    pub quality_flags: Vec<String>,
+    /// The source file is gone; the document is kept but excluded from search
+    pub tombstoned: bool,
+    /// SHA-256 of the original bytes, for documents indexed since hashing was added
+    pub content_hash: Option<String>,
+    /// Modification time of the source file when it was indexed, in microseconds since the
+    /// Unix epoch
+    pub modified: Option<i64>,
+    /// Title derived from the document's text, for documents indexed since titles were added
+    pub title: Option<String>,
+}
+
+/// What the indexer assigned to a document besides its content, as carried over when a
+/// brain is copied to another backend
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct DocumentAttributes {
+    /// Embedding priority; higher values are embedded first
+    pub priority: i32,
+    /// Category from the classifier, with its confidence score
+    pub category: Option<(String, f64)>,
+    /// Collection from the routing rules
+    pub collection: Option<String>,
+    pub tags: Vec<String>,
+}
+
+/// An entity named in stored fragments, as listed by `list_entities`
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct EntityCount {
+    /// The name as first seen
+    pub name: String,
+    /// Fragments mentioning it
+    pub fragments: usize,
+    /// Documents mentioning it
+    pub documents: usize,
+}
+
+/// A document mentioning an entity, as found by `find_entity`
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct EntityMention {
+    pub document_id: String,
+    pub file_path: String,
+    /// The names it was mentioned by, e.g. both "Chen" and "Maria Chen", sorted
+    pub names: Vec<String>,
+    /// Fragments mentioning it
+    pub fragments: usize,
+}
+
+/// Where a fragment sits within its document, recorded alongside its content
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct FragmentMeta {
+    /// Continuation segment the fragment was chunked from; 0 unless the document's text
+    /// was longer than one segment
+    pub segment: u32,
+    /// Headings the fragment sits under, outermost first and joined with ` > `; absent
+    /// before the first heading and for formats without an outline
+    pub section: Option<String>,
+    /// PDF page the fragment's text is from
+    #[serde(default)]
+    pub page: Option<u32>,
+    /// Slide or worksheet the fragment's text is from
+    #[serde(default)]
+    pub location: Option<Location>,
+    /// Block the fragment holds whole, when it is a code block, table or list rather than
+    /// running prose
+    #[serde(default)]
+    pub structure: Option<Structure>,
+    /// Byte range of the fragment in its document's extracted text (the sections joined as
+    /// by `join_sections`), when the fragment is a verbatim piece of it
+    #[serde(default)]
+    pub span: Option<(usize, usize)>,
+}
+
+/// Where in a slide deck or workbook a fragment's text is from, for formats whose parts
+/// are slides or sheets rather than pages
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Location {
+    /// Slide number, from 1
+    Slide(u32),
+    /// Worksheet name
+    Sheet(String),
+}
+
+impl Location {
+    /// As stored and cited, e.g. `slide 12` or `sheet Q3 Forecast`
+    pub fn label(&self) -> String {
+        match self {
+            Location::Slide(number) => format!("slide {}", number),
+            Location::Sheet(name) => format!("sheet {}", name),
+        }
+    }
+
+    pub fn parse(label: &str) -> Option<Self> {
+        if let Some(number) = label.strip_prefix("slide ") {
+            return number.parse().ok().map(Location::Slide);
+        }
+        label.strip_prefix("sheet ").map(|name| Location::Sheet(name.to_string()))
+    }
+}
+
+/// Kind of block the chunker keeps whole instead of splitting it into sentences
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Structure {
+    /// A fenced code block, fences included
+    Code,
+    /// Rows of a pipe table
+    Table,
+    /// Bulleted or numbered list items
+    List,
+}
+
+impl Structure {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Structure::Code => "code",
+            Structure::Table => "table",
+            Structure::List => "list",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "code" => Some(Structure::Code),
+            "table" => Some(Structure::Table),
+            "list" => Some(Structure::List),
+            _ => None,
+        }
+    }
+}
+
+/// How far indexing got with a document, recorded as it advances so a run that dies part
+/// way can be resumed. Documents indexed before states were recorded have none.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IndexState {
+    /// The original is stored; its fragments may be missing or partly written
+    Stored,
+    /// Every fragment is written; some may still lack a current vector
+    Chunked,
+    /// Every fragment has a vector from the current model
+    Embedded,
+}
+
+impl IndexState {
+    pub fn name(self) -> &'static str {
+        match self {
+            IndexState::Stored => "stored",
+            IndexState::Chunked => "chunked",
+            IndexState::Embedded => "embedded",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "stored" => Some(IndexState::Stored),
+            "chunked" => Some(IndexState::Chunked),
+            "embedded" => Some(IndexState::Embedded),
+            _ => None,
+        }
+    }
+}
+
+/// How `replace_fragments` changed a document's fragments
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct FragmentChanges {
+    /// Fragments whose text was unchanged; they keep their id and vector
+    pub kept: usize,
+    /// New fragments, stored without a vector
+    pub added: usize,
+    pub removed: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MetaInfo {
+    pub version: String,
+    pub embedding_model: String,
+    /// Length of the current model's vectors, once the first have been stored
+    pub embedding_dimension: Option<usize>,
+    /// Provider that produced them, e.g. `local` or `ollama`
+    pub embedding_provider: Option<String>,
+}
+
+/// How much of the current model's vectors an approximate nearest-neighbour index covers
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct VectorIndexCoverage {
+    /// Fragments searched through the index
+    pub indexed: usize,
+    /// Fragments embedded since the index was last brought up to date, searched by scanning
+    /// them until the next embed phase indexes them
+    pub unindexed: usize,
+}
+
+/// Size of the corpus a database holds, as reported by `stats`
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct CorpusStats {
+    /// Documents per file type, most common first
+    pub file_types: Vec<(String, usize)>,
+    pub documents: usize,
+    pub fragments: usize,
+    /// Characters of fragment text, summed over all fragments
+    pub fragment_chars: u64,
+    /// Bytes of the stored originals, summed over all documents
+    pub document_bytes: u64,
+    /// The largest documents by original size, largest first
+    pub largest: Vec<DocumentSize>,
+}
+
+/// A document's original size and how many fragments it was chunked into
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct DocumentSize {
+    pub file_path: String,
+    pub bytes: u64,
+    pub fragments: usize,
+}
+
+impl CorpusStats {
+    /// Average fragment length in characters
+    pub fn average_fragment_chars(&self) -> f64 {
+        if self.fragments == 0 {
+            0.0
+        } else {
+            self.fragment_chars as f64 / self.fragments as f64
+        }
+    }
+
+    /// Add another store's statistics, keeping the `largest` biggest documents of both
+    pub fn merge(&mut self, other: CorpusStats, largest: usize) {
+        let mut file_types: HashMap<String, usize> = self.file_types.drain(..).collect();
+        for (file_type, count) in other.file_types {
+            *file_types.entry(file_type).or_default() += count;
+        }
+        self.file_types = file_types.into_iter().collect();
+        sort_file_types(&mut self.file_types);
+        self.documents += other.documents;
+        self.fragments += other.fragments;
+        self.fragment_chars += other.fragment_chars;
+        self.document_bytes += other.document_bytes;
+        self.largest.extend(other.largest);
+        sort_largest(&mut self.largest);
+        self.largest.truncate(largest);
+    }
+}
+
+/// Most common file type first, ties by name
+pub fn sort_file_types(file_types: &mut [(String, usize)]) {
+    file_types.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+}
+
+/// Largest document first, ties by path
+pub fn sort_largest(documents: &mut [DocumentSize]) {
+    documents.sort_by(|a, b| b.bytes.cmp(&a.bytes).then_with(|| a.file_path.cmp(&b.file_path)));
+}
+
+/// Everything stored about a fragment, as written by `export`
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct FragmentRecord {
+    pub id: String,
+    pub order: i32,
+    pub section: Option<String>,
+    pub content: String,
+    /// `None` until the embed phase has run
+    pub embedding: Option<Vec<f64>>,
+    /// The vector is from the previous embedding model
+    pub stale: bool,
+}
+
+/// A fragment that needs a vector, with what decides how soon it is embedded
+#[derive(Debug, Clone, PartialEq)]
+pub struct PendingFragment {
+    pub id: String,
+    pub content: String,
+    /// Embedding priority of its document
+    pub priority: i32,
+    /// It has a vector from the previous model rather than none
+    pub stale: bool,
+    /// Times searches returned it
+    pub hits: u32,
+}
+
+impl PendingFragment {
+    /// Sort key of the embed queue: highest priority first, missing vectors before stale
+    /// ones, then most-queried first. Backends break ties further on their own.
+    pub fn queue_key(&self) -> (std::cmp::Reverse<i32>, bool, std::cmp::Reverse<u32>) {
+        (std::cmp::Reverse(self.priority), self.stale, std::cmp::Reverse(self.hits))
+    }
+}
+
+/// Arrow schema of the fragment batches read and written in bulk by `read_fragment_batch`
+/// and `write_fragment_batch`: one row per fragment, vectors as lists of floats
+pub fn fragment_schema() -> SchemaRef {
+    let item = Arc::new(Field::new("item", DataType::Float32, true));
+    Arc::new(Schema::new(vec![
+        Field::new("id", DataType::Utf8, false),
+        Field::new("document_id", DataType::Utf8, false),
+        Field::new("fragment_order", DataType::Int32, false),
+        Field::new("segment", DataType::Int32, false),
+        Field::new("section", DataType::Utf8, true),
+        Field::new("page", DataType::Int32, true),
+        Field::new("structure", DataType::Utf8, true),
+        Field::new("location", DataType::Utf8, true),
+        Field::new("content", DataType::Utf8, false),
+        Field::new("embedding", DataType::List(item), true),
+        Field::new("stale", DataType::Boolean, false),
+    ]))
+}
+
+/// A batch with the columns of `fragment_schema`, cast to its types. Backends name list
+/// items differently, and DuckDB returns fixed-size lists from the compact layout. Optional
+/// columns missing from batches written before they existed are filled with nulls.
+pub fn conform_fragment_batch(batch: &RecordBatch) -> Result<RecordBatch> {
+    let schema = fragment_schema();
+    let columns = schema.fields().iter()
+        .map(|field| {
+            let Some(column) = batch.column_by_name(field.name()) else {
+                if field.is_nullable() {
+                    return Ok(arrow::array::new_null_array(field.data_type(), batch.num_rows()));
+                }
+                anyhow::bail!("Fragment batch has no {} column", field.name());
+            };
+            arrow::compute::cast(column, field.data_type())
+                .with_context(|| format!("Fragment batch column {} has the wrong type", field.name()))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    RecordBatch::try_new(schema, columns).context("Invalid fragment batch")
+}
+
+/// Column `name` of a fragment batch as its concrete array type
+pub fn fragment_column<'a, T: Array + 'static>(batch: &'a RecordBatch, name: &str) -> Result<&'a T> {
+    batch.column_by_name(name)
+        .and_then(|column| column.as_any().downcast_ref::<T>())
+        .ok_or_else(|| anyhow::anyhow!("Fragment batch has no {} column of the expected type", name))
+}
+
+/// Restricts which fragments a similarity search may return
+#[derive(Debug, Clone, Default)]
+pub struct SearchFilter {
+    /// Only return fragments of documents classified into one of these categories
+    pub categories: Vec<String>,
+    /// Compare against vectors left stale by a model upgrade (the previous model's space)
+    /// instead of current ones
+    pub stale: bool,
+    /// Only return fragments whose section path contains this heading (case-insensitive)
+    pub section: Option<String>,
+    /// Only return fragments of documents in one of these collections
+    pub collections: Vec<String>,
+    /// Only return fragments of the document with this id
+    pub document: Option<String>,
+    /// Only return fragments mentioning this entity, matched as by `entities::matches`
+    pub entity: Option<String>,
+    /// Only return fragments of documents with at least one of these tags
+    pub tags: Vec<String>,
+    /// Only return fragments of documents with one of these file types (lower-case
+    /// extensions without the dot)
+    pub file_types: Vec<String>,
+    /// Only return fragments of documents whose path matches this glob, as by `path_matches`
+    pub path: Option<String>,
+    /// Only return fragments of documents whose source file was last modified at or after
+    /// this time, in microseconds since the Unix epoch
+    pub modified_after: Option<i64>,
+    /// Only return fragments of documents whose source file was last modified before this
+    /// time, in microseconds since the Unix epoch
+    pub modified_before: Option<i64>,
+    /// Only return fragments ranked after this position, to fetch the next page of a
+    /// vector or keyword search
+    pub after: Option<RankPosition>,
+}
+
+impl SearchFilter {
+    /// Take `section:"Heading"` (or `section:Heading` for a single word) out of a query,
+    /// returning the remaining query text and the section to filter on
+    pub fn parse_section(query: &str) -> (String, Option<String>) {
+        let Some(start) = query.find("section:") else {
+            return (query.to_string(), None);
+        };
+        let value = &query[start + "section:".len()..];
+        let (section, rest) = match value.strip_prefix('"') {
+            Some(quoted) => match quoted.find('"') {
+                Some(end) => (&quoted[..end], &quoted[end + 1..]),
+                None => (quoted, ""),
+            },
+            None => {
+                let end = value.find(char::is_whitespace).unwrap_or(value.len());
+                (&value[..end], &value[end..])
+            }
+        };
+        let remaining = format!("{} {}", &query[..start], rest).trim().to_string();
+        let section = section.trim();
+        (remaining, (!section.is_empty()).then(|| section.to_string()))
+    }
+    
+    /// Take `collection:`, `tag:`, `type:`, `path:`, `since:` and `until:` filters out of
+    /// `spec`, each `key:value` or `key:"quoted value"` and repeatable where the filter takes
+    /// several values, returning the filter and any text that isn't a filter
+    pub fn parse_metadata(spec: &str) -> Result<(Self, String)> {
+        let mut filter = SearchFilter::default();
+        let mut rest = Vec::new();
+        let mut remaining = spec.trim_start();
+        while !remaining.is_empty() {
+            let (token, after) = match remaining.find(char::is_whitespace) {
+                Some(end) => remaining.split_at(end),
+                None => (remaining, ""),
+            };
+            let Some((key, value)) = token.split_once(':') else {
+                rest.push(token);
+                remaining = after.trim_start();
+                continue;
+            };
+            // A quoted value runs to the closing quote, spaces included
+            let (value, after) = match remaining[key.len() + 1..].strip_prefix('"') {
+                Some(quoted) => match quoted.find('"') {
+                    Some(end) => (&quoted[..end], &quoted[end + 1..]),
+                    None => (quoted, ""),
+                },
+                None => (value, after),
+            };
+            match key.to_lowercase().as_str() {
+                "collection" => filter.collections.push(value.to_string()),
+                "tag" => filter.tags.push(value.to_string()),
+                "type" => filter.file_types.push(file_type(value)),
+                "path" => filter.path = Some(value.to_string()),
+                "since" => filter.modified_after = Some(parse_date(value, false)?),
+                "until" => filter.modified_before = Some(parse_date(value, true)?),
+                _ => rest.push(token),
+            }
+            remaining = after.trim_start();
+        }
+        Ok((filter, rest.join(" ")))
+    }
+
+    /// Parse a `--where` condition such as `file_type = "pptx" AND collection = "old"`.
+    /// Conditions are joined by `AND`: `file_type` (or `type`), `collection`, `tag`,
+    /// `category`, `path` (a glob, as `--path`) and `id` compared with `=` or with
+    /// `IN ("a", "b")`, and `modified` compared with `>=`, `>`, `<=` or `<` to a date.
+    pub fn parse_where(expression: &str) -> Result<Self> {
+        let tokens = where_tokens(expression)?;
+        let mut filter = SearchFilter::default();
+        let mut seen = HashSet::new();
+        let mut tokens = tokens.into_iter().peekable();
+        loop {
+            let Some(WhereToken::Word(field)) = tokens.next() else {
+                anyhow::bail!("Expected a field name in --where '{}'", expression);
+            };
+            let field = field.to_lowercase();
+            let op = match tokens.next() {
+                Some(WhereToken::Op(op)) => op,
+                Some(WhereToken::Word(word)) if word.eq_ignore_ascii_case("in") => "in".to_string(),
+                _ => anyhow::bail!("Expected =, IN or a comparison after {} in --where", field),
+            };
+            let values = if op == "in" {
+                if tokens.next() != Some(WhereToken::Open) {
+                    anyhow::bail!("Expected ( after {} IN in --where", field);
+                }
+                let mut values = Vec::new();
+                loop {
+                    match tokens.next() {
+                        Some(WhereToken::Word(value)) | Some(WhereToken::Text(value)) => values.push(value),
+                        _ => anyhow::bail!("Expected a value in {} IN (...)", field),
+                    }
+                    match tokens.next() {
+                        Some(WhereToken::Comma) => continue,
+                        Some(WhereToken::Close) => break,
+                        _ => anyhow::bail!("Expected , or ) in {} IN (...)", field),
+                    }
+                }
+                values
+            } else {
+                match tokens.next() {
+                    Some(WhereToken::Word(value)) | Some(WhereToken::Text(value)) => vec![value],
+                    _ => anyhow::bail!("Expected a value after {} {} in --where", field, op),
+                }
+            };
+
+            // Values of one field already match any of them, so AND on the same field is refused
+            if !seen.insert((field.clone(), op.clone())) {
+                anyhow::bail!("{} appears twice in --where; list its values with {} IN (...)", field, field);
+            }
+            match (field.as_str(), op.as_str()) {
+                ("file_type" | "type", "=" | "in") => filter.file_types = values.iter().map(|value| file_type(value)).collect(),
+                ("collection", "=" | "in") => filter.collections = values,
+                ("tag", "=" | "in") => filter.tags = values,
+                ("category", "=" | "in") => filter.categories = values.iter().map(|value| value.to_lowercase()).collect(),
+                ("path", "=") => filter.path = values.into_iter().next(),
+                ("id", "=") => filter.document = values.into_iter().next(),
+                ("modified", ">=") => filter.modified_after = Some(parse_date(&values[0], false)?),
+                ("modified", ">") => filter.modified_after = Some(parse_date(&values[0], true)?),
+                ("modified", "<") => filter.modified_before = Some(parse_date(&values[0], false)?),
+                ("modified", "<=") => filter.modified_before = Some(parse_date(&values[0], true)?),
+                ("file_type" | "type" | "collection" | "tag" | "category" | "path" | "id" | "modified", _) => {
+                    anyhow::bail!("{} can't be compared with {} in --where", field, op.to_uppercase())
+                }
+                _ => anyhow::bail!("Unknown field {} in --where; use file_type, collection, tag, category, path, id or modified", field),
+            }
+
+            match tokens.next() {
+                None => return Ok(filter),
+                Some(WhereToken::Word(word)) if word.eq_ignore_ascii_case("and") => {}
+                Some(WhereToken::Word(word)) if word.eq_ignore_ascii_case("or") => {
+                    anyhow::bail!("--where only joins conditions with AND; list alternatives with IN (...)")
+                }
+                _ => anyhow::bail!("Expected AND between conditions in --where '{}'", expression),
+            }
+        }
+    }
+
+    /// Whether a document passes the document-level filters: categories, collections,
+    /// tags, file types, path, dates and document id
+    pub fn matches_attributes(&self, document: &DocumentSummary, attributes: &DocumentAttributes) -> bool {
+        let category = attributes.category.as_ref().map(|(category, _)| category.as_str());
+        (self.categories.is_empty() || category.is_some_and(|category| self.categories.iter().any(|wanted| wanted == category)))
+            && (self.collections.is_empty() || attributes.collection.as_ref().is_some_and(|collection| self.collections.contains(collection)))
+            && self.document.as_ref().is_none_or(|id| *id == document.id)
+            && self.matches_document(&attributes.tags, &document.file_path, document.modified)
+    }
+
+    /// Whether a document's tags, path and source modification time pass the tag, file
+    /// type, path and date filters, for backends that filter outside SQL
+    pub fn matches_document(&self, tags: &[String], file_path: &str, modified: Option<i64>) -> bool {
+        let extension = Path::new(file_path).extension()
+            .map(|extension| extension.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+        (self.tags.is_empty() || tags.iter().any(|tag| self.tags.contains(tag)))
+            && (self.file_types.is_empty() || self.file_types.contains(&extension))
+            && self.path.as_ref().is_none_or(|pattern| path_matches(pattern, file_path))
+            && self.modified_after.is_none_or(|after| modified.is_some_and(|modified| modified >= after))
+            && self.modified_before.is_none_or(|before| modified.is_some_and(|modified| modified < before))
+    }
+
+    /// Human-readable description of each active filter
+    pub fn describe(&self) -> Vec<String> {
+        let mut filters = Vec::new();
+        if !self.categories.is_empty() {
+            filters.push(format!("category in [{}]", self.categories.join(", ")));
+        }
+        if self.stale {
+            filters.push("vectors from the previous embedding model".to_string());
+        }
+        if let Some(section) = &self.section {
+            filters.push(format!("section contains \"{}\"", section));
+        }
+        if !self.collections.is_empty() {
+            filters.push(format!("collection in [{}]", self.collections.join(", ")));
+        }
+        if let Some(document) = &self.document {
+            filters.push(format!("document {}", document));
+        }
+        if let Some(entity) = &self.entity {
+            filters.push(format!("mentions \"{}\"", entity));
+        }
+        if !self.tags.is_empty() {
+            filters.push(format!("tagged [{}]", self.tags.join(", ")));
+        }
+        if !self.file_types.is_empty() {
+            filters.push(format!("file type in [{}]", self.file_types.join(", ")));
+        }
+        if let Some(path) = &self.path {
+            filters.push(format!("path matches {}", path));
+        }
+        let date = |micros: i64| chrono::DateTime::from_timestamp_micros(micros)
+            .map_or_else(|| micros.to_string(), |date| date.format("%Y-%m-%d").to_string());
+        if let Some(after) = self.modified_after {
+            filters.push(format!("modified on or after {}", date(after)));
+        }
+        if let Some(before) = self.modified_before {
+            filters.push(format!("modified before {}", date(before)));
+        }
+        if let Some(after) = &self.after {
+            filters.push(format!("ranked after {} fragment {} ({:.4})", after.file_path, after.order, after.score));
+        }
+        filters
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum WhereToken {
+    Word(String),
+    /// A quoted value
+    Text(String),
+    Op(String),
+    Open,
+    Close,
+    Comma,
+}
+
+fn where_tokens(expression: &str) -> Result<Vec<WhereToken>> {
+    let mut tokens = Vec::new();
+    let mut chars = expression.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        match c {
+            c if c.is_whitespace() => { chars.next(); }
+            '(' => { chars.next(); tokens.push(WhereToken::Open); }
+            ')' => { chars.next(); tokens.push(WhereToken::Close); }
+            ',' => { chars.next(); tokens.push(WhereToken::Comma); }
+            '"' | '\'' => {
+                chars.next();
+                let text: String = chars.by_ref().take_while(|&next| next != c).collect();
+                tokens.push(WhereToken::Text(text));
+            }
+            '=' | '<' | '>' => {
+                chars.next();
+                let mut op = c.to_string();
+                if c != '=' && chars.peek() == Some(&'=') {
+                    chars.next();
+                    op.push('=');
+                }
+                tokens.push(WhereToken::Op(op));
+            }
+            c if c.is_alphanumeric() || matches!(c, '_' | '-' | '.' | '*' | '/') => {
+                let mut word = String::new();
+                while let Some(&next) = chars.peek() {
+                    if next.is_whitespace() || matches!(next, '(' | ')' | ',' | '=' | '<' | '>' | '"' | '\'') {
+                        break;
+                    }
+                    word.push(next);
+                    chars.next();
+                }
+                tokens.push(WhereToken::Word(word));
+            }
+            _ => anyhow::bail!("Unexpected '{}' in --where '{}'", c, expression),
+        }
+    }
+    if tokens.is_empty() {
+        anyhow::bail!("--where needs at least one condition");
+    }
+    Ok(tokens)
+}
+
+/// What `bulk_update` does to each document a filter selects
+#[derive(Debug, Clone, PartialEq)]
+pub enum BulkAction {
+    /// Delete the documents with their fragments and annotations
+    Delete,
+    /// Add and remove tags, and move the documents to another collection when `collection`
+    /// is given (`Some(None)` takes them out of their collection)
+    Reorganize {
+        add_tags: Vec<String>,
+        remove_tags: Vec<String>,
+        collection: Option<Option<String>>,
+    },
+}
+
+impl BulkAction {
+    /// A document's collection and tags after the action, or `None` for a deletion
+    pub fn apply(&self, collection: Option<String>, tags: &[String]) -> Option<(Option<String>, Vec<String>)> {
+        let BulkAction::Reorganize { add_tags, remove_tags, collection: moved } = self else {
+            return None;
+        };
+        let mut tags: Vec<String> = tags.iter().filter(|tag| !remove_tags.contains(tag)).cloned().collect();
+        for tag in add_tags {
+            if !tags.contains(tag) {
+                tags.push(tag.clone());
+            }
+        }
+        Some((moved.clone().unwrap_or(collection), tags))
+    }
+}
+
+/// Documents passing the document-level parts of `filter`, as `bulk_update` selects them
+pub async fn matching_documents(storage: &mut dyn Storage, filter: &SearchFilter) -> Result<Vec<DocumentSummary>> {
+    let mut matching = Vec::new();
+    for document in storage.list_documents().await? {
+        if let Some(attributes) = storage.get_document_attributes(&document.id).await? {
+            if filter.matches_attributes(&document, &attributes) {
+                matching.push(document);
+            }
+        }
+    }
+    Ok(matching)
+}
+
+/// A file type as filtered on: the extension in lower case, without a leading dot
+pub fn file_type(value: &str) -> String {
+    value.trim_start_matches('.').to_lowercase()
+}
+
+/// Microseconds since the Unix epoch at the start of a `YYYY-MM-DD` date (UTC), or at the end
+/// of it when `end_of_day`, so an `until` date includes that whole day. RFC 3339 times are
+/// taken as given.
+pub fn parse_date(value: &str, end_of_day: bool) -> Result<i64> {
+    if let Ok(time) = chrono::DateTime::parse_from_rfc3339(value) {
+        return Ok(time.timestamp_micros());
+    }
+    let date = chrono::NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .with_context(|| format!("Invalid date '{}': expected YYYY-MM-DD", value))?;
+    let date = if end_of_day { date.succ_opt().unwrap_or(date) } else { date };
+    Ok(date.and_hms_opt(0, 0, 0).unwrap_or_default().and_utc().timestamp_micros())
+}
+
+/// A document path filter as the glob to match and whether it applies to the whole path
+/// rather than the file name. A pattern without `/` is matched against the file name; one
+/// with `/` against the end of the (absolute) stored path, unless it starts with `/` or `*`.
+pub fn path_glob(pattern: &str) -> (String, bool) {
+    if !pattern.contains('/') {
+        (pattern.to_string(), false)
+    } else if pattern.starts_with(['/', '*']) {
+        (pattern.to_string(), true)
+    } else {
+        (format!("*/{}", pattern), true)
+    }
+}
+
+/// Whether a document path matches a filter glob (see `path_glob`). `*` matches any run of
+/// characters, slashes included, `?` one character and `[...]` one of a set, as in SQL `GLOB`.
+pub fn path_matches(pattern: &str, file_path: &str) -> bool {
+    let (glob, whole_path) = path_glob(pattern);
+    let target = if whole_path {
+        file_path
+    } else {
+        file_path.rsplit(['/', '\\']).next().unwrap_or(file_path)
+    };
+    globset::Glob::new(&glob)
+        .map(|glob| glob.compile_matcher().is_match(target))
+        .unwrap_or(false)
+}
+
+/// A fragment's place in a ranking: best score first, ties broken by document path and
+/// fragment order rather than the random ids, so equal scores come back in the same order
+/// in every run and a paged search can resume after the last result it returned
+#[derive(Debug, Clone, PartialEq)]
+pub struct RankPosition {
+    pub score: f64,
+    pub file_path: String,
+    pub order: i32,
+}
+
+impl RankPosition {
+    /// Whether `fragment` ranks after this position
+    pub fn precedes(&self, fragment: &FragmentMatch) -> bool {
+        rank_order(self.score, &self.file_path, self.order, fragment) == std::cmp::Ordering::Less
+    }
+}
+
+/// Compare a ranking position with a fragment's, `Less` when the position ranks first
+fn rank_order(score: f64, file_path: &str, order: i32, fragment: &FragmentMatch) -> std::cmp::Ordering {
+    fragment.score.total_cmp(&score)
+        .then_with(|| file_path.cmp(&fragment.source.file_path))
+        .then_with(|| order.cmp(&fragment.source.order))
+}
+
+/// Sort matches into ranking order, best first
+pub fn sort_ranked(matches: &mut [FragmentMatch]) {
+    matches.sort_by(|a, b| rank_order(a.score, &a.source.file_path, a.source.order, b));
+}
+
+/// Where a fragment came from, for citing it
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct FragmentSource {
+    /// Id of the document, for linking to its original
+    #[serde(default)]
+    pub document_id: String,
+    pub filename: String,
+    pub file_path: String,
+    /// Title derived from the document's text when it was indexed
+    #[serde(default)]
+    pub title: Option<String>,
+    /// Position of the fragment within its document, from 0
+    pub order: i32,
+    pub section: Option<String>,
+    /// PDF page the fragment's text is from
+    pub page: Option<u32>,
+    /// Slide or worksheet the fragment's text is from
+    #[serde(default)]
+    pub location: Option<Location>,
+    /// Code block, table or list the fragment holds whole
+    pub structure: Option<Structure>,
+    /// Modification time of the source file when it was indexed, in microseconds since the
+    /// Unix epoch
+    #[serde(default)]
+    pub modified: Option<i64>,
+    /// When the document was indexed, in microseconds since the Unix epoch
+    #[serde(default)]
+    pub indexed: Option<i64>,
+    /// Reviewers' annotations on the fragment and its document
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub annotations: Vec<AnnotationNote>,
+}
+
+impl FragmentSource {
+    /// Short human-readable citation, e.g.
+    /// `report.pdf, p. 12, Results > Costs, fragment 31, modified 2019-04-02` or
+    /// `Quarterly Review (deck.pptx), slide 12, fragment 40`
+    pub fn citation(&self) -> String {
+        let mut parts = vec![match &self.title {
+            Some(title) => format!("{} ({})", title, self.filename),
+            None => self.filename.clone(),
+        }];
+        if let Some(page) = self.page {
+            parts.push(format!("p. {}", page));
+        }
+        if let Some(location) = &self.location {
+            parts.push(location.label());
+        }
+        if let Some(section) = &self.section {
+            parts.push(section.clone());
+        }
+        parts.push(format!("fragment {}", self.order));
+        if let Some(freshness) = self.freshness() {
+            parts.push(freshness);
+        }
+        let mut labels: Vec<&str> = self.annotations.iter().filter_map(|a| a.label.map(|label| label.name())).collect();
+        labels.sort();
+        labels.dedup();
+        if !labels.is_empty() {
+            parts.push(format!("marked {}", labels.join(", ")));
+        }
+        parts.join(", ")
+    }
+
+    /// URL fragment opening the original at the fragment with `content`: `page=12` for a
+    /// PDF page, read by browsers' PDF viewers, or a text fragment (`:~:text=start,end`)
+    /// for HTML, which browsers scroll to and highlight. `None` for other types, and for
+    /// PDF fragments whose page wasn't recorded.
+    pub fn anchor(&self, content: &str) -> Option<String> {
+        let extension = Path::new(&self.filename).extension()?.to_str()?.to_lowercase();
+        match extension.as_str() {
+            "pdf" => self.page.map(|page| format!("page={}", page)),
+            "html" | "htm" => {
+                let words: Vec<&str> = content.split_whitespace().collect();
+                if words.is_empty() {
+                    return None;
+                }
+                // Long fragments are matched from their first words to their last, which
+                // keeps the link short and survives small differences in between
+                let text = if words.len() > 2 * ANCHOR_WORDS {
+                    format!(
+                        "{},{}",
+                        encode_text_directive(&words[..ANCHOR_WORDS].join(" ")),
+                        encode_text_directive(&words[words.len() - ANCHOR_WORDS..].join(" ")),
+                    )
+                } else {
+                    encode_text_directive(&words.join(" "))
+                };
+                Some(format!(":~:text={}", text))
+            }
+            _ => None,
+        }
+    }
+
+    /// How current the source is, from the file's modification time or else the time it
+    /// was indexed
+    pub fn dated(&self) -> Option<chrono::DateTime<chrono::Utc>> {
+        self.modified.or(self.indexed).and_then(chrono::DateTime::from_timestamp_micros)
+    }
+
+    /// `modified 2019-04-02`, or `indexed 2019-04-02` when the file's modification time is
+    /// unknown
+    pub fn freshness(&self) -> Option<String> {
+        let label = if self.modified.is_some() { "modified" } else { "indexed" };
+        self.dated().map(|date| format!("{} {}", label, date.format("%Y-%m-%d")))
+    }
+}
+
+/// Words quoted from each end of a long fragment in a text fragment link
+const ANCHOR_WORDS: usize = 5;
+
+/// Percent-encode text for a `:~:text=` directive. Besides what URLs reserve, `-`, `,` and
+/// `&` are encoded, since the directive itself uses them as delimiters.
+fn encode_text_directive(text: &str) -> String {
+    let mut encoded = String::with_capacity(text.len());
+    for byte in text.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'.' | b'_' | b'~' => encoded.push(byte as char),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+/// A fragment returned by a search, with its score and source
+#[derive(Debug, Clone)]
+pub struct FragmentMatch {
+    pub fragment_id: String,
+    pub content: String,
+    pub score: f64,
+    pub source: FragmentSource,
+}
+
+/// Meta key recording the model a model upgrade is moving away from
+pub const PREVIOUS_MODEL_KEY: &str = "previous_embedding_model";
+
+/// Meta key recording the length of the current model's vectors
+pub const DIMENSION_KEY: &str = "embedding_dimension";
+
+/// Meta key recording the provider the current model's vectors were generated with
+pub const PROVIDER_KEY: &str = "embedding_provider";
+
+/// Meta key recording the dataset version, bumped each time a write changes what searches
+/// can return
+pub const DATA_VERSION_KEY: &str = "data_version";
+
+/// Meta key recording every embedding model used on the database, as a JSON list of
+/// `ModelEpoch`s oldest first
+pub const MODEL_LINEAGE_KEY: &str = "model_lineage";
+
+/// Meta key recording that document and fragment ids are derived from content
+pub const DETERMINISTIC_IDS_KEY: &str = "deterministic_ids";
+
+/// Meta key recording the portable-brains version that last indexed with `--deterministic`
+pub const INDEXER_VERSION_KEY: &str = "indexer_version";
+
+/// Meta key recording the text prepended to fragments before they are embedded
+pub const DOCUMENT_PREFIX_KEY: &str = "document_prefix";
+
+/// Meta key recording the text prepended to queries before they are embedded
+pub const QUERY_PREFIX_KEY: &str = "query_prefix";
+
+/// Meta key recording that fragments are embedded by late chunking their section
+pub const LATE_CHUNKING_KEY: &str = "late_chunking";
+
+/// Meta key recording how the originals of new documents are stored
+pub const STORE_ORIGINALS_KEY: &str = "store_originals";
+
+/// Meta key recording an embeddings-only database, which drops fragment text once it is
+/// embedded and keeps no originals or extracted text
+pub const EMBEDDINGS_ONLY_KEY: &str = "embeddings_only";
+
+/// Meta key recording how searches compare query vectors with fragment vectors
+pub const SIMILARITY_METRIC_KEY: &str = "similarity_metric";
+
+/// Encoding recorded next to an original compressed with zstd
+const ZSTD_ENCODING: &str = "zstd";
+
+/// Encoding recorded for a document whose original wasn't kept
+const NOT_STORED_ENCODING: &str = "none";
+
+/// How the original bytes of newly stored documents are kept
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum OriginalsMode {
+    /// The file's bytes as read
+    #[default]
+    Full,
+    /// Compressed with zstd; read back decompressed
+    Compressed,
+    /// Only the path and content hash; the original can't be read back
+    None,
+}
+
+impl OriginalsMode {
+    pub fn name(self) -> &'static str {
+        match self {
+            OriginalsMode::Full => "full",
+            OriginalsMode::Compressed => "compressed",
+            OriginalsMode::None => "none",
+        }
+    }
+
+    /// The mode recorded under `STORE_ORIGINALS_KEY`; databases without one keep full originals
+    pub fn from_meta(value: Option<&str>) -> Self {
+        match value {
+            Some("compressed") => OriginalsMode::Compressed,
+            Some("none") => OriginalsMode::None,
+            _ => OriginalsMode::Full,
+        }
+    }
+
+    /// The blob to store for `data`, and the encoding to record next to it (`None` for the
+    /// bytes as they are)
+    pub fn encode(self, data: &[u8]) -> Result<(Vec<u8>, Option<&'static str>)> {
+        match self {
+            OriginalsMode::Full => Ok((data.to_vec(), None)),
+            OriginalsMode::Compressed => Ok((
+                zstd::encode_all(data, 0).context("Failed to compress original")?,
+                Some(ZSTD_ENCODING),
+            )),
+            OriginalsMode::None => Ok((Vec::new(), Some(NOT_STORED_ENCODING))),
+        }
+    }
+}
+
+/// How `search_similar` compares a query's vector with fragment vectors. Every metric
+/// scores closer vectors higher.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum SimilarityMetric {
+    /// Cosine of the angle between them, ignoring their lengths
+    #[default]
+    Cosine,
+    /// Inner product, for models trained for maximum inner product search
+    Dot,
+    /// Euclidean distance `d`, scored as `1 / (1 + d)`
+    Euclidean,
+}
+
+impl SimilarityMetric {
+    pub fn name(self) -> &'static str {
+        match self {
+            SimilarityMetric::Cosine => "cosine",
+            SimilarityMetric::Dot => "dot",
+            SimilarityMetric::Euclidean => "euclidean",
+        }
+    }
+
+    /// The metric recorded under `SIMILARITY_METRIC_KEY`; databases without one use cosine
+    pub fn from_meta(value: Option<&str>) -> Self {
+        match value {
+            Some("dot") => SimilarityMetric::Dot,
+            Some("euclidean") => SimilarityMetric::Euclidean,
+            _ => SimilarityMetric::Cosine,
+        }
+    }
+
+    /// Score of a stored `vector`, or one read back as `f64`, against `query`
+    pub fn score<T: Copy + Into<f64>>(self, query: &[f64], vector: &[T]) -> f64 {
+        let vector = vector.iter().map(|&y| y.into());
+        match self {
+            SimilarityMetric::Cosine => {
+                let dot: f64 = query.iter().zip(vector.clone()).map(|(x, y)| x * y).sum();
+                let norm_a = query.iter().map(|x| x * x).sum::<f64>().sqrt();
+                let norm_b = vector.map(|y| y * y).sum::<f64>().sqrt();
+                if norm_a == 0.0 || norm_b == 0.0 { 0.0 } else { dot / (norm_a * norm_b) }
+            }
+            SimilarityMetric::Dot => query.iter().zip(vector).map(|(x, y)| x * y).sum(),
+            SimilarityMetric::Euclidean => {
+                Self::from_distance(query.iter().zip(vector).map(|(x, y)| (x - y) * (x - y)).sum::<f64>().sqrt())
+            }
+        }
+    }
+
+    /// Score of a Euclidean `distance`, so nearer is higher like the other metrics
+    pub fn from_distance(distance: f64) -> f64 {
+        1.0 / (1.0 + distance)
+    }
+}
+
+/// The original bytes of a blob stored with `encoding` by `OriginalsMode::encode`, or
+/// `None` when the original wasn't kept
+pub fn decode_original(encoding: Option<&str>, blob: Vec<u8>) -> Result<Option<Vec<u8>>> {
+    match encoding {
+        None => Ok(Some(blob)),
+        Some(ZSTD_ENCODING) => Ok(Some(zstd::decode_all(blob.as_slice()).context("Failed to decompress original")?)),
+        Some(NOT_STORED_ENCODING) => Ok(None),
+        Some(other) => anyhow::bail!("Original stored with unknown encoding '{}'", other),
+    }
+}
+
+/// Hex SHA-256 of a document's original bytes, recorded to detect changed files
+pub fn content_hash(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+/// Hex SHA-256 of a fragment's text with runs of whitespace collapsed, so the same chunk
+/// cut from different documents, such as a header repeated on every page, hashes alike
+pub fn chunk_hash(text: &str) -> String {
+    content_hash(text.split_whitespace().collect::<Vec<_>>().join(" ").as_bytes())
+}
+
+/// A file's modification time in microseconds since the Unix epoch; `None` when the file
+/// is unreadable or the platform doesn't record one
+pub fn modified_micros(file_path: &Path) -> Option<i64> {
+    let modified = std::fs::metadata(file_path).ok()?.modified().ok()?;
+    let micros = modified.duration_since(UNIX_EPOCH).ok()?.as_micros();
+    i64::try_from(micros).ok()
+}
+
+/// Namespace of content-derived ids, fixed so every build derives the same ones
+const ID_NAMESPACE: Uuid = Uuid::from_u128(0x5c1f_0e2a_7d4b_4f61_9a83_2b6e_d04c_71f9);
+
+/// How new documents and fragments are given ids
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IdScheme {
+    /// Random UUIDv4s
+    #[default]
+    Random,
+    /// UUIDv5s of the content, so indexing the same files again gives the same ids
+    Content,
+}
+
+impl IdScheme {
+    /// The scheme recorded under `DETERMINISTIC_IDS_KEY`
+    pub fn from_meta(value: Option<&str>) -> Self {
+        if value == Some("true") { IdScheme::Content } else { IdScheme::Random }
+    }
+
+    /// Derived from the file name rather than the full path, so the same files indexed from
+    /// another directory or machine get the same id
+    pub fn document_id(self, filename: &str, data: &[u8]) -> String {
+        match self {
+            IdScheme::Random => Uuid::new_v4().to_string(),
+            IdScheme::Content => Uuid::new_v5(&Uuid::new_v5(&ID_NAMESPACE, data), filename.as_bytes()).to_string(),
+        }
+    }
+
+    /// The content-derived id of the fragment spanning `span` of the extracted text of a
+    /// document whose bytes hash to `document_hash`. It depends on neither the file's name nor
+    /// the database, so a citation saved elsewhere keeps pointing at the same text.
+    pub fn span_fragment_id(document_hash: &str, span: (usize, usize)) -> String {
+        let name = format!("{}\0{}\0{}", document_hash, span.0, span.1);
+        Uuid::new_v5(&ID_NAMESPACE, name.as_bytes()).to_string()
+    }
+
+    pub fn fragment_id(self, document_id: &str, order: i32, content: &str) -> String {
+        match self {
+            IdScheme::Random => Uuid::new_v4().to_string(),
+            IdScheme::Content => {
+                let name = format!("{}\0{}\0{}", document_id, order, content);
+                Uuid::new_v5(&ID_NAMESPACE, name.as_bytes()).to_string()
+            }
+        }
+    }
+}
+
+/// The embedding prefixes recorded in the database, or `None` for one indexed before
+/// prefixes were recorded
+pub async fn recorded_prefixes(storage: &mut dyn Storage) -> Result<Option<EmbeddingPrefixes>> {
+    let document = storage.get_meta_value(DOCUMENT_PREFIX_KEY).await?;
+    let query = storage.get_meta_value(QUERY_PREFIX_KEY).await?;
+    if document.is_none() && query.is_none() {
+        return Ok(None);
+    }
+    Ok(Some(EmbeddingPrefixes {
+        document: document.unwrap_or_default(),
+        query: query.unwrap_or_default(),
+    }))
+}
+
+/// The prefixes fragments and queries are embedded with; databases indexed before they
+/// were recorded used none
+pub async fn embedding_prefixes(storage: &mut dyn Storage) -> Result<EmbeddingPrefixes> {
+    Ok(recorded_prefixes(storage).await?.unwrap_or_default())
+}
+
+/// Record the prefixes to embed with. They can only change while the database has no
+/// vectors from the current model, since existing vectors wouldn't match them.
+pub async fn set_embedding_prefixes(storage: &mut dyn Storage, prefixes: &EmbeddingPrefixes) -> Result<()> {
+    let current = embedding_prefixes(storage).await?;
+    if *prefixes != current && embedding_dimension(storage).await?.is_some() {
+        anyhow::bail!(
+            "Database was embedded with document prefix {:?} and query prefix {:?}; \
+             prefixes can't change once fragments have vectors",
+            current.document, current.query
+        );
+    }
+    storage.set_meta_value(DOCUMENT_PREFIX_KEY, &prefixes.document).await?;
+    storage.set_meta_value(QUERY_PREFIX_KEY, &prefixes.query).await
+}
+
+/// Length of the vectors stored under the current model, if any have been stored
+pub async fn embedding_dimension(storage: &mut dyn Storage) -> Result<Option<usize>> {
+    if let Some(dimension) = parse_dimension(storage.get_meta_value(DIMENSION_KEY).await?.as_deref()) {
+        return Ok(Some(dimension));
+    }
+    // Databases embedded before the dimension was recorded
+    Ok(storage.get_fragment_embeddings(1).await?.first().map(|(_, _, embedding)| embedding.len()))
+}
+
+/// Parse a recorded dimension, treating the empty value an upgrade leaves as unrecorded
+pub fn parse_dimension(value: Option<&str>) -> Option<usize> {
+    value.and_then(|value| value.parse().ok()).filter(|&dimension| dimension > 0)
+}
+
+/// Error for vectors of `found` dimensions meant for a database storing `stored`-dimensional
+/// ones, naming the model and provider that produced the stored vectors
+pub fn dimension_mismatch(meta: &MetaInfo, model: &str, stored: usize, found: usize) -> anyhow::Error {
+    let provider = meta.embedding_provider.as_deref().filter(|provider| !provider.is_empty()).unwrap_or("unknown");
+    anyhow::anyhow!(
+        "Embedding dimension mismatch: the database stores {}-dimensional vectors from {} (provider: {}), \
+         but {} produces {}-dimensional ones. Use the model and provider the database was indexed with, \
+         or pass a new --model to `portable-brains embed` to re-embed everything",
+        stored, meta.embedding_model, provider, model, found
+    )
+}
+
+/// Check that vectors of `dimension` from `model` can be compared with the current vectors
+/// stored, before searching with or storing them
+pub async fn check_dimension(storage: &mut dyn Storage, model: &str, dimension: usize) -> Result<()> {
+    match embedding_dimension(storage).await? {
+        Some(stored) if stored != dimension => {
+            let meta = storage.get_meta_info().await?;
+            Err(dimension_mismatch(&meta, model, stored, dimension))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// The vectors a query embedded with a given model can be compared against
+#[derive(Debug, Clone)]
+pub struct QuerySpace {
+    /// Search the stale vectors of the previous model rather than current ones
+    pub stale: bool,
+    /// Explains which fragments a search leaves out while an upgrade is in progress
+    pub warning: Option<String>,
+}
+
+/// Work out which vectors a query embedded with `model` can be compared against.
+///
+/// After a model upgrade, fragments not yet re-embedded still carry vectors from the
+/// previous model; they can be searched with that model until they are re-embedded.
+pub async fn query_space(storage: &mut dyn Storage, model: &str) -> Result<QuerySpace> {
+    let current = storage.get_meta_info().await?.embedding_model;
+    let stale = storage.count_stale_fragments().await?;
+    let previous = storage.get_meta_value(PREVIOUS_MODEL_KEY).await?;
+
+    if model == current {
+        let warning = (stale > 0).then(|| format!(
+            "{} fragments still have vectors from {} and are left out until re-embedded; \
+             run `portable-brains embed` to finish the upgrade",
+            stale, previous.as_deref().unwrap_or("the previous model")
+        ));
+        return Ok(QuerySpace { stale: false, warning });
+    }
+
+    if previous.as_deref() == Some(model) && stale > 0 {
+        return Ok(QuerySpace {
+            stale: true,
+            warning: Some(format!(
+                "Searching the {} fragments not yet re-embedded with {}; fragments already re-embedded are left out",
+                stale, current
+            )),
+        });
+    }
+
+    anyhow::bail!("Embedding model mismatch: indexed with {}, querying with {}", current, model)
+}
+
+/// One embedding model's time as the database's model, from the model lineage
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ModelEpoch {
+    pub model: String,
+    pub dimension: Option<usize>,
+    pub provider: Option<String>,
+    /// When the model stored its first and latest vectors; unknown for vectors stored before
+    /// the lineage was recorded
+    pub first_embedded: Option<chrono::DateTime<chrono::Utc>>,
+    pub last_embedded: Option<chrono::DateTime<chrono::Utc>>,
+    /// Vectors stored while the lineage was recorded, counting re-embedded fragments again
+    pub embedded: u64,
+    /// When an upgrade replaced the model; `None` for the current model
+    pub retired: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+impl ModelEpoch {
+    fn new(model: &str) -> Self {
+        Self {
+            model: model.to_string(),
+            dimension: None,
+            provider: None,
+            first_embedded: None,
+            last_embedded: None,
+            embedded: 0,
+            retired: None,
+        }
+    }
+}
+
+/// Every embedding model used on the database, oldest first, ending with the current one.
+/// Databases from before the lineage was recorded get one reconstructed from the current
+/// and previous model.
+pub async fn model_lineage(storage: &mut dyn Storage) -> Result<Vec<ModelEpoch>> {
+    if let Some(lineage) = storage.get_meta_value(MODEL_LINEAGE_KEY).await? {
+        return serde_json::from_str(&lineage).context("Failed to parse the recorded model lineage");
+    }
+
+    let meta = storage.get_meta_info().await?;
+    let mut lineage = Vec::new();
+    if let Some(previous) = storage.get_meta_value(PREVIOUS_MODEL_KEY).await? {
+        lineage.push(ModelEpoch::new(&previous));
+    }
+    if !meta.embedding_model.is_empty() {
+        lineage.push(ModelEpoch {
+            dimension: meta.embedding_dimension,
+            provider: meta.embedding_provider,
+            ..ModelEpoch::new(&meta.embedding_model)
+        });
+    }
+    Ok(lineage)
+}
+
+async fn save_model_lineage(storage: &mut dyn Storage, lineage: &[ModelEpoch]) -> Result<()> {
+    let lineage = serde_json::to_string(lineage).context("Failed to serialize the model lineage")?;
+    storage.set_meta_value(MODEL_LINEAGE_KEY, &lineage).await
+}
+
+/// Record `count` vectors just stored by the current model in the lineage
+async fn record_embedded(storage: &mut dyn Storage, dimension: usize, provider: &str, count: usize) -> Result<()> {
+    let model = storage.get_meta_info().await?.embedding_model;
+    let mut lineage = model_lineage(storage).await?;
+    if lineage.last().is_none_or(|epoch| epoch.model != model) {
+        lineage.push(ModelEpoch::new(&model));
+    }
+
+    let now = chrono::Utc::now();
+    let epoch = lineage.last_mut().expect("the current model's epoch was just ensured");
+    epoch.dimension = Some(dimension);
+    epoch.provider = Some(provider.to_string());
+    epoch.first_embedded.get_or_insert(now);
+    epoch.last_embedded = Some(now);
+    epoch.embedded += count as u64;
+    save_model_lineage(storage, &lineage).await
+}
+
+/// Switch the recorded embedding model, marking every existing vector stale so the embed
+/// phase replaces them. Returns the number of vectors marked stale.
+pub async fn upgrade_embedding_model(storage: &mut dyn Storage, model: &str) -> Result<i32> {
+    let previous = storage.get_meta_info().await?.embedding_model;
+    if embeddings_only(storage).await? {
+        anyhow::bail!(
+            "The database is embeddings-only, so it keeps no text to embed with {}; index the documents into a new database instead",
+            model
+        );
+    }
+
+    // The lineage keeps the model being replaced and when, so mixed states can be explained
+    let mut lineage = model_lineage(storage).await?;
+    if lineage.last().is_none_or(|epoch| epoch.model != previous) {
+        lineage.push(ModelEpoch::new(&previous));
+    }
+    if let Some(epoch) = lineage.last_mut() {
+        epoch.retired = Some(chrono::Utc::now());
+    }
+    lineage.push(ModelEpoch::new(model));
+    save_model_lineage(storage, &lineage).await?;
+
+    storage.set_meta_value(PREVIOUS_MODEL_KEY, &previous).await?;
+    storage.set_meta_value("embedding_model", model).await?;
+    // The new model's first batch records its own dimension and provider
+    storage.set_meta_value(DIMENSION_KEY, "").await?;
+    storage.set_meta_value(PROVIDER_KEY, "").await?;
+    for document_id in storage.list_index_state(IndexState::Embedded).await? {
+        storage.set_index_state(&document_id, IndexState::Chunked).await?;
+    }
+    storage.mark_embeddings_stale().await
+}
+
+/// Whether the database embeds fragments by late chunking their sections
+pub async fn late_chunking_enabled(storage: &mut dyn Storage) -> Result<bool> {
+    Ok(storage.get_meta_value(LATE_CHUNKING_KEY).await?.as_deref() == Some("true"))
+}
+
+/// Whether the database keeps only the vectors of fragments, not their text
+pub async fn embeddings_only(storage: &mut dyn Storage) -> Result<bool> {
+    Ok(storage.get_meta_value(EMBEDDINGS_ONLY_KEY).await?.as_deref() == Some("true"))
+}
+
+/// The metric searches of the database compare vectors by
+pub async fn similarity_metric(storage: &mut dyn Storage) -> Result<SimilarityMetric> {
+    Ok(SimilarityMetric::from_meta(storage.get_meta_value(SIMILARITY_METRIC_KEY).await?.as_deref()))
+}
+
+/// Byte ranges of fragments in the sections they were chunked from, found by searching
+/// each section from where the previous fragment started (fragments overlap). A fragment
+/// that isn't a verbatim piece of its section, such as a table rendered as text, gets
+/// `None`. Ranges are `(section index, start, end)`.
+pub fn locate_fragments(sections: &[&str], fragments: &[&str]) -> Vec<Option<(usize, usize, usize)>> {
+    let mut section = 0;
+    let mut cursor = 0;
+    fragments.iter()
+        .map(|fragment| {
+            let fragment = fragment.trim();
+            let found = sections.iter().enumerate().skip(section).find_map(|(index, text)| {
+                let from = if index == section { cursor } else { 0 };
+                text.get(from..)?.find(fragment).map(|start| (index, from + start))
+            });
+            let (index, start) = found?;
+            section = index;
+            cursor = text_boundary(sections[index], start + 1);
+            Some((index, start, start + fragment.len()))
+        })
+        .collect()
+}
+
+/// The first char boundary of `text` at or after `index`
+fn text_boundary(text: &str, index: usize) -> usize {
+    (index..=text.len()).find(|&index| text.is_char_boundary(index)).unwrap_or(text.len())
+}
+
+/// Late chunking: embed up to `limit` fragments that need a vector by embedding each
+/// document section they were cut from whole and pooling its token embeddings over the
+/// fragment, so the vector reflects the surrounding text too. Fragments that can't be
+/// located in their section, or fall past the model's input limit, are left for
+/// `embed_fragment_batch`. Returns the number of fragments embedded.
+pub async fn embed_late_chunks(
+    storage: &mut dyn Storage,
+    embedding_manager: &mut EmbeddingManager,
+    limit: i32,
+) -> Result<i32> {
+    let prefixes = embedding_prefixes(storage).await?;
+    let mut embedded = 0;
+
+    for document in storage.list_documents().await? {
+        if embedded >= limit {
+            break;
+        }
+        let records = storage.get_fragment_records(&document.id).await?;
+        if records.iter().all(|record| record.embedding.is_some() && !record.stale) {
+            continue;
+        }
+        let Some(text) = storage.get_document_text(&document.id).await? else {
+            continue;
+        };
+        let sections = unpack_sections(&text)?;
+        let texts: Vec<&str> = sections.iter().map(|section| section.text.as_str()).collect();
+        let contents: Vec<&str> = records.iter().map(|record| record.content.as_str()).collect();
+        let located = locate_fragments(&texts, &contents);
+
+        // Pending fragments grouped by the section they sit in
+        let mut by_section: Vec<Vec<(&str, (usize, usize))>> = vec![Vec::new(); sections.len()];
+        for (record, location) in records.iter().zip(located) {
+            let pending = record.embedding.is_none() || record.stale;
+            if let (true, Some((section, start, end))) = (pending, location) {
+                by_section[section].push((record.id.as_str(), (start, end)));
+            }
+        }
+
+        let mut batch: Vec<(String, Vec<f32>)> = Vec::new();
+        for (section, fragments) in by_section.iter().enumerate() {
+            if fragments.is_empty() || embedded + batch.len() as i32 >= limit {
+                continue;
+            }
+            let fragments = &fragments[..fragments.len().min((limit - embedded) as usize - batch.len())];
+            // Ranges shift past the document prefix embedded in front of the section
+            let shift = prefixes.document.len();
+            let spans: Vec<(usize, usize)> = fragments.iter().map(|(_, (start, end))| (start + shift, end + shift)).collect();
+            let vectors = embedding_manager.embed_late_chunks(&prefixes.document(texts[section]), &spans).await
+                .with_context(|| format!("Failed to late chunk {}", document.filename))?;
+            for ((fragment_id, _), vector) in fragments.iter().zip(vectors) {
+                if let Some(vector) = vector {
+                    batch.push((fragment_id.to_string(), vector.into_iter().map(|x| x as f32).collect()));
+                }
+            }
+        }
+
+        store_embedded(storage, embedding_manager, &batch).await?;
+        embedded += batch.len() as i32;
+    }
+
+    Ok(embedded)
+}
+
+/// What one call to `embed_fragment_batch` did
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct EmbeddedBatch {
+    /// Fragments fetched, 0 once none are left
+    pub fragments: i32,
+    /// Fragments given the vector of an identical chunk instead of their own request
+    pub reused: i32,
+}
+
+/// Embed the next `batch_size` fragments that need a vector (missing or stale) in one batch,
+/// with the database's document prefix. Identical chunks, such as boilerplate headers and
+/// footers, are embedded once: vectors already stored for the same text are reused, and
+/// repeats within the batch share one request. Fragments the embedding server fails on are
+/// recorded and set aside until `retry_embedding_failures`.
+pub async fn embed_fragment_batch(
+    storage: &mut dyn Storage,
+    embedding_manager: &mut EmbeddingManager,
+    batch_size: i32,
+) -> Result<EmbeddedBatch> {
+    let fragments = storage.get_fragments_without_embeddings(batch_size).await?;
+
+    if fragments.is_empty() {
+        return Ok(EmbeddedBatch::default());
+    }
+
+    // Vectors by chunk hash, starting with those already stored; each other text is embedded once
+    let hashes: Vec<String> = fragments.iter().map(|(_, content)| chunk_hash(content)).collect();
+    let mut vectors = storage.get_embeddings_by_chunk(&hashes).await?;
+    let mut seen = HashSet::new();
+    let mut unique: Vec<(&str, &str)> = Vec::new();
+    for ((_, content), hash) in fragments.iter().zip(&hashes) {
+        if !vectors.contains_key(hash) && seen.insert(hash.as_str()) {
+            unique.push((hash, content));
+        }
+    }
+
+    // Extract texts for batch processing, with the model's document prefix
+    let prefixes = embedding_prefixes(storage).await?;
+    let texts: Vec<String> = unique.iter().map(|(_, content)| prefixes.document(content)).collect();
+
+    // Generate all embeddings in one batch call, isolating any fragments a remote server fails on
+    let results = if texts.is_empty() {
+        Vec::new()
+    } else {
+        embedding_manager.generate_embeddings_isolating(&texts).await
+            .context("Failed to generate batch embeddings")?
+    };
+
+    if results.len() != texts.len() {
+        anyhow::bail!("Embedding count mismatch: expected {}, got {}", texts.len(), results.len());
+    }
+
+    let mut errors = HashMap::new();
+    for ((hash, _), result) in unique.iter().zip(results) {
+        match result {
+            Ok(embedding) => {
+                vectors.insert(hash.to_string(), embedding.into_iter().map(|x| x as f32).collect());
+            }
+            Err(error) => {
+                errors.insert(*hash, error);
+            }
+        }
+    }
+
+    // Every fragment sharing a failed text is set aside with it
+    let mut failures = Vec::new();
+    let mut batch: Vec<(String, Vec<f32>)> = Vec::with_capacity(fragments.len());
+    for ((fragment_id, _), hash) in fragments.iter().zip(&hashes) {
+        if let Some(embedding) = vectors.get(hash) {
+            batch.push((fragment_id.clone(), embedding.clone()));
+        } else if let Some(error) = errors.get(hash.as_str()) {
+            warn!("Failed to embed fragment {}: {}", fragment_id, error);
+            failures.push((fragment_id.clone(), error.clone()));
+        }
+    }
+    if !failures.is_empty() {
+        storage.record_embedding_failures(&failures).await?;
+    }
+
+    store_embedded(storage, embedding_manager, &batch).await?;
+
+    Ok(EmbeddedBatch {
+        fragments: fragments.len() as i32,
+        reused: (fragments.len() - unique.len()) as i32,
+    })
+}
+
+/// Store freshly generated vectors in one write, as the f32 vectors backends keep, and
+/// record them in the model lineage
+async fn store_embedded(
+    storage: &mut dyn Storage,
+    embedding_manager: &mut EmbeddingManager,
+    batch: &[(String, Vec<f32>)],
+) -> Result<()> {
+    let Some(dimension) = batch.first().map(|(_, embedding)| embedding.len()) else {
+        return Ok(());
+    };
+
+    // The first vectors stored fix the dimension every later batch is checked against
+    if embedding_manager.expected_dimension().is_none() {
+        storage.set_meta_value(DIMENSION_KEY, &dimension.to_string()).await?;
+        storage.set_meta_value(PROVIDER_KEY, embedding_manager.provider_name()).await?;
+        embedding_manager.expect_dimension(dimension);
+    }
+
+    storage.update_fragment_embeddings_batch(batch).await
+        .context("Failed to store batch embeddings")?;
+    // The vector is all an embeddings-only database keeps of a fragment's text
+    if embeddings_only(storage).await? {
+        let ids: Vec<String> = batch.iter().map(|(id, _)| id.clone()).collect();
+        storage.clear_fragment_text(&ids).await?;
+    }
+    record_embedded(storage, dimension, embedding_manager.provider_name(), batch.len()).await?;
+    publish(storage).await.map(|_| ())
+}
+
+/// Remove documents a run died part way through writing: their original is stored but not
+/// every fragment is. Returns their paths, which the next index run picks up again as new
+/// files.
+pub async fn remove_interrupted(storage: &mut dyn Storage) -> Result<Vec<String>> {
+    let interrupted = storage.list_index_state(IndexState::Stored).await?;
+    if interrupted.is_empty() {
+        return Ok(Vec::new());
+    }
+    let mut paths = Vec::new();
+    for document in storage.list_documents().await? {
+        if interrupted.contains(&document.id) {
+            storage.remove_document(&document.id).await?;
+            paths.push(document.file_path);
+        }
+    }
+    Ok(paths)
+}
+
+/// Advance chunked documents whose every fragment now has a current vector to
+/// `IndexState::Embedded`, returning how many advanced
+pub async fn mark_embedded_documents(storage: &mut dyn Storage) -> Result<usize> {
+    let mut advanced = 0;
+    for document_id in storage.list_index_state(IndexState::Chunked).await? {
+        let records = storage.get_fragment_records(&document_id).await?;
+        if records.iter().all(|record| record.embedding.is_some() && !record.stale) {
+            storage.set_index_state(&document_id, IndexState::Embedded).await?;
+            advanced += 1;
+        }
+    }
+    Ok(advanced)
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct MetaInfo {
-    pub version: String,
-    pub embedding_model: String,
+/// The dataset version searches currently see; 0 for a database nothing has been published to
+pub async fn data_version(storage: &mut dyn Storage) -> Result<u64> {
+    Ok(storage.get_meta_value(DATA_VERSION_KEY).await?
+        .and_then(|version| version.parse().ok())
+        .unwrap_or(0))
+}
+
+/// Bump the dataset version after a write that changes what searches can return, so a
+/// search can report which version it saw. Returns the new version.
+pub async fn publish(storage: &mut dyn Storage) -> Result<u64> {
+    let version = data_version(storage).await? + 1;
+    storage.set_meta_value(DATA_VERSION_KEY, &version.to_string()).await?;
+    Ok(version)
+}
+
+/// Record that every fragment of a document is written and publish it as a new dataset version
+pub async fn publish_document(storage: &mut dyn Storage, document_id: &str) -> Result<u64> {
+    storage.set_index_state(document_id, IndexState::Chunked).await?;
+    publish(storage).await
+}
+/// Find a stored document by id, file name or the end of its path, e.g. `reports/q3.pdf`.
+/// File names are matched case-insensitively; a name shared by several documents is an
+/// error listing their paths.
+pub async fn find_document(storage: &mut dyn Storage, name_or_id: &str) -> Result<DocumentSummary> {
+    let documents = storage.list_documents().await?;
+    if let Some(document) = documents.iter().find(|document| document.id == name_or_id) {
+        return Ok(document.clone());
+    }
+
+    let wanted = name_or_id.to_lowercase();
+    let mut matches: Vec<&DocumentSummary> = documents.iter()
+        .filter(|document| document.filename.to_lowercase() == wanted)
+        .collect();
+    if matches.is_empty() {
+        matches = documents.iter()
+            .filter(|document| Path::new(&document.file_path).ends_with(name_or_id))
+            .collect();
+    }
+
+    match matches.as_slice() {
+        [] => anyhow::bail!("No document with id or file name '{}'", name_or_id),
+        [document] => Ok((*document).clone()),
+        _ => anyhow::bail!(
+            "'{}' matches {} documents; use one of their paths or ids: {}",
+            name_or_id,
+            matches.len(),
+            matches.iter().map(|document| document.file_path.as_str()).collect::<Vec<_>>().join(", ")
+        ),
+    }
+}
+
+/// Write a stored document's original bytes into `dir` under its file name, returning the
+/// path written. Existing files are kept: the name gets ` (2)`, ` (3)`, ... instead.
+pub async fn extract_document(storage: &mut dyn Storage, document_id: &str, dir: &Path) -> Result<PathBuf> {
+    let document = storage.get_document(document_id).await?
+        .ok_or_else(|| anyhow::anyhow!("No document with id {}", document_id))?;
+    let data = document.file_data
+        .ok_or_else(|| anyhow::anyhow!("The original of {} wasn't stored (indexed with --store-originals none)", document.file_path))?;
+    std::fs::create_dir_all(dir)
+        .with_context(|| format!("Failed to create {}", dir.display()))?;
+
+    // Names come from the database, so only their last component is trusted
+    let filename = Path::new(&document.filename).file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_else(|| document.id.clone());
+    let path = free_path(dir, &filename);
+    std::fs::write(&path, &data)
+        .with_context(|| format!("Failed to write {}", path.display()))?;
+    Ok(path)
+}
+
+/// `dir/filename`, or the first of `dir/stem (2).ext`, `dir/stem (3).ext`, ... not taken yet
+fn free_path(dir: &Path, filename: &str) -> PathBuf {
+    let path = dir.join(filename);
+    if !path.exists() {
+        return path;
+    }
+    let name = Path::new(filename);
+    let stem = name.file_stem().map(|stem| stem.to_string_lossy().to_string()).unwrap_or_default();
+    let extension = name.extension().map(|ext| format!(".{}", ext.to_string_lossy())).unwrap_or_default();
+    (2..)
+        .map(|n| dir.join(format!("{} ({}){}", stem, n, extension)))
+        .find(|path| !path.exists())
+        .expect("some numbered name is free")
 }
 
 #[derive(Debug, Clone)]
 pub enum StorageBackend {
     DuckDB,
     LanceDB,
+    /// A brain on a Qdrant server, named by its URL
+    Remote,
 }
 
-impl StorageBackend {
-    pub fn from_str(s: &str) -> Option<Self> {
+impl std::str::FromStr for StorageBackend {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
         match s.to_lowercase().as_str() {
-            "duckdb" => Some(StorageBackend::DuckDB),
-            "lancedb" => Some(StorageBackend::LanceDB),
-            _ => None,
+            "duckdb" => Ok(StorageBackend::DuckDB),
+            "lancedb" => Ok(StorageBackend::LanceDB),
+            "remote" | "qdrant" => Ok(StorageBackend::Remote),
+            _ => anyhow::bail!("Unknown storage backend '{}'", s),
         }
     }
+}
 
+impl StorageBackend {
     pub fn as_str(&self) -> &'static str {
         match self {
             StorageBackend::DuckDB => "duckdb",
             StorageBackend::LanceDB => "lancedb",
+            StorageBackend::Remote => "remote",
+        }
+    }
+
+    /// Infer the backend from a database path: remote for an `http(s)://` URL, otherwise
+    /// by extension (.lancedb, otherwise DuckDB)
+    pub fn from_path(path: &Path) -> Self {
+        if remote_storage::is_remote(path) {
+            return StorageBackend::Remote;
+        }
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some(ext) if ext.eq_ignore_ascii_case("lancedb") => StorageBackend::LanceDB,
+            _ => StorageBackend::DuckDB,
+        }
+    }
+
+    pub fn display_name(&self) -> &'static str {
+        match self {
+            StorageBackend::DuckDB => "DuckDB",
+            StorageBackend::LanceDB => "LanceDB",
+            StorageBackend::Remote => "Qdrant",
+        }
+    }
+}
+
+/// Open a storage backend for the given database path.
+///
+/// A shard manifest (`.shards`) is opened as a sharded brain regardless of `backend`;
+/// the manifest records the backend of its shards.
+pub async fn create_storage(backend: &StorageBackend, database_path: &Path) -> Result<Box<dyn Storage>> {
+    if ShardedStorage::is_manifest(database_path) {
+        let storage = ShardedStorage::open(database_path).await?;
+        return Ok(Box::new(storage));
+    }
+
+    open_backend(backend, database_path).await
+}
+
+/// Open a single database file with the given backend
+pub async fn open_backend(backend: &StorageBackend, database_path: &Path) -> Result<Box<dyn Storage>> {
+    match backend {
+        StorageBackend::DuckDB => {
+            // DuckDB calls block, so they run on a thread of their own
+            let storage = DuckDBStorage::new(database_path).await.map_err(error::database)?;
+            Ok(Box::new(StorageThread::spawn(Box::new(storage))?))
+        }
+        StorageBackend::LanceDB => {
+            let storage = LanceDBStorage::new(database_path).await?;
+            Ok(Box::new(storage))
+        }
+        StorageBackend::Remote => {
+            // Requests to the server don't block, so no thread of its own is needed
+            let storage = RemoteStorage::connect(&database_path.to_string_lossy()).await?;
+            Ok(Box::new(storage))
+        }
+    }
+}
+
+/// Path of a file or directory kept beside the database, named by adding `suffix` to the
+/// database's path (`brain.db.staging`). Remote brains have no local path, so theirs are
+/// named after the brain in the current directory (`team.staging`).
+pub fn sidecar_path(database: &Path, suffix: &str) -> PathBuf {
+    match remote_storage::brain_name(database) {
+        Some(name) => PathBuf::from(format!("{}{}", name, suffix)),
+        None => {
+            let mut path = database.as_os_str().to_owned();
+            path.push(suffix);
+            PathBuf::from(path)
         }
     }
 }
@@ -58,21 +1855,174 @@ pub trait Storage: Send {
     /// Initialize the storage backend
     async fn initialize(&mut self) -> Result<()>;
 
-    /// Verify or set the embedding model
-    async fn verify_or_set_model(&mut self, model_name: &str) -> Result<()>;
+    /// Verify or set the embedding model. When the model's vector length is known, it must
+    /// also match the dimension recorded for the database's vectors.
+    async fn verify_or_set_model(&mut self, model_name: &str, dimension: Option<usize>) -> Result<()>;
+
+    /// Collections embedded with a model of their own instead of the database's, as
+    /// (collection, model). Only brains sharded by collection keep them apart; their
+    /// fragments are left out of the database-wide vector calls below.
+    fn collection_models(&self) -> Vec<(String, String)> {
+        Vec::new()
+    }
+
+    /// Embed `collection` with `model` from now on, re-embedding what it already holds.
+    /// Giving it the database's model again returns it to the shared space.
+    async fn set_collection_model(&mut self, _collection: &str, _model: &str) -> Result<()> {
+        anyhow::bail!("Only brains sharded by collection can embed a collection with its own model; index with --shard-by collection")
+    }
+
+    /// The part of the database holding a collection with a model of its own, to embed it
+    /// with that model. Ids it hands out are its own rather than the database's.
+    fn collection_space(&mut self, _collection: &str) -> Option<&mut dyn Storage> {
+        None
+    }
+
+    /// Search a collection with a model of its own by similarity to a query embedded with
+    /// that model, returning the database's ids
+    async fn search_collection(
+        &mut self,
+        collection: &str,
+        _query_embedding: &[f64],
+        _limit: usize,
+        _filter: &SearchFilter,
+    ) -> Result<Vec<FragmentMatch>> {
+        anyhow::bail!("Collection {} has no embedding model of its own", collection)
+    }
+
+    /// Derive the ids of documents and fragments stored from now on from their content,
+    /// recording it so later runs against the database keep doing so
+    async fn enable_deterministic_ids(&mut self) -> Result<()>;
+
+    /// Keep the originals of documents stored from now on as `mode` says, recording it so
+    /// later runs against the database keep doing so. Documents already stored are left as
+    /// they are.
+    async fn set_originals_mode(&mut self, mode: OriginalsMode) -> Result<()>;
+
+    /// Compare query vectors with fragment vectors by `metric` in `search_similar`,
+    /// recording it so later searches of the database do too
+    async fn set_similarity_metric(&mut self, metric: SimilarityMetric) -> Result<()>;
 
     /// Check if a document already exists
     async fn document_exists(&mut self, file_path: &Path) -> Result<bool>;
 
-    /// Store a document and return its ID
+    /// Path of a searchable document whose original has this `content_hash`, so the same
+    /// file found at another path isn't stored and embedded again
+    async fn find_duplicate(&mut self, content_hash: &str) -> Result<Option<String>>;
+
+    /// Store a document and return its ID. Its content hash is recorded along with the
+    /// modification time of `file_path`, when it still exists. The document starts in
+    /// `IndexState::Stored` until its fragments are written.
     async fn store_document(&mut self, file_path: &Path, file_data: &[u8]) -> Result<String>;
 
+    /// Record how far indexing got with a document
+    async fn set_index_state(&mut self, document_id: &str, state: IndexState) -> Result<()>;
+
+    /// Ids of the documents in `state`
+    async fn list_index_state(&mut self, state: IndexState) -> Result<Vec<String>>;
+
+    /// Record the content hash and source modification time a document was last checked
+    /// against, e.g. after a touched file turned out to be unchanged
+    async fn set_document_source(&mut self, document_id: &str, content_hash: &str, modified: Option<i64>) -> Result<()>;
+
+    /// Set the embedding priority of a document (higher values are embedded first)
+    async fn set_document_priority(&mut self, document_id: &str, priority: i32) -> Result<()>;
+
+    /// Record the category assigned to a document by the classifier, with its confidence score
+    async fn set_document_category(&mut self, document_id: &str, category: &str, score: f64) -> Result<()>;
+
+    /// Record the collection and tags assigned to a document by the routing rules
+    async fn set_document_collection(&mut self, document_id: &str, collection: Option<&str>, tags: &[String]) -> Result<()>;
+
+    /// The collection a document was routed to; `None` for unrouted or unknown documents
+    async fn get_document_collection(&mut self, document_id: &str) -> Result<Option<String>>;
+
+    /// The priority, category, collection and tags recorded for a document; `None` for
+    /// unknown documents
+    async fn get_document_attributes(&mut self, document_id: &str) -> Result<Option<DocumentAttributes>>;
+
+    /// Record how cleanly a document's text was extracted, with the reasons it was flagged
+    async fn set_document_quality(&mut self, document_id: &str, score: f64, flags: &[String]) -> Result<()>;
+
+    /// Record the title derived from a document's text, shown instead of its file name
+    async fn set_document_title(&mut self, document_id: &str, title: &str) -> Result<()>;
+
+    /// Mark a document whose source file is gone, or unmark it when the file returns.
+    /// Tombstoned documents are excluded from search.
+    async fn tombstone_document(&mut self, document_id: &str, tombstoned: bool) -> Result<()>;
+
+    /// Delete a document together with its fragments
+    async fn remove_document(&mut self, document_id: &str) -> Result<()>;
+
+    /// Apply `action` to every document passing the document-level parts of `filter`
+    /// (categories, collections, tags, file types, path, dates and id), returning how many
+    /// it selected. Backends with a query engine do this in one statement per table.
+    async fn bulk_update(&mut self, filter: &SearchFilter, action: &BulkAction) -> Result<usize> {
+        let mut selected = 0;
+        for document in self.list_documents().await? {
+            let Some(attributes) = self.get_document_attributes(&document.id).await? else {
+                continue;
+            };
+            if !filter.matches_attributes(&document, &attributes) {
+                continue;
+            }
+            match action.apply(attributes.collection.clone(), &attributes.tags) {
+                None => self.remove_document(&document.id).await?,
+                Some((collection, tags)) => {
+                    if collection != attributes.collection || tags != attributes.tags {
+                        self.set_document_collection(&document.id, collection.as_deref(), &tags).await?;
+                    }
+                }
+            }
+            selected += 1;
+        }
+        Ok(selected)
+    }
+
+    /// List stored documents ordered by path
+    async fn list_documents(&mut self) -> Result<Vec<DocumentSummary>>;
+
+    /// Fetch a document and its original bytes
+    async fn get_document(&mut self, document_id: &str) -> Result<Option<DocumentInfo>>;
+
+    /// Store a document's extracted text, compressed, so it can be re-chunked later
+    async fn set_document_text(&mut self, document_id: &str, text: &[u8]) -> Result<()>;
+
+    /// The compressed extracted text stored with a document, if it was indexed with one
+    async fn get_document_text(&mut self, document_id: &str) -> Result<Option<Vec<u8>>>;
+
+    /// Replace a document's fragments with a new chunking, in order. Existing fragments with
+    /// the same text are kept along with their vectors; only new ones need embedding.
+    async fn replace_fragments(
+        &mut self,
+        document_id: &str,
+        fragments: &[(String, FragmentMeta)],
+    ) -> Result<FragmentChanges>;
+
+    /// Fetch up to `limit` of a document's fragments in order, starting at `offset`
+    async fn get_document_fragments(&mut self, document_id: &str, offset: i32, limit: i32) -> Result<Vec<String>>;
+
+    /// Every fragment of a document in order, with its id, section and vector
+    async fn get_fragment_records(&mut self, document_id: &str) -> Result<Vec<FragmentRecord>>;
+
+    /// Up to `limit` fragments of all documents as one batch in `fragment_schema`, ordered
+    /// by id and starting after the fragment `after`. `None` once no fragments are left.
+    /// Bulk readers such as exports page through large stores this way without building
+    /// a struct per fragment.
+    async fn read_fragment_batch(&mut self, after: Option<&str>, limit: usize) -> Result<Option<RecordBatch>>;
+
+    /// Insert a batch of fragments in `fragment_schema` with their ids and vectors, e.g.
+    /// read from another store. Their documents must already be stored. Returns the
+    /// number of fragments inserted.
+    async fn write_fragment_batch(&mut self, batch: &RecordBatch) -> Result<usize>;
+
     /// Store a text fragment without embedding initially
     async fn store_text_fragment(
         &mut self,
         document_id: &str,
         order: i32,
         content: &str,
+        meta: &FragmentMeta,
     ) -> Result<String>;
 
     /// Update fragment with embedding
@@ -82,19 +2032,763 @@ pub trait Storage: Send {
         embedding: &[f64],
     ) -> Result<()>;
 
-    /// Get fragments without embeddings for batch processing
-    async fn get_fragments_without_embeddings(&mut self, limit: i32) -> Result<Vec<(String, String)>>;
+    /// Store a batch of `(fragment id, vector)` pairs at once, inside a single transaction
+    /// where the backend has them, clearing their staleness and failure records
+    async fn update_fragment_embeddings_batch(&mut self, embeddings: &[(String, Vec<f32>)]) -> Result<()>;
+
+    /// Drop the text of embedded fragments, keeping their vectors, chunk hashes and
+    /// metadata, for embeddings-only databases
+    async fn clear_fragment_text(&mut self, fragment_ids: &[String]) -> Result<()>;
 
-    /// Count fragments without embeddings
+    /// Get up to `limit` fragments that need an embedding (missing or stale), in the order
+    /// of `PendingFragment::queue_key`, leaving out those set aside by
+    /// `record_embedding_failures`
+    async fn get_pending_fragments(&mut self, limit: i32) -> Result<Vec<PendingFragment>>;
+
+    /// Ids and text of the fragments `get_pending_fragments` returns, for batch processing
+    async fn get_fragments_without_embeddings(&mut self, limit: i32) -> Result<Vec<(String, String)>> {
+        Ok(self.get_pending_fragments(limit).await?
+            .into_iter()
+            .map(|fragment| (fragment.id, fragment.content))
+            .collect())
+    }
+
+    /// Count fragments that need an embedding (missing or stale), leaving out those set aside
     async fn count_fragments_without_embeddings(&mut self) -> Result<i32>;
 
+    /// Current vectors of fragments whose text has one of these `chunk_hash`es, one per hash,
+    /// so identical chunks are only embedded once
+    async fn get_embeddings_by_chunk(&mut self, chunk_hashes: &[String]) -> Result<HashMap<String, Vec<f32>>>;
+
+    /// Record fragments whose embedding failed, with the error, setting them aside for the
+    /// rest of the run; a later successful embedding clears the record
+    async fn record_embedding_failures(&mut self, failures: &[(String, String)]) -> Result<()>;
+
+    /// Return fragments set aside after failing to the embedding queue. Returns their number.
+    async fn retry_embedding_failures(&mut self) -> Result<i32>;
+
+    /// Count fragments whose last embedding attempt failed
+    async fn count_embedding_failures(&mut self) -> Result<i32>;
+
+    /// Build an approximate nearest-neighbour index over the vectors once every fragment is
+    /// embedded with the current model. Returns whether searches can use one.
+    async fn build_vector_index(&mut self) -> Result<bool>;
+
+    /// How many embedded fragments the vector index covers and how many are scanned beside
+    /// it, or `None` when searches don't use an index
+    async fn vector_index_coverage(&mut self) -> Result<Option<VectorIndexCoverage>> {
+        Ok(None)
+    }
+
+    /// Mark every embedded fragment stale, e.g. after the embedding model changed
+    async fn mark_embeddings_stale(&mut self) -> Result<i32>;
+
+    /// Count fragments whose vectors are stale
+    async fn count_stale_fragments(&mut self) -> Result<i32>;
+
+    /// Documents per file type, fragment and text totals, and the `largest` biggest
+    /// documents, tombstoned ones included
+    async fn corpus_stats(&mut self, largest: usize) -> Result<CorpusStats>;
+
+    /// Count a search hit for each fragment; frequently retrieved stale fragments are re-embedded first
+    async fn record_fragment_hits(&mut self, fragment_ids: &[String]) -> Result<()>;
+
+    /// Stored vectors of the given fragments, for re-scoring search candidates; fragments
+    /// without a vector are left out
+    async fn get_fragment_vectors(&mut self, fragment_ids: &[String]) -> Result<HashMap<String, Vec<f64>>>;
+
+    /// Get a deterministic sample of embedded fragments labelled with their document filename
+    async fn get_fragment_embeddings(&mut self, limit: usize) -> Result<Vec<(String, String, Vec<f64>)>>; // (fragment_id, filename, embedding)
+
+    /// Get the same sample as `get_fragment_embeddings`, with each fragment's text instead of its filename
+    async fn sample_fragments(&mut self, limit: usize) -> Result<Vec<(String, String, Vec<f64>)>>; // (fragment_id, content, embedding)
+
     /// Get metadata information
     async fn get_meta_info(&mut self) -> Result<MetaInfo>;
 
-    /// Search for similar documents using vector similarity
+    /// Read a value from the meta table
+    async fn get_meta_value(&mut self, key: &str) -> Result<Option<String>>;
+
+    /// Insert or replace a value in the meta table
+    async fn set_meta_value(&mut self, key: &str, value: &str) -> Result<()>;
+
+    /// The entities mentioned in the most fragments of searchable documents, most first
+    async fn list_entities(&mut self, limit: usize) -> Result<Vec<EntityCount>>;
+
+    /// Searchable documents mentioning an entity, matched as by `SearchFilter::entity`,
+    /// with the most mentions first
+    async fn find_entity(&mut self, entity: &str) -> Result<Vec<EntityMention>>;
+
+    /// Attach an annotation to a fragment or document
+    async fn add_annotation(&mut self, annotation: &Annotation) -> Result<()>;
+
+    /// Annotations on `target`, or every annotation when it's `None`, oldest first
+    async fn list_annotations(&mut self, target: Option<&str>) -> Result<Vec<Annotation>>;
+
+    /// Delete an annotation, returning whether it existed
+    async fn remove_annotation(&mut self, annotation_id: &str) -> Result<bool>;
+
+    /// Search for similar fragments using vector similarity; scores are by the database's
+    /// `SimilarityMetric`, cosine unless another was recorded
     async fn search_similar(
         &mut self,
         query_embedding: &[f64],
         limit: usize,
-    ) -> Result<Vec<(String, String, f64)>>; // (fragment_id, content, similarity_score)
-}
\ No newline at end of file
+        filter: &SearchFilter,
+    ) -> Result<Vec<FragmentMatch>>;
+
+    /// Search fragment text by BM25 full-text ranking. Text has no embedding space, so
+    /// `filter.stale` doesn't apply. Scores are BM25 scores.
+    async fn search_keyword(
+        &mut self,
+        query: &str,
+        limit: usize,
+        filter: &SearchFilter,
+    ) -> Result<Vec<FragmentMatch>>;
+
+    /// Search by vector similarity and BM25 together, fusing the two rankings by reciprocal
+    /// rank. Fused scores depend on both whole rankings, so `filter.after` doesn't apply.
+    async fn search_hybrid(
+        &mut self,
+        query: &str,
+        query_embedding: &[f64],
+        limit: usize,
+        filter: &SearchFilter,
+    ) -> Result<Vec<FusedHit>> {
+        let pool = limit.max(FUSION_POOL);
+        let filter = &SearchFilter { after: None, ..filter.clone() };
+        let dense = self.search_similar(query_embedding, pool, filter).await?;
+        let sparse = self.search_keyword(query, pool, filter).await?;
+        Ok(reciprocal_rank_fusion(dense, sparse, limit))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_fragment() {
+        assert!(matches!(validate_fragment("plain text").unwrap(), Cow::Borrowed("plain text")));
+        assert_eq!(validate_fragment("nul\0 inside").unwrap(), "nul inside");
+
+        for bad in ["", "  \n\t", "\0\0", &"x".repeat(MAX_FRAGMENT_BYTES + 1)] {
+            let error = validate_fragment(bad).unwrap_err();
+            assert!(matches!(error.downcast_ref(), Some(PortableBrainsError::ValidationError(_))));
+        }
+    }
+
+    #[test]
+    fn test_parse_storage_backend() {
+        assert!(matches!("DuckDB".parse::<StorageBackend>().unwrap(), StorageBackend::DuckDB));
+        assert!(matches!("qdrant".parse::<StorageBackend>().unwrap(), StorageBackend::Remote));
+        assert!("sqlite".parse::<StorageBackend>().is_err());
+    }
+
+    #[test]
+    fn test_parse_section_filter() {
+        assert_eq!(
+            SearchFilter::parse_section(r#"key rotation section:"Security Requirements""#),
+            ("key rotation".to_string(), Some("Security Requirements".to_string()))
+        );
+        assert_eq!(
+            SearchFilter::parse_section("section:Appendix retention period"),
+            ("retention period".to_string(), Some("Appendix".to_string()))
+        );
+        assert_eq!(SearchFilter::parse_section("no filter here"), ("no filter here".to_string(), None));
+    }
+
+    #[test]
+    fn test_parse_metadata_filter() {
+        let (filter, rest) = SearchFilter::parse_metadata(r#"collection:finance tag:q3 tag:audit type:.PDF path:"board *.pdf" since:2024-01-01 until:2024-06-30"#).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(filter.collections, vec!["finance"]);
+        assert_eq!(filter.tags, vec!["q3", "audit"]);
+        assert_eq!(filter.file_types, vec!["pdf"]);
+        assert_eq!(filter.path.as_deref(), Some("board *.pdf"));
+        // The until date is included whole
+        assert_eq!(filter.modified_after, Some(parse_date("2024-01-01T00:00:00Z", false).unwrap()));
+        assert_eq!(filter.modified_before, Some(parse_date("2024-07-01T00:00:00Z", false).unwrap()));
+
+        assert_eq!(SearchFilter::parse_metadata("colour:red").unwrap().1, "colour:red");
+        assert!(SearchFilter::parse_metadata("since:last-week").is_err());
+
+        assert!(path_matches("board *.pdf", "/data/finance/board minutes.pdf"));
+        assert!(path_matches("finance/*.pdf", "/data/finance/2024/board.pdf"));
+        assert!(!path_matches("finance/*.pdf", "/data/legal/board.pdf"));
+        assert!(!path_matches("*.pdf", "/data/finance.pdf/notes.txt"));
+    }
+
+    #[test]
+    fn test_content_ids_are_stable() {
+        let ids = IdScheme::from_meta(Some("true"));
+        let document = ids.document_id("report.pdf", b"quarterly numbers");
+        assert_eq!(document, ids.document_id("report.pdf", b"quarterly numbers"));
+        assert_ne!(document, ids.document_id("report.pdf", b"revised numbers"));
+        assert_ne!(document, ids.document_id("copy.pdf", b"quarterly numbers"));
+
+        let fragment = ids.fragment_id(&document, 0, "Revenue grew");
+        assert_eq!(fragment, ids.fragment_id(&document, 0, "Revenue grew"));
+        assert_ne!(fragment, ids.fragment_id(&document, 1, "Revenue grew"));
+
+        let random = IdScheme::from_meta(None);
+        assert_ne!(random.document_id("report.pdf", b"x"), random.document_id("report.pdf", b"x"));
+    }
+
+    #[tokio::test]
+    async fn test_span_ids_survive_renames() {
+        let meta = FragmentMeta { span: Some((0, 12)), ..FragmentMeta::default() };
+        let mut first = LanceDBStorage::new(Path::new("first")).await.unwrap();
+        let mut second = LanceDBStorage::new(Path::new("second")).await.unwrap();
+        first.enable_deterministic_ids().await.unwrap();
+        second.enable_deterministic_ids().await.unwrap();
+
+        let document = first.store_document(Path::new("q3.pdf"), b"quarterly numbers").await.unwrap();
+        let id = first.store_text_fragment(&document, 0, "Revenue grew", &meta).await.unwrap();
+        assert_eq!(id, IdScheme::span_fragment_id(&content_hash(b"quarterly numbers"), (0, 12)));
+
+        let renamed = second.store_document(Path::new("archive/q3-final.pdf"), b"quarterly numbers").await.unwrap();
+        assert_eq!(second.store_text_fragment(&renamed, 0, "Revenue grew", &meta).await.unwrap(), id);
+
+        // A copy under another name in the same database can't take the same id
+        let copy = first.store_document(Path::new("copy.pdf"), b"quarterly numbers").await.unwrap();
+        assert_ne!(first.store_text_fragment(&copy, 0, "Revenue grew", &meta).await.unwrap(), id);
+    }
+
+    #[tokio::test]
+    async fn test_prefixes_fixed_once_embedded() {
+        let mut storage = LanceDBStorage::new(Path::new("prefixes")).await.unwrap();
+        assert_eq!(recorded_prefixes(&mut storage).await.unwrap(), None);
+
+        let e5 = EmbeddingPrefixes::for_model("intfloat/e5-large-v2");
+        set_embedding_prefixes(&mut storage, &e5).await.unwrap();
+        assert_eq!(embedding_prefixes(&mut storage).await.unwrap(), e5);
+
+        storage.set_meta_value(DIMENSION_KEY, "1024").await.unwrap();
+        set_embedding_prefixes(&mut storage, &e5).await.unwrap();
+        assert!(set_embedding_prefixes(&mut storage, &EmbeddingPrefixes::default()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_late_chunks_embedded_from_their_section() {
+        let sections = ["Backups run nightly. Restores are tested monthly.", "Keys rotate yearly."];
+        let located = locate_fragments(&sections, &["Backups run nightly.", "nightly. Restores", "Keys rotate", "| a | b |"]);
+        assert_eq!(located, vec![Some((0, 0, 20)), Some((0, 12, 29)), Some((1, 0, 11)), None]);
+
+        let mut storage = LanceDBStorage::new(Path::new("late")).await.unwrap();
+        let mut embedding_manager = EmbeddingManager::hashing(16);
+        let document = storage.store_document(Path::new("ops.md"), b"ops").await.unwrap();
+        let packed = crate::document_processor::pack_sections(&[crate::document_processor::Section {
+            path: Vec::new(),
+            text: sections[0].to_string(),
+            page: None,
+            location: None,
+        }]).unwrap();
+        storage.set_document_text(&document, &packed).await.unwrap();
+        storage.store_text_fragment(&document, 0, "Backups run nightly.", &FragmentMeta::default()).await.unwrap();
+        storage.store_text_fragment(&document, 1, "Restores are tested monthly.", &FragmentMeta::default()).await.unwrap();
+        storage.store_text_fragment(&document, 2, "A summary table", &FragmentMeta::default()).await.unwrap();
+
+        assert_eq!(embed_late_chunks(&mut storage, &mut embedding_manager, 10).await.unwrap(), 2);
+        // The fragment that isn't a piece of its section is left for the usual batches
+        assert_eq!(storage.count_fragments_without_embeddings().await.unwrap(), 1);
+        assert_eq!(embedding_dimension(&mut storage).await.unwrap(), Some(16));
+        assert_eq!(embed_late_chunks(&mut storage, &mut embedding_manager, 10).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_dimension_recorded_and_verified() {
+        let mut storage = LanceDBStorage::new(Path::new("dimension")).await.unwrap();
+        let mut embedding_manager = EmbeddingManager::hashing(16);
+        let model = embedding_manager.model_name().to_string();
+        storage.verify_or_set_model(&model, embedding_manager.known_dimension()).await.unwrap();
+        let document = storage.store_document(Path::new("notes.txt"), b"notes").await.unwrap();
+        storage.store_text_fragment(&document, 0, "Backups run nightly", &FragmentMeta::default()).await.unwrap();
+        embed_fragment_batch(&mut storage, &mut embedding_manager, 10).await.unwrap();
+
+        let meta = storage.get_meta_info().await.unwrap();
+        assert_eq!(meta.embedding_dimension, Some(16));
+        assert_eq!(meta.embedding_provider.as_deref(), Some("hashing"));
+
+        storage.verify_or_set_model(&model, Some(16)).await.unwrap();
+        let error = storage.verify_or_set_model(&model, Some(384)).await.unwrap_err().to_string();
+        assert!(error.contains("16-dimensional") && error.contains("384-dimensional"));
+        assert!(check_dimension(&mut storage, &model, 8).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_model_lineage_tracks_upgrades() {
+        let mut storage = LanceDBStorage::new(Path::new("lineage")).await.unwrap();
+        let mut embedding_manager = EmbeddingManager::hashing(16);
+        let model = embedding_manager.model_name().to_string();
+        storage.verify_or_set_model(&model, Some(16)).await.unwrap();
+        let document = storage.store_document(Path::new("notes.txt"), b"notes").await.unwrap();
+        storage.store_text_fragment(&document, 0, "Backups run nightly", &FragmentMeta::default()).await.unwrap();
+        storage.store_text_fragment(&document, 1, "Restores are tested monthly", &FragmentMeta::default()).await.unwrap();
+        embed_fragment_batch(&mut storage, &mut embedding_manager, 10).await.unwrap();
+
+        upgrade_embedding_model(&mut storage, "hashing-v2").await.unwrap();
+        let lineage = model_lineage(&mut storage).await.unwrap();
+        assert_eq!(lineage.len(), 2);
+        assert_eq!((lineage[0].model.as_str(), lineage[0].dimension, lineage[0].embedded), (model.as_str(), Some(16), 2));
+        assert!(lineage[0].first_embedded.is_some() && lineage[0].retired.is_some());
+        assert_eq!(lineage[1], ModelEpoch::new("hashing-v2"));
+    }
+
+    #[tokio::test]
+    async fn test_identical_chunks_embedded_once() {
+        let mut storage = LanceDBStorage::new(Path::new("dedupe")).await.unwrap();
+        let mut embedding_manager = EmbeddingManager::hashing(16);
+        let first = storage.store_document(Path::new("a.txt"), b"first").await.unwrap();
+        storage.store_text_fragment(&first, 0, "Confidential - do not distribute", &FragmentMeta::default()).await.unwrap();
+        storage.store_text_fragment(&first, 1, "Backups run nightly", &FragmentMeta::default()).await.unwrap();
+        let batch = embed_fragment_batch(&mut storage, &mut embedding_manager, 10).await.unwrap();
+        assert_eq!(batch, EmbeddedBatch { fragments: 2, reused: 0 });
+
+        // The same footer, differently wrapped, reuses the stored vector
+        let second = storage.store_document(Path::new("b.txt"), b"second").await.unwrap();
+        storage.store_text_fragment(&second, 0, "Confidential -\n do not distribute", &FragmentMeta::default()).await.unwrap();
+        storage.store_text_fragment(&second, 1, "Restores are tested monthly", &FragmentMeta::default()).await.unwrap();
+        let batch = embed_fragment_batch(&mut storage, &mut embedding_manager, 10).await.unwrap();
+        assert_eq!(batch, EmbeddedBatch { fragments: 2, reused: 1 });
+        assert_eq!(storage.count_fragments_without_embeddings().await.unwrap(), 0);
+
+        let original = storage.find_duplicate(&content_hash(b"second")).await.unwrap().unwrap();
+        assert!(original.ends_with("b.txt"), "{}", original);
+        assert_eq!(storage.find_duplicate(&content_hash(b"third")).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_embeddings_only_drops_text_once_embedded() {
+        let mut storage = LanceDBStorage::new(Path::new("embeddings-only")).await.unwrap();
+        let mut embedding_manager = EmbeddingManager::hashing(16);
+        storage.set_meta_value(EMBEDDINGS_ONLY_KEY, "true").await.unwrap();
+        let document = storage.store_document(Path::new("hr.txt"), b"hr").await.unwrap();
+        storage.store_text_fragment(&document, 0, "Salaries are reviewed in March", &FragmentMeta::default()).await.unwrap();
+        assert_eq!(storage.get_document_fragments(&document, 0, 10).await.unwrap(), vec!["Salaries are reviewed in March"]);
+
+        embed_fragment_batch(&mut storage, &mut embedding_manager, 10).await.unwrap();
+        assert_eq!(storage.count_fragments_without_embeddings().await.unwrap(), 0);
+        assert_eq!(storage.get_document_fragments(&document, 0, 10).await.unwrap(), vec![""]);
+        assert!(upgrade_embedding_model(&mut storage, "hashing-v2").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_interrupted_documents_removed_and_finished_ones_advance() {
+        let mut storage = LanceDBStorage::new(Path::new("resume")).await.unwrap();
+        let half_written = storage.store_document(Path::new("crashed.md"), b"crashed").await.unwrap();
+        storage.store_text_fragment(&half_written, 0, "Half of it", &FragmentMeta::default()).await.unwrap();
+        let committed = storage.store_document(Path::new("done.md"), b"done").await.unwrap();
+        let fragment = storage.store_text_fragment(&committed, 0, "All of it", &FragmentMeta::default()).await.unwrap();
+        storage.set_index_state(&committed, IndexState::Chunked).await.unwrap();
+
+        let removed = remove_interrupted(&mut storage).await.unwrap();
+        assert_eq!(removed.len(), 1);
+        assert!(removed[0].ends_with("crashed.md"));
+        assert_eq!(storage.list_documents().await.unwrap().len(), 1);
+        assert_eq!(mark_embedded_documents(&mut storage).await.unwrap(), 0);
+
+        storage.update_fragment_embedding(&fragment, &[1.0, 0.0]).await.unwrap();
+        assert_eq!(mark_embedded_documents(&mut storage).await.unwrap(), 1);
+        assert_eq!(storage.list_index_state(IndexState::Embedded).await.unwrap(), vec![committed]);
+        assert!(storage.list_index_state(IndexState::Chunked).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_commits_and_embeddings_publish_dataset_versions() {
+        let mut storage = LanceDBStorage::new(Path::new("versions")).await.unwrap();
+        let mut embedding_manager = EmbeddingManager::hashing(16);
+        assert_eq!(data_version(&mut storage).await.unwrap(), 0);
+
+        let document = storage.store_document(Path::new("ops.md"), b"ops").await.unwrap();
+        storage.store_text_fragment(&document, 0, "Backups run nightly", &FragmentMeta::default()).await.unwrap();
+        // Writing fragments publishes nothing until the document is complete
+        assert_eq!(data_version(&mut storage).await.unwrap(), 0);
+        assert_eq!(publish_document(&mut storage, &document).await.unwrap(), 1);
+        assert_eq!(storage.list_index_state(IndexState::Chunked).await.unwrap(), vec![document]);
+
+        embed_fragment_batch(&mut storage, &mut embedding_manager, 10).await.unwrap();
+        assert_eq!(data_version(&mut storage).await.unwrap(), 2);
+        // A batch with nothing to embed changes nothing
+        embed_fragment_batch(&mut storage, &mut embedding_manager, 10).await.unwrap();
+        assert_eq!(data_version(&mut storage).await.unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_corpus_stats_count_types_and_merge_across_stores() {
+        let mut storage = LanceDBStorage::new(Path::new("stats")).await.unwrap();
+        let manual = storage.store_document(Path::new("manual.pdf"), b"a long manual").await.unwrap();
+        storage.store_text_fragment(&manual, 0, "abcd", &FragmentMeta::default()).await.unwrap();
+        storage.store_text_fragment(&manual, 1, "ef", &FragmentMeta::default()).await.unwrap();
+        storage.store_document(Path::new("notes.md"), b"notes").await.unwrap();
+        storage.store_document(Path::new("todo.MD"), b"todo").await.unwrap();
+
+        let stats = storage.corpus_stats(2).await.unwrap();
+        assert_eq!(stats.file_types, vec![("md".to_string(), 2), ("pdf".to_string(), 1)]);
+        assert_eq!((stats.documents, stats.fragments, stats.document_bytes), (3, 2, 22));
+        assert_eq!(stats.average_fragment_chars(), 3.0);
+        assert_eq!(stats.largest.len(), 2);
+        assert!(stats.largest[0].file_path.ends_with("manual.pdf"));
+        assert_eq!((stats.largest[0].bytes, stats.largest[0].fragments), (13, 2));
+
+        let mut merged = CorpusStats::default();
+        merged.merge(stats.clone(), 2);
+        merged.merge(stats, 2);
+        assert_eq!(merged.file_types, vec![("md".to_string(), 4), ("pdf".to_string(), 2)]);
+        assert_eq!((merged.documents, merged.fragments), (6, 4));
+        assert_eq!(merged.largest.len(), 2);
+        assert!(merged.largest.iter().all(|document| document.bytes == 13));
+    }
+
+    #[tokio::test]
+    async fn test_extract_document_writes_original_without_overwriting() {
+        let mut storage = LanceDBStorage::new(Path::new("extract")).await.unwrap();
+        let document = storage.store_document(Path::new("report.pdf"), b"%PDF-1.7 original").await.unwrap();
+        let dir = std::env::temp_dir().join(format!("portable-brains-extract-{}", Uuid::new_v4()));
+
+        let first = extract_document(&mut storage, &document, &dir).await.unwrap();
+        let second = extract_document(&mut storage, &document, &dir).await.unwrap();
+        assert_eq!(first, dir.join("report.pdf"));
+        assert_eq!(second, dir.join("report (2).pdf"));
+        assert_eq!(std::fs::read(&second).unwrap(), b"%PDF-1.7 original");
+        assert!(extract_document(&mut storage, "missing", &dir).await.is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_originals_stored_compressed_or_not_at_all() {
+        let mut storage = LanceDBStorage::new(Path::new("originals")).await.unwrap();
+        let original = "The quarterly report. ".repeat(200).into_bytes();
+        let full = storage.store_document(Path::new("full.txt"), &original).await.unwrap();
+
+        storage.set_originals_mode(OriginalsMode::Compressed).await.unwrap();
+        let compressed = storage.store_document(Path::new("compressed.txt"), &original).await.unwrap();
+        let (blob, encoding) = OriginalsMode::Compressed.encode(&original).unwrap();
+        assert!(blob.len() < original.len() / 10);
+        assert_eq!(decode_original(encoding, blob).unwrap().as_deref(), Some(original.as_slice()));
+
+        storage.set_originals_mode(OriginalsMode::None).await.unwrap();
+        let skipped = storage.store_document(Path::new("skipped.txt"), &original).await.unwrap();
+        assert_eq!(storage.get_meta_value(STORE_ORIGINALS_KEY).await.unwrap().as_deref(), Some("none"));
+
+        // Documents already stored keep the form they were stored in
+        for document in [&full, &compressed] {
+            assert_eq!(storage.get_document(document).await.unwrap().unwrap().file_data, Some(original.clone()));
+        }
+        assert_eq!(storage.get_document(&skipped).await.unwrap().unwrap().file_data, None);
+        // The hash of the original is kept, so duplicates and --update still work
+        assert!(storage.find_duplicate(&content_hash(&original)).await.unwrap().is_some());
+        let dir = std::env::temp_dir().join(format!("portable-brains-originals-{}", Uuid::new_v4()));
+        assert!(extract_document(&mut storage, &skipped, &dir).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_similarity_metric_orders_results() {
+        let mut storage = LanceDBStorage::new(Path::new("metric")).await.unwrap();
+        let document = storage.store_document(Path::new("vectors.txt"), b"vectors").await.unwrap();
+        let short = storage.store_text_fragment(&document, 0, "Short vector", &FragmentMeta::default()).await.unwrap();
+        let long = storage.store_text_fragment(&document, 1, "Long vector", &FragmentMeta::default()).await.unwrap();
+        storage.update_fragment_embedding(&short, &[1.0, 0.0]).await.unwrap();
+        storage.update_fragment_embedding(&long, &[3.0, 1.0]).await.unwrap();
+
+        let mut ranked = Vec::new();
+        for metric in [SimilarityMetric::Cosine, SimilarityMetric::Dot, SimilarityMetric::Euclidean] {
+            storage.set_similarity_metric(metric).await.unwrap();
+            let hits = storage.search_similar(&[1.0, 0.0], 2, &SearchFilter::default()).await.unwrap();
+            ranked.push((hits[0].fragment_id == long, hits[0].score));
+        }
+        // Only the inner product favours the longer vector pointing slightly away
+        assert_eq!(ranked[0], (false, 1.0));
+        assert_eq!(ranked[1], (true, 3.0));
+        assert_eq!(ranked[2], (false, 1.0));
+        assert_eq!(similarity_metric(&mut storage).await.unwrap(), SimilarityMetric::Euclidean);
+        assert!((SimilarityMetric::Euclidean.score(&[1.0, 0.0], &[3.0, 1.0]) - 1.0 / (1.0 + 5f64.sqrt())).abs() < 1e-9);
+    }
+
+    #[tokio::test]
+    async fn test_hybrid_search_fuses_keyword_matches() {
+        let mut storage = LanceDBStorage::new(Path::new("hybrid")).await.unwrap();
+        let document = storage.store_document(Path::new("errors.txt"), b"errors").await.unwrap();
+        let fragments = [
+            ("Uploads fail when the network drops.", [1.0, 0.0]),
+            ("Error E1042 means the retry limit was reached.", [0.6, 0.8]),
+            ("Quarterly revenue grew.", [0.0, 1.0]),
+        ];
+        let mut ids = Vec::new();
+        for (order, (content, embedding)) in fragments.iter().enumerate() {
+            let id = storage.store_text_fragment(&document, order as i32, content, &FragmentMeta::default()).await.unwrap();
+            storage.update_fragment_embedding(&id, embedding).await.unwrap();
+            ids.push(id);
+        }
+        let filter = SearchFilter::default();
+
+        let keyword = storage.search_keyword("what is E1042", 5, &filter).await.unwrap();
+        assert_eq!(keyword.len(), 1);
+        assert_eq!(keyword[0].fragment_id, ids[1]);
+
+        let hybrid = storage.search_hybrid("what is E1042", &[1.0, 0.0], 2, &filter).await.unwrap();
+        assert_eq!(hybrid[0].fragment.fragment_id, ids[1]);
+        assert_eq!(hybrid[1].fragment.fragment_id, ids[0]);
+        assert_eq!(hybrid[1].sparse_score, None);
+    }
+
+    #[tokio::test]
+    async fn test_fragment_batches_round_trip() {
+        let mut source = LanceDBStorage::new(Path::new("batches")).await.unwrap();
+        let mut target = LanceDBStorage::new(Path::new("batches-copy")).await.unwrap();
+        source.enable_deterministic_ids().await.unwrap();
+        target.enable_deterministic_ids().await.unwrap();
+
+        let document = source.store_document(Path::new("guide.md"), b"guide").await.unwrap();
+        assert_eq!(target.store_document(Path::new("guide.md"), b"guide").await.unwrap(), document);
+        let meta = FragmentMeta { section: Some("Setup".to_string()), structure: Some(Structure::Code), ..FragmentMeta::default() };
+        for order in 0..3 {
+            let id = source.store_text_fragment(&document, order, &format!("Step {} of the setup", order), &meta).await.unwrap();
+            if order > 0 {
+                source.update_fragment_embedding(&id, &[0.5, order as f64]).await.unwrap();
+            }
+        }
+
+        let mut after: Option<String> = None;
+        let mut copied = 0;
+        while let Some(batch) = source.read_fragment_batch(after.as_deref(), 2).await.unwrap() {
+            assert!(batch.num_rows() <= 2);
+            let ids = fragment_column::<arrow::array::StringArray>(&batch, "id").unwrap();
+            after = Some(ids.value(ids.len() - 1).to_string());
+            copied += target.write_fragment_batch(&batch).await.unwrap();
+        }
+        assert_eq!(copied, 3);
+
+        let original = source.get_fragment_records(&document).await.unwrap();
+        let copy = target.get_fragment_records(&document).await.unwrap();
+        assert_eq!(serde_json::to_value(&copy).unwrap(), serde_json::to_value(&original).unwrap());
+        assert_eq!(target.count_fragments_without_embeddings().await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_failed_fragments_set_aside_until_retried() {
+        let mut storage = LanceDBStorage::new(Path::new("failures")).await.unwrap();
+        let document = storage.store_document(Path::new("notes.txt"), b"notes").await.unwrap();
+        let first = storage.store_text_fragment(&document, 0, "Backups run nightly", &FragmentMeta::default()).await.unwrap();
+        let second = storage.store_text_fragment(&document, 1, "Restores are tested monthly", &FragmentMeta::default()).await.unwrap();
+
+        storage.record_embedding_failures(&[(first.clone(), "payload too large".to_string())]).await.unwrap();
+        let pending = storage.get_fragments_without_embeddings(10).await.unwrap();
+        assert_eq!(pending.iter().map(|(id, _)| id).collect::<Vec<_>>(), vec![&second]);
+        assert_eq!(storage.count_fragments_without_embeddings().await.unwrap(), 1);
+        assert_eq!(storage.count_embedding_failures().await.unwrap(), 1);
+
+        assert_eq!(storage.retry_embedding_failures().await.unwrap(), 1);
+        assert_eq!(storage.count_fragments_without_embeddings().await.unwrap(), 2);
+        assert_eq!(storage.retry_embedding_failures().await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_search_results_carry_sources() {
+        let mut storage = LanceDBStorage::new(Path::new("sources")).await.unwrap();
+        let document = storage.store_document(Path::new("reports/annual.pdf"), b"annual").await.unwrap();
+        let meta = FragmentMeta {
+            section: Some("Results > Costs".to_string()),
+            page: Some(12),
+            structure: Some(Structure::Table),
+            ..FragmentMeta::default()
+        };
+        let id = storage.store_text_fragment(&document, 31, "| Year | Costs |\n| 2023 | 9.1 |", &meta).await.unwrap();
+        storage.update_fragment_embedding(&id, &[1.0, 0.0]).await.unwrap();
+
+        let hits = storage.search_similar(&[1.0, 0.0], 1, &SearchFilter::default()).await.unwrap();
+        let source = &hits[0].source;
+        assert_eq!(source.filename, "annual.pdf");
+        assert_eq!(source.order, 31);
+        assert_eq!(source.structure, Some(Structure::Table));
+        assert_eq!(source.citation(), "annual.pdf, p. 12, Results > Costs, fragment 31");
+        assert_eq!(source.document_id, document);
+        assert_eq!(source.anchor(&hits[0].content).as_deref(), Some("page=12"));
+
+        let deck = storage.store_document(Path::new("deck.pptx"), b"deck").await.unwrap();
+        let meta = FragmentMeta { location: Some(Location::Slide(12)), ..FragmentMeta::default() };
+        let id = storage.store_text_fragment(&deck, 40, "Roadmap for next year", &meta).await.unwrap();
+        storage.update_fragment_embedding(&id, &[0.0, 1.0]).await.unwrap();
+        storage.set_document_title(&deck, "Quarterly Review").await.unwrap();
+
+        let hits = storage.search_similar(&[0.0, 1.0], 1, &SearchFilter::default()).await.unwrap();
+        assert_eq!(hits[0].source.citation(), "Quarterly Review (deck.pptx), slide 12, fragment 40");
+        let documents = storage.list_documents().await.unwrap();
+        assert_eq!(documents.iter().find(|d| d.id == deck).unwrap().title.as_deref(), Some("Quarterly Review"));
+        assert_eq!(Location::parse(&Location::Sheet("Q3 Forecast".to_string()).label()), Some(Location::Sheet("Q3 Forecast".to_string())));
+    }
+
+    #[test]
+    fn test_html_anchors_quote_the_fragment() {
+        let source = FragmentSource { filename: "guide.html".to_string(), ..FragmentSource::default() };
+        assert_eq!(source.anchor("Set-up, step 1").as_deref(), Some(":~:text=Set%2Dup%2C%20step%201"));
+        assert_eq!(
+            source.anchor("one two three four five six seven eight nine ten eleven").as_deref(),
+            Some(":~:text=one%20two%20three%20four%20five,seven%20eight%20nine%20ten%20eleven"),
+        );
+        let notes = FragmentSource { filename: "notes.txt".to_string(), ..FragmentSource::default() };
+        assert_eq!(notes.anchor("anything"), None);
+    }
+
+    #[tokio::test]
+    async fn test_document_filter_and_lookup() {
+        let mut storage = LanceDBStorage::new(Path::new("focus")).await.unwrap();
+        let mut documents = Vec::new();
+        for path in ["q3/report.pdf", "q4/report.pdf", "notes.txt"] {
+            let document = storage.store_document(Path::new(path), path.as_bytes()).await.unwrap();
+            let id = storage.store_text_fragment(&document, 0, &format!("Contents of {}", path), &FragmentMeta::default()).await.unwrap();
+            storage.update_fragment_embedding(&id, &[1.0, 0.0]).await.unwrap();
+            documents.push(document);
+        }
+
+        assert_eq!(find_document(&mut storage, "NOTES.TXT").await.unwrap().id, documents[2]);
+        assert_eq!(find_document(&mut storage, "q4/report.pdf").await.unwrap().id, documents[1]);
+        assert!(find_document(&mut storage, &documents[0]).await.unwrap().file_path.ends_with("q3/report.pdf"));
+        assert!(find_document(&mut storage, "report.pdf").await.is_err());
+        assert!(find_document(&mut storage, "missing.pdf").await.is_err());
+
+        let filter = SearchFilter { document: Some(documents[1].clone()), ..SearchFilter::default() };
+        let hits = storage.search_similar(&[1.0, 0.0], 5, &filter).await.unwrap();
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].content, "Contents of q4/report.pdf");
+        assert_eq!(storage.search_keyword("contents", 5, &filter).await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_entity_lookup_and_filter() {
+        let mut storage = LanceDBStorage::new(Path::new("entities")).await.unwrap();
+        let fragments = [
+            ("team.md", "The rollout was approved by Maria Chen last spring."),
+            ("team.md", "Budget questions go to Chen as well."),
+            ("kitchen.md", "The kitchen rota is pinned by the fridge."),
+            ("atlas.md", "Project Atlas replaced the old reporting stack."),
+        ];
+        for (order, (path, text)) in fragments.iter().enumerate() {
+            let document = match storage.list_documents().await.unwrap().into_iter().find(|d| d.file_path.ends_with(path)) {
+                Some(document) => document.id,
+                None => storage.store_document(Path::new(path), path.as_bytes()).await.unwrap(),
+            };
+            let id = storage.store_text_fragment(&document, order as i32, text, &FragmentMeta::default()).await.unwrap();
+            storage.update_fragment_embedding(&id, &[1.0, 0.0]).await.unwrap();
+        }
+
+        let mentions = storage.find_entity("chen").await.unwrap();
+        assert_eq!(mentions.len(), 1);
+        assert!(mentions[0].file_path.ends_with("team.md"));
+        assert_eq!(mentions[0].fragments, 2);
+        assert_eq!(mentions[0].names, vec!["Chen", "Maria Chen"]);
+        // Equally mentioned entities are listed by name
+        let entities = storage.list_entities(2).await.unwrap();
+        assert_eq!(entities.iter().map(|e| e.name.as_str()).collect::<Vec<_>>(), vec!["Chen", "Maria Chen"]);
+        assert_eq!((entities[1].fragments, entities[1].documents), (1, 1));
+
+        // "kitchen" is not a mention of Chen, although it contains the name
+        let filter = SearchFilter { entity: Some("Chen".to_string()), ..SearchFilter::default() };
+        let hits = storage.search_similar(&[1.0, 0.0], 5, &filter).await.unwrap();
+        assert_eq!(hits.len(), 2);
+        assert!(hits.iter().all(|hit| hit.source.file_path.ends_with("team.md")));
+        assert_eq!(storage.search_keyword("kitchen", 5, &filter).await.unwrap().len(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_metadata_filters() {
+        let mut storage = LanceDBStorage::new(Path::new("metadata")).await.unwrap();
+        let documents = [
+            ("finance/q3-report.pdf", Some("finance"), vec!["q3".to_string()], "2024-03-01"),
+            ("finance/budget.xlsx", Some("finance"), Vec::new(), "2024-08-15"),
+            ("legal/nda.pdf", Some("legal"), vec!["contracts".to_string()], "2023-11-20"),
+        ];
+        for (order, (path, collection, tags, modified)) in documents.iter().enumerate() {
+            let document = storage.store_document(Path::new(path), path.as_bytes()).await.unwrap();
+            storage.set_document_collection(&document, *collection, tags).await.unwrap();
+            storage.set_document_source(&document, &content_hash(path.as_bytes()), Some(parse_date(modified, false).unwrap())).await.unwrap();
+            let id = storage.store_text_fragment(&document, order as i32, "quarterly figures", &FragmentMeta::default()).await.unwrap();
+            storage.update_fragment_embedding(&id, &[1.0, 0.0]).await.unwrap();
+        }
+
+        async fn search(storage: &mut LanceDBStorage, filter: SearchFilter) -> Vec<String> {
+            let hits = storage.search_similar(&[1.0, 0.0], 5, &filter).await.unwrap();
+            let mut names: Vec<String> = hits.into_iter().map(|hit| hit.source.filename).collect();
+            names.sort();
+            names
+        }
+        let pdfs = SearchFilter { file_types: vec!["pdf".to_string()], ..Default::default() };
+        assert_eq!(search(&mut storage, pdfs).await, vec!["nda.pdf", "q3-report.pdf"]);
+        let tagged = SearchFilter { tags: vec!["q3".to_string(), "contracts".to_string()], ..Default::default() };
+        assert_eq!(search(&mut storage, tagged).await, vec!["nda.pdf", "q3-report.pdf"]);
+        let finance = SearchFilter { path: Some("finance/*".to_string()), ..Default::default() };
+        assert_eq!(search(&mut storage, finance).await, vec!["budget.xlsx", "q3-report.pdf"]);
+        let (first_half, _) = SearchFilter::parse_metadata("since:2024-01-01 until:2024-06-30").unwrap();
+        assert_eq!(search(&mut storage, first_half).await, vec!["q3-report.pdf"]);
+    }
+
+    #[tokio::test]
+    async fn test_bulk_update_by_where() {
+        let filter = SearchFilter::parse_where(r#"type IN ("pptx", ".PDF") AND collection = "old" AND modified < "2024-01-01""#).unwrap();
+        assert_eq!(filter.file_types, vec!["pptx", "pdf"]);
+        assert_eq!(filter.collections, vec!["old"]);
+        assert_eq!(filter.modified_before, Some(parse_date("2024-01-01", false).unwrap()));
+        assert!(SearchFilter::parse_where(r#"collection = "a" OR collection = "b""#).is_err());
+        assert!(SearchFilter::parse_where(r#"tag = "a" AND tag = "b""#).is_err());
+        assert!(SearchFilter::parse_where(r#"colour = "red""#).is_err());
+
+        let mut storage = LanceDBStorage::new(Path::new("bulk")).await.unwrap();
+        for (path, collection) in [("old/deck.pptx", "old"), ("old/notes.md", "old"), ("new/deck.pptx", "new")] {
+            let document = storage.store_document(Path::new(path), path.as_bytes()).await.unwrap();
+            storage.set_document_collection(&document, Some(collection), &["draft".to_string()]).await.unwrap();
+        }
+
+        let old = SearchFilter::parse_where(r#"collection = "old""#).unwrap();
+        let archive = BulkAction::Reorganize {
+            add_tags: vec!["archived".to_string()],
+            remove_tags: vec!["draft".to_string()],
+            collection: Some(Some("archive".to_string())),
+        };
+        assert_eq!(storage.bulk_update(&old, &archive).await.unwrap(), 2);
+        let archived = SearchFilter::parse_where(r#"collection = "archive" AND tag = "archived""#).unwrap();
+        assert_eq!(matching_documents(&mut storage, &archived).await.unwrap().len(), 2);
+        assert!(matching_documents(&mut storage, &old).await.unwrap().is_empty());
+
+        let decks = SearchFilter::parse_where(r#"file_type = "pptx" AND collection = "archive""#).unwrap();
+        assert_eq!(storage.bulk_update(&decks, &BulkAction::Delete).await.unwrap(), 1);
+        let remaining: Vec<String> = storage.list_documents().await.unwrap().into_iter().map(|d| d.file_path).collect();
+        assert_eq!(remaining.len(), 2);
+        assert!(remaining.iter().any(|path| path.ends_with("old/notes.md")));
+        assert!(remaining.iter().any(|path| path.ends_with("new/deck.pptx")));
+    }
+
+    #[tokio::test]
+    async fn test_annotations_surface_and_exclude() {
+        use crate::annotations::AnnotationLabel;
+
+        let mut storage = LanceDBStorage::new(Path::new("annotated")).await.unwrap();
+        let mut fragments = Vec::new();
+        for path in ["policy/2019.pdf", "policy/2024.pdf"] {
+            let document = storage.store_document(Path::new(path), path.as_bytes()).await.unwrap();
+            let id = storage.store_text_fragment(&document, 0, "retention policy", &FragmentMeta::default()).await.unwrap();
+            storage.update_fragment_embedding(&id, &[1.0, 0.0]).await.unwrap();
+            fragments.push((document, id));
+        }
+        let outdated = Annotation::new(&fragments[0].0, Some(AnnotationLabel::Outdated), Some("superseded".to_string()), "alice");
+        storage.add_annotation(&outdated).await.unwrap();
+        storage.add_annotation(&Annotation::new(&fragments[1].1, Some(AnnotationLabel::Wrong), None, "bob")).await.unwrap();
+
+        // Document annotations apply to each of its fragments; wrong ones are never returned
+        let hits = storage.search_similar(&[1.0, 0.0], 5, &SearchFilter::default()).await.unwrap();
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].source.annotations, vec![outdated.summary()]);
+        assert!(hits[0].source.citation().ends_with("marked outdated"));
+
+        assert!(storage.remove_annotation(&outdated.id).await.unwrap());
+        assert!(!storage.remove_annotation(&outdated.id).await.unwrap());
+        assert_eq!(storage.list_annotations(None).await.unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_content_hash() {
+        assert_eq!(content_hash(b"abc"), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+        assert_ne!(content_hash(b"abc"), content_hash(b"abd"));
+        assert_eq!(modified_micros(Path::new("/nonexistent/portable-brains")), None);
+    }
+}
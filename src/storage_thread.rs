@@ -0,0 +1,425 @@
+use anyhow::{anyhow, Context, Result};
+use async_trait::async_trait;
+use futures::future::BoxFuture;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::mpsc;
+use std::thread::JoinHandle;
+use tokio::sync::oneshot;
+
+use crate::annotations::Annotation;
+use crate::error;
+use crate::hybrid::FusedHit;
+use crate::storage::arrow::array::RecordBatch;
+use crate::storage::{BulkAction, CorpusStats, DocumentAttributes, DocumentInfo, DocumentSummary, EntityCount, EntityMention, FragmentChanges, FragmentMatch, FragmentMeta, FragmentRecord, IndexState, MetaInfo, OriginalsMode, PendingFragment, SearchFilter, SimilarityMetric, Storage, VectorIndexCoverage};
+
+/// A call waiting to run against the storage the thread owns
+type Task = Box<dyn for<'a> FnOnce(&'a mut dyn Storage) -> BoxFuture<'a, ()> + Send>;
+
+/// Runs a storage backend whose calls block, such as DuckDB, on a thread of its own. Each
+/// call is sent to the thread with owned copies of its arguments and awaited, so the
+/// Tokio worker that made it stays free for other tasks (server requests, the watcher)
+/// while the database works. Calls run one at a time in the order they were made.
+pub struct StorageThread {
+    tasks: Option<mpsc::Sender<Task>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl StorageThread {
+    /// Move `storage` onto a new thread that serves calls until this handle is dropped
+    pub fn spawn(mut storage: Box<dyn Storage>) -> Result<Self> {
+        let (tasks, queued) = mpsc::channel::<Task>();
+        let thread = std::thread::Builder::new()
+            .name("storage".to_string())
+            .spawn(move || {
+                // The backend's futures finish without waking anything else, so a
+                // current-thread runtime is enough to drive them
+                let runtime = match tokio::runtime::Builder::new_current_thread().enable_all().build() {
+                    Ok(runtime) => runtime,
+                    Err(e) => {
+                        log::error!("Failed to start the storage thread's runtime: {}", e);
+                        return;
+                    }
+                };
+                for task in queued {
+                    runtime.block_on(task(&mut *storage));
+                }
+            })
+            .context("Failed to start the storage thread")?;
+
+        Ok(Self { tasks: Some(tasks), thread: Some(thread) })
+    }
+
+    /// Run `call` against the storage on its thread and wait for the result without
+    /// blocking the caller's runtime. Failures come back typed as database errors, so
+    /// callers can tell a locked database from a broken one.
+    async fn call<T, F>(&self, call: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: for<'a> FnOnce(&'a mut dyn Storage) -> BoxFuture<'a, Result<T>> + Send + 'static,
+    {
+        let (reply, result) = oneshot::channel();
+        let task: Task = Box::new(move |storage| Box::pin(async move {
+            let _ = reply.send(call(storage).await);
+        }));
+        self.tasks.as_ref()
+            .and_then(|tasks| tasks.send(task).ok())
+            .ok_or_else(|| error::database(anyhow!("The storage thread has stopped")))?;
+        let result = result.await.map_err(|_| error::database(anyhow!("The storage thread stopped during a call")))?;
+        result.map_err(error::database)
+    }
+}
+
+impl Drop for StorageThread {
+    /// Close the storage before returning, so the database is released (and DuckDB has
+    /// checkpointed) by the time the handle is gone
+    fn drop(&mut self) {
+        self.tasks.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[async_trait]
+impl Storage for StorageThread {
+    async fn initialize(&mut self) -> Result<()> {
+        self.call(|storage| Box::pin(storage.initialize())).await
+    }
+
+    async fn verify_or_set_model(&mut self, model_name: &str, dimension: Option<usize>) -> Result<()> {
+        let model_name = model_name.to_string();
+        self.call(move |storage| Box::pin(async move { storage.verify_or_set_model(&model_name, dimension).await })).await
+    }
+
+    async fn enable_deterministic_ids(&mut self) -> Result<()> {
+        self.call(|storage| Box::pin(storage.enable_deterministic_ids())).await
+    }
+
+    async fn set_originals_mode(&mut self, mode: OriginalsMode) -> Result<()> {
+        self.call(move |storage| Box::pin(storage.set_originals_mode(mode))).await
+    }
+
+    async fn set_similarity_metric(&mut self, metric: SimilarityMetric) -> Result<()> {
+        self.call(move |storage| Box::pin(storage.set_similarity_metric(metric))).await
+    }
+
+    async fn document_exists(&mut self, file_path: &Path) -> Result<bool> {
+        let file_path = file_path.to_path_buf();
+        self.call(move |storage| Box::pin(async move { storage.document_exists(&file_path).await })).await
+    }
+
+    async fn find_duplicate(&mut self, content_hash: &str) -> Result<Option<String>> {
+        let content_hash = content_hash.to_string();
+        self.call(move |storage| Box::pin(async move { storage.find_duplicate(&content_hash).await })).await
+    }
+
+    async fn store_document(&mut self, file_path: &Path, file_data: &[u8]) -> Result<String> {
+        let (file_path, file_data) = (file_path.to_path_buf(), file_data.to_vec());
+        self.call(move |storage| Box::pin(async move { storage.store_document(&file_path, &file_data).await })).await
+    }
+
+    async fn set_index_state(&mut self, document_id: &str, state: IndexState) -> Result<()> {
+        let document_id = document_id.to_string();
+        self.call(move |storage| Box::pin(async move { storage.set_index_state(&document_id, state).await })).await
+    }
+
+    async fn list_index_state(&mut self, state: IndexState) -> Result<Vec<String>> {
+        self.call(move |storage| Box::pin(storage.list_index_state(state))).await
+    }
+
+    async fn set_document_source(&mut self, document_id: &str, content_hash: &str, modified: Option<i64>) -> Result<()> {
+        let (document_id, content_hash) = (document_id.to_string(), content_hash.to_string());
+        self.call(move |storage| Box::pin(async move { storage.set_document_source(&document_id, &content_hash, modified).await })).await
+    }
+
+    async fn set_document_priority(&mut self, document_id: &str, priority: i32) -> Result<()> {
+        let document_id = document_id.to_string();
+        self.call(move |storage| Box::pin(async move { storage.set_document_priority(&document_id, priority).await })).await
+    }
+
+    async fn set_document_category(&mut self, document_id: &str, category: &str, score: f64) -> Result<()> {
+        let (document_id, category) = (document_id.to_string(), category.to_string());
+        self.call(move |storage| Box::pin(async move { storage.set_document_category(&document_id, &category, score).await })).await
+    }
+
+    async fn set_document_collection(&mut self, document_id: &str, collection: Option<&str>, tags: &[String]) -> Result<()> {
+        let (document_id, collection, tags) = (document_id.to_string(), collection.map(str::to_string), tags.to_vec());
+        self.call(move |storage| Box::pin(async move {
+            storage.set_document_collection(&document_id, collection.as_deref(), &tags).await
+        })).await
+    }
+
+    async fn get_document_collection(&mut self, document_id: &str) -> Result<Option<String>> {
+        let document_id = document_id.to_string();
+        self.call(move |storage| Box::pin(async move { storage.get_document_collection(&document_id).await })).await
+    }
+
+    async fn get_document_attributes(&mut self, document_id: &str) -> Result<Option<DocumentAttributes>> {
+        let document_id = document_id.to_string();
+        self.call(move |storage| Box::pin(async move { storage.get_document_attributes(&document_id).await })).await
+    }
+
+    async fn set_document_quality(&mut self, document_id: &str, score: f64, flags: &[String]) -> Result<()> {
+        let (document_id, flags) = (document_id.to_string(), flags.to_vec());
+        self.call(move |storage| Box::pin(async move { storage.set_document_quality(&document_id, score, &flags).await })).await
+    }
+
+    async fn set_document_title(&mut self, document_id: &str, title: &str) -> Result<()> {
+        let (document_id, title) = (document_id.to_string(), title.to_string());
+        self.call(move |storage| Box::pin(async move { storage.set_document_title(&document_id, &title).await })).await
+    }
+
+    async fn tombstone_document(&mut self, document_id: &str, tombstoned: bool) -> Result<()> {
+        let document_id = document_id.to_string();
+        self.call(move |storage| Box::pin(async move { storage.tombstone_document(&document_id, tombstoned).await })).await
+    }
+
+    async fn remove_document(&mut self, document_id: &str) -> Result<()> {
+        let document_id = document_id.to_string();
+        self.call(move |storage| Box::pin(async move { storage.remove_document(&document_id).await })).await
+    }
+
+    async fn bulk_update(&mut self, filter: &SearchFilter, action: &BulkAction) -> Result<usize> {
+        let filter = filter.clone();
+        let action = action.clone();
+        self.call(move |storage| Box::pin(async move { storage.bulk_update(&filter, &action).await })).await
+    }
+
+    async fn list_documents(&mut self) -> Result<Vec<DocumentSummary>> {
+        self.call(|storage| Box::pin(storage.list_documents())).await
+    }
+
+    async fn get_document(&mut self, document_id: &str) -> Result<Option<DocumentInfo>> {
+        let document_id = document_id.to_string();
+        self.call(move |storage| Box::pin(async move { storage.get_document(&document_id).await })).await
+    }
+
+    async fn set_document_text(&mut self, document_id: &str, text: &[u8]) -> Result<()> {
+        let (document_id, text) = (document_id.to_string(), text.to_vec());
+        self.call(move |storage| Box::pin(async move { storage.set_document_text(&document_id, &text).await })).await
+    }
+
+    async fn get_document_text(&mut self, document_id: &str) -> Result<Option<Vec<u8>>> {
+        let document_id = document_id.to_string();
+        self.call(move |storage| Box::pin(async move { storage.get_document_text(&document_id).await })).await
+    }
+
+    async fn replace_fragments(
+        &mut self,
+        document_id: &str,
+        fragments: &[(String, FragmentMeta)],
+    ) -> Result<FragmentChanges> {
+        let (document_id, fragments) = (document_id.to_string(), fragments.to_vec());
+        self.call(move |storage| Box::pin(async move { storage.replace_fragments(&document_id, &fragments).await })).await
+    }
+
+    async fn get_document_fragments(&mut self, document_id: &str, offset: i32, limit: i32) -> Result<Vec<String>> {
+        let document_id = document_id.to_string();
+        self.call(move |storage| Box::pin(async move { storage.get_document_fragments(&document_id, offset, limit).await })).await
+    }
+
+    async fn get_fragment_records(&mut self, document_id: &str) -> Result<Vec<FragmentRecord>> {
+        let document_id = document_id.to_string();
+        self.call(move |storage| Box::pin(async move { storage.get_fragment_records(&document_id).await })).await
+    }
+
+    async fn read_fragment_batch(&mut self, after: Option<&str>, limit: usize) -> Result<Option<RecordBatch>> {
+        let after = after.map(str::to_string);
+        self.call(move |storage| Box::pin(async move { storage.read_fragment_batch(after.as_deref(), limit).await })).await
+    }
+
+    async fn write_fragment_batch(&mut self, batch: &RecordBatch) -> Result<usize> {
+        // Cloning a batch only clones references to its columns
+        let batch = batch.clone();
+        self.call(move |storage| Box::pin(async move { storage.write_fragment_batch(&batch).await })).await
+    }
+
+    async fn store_text_fragment(
+        &mut self,
+        document_id: &str,
+        order: i32,
+        content: &str,
+        meta: &FragmentMeta,
+    ) -> Result<String> {
+        let (document_id, content, meta) = (document_id.to_string(), content.to_string(), meta.clone());
+        self.call(move |storage| Box::pin(async move {
+            storage.store_text_fragment(&document_id, order, &content, &meta).await
+        })).await
+    }
+
+    async fn update_fragment_embedding(
+        &mut self,
+        fragment_id: &str,
+        embedding: &[f64],
+    ) -> Result<()> {
+        let (fragment_id, embedding) = (fragment_id.to_string(), embedding.to_vec());
+        self.call(move |storage| Box::pin(async move { storage.update_fragment_embedding(&fragment_id, &embedding).await })).await
+    }
+
+    async fn update_fragment_embeddings_batch(&mut self, embeddings: &[(String, Vec<f32>)]) -> Result<()> {
+        let embeddings = embeddings.to_vec();
+        self.call(move |storage| Box::pin(async move { storage.update_fragment_embeddings_batch(&embeddings).await })).await
+    }
+
+    async fn clear_fragment_text(&mut self, fragment_ids: &[String]) -> Result<()> {
+        let fragment_ids = fragment_ids.to_vec();
+        self.call(move |storage| Box::pin(async move { storage.clear_fragment_text(&fragment_ids).await })).await
+    }
+
+    async fn get_pending_fragments(&mut self, limit: i32) -> Result<Vec<PendingFragment>> {
+        self.call(move |storage| Box::pin(storage.get_pending_fragments(limit))).await
+    }
+
+    async fn count_fragments_without_embeddings(&mut self) -> Result<i32> {
+        self.call(|storage| Box::pin(storage.count_fragments_without_embeddings())).await
+    }
+
+    async fn get_embeddings_by_chunk(&mut self, chunk_hashes: &[String]) -> Result<HashMap<String, Vec<f32>>> {
+        let chunk_hashes = chunk_hashes.to_vec();
+        self.call(move |storage| Box::pin(async move { storage.get_embeddings_by_chunk(&chunk_hashes).await })).await
+    }
+
+    async fn record_embedding_failures(&mut self, failures: &[(String, String)]) -> Result<()> {
+        let failures = failures.to_vec();
+        self.call(move |storage| Box::pin(async move { storage.record_embedding_failures(&failures).await })).await
+    }
+
+    async fn retry_embedding_failures(&mut self) -> Result<i32> {
+        self.call(|storage| Box::pin(storage.retry_embedding_failures())).await
+    }
+
+    async fn count_embedding_failures(&mut self) -> Result<i32> {
+        self.call(|storage| Box::pin(storage.count_embedding_failures())).await
+    }
+
+    async fn build_vector_index(&mut self) -> Result<bool> {
+        self.call(|storage| Box::pin(storage.build_vector_index())).await
+    }
+
+    async fn vector_index_coverage(&mut self) -> Result<Option<VectorIndexCoverage>> {
+        self.call(|storage| Box::pin(storage.vector_index_coverage())).await
+    }
+
+    async fn mark_embeddings_stale(&mut self) -> Result<i32> {
+        self.call(|storage| Box::pin(storage.mark_embeddings_stale())).await
+    }
+
+    async fn count_stale_fragments(&mut self) -> Result<i32> {
+        self.call(|storage| Box::pin(storage.count_stale_fragments())).await
+    }
+
+    async fn corpus_stats(&mut self, largest: usize) -> Result<CorpusStats> {
+        self.call(move |storage| Box::pin(storage.corpus_stats(largest))).await
+    }
+
+    async fn record_fragment_hits(&mut self, fragment_ids: &[String]) -> Result<()> {
+        let fragment_ids = fragment_ids.to_vec();
+        self.call(move |storage| Box::pin(async move { storage.record_fragment_hits(&fragment_ids).await })).await
+    }
+
+    async fn get_fragment_vectors(&mut self, fragment_ids: &[String]) -> Result<HashMap<String, Vec<f64>>> {
+        let fragment_ids = fragment_ids.to_vec();
+        self.call(move |storage| Box::pin(async move { storage.get_fragment_vectors(&fragment_ids).await })).await
+    }
+
+    async fn get_fragment_embeddings(&mut self, limit: usize) -> Result<Vec<(String, String, Vec<f64>)>> {
+        self.call(move |storage| Box::pin(storage.get_fragment_embeddings(limit))).await
+    }
+
+    async fn sample_fragments(&mut self, limit: usize) -> Result<Vec<(String, String, Vec<f64>)>> {
+        self.call(move |storage| Box::pin(storage.sample_fragments(limit))).await
+    }
+
+    async fn get_meta_info(&mut self) -> Result<MetaInfo> {
+        self.call(|storage| Box::pin(storage.get_meta_info())).await
+    }
+
+    async fn get_meta_value(&mut self, key: &str) -> Result<Option<String>> {
+        let key = key.to_string();
+        self.call(move |storage| Box::pin(async move { storage.get_meta_value(&key).await })).await
+    }
+
+    async fn set_meta_value(&mut self, key: &str, value: &str) -> Result<()> {
+        let (key, value) = (key.to_string(), value.to_string());
+        self.call(move |storage| Box::pin(async move { storage.set_meta_value(&key, &value).await })).await
+    }
+
+    async fn list_entities(&mut self, limit: usize) -> Result<Vec<EntityCount>> {
+        self.call(move |storage| Box::pin(storage.list_entities(limit))).await
+    }
+
+    async fn find_entity(&mut self, entity: &str) -> Result<Vec<EntityMention>> {
+        let entity = entity.to_string();
+        self.call(move |storage| Box::pin(async move { storage.find_entity(&entity).await })).await
+    }
+
+    async fn add_annotation(&mut self, annotation: &Annotation) -> Result<()> {
+        let annotation = annotation.clone();
+        self.call(move |storage| Box::pin(async move { storage.add_annotation(&annotation).await })).await
+    }
+
+    async fn list_annotations(&mut self, target: Option<&str>) -> Result<Vec<Annotation>> {
+        let target = target.map(str::to_string);
+        self.call(move |storage| Box::pin(async move { storage.list_annotations(target.as_deref()).await })).await
+    }
+
+    async fn remove_annotation(&mut self, annotation_id: &str) -> Result<bool> {
+        let annotation_id = annotation_id.to_string();
+        self.call(move |storage| Box::pin(async move { storage.remove_annotation(&annotation_id).await })).await
+    }
+
+    async fn search_similar(
+        &mut self,
+        query_embedding: &[f64],
+        limit: usize,
+        filter: &SearchFilter,
+    ) -> Result<Vec<FragmentMatch>> {
+        let (query_embedding, filter) = (query_embedding.to_vec(), filter.clone());
+        self.call(move |storage| Box::pin(async move { storage.search_similar(&query_embedding, limit, &filter).await })).await
+    }
+
+    async fn search_keyword(
+        &mut self,
+        query: &str,
+        limit: usize,
+        filter: &SearchFilter,
+    ) -> Result<Vec<FragmentMatch>> {
+        let (query, filter) = (query.to_string(), filter.clone());
+        self.call(move |storage| Box::pin(async move { storage.search_keyword(&query, limit, &filter).await })).await
+    }
+
+    async fn search_hybrid(
+        &mut self,
+        query: &str,
+        query_embedding: &[f64],
+        limit: usize,
+        filter: &SearchFilter,
+    ) -> Result<Vec<FusedHit>> {
+        // One trip to the thread for both searches
+        let (query, query_embedding, filter) = (query.to_string(), query_embedding.to_vec(), filter.clone());
+        self.call(move |storage| Box::pin(async move {
+            storage.search_hybrid(&query, &query_embedding, limit, &filter).await
+        })).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lancedb_storage::LanceDBStorage;
+
+    #[tokio::test]
+    async fn test_calls_run_on_the_storage_thread_in_order() {
+        let storage = LanceDBStorage::new(Path::new("thread")).await.unwrap();
+        let mut storage = StorageThread::spawn(Box::new(storage)).unwrap();
+
+        let document = storage.store_document(Path::new("notes.md"), b"notes").await.unwrap();
+        storage.store_text_fragment(&document, 0, "Backups run nightly.", &FragmentMeta::default()).await.unwrap();
+        storage.set_meta_value("owner", "ops").await.unwrap();
+
+        assert_eq!(storage.get_document_fragments(&document, 0, 10).await.unwrap(), vec!["Backups run nightly.".to_string()]);
+        assert_eq!(storage.get_meta_value("owner").await.unwrap().as_deref(), Some("ops"));
+        assert_eq!(storage.count_fragments_without_embeddings().await.unwrap(), 1);
+    }
+}
@@ -0,0 +1,382 @@
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+
+use crate::embedding_manager::EmbeddingManager;
+use crate::llm::{ChatMessage, LlmClient, TokenUsage};
+use crate::storage::{self, FragmentMatch, FragmentSource, SimilarityMetric, Storage};
+
+/// Meta key holding a database's summary tree as JSON
+pub const SUMMARY_TREE_KEY: &str = "summary_tree";
+
+/// Rounds of k-means before clusters are taken as they are
+const KMEANS_ITERATIONS: usize = 20;
+
+/// Longest summary asked of the model, in tokens
+const SUMMARY_MAX_TOKENS: u32 = 400;
+
+const SUMMARY_PROMPT: &str = "You summarize passages from a document collection for a search index. \
+Write one dense paragraph covering every topic, name, figure and date the passages mention, without \
+adding anything they don't say. Reply with the summary only.";
+
+/// How a summary tree is built
+#[derive(Debug, Clone, Copy)]
+pub struct TreeOptions {
+    /// Passages summarized together, on average
+    pub cluster_size: usize,
+    /// Levels of summaries above the fragments
+    pub levels: u32,
+    /// Fragments read, as a sample spread across documents when there are more
+    pub max_fragments: usize,
+}
+
+impl Default for TreeOptions {
+    fn default() -> Self {
+        Self { cluster_size: 8, levels: 3, max_fragments: 20_000 }
+    }
+}
+
+/// A summary of a cluster of fragments (level 1) or of summaries one level down
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SummaryNode {
+    pub id: String,
+    pub level: u32,
+    pub content: String,
+    pub embedding: Vec<f32>,
+    /// Ids of the fragments or summaries it summarizes
+    pub children: Vec<String>,
+    /// Fragments it covers, through its children
+    pub fragments: usize,
+    /// Names of the files those fragments are from
+    pub files: Vec<String>,
+}
+
+impl SummaryNode {
+    /// Where the summary came from, for citing it next to fragments
+    pub fn source(&self) -> FragmentSource {
+        let mut files = self.files.iter().take(3).cloned().collect::<Vec<_>>().join(", ");
+        if self.files.len() > 3 {
+            files.push_str(&format!(" and {} more", self.files.len() - 3));
+        }
+        FragmentSource {
+            filename: format!("Summary of {} passages from {}", self.fragments, files),
+            file_path: self.id.clone(),
+            section: Some(format!("Summary level {}", self.level)),
+            ..FragmentSource::default()
+        }
+    }
+}
+
+/// Recursive summaries over a database's fragments, searched alongside them so broad
+/// questions find passages that speak for a whole topic rather than one detail of it
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SummaryTree {
+    /// Embedding model the summaries were embedded with
+    pub model: String,
+    /// Dataset version of the database when the tree was built
+    pub data_version: u64,
+    pub nodes: Vec<SummaryNode>,
+}
+
+impl SummaryTree {
+    pub fn levels(&self) -> u32 {
+        self.nodes.iter().map(|node| node.level).max().unwrap_or(0)
+    }
+
+    /// The `limit` summaries of any level most similar to `query_embedding` by `metric`, the
+    /// database's, best first
+    pub fn search(&self, query_embedding: &[f64], limit: usize, metric: SimilarityMetric) -> Vec<FragmentMatch> {
+        let mut scored: Vec<(&SummaryNode, f64)> = self.nodes.iter()
+            .map(|node| (node, metric.score(query_embedding, &node.embedding)))
+            .collect();
+        scored.sort_by(|a, b| b.1.total_cmp(&a.1));
+        scored.into_iter()
+            .take(limit)
+            .map(|(node, score)| FragmentMatch {
+                fragment_id: node.id.clone(),
+                content: node.content.clone(),
+                score,
+                source: node.source(),
+            })
+            .collect()
+    }
+}
+
+/// Writes the summary of a cluster of passages
+#[async_trait]
+pub trait Summarizer: Send {
+    async fn summarize(&mut self, passages: &[&str]) -> Result<String>;
+}
+
+/// Summarizes with a chat model, adding up the tokens it used
+pub struct LlmSummarizer<'a> {
+    llm: &'a LlmClient,
+    pub usage: TokenUsage,
+}
+
+impl<'a> LlmSummarizer<'a> {
+    pub fn new(llm: &'a LlmClient) -> Self {
+        Self { llm, usage: TokenUsage::default() }
+    }
+}
+
+#[async_trait]
+impl Summarizer for LlmSummarizer<'_> {
+    async fn summarize(&mut self, passages: &[&str]) -> Result<String> {
+        let passages = passages.iter()
+            .enumerate()
+            .map(|(i, passage)| format!("[{}] {}", i + 1, passage))
+            .collect::<Vec<_>>()
+            .join("\n\n");
+        let reply = self.llm.complete(
+            vec![ChatMessage::system(SUMMARY_PROMPT), ChatMessage::user(passages)],
+            SUMMARY_MAX_TOKENS,
+            0.0,
+        ).await?;
+        self.usage += reply.usage;
+        Ok(reply.content.trim().to_string())
+    }
+}
+
+/// A fragment or summary being clustered into the next level
+struct TreeEntry {
+    id: String,
+    text: String,
+    vector: Vec<f64>,
+    fragments: usize,
+    files: BTreeSet<String>,
+}
+
+/// Build a summary tree over the embedded fragments of `storage`: cluster them by
+/// similarity, summarize each cluster, embed the summaries and repeat on those, until
+/// `options.levels` levels are built or a level is down to one cluster. `report` is told
+/// each level's number and how many entries it summarized into how many summaries. The
+/// tree is returned rather than saved.
+pub async fn build(
+    storage: &mut dyn Storage,
+    embedding_manager: &mut EmbeddingManager,
+    summarizer: &mut dyn Summarizer,
+    options: &TreeOptions,
+    report: &mut dyn FnMut(u32, usize, usize),
+) -> Result<SummaryTree> {
+    // Both read the same stable sample, spread across documents by hashing fragment ids
+    let files: HashMap<String, String> = storage.get_fragment_embeddings(options.max_fragments).await?
+        .into_iter()
+        .map(|(id, file, _)| (id, file))
+        .collect();
+    let mut entries: Vec<TreeEntry> = storage.sample_fragments(options.max_fragments).await?
+        .into_iter()
+        .map(|(id, text, vector)| TreeEntry {
+            files: files.get(&id).cloned().into_iter().collect(),
+            id,
+            text,
+            vector,
+            fragments: 1,
+        })
+        .collect();
+    if entries.len() < 2 {
+        anyhow::bail!("There are too few embedded fragments to summarize; run `embed` first");
+    }
+
+    let prefixes = storage::embedding_prefixes(storage).await?;
+    let mut tree = SummaryTree {
+        model: embedding_manager.model_name().to_string(),
+        data_version: storage::data_version(storage).await?,
+        nodes: Vec::new(),
+    };
+    for level in 1..=options.levels {
+        if entries.len() < 2 {
+            break;
+        }
+        let clusters = cluster(&entries.iter().map(|entry| entry.vector.clone()).collect::<Vec<_>>(), options.cluster_size);
+        if clusters.len() == entries.len() {
+            break;
+        }
+
+        let mut summaries = Vec::new();
+        for members in &clusters {
+            let passages: Vec<&str> = members.iter().map(|&i| entries[i].text.as_str()).collect();
+            let summary = summarizer.summarize(&passages).await
+                .with_context(|| format!("Failed to summarize a cluster at level {}", level))?;
+            summaries.push(summary);
+        }
+        let texts: Vec<String> = summaries.iter().map(|summary| prefixes.document(summary)).collect();
+        let vectors = embedding_manager.generate_embeddings_batch(&texts).await
+            .context("Failed to embed summaries")?;
+
+        let mut next = Vec::new();
+        for (n, ((members, summary), vector)) in clusters.iter().zip(summaries).zip(vectors).enumerate() {
+            let node = SummaryNode {
+                id: format!("summary-{}-{}", level, n),
+                level,
+                content: summary,
+                embedding: vector.iter().map(|&x| x as f32).collect(),
+                children: members.iter().map(|&i| entries[i].id.clone()).collect(),
+                fragments: members.iter().map(|&i| entries[i].fragments).sum(),
+                files: members.iter().flat_map(|&i| entries[i].files.iter().cloned()).collect::<BTreeSet<_>>().into_iter().collect(),
+            };
+            next.push(TreeEntry {
+                id: node.id.clone(),
+                text: node.content.clone(),
+                vector,
+                fragments: node.fragments,
+                files: node.files.iter().cloned().collect(),
+            });
+            tree.nodes.push(node);
+        }
+        report(level, entries.len(), next.len());
+        entries = next;
+    }
+    Ok(tree)
+}
+
+/// Group `vectors` by cosine k-means into clusters of about `size`, as lists of indices.
+/// Seeds are spread evenly through the input so the same vectors always cluster the same
+/// way, and clusters grown past twice `size` are split, so no prompt gets too long.
+pub fn cluster(vectors: &[Vec<f64>], size: usize) -> Vec<Vec<usize>> {
+    let size = size.max(2);
+    let k = vectors.len().div_ceil(size);
+    if k <= 1 {
+        return if vectors.is_empty() { Vec::new() } else { vec![(0..vectors.len()).collect()] };
+    }
+
+    let points: Vec<Vec<f64>> = vectors.iter().map(|vector| unit(vector)).collect();
+    let mut centroids: Vec<Vec<f64>> = (0..k).map(|i| points[i * points.len() / k].clone()).collect();
+    let mut assignment = vec![usize::MAX; points.len()];
+    for _ in 0..KMEANS_ITERATIONS {
+        let mut changed = false;
+        for (point, assigned) in points.iter().zip(assignment.iter_mut()) {
+            let nearest = centroids.iter()
+                .map(|centroid| dot(point, centroid))
+                .enumerate()
+                .max_by(|a, b| a.1.total_cmp(&b.1))
+                .map_or(0, |(c, _)| c);
+            if *assigned != nearest {
+                *assigned = nearest;
+                changed = true;
+            }
+        }
+        if !changed {
+            break;
+        }
+        for (c, centroid) in centroids.iter_mut().enumerate() {
+            let mut sum = vec![0.0; centroid.len()];
+            for (point, _) in points.iter().zip(&assignment).filter(|(_, assigned)| **assigned == c) {
+                for (total, x) in sum.iter_mut().zip(point) {
+                    *total += x;
+                }
+            }
+            // An empty cluster keeps its centroid and is dropped below if it stays empty
+            if sum.iter().any(|&x| x != 0.0) {
+                *centroid = unit(&sum);
+            }
+        }
+    }
+
+    let mut clusters = vec![Vec::new(); k];
+    for (i, &assigned) in assignment.iter().enumerate() {
+        clusters[assigned].push(i);
+    }
+    clusters.into_iter()
+        .filter(|members| !members.is_empty())
+        .flat_map(|members| {
+            if members.len() > size * 2 {
+                members.chunks(size).map(<[usize]>::to_vec).collect()
+            } else {
+                vec![members]
+            }
+        })
+        .collect()
+}
+
+/// The summary tree recorded in `storage`, if one was built
+pub async fn load(storage: &mut dyn Storage) -> Result<Option<SummaryTree>> {
+    match storage.get_meta_value(SUMMARY_TREE_KEY).await? {
+        Some(json) if !json.is_empty() => serde_json::from_str(&json)
+            .map(Some)
+            .context("Invalid summary tree in the meta table"),
+        _ => Ok(None),
+    }
+}
+
+pub async fn save(storage: &mut dyn Storage, tree: &SummaryTree) -> Result<()> {
+    storage.set_meta_value(SUMMARY_TREE_KEY, &serde_json::to_string(tree)?).await
+}
+
+/// Remove the summary tree, so searches return fragments only
+pub async fn clear(storage: &mut dyn Storage) -> Result<()> {
+    storage.set_meta_value(SUMMARY_TREE_KEY, "").await
+}
+
+fn dot(a: &[f64], b: &[f64]) -> f64 {
+    a.iter().zip(b).map(|(x, y)| x * y).sum()
+}
+
+fn unit(vector: &[f64]) -> Vec<f64> {
+    let norm = dot(vector, vector).sqrt();
+    if norm == 0.0 {
+        return vector.to_vec();
+    }
+    vector.iter().map(|x| x / norm).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lancedb_storage::LanceDBStorage;
+    use crate::storage::FragmentMeta;
+    use std::path::Path;
+
+    /// Joins the first word of each passage
+    struct FirstWords;
+
+    #[async_trait]
+    impl Summarizer for FirstWords {
+        async fn summarize(&mut self, passages: &[&str]) -> Result<String> {
+            Ok(passages.iter().filter_map(|passage| passage.split_whitespace().next()).collect::<Vec<_>>().join(" "))
+        }
+    }
+
+    #[test]
+    fn test_cluster_groups_similar_vectors() {
+        let vectors = vec![
+            vec![1.0, 0.0], vec![0.0, 1.0], vec![0.9, 0.1], vec![0.1, 0.9], vec![1.0, 0.05], vec![0.05, 1.0],
+        ];
+        let mut clusters = cluster(&vectors, 3);
+        clusters.sort();
+        assert_eq!(clusters, vec![vec![0, 2, 4], vec![1, 3, 5]]);
+        assert_eq!(cluster(&vectors[..2], 8), vec![vec![0, 1]]);
+        assert!(cluster(&[], 8).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_tree_summarizes_levels_and_is_searched() {
+        let mut storage = LanceDBStorage::new(Path::new("summaries")).await.unwrap();
+        let mut embedding_manager = EmbeddingManager::hashing(16);
+        for (name, texts) in [("ops.md", ["Backups run nightly", "Restores are tested monthly"]), ("hr.md", ["Salaries are reviewed yearly", "Leave accrues monthly"])] {
+            let document = storage.store_document(Path::new(name), name.as_bytes()).await.unwrap();
+            for (order, text) in texts.iter().enumerate() {
+                storage.store_text_fragment(&document, order as i32, text, &FragmentMeta::default()).await.unwrap();
+            }
+        }
+        storage::embed_fragment_batch(&mut storage, &mut embedding_manager, 10).await.unwrap();
+
+        let options = TreeOptions { cluster_size: 2, levels: 3, max_fragments: 100 };
+        let mut levels = Vec::new();
+        let tree = build(&mut storage, &mut embedding_manager, &mut FirstWords, &options, &mut |level, from, to| levels.push((level, from, to))).await.unwrap();
+        assert_eq!(levels, vec![(1, 4, 2), (2, 2, 1)]);
+        assert_eq!(tree.levels(), 2);
+        let root = tree.nodes.last().unwrap();
+        assert_eq!((root.fragments, root.files.len()), (4, 2));
+
+        save(&mut storage, &tree).await.unwrap();
+        assert_eq!(load(&mut storage).await.unwrap(), Some(tree.clone()));
+        let hits = tree.search(&root.embedding.iter().map(|&x| x as f64).collect::<Vec<_>>(), 1, SimilarityMetric::Cosine);
+        assert_eq!(hits[0].fragment_id, root.id);
+        assert_eq!(hits[0].source.section.as_deref(), Some("Summary level 2"));
+
+        clear(&mut storage).await.unwrap();
+        assert_eq!(load(&mut storage).await.unwrap(), None);
+    }
+}
@@ -0,0 +1,213 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use utoipa::ToSchema;
+
+use crate::config::Config;
+use crate::jobs::JobRecord;
+use crate::server::constant_time_eq;
+use crate::storage::{SearchFilter, Storage};
+
+/// Meta key prefix under which each tenant's usage is kept
+const USAGE_KEY_PREFIX: &str = "tenant_usage:";
+
+/// A team sharing the server, limited to its own collections
+#[derive(Debug)]
+pub struct Tenant {
+    pub id: String,
+    /// Collections the tenant can read; the first is where its documents go by default
+    pub collections: Vec<String>,
+}
+
+/// Who a request was authenticated as
+#[derive(Debug, Clone)]
+pub enum Caller {
+    /// `--token` or a `[serve]` token: unrestricted
+    Admin,
+    Tenant(Arc<Tenant>),
+}
+
+impl Caller {
+    pub fn tenant_id(&self) -> Option<&str> {
+        match self {
+            Caller::Admin => None,
+            Caller::Tenant(tenant) => Some(&tenant.id),
+        }
+    }
+
+    /// Whether a document in `collection` belongs to the caller
+    pub fn can_read(&self, collection: Option<&str>) -> bool {
+        match self {
+            Caller::Admin => true,
+            Caller::Tenant(tenant) => collection.is_some_and(|collection| tenant.collections.iter().any(|own| own == collection)),
+        }
+    }
+
+    /// Narrow a search to the caller's collections, keeping those it asked for that it can
+    /// read. `None` when it only asked for collections it can't read.
+    pub fn scope(&self, filter: SearchFilter) -> Option<SearchFilter> {
+        match self {
+            Caller::Admin => Some(filter),
+            Caller::Tenant(tenant) if filter.collections.is_empty() => {
+                Some(SearchFilter { collections: tenant.collections.clone(), ..filter })
+            }
+            Caller::Tenant(_) => {
+                let collections: Vec<String> = filter.collections.iter()
+                    .filter(|collection| self.can_read(Some(collection)))
+                    .cloned()
+                    .collect();
+                (!collections.is_empty()).then_some(SearchFilter { collections, ..filter })
+            }
+        }
+    }
+
+    /// Collection a document ingested by the caller is stored in: the routed one when it is
+    /// the caller's own, otherwise the tenant's first collection
+    pub fn place(&self, routed: Option<String>) -> Option<String> {
+        match self {
+            Caller::Admin => routed,
+            Caller::Tenant(_) if self.can_read(routed.as_deref()) => routed,
+            Caller::Tenant(tenant) => tenant.collections.first().cloned(),
+        }
+    }
+
+    /// Tenants see the jobs they submitted; admins see every job, including the CLI's
+    pub fn can_see(&self, job: &JobRecord) -> bool {
+        match self {
+            Caller::Admin => true,
+            Caller::Tenant(tenant) => job.tenant.as_deref() == Some(tenant.id.as_str()),
+        }
+    }
+}
+
+/// Bearer tokens the server accepts and who each one acts as
+#[derive(Debug, Default)]
+pub struct Access {
+    admin: Vec<String>,
+    tenants: Vec<(String, Arc<Tenant>)>,
+}
+
+impl Access {
+    /// `token` and the config's `[serve] tokens` act as admin; `[tenants]` tokens act as their tenant
+    pub fn new(token: Option<&str>, config: Option<&Config>) -> Self {
+        let admin = token.map(str::to_string).into_iter()
+            .chain(config.into_iter().flat_map(|config| config.serve.tokens.iter().cloned()))
+            .collect();
+        let tenants = config.into_iter()
+            .flat_map(|config| config.tenants.iter())
+            .flat_map(|(id, settings)| {
+                let tenant = Arc::new(Tenant { id: id.clone(), collections: settings.collections.clone() });
+                settings.tokens.iter().map(move |token| (token.clone(), tenant.clone()))
+            })
+            .collect();
+        Self { admin, tenants }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.admin.is_empty() && self.tenants.is_empty()
+    }
+
+    /// Ids of the configured tenants, in config order
+    pub fn tenant_ids(&self) -> Vec<String> {
+        let mut ids: Vec<String> = self.tenants.iter().map(|(_, tenant)| tenant.id.clone()).collect();
+        ids.dedup();
+        ids
+    }
+
+    /// Who `presented` acts as. Every token is compared so the time taken doesn't reveal
+    /// which one matched.
+    pub fn authenticate(&self, presented: &str) -> Option<Caller> {
+        let mut caller = None;
+        for token in &self.admin {
+            if constant_time_eq(presented.as_bytes(), token.as_bytes()) {
+                caller = Some(Caller::Admin);
+            }
+        }
+        for (token, tenant) in &self.tenants {
+            if constant_time_eq(presented.as_bytes(), token.as_bytes()) {
+                caller = Some(Caller::Tenant(tenant.clone()));
+            }
+        }
+        caller
+    }
+}
+
+/// What a tenant has used the server for, kept in the database's meta table
+#[derive(Debug, Clone, Default, Serialize, Deserialize, ToSchema)]
+pub struct TenantUsage {
+    pub tenant: String,
+    pub searches: u64,
+    /// Fragments returned across all searches
+    pub fragments_returned: u64,
+    pub documents_ingested: u64,
+    /// Size of the ingested originals
+    pub bytes_ingested: u64,
+    pub last_active: Option<String>,
+}
+
+/// A tenant's usage so far; zero when it hasn't used the server yet
+pub async fn load_usage(storage: &mut dyn Storage, tenant: &str) -> Result<TenantUsage> {
+    match storage.get_meta_value(&format!("{}{}", USAGE_KEY_PREFIX, tenant)).await? {
+        Some(json) => serde_json::from_str(&json)
+            .with_context(|| format!("Failed to parse usage of tenant {}", tenant)),
+        None => Ok(TenantUsage { tenant: tenant.to_string(), ..Default::default() }),
+    }
+}
+
+/// Add to a tenant's usage; the caller holds the storage lock, so updates don't race
+pub async fn record_usage(storage: &mut dyn Storage, tenant: &str, update: impl FnOnce(&mut TenantUsage)) -> Result<()> {
+    let mut usage = load_usage(storage, tenant).await?;
+    update(&mut usage);
+    usage.last_active = Some(chrono::Utc::now().to_rfc3339());
+    storage.set_meta_value(&format!("{}{}", USAGE_KEY_PREFIX, tenant), &serde_json::to_string(&usage)?).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn access() -> Access {
+        let config: Config = toml::from_str(r#"
+            [serve]
+            tokens = ["admin-token"]
+
+            [tenants.legal]
+            tokens = ["legal-token"]
+            collections = ["legal", "contracts"]
+        "#).unwrap();
+        Access::new(None, Some(&config))
+    }
+
+    #[test]
+    fn test_tokens_resolve_to_callers() {
+        let access = access();
+        assert!(matches!(access.authenticate("admin-token"), Some(Caller::Admin)));
+        assert_eq!(access.authenticate("legal-token").unwrap().tenant_id(), Some("legal"));
+        assert!(access.authenticate("legal").is_none());
+        assert_eq!(access.tenant_ids(), vec!["legal"]);
+    }
+
+    #[test]
+    fn test_tenant_is_confined_to_its_collections() {
+        let caller = access().authenticate("legal-token").unwrap();
+        assert!(caller.can_read(Some("contracts")));
+        assert!(!caller.can_read(Some("finance")));
+        assert!(!caller.can_read(None));
+
+        assert_eq!(caller.place(Some("contracts".to_string())).as_deref(), Some("contracts"));
+        assert_eq!(caller.place(Some("finance".to_string())).as_deref(), Some("legal"));
+        assert_eq!(caller.place(None).as_deref(), Some("legal"));
+
+        let filter = caller.scope(SearchFilter { section: Some("Terms".to_string()), ..Default::default() }).unwrap();
+        assert_eq!(filter.collections, vec!["legal", "contracts"]);
+        assert_eq!(filter.section.as_deref(), Some("Terms"));
+
+        let requested = |collections: &[&str]| SearchFilter {
+            collections: collections.iter().map(|c| c.to_string()).collect(),
+            ..Default::default()
+        };
+        assert_eq!(caller.scope(requested(&["contracts", "finance"])).unwrap().collections, vec!["contracts"]);
+        assert!(caller.scope(requested(&["finance"])).is_none());
+        assert_eq!(Caller::Admin.scope(requested(&["finance"])).unwrap().collections, vec!["finance"]);
+    }
+}
@@ -0,0 +1,228 @@
+//! Retrieval test harness: indexes fixture documents into the in-memory backend with the
+//! deterministic hashing embedder, and compares search rankings against golden snapshots,
+//! so chunking and embedding changes can be checked from a plain `cargo test`.
+//!
+//! ```no_run
+//! # async fn run() -> anyhow::Result<()> {
+//! use portable_brains::testing::{assert_snapshot, RetrievalHarness};
+//!
+//! let mut harness = RetrievalHarness::new().await?;
+//! harness.add_dir("tests/fixtures/retrieval".as_ref()).await?;
+//! let snapshot = harness.snapshot(&["notice period for termination"], 3).await?;
+//! assert_snapshot("tests/fixtures/retrieval.golden".as_ref(), &snapshot);
+//! # Ok(())
+//! # }
+//! ```
+
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::path::{Path, PathBuf};
+
+use crate::document_processor::{DocumentFormat, DocumentProcessor, Section};
+use crate::embedding_manager::EmbeddingManager;
+use crate::hybrid::SearchMode;
+use crate::lancedb_storage::LanceDBStorage;
+use crate::retrieval;
+use crate::storage::{self, IndexState, SearchFilter, Storage};
+
+/// Dimension of the harness's default hashing embedder
+pub const HARNESS_DIMENSION: usize = 256;
+
+/// Environment variable that makes `assert_snapshot` rewrite golden files instead of comparing
+pub const UPDATE_SNAPSHOTS_VAR: &str = "UPDATE_SNAPSHOTS";
+
+/// A search hit named by where its fragment came from, since fragment ids are random
+#[derive(Debug, Clone)]
+pub struct RankedHit {
+    /// `<file name>#<fragment order>`
+    pub label: String,
+    pub score: f64,
+    pub content: String,
+}
+
+/// An in-memory brain for retrieval tests
+pub struct RetrievalHarness {
+    storage: LanceDBStorage,
+    embedding_manager: EmbeddingManager,
+    processor: DocumentProcessor,
+    /// Fragment id -> label
+    labels: HashMap<String, String>,
+}
+
+impl RetrievalHarness {
+    /// A harness using the hashing embedder and the default chunking
+    pub async fn new() -> Result<Self> {
+        Self::with_embedding_manager(EmbeddingManager::hashing(HARNESS_DIMENSION)).await
+    }
+
+    /// A harness embedding with a real model, to check a model change against the snapshots
+    pub async fn with_embedding_manager(embedding_manager: EmbeddingManager) -> Result<Self> {
+        let mut storage = LanceDBStorage::new(Path::new("retrieval-harness")).await?;
+        storage.verify_or_set_model(embedding_manager.model_name(), embedding_manager.known_dimension()).await?;
+        Ok(Self {
+            storage,
+            embedding_manager,
+            processor: DocumentProcessor::new(),
+            labels: HashMap::new(),
+        })
+    }
+
+    /// Chunk documents added from now on with this processor
+    pub fn with_processor(mut self, processor: DocumentProcessor) -> Self {
+        self.processor = processor;
+        self
+    }
+
+    /// Index plain text under `name`. Returns the number of fragments stored.
+    pub async fn add_text(&mut self, name: &str, text: &str) -> Result<usize> {
+        let section = Section { path: Vec::new(), text: text.to_string(), page: None, location: None };
+        self.add_sections(Path::new(name), text.as_bytes(), &[section]).await
+    }
+
+    /// Extract and index a document file. Returns the number of fragments stored.
+    pub async fn add_file(&mut self, path: &Path) -> Result<usize> {
+        let data = std::fs::read(path)
+            .with_context(|| format!("Failed to read fixture {}", path.display()))?;
+        let sections = self.processor.extract_sections_from_document(path, &data)
+            .with_context(|| format!("Failed to extract fixture {}", path.display()))?;
+        let name = path.file_name().map(PathBuf::from).unwrap_or_else(|| path.to_path_buf());
+        self.add_sections(&name, &data, &sections).await
+    }
+
+    /// Index every supported document in `dir` in file-name order. Returns the number of
+    /// fragments stored.
+    pub async fn add_dir(&mut self, dir: &Path) -> Result<usize> {
+        let mut files = Vec::new();
+        for entry in std::fs::read_dir(dir).with_context(|| format!("Failed to read fixture directory {}", dir.display()))? {
+            let path = entry?.path();
+            let supported = path.extension()
+                .and_then(|extension| DocumentFormat::from_extension(&extension.to_string_lossy()))
+                .is_some();
+            if path.is_file() && supported {
+                files.push(path);
+            }
+        }
+        files.sort();
+
+        let mut fragments = 0;
+        for file in files {
+            fragments += self.add_file(&file).await?;
+        }
+        Ok(fragments)
+    }
+
+    /// Chunk the sections as `index` does, store them and embed them straight away
+    async fn add_sections(&mut self, name: &Path, data: &[u8], sections: &[Section]) -> Result<usize> {
+        let fragments = self.processor.chunk_sections(sections)?;
+
+        let document_id = self.storage.store_document(name, data).await?;
+        let prefixes = storage::embedding_prefixes(&mut self.storage).await?;
+        let contents: Vec<String> = fragments.iter().map(|(content, _)| prefixes.document(content)).collect();
+        let embeddings = self.embedding_manager.generate_embeddings_batch(&contents).await?;
+        for (order, ((content, meta), embedding)) in fragments.iter().zip(embeddings).enumerate() {
+            let fragment_id = self.storage.store_text_fragment(&document_id, order as i32, content, meta).await?;
+            self.storage.update_fragment_embedding(&fragment_id, &embedding).await?;
+            self.labels.insert(fragment_id, format!("{}#{}", name.display(), order));
+        }
+        self.storage.set_index_state(&document_id, IndexState::Embedded).await?;
+        Ok(fragments.len())
+    }
+
+    /// The `limit` fragments most similar to `query`, best first. Accepts the same
+    /// `section:"Heading"` filter as `portable-brains search`.
+    pub async fn search(&mut self, query: &str, limit: usize) -> Result<Vec<RankedHit>> {
+        let (query, section) = SearchFilter::parse_section(query);
+        let filter = SearchFilter { section, ..Default::default() };
+        let (hits, _) = retrieval::search(&mut self.storage, &mut self.embedding_manager, &query, limit, &filter, SearchMode::Vector, false).await?;
+
+        Ok(hits.into_iter()
+            .map(|hit| RankedHit {
+                label: self.labels.get(&hit.fragment_id).cloned().unwrap_or(hit.fragment_id),
+                score: hit.score,
+                content: hit.content,
+            })
+            .collect())
+    }
+
+    /// The rankings for each query as stable text, one hit per line with its score to four
+    /// places, ready for `assert_snapshot`
+    pub async fn snapshot(&mut self, queries: &[&str], limit: usize) -> Result<String> {
+        let mut snapshot = String::new();
+        for query in queries {
+            writeln!(snapshot, "query: {}", query)?;
+            for (rank, hit) in self.search(query, limit).await?.iter().enumerate() {
+                writeln!(snapshot, "  {}. {} {:.4}", rank + 1, hit.label, hit.score)?;
+            }
+            snapshot.push('\n');
+        }
+        Ok(snapshot)
+    }
+}
+
+/// Compare `actual` with the golden file at `path`, panicking with the differing lines on a
+/// mismatch. A missing golden file is written instead, as is every golden file when
+/// `UPDATE_SNAPSHOTS=1` is set, so an intended ranking change is accepted by re-running
+/// with it and reviewing the diff.
+pub fn assert_snapshot(path: &Path, actual: &str) {
+    let update = std::env::var(UPDATE_SNAPSHOTS_VAR).is_ok_and(|value| value == "1");
+    if update || !path.exists() {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent).expect("create snapshot directory");
+        }
+        std::fs::write(path, actual).expect("write snapshot");
+        return;
+    }
+
+    let expected = std::fs::read_to_string(path).expect("read snapshot");
+    if expected == actual {
+        return;
+    }
+
+    let expected_lines: Vec<&str> = expected.lines().collect();
+    let actual_lines: Vec<&str> = actual.lines().collect();
+    let mut diff = String::new();
+    for line in 0..expected_lines.len().max(actual_lines.len()) {
+        let (old, new) = (expected_lines.get(line), actual_lines.get(line));
+        if old != new {
+            let _ = writeln!(diff, "line {}:\n  - {}\n  + {}", line + 1, old.unwrap_or(&""), new.unwrap_or(&""));
+        }
+    }
+    panic!(
+        "Retrieval snapshot {} changed; re-run with {}=1 to accept:\n{}",
+        path.display(), UPDATE_SNAPSHOTS_VAR, diff
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixtures() -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures")
+    }
+
+    #[tokio::test]
+    async fn test_fixture_rankings_match_golden_snapshot() {
+        let mut harness = RetrievalHarness::new().await.unwrap();
+        assert!(harness.add_dir(&fixtures().join("retrieval")).await.unwrap() > 0);
+
+        let snapshot = harness.snapshot(&[
+            "notice period for terminating the lease",
+            "quarterly revenue growth",
+            "how do I reset my password",
+        ], 3).await.unwrap();
+        assert_snapshot(&fixtures().join("retrieval.golden"), &snapshot);
+    }
+
+    #[tokio::test]
+    async fn test_ranking_prefers_shared_words() {
+        let mut harness = RetrievalHarness::new().await.unwrap();
+        harness.add_text("pets.txt", "Cats and dogs are common household pets.").await.unwrap();
+        harness.add_text("tax.txt", "Income tax returns are due in April.").await.unwrap();
+
+        let hits = harness.search("when are tax returns due", 2).await.unwrap();
+        assert_eq!(hits[0].label, "tax.txt#0");
+        assert!(hits[0].score > hits[1].score);
+    }
+}
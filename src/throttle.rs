@@ -0,0 +1,225 @@
+use anyhow::{Context, Result};
+use std::time::{Duration, Instant};
+
+/// How often the power source is re-checked while indexing
+const POWER_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+/// How long to sleep between checks while paused on battery
+const BATTERY_POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Limits that keep background indexing from taking over the machine
+#[derive(Debug, Clone, Default)]
+pub struct ThrottleSettings {
+    /// Lower CPU (and on Linux, IO) scheduling priority
+    pub nice: bool,
+    /// Restrict the process to this many CPU cores
+    pub max_cores: Option<usize>,
+    /// Cap on document reads, in megabytes per second
+    pub io_limit_mb: Option<f64>,
+    /// Wait for AC power before continuing
+    pub pause_on_battery: bool,
+}
+
+/// Applies process-wide limits once and paces work between documents and batches
+pub struct Throttle {
+    io_rate: Option<f64>,
+    io_started: Instant,
+    io_bytes: u64,
+    pause_on_battery: bool,
+    last_power_check: Option<Instant>,
+}
+
+impl Throttle {
+    /// Apply the priority and core limits to the current process.
+    ///
+    /// Call before the embedding model is loaded so its thread pool is sized to the
+    /// restricted core set.
+    pub fn new(settings: &ThrottleSettings) -> Result<Self> {
+        if settings.nice {
+            lower_priority()?;
+            println!("🐢 Running at reduced CPU and IO priority");
+        }
+
+        if let Some(cores) = settings.max_cores {
+            if cores == 0 {
+                anyhow::bail!("--max-cores must be at least 1");
+            }
+            if limit_cores(cores)? {
+                println!("🐢 Limited to {} CPU core(s)", cores);
+            } else {
+                println!("⚠️  --max-cores is only supported on Linux; ignoring it");
+            }
+        }
+
+        let io_rate = match settings.io_limit_mb {
+            Some(mb) if mb <= 0.0 => anyhow::bail!("--io-limit must be greater than 0"),
+            Some(mb) => {
+                println!("🐢 Reading documents at up to {:.1} MB/s", mb);
+                Some(mb * 1024.0 * 1024.0)
+            }
+            None => None,
+        };
+
+        Ok(Self {
+            io_rate,
+            io_started: Instant::now(),
+            io_bytes: 0,
+            pause_on_battery: settings.pause_on_battery,
+            last_power_check: None,
+        })
+    }
+
+    /// Record `bytes` read and sleep long enough to keep the average read rate under the cap
+    pub async fn pace_io(&mut self, bytes: u64) {
+        let Some(rate) = self.io_rate else {
+            return;
+        };
+        self.io_bytes += bytes;
+        let delay = io_delay(self.io_bytes, rate, self.io_started.elapsed());
+        if !delay.is_zero() {
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    /// Block while the machine is on battery power, if pausing was requested
+    pub async fn wait_for_power(&mut self) {
+        if !self.pause_on_battery {
+            return;
+        }
+        if self.last_power_check.is_some_and(|checked| checked.elapsed() < POWER_CHECK_INTERVAL) {
+            return;
+        }
+        self.last_power_check = Some(Instant::now());
+
+        if !on_battery() {
+            return;
+        }
+        println!("\n🔋 Running on battery; paused until AC power returns...");
+        while on_battery() {
+            tokio::time::sleep(BATTERY_POLL_INTERVAL).await;
+        }
+        println!("🔌 AC power restored; resuming");
+
+        // Time spent paused shouldn't count as read budget
+        self.io_started = Instant::now();
+        self.io_bytes = 0;
+    }
+}
+
+/// Time still to wait so that `bytes` read since the start average at most `rate` bytes/second
+fn io_delay(bytes: u64, rate: f64, elapsed: Duration) -> Duration {
+    let target = Duration::from_secs_f64(bytes as f64 / rate);
+    target.saturating_sub(elapsed)
+}
+
+#[cfg(unix)]
+fn lower_priority() -> Result<()> {
+    // On Linux the CFQ/BFQ IO schedulers derive IO priority from the nice value
+    let result = unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, 10) };
+    if result != 0 {
+        return Err(std::io::Error::last_os_error()).context("Failed to lower process priority");
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn lower_priority() -> Result<()> {
+    println!("⚠️  --nice is not supported on this platform; ignoring it");
+    Ok(())
+}
+
+/// Pin every thread of the process to its first `cores` allowed CPUs.
+///
+/// Returns false where core limits aren't supported.
+#[cfg(target_os = "linux")]
+fn limit_cores(cores: usize) -> Result<bool> {
+    unsafe {
+        let mut allowed: libc::cpu_set_t = std::mem::zeroed();
+        if libc::sched_getaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &mut allowed) != 0 {
+            return Err(std::io::Error::last_os_error()).context("Failed to read CPU affinity");
+        }
+
+        let mut limited: libc::cpu_set_t = std::mem::zeroed();
+        let mut chosen = 0;
+        for cpu in 0..libc::CPU_SETSIZE as usize {
+            if chosen == cores {
+                break;
+            }
+            if libc::CPU_ISSET(cpu, &allowed) {
+                libc::CPU_SET(cpu, &mut limited);
+                chosen += 1;
+            }
+        }
+
+        // Affinity is per thread, so apply it to the runtime's existing threads too;
+        // threads created later inherit it
+        for entry in std::fs::read_dir("/proc/self/task").context("Failed to list process threads")? {
+            let Some(tid) = entry?.file_name().to_str().and_then(|name| name.parse::<libc::pid_t>().ok()) else {
+                continue;
+            };
+            if libc::sched_setaffinity(tid, std::mem::size_of::<libc::cpu_set_t>(), &limited) != 0 {
+                return Err(std::io::Error::last_os_error()).context("Failed to set CPU affinity");
+            }
+        }
+    }
+    Ok(true)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn limit_cores(_cores: usize) -> Result<bool> {
+    Ok(false)
+}
+
+/// Whether the machine is currently running on battery power.
+///
+/// Unknown power sources are treated as AC so indexing never stalls indefinitely.
+#[cfg(target_os = "linux")]
+fn on_battery() -> bool {
+    let Ok(entries) = std::fs::read_dir("/sys/class/power_supply") else {
+        return false;
+    };
+    entries.flatten().any(|entry| {
+        let read = |name: &str| std::fs::read_to_string(entry.path().join(name)).unwrap_or_default();
+        read("type").trim() == "Battery" && read("status").trim() == "Discharging"
+    })
+}
+
+#[cfg(target_os = "macos")]
+fn on_battery() -> bool {
+    std::process::Command::new("pmset")
+        .args(["-g", "batt"])
+        .output()
+        .map(|output| pmset_on_battery(&String::from_utf8_lossy(&output.stdout)))
+        .unwrap_or(false)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn on_battery() -> bool {
+    false
+}
+
+/// `pmset -g batt` starts with e.g. "Now drawing from 'Battery Power'"
+#[cfg(any(target_os = "macos", test))]
+fn pmset_on_battery(output: &str) -> bool {
+    output.lines().next().is_some_and(|line| line.contains("'Battery Power'"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_io_delay() {
+        let rate = 1024.0 * 1024.0;
+        // 2 MB at 1 MB/s takes 2 seconds; half a second has passed
+        let delay = io_delay(2 * 1024 * 1024, rate, Duration::from_millis(500));
+        assert_eq!(delay, Duration::from_millis(1500));
+        // Already slower than the cap
+        assert!(io_delay(1024, rate, Duration::from_secs(1)).is_zero());
+    }
+
+    #[test]
+    fn test_pmset_on_battery() {
+        assert!(pmset_on_battery("Now drawing from 'Battery Power'\n -InternalBattery-0 (id=1)\t80%; discharging"));
+        assert!(!pmset_on_battery("Now drawing from 'AC Power'\n -InternalBattery-0 (id=1)\t100%; charged"));
+    }
+}
@@ -0,0 +1,417 @@
+use anyhow::{Context, Result};
+use duckdb::{params, Connection};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use crate::storage::SearchFilter;
+
+/// A fragment retrieved for a question
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TracedHit {
+    pub brain: String,
+    pub fragment_id: String,
+    pub file_path: String,
+    /// Dataset version of its brain when it was retrieved
+    pub version: u64,
+    /// Similarity or fused score it was retrieved with; for passages, the reranker's score
+    /// when one re-scored them
+    pub score: f64,
+    /// The reranker's score, for candidates it re-scored
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rerank_score: Option<f64>,
+}
+
+/// The chat's document filters when a question was asked, as set with `--types`, `/filter`
+/// and `/focus`
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct TracedFilter {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub collections: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub file_types: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub modified_after: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub modified_before: Option<i64>,
+    /// Id of the document the chat was focused on
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub focus: Option<String>,
+}
+
+impl TracedFilter {
+    pub fn new(filter: &SearchFilter, focus: Option<&str>) -> Self {
+        Self {
+            collections: filter.collections.clone(),
+            tags: filter.tags.clone(),
+            file_types: filter.file_types.clone(),
+            path: filter.path.clone(),
+            modified_after: filter.modified_after,
+            modified_before: filter.modified_before,
+            focus: focus.map(str::to_string),
+        }
+    }
+
+    /// The metadata filter to search with again; the focus is applied separately
+    pub fn search_filter(&self) -> SearchFilter {
+        SearchFilter {
+            collections: self.collections.clone(),
+            tags: self.tags.clone(),
+            file_types: self.file_types.clone(),
+            path: self.path.clone(),
+            modified_after: self.modified_after,
+            modified_before: self.modified_before,
+            ..SearchFilter::default()
+        }
+    }
+}
+
+/// How the passages for one question were retrieved, enough to run the retrieval again and
+/// tell what changed
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RetrievalTrace {
+    pub question: String,
+    /// SHA-256 of the question's vector, which changes with the embedding model or prefixes
+    pub query_embedding_hash: String,
+    /// Paraphrases or hypothetical passages searched along with the question
+    pub variants: Vec<String>,
+    pub filter: TracedFilter,
+    /// Retrieval settings: databases, routing, embedding model, search mode, expansion,
+    /// reranker and context limits
+    pub settings: Value,
+    /// Brain routing sent the question to
+    pub routed_to: Option<String>,
+    /// Every candidate retrieved, best first, before reranking and passage selection
+    pub candidates: Vec<TracedHit>,
+    /// The passages answered from, in the order they were given to the model
+    pub passages: Vec<TracedHit>,
+}
+
+/// A trace as stored, with its turn number
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TraceRecord {
+    /// Turn in the log, from 1
+    pub sequence: u64,
+    pub id: String,
+    /// When the question was asked, RFC 3339
+    pub recorded_at: String,
+    #[serde(flatten)]
+    pub trace: RetrievalTrace,
+}
+
+/// SHA-256 of a vector's values as little-endian 32-bit floats, the precision brains store
+pub fn embedding_hash(embedding: &[f64]) -> String {
+    let mut hasher = Sha256::new();
+    for value in embedding {
+        hasher.update((*value as f32).to_le_bytes());
+    }
+    format!("{:x}", hasher.finalize())
+}
+
+/// Log of the retrieval behind each chat turn in a DuckDB file, for replaying questions
+/// later against a changed corpus or settings
+pub struct TraceLog {
+    path: PathBuf,
+}
+
+impl TraceLog {
+    /// The log in `path`, created on the first question
+    pub fn new(path: &Path) -> Self {
+        Self { path: path.to_path_buf() }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Opened for each write rather than held, so several chat sessions can share one log
+    fn connect(&self) -> Result<Connection> {
+        let conn = Connection::open(&self.path)
+            .with_context(|| format!("Failed to open the trace log {}", self.path.display()))?;
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS retrieval_traces (
+                sequence BIGINT PRIMARY KEY,
+                id VARCHAR NOT NULL,
+                recorded_at VARCHAR NOT NULL,
+                question VARCHAR NOT NULL,
+                trace VARCHAR NOT NULL
+            )",
+            [],
+        ).context("Failed to create the trace table")?;
+        Ok(conn)
+    }
+
+    /// Record a turn's retrieval at the end of the log
+    pub fn append(&self, trace: RetrievalTrace) -> Result<TraceRecord> {
+        let mut conn = self.connect()?;
+        let tx = conn.transaction()?;
+        let last: i64 = tx.query_row("SELECT COALESCE(MAX(sequence), 0) FROM retrieval_traces", [], |row| row.get(0))
+            .context("Failed to read the trace log")?;
+        let record = TraceRecord {
+            sequence: last as u64 + 1,
+            id: uuid::Uuid::new_v4().to_string(),
+            recorded_at: chrono::Utc::now().to_rfc3339(),
+            trace,
+        };
+        tx.execute(
+            "INSERT INTO retrieval_traces VALUES (?, ?, ?, ?, ?)",
+            params![
+                record.sequence as i64,
+                record.id,
+                record.recorded_at,
+                record.trace.question,
+                serde_json::to_string(&record.trace)?,
+            ],
+        ).context("Failed to write to the trace log")?;
+        tx.commit()?;
+        Ok(record)
+    }
+
+    /// Every trace, oldest first
+    pub fn records(&self) -> Result<Vec<TraceRecord>> {
+        let conn = self.connect()?;
+        let mut stmt = conn.prepare("SELECT sequence, id, recorded_at, trace FROM retrieval_traces ORDER BY sequence")?;
+        let rows = stmt.query_map([], |row| {
+            Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?, row.get::<_, String>(2)?, row.get::<_, String>(3)?))
+        })?;
+        let mut records = Vec::new();
+        for row in rows {
+            let (sequence, id, recorded_at, trace) = row?;
+            records.push(TraceRecord {
+                sequence: sequence as u64,
+                id,
+                recorded_at,
+                trace: serde_json::from_str(&trace).context("Invalid trace in the trace log")?,
+            });
+        }
+        Ok(records)
+    }
+
+    /// The traces `turn` names: a turn number, a trace id, `last` or `all`
+    pub fn select(&self, turn: &str) -> Result<Vec<TraceRecord>> {
+        let records = self.records()?;
+        let selected: Vec<TraceRecord> = match turn {
+            "all" => records,
+            "last" => records.into_iter().last().into_iter().collect(),
+            _ => match turn.parse::<u64>() {
+                Ok(sequence) => records.into_iter().filter(|record| record.sequence == sequence).collect(),
+                Err(_) => records.into_iter().filter(|record| record.id == turn).collect(),
+            },
+        };
+        if selected.is_empty() {
+            anyhow::bail!("No turn {} in the trace log {}", turn, self.path.display());
+        }
+        Ok(selected)
+    }
+}
+
+/// A setting, filter or intermediate result that differs between a turn and its replay
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Change {
+    pub name: String,
+    pub before: Value,
+    pub after: Value,
+}
+
+/// Where a passage ranked in a turn and in its replay, by fragment
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PassageChange {
+    pub brain: String,
+    pub fragment_id: String,
+    pub file_path: String,
+    /// Rank among the passages, from 1; `None` where it wasn't one
+    pub before: Option<usize>,
+    pub after: Option<usize>,
+    pub score_before: Option<f64>,
+    pub score_after: Option<f64>,
+}
+
+/// What changed between a recorded turn's retrieval and running it again
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TraceDiff {
+    /// The question embeds to another vector: the embedding model or its prefixes changed
+    pub embedding_changed: bool,
+    pub changes: Vec<Change>,
+    /// The replay's passages in order, then those it dropped in their recorded order
+    pub passages: Vec<PassageChange>,
+    /// Recorded candidates retrieved again
+    pub candidates_kept: usize,
+    pub candidates_before: usize,
+    pub candidates_after: usize,
+}
+
+impl TraceDiff {
+    /// Passages kept in the same order, from the same question vector and settings
+    pub fn unchanged(&self) -> bool {
+        !self.embedding_changed && self.changes.is_empty() && self.passages.iter().all(|passage| passage.before == passage.after)
+    }
+}
+
+/// Compare a recorded retrieval with a replay of it. Fragments are matched by brain and id,
+/// so re-chunked documents show as dropped and added passages unless their ids are
+/// deterministic.
+pub fn diff(before: &RetrievalTrace, after: &RetrievalTrace) -> TraceDiff {
+    let key = |hit: &TracedHit| (hit.brain.clone(), hit.fragment_id.clone());
+
+    let mut changes = Vec::new();
+    let empty = serde_json::Map::new();
+    let (old, new) = (before.settings.as_object().unwrap_or(&empty), after.settings.as_object().unwrap_or(&empty));
+    for name in old.keys().chain(new.keys()).collect::<BTreeSet<_>>() {
+        let (was, is) = (old.get(name).cloned().unwrap_or(Value::Null), new.get(name).cloned().unwrap_or(Value::Null));
+        if was != is {
+            changes.push(Change { name: name.clone(), before: was, after: is });
+        }
+    }
+    let mut compare = |name: &str, was: Value, is: Value| {
+        if was != is {
+            changes.push(Change { name: name.to_string(), before: was, after: is });
+        }
+    };
+    compare("filter", serde_json::json!(before.filter), serde_json::json!(after.filter));
+    compare("routed_to", serde_json::json!(before.routed_to), serde_json::json!(after.routed_to));
+    compare("variants", serde_json::json!(before.variants), serde_json::json!(after.variants));
+
+    let recorded: HashMap<_, _> = before.passages.iter().enumerate().map(|(i, hit)| (key(hit), (i + 1, hit))).collect();
+    let mut passages: Vec<PassageChange> = after.passages.iter().enumerate()
+        .map(|(i, hit)| {
+            let was = recorded.get(&key(hit));
+            PassageChange {
+                brain: hit.brain.clone(),
+                fragment_id: hit.fragment_id.clone(),
+                file_path: hit.file_path.clone(),
+                before: was.map(|(rank, _)| *rank),
+                after: Some(i + 1),
+                score_before: was.map(|(_, hit)| hit.score),
+                score_after: Some(hit.score),
+            }
+        })
+        .collect();
+    let replayed: BTreeSet<_> = after.passages.iter().map(key).collect();
+    for (i, hit) in before.passages.iter().enumerate().filter(|(_, hit)| !replayed.contains(&key(hit))) {
+        passages.push(PassageChange {
+            brain: hit.brain.clone(),
+            fragment_id: hit.fragment_id.clone(),
+            file_path: hit.file_path.clone(),
+            before: Some(i + 1),
+            after: None,
+            score_before: Some(hit.score),
+            score_after: None,
+        });
+    }
+
+    let retrieved: BTreeSet<_> = after.candidates.iter().map(key).collect();
+    TraceDiff {
+        embedding_changed: before.query_embedding_hash != after.query_embedding_hash,
+        changes,
+        passages,
+        candidates_kept: before.candidates.iter().filter(|hit| retrieved.contains(&key(hit))).count(),
+        candidates_before: before.candidates.len(),
+        candidates_after: after.candidates.len(),
+    }
+}
+
+impl fmt::Display for TraceDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.embedding_changed {
+            writeln!(f, "   ⚠️  The question embeds to another vector (embedding model or prefixes changed)")?;
+        }
+        for change in &self.changes {
+            writeln!(f, "   ⚙️  {}: {} → {}", change.name, change.before, change.after)?;
+        }
+        let score = |score: Option<f64>| score.map_or_else(|| "-".to_string(), |score| format!("{:.3}", score));
+        for passage in &self.passages {
+            let (marker, rank) = match (passage.before, passage.after) {
+                (Some(before), Some(after)) if before == after => ("=", format!("{}", after)),
+                (Some(before), Some(after)) if after < before => ("↑", format!("{} (was {})", after, before)),
+                (Some(before), Some(after)) => ("↓", format!("{} (was {})", after, before)),
+                (None, Some(after)) => ("+", format!("{}", after)),
+                (Some(before), None) => ("-", format!("was {}", before)),
+                (None, None) => continue,
+            };
+            writeln!(f, "   {} [{}] {} fragment {} ({} → {})",
+                     marker, rank, passage.file_path, passage.fragment_id, score(passage.score_before), score(passage.score_after))?;
+        }
+        let kept = self.passages.iter().filter(|passage| passage.before.is_some() && passage.after.is_some()).count();
+        let recorded = self.passages.iter().filter(|passage| passage.before.is_some()).count();
+        writeln!(f, "   📊 {} of {} passages kept, {} of {} candidates retrieved again ({} now)",
+                 kept, recorded, self.candidates_kept, self.candidates_before, self.candidates_after)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn hit(fragment_id: &str, score: f64) -> TracedHit {
+        TracedHit {
+            brain: "handbook".to_string(),
+            fragment_id: fragment_id.to_string(),
+            file_path: format!("{}.md", fragment_id),
+            version: 4,
+            score,
+            rerank_score: None,
+        }
+    }
+
+    fn trace(passages: &[&str], settings: Value) -> RetrievalTrace {
+        RetrievalTrace {
+            question: "How many vacation days?".to_string(),
+            query_embedding_hash: embedding_hash(&[0.6, 0.8]),
+            variants: Vec::new(),
+            filter: TracedFilter { file_types: vec!["pdf".to_string()], ..TracedFilter::default() },
+            settings,
+            routed_to: None,
+            candidates: passages.iter().map(|id| hit(id, 0.5)).collect(),
+            passages: passages.iter().enumerate().map(|(i, id)| hit(id, 0.9 - i as f64 / 10.0)).collect(),
+        }
+    }
+
+    #[test]
+    fn test_trace_log_selects_turns() {
+        let path = std::env::temp_dir().join(format!("pb-traces-{}.traces", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let log = TraceLog::new(&path);
+        let first = log.append(trace(&["a", "b"], json!({"results": 2}))).unwrap();
+        let second = log.append(trace(&["c"], json!({"results": 1}))).unwrap();
+        assert_eq!((first.sequence, second.sequence), (1, 2));
+
+        assert_eq!(log.select("all").unwrap().len(), 2);
+        assert_eq!(log.select("last").unwrap()[0].trace.passages[0].fragment_id, "c");
+        assert_eq!(log.select("1").unwrap()[0].trace, first.trace);
+        assert_eq!(log.select(&second.id).unwrap()[0].sequence, 2);
+        assert!(log.select("7").is_err());
+        // The filter reads back as the one searched with
+        assert_eq!(first.trace.filter.search_filter().file_types, vec!["pdf"]);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_replay_diff_reports_moved_added_and_dropped_passages() {
+        let before = trace(&["a", "b", "c"], json!({"search_mode": "vector", "results": 3}));
+        assert!(diff(&before, &before).unchanged());
+
+        let mut after = trace(&["b", "d", "c"], json!({"search_mode": "hybrid", "results": 3}));
+        after.query_embedding_hash = embedding_hash(&[0.8, 0.6]);
+        let changes = diff(&before, &after);
+        assert!(changes.embedding_changed && !changes.unchanged());
+        assert_eq!(changes.changes, vec![Change { name: "search_mode".to_string(), before: json!("vector"), after: json!("hybrid") }]);
+        let ranks: Vec<(&str, Option<usize>, Option<usize>)> = changes.passages.iter()
+            .map(|passage| (passage.fragment_id.as_str(), passage.before, passage.after))
+            .collect();
+        assert_eq!(ranks, vec![("b", Some(2), Some(1)), ("d", None, Some(2)), ("c", Some(3), Some(3)), ("a", Some(1), None)]);
+        assert_eq!((changes.candidates_kept, changes.candidates_before), (2, 3));
+
+        let report = changes.to_string();
+        assert!(report.contains("↑ [1 (was 2)] b.md fragment b (0.800 → 0.900)"));
+        assert!(report.contains("- [was 1] a.md fragment a (0.900 → -)"));
+        assert!(report.contains("2 of 3 passages kept, 2 of 3 candidates"));
+    }
+}
@@ -0,0 +1,348 @@
+use anyhow::{Context, Result};
+use regex::Regex;
+use std::collections::HashSet;
+use std::fmt;
+
+use crate::llm::{ChatMessage, LlmClient, TokenUsage};
+
+/// A sentence of a generated answer and the context passages it cites (1-based)
+#[derive(Debug, Clone, PartialEq)]
+pub struct CitedSentence {
+    pub text: String,
+    pub citations: Vec<usize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Support {
+    Supported,
+    Partial,
+    Unsupported,
+    /// The sentence makes no citation, so there is nothing to check it against
+    Uncited,
+}
+
+#[derive(Debug, Clone)]
+pub struct SentenceCheck {
+    pub sentence: String,
+    pub citations: Vec<usize>,
+    pub support: Support,
+    pub reason: String,
+}
+
+/// Outcome of checking every cited sentence of an answer against its sources
+#[derive(Debug, Clone)]
+pub struct VerificationReport {
+    pub checks: Vec<SentenceCheck>,
+    /// Tokens spent on the verification request
+    pub token_usage: TokenUsage,
+}
+
+impl VerificationReport {
+    /// Number of sentences whose citations don't back them up
+    pub fn flagged(&self) -> usize {
+        self.checks.iter()
+            .filter(|c| matches!(c.support, Support::Unsupported | Support::Partial))
+            .count()
+    }
+}
+
+fn citation_pattern() -> Regex {
+    Regex::new(r"\s*\[(\d+(?:\s*,\s*\d+)*)\]").expect("valid citation regex")
+}
+
+/// Split an answer into sentences, attaching `[n]` / `[n, m]` markers to the sentence they follow
+pub fn split_cited_sentences(answer: &str) -> Vec<CitedSentence> {
+    let citations = citation_pattern();
+    let boundary = Regex::new(r"[.!?](\s+|$)|\n+").expect("valid sentence regex");
+
+    let mut sentences: Vec<CitedSentence> = Vec::new();
+    let mut start = 0;
+    let mut pieces = Vec::new();
+    for m in boundary.find_iter(answer) {
+        pieces.push(&answer[start..m.end()]);
+        start = m.end();
+    }
+    if start < answer.len() {
+        pieces.push(&answer[start..]);
+    }
+
+    for piece in pieces {
+        let cited: Vec<usize> = citations.captures_iter(piece)
+            .flat_map(|caps| {
+                caps[1].split(',')
+                    .filter_map(|n| n.trim().parse().ok())
+                    .collect::<Vec<usize>>()
+            })
+            .collect();
+        let text = citations.replace_all(piece, "").trim().to_string();
+
+        if text.is_empty() {
+            // A marker placed after the full stop belongs to the previous sentence
+            if let Some(previous) = sentences.last_mut() {
+                previous.citations.extend(cited);
+            }
+            continue;
+        }
+
+        sentences.push(CitedSentence { text, citations: cited });
+    }
+
+    for sentence in &mut sentences {
+        sentence.citations.sort_unstable();
+        sentence.citations.dedup();
+    }
+    sentences
+}
+
+#[derive(serde::Deserialize)]
+struct Verdict {
+    id: usize,
+    verdict: String,
+    #[serde(default)]
+    reason: String,
+}
+
+/// Parse the checker's JSON array, tolerating prose or code fences around it
+fn parse_verdicts(text: &str) -> Result<Vec<Verdict>> {
+    let start = text.find('[').ok_or_else(|| anyhow::anyhow!("Verifier response contains no JSON array"))?;
+    let end = text.rfind(']').ok_or_else(|| anyhow::anyhow!("Verifier response contains no JSON array"))?;
+    serde_json::from_str(&text[start..=end]).context("Failed to parse verifier response")
+}
+
+/// Check that each cited sentence of `answer` is supported by the passages it cites,
+/// using the LLM as a judge
+pub async fn verify_answer(llm: &LlmClient, answer: &str, passages: &[String]) -> Result<VerificationReport> {
+    let sentences = split_cited_sentences(answer);
+    let mut checks: Vec<SentenceCheck> = Vec::new();
+    let mut claims = String::new();
+    let mut pending = Vec::new();
+    let mut token_usage = TokenUsage::default();
+
+    for sentence in sentences {
+        // Sentences that can be judged without asking the LLM
+        let support = if sentence.citations.is_empty() {
+            Some((Support::Uncited, "no citation given".to_string()))
+        } else {
+            sentence.citations.iter()
+                .find(|&&n| n == 0 || n > passages.len())
+                .map(|missing| (Support::Unsupported, format!("cites passage [{}], which was not retrieved", missing)))
+        };
+
+        match support {
+            Some((support, reason)) => checks.push(SentenceCheck {
+                sentence: sentence.text,
+                citations: sentence.citations,
+                support,
+                reason,
+            }),
+            None => {
+                let id = pending.len() + 1;
+                claims.push_str(&format!("Claim {}: {}\n", id, sentence.text));
+                for n in &sentence.citations {
+                    claims.push_str(&format!("  Passage [{}]: {}\n", n, passages[n - 1]));
+                }
+                claims.push('\n');
+                pending.push((checks.len(), sentence));
+                // Placeholder until the verdict arrives
+                checks.push(SentenceCheck {
+                    sentence: String::new(),
+                    citations: Vec::new(),
+                    support: Support::Uncited,
+                    reason: String::new(),
+                });
+            }
+        }
+    }
+
+    if !pending.is_empty() {
+        let messages = vec![
+            ChatMessage::system(
+                "You check whether claims are supported by the passages cited for them. \
+                 Judge each claim only against its own passages, not outside knowledge. \
+                 Reply with a JSON array and nothing else, one object per claim: \
+                 {\"id\": <claim number>, \"verdict\": \"supported\" | \"partial\" | \"unsupported\", \
+                 \"reason\": <one short sentence>}",
+            ).cached(),
+            ChatMessage::user(claims),
+        ];
+        let reply = llm.complete(messages, 1000, 0.0).await
+            .context("Verification request failed")?;
+        token_usage = reply.usage;
+        let verdicts = parse_verdicts(&reply.content)?;
+
+        for (i, (index, sentence)) in pending.into_iter().enumerate() {
+            let verdict = verdicts.iter().find(|v| v.id == i + 1);
+            let (support, reason) = match verdict {
+                Some(v) => {
+                    let support = match v.verdict.to_lowercase().as_str() {
+                        "supported" => Support::Supported,
+                        "partial" => Support::Partial,
+                        _ => Support::Unsupported,
+                    };
+                    (support, v.reason.clone())
+                }
+                None => (Support::Unsupported, "verifier returned no verdict".to_string()),
+            };
+            checks[index] = SentenceCheck {
+                sentence: sentence.text,
+                citations: sentence.citations,
+                support,
+                reason,
+            };
+        }
+    }
+
+    Ok(VerificationReport { checks, token_usage })
+}
+
+impl fmt::Display for VerificationReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let cited = self.checks.iter().filter(|c| c.support != Support::Uncited).count();
+        let supported = self.checks.iter().filter(|c| c.support == Support::Supported).count();
+        writeln!(f, "🔎 Citation check: {} of {} cited statements supported", supported, cited)?;
+
+        for check in &self.checks {
+            let marker = match check.support {
+                Support::Supported => continue,
+                Support::Partial => "⚠️  Partially supported",
+                Support::Unsupported => "❌ Unsupported",
+                Support::Uncited => "·  Uncited",
+            };
+            let citations: Vec<String> = check.citations.iter().map(|n| format!("[{}]", n)).collect();
+            writeln!(f, "   {}: \"{}\" {} — {}", marker, check.sentence, citations.join(""), check.reason)?;
+        }
+        Ok(())
+    }
+}
+
+/// Words too common to show where a statement came from
+const STOPWORDS: &[&str] = &[
+    "the", "and", "for", "are", "was", "were", "but", "not", "with", "this", "that", "these",
+    "those", "from", "has", "have", "had", "its", "it's", "their", "they", "them", "there",
+    "which", "who", "what", "when", "where", "how", "can", "will", "would", "should", "could",
+    "also", "than", "then", "into", "about", "been", "being", "all", "any", "each", "more",
+    "most", "other", "some", "such", "only", "our", "you", "your", "his", "her", "she",
+    "don't", "doesn't", "isn't", "aren't", "can't", "cannot",
+];
+
+/// Lowercased words of `text` that say something: three letters or more, or any number,
+/// and not a stopword
+fn content_words(text: &str) -> Vec<String> {
+    text.split(|c: char| !(c.is_alphanumeric() || c == '\''))
+        .map(|word| word.trim_matches('\'').to_lowercase())
+        .filter(|word| word.chars().count() >= 3 || word.chars().any(|c| c.is_ascii_digit()))
+        .filter(|word| !word.is_empty() && !STOPWORDS.contains(&word.as_str()))
+        .collect()
+}
+
+/// A sentence of an answer with the share of its words found in the retrieved passages
+#[derive(Debug, Clone)]
+pub struct SentenceGrounding {
+    pub sentence: String,
+    pub grounded: f64,
+}
+
+/// How much of an answer can be traced to the retrieved passages by word overlap, a cheap
+/// check run on every answer, unlike `verify_answer`
+#[derive(Debug, Clone)]
+pub struct GroundingReport {
+    /// Share in [0, 1] of the answer's words found in the passages, over all sentences
+    pub score: f64,
+    pub sentences: Vec<SentenceGrounding>,
+    /// Score below which the answer is flagged
+    pub threshold: f64,
+}
+
+impl GroundingReport {
+    /// Whether the answer scores below the threshold
+    pub fn below_threshold(&self) -> bool {
+        self.score < self.threshold
+    }
+}
+
+/// Score how much of `answer` is attributable to `passages`: each sentence by the share of
+/// its content words that occur in any passage, and the answer by the share over all of
+/// them. Citation markers are ignored, so uncited statements count too.
+pub fn ground_answer(answer: &str, passages: &[String], threshold: f64) -> GroundingReport {
+    let vocabulary: HashSet<String> = passages.iter().flat_map(|passage| content_words(passage)).collect();
+    let mut sentences = Vec::new();
+    let (mut words, mut found) = (0, 0);
+    for sentence in split_cited_sentences(answer) {
+        let sentence_words = content_words(&sentence.text);
+        if sentence_words.is_empty() {
+            continue;
+        }
+        let sentence_found = sentence_words.iter().filter(|word| vocabulary.contains(*word)).count();
+        words += sentence_words.len();
+        found += sentence_found;
+        sentences.push(SentenceGrounding {
+            sentence: sentence.text,
+            grounded: sentence_found as f64 / sentence_words.len() as f64,
+        });
+    }
+    // An answer with nothing to check has nothing ungrounded
+    let score = if words == 0 { 1.0 } else { found as f64 / words as f64 };
+    GroundingReport { score, sentences, threshold }
+}
+
+impl fmt::Display for GroundingReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "🧷 Grounding: {:.0}% of the answer's words appear in the retrieved passages", self.score * 100.0)?;
+        if self.below_threshold() {
+            writeln!(f, "⚠️  Below the {:.0}% grounding threshold; check these statements against the sources:", self.threshold * 100.0)?;
+            for sentence in self.sentences.iter().filter(|s| s.grounded < self.threshold) {
+                writeln!(f, "   {:>3.0}% \"{}\"", sentence.grounded * 100.0, sentence.sentence)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_cited_sentences() {
+        let sentences = split_cited_sentences(
+            "Revenue grew 12% in 2023 [1]. Costs fell [2, 3]. [4]\nNo source here."
+        );
+
+        assert_eq!(sentences.len(), 3);
+        assert_eq!(sentences[0].text, "Revenue grew 12% in 2023.");
+        assert_eq!(sentences[0].citations, vec![1]);
+        assert_eq!(sentences[1].citations, vec![2, 3, 4]);
+        assert!(sentences[2].citations.is_empty());
+    }
+
+    #[test]
+    fn test_parse_verdicts_in_code_fence() {
+        let verdicts = parse_verdicts(
+            "```json\n[{\"id\": 1, \"verdict\": \"supported\", \"reason\": \"stated directly\"}]\n```"
+        ).unwrap();
+
+        assert_eq!(verdicts.len(), 1);
+        assert_eq!(verdicts[0].verdict, "supported");
+    }
+
+    #[test]
+    fn test_grounding_by_word_overlap() {
+        let passages = vec![
+            "Revenue grew 12% in 2023, driven by the northern region.".to_string(),
+            "Operating costs fell after the warehouse closed.".to_string(),
+        ];
+        let grounded = ground_answer("Revenue grew 12% in 2023 [1]. Costs fell [2].", &passages, 0.5);
+        assert!((grounded.score - 1.0).abs() < 1e-9);
+        assert!(!grounded.below_threshold());
+
+        let drifting = ground_answer("Revenue grew [1]. Analysts expect dividends tripling next quarter.", &passages, 0.5);
+        assert_eq!(drifting.sentences.len(), 2);
+        assert!((drifting.sentences[0].grounded - 1.0).abs() < 1e-9);
+        assert_eq!(drifting.sentences[1].grounded, 0.0);
+        assert!((drifting.score - 2.0 / 8.0).abs() < 1e-9);
+        assert!(drifting.below_threshold());
+        assert!(drifting.to_string().contains("0% \"Analysts expect dividends tripling next quarter.\""));
+
+        assert_eq!(ground_answer("", &passages, 0.5).score, 1.0);
+    }
+}
@@ -0,0 +1,199 @@
+use anyhow::Result;
+use std::collections::HashMap;
+use std::fmt::Write as _;
+
+/// A fragment embedding labelled with the document it came from
+pub struct LabelledEmbedding {
+    pub fragment_id: String,
+    pub label: String,
+    pub embedding: Vec<f64>,
+}
+
+/// A fragment projected into two dimensions
+pub struct ProjectedPoint {
+    pub fragment_id: String,
+    pub label: String,
+    pub x: f64,
+    pub y: f64,
+}
+
+/// Project embeddings onto their first two principal components.
+///
+/// PCA keeps this dependency-free and deterministic; for UMAP/t-SNE export the raw
+/// vectors with `--format raw` and project them in Python.
+pub fn project_2d(points: &[LabelledEmbedding]) -> Result<Vec<ProjectedPoint>> {
+    if points.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let dimension = points[0].embedding.len();
+    if let Some(bad) = points.iter().find(|p| p.embedding.len() != dimension) {
+        anyhow::bail!(
+            "Mixed embedding dimensions: fragment {} has {} dimensions, expected {}",
+            bad.fragment_id, bad.embedding.len(), dimension
+        );
+    }
+
+    // Center the data
+    let n = points.len() as f64;
+    let mut mean = vec![0.0; dimension];
+    for point in points {
+        for (m, v) in mean.iter_mut().zip(&point.embedding) {
+            *m += v / n;
+        }
+    }
+    let centered: Vec<Vec<f64>> = points.iter()
+        .map(|p| p.embedding.iter().zip(&mean).map(|(v, m)| v - m).collect())
+        .collect();
+
+    let first = principal_component(&centered, None);
+    let second = principal_component(&centered, Some(&first));
+
+    Ok(points.iter()
+        .zip(&centered)
+        .map(|(point, row)| ProjectedPoint {
+            fragment_id: point.fragment_id.clone(),
+            label: point.label.clone(),
+            x: dot(row, &first),
+            y: dot(row, &second),
+        })
+        .collect())
+}
+
+fn dot(a: &[f64], b: &[f64]) -> f64 {
+    a.iter().zip(b).map(|(x, y)| x * y).sum()
+}
+
+/// Power iteration on X^T X without materializing the covariance matrix,
+/// optionally orthogonal to a previously found component
+fn principal_component(rows: &[Vec<f64>], orthogonal_to: Option<&[f64]>) -> Vec<f64> {
+    let dimension = rows.first().map(|r| r.len()).unwrap_or(0);
+    // Deterministic, non-degenerate starting vector
+    let mut vector: Vec<f64> = (0..dimension).map(|i| 1.0 + (i % 7) as f64 * 0.1).collect();
+
+    for _ in 0..100 {
+        let mut next = vec![0.0; dimension];
+        for row in rows {
+            let projection = dot(row, &vector);
+            for (n, r) in next.iter_mut().zip(row) {
+                *n += projection * r;
+            }
+        }
+
+        if let Some(previous) = orthogonal_to {
+            let overlap = dot(&next, previous);
+            for (n, p) in next.iter_mut().zip(previous) {
+                *n -= overlap * p;
+            }
+        }
+
+        let norm = dot(&next, &next).sqrt();
+        if norm == 0.0 {
+            break;
+        }
+        next.iter_mut().for_each(|v| *v /= norm);
+        vector = next;
+    }
+
+    vector
+}
+
+pub fn to_csv(points: &[ProjectedPoint]) -> String {
+    let mut out = String::from("fragment_id,document,x,y\n");
+    for point in points {
+        let _ = writeln!(out, "{},{},{:.6},{:.6}", point.fragment_id, csv_escape(&point.label), point.x, point.y);
+    }
+    out
+}
+
+fn csv_escape(value: &str) -> String {
+    if value.contains(',') || value.contains('"') || value.contains('\n') {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+fn html_escape(value: &str) -> String {
+    value.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+/// Render a self-contained SVG scatter plot with one colour per document
+pub fn to_html(points: &[ProjectedPoint]) -> String {
+    const SIZE: f64 = 800.0;
+    const MARGIN: f64 = 20.0;
+
+    let (min_x, max_x) = points.iter().fold((f64::MAX, f64::MIN), |(lo, hi), p| (lo.min(p.x), hi.max(p.x)));
+    let (min_y, max_y) = points.iter().fold((f64::MAX, f64::MIN), |(lo, hi), p| (lo.min(p.y), hi.max(p.y)));
+    let span_x = (max_x - min_x).max(f64::EPSILON);
+    let span_y = (max_y - min_y).max(f64::EPSILON);
+
+    let mut colours: HashMap<&str, String> = HashMap::new();
+    let mut circles = String::new();
+    for point in points {
+        let next_index = colours.len();
+        let colour = colours.entry(point.label.as_str())
+            .or_insert_with(|| format!("hsl({}, 65%, 50%)", (next_index * 137) % 360))
+            .clone();
+        let cx = MARGIN + (point.x - min_x) / span_x * (SIZE - 2.0 * MARGIN);
+        let cy = MARGIN + (point.y - min_y) / span_y * (SIZE - 2.0 * MARGIN);
+        let _ = writeln!(
+            circles,
+            "<circle cx=\"{:.1}\" cy=\"{:.1}\" r=\"3\" fill=\"{}\"><title>{} ({})</title></circle>",
+            cx, cy, colour, html_escape(&point.label), point.fragment_id
+        );
+    }
+
+    let mut legend: Vec<(&&str, &String)> = colours.iter().collect();
+    legend.sort();
+    let legend_items: String = legend.iter()
+        .map(|(label, colour)| format!("<li><span style=\"color:{}\">●</span> {}</li>", colour, html_escape(label)))
+        .collect();
+
+    format!(
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>Portable Brains embedding map</title></head>\n\
+         <body style=\"font-family:sans-serif\">\n<h1>Embedding map ({} fragments, PCA)</h1>\n\
+         <svg width=\"{}\" height=\"{}\" style=\"border:1px solid #ccc\">\n{}</svg>\n<ul>{}</ul>\n</body></html>\n",
+        points.len(), SIZE, SIZE, circles, legend_items
+    )
+}
+
+/// Raw vectors as JSON lines, for projecting with UMAP/t-SNE in Python
+pub fn to_jsonl(points: &[LabelledEmbedding]) -> Result<String> {
+    let mut out = String::new();
+    for point in points {
+        let line = serde_json::json!({
+            "fragment_id": point.fragment_id,
+            "document": point.label,
+            "embedding": point.embedding,
+        });
+        out.push_str(&serde_json::to_string(&line)?);
+        out.push('\n');
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_projection_separates_clusters() {
+        let points: Vec<LabelledEmbedding> = (0..10)
+            .map(|i| {
+                let (label, base) = if i < 5 { ("a", 1.0) } else { ("b", -1.0) };
+                LabelledEmbedding {
+                    fragment_id: i.to_string(),
+                    label: label.to_string(),
+                    embedding: vec![base + i as f64 * 0.01, base, 0.5],
+                }
+            })
+            .collect();
+
+        let projected = project_2d(&points).unwrap();
+
+        assert_eq!(projected.len(), 10);
+        // The two clusters should land on opposite sides of the first component
+        assert!(projected[0].x.signum() != projected[9].x.signum());
+    }
+}